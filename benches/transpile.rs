@@ -0,0 +1,201 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Measures transpiling a large generated Lua 5.1 chunk back into Lua 5.1, which is dominated by
+//! building, walking and dropping the lifted intermediate language, and building the control
+//! flow graph of the lifted chunk.
+//!
+//! Run with `cargo bench --features transpile`.
+
+use lunir::ir::mir::cir::into_cir_graph;
+use lunir::prelude::*;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Counts allocations, which is steadier than timings for comparing memory layouts.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// The number of times the straight-line block is repeated in the generated chunk.
+const BLOCKS: usize = 100_000;
+const ITERATIONS: usize = 10;
+
+const OP_LOADK: u32 = 1;
+const OP_GETGLOBAL: u32 = 5;
+const OP_ADD: u32 = 12;
+const OP_CALL: u32 = 28;
+const OP_RETURN: u32 = 30;
+const OP_FORLOOP: u32 = 31;
+const OP_FORPREP: u32 = 32;
+
+/// The number of number constants the generated code cycles through.
+const NUMBERS: usize = 200;
+
+fn abc(op: u32, a: u32, b: u32, c: u32) -> u32 {
+    op | a << 6 | c << 14 | b << 23
+}
+
+fn abx(op: u32, a: u32, bx: u32) -> u32 {
+    op | a << 6 | bx << 14
+}
+
+fn asbx(op: u32, a: u32, sbx: i32) -> u32 {
+    abx(op, a, (sbx + 131_071) as u32)
+}
+
+fn write_int(bytes: &mut Vec<u8>, n: usize) {
+    bytes.extend_from_slice(&(n as u32).to_le_bytes());
+}
+
+/// Generates a chunk repeating `print(n + m)` along with an empty numeric for loop.
+fn generate() -> (Vec<u8>, usize) {
+    let number = |i: usize| 1 + (i % NUMBERS) as u32;
+
+    let mut code = Vec::with_capacity(BLOCKS * 8 + 1);
+    for i in 0..BLOCKS {
+        code.push(abx(OP_GETGLOBAL, 0, 0));
+        code.push(abx(OP_LOADK, 1, number(i)));
+        code.push(abc(OP_ADD, 1, 1, 256 + number(i + 1)));
+        code.push(abc(OP_CALL, 0, 2, 1));
+
+        code.push(abx(OP_LOADK, 2, number(0)));
+        code.push(abx(OP_LOADK, 3, number(i)));
+        code.push(abx(OP_LOADK, 4, number(0)));
+        code.push(asbx(OP_FORPREP, 2, 0));
+        code.push(asbx(OP_FORLOOP, 2, -1));
+    }
+    code.push(abc(OP_RETURN, 0, 1, 0));
+
+    let mut bytes = vec![0x1b, b'L', b'u', b'a', 0x51, 0, 1, 4, 8, 4, 8, 0];
+
+    // source, linedefined, lastlinedefined
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    write_int(&mut bytes, 0);
+    write_int(&mut bytes, 0);
+
+    // upvalues, parameters, vararg flags and stack size
+    bytes.extend_from_slice(&[0, 0, 2, 6]);
+
+    write_int(&mut bytes, code.len());
+    for instruction in &code {
+        bytes.extend_from_slice(&instruction.to_le_bytes());
+    }
+
+    write_int(&mut bytes, NUMBERS + 1);
+    bytes.push(4);
+    bytes.extend_from_slice(&6u64.to_le_bytes());
+    bytes.extend_from_slice(b"print\0");
+    for n in 0..NUMBERS {
+        bytes.push(3);
+        bytes.extend_from_slice(&(n as f64).to_le_bytes());
+    }
+
+    // prototypes, line info, locals and upvalue names
+    for _ in 0..4 {
+        write_int(&mut bytes, 0);
+    }
+
+    (bytes, code.len())
+}
+
+/// Runs `f` `ITERATIONS` times, printing its timings and the allocations of one run.
+fn measure<T>(name: &str, mut f: impl FnMut() -> T) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+
+    let mut times = (0..ITERATIONS)
+        .map(|_| {
+            let start = Instant::now();
+            let result = f();
+            let elapsed = start.elapsed();
+
+            drop(result);
+            elapsed
+        })
+        .collect::<Vec<_>>();
+
+    let allocations = (ALLOCATIONS.load(Ordering::Relaxed) - before) / ITERATIONS;
+
+    times.sort();
+
+    let millis = |time: Duration| time.as_secs_f64() * 1000.0;
+
+    println!(
+        "{name}: min {:.1} ms, median {:.1} ms, {allocations} allocations",
+        millis(times[0]),
+        millis(times[times.len() / 2]),
+    );
+}
+
+fn main() {
+    let (bytecode, instructions) = generate();
+    let transpiler = Transpiler::new();
+
+    println!(
+        "{instructions} instructions, {} KiB of Lua 5.1 bytecode",
+        bytecode.len() / 1024
+    );
+
+    measure("transpile Lua 5.1 to Lua 5.1", || {
+        let transpilation = transpiler
+            .create_job()
+            .bytecode(&bytecode)
+            .target(Format::Lua51)
+            .run()
+            .unwrap();
+
+        assert!(!transpilation.bytecode.is_empty());
+    });
+
+    // blocks are ranges of the chunk the graph owns, so building the graph allocates per
+    // block rather than per instruction
+    let function = Format::Lua51.deserialize(&bytecode).unwrap();
+
+    measure("build the control flow graph", || {
+        let cfg = into_cir_graph(function.chunk.clone()).unwrap();
+        assert!(cfg.inner().node_count() > BLOCKS);
+
+        cfg
+    });
+}
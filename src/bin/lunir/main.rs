@@ -0,0 +1,468 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The `lunir` command line interface, which drives the LUNIR pipelines on files or on whole
+//! directories of them.
+
+mod tests;
+
+use anyhow::{Context, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use lunir::formats::{Deserializer, Detect, Format};
+use lunir::ir::{
+    ast::{parse, Dialect},
+    il::{Function, IlChunk},
+    mir::cir::{into_cir_graph, CirGraph},
+};
+use lunir::pipelines::{Compiler, Decompiler, LuaReconstructor, OptimizationLevel, Transpiler};
+use lunir::prelude::Compatibility;
+use petgraph::visit::EdgeRef;
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "lunir",
+    version,
+    about = "The Lua Universal Intermediate Representation"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    /// Prints an intermediate stage of every input to standard error, may be repeated.
+    #[arg(long, global = true, value_enum)]
+    dump_stage: Vec<Stage>,
+
+    /// How much the compiler and the decompiler optimize their output.
+    #[arg(long, global = true, value_enum, default_value = "moderate")]
+    opt: Opt,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Decompiles bytecode into source of the dialect its format was compiled from.
+    Decompile(Io),
+    /// Compiles source into bytecode, parsing it as the dialect of the target format.
+    Compile {
+        #[command(flatten)]
+        io: Io,
+        /// The format of the bytecode to produce.
+        #[arg(long, short)]
+        target: Format,
+    },
+    /// Lifts bytecode into intermediate language in its textual form.
+    Disasm(Io),
+    /// Prints the control flow graph of every function of bytecode.
+    Cfg {
+        #[command(flatten)]
+        io: Io,
+        /// The format of the graph.
+        #[arg(long, short, value_enum, default_value = "dot")]
+        format: GraphFormat,
+    },
+    /// Converts bytecode from one format into another.
+    Convert {
+        #[command(flatten)]
+        io: Io,
+        /// The format of the bytecode to produce.
+        #[arg(long, short)]
+        target: Format,
+        /// Fails on constructs the target has no instructions for instead of polyfilling them.
+        #[arg(long)]
+        strict: bool,
+    },
+}
+
+/// The input and output of a command. When the input is a directory every file in it is
+/// processed, and the output names the directory the results are written to.
+#[derive(Args, Debug)]
+struct Io {
+    /// The file or directory to read.
+    input: PathBuf,
+    /// The file or directory to write, standard output when a single file is read.
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+}
+
+/// The intermediate stages that can be dumped while a command runs.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Stage {
+    /// The intermediate language lifted from the input.
+    Il,
+    /// The control flow graph of every function, as DOT.
+    Cfg,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Opt {
+    None,
+    Moderate,
+    All,
+}
+
+impl From<Opt> for OptimizationLevel {
+    fn from(opt: Opt) -> Self {
+        match opt {
+            Opt::None => Self::None,
+            Opt::Moderate => Self::Moderate,
+            Opt::All => Self::All,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum GraphFormat {
+    Dot,
+    Json,
+}
+
+/// Runs `cli` on a single input, returning the contents of the output.
+fn process(cli: &Cli, path: &Path, bytes: &[u8]) -> Result<Vec<u8>> {
+    match &cli.command {
+        Command::Decompile(_) => {
+            let function = lift(cli, path, bytes)?;
+            let format = Format::detect(bytes).expect("lifted bytecode has a known format");
+
+            let source = Decompiler::new()
+                .create_job()
+                .function(&function)
+                .optimization_level(cli.opt.into())
+                .reconstructor(LuaReconstructor::new(dialect(format)))
+                .run()?;
+
+            Ok(source.into_bytes())
+        }
+        Command::Compile { target, .. } => {
+            let source = std::str::from_utf8(bytes).context("the source is not valid UTF-8")?;
+            let tree = parse(source, dialect(*target))?;
+
+            let bytecode = Compiler::new()
+                .create_job()
+                .tree(&tree)
+                .serializer(*target)
+                .optimization_level(cli.opt.into())
+                .run()?;
+
+            if !cli.dump_stage.is_empty() {
+                dump(cli, path, &target.deserialize(&bytecode)?)?;
+            }
+
+            Ok(bytecode)
+        }
+        Command::Disasm(_) => Ok(lift(cli, path, bytes)?.to_string().into_bytes()),
+        Command::Cfg { format, .. } => {
+            let function = lift(cli, path, bytes)?;
+            let graphs = graphs(&function)?;
+
+            Ok(match format {
+                GraphFormat::Dot => dot(&graphs).into_bytes(),
+                GraphFormat::Json => json(&graphs)?.into_bytes(),
+            })
+        }
+        Command::Convert { target, strict, .. } => {
+            let compatibility = if *strict {
+                Compatibility::Strict
+            } else {
+                Compatibility::Polyfill
+            };
+
+            let transpilation = Transpiler::new()
+                .create_job()
+                .source(Lifter { cli, path })
+                .compatibility(compatibility)
+                .bytecode(bytes)
+                .target(*target)
+                .run()?;
+
+            for polyfill in &transpilation.polyfills {
+                eprintln!(
+                    "{}: polyfilled {:?} at instruction {}",
+                    path.display(),
+                    polyfill.feature,
+                    polyfill.instruction
+                );
+            }
+
+            Ok(transpilation.bytecode)
+        }
+    }
+}
+
+/// The dialect of the source that bytecode of `format` is compiled from, which is Lua 5.4 for
+/// intermediate language as it can express every construct of it.
+fn dialect(format: Format) -> Dialect {
+    match format {
+        Format::Lua51 => Dialect::Lua51,
+        Format::Lua54 | Format::Lir => Dialect::Lua54,
+        Format::Luau => Dialect::Luau,
+    }
+}
+
+/// Lifts bytecode of any format into intermediate language, which debug builds verify,
+/// dumping the requested stages.
+fn lift(cli: &Cli, path: &Path, bytes: &[u8]) -> Result<Function> {
+    let function = Detect.deserialize(bytes)?;
+
+    if cfg!(debug_assertions) {
+        function
+            .verify()
+            .context("the bytecode was lifted into malformed IL")?;
+    }
+
+    dump(cli, path, &function)?;
+
+    Ok(function)
+}
+
+/// Lifts the input of a transpilation through `lift`, so that its stages are dumped without
+/// deserializing the bytecode a second time.
+struct Lifter<'a> {
+    cli: &'a Cli,
+    path: &'a Path,
+}
+
+impl Deserializer for Lifter<'_> {
+    fn deserialize(&self, bytes: &[u8]) -> Result<Function> {
+        lift(self.cli, self.path, bytes)
+    }
+}
+
+fn dump(cli: &Cli, path: &Path, function: &Function) -> Result<()> {
+    for stage in &cli.dump_stage {
+        let text = match stage {
+            Stage::Il => function.to_string(),
+            Stage::Cfg => dot(&graphs(function)?),
+        };
+
+        let name = stage.to_possible_value().expect("stages are never skipped");
+        eprintln!(";; {} of {}", name.get_name(), path.display());
+        eprint!("{text}");
+    }
+
+    Ok(())
+}
+
+/// The control flow graph of `function` and of every function nested in it, each along with
+/// the indices of the prototypes leading to it from `function`.
+fn graphs(function: &Function) -> Result<Vec<(Vec<usize>, CirGraph)>> {
+    fn walk(
+        function: &Function,
+        path: &mut Vec<usize>,
+        graphs: &mut Vec<(Vec<usize>, CirGraph)>,
+    ) -> Result<()> {
+        let graph = into_cir_graph(function.chunk.clone())
+            .with_context(|| format!("failed to build the graph of function {path:?}"))?;
+        graphs.push((path.clone(), graph));
+
+        for (index, prototype) in function.prototypes.iter().enumerate() {
+            path.push(index);
+            walk(prototype, path, graphs)?;
+            path.pop();
+        }
+
+        Ok(())
+    }
+
+    let mut graphs = Vec::new();
+    walk(function, &mut vec![], &mut graphs)?;
+
+    Ok(graphs)
+}
+
+/// Prints graphs as one DOT digraph, with a cluster for every function. Edges labeled `false`
+/// are dashed, which are those taken when the condition of a `jumpnot` is falsy or when a
+/// loop or another conditional jump falls through.
+fn dot(graphs: &[(Vec<usize>, CirGraph)]) -> String {
+    let mut out = String::from("digraph cfg {\n    node [shape=box, fontname=monospace];\n");
+
+    for (id, (path, graph)) in graphs.iter().enumerate() {
+        let _ = writeln!(out, "    subgraph cluster_{id} {{");
+        let _ = writeln!(out, "        label=\"function {path:?}\";");
+
+        for node in graph.inner().node_indices() {
+            let block = IlChunk::from(graph.block(graph.inner()[node])).to_string();
+            let label = block
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\l");
+
+            let _ = writeln!(out, "        f{id}_{} [label=\"{label}\"];", node.index());
+        }
+
+        for edge in graph.inner().edge_references() {
+            let style = if *edge.weight() {
+                ""
+            } else {
+                " [style=dashed]"
+            };
+
+            let _ = writeln!(
+                out,
+                "        f{id}_{} -> f{id}_{}{style};",
+                edge.source().index(),
+                edge.target().index()
+            );
+        }
+
+        out.push_str("    }\n");
+    }
+
+    out.push_str("}\n");
+    out
+}
+
+/// Prints graphs as a JSON array of `{"function": [...], "graph": ...}` objects, where the
+/// graph has the shape documented in `lunir::ir::il::schema`.
+fn json(graphs: &[(Vec<usize>, CirGraph)]) -> Result<String> {
+    let graphs = graphs
+        .iter()
+        .map(|(path, graph)| serde_json::json!({ "function": path, "graph": graph }))
+        .collect::<Vec<_>>();
+
+    Ok(serde_json::to_string_pretty(&graphs)? + "\n")
+}
+
+impl Cli {
+    fn io(&self) -> &Io {
+        match &self.command {
+            Command::Decompile(io) | Command::Disasm(io) => io,
+            Command::Compile { io, .. } | Command::Convert { io, .. } | Command::Cfg { io, .. } => {
+                io
+            }
+        }
+    }
+
+    /// The extension of the files written for a directory of inputs.
+    fn extension(&self) -> &'static str {
+        match &self.command {
+            Command::Decompile(_) => "lua",
+            Command::Disasm(_) => "il",
+            Command::Cfg { format, .. } => match format {
+                GraphFormat::Dot => "dot",
+                GraphFormat::Json => "json",
+            },
+            Command::Compile { target, .. } | Command::Convert { target, .. } => match target {
+                Format::Lua51 | Format::Lua54 => "luac",
+                Format::Luau => "luauc",
+                Format::Lir => "lir",
+            },
+        }
+    }
+}
+
+/// Every file in `dir` and the directories nested in it, in a stable order.
+fn files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("failed to read {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            files.extend(self::files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Processes every file of the directory `input` into the same place under `output`,
+/// returning how many of them failed.
+fn run_batch(cli: &Cli, input: &Path, output: &Path) -> Result<usize> {
+    let mut failures = 0;
+
+    for path in files(input)? {
+        let relative = path
+            .strip_prefix(input)
+            .with_context(|| format!("{} is not inside {}", path.display(), input.display()))?;
+        let out = output.join(relative).with_extension(cli.extension());
+
+        let result = fs::read(&path)
+            .with_context(|| format!("failed to read {}", path.display()))
+            .and_then(|bytes| process(cli, &path, &bytes))
+            .and_then(|bytes| {
+                if let Some(parent) = out.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                fs::write(&out, bytes).with_context(|| format!("failed to write {}", out.display()))
+            });
+
+        if let Err(error) = result {
+            eprintln!("{}: {error:#}", path.display());
+            failures += 1;
+        }
+    }
+
+    Ok(failures)
+}
+
+fn run(cli: &Cli) -> Result<usize> {
+    let io = cli.io();
+
+    if io.input.is_dir() {
+        let output = io
+            .output
+            .as_deref()
+            .context("an output directory is required for a directory of inputs")?;
+
+        return run_batch(cli, &io.input, output);
+    }
+
+    let bytes =
+        fs::read(&io.input).with_context(|| format!("failed to read {}", io.input.display()))?;
+    let result = process(cli, &io.input, &bytes)?;
+
+    match &io.output {
+        Some(path) => fs::write(path, result)
+            .with_context(|| format!("failed to write {}", path.display()))?,
+        None => io::stdout().write_all(&result)?,
+    }
+
+    Ok(0)
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(&cli) {
+        Ok(0) => ExitCode::SUCCESS,
+        Ok(failures) => {
+            eprintln!("{failures} inputs failed");
+            ExitCode::FAILURE
+        }
+        Err(error) => {
+            eprintln!("error: {error:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,156 @@
+#![cfg(test)]
+use super::*;
+use lunir::formats::Serializer;
+
+const LISTING: &str = "function (0, ...)
+    stack 2
+    r0 = true @1
+    jumpnot r0 L0
+    r0 = false @2
+    L0:
+    return r0, 1 @3
+end
+";
+
+fn cli(args: &[&str]) -> Cli {
+    Cli::try_parse_from(std::iter::once("lunir").chain(args.iter().copied())).unwrap()
+}
+
+fn bytecode(format: Format) -> Vec<u8> {
+    format
+        .serialize(&LISTING.parse::<Function>().unwrap())
+        .unwrap()
+}
+
+#[test]
+fn options_map_onto_the_pipeline() {
+    let cli = cli(&["convert", "in.luac", "-t", "luau", "--dump-stage", "il"]);
+
+    assert_eq!(cli.dump_stage, vec![Stage::Il]);
+    assert!(matches!(
+        cli.command,
+        Command::Convert {
+            target: Format::Luau,
+            strict: false,
+            ..
+        }
+    ));
+    assert_eq!(cli.extension(), "luauc");
+
+    assert!(Cli::try_parse_from(["lunir", "convert", "in.luac", "-t", "lua53"]).is_err());
+    assert!(Cli::try_parse_from(["lunir", "disasm", "in.luac", "--dump-stage", "ast"]).is_err());
+    assert!(Cli::try_parse_from(["lunir", "compile", "in.lua"]).is_err());
+
+    let cli = self::cli(&["decompile", "in.luac", "--opt", "all"]);
+    assert!(matches!(cli.opt.into(), OptimizationLevel::All));
+    assert_eq!(cli.extension(), "lua");
+    assert!(matches!(
+        self::cli(&["disasm", "in.luac"]).opt.into(),
+        OptimizationLevel::Moderate
+    ));
+    assert!(Cli::try_parse_from(["lunir", "decompile", "in.luac", "--opt", "some"]).is_err());
+}
+
+#[test]
+fn source_is_compiled_and_decompiled() {
+    let path = Path::new("in.lua");
+    let source = "local t = {1, 2, 3}\nprint(#t)\n";
+
+    for (name, target, dialect) in [
+        ("lua51", Format::Lua51, Dialect::Lua51),
+        ("lua54", Format::Lua54, Dialect::Lua54),
+        ("luau", Format::Luau, Dialect::Luau),
+        ("lir", Format::Lir, Dialect::Lua54),
+    ] {
+        for opt in ["none", "moderate", "all"] {
+            let compile = cli(&["compile", "in.lua", "-t", name, "--opt", opt]);
+            let bytecode = process(&compile, path, source.as_bytes()).unwrap();
+            assert_eq!(Format::detect(&bytecode), Some(target));
+
+            let decompile = cli(&["decompile", "in.luac", "--opt", opt]);
+            let decompiled = process(&decompile, path, &bytecode).unwrap();
+            let decompiled = String::from_utf8(decompiled).unwrap();
+
+            assert!(parse(&decompiled, dialect).is_ok(), "{decompiled}");
+            assert_eq!(
+                decompiled.contains("print(#t)"),
+                opt != "none",
+                "{decompiled}"
+            );
+        }
+    }
+
+    let error = process(
+        &cli(&["compile", "in.lua", "-t", "lua51"]),
+        path,
+        b"x = 1 // 2",
+    );
+    assert!(error.is_err());
+}
+
+#[test]
+fn bytecode_is_disassembled_and_converted() {
+    let path = Path::new("in.luac");
+    let lua51 = bytecode(Format::Lua51);
+
+    let il = process(&cli(&["disasm", "in.luac"]), path, &lua51).unwrap();
+    let function = String::from_utf8(il).unwrap().parse::<Function>().unwrap();
+    assert_eq!(
+        function.chunk.inner().last(),
+        LISTING.parse::<Function>().unwrap().chunk.inner().last()
+    );
+
+    let luau = process(&cli(&["convert", "in.luac", "-t", "luau"]), path, &lua51).unwrap();
+    assert_eq!(Format::detect(&luau), Some(Format::Luau));
+}
+
+#[test]
+fn graphs_are_printed_as_dot_and_json() {
+    let path = Path::new("in.luac");
+    let lua54 = bytecode(Format::Lua54);
+
+    let dot = process(&cli(&["cfg", "in.luac"]), path, &lua54).unwrap();
+    let dot = String::from_utf8(dot).unwrap();
+
+    let json = process(&cli(&["cfg", "in.luac", "-f", "json"]), path, &lua54).unwrap();
+    let json = serde_json::from_slice::<serde_json::Value>(&json).unwrap();
+
+    assert_eq!(json[0]["function"], serde_json::json!([]));
+
+    let blocks = json[0]["graph"]["blocks"].as_array().unwrap().len();
+    let edges = json[0]["graph"]["edges"].as_array().unwrap().len();
+
+    assert!(dot.starts_with("digraph cfg {"));
+    assert_eq!(dot.matches("[label=").count(), blocks);
+    assert_eq!(dot.matches(" -> ").count(), edges);
+    assert_eq!(dot.matches("style=dashed").count(), 1);
+}
+
+#[test]
+fn directories_are_processed_in_batches() {
+    let root = std::env::temp_dir().join(format!("lunir-cli-{}", std::process::id()));
+    let input = root.join("in");
+    let output = root.join("out");
+
+    fs::create_dir_all(input.join("nested")).unwrap();
+    fs::write(input.join("a.luac"), bytecode(Format::Lua51)).unwrap();
+    fs::write(input.join("nested/b.luauc"), bytecode(Format::Luau)).unwrap();
+    fs::write(input.join("broken.luac"), b"\x1bLua\x51").unwrap();
+
+    let cli = cli(&[
+        "convert",
+        input.to_str().unwrap(),
+        "-o",
+        output.to_str().unwrap(),
+        "-t",
+        "lir",
+    ]);
+    let failures = run(&cli).unwrap();
+
+    assert_eq!(failures, 1);
+    assert!(output.join("a.lir").is_file());
+    assert!(output.join("nested/b.lir").is_file());
+    assert!(!output.join("broken.lir").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
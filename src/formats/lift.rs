@@ -0,0 +1,230 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::ir::{
+    il::{Condition, ConditionalJump, IlChunk, Inst, Instruction, Jump, JumpNot, Label, Local},
+    span::Span,
+};
+use anyhow::{ensure, Result};
+use cranelift_entity::EntityRef;
+use std::collections::BTreeMap;
+
+/// The destination of a jump produced while lifting bytecode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Target {
+    /// The first instruction lifted from the bytecode instruction at this program counter,
+    /// the length of the code refers to the end of the chunk.
+    Pc(usize),
+    /// An instruction in the sequence lifted from the current program counter, one past
+    /// the end of the sequence refers to the next program counter.
+    Local(usize),
+}
+
+/// Builds an instruction once the label it jumps to has been assigned.
+type BuildBranch = Box<dyn FnOnce(Label) -> Instruction>;
+
+/// An IL instruction whose jump target has not been resolved yet.
+enum Lifted {
+    Instruction(Instruction),
+    Branch(Target, BuildBranch),
+}
+
+/// Collects the IL instructions lifted from each bytecode instruction and resolves the
+/// bytecode program counters jumps refer to into labels, which are placed in front of the
+/// instructions they mark.
+pub(crate) struct Lifter {
+    sequences: Vec<Vec<Lifted>>,
+    spans: Vec<Option<Span>>,
+}
+
+impl Lifter {
+    /// Creates a lifter for `code_size` bytecode instructions.
+    pub(crate) fn new(code_size: usize) -> Self {
+        Self {
+            sequences: Vec::with_capacity(code_size),
+            spans: Vec::with_capacity(code_size),
+        }
+    }
+
+    /// Starts the sequence of the next bytecode instruction, which is attributed to `span`.
+    pub(crate) fn begin(&mut self, span: Option<Span>) {
+        self.sequences.push(Vec::new());
+        self.spans.push(span);
+    }
+
+    /// The program counter of the bytecode instruction currently being lifted.
+    pub(crate) fn pc(&self) -> usize {
+        self.sequences.len() - 1
+    }
+
+    fn lifted(&mut self, lifted: Lifted) {
+        self.sequences
+            .last_mut()
+            .expect("`Lifter::begin` must be called before lifting")
+            .push(lifted);
+    }
+
+    pub(crate) fn push(&mut self, instruction: Instruction) {
+        self.lifted(Lifted::Instruction(instruction));
+    }
+
+    /// Lifts any instruction that may jump to `target`, such as the instructions of loops.
+    pub(crate) fn branch(
+        &mut self,
+        target: Target,
+        build: impl FnOnce(Label) -> Instruction + 'static,
+    ) {
+        self.lifted(Lifted::Branch(target, Box::new(build)));
+    }
+
+    pub(crate) fn jump(&mut self, target: Target) {
+        self.branch(target, |target| Instruction::Jump(Jump { target }));
+    }
+
+    pub(crate) fn jump_not(&mut self, cond: usize, target: Target) {
+        self.branch(target, move |target| {
+            Instruction::JumpNot(JumpNot { target, cond })
+        });
+    }
+
+    pub(crate) fn conditional_jump(&mut self, condition: Condition, target: Target) {
+        self.branch(target, |target| {
+            Instruction::ConditionalJump(ConditionalJump { target, condition })
+        });
+    }
+
+    /// Resolves every jump and returns the lifted chunk, whose instructions carry the span
+    /// of the bytecode instruction they were lifted from. Labels are numbered in the order
+    /// they appear in and take the span of the instruction they mark.
+    ///
+    /// Also returns the first instruction lifted from every program counter, including the
+    /// label in front of it, followed by the end of the chunk.
+    pub(crate) fn finish(self) -> Result<(IlChunk, Vec<Inst>)> {
+        let mut starts = Vec::with_capacity(self.sequences.len() + 1);
+        let mut total = 0;
+
+        for sequence in &self.sequences {
+            starts.push(total);
+            total += sequence.len();
+        }
+
+        starts.push(total);
+
+        // the position among the lifted instructions that a jump at `pc` refers to
+        let resolve = |pc: usize, size: usize, target: Target| -> Result<usize> {
+            match target {
+                Target::Pc(target) => {
+                    ensure!(
+                        target < starts.len(),
+                        "jump at {pc} targets nonexistent instruction {target}"
+                    );
+
+                    Ok(starts[target])
+                }
+                Target::Local(index) => {
+                    ensure!(index <= size, "jump at {pc} leaves its sequence");
+
+                    Ok(starts[pc] + index)
+                }
+            }
+        };
+
+        let mut labels = BTreeMap::new();
+
+        for (pc, sequence) in self.sequences.iter().enumerate() {
+            for lifted in sequence {
+                if let Lifted::Branch(target, _) = lifted {
+                    labels.insert(resolve(pc, sequence.len(), *target)?, Label(0));
+                }
+            }
+        }
+
+        for (index, label) in labels.values_mut().enumerate() {
+            *label = Label(index);
+        }
+
+        let mut chunk = IlChunk::default();
+        let mut pcs = Vec::with_capacity(self.sequences.len() + 1);
+        let mut position = 0;
+
+        for (pc, (sequence, &span)) in self.sequences.into_iter().zip(&self.spans).enumerate() {
+            let size = sequence.len();
+            pcs.push(Inst::new(chunk.len()));
+
+            for lifted in sequence {
+                if let Some(&label) = labels.get(&position) {
+                    chunk.push_spanned(Instruction::Label(label), span);
+                }
+
+                let instruction = match lifted {
+                    Lifted::Instruction(instruction) => instruction,
+                    Lifted::Branch(target, build) => build(labels[&resolve(pc, size, target)?]),
+                };
+
+                chunk.push_spanned(instruction, span);
+                position += 1;
+            }
+        }
+
+        // jumps past the last instruction land on a label at the end of the chunk
+        if let Some(&label) = labels.get(&total) {
+            chunk.push_spanned(
+                Instruction::Label(label),
+                self.spans.last().copied().flatten(),
+            );
+        }
+
+        pcs.push(Inst::new(chunk.len()));
+
+        Ok((chunk, pcs))
+    }
+}
+
+/// The first instruction lifted from the program counter `pc`, as mapped by `pcs`. Program
+/// counters past the end of the code map to the end of the chunk.
+pub(crate) fn inst_at(pcs: &[Inst], pc: usize) -> Inst {
+    pcs[pc.min(pcs.len() - 1)]
+}
+
+/// Converts the local variables of a function's debug information, as (name, startpc,
+/// endpc) triples, into locals whose range is given by the lifted instructions `pcs` maps
+/// program counters to. As in the reference implementation, the register of a local is the
+/// number of locals declared before it that are still live where it starts.
+pub(crate) fn lift_locals(locals: &[(String, usize, usize)], pcs: &[Inst]) -> Vec<Local> {
+    let mut lifted = Vec::with_capacity(locals.len());
+
+    for (index, (name, start, end)) in locals.iter().enumerate() {
+        let register = locals[..index]
+            .iter()
+            .filter(|&&(_, other_start, other_end)| other_start <= *start && *start < other_end)
+            .count();
+
+        lifted.push(Local {
+            name: name.clone(),
+            register,
+            start: inst_at(pcs, *start),
+            end: inst_at(pcs, *end),
+        });
+    }
+
+    lifted
+}
@@ -0,0 +1,536 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use super::*;
+use crate::formats::{check_nesting, Deserializer};
+use crate::ir::il::{
+    Arity, BinaryOp, Call, Close, Closure, Concat, Condition, ConditionalJump, Constant, DebugInfo,
+    ForGenCall, ForGenLoop, ForGenPrep, ForNumLoop, ForNumPrep, Function, GetGlobal, GetTable,
+    GetUpvalue, IlChunk, Inst, Instruction, Intrinsic, IntrinsicKind, Jump, JumpNot, Label, Load,
+    Local, Move, NewTable, OptVariable, Return, SelfLookup, SetGlobal, SetList, SetTable,
+    SetUpvalue, Table, UnaryOp, Upvalue, Value, VarArg,
+};
+use crate::ir::span::Span;
+use anyhow::{anyhow, ensure, Context, Result};
+use cranelift_entity::EntityRef;
+
+/// Deserializes a `.lir` container written by `LirSerializer` back into LUNIR intermediate
+/// language.
+#[derive(Clone, Debug, Default)]
+pub struct LirDeserializer;
+
+impl Deserializer for LirDeserializer {
+    /// Deserializes the container `bytes`, returning its main function.
+    fn deserialize(&self, bytes: &[u8]) -> Result<Function> {
+        let mut reader = Reader {
+            bytes,
+            position: 0,
+            version: VERSION,
+            strings: Vec::new(),
+            debug: false,
+        };
+
+        ensure!(
+            reader.read_bytes(4)? == SIGNATURE,
+            "not a LUNIR IL container"
+        );
+
+        let version = reader.read_byte()?;
+        ensure!(
+            version <= VERSION,
+            "unsupported container version {version}, expected at most {VERSION}"
+        );
+        reader.version = version;
+
+        let flags = reader.read_byte()?;
+        ensure!(
+            flags & !FLAG_DEBUG == 0,
+            "unknown header flags {flags:#04x}"
+        );
+        reader.debug = flags & FLAG_DEBUG != 0;
+
+        let string_count = reader.read_size()?;
+
+        for _ in 0..string_count {
+            let size = reader.read_size()?;
+            let string = reader.read_bytes(size)?.to_vec();
+
+            reader.strings.push(string);
+        }
+
+        let function = reader
+            .read_function(0)
+            .context("failed to read the main function")?;
+
+        ensure!(
+            reader.position == bytes.len(),
+            "{} trailing bytes after the main function",
+            bytes.len() - reader.position
+        );
+
+        Ok(function)
+    }
+}
+
+/// Reads the primitive types of the `.lir` format.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    /// The version of the container, which decides how older encodings are read.
+    version: u8,
+    strings: Vec<Vec<u8>>,
+    /// Whether every function is followed by a debug section.
+    debug: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.position..)
+            .and_then(|rest| rest.get(..count))
+            .with_context(|| format!("unexpected end of container at offset {}", self.position))?;
+
+        self.position += count;
+
+        Ok(bytes)
+    }
+
+    fn read_byte(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_bool(&mut self) -> Result<bool> {
+        match self.read_byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => Err(anyhow!("invalid boolean {byte}")),
+        }
+    }
+
+    fn read_varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        let mut shift = 0;
+
+        loop {
+            ensure!(shift < u64::BITS, "varint is too long");
+
+            let byte = self.read_byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn read_size(&mut self) -> Result<usize> {
+        let value = self.read_varint()?;
+
+        usize::try_from(value).context("size does not fit in usize")
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let value = self.read_varint()?;
+
+        u32::try_from(value).context("integer does not fit in u32")
+    }
+
+    fn read_signed(&mut self) -> Result<i64> {
+        let value = self.read_varint()?;
+
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn read_string(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.read_size()? {
+            0 => Ok(None),
+            id => self
+                .strings
+                .get(id - 1)
+                .cloned()
+                .map(Some)
+                .with_context(|| format!("string {id} does not exist")),
+        }
+    }
+
+    /// Reads a string that names something, which must be valid UTF-8.
+    fn read_text(&mut self) -> Result<Option<String>> {
+        self.read_string()?
+            .map(|string| String::from_utf8(string).context("names must be valid UTF-8"))
+            .transpose()
+    }
+
+    fn read_name(&mut self) -> Result<String> {
+        self.read_text()?.context("expected a name")
+    }
+
+    fn read_value(&mut self) -> Result<Value> {
+        let tag = self.read_byte()?;
+
+        Ok(
+            match ValueTag::try_from(tag).map_err(|tag| anyhow!("invalid value tag {tag}"))? {
+                ValueTag::Nil => Value::Nil,
+                ValueTag::Boolean => Value::Boolean(self.read_bool()?),
+                ValueTag::ConstantIndex => Value::ConstantIndex(self.read_size()?),
+                ValueTag::Immediate => Value::Immediate(
+                    i32::try_from(self.read_signed()?).context("immediate does not fit in i32")?,
+                ),
+                ValueTag::StackIndex => Value::StackIndex(self.read_size()?),
+            },
+        )
+    }
+
+    fn read_count(&mut self) -> Result<OptVariable> {
+        Ok(match self.read_size()? {
+            0 => OptVariable::Variable,
+            n => OptVariable::Number(n - 1),
+        })
+    }
+
+    fn read_label(&mut self) -> Result<Label> {
+        Ok(Label(self.read_size()?))
+    }
+
+    fn read_constant(&mut self) -> Result<Constant> {
+        let tag = self.read_byte()?;
+
+        Ok(
+            match ConstantTag::try_from(tag).map_err(|tag| anyhow!("invalid constant tag {tag}"))? {
+                ConstantTag::Nil => Constant::Nil,
+                ConstantTag::Boolean => Constant::Boolean(self.read_bool()?),
+                ConstantTag::Number => {
+                    Constant::Number(f64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+                }
+                ConstantTag::Integer => Constant::Integer(self.read_signed()?),
+                ConstantTag::String => Constant::String(
+                    self.read_string()?
+                        .context("string constant has no string")?,
+                ),
+                ConstantTag::Table => match self.read_byte()? {
+                    TABLE_MAP => {
+                        let count = self.read_size()?;
+                        let mut map = indexmap::IndexMap::new();
+
+                        for _ in 0..count {
+                            let key = self.read_value()?;
+                            let value = self.read_value()?;
+
+                            ensure!(
+                                map.insert(key.clone(), value).is_none(),
+                                "table constant has the key {key:?} twice"
+                            );
+                        }
+
+                        Constant::Table(Table::Map(map))
+                    }
+                    TABLE_ARRAY => {
+                        let count = self.read_size()?;
+
+                        Constant::Table(Table::Array(
+                            (0..count)
+                                .map(|_| self.read_value())
+                                .collect::<Result<_>>()?,
+                        ))
+                    }
+                    kind => return Err(anyhow!("invalid table kind {kind}")),
+                },
+            },
+        )
+    }
+
+    fn read_instruction(&mut self) -> Result<Instruction> {
+        let op = self.read_byte()?;
+        let op = OpCode::try_from(op).map_err(|op| anyhow!("invalid opcode {op}"))?;
+
+        Ok(match op {
+            OpCode::Label => Instruction::Label(self.read_label()?),
+            OpCode::Load => Instruction::Load(Load {
+                dest: self.read_size()?,
+                src: self.read_value()?,
+            }),
+            OpCode::Move => Instruction::Move(Move {
+                dest: self.read_size()?,
+                src: self.read_size()?,
+            }),
+            OpCode::Intrinsic => {
+                let kind = match self.read_byte()? {
+                    INTRINSIC_BAND => IntrinsicKind::BitAnd(self.read_value()?, self.read_value()?),
+                    INTRINSIC_BOR => IntrinsicKind::BitOr(self.read_value()?, self.read_value()?),
+                    INTRINSIC_BXOR => IntrinsicKind::BitXor(self.read_value()?, self.read_value()?),
+                    INTRINSIC_BNOT => IntrinsicKind::BitNot(self.read_value()?),
+                    INTRINSIC_SHL => {
+                        IntrinsicKind::LeftShift(self.read_value()?, self.read_value()?)
+                    }
+                    INTRINSIC_SHR => {
+                        IntrinsicKind::RightShift(self.read_value()?, self.read_value()?)
+                    }
+                    kind => return Err(anyhow!("invalid intrinsic {kind}")),
+                };
+
+                Instruction::Intrinsic(Intrinsic {
+                    kind,
+                    dest: self.read_size()?,
+                    source: decode_kind(&INTRINSIC_SOURCES, self.read_byte()?, "intrinsic source")?,
+                })
+            }
+            OpCode::GetGlobal => Instruction::GetGlobal(GetGlobal {
+                dest: self.read_size()?,
+                constant: self.read_size()?,
+            }),
+            OpCode::SetGlobal => Instruction::SetGlobal(SetGlobal {
+                src: self.read_size()?,
+                constant: self.read_size()?,
+            }),
+            OpCode::GetTable => Instruction::GetTable(GetTable {
+                dest: self.read_size()?,
+                source: self.read_size()?,
+                key: self.read_value()?,
+            }),
+            OpCode::SetTable => Instruction::SetTable(SetTable {
+                table: self.read_size()?,
+                key: self.read_value()?,
+                value: self.read_value()?,
+            }),
+            OpCode::SelfLookup => Instruction::SelfLookup(SelfLookup {
+                dest: self.read_size()?,
+                object: self.read_size()?,
+                key: self.read_value()?,
+            }),
+            OpCode::BinaryOp => Instruction::BinaryOp(BinaryOp {
+                operator: decode_kind(&BINARY_OPERATORS, self.read_byte()?, "binary operator")?,
+                dest: self.read_size()?,
+                left: self.read_value()?,
+                right: self.read_value()?,
+            }),
+            OpCode::UnaryOp => Instruction::UnaryOp(UnaryOp {
+                operator: decode_kind(&UNARY_OPERATORS, self.read_byte()?, "unary operator")?,
+                dest: self.read_size()?,
+                left: self.read_value()?,
+            }),
+            OpCode::Concat => Instruction::Concat(Concat {
+                dest: self.read_size()?,
+                start: self.read_size()?,
+                end: self.read_size()?,
+            }),
+            OpCode::Jump => Instruction::Jump(Jump {
+                target: self.read_label()?,
+            }),
+            OpCode::JumpNot => Instruction::JumpNot(JumpNot {
+                target: self.read_label()?,
+                cond: self.read_size()?,
+            }),
+            OpCode::ConditionalJump => Instruction::ConditionalJump(ConditionalJump {
+                target: self.read_label()?,
+                condition: Condition {
+                    kind: decode_kind(&CONDITIONS, self.read_byte()?, "condition")?,
+                    left: self.read_value()?,
+                    right: self.read_value()?,
+                },
+            }),
+            OpCode::ForNumPrep => Instruction::ForNumPrep(ForNumPrep {
+                target: self.read_label()?,
+                index: self.read_size()?,
+                limit: self.read_size()?,
+                step: self.read_size()?,
+                var: self.read_size()?,
+            }),
+            OpCode::ForNumLoop => Instruction::ForNumLoop(ForNumLoop {
+                target: self.read_label()?,
+                index: self.read_size()?,
+                limit: self.read_size()?,
+                step: self.read_size()?,
+                var: self.read_size()?,
+            }),
+            OpCode::ForGenPrep => Instruction::ForGenPrep(ForGenPrep {
+                target: self.read_label()?,
+                base: self.read_size()?,
+            }),
+            OpCode::ForGenCall => Instruction::ForGenCall(ForGenCall {
+                base: self.read_size()?,
+                dest: self.read_size()?,
+                count: self.read_size()?,
+            }),
+            OpCode::ForGenLoop => Instruction::ForGenLoop(ForGenLoop {
+                target: self.read_label()?,
+                base: self.read_size()?,
+                var: self.read_size()?,
+            }),
+            OpCode::NewTable => Instruction::NewTable(NewTable {
+                dest: self.read_size()?,
+                array_size: self.read_size()?,
+                table_size: self.read_size()?,
+            }),
+            OpCode::SetList => Instruction::SetList(SetList {
+                table: self.read_size()?,
+                start: self.read_size()?,
+                count: self.read_count()?,
+                offset: self.read_size()?,
+            }),
+            OpCode::Return => Instruction::Return(Return {
+                result_start: self.read_size()?,
+                // version 1 could not express a variable number of results
+                result_count: if self.version >= 2 {
+                    self.read_count()?
+                } else {
+                    OptVariable::Number(self.read_size()?)
+                },
+            }),
+            OpCode::Call => Instruction::Call(Call {
+                callee: self.read_size()?,
+                self_call: self.read_bool()?,
+                num_args: self.read_count()?,
+                num_returns: self.read_count()?,
+            }),
+            OpCode::VarArg => Instruction::VarArg(VarArg {
+                dest: self.read_size()?,
+                count: self.read_count()?,
+            }),
+            OpCode::Closure => Instruction::Closure(Closure {
+                dest: self.read_size()?,
+                prototype: self.read_size()?,
+            }),
+            OpCode::GetUpvalue => Instruction::GetUpvalue(GetUpvalue {
+                dest: self.read_size()?,
+                upvalue: self.read_size()?,
+            }),
+            OpCode::SetUpvalue => Instruction::SetUpvalue(SetUpvalue {
+                src: self.read_size()?,
+                upvalue: self.read_size()?,
+            }),
+            OpCode::Close => Instruction::Close(Close {
+                start: self.read_size()?,
+            }),
+        })
+    }
+
+    fn read_inst(&mut self, len: usize) -> Result<Inst> {
+        let index = self.read_size()?;
+        ensure!(
+            index <= len,
+            "instruction {index} is outside of a chunk of {len} instructions"
+        );
+
+        Ok(Inst::new(index))
+    }
+
+    /// Reads a function nested `depth` functions deep in the main one.
+    fn read_function(&mut self, depth: usize) -> Result<Function> {
+        check_nesting(depth)?;
+
+        let name = self.read_text()?;
+
+        let params = self.read_byte()?;
+        let arity = self.read_byte()?;
+        ensure!(
+            arity & !(ARITY_VARARG | ARITY_NEEDS_ARG) == 0,
+            "invalid arity flags {arity:#04x}"
+        );
+
+        let max_stack_size = self.read_byte()?;
+
+        let upvalues = (0..self.read_size()?)
+            .map(|_| {
+                Ok(Upvalue {
+                    in_stack: self.read_bool()?,
+                    index: self.read_size()?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let constants = (0..self.read_size()?)
+            .map(|index| {
+                self.read_constant()
+                    .with_context(|| format!("failed to read constant {index}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let instructions = (0..self.read_size()?)
+            .map(|index| {
+                self.read_instruction()
+                    .with_context(|| format!("failed to read instruction {index}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let prototypes = (0..self.read_size()?)
+            .map(|index| {
+                self.read_function(depth + 1)
+                    .with_context(|| format!("failed to read prototype {index}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut chunk = IlChunk::new(instructions);
+        let mut debug = DebugInfo::default();
+
+        if self.debug {
+            debug.source = self.read_text()?;
+            debug.line_defined = self.read_u32()?;
+            debug.last_line_defined = self.read_u32()?;
+
+            for index in 0..chunk.len() {
+                let line = self.read_varint()?;
+
+                if line != 0 {
+                    let line = u32::try_from(line - 1).context("line does not fit in u32")?;
+                    let span = Span::new(line, self.read_u32()?);
+
+                    chunk.set_span(Inst::new(index), Some(span));
+                }
+            }
+
+            let len = chunk.len();
+
+            debug.locals = (0..self.read_size()?)
+                .map(|_| {
+                    Ok(Local {
+                        name: self.read_name()?,
+                        register: self.read_size()?,
+                        start: self.read_inst(len)?,
+                        end: self.read_inst(len)?,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()
+                .context("failed to read locals")?;
+
+            debug.upvalue_names = (0..self.read_size()?)
+                .map(|_| self.read_name())
+                .collect::<Result<Vec<_>>>()
+                .context("failed to read upvalue names")?;
+        }
+
+        Ok(Function {
+            chunk,
+            constants,
+            prototypes,
+            upvalues,
+            arity: Arity {
+                params,
+                is_vararg: arity & ARITY_VARARG != 0,
+                needs_arg: arity & ARITY_NEEDS_ARG != 0,
+            },
+            name,
+            max_stack_size,
+            debug,
+        })
+    }
+}
@@ -0,0 +1,226 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The `.lir` container, a compact binary encoding of a whole `Function` tree that can be
+//! read back without lifting bytecode again. Integers are unsigned LEB128 varints, signed
+//! ones are zigzag encoded first, and numbers are little endian `f64` bits.
+//!
+//! - The header is `SIGNATURE`, the `VERSION` byte and a flags byte.
+//! - The string table follows as a count and then the length and bytes of every string.
+//!   Strings everywhere else are ids into it, where 0 is no string and `n` is the string
+//!   at `n - 1`. Names must be valid UTF-8, while string constants may hold any bytes.
+//! - The main function comes last. A function is its name, arity, stack size, upvalues,
+//!   constants, instructions and nested functions in order, followed by its debug section
+//!   when the header has `FLAG_DEBUG` set.
+//! - A debug section holds the source, the lines the function is defined on, the span of
+//!   every instruction, the locals and the names of the upvalues.
+
+mod deserializer;
+mod serializer;
+mod tests;
+
+pub use deserializer::*;
+pub use serializer::*;
+
+use crate::ir::il::{BinaryOpKind, ConditionKind, IntrinsicSource, UnaryOpKind};
+use anyhow::{Context, Result};
+
+/// The signature every `.lir` file starts with.
+pub const SIGNATURE: &[u8; 4] = b"\x1bLIR";
+
+/// The version of the `.lir` format written by `LirSerializer`, readers reject newer ones.
+pub const VERSION: u8 = 2;
+
+/// Set in the flags of the header when every function carries a debug section.
+pub(crate) const FLAG_DEBUG: u8 = 1;
+
+/// Set in the arity flags of a function that accepts extra arguments.
+pub(crate) const ARITY_VARARG: u8 = 1;
+/// Set in the arity flags of a function that collects its extra arguments into `arg`.
+pub(crate) const ARITY_NEEDS_ARG: u8 = 2;
+
+/// The tags of `Instruction` variants, in the order of the enum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OpCode {
+    Label,
+    Load,
+    Move,
+    Intrinsic,
+    GetGlobal,
+    GetTable,
+    SetTable,
+    SelfLookup,
+    BinaryOp,
+    UnaryOp,
+    Concat,
+    Jump,
+    JumpNot,
+    ConditionalJump,
+    ForNumPrep,
+    ForNumLoop,
+    ForGenPrep,
+    ForGenCall,
+    ForGenLoop,
+    NewTable,
+    SetList,
+    Return,
+    Call,
+    VarArg,
+    SetGlobal,
+    Closure,
+    GetUpvalue,
+    SetUpvalue,
+    Close,
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if value <= OpCode::Close as u8 {
+            // SAFETY: `OpCode` is `repr(u8)` with contiguous discriminants up to `Close`
+            Ok(unsafe { std::mem::transmute::<u8, OpCode>(value) })
+        } else {
+            Err(value)
+        }
+    }
+}
+
+/// The tags of `Value` variants.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ValueTag {
+    Nil,
+    Boolean,
+    ConstantIndex,
+    Immediate,
+    StackIndex,
+}
+
+impl TryFrom<u8> for ValueTag {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Nil),
+            1 => Ok(Self::Boolean),
+            2 => Ok(Self::ConstantIndex),
+            3 => Ok(Self::Immediate),
+            4 => Ok(Self::StackIndex),
+            _ => Err(value),
+        }
+    }
+}
+
+/// The tags of the entries of a constant table, tables are followed by one of the
+/// `TABLE_*` kinds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ConstantTag {
+    Nil,
+    Boolean,
+    Number,
+    Integer,
+    String,
+    Table,
+}
+
+impl TryFrom<u8> for ConstantTag {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Nil),
+            1 => Ok(Self::Boolean),
+            2 => Ok(Self::Number),
+            3 => Ok(Self::Integer),
+            4 => Ok(Self::String),
+            5 => Ok(Self::Table),
+            _ => Err(value),
+        }
+    }
+}
+
+/// The kind of a table constant holding key-value pairs.
+pub(crate) const TABLE_MAP: u8 = 0;
+/// The kind of a table constant holding a list of values.
+pub(crate) const TABLE_ARRAY: u8 = 1;
+
+/// The kinds of intrinsic operations, in the order of `IntrinsicKind`.
+pub(crate) const INTRINSIC_BAND: u8 = 0;
+pub(crate) const INTRINSIC_BOR: u8 = 1;
+pub(crate) const INTRINSIC_BXOR: u8 = 2;
+pub(crate) const INTRINSIC_BNOT: u8 = 3;
+pub(crate) const INTRINSIC_SHL: u8 = 4;
+pub(crate) const INTRINSIC_SHR: u8 = 5;
+
+/// Binary operators, encoded as their position in this list.
+pub(crate) const BINARY_OPERATORS: [BinaryOpKind; 8] = [
+    BinaryOpKind::Add,
+    BinaryOpKind::Concat,
+    BinaryOpKind::Div,
+    BinaryOpKind::IDiv,
+    BinaryOpKind::Mod,
+    BinaryOpKind::Mul,
+    BinaryOpKind::Pow,
+    BinaryOpKind::Sub,
+];
+
+/// Unary operators, encoded as their position in this list.
+pub(crate) const UNARY_OPERATORS: [UnaryOpKind; 3] =
+    [UnaryOpKind::Len, UnaryOpKind::Not, UnaryOpKind::Neg];
+
+/// Condition kinds, encoded as their position in this list.
+pub(crate) const CONDITIONS: [ConditionKind; 8] = [
+    ConditionKind::Eq,
+    ConditionKind::Ge,
+    ConditionKind::Gt,
+    ConditionKind::Ne,
+    ConditionKind::Lt,
+    ConditionKind::Le,
+    ConditionKind::And,
+    ConditionKind::Or,
+];
+
+/// Intrinsic sources, encoded as their position in this list.
+pub(crate) const INTRINSIC_SOURCES: [IntrinsicSource; 3] = [
+    IntrinsicSource::Operator,
+    IntrinsicSource::Bit32,
+    IntrinsicSource::Bit,
+];
+
+/// Encodes `kind` as its position in `kinds`.
+pub(crate) fn encode_kind<T: PartialEq>(kinds: &[T], kind: &T) -> u8 {
+    kinds
+        .iter()
+        .position(|k| k == kind)
+        .expect("every kind is listed") as u8
+}
+
+/// Decodes the kind at position `byte` of `kinds`.
+pub(crate) fn decode_kind<T: Clone>(kinds: &[T], byte: u8, what: &str) -> Result<T> {
+    kinds
+        .get(byte as usize)
+        .cloned()
+        .with_context(|| format!("invalid {what} {byte}"))
+}
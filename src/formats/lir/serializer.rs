@@ -0,0 +1,454 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use super::*;
+use crate::formats::{Feature, Serializer};
+use crate::ir::il::{Constant, Function, Instruction, IntrinsicKind, OptVariable, Table, Value};
+use anyhow::Result;
+use cranelift_entity::EntityRef;
+use derive_builder::Builder;
+use indexmap::IndexSet;
+
+/// Serializes LUNIR intermediate language into a `.lir` container, which keeps every part
+/// of a function that the intermediate language can express.
+#[derive(Builder, Clone, Debug, Default)]
+#[builder(default)]
+pub struct LirSerializer {
+    /// Whether function names, spans and debug sections should be omitted.
+    strip_debug: bool,
+}
+
+impl LirSerializer {
+    /// Creates a builder for a `LirSerializer`.
+    pub fn builder() -> LirSerializerBuilder {
+        LirSerializerBuilder::default()
+    }
+}
+
+impl Serializer for LirSerializer {
+    /// Serializes `function` along with every prototype nested in it.
+    fn serialize(&self, function: &Function) -> Result<Vec<u8>> {
+        let mut body = Writer {
+            buf: Vec::new(),
+            strings: IndexSet::new(),
+            strip_debug: self.strip_debug,
+        };
+        body.write_function(function);
+
+        let mut writer = Writer {
+            buf: SIGNATURE.to_vec(),
+            strings: IndexSet::new(),
+            strip_debug: self.strip_debug,
+        };
+
+        writer.write_byte(VERSION);
+        writer.write_byte(if self.strip_debug { 0 } else { FLAG_DEBUG });
+
+        writer.write_size(body.strings.len());
+        for string in &body.strings {
+            writer.write_size(string.len());
+            writer.buf.extend_from_slice(string);
+        }
+
+        writer.buf.extend_from_slice(&body.buf);
+
+        Ok(writer.buf)
+    }
+
+    /// The intermediate language is the native format of every feature.
+    fn supports(&self, _feature: Feature) -> bool {
+        true
+    }
+}
+
+/// Writes the primitive types of the `.lir` format, interning strings as they are written.
+struct Writer {
+    buf: Vec<u8>,
+    strings: IndexSet<Vec<u8>>,
+    strip_debug: bool,
+}
+
+impl Writer {
+    fn write_byte(&mut self, byte: u8) {
+        self.buf.push(byte);
+    }
+
+    fn write_varint(&mut self, mut value: u64) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+
+            if value == 0 {
+                self.buf.push(byte);
+                break;
+            }
+
+            self.buf.push(byte | 0x80);
+        }
+    }
+
+    fn write_size(&mut self, size: usize) {
+        self.write_varint(size as u64);
+    }
+
+    fn write_signed(&mut self, value: i64) {
+        self.write_varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn write_string(&mut self, string: Option<&[u8]>) {
+        let id = match string {
+            Some(string) => match self.strings.get_index_of(string) {
+                Some(index) => index + 1,
+                None => self.strings.insert_full(string.to_vec()).0 + 1,
+            },
+            None => 0,
+        };
+
+        self.write_size(id);
+    }
+
+    fn write_value(&mut self, value: &Value) {
+        match *value {
+            Value::Nil => self.write_byte(ValueTag::Nil as u8),
+            Value::Boolean(b) => {
+                self.write_byte(ValueTag::Boolean as u8);
+                self.write_byte(b as u8);
+            }
+            Value::ConstantIndex(index) => {
+                self.write_byte(ValueTag::ConstantIndex as u8);
+                self.write_size(index);
+            }
+            Value::Immediate(n) => {
+                self.write_byte(ValueTag::Immediate as u8);
+                self.write_signed(n as i64);
+            }
+            Value::StackIndex(index) => {
+                self.write_byte(ValueTag::StackIndex as u8);
+                self.write_size(index);
+            }
+        }
+    }
+
+    fn write_count(&mut self, count: &OptVariable) {
+        match *count {
+            OptVariable::Variable => self.write_size(0),
+            OptVariable::Number(n) => self.write_size(n + 1),
+        }
+    }
+
+    fn write_constant(&mut self, constant: &Constant) {
+        match constant {
+            Constant::Nil => self.write_byte(ConstantTag::Nil as u8),
+            Constant::Boolean(b) => {
+                self.write_byte(ConstantTag::Boolean as u8);
+                self.write_byte(*b as u8);
+            }
+            Constant::Number(n) => {
+                self.write_byte(ConstantTag::Number as u8);
+                self.buf.extend_from_slice(&n.to_le_bytes());
+            }
+            Constant::Integer(n) => {
+                self.write_byte(ConstantTag::Integer as u8);
+                self.write_signed(*n);
+            }
+            Constant::String(s) => {
+                self.write_byte(ConstantTag::String as u8);
+                self.write_string(Some(s));
+            }
+            Constant::Table(Table::Map(map)) => {
+                self.write_byte(ConstantTag::Table as u8);
+                self.write_byte(TABLE_MAP);
+                self.write_size(map.len());
+
+                for (key, value) in map {
+                    self.write_value(key);
+                    self.write_value(value);
+                }
+            }
+            Constant::Table(Table::Array(array)) => {
+                self.write_byte(ConstantTag::Table as u8);
+                self.write_byte(TABLE_ARRAY);
+                self.write_size(array.len());
+
+                for value in array {
+                    self.write_value(value);
+                }
+            }
+        }
+    }
+
+    fn write_instruction(&mut self, instruction: &Instruction) {
+        match instruction {
+            Instruction::Label(label) => {
+                self.write_byte(OpCode::Label as u8);
+                self.write_size(label.0);
+            }
+            Instruction::Load(load) => {
+                self.write_byte(OpCode::Load as u8);
+                self.write_size(load.dest);
+                self.write_value(&load.src);
+            }
+            Instruction::Move(mov) => {
+                self.write_byte(OpCode::Move as u8);
+                self.write_size(mov.dest);
+                self.write_size(mov.src);
+            }
+            Instruction::Intrinsic(intrinsic) => {
+                self.write_byte(OpCode::Intrinsic as u8);
+
+                let kind = match intrinsic.kind {
+                    IntrinsicKind::BitAnd(..) => INTRINSIC_BAND,
+                    IntrinsicKind::BitOr(..) => INTRINSIC_BOR,
+                    IntrinsicKind::BitXor(..) => INTRINSIC_BXOR,
+                    IntrinsicKind::BitNot(_) => INTRINSIC_BNOT,
+                    IntrinsicKind::LeftShift(..) => INTRINSIC_SHL,
+                    IntrinsicKind::RightShift(..) => INTRINSIC_SHR,
+                };
+
+                self.write_byte(kind);
+                for operand in intrinsic.kind.operands() {
+                    self.write_value(operand);
+                }
+
+                self.write_size(intrinsic.dest);
+                self.write_byte(encode_kind(&INTRINSIC_SOURCES, &intrinsic.source));
+            }
+            Instruction::GetGlobal(get) => {
+                self.write_byte(OpCode::GetGlobal as u8);
+                self.write_size(get.dest);
+                self.write_size(get.constant);
+            }
+            Instruction::SetGlobal(set) => {
+                self.write_byte(OpCode::SetGlobal as u8);
+                self.write_size(set.src);
+                self.write_size(set.constant);
+            }
+            Instruction::GetTable(get) => {
+                self.write_byte(OpCode::GetTable as u8);
+                self.write_size(get.dest);
+                self.write_size(get.source);
+                self.write_value(&get.key);
+            }
+            Instruction::SetTable(set) => {
+                self.write_byte(OpCode::SetTable as u8);
+                self.write_size(set.table);
+                self.write_value(&set.key);
+                self.write_value(&set.value);
+            }
+            Instruction::SelfLookup(lookup) => {
+                self.write_byte(OpCode::SelfLookup as u8);
+                self.write_size(lookup.dest);
+                self.write_size(lookup.object);
+                self.write_value(&lookup.key);
+            }
+            Instruction::BinaryOp(op) => {
+                self.write_byte(OpCode::BinaryOp as u8);
+                self.write_byte(encode_kind(&BINARY_OPERATORS, &op.operator));
+                self.write_size(op.dest);
+                self.write_value(&op.left);
+                self.write_value(&op.right);
+            }
+            Instruction::UnaryOp(op) => {
+                self.write_byte(OpCode::UnaryOp as u8);
+                self.write_byte(encode_kind(&UNARY_OPERATORS, &op.operator));
+                self.write_size(op.dest);
+                self.write_value(&op.left);
+            }
+            Instruction::Concat(concat) => {
+                self.write_byte(OpCode::Concat as u8);
+                self.write_size(concat.dest);
+                self.write_size(concat.start);
+                self.write_size(concat.end);
+            }
+            Instruction::Jump(jump) => {
+                self.write_byte(OpCode::Jump as u8);
+                self.write_size(jump.target.0);
+            }
+            Instruction::JumpNot(jump) => {
+                self.write_byte(OpCode::JumpNot as u8);
+                self.write_size(jump.target.0);
+                self.write_size(jump.cond);
+            }
+            Instruction::ConditionalJump(jump) => {
+                self.write_byte(OpCode::ConditionalJump as u8);
+                self.write_size(jump.target.0);
+                self.write_byte(encode_kind(&CONDITIONS, &jump.condition.kind));
+                self.write_value(&jump.condition.left);
+                self.write_value(&jump.condition.right);
+            }
+            Instruction::ForNumPrep(prep) => {
+                self.write_byte(OpCode::ForNumPrep as u8);
+                self.write_size(prep.target.0);
+                self.write_size(prep.index);
+                self.write_size(prep.limit);
+                self.write_size(prep.step);
+                self.write_size(prep.var);
+            }
+            Instruction::ForNumLoop(lp) => {
+                self.write_byte(OpCode::ForNumLoop as u8);
+                self.write_size(lp.target.0);
+                self.write_size(lp.index);
+                self.write_size(lp.limit);
+                self.write_size(lp.step);
+                self.write_size(lp.var);
+            }
+            Instruction::ForGenPrep(prep) => {
+                self.write_byte(OpCode::ForGenPrep as u8);
+                self.write_size(prep.target.0);
+                self.write_size(prep.base);
+            }
+            Instruction::ForGenCall(call) => {
+                self.write_byte(OpCode::ForGenCall as u8);
+                self.write_size(call.base);
+                self.write_size(call.dest);
+                self.write_size(call.count);
+            }
+            Instruction::ForGenLoop(lp) => {
+                self.write_byte(OpCode::ForGenLoop as u8);
+                self.write_size(lp.target.0);
+                self.write_size(lp.base);
+                self.write_size(lp.var);
+            }
+            Instruction::NewTable(table) => {
+                self.write_byte(OpCode::NewTable as u8);
+                self.write_size(table.dest);
+                self.write_size(table.array_size);
+                self.write_size(table.table_size);
+            }
+            Instruction::SetList(list) => {
+                self.write_byte(OpCode::SetList as u8);
+                self.write_size(list.table);
+                self.write_size(list.start);
+                self.write_count(&list.count);
+                self.write_size(list.offset);
+            }
+            Instruction::Return(ret) => {
+                self.write_byte(OpCode::Return as u8);
+                self.write_size(ret.result_start);
+                self.write_count(&ret.result_count);
+            }
+            Instruction::Call(call) => {
+                self.write_byte(OpCode::Call as u8);
+                self.write_size(call.callee);
+                self.write_byte(call.self_call as u8);
+                self.write_count(&call.num_args);
+                self.write_count(&call.num_returns);
+            }
+            Instruction::VarArg(vararg) => {
+                self.write_byte(OpCode::VarArg as u8);
+                self.write_size(vararg.dest);
+                self.write_count(&vararg.count);
+            }
+            Instruction::Closure(closure) => {
+                self.write_byte(OpCode::Closure as u8);
+                self.write_size(closure.dest);
+                self.write_size(closure.prototype);
+            }
+            Instruction::GetUpvalue(get) => {
+                self.write_byte(OpCode::GetUpvalue as u8);
+                self.write_size(get.dest);
+                self.write_size(get.upvalue);
+            }
+            Instruction::SetUpvalue(set) => {
+                self.write_byte(OpCode::SetUpvalue as u8);
+                self.write_size(set.src);
+                self.write_size(set.upvalue);
+            }
+            Instruction::Close(close) => {
+                self.write_byte(OpCode::Close as u8);
+                self.write_size(close.start);
+            }
+        }
+    }
+
+    fn write_function(&mut self, function: &Function) {
+        let name = function.name.as_deref().filter(|_| !self.strip_debug);
+        self.write_string(name.map(str::as_bytes));
+
+        let mut arity = 0;
+        if function.arity.is_vararg {
+            arity |= ARITY_VARARG;
+        }
+        if function.arity.needs_arg {
+            arity |= ARITY_NEEDS_ARG;
+        }
+
+        self.write_byte(function.arity.params);
+        self.write_byte(arity);
+        self.write_byte(function.max_stack_size);
+
+        self.write_size(function.upvalues.len());
+        for upvalue in &function.upvalues {
+            self.write_byte(upvalue.in_stack as u8);
+            self.write_size(upvalue.index);
+        }
+
+        self.write_size(function.constants.len());
+        for constant in &function.constants {
+            self.write_constant(constant);
+        }
+
+        self.write_size(function.chunk.len());
+        for instruction in function.chunk.inner() {
+            self.write_instruction(instruction);
+        }
+
+        self.write_size(function.prototypes.len());
+        for prototype in &function.prototypes {
+            self.write_function(prototype);
+        }
+
+        if self.strip_debug {
+            return;
+        }
+
+        let debug = &function.debug;
+
+        self.write_string(debug.source.as_deref().map(str::as_bytes));
+        self.write_varint(debug.line_defined as u64);
+        self.write_varint(debug.last_line_defined as u64);
+
+        // the span of every instruction, 0 when it has none and its line plus one otherwise
+        for (_, span) in function.chunk.spanned() {
+            match span {
+                Some(span) => {
+                    self.write_varint(span.line as u64 + 1);
+                    self.write_varint(span.column as u64);
+                }
+                None => self.write_size(0),
+            }
+        }
+
+        self.write_size(debug.locals.len());
+        for local in &debug.locals {
+            self.write_string(Some(local.name.as_bytes()));
+            self.write_size(local.register);
+            self.write_size(local.start.index());
+            self.write_size(local.end.index());
+        }
+
+        self.write_size(debug.upvalue_names.len());
+        for name in &debug.upvalue_names {
+            self.write_string(Some(name.as_bytes()));
+        }
+    }
+}
@@ -0,0 +1,168 @@
+#![cfg(test)]
+use super::*;
+use crate::formats::{Deserializer, Detect, Format, Serializer, MAX_NESTING};
+use crate::ir::il::Function;
+
+const LISTING: &str = r#"function "main" (0, ...)
+    stack 12
+    source "@main.lua"
+    upvalue r0 "a"
+    upvalue u1 "b"
+    k0 = nil
+    k1 = true
+    k2 = 1.5
+    k3 = -0.0
+    k4 = inf
+    k5 = -7
+    k6 = "say \"hi\"; bye\n"
+    k7 = [nil, k2, r1, 3]
+    k8 = {k6 = true, -1 = k2}
+    k9 = "main"
+    local "x" r0 1..2
+    local "main" r1 2..33
+    L0: @1
+    r0 = nil @1
+    r0 = k1
+    r0 = -3
+    r0 = move r1
+    r0 = global k6
+    global k6 = r0
+    r0 = r1[k6]
+    r0[r1] = false
+    r0 = r1:k6 @2:9
+    r0 = r1 + k2
+    r0 = r1 // r2
+    r0 = not r1
+    r0 = concat r1..r3
+    r0 = band r1, 7
+    r0 = bit32.bnot r1
+    r0 = bit.rshift r1, r2
+    jump L1
+    jumpnot r0 L0
+    jumpif r0 lt k2 L1
+    fornprep r0, r1, r2, r3 L1
+    fornloop r0, r1, r2, r3 L0
+    forgprep r4 L1
+    forgcall r4, r7, 2
+    forgloop r4, r7 L0
+    r0 = newtable 4, 2
+    setlist r0, r1, top, 50
+    call r0, 2, top
+    selfcall r0, top, 1
+    vararg r0, top
+    return r0, top
+    r0 = closure f1
+    r0 = u1
+    u1 = r0
+    close r2
+    L1:
+    return r0, 1 @3
+    function (1, ..., arg)
+        stack 2
+        return r0, 0
+    end
+    function "f" (2)
+        stack 2
+        defined 3, 5
+        upvalue u0 "x"
+        return r0, 0 @4
+    end
+end
+"#;
+
+fn listing() -> Function {
+    LISTING.parse().unwrap()
+}
+
+#[test]
+fn containers_round_trip() {
+    let bytes = LirSerializer::default().serialize(&listing()).unwrap();
+
+    assert_eq!(&bytes[..4], SIGNATURE);
+    assert_eq!(bytes[4], VERSION);
+
+    let function = LirDeserializer.deserialize(&bytes).unwrap();
+    assert_eq!(function.to_string(), LISTING);
+
+    // strings are interned, so a name shared by a constant and a local is stored once
+    let count = bytes
+        .windows(5)
+        .filter(|window| *window == b"\x04main".as_slice())
+        .count();
+    assert_eq!(count, 1);
+}
+
+#[test]
+fn stripping_drops_names_and_debug_sections() {
+    let serializer = LirSerializer::builder().strip_debug(true).build().unwrap();
+    let bytes = serializer.serialize(&listing()).unwrap();
+
+    assert_eq!(bytes[5] & FLAG_DEBUG, 0);
+
+    let function = LirDeserializer.deserialize(&bytes).unwrap();
+
+    assert_eq!(function.name, None);
+    assert!(function.debug.is_empty());
+    assert!(!function.chunk.has_spans());
+    assert!(function.prototypes[1].debug.is_empty());
+    assert_eq!(function.chunk.inner(), listing().chunk.inner());
+    assert_eq!(function.constants.len(), listing().constants.len());
+}
+
+#[test]
+fn containers_are_detected() {
+    let bytes = Format::Lir.serialize(&listing()).unwrap();
+
+    assert_eq!(Format::detect(&bytes), Some(Format::Lir));
+    assert_eq!(Detect.deserialize(&bytes).unwrap().to_string(), LISTING);
+}
+
+#[test]
+fn malformed_containers_are_rejected() {
+    let bytes = LirSerializer::default().serialize(&listing()).unwrap();
+
+    let mut newer = bytes.clone();
+    newer[4] = VERSION + 1;
+    assert!(LirDeserializer.deserialize(&newer).is_err());
+
+    // version 1 stored the result count of returns as a plain size
+    let mut older = SIGNATURE.to_vec();
+    older.extend_from_slice(&[1, 0, 0, 0, 0, 0, 1, 0, 0, 1, OpCode::Return as u8, 0, 1, 0]);
+
+    let function = LirDeserializer.deserialize(&older).unwrap();
+    assert_eq!(function.chunk.to_string(), "return r0, 1\n");
+
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert!(LirDeserializer.deserialize(&trailing).is_err());
+
+    for len in 0..bytes.len() {
+        assert!(LirDeserializer.deserialize(&bytes[..len]).is_err());
+    }
+}
+
+#[test]
+fn deep_nesting_is_rejected() {
+    let leaf = "function (0)\n    stack 1\n    return r0, 0\nend"
+        .parse::<Function>()
+        .unwrap();
+
+    let serialize = |depth| {
+        let nested = (0..depth).fold(leaf.clone(), |inner, _| Function {
+            prototypes: vec![inner],
+            ..leaf.clone()
+        });
+
+        LirSerializer::default().serialize(&nested).unwrap()
+    };
+
+    assert!(LirDeserializer.deserialize(&serialize(MAX_NESTING)).is_ok());
+
+    let error = LirDeserializer
+        .deserialize(&serialize(MAX_NESTING + 1))
+        .unwrap_err();
+    assert!(
+        format!("{error:#}").contains("nested more than"),
+        "{error:#}"
+    );
+}
@@ -0,0 +1,779 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use super::*;
+use crate::formats::{
+    check_nesting,
+    lift::{lift_locals, Lifter, Target},
+    Deserializer,
+};
+use crate::ir::il::{
+    Arity, BinaryOp, BinaryOpKind, Call, Close, Closure, Concat, Condition, ConditionKind,
+    Constant, DebugInfo, ForGenCall, ForGenLoop, ForGenPrep, ForNumLoop, ForNumPrep, Function,
+    GetGlobal, GetTable, GetUpvalue, Instruction, Load, Move, NewTable, OptVariable, Return,
+    SelfLookup, SetGlobal, SetList, SetTable, SetUpvalue, UnaryOp, UnaryOpKind, Upvalue, Value,
+    VarArg,
+};
+use crate::ir::span::Span;
+use anyhow::{bail, ensure, Context, Result};
+
+/// A function prototype exactly as it is stored in a Lua 5.1 binary chunk.
+#[derive(Clone, Debug)]
+struct Prototype {
+    source: Option<String>,
+    line_defined: u32,
+    last_line_defined: u32,
+    upvalue_count: u8,
+    param_count: u8,
+    is_vararg: u8,
+    max_stack_size: u8,
+    code: Vec<u32>,
+    constants: Vec<Constant>,
+    prototypes: Vec<Prototype>,
+    lineinfo: Vec<u32>,
+    /// The local variables, as (name, startpc, endpc) triples.
+    locals: Vec<(String, usize, usize)>,
+    upvalue_names: Vec<String>,
+}
+
+/// Deserializes Lua 5.1 binary chunks, as produced by `luac` or `string.dump`, into
+/// LUNIR intermediate language.
+#[derive(Clone, Debug, Default)]
+pub struct Lua51Deserializer;
+
+impl Lua51Deserializer {
+    /// Reads the header parameters of the binary chunk `bytes`.
+    pub fn header(&self, bytes: &[u8]) -> Result<Lua51Header> {
+        Reader::new(bytes).read_header()
+    }
+}
+
+impl Deserializer for Lua51Deserializer {
+    /// Deserializes the binary chunk `bytes`, returning the lifted prototype of its main
+    /// function.
+    fn deserialize(&self, bytes: &[u8]) -> Result<Function> {
+        let mut reader = Reader::new(bytes);
+
+        reader.header = reader.read_header()?;
+        let main = reader.read_function(None, 0)?;
+
+        ensure!(
+            reader.position == bytes.len(),
+            "{} trailing bytes after the main function",
+            bytes.len() - reader.position
+        );
+
+        ensure!(
+            main.upvalue_count == 0,
+            "the main function cannot have upvalues"
+        );
+
+        lift(&main, Vec::new())
+    }
+}
+
+/// Lifts `prototype` and the prototypes nested in it into LUNIR intermediate language,
+/// `upvalues` describes what closures of `prototype` capture.
+fn lift(prototype: &Prototype, upvalues: Vec<Upvalue>) -> Result<Function> {
+    let flags = prototype.is_vararg;
+
+    ensure!(
+        flags & !(VARARG_HASARG | VARARG_ISVARARG | VARARG_NEEDSARG) == 0
+            && (flags == 0 || flags & VARARG_ISVARARG != 0),
+        "unsupported vararg flags {flags:#x}"
+    );
+
+    // `VARARG_HASARG` only tells the parser that `arg` may be used, so it is not kept
+    let arity = Arity {
+        params: prototype.param_count,
+        is_vararg: flags & VARARG_ISVARARG != 0,
+        needs_arg: flags & VARARG_NEEDSARG != 0,
+    };
+
+    let code = &prototype.code;
+
+    // the upvalues a closure captures are described by the pseudo-instructions following its
+    // CLOSURE, which are not lifted themselves
+    let mut captures = vec![None; prototype.prototypes.len()];
+    let mut pseudo = vec![false; code.len()];
+
+    for (pc, &instruction) in code.iter().enumerate() {
+        if pseudo[pc] {
+            continue;
+        }
+
+        // SETLIST stores batch numbers that do not fit into C in the following word
+        if instruction & 0x3f == OpCode::SetList as u32
+            && (instruction >> POS_C) & MAXARG_C as u32 == 0
+        {
+            ensure!(
+                pc + 1 < code.len(),
+                "SETLIST at {pc} is missing its batch number"
+            );
+
+            pseudo[pc + 1] = true;
+            continue;
+        }
+
+        // the jump back to the body of a generic for loop is part of its TFORLOOP
+        if instruction & 0x3f == OpCode::TForLoop as u32 {
+            ensure!(
+                code.get(pc + 1).map(|next| next & 0x3f) == Some(OpCode::Jmp as u32),
+                "TFORLOOP at {pc} is not followed by a jump"
+            );
+
+            pseudo[pc + 1] = true;
+            continue;
+        }
+
+        if instruction & 0x3f != OpCode::Closure as u32 {
+            continue;
+        }
+
+        let index = ((instruction >> POS_BX) as usize) & MAXARG_BX;
+        let child = prototype
+            .prototypes
+            .get(index)
+            .with_context(|| format!("CLOSURE at {pc} refers to nonexistent prototype {index}"))?;
+
+        let descriptions = pc + 1..pc + 1 + child.upvalue_count as usize;
+
+        ensure!(
+            descriptions.end <= code.len(),
+            "CLOSURE at {pc} is missing the descriptions of its upvalues"
+        );
+
+        let descriptors = code[descriptions.clone()]
+            .iter()
+            .zip(descriptions.clone())
+            .map(|(&description, pc)| {
+                let in_stack = match OpCode::try_from((description & 0x3f) as u8) {
+                    Ok(OpCode::Move) => true,
+                    Ok(OpCode::GetUpval) => false,
+                    _ => bail!("instruction {pc} does not describe an upvalue"),
+                };
+
+                Ok(Upvalue {
+                    in_stack,
+                    index: ((description >> POS_B) as usize) & MAXARG_B,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        pseudo[descriptions].fill(true);
+
+        match &captures[index] {
+            Some(existing) if *existing != descriptors => {
+                bail!("prototype {index} is instantiated with different upvalues")
+            }
+            _ => captures[index] = Some(descriptors),
+        }
+    }
+
+    let prototypes = prototype
+        .prototypes
+        .iter()
+        .zip(captures)
+        .enumerate()
+        .map(|(index, (child, upvalues))| {
+            let upvalues = match upvalues {
+                Some(upvalues) => upvalues,
+                None if child.upvalue_count == 0 => Vec::new(),
+                None => {
+                    bail!("prototype {index} is never instantiated, so its upvalues are unknown")
+                }
+            };
+
+            lift(child, upvalues).with_context(|| format!("failed to lift prototype {index}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut lifter = Lifter::new(code.len());
+
+    for (pc, &instruction) in code.iter().enumerate() {
+        lifter.begin(prototype.lineinfo.get(pc).copied().map(Span::line));
+
+        if pseudo[pc] {
+            continue;
+        }
+
+        lift_instruction(&mut lifter, code, instruction)
+            .with_context(|| format!("failed to lift instruction {pc} ({instruction:#010x})"))?;
+    }
+
+    let (chunk, pcs) = lifter.finish()?;
+
+    Ok(Function {
+        chunk,
+        constants: prototype.constants.clone(),
+        prototypes,
+        upvalues,
+        arity,
+        name: None,
+        max_stack_size: prototype.max_stack_size,
+        debug: DebugInfo {
+            source: prototype.source.clone(),
+            line_defined: prototype.line_defined,
+            last_line_defined: prototype.last_line_defined,
+            locals: lift_locals(&prototype.locals, &pcs),
+            upvalue_names: prototype.upvalue_names.clone(),
+        },
+    })
+}
+
+/// Decodes an `RK` operand.
+fn rk(operand: u32) -> Value {
+    if operand & BITRK != 0 {
+        Value::ConstantIndex((operand & !BITRK) as usize)
+    } else {
+        Value::StackIndex(operand as usize)
+    }
+}
+
+/// Converts a "floating point byte" back into the integer it encodes.
+fn fb_to_int(x: u32) -> usize {
+    let e = (x >> 3) & 0x1f;
+
+    if e == 0 {
+        x as usize
+    } else {
+        (((x & 7) + 8) as usize) << (e - 1)
+    }
+}
+
+fn lift_instruction(lifter: &mut Lifter, code: &[u32], instruction: u32) -> Result<()> {
+    let pc = lifter.pc();
+
+    let opcode = OpCode::try_from((instruction & 0x3f) as u8)
+        .map_err(|op| anyhow::anyhow!("unknown opcode {op}"))?;
+
+    let a = ((instruction >> POS_A) as usize) & MAXARG_A;
+    let b = (instruction >> POS_B) & MAXARG_B as u32;
+    let c = (instruction >> POS_C) & MAXARG_C as u32;
+    let bx = ((instruction >> POS_BX) as usize) & MAXARG_BX;
+    let sbx = bx as isize - MAXARG_SBX;
+
+    let load = |dest, src| Instruction::Load(Load { dest, src });
+    let mv = |dest, src| Instruction::Move(Move { dest, src });
+
+    let binary = |operator| {
+        Instruction::BinaryOp(BinaryOp {
+            operator,
+            dest: a,
+            left: rk(b),
+            right: rk(c),
+        })
+    };
+
+    let unary = |operator| {
+        Instruction::UnaryOp(UnaryOp {
+            operator,
+            dest: a,
+            left: Value::StackIndex(b as usize),
+        })
+    };
+
+    // comparisons and tests skip the jump that follows them when their condition does
+    // not match, so they are lifted as jumps over that jump
+    let skip = Target::Pc(pc + 2);
+    let follow = Target::Pc(pc + 1);
+
+    match opcode {
+        OpCode::Move => lifter.push(mv(a, b as usize)),
+        OpCode::LoadK => lifter.push(load(a, Value::ConstantIndex(bx))),
+        OpCode::LoadBool => {
+            lifter.push(load(a, Value::Boolean(b != 0)));
+
+            if c != 0 {
+                lifter.jump(skip);
+            }
+        }
+        OpCode::LoadNil => {
+            for dest in a..=b as usize {
+                lifter.push(load(dest, Value::Nil));
+            }
+        }
+
+        OpCode::GetGlobal => lifter.push(Instruction::GetGlobal(GetGlobal {
+            dest: a,
+            constant: bx,
+        })),
+        OpCode::SetGlobal => lifter.push(Instruction::SetGlobal(SetGlobal {
+            src: a,
+            constant: bx,
+        })),
+        OpCode::GetTable => lifter.push(Instruction::GetTable(GetTable {
+            dest: a,
+            source: b as usize,
+            key: rk(c),
+        })),
+
+        OpCode::NewTable => lifter.push(Instruction::NewTable(NewTable {
+            dest: a,
+            array_size: fb_to_int(b),
+            table_size: fb_to_int(c),
+        })),
+
+        OpCode::SetTable => lifter.push(Instruction::SetTable(SetTable {
+            table: a,
+            key: rk(b),
+            value: rk(c),
+        })),
+        OpCode::SelfOp => lifter.push(Instruction::SelfLookup(SelfLookup {
+            dest: a,
+            object: b as usize,
+            key: rk(c),
+        })),
+
+        OpCode::SetList => {
+            let batch = match c {
+                0 => code[pc + 1] as usize,
+                c => c as usize,
+            };
+
+            ensure!(batch > 0, "SETLIST stores batch 0");
+
+            lifter.push(Instruction::SetList(SetList {
+                table: a,
+                start: a + 1,
+                count: match b {
+                    0 => OptVariable::Variable,
+                    b => OptVariable::Number(b as usize),
+                },
+                offset: (batch - 1) * FIELDS_PER_FLUSH,
+            }));
+        }
+
+        OpCode::Add => lifter.push(binary(BinaryOpKind::Add)),
+        OpCode::Sub => lifter.push(binary(BinaryOpKind::Sub)),
+        OpCode::Mul => lifter.push(binary(BinaryOpKind::Mul)),
+        OpCode::Div => lifter.push(binary(BinaryOpKind::Div)),
+        OpCode::Mod => lifter.push(binary(BinaryOpKind::Mod)),
+        OpCode::Pow => lifter.push(binary(BinaryOpKind::Pow)),
+
+        OpCode::Unm => lifter.push(unary(UnaryOpKind::Neg)),
+        OpCode::Not => lifter.push(unary(UnaryOpKind::Not)),
+        OpCode::Len => lifter.push(unary(UnaryOpKind::Len)),
+
+        OpCode::Concat => {
+            ensure!(b < c, "CONCAT needs at least two operands");
+
+            lifter.push(Instruction::Concat(Concat {
+                dest: a,
+                start: b as usize,
+                end: c as usize,
+            }));
+        }
+
+        OpCode::Jmp => {
+            let target = (pc as isize + 1 + sbx) as usize;
+
+            // generic for loops begin with a jump to their TFORLOOP, whose jump back leads to
+            // the instruction after this one
+            match code.get(target..target + 2) {
+                Some(&[call, back])
+                    if call & 0x3f == OpCode::TForLoop as u32
+                        && jump_target(target + 1, back) == pc + 1 =>
+                {
+                    let base = ((call >> POS_A) as usize) & MAXARG_A;
+
+                    lifter.branch(Target::Pc(target), move |target| {
+                        Instruction::ForGenPrep(ForGenPrep { target, base })
+                    });
+                }
+                _ => lifter.jump(Target::Pc(target)),
+            }
+        }
+
+        // FORPREP jumps to its FORLOOP, so the loop is skipped by going past that
+        OpCode::ForPrep => lifter.branch(
+            Target::Pc((pc as isize + 2 + sbx) as usize),
+            move |target| {
+                Instruction::ForNumPrep(ForNumPrep {
+                    target,
+                    index: a,
+                    limit: a + 1,
+                    step: a + 2,
+                    var: a + 3,
+                })
+            },
+        ),
+        OpCode::ForLoop => lifter.branch(
+            Target::Pc((pc as isize + 1 + sbx) as usize),
+            move |target| {
+                Instruction::ForNumLoop(ForNumLoop {
+                    target,
+                    index: a,
+                    limit: a + 1,
+                    step: a + 2,
+                    var: a + 3,
+                })
+            },
+        ),
+        OpCode::TForLoop => {
+            ensure!(c != 0, "TFORLOOP at {pc} stores no results");
+
+            lifter.push(Instruction::ForGenCall(ForGenCall {
+                base: a,
+                dest: a + 3,
+                count: c as usize,
+            }));
+
+            // the loop continues through the jump that follows TFORLOOP
+            lifter.branch(
+                Target::Pc(jump_target(pc + 1, code[pc + 1])),
+                move |target| {
+                    Instruction::ForGenLoop(ForGenLoop {
+                        target,
+                        base: a,
+                        var: a + 3,
+                    })
+                },
+            );
+        }
+
+        OpCode::Eq | OpCode::Lt | OpCode::Le => {
+            let kind = match opcode {
+                OpCode::Eq => ConditionKind::Eq,
+                OpCode::Lt => ConditionKind::Lt,
+                _ => ConditionKind::Le,
+            };
+
+            let condition = |kind| Condition {
+                kind,
+                left: rk(b),
+                right: rk(c),
+            };
+
+            match (a != 0, kind) {
+                // equality is the only comparison that can be negated exactly
+                (true, ConditionKind::Eq) => {
+                    lifter.conditional_jump(condition(ConditionKind::Ne), skip)
+                }
+                (true, kind) => {
+                    lifter.conditional_jump(condition(kind), follow);
+                    lifter.jump(skip);
+                }
+                (false, kind) => lifter.conditional_jump(condition(kind), skip),
+            }
+        }
+
+        OpCode::Test => {
+            if c != 0 {
+                lifter.jump_not(a, skip);
+            } else {
+                lifter.jump_not(a, follow);
+                lifter.jump(skip);
+            }
+        }
+        OpCode::TestSet => {
+            if c != 0 {
+                lifter.jump_not(b as usize, skip);
+            } else {
+                lifter.jump_not(b as usize, Target::Local(2));
+                lifter.jump(skip);
+            }
+
+            lifter.push(mv(a, b as usize));
+        }
+
+        // a tail call is lifted as a call whose results are all returned
+        OpCode::Call | OpCode::TailCall => {
+            let self_call = pc > 0 && {
+                let previous = code[pc - 1];
+
+                previous & 0x3f == OpCode::SelfOp as u32
+                    && ((previous >> POS_A) as usize) & MAXARG_A == a
+            };
+
+            let num_args = match b {
+                0 => OptVariable::Variable,
+                b => OptVariable::Number(b as usize - 1 - self_call as usize),
+            };
+
+            let num_returns = match c {
+                _ if opcode == OpCode::TailCall => OptVariable::Variable,
+                0 => OptVariable::Variable,
+                c => OptVariable::Number(c as usize - 1),
+            };
+
+            lifter.push(Instruction::Call(Call {
+                callee: a,
+                self_call,
+                num_args,
+                num_returns,
+            }));
+
+            if opcode == OpCode::TailCall {
+                lifter.push(Instruction::Return(Return {
+                    result_start: a,
+                    result_count: OptVariable::Variable,
+                }));
+            }
+        }
+
+        OpCode::GetUpval => lifter.push(Instruction::GetUpvalue(GetUpvalue {
+            dest: a,
+            upvalue: b as usize,
+        })),
+        OpCode::SetUpval => lifter.push(Instruction::SetUpvalue(SetUpvalue {
+            src: a,
+            upvalue: b as usize,
+        })),
+        OpCode::Closure => lifter.push(Instruction::Closure(Closure {
+            dest: a,
+            prototype: bx,
+        })),
+        OpCode::Close => lifter.push(Instruction::Close(Close { start: a })),
+
+        OpCode::Return => lifter.push(Instruction::Return(Return {
+            result_start: a,
+            result_count: match b {
+                0 => OptVariable::Variable,
+                b => OptVariable::Number(b as usize - 1),
+            },
+        })),
+
+        OpCode::VarArg => lifter.push(Instruction::VarArg(VarArg {
+            dest: a,
+            count: match b {
+                0 => OptVariable::Variable,
+                b => OptVariable::Number(b as usize - 1),
+            },
+        })),
+    }
+
+    Ok(())
+}
+
+/// Returns the program counter the jump `instruction` at `pc` leads to.
+fn jump_target(pc: usize, instruction: u32) -> usize {
+    let sbx = (((instruction >> POS_BX) as usize) & MAXARG_BX) as isize - MAXARG_SBX;
+
+    (pc as isize + 1 + sbx) as usize
+}
+
+/// Reads the primitive types of a binary chunk according to its `Lua51Header`.
+struct Reader<'a> {
+    header: Lua51Header,
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            header: Lua51Header::default(),
+            bytes,
+            position: 0,
+        }
+    }
+
+    fn read_bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.position..)
+            .and_then(|rest| rest.get(..count))
+            .with_context(|| format!("unexpected end of chunk at offset {}", self.position))?;
+
+        self.position += count;
+
+        Ok(bytes)
+    }
+
+    /// Checks that `count` items of at least one byte each fit in the rest of the chunk, so
+    /// a corrupted count cannot request an enormous allocation.
+    fn check_count(&self, count: usize, what: &str) -> Result<usize> {
+        let remaining = self.bytes.len() - self.position;
+        ensure!(
+            count <= remaining,
+            "{what} count {count} exceeds the {remaining} remaining bytes"
+        );
+
+        Ok(count)
+    }
+
+    fn read_byte(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_sized(&mut self, size: u8) -> Result<u64> {
+        let bytes = self.read_bytes(size as usize)?;
+        let mut value = [0; 8];
+
+        if self.header.little_endian {
+            value[..bytes.len()].copy_from_slice(bytes);
+        } else {
+            value[..bytes.len()]
+                .iter_mut()
+                .zip(bytes.iter().rev())
+                .for_each(|(dest, src)| *dest = *src);
+        }
+
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn read_int(&mut self) -> Result<usize> {
+        let value = self.read_sized(self.header.int_size)?;
+
+        usize::try_from(value).context("integer does not fit in usize")
+    }
+
+    fn read_size(&mut self) -> Result<usize> {
+        let value = self.read_sized(self.header.size_t_size)?;
+
+        usize::try_from(value).context("size does not fit in usize")
+    }
+
+    fn read_number(&mut self) -> Result<f64> {
+        let size = self.header.number_size;
+        let bits = self.read_sized(size)?;
+
+        Ok(match (self.header.integral, size) {
+            (false, 8) => f64::from_bits(bits),
+            (false, _) => f32::from_bits(bits as u32) as f64,
+            (true, 8) => bits as i64 as f64,
+            (true, _) => bits as u32 as i32 as f64,
+        })
+    }
+
+    fn read_string(&mut self) -> Result<Option<Vec<u8>>> {
+        let size = self.read_size()?;
+
+        if size == 0 {
+            return Ok(None);
+        }
+
+        let bytes = self.read_bytes(size)?;
+        ensure!(bytes[size - 1] == 0, "string is not null terminated");
+
+        Ok(Some(bytes[..size - 1].to_vec()))
+    }
+
+    /// Reads the source or variable name of the debug information, which is only used for
+    /// display, so any bytes that are not valid UTF-8 are replaced.
+    fn read_name(&mut self) -> Result<Option<String>> {
+        Ok(self
+            .read_string()?
+            .map(|name| String::from_utf8_lossy(&name).into_owned()))
+    }
+
+    fn read_header(&mut self) -> Result<Lua51Header> {
+        ensure!(self.read_bytes(4)? == SIGNATURE, "not a Lua binary chunk");
+
+        let version = self.read_byte()?;
+        ensure!(
+            version == VERSION,
+            "expected a Lua 5.1 binary chunk, got version {version:#x}"
+        );
+
+        let format = self.read_byte()?;
+        ensure!(format == FORMAT, "unsupported chunk format {format}");
+
+        Lua51Header::builder()
+            .little_endian(self.read_byte()? != 0)
+            .int_size(self.read_byte()?)
+            .size_t_size(self.read_byte()?)
+            .instruction_size(self.read_byte()?)
+            .number_size(self.read_byte()?)
+            .integral(self.read_byte()? != 0)
+            .build()
+            .map_err(|error| anyhow::anyhow!("unsupported chunk header: {error}"))
+    }
+
+    /// Reads a function nested `depth` functions deep in the main one.
+    fn read_function(&mut self, parent_source: Option<&str>, depth: usize) -> Result<Prototype> {
+        check_nesting(depth)?;
+
+        // nested functions store no source when it is the same as their parent's
+        let source = self
+            .read_name()?
+            .or_else(|| parent_source.map(str::to_owned));
+
+        let line_defined = self.read_int()? as u32;
+        let last_line_defined = self.read_int()? as u32;
+
+        let upvalue_count = self.read_byte()?;
+        let param_count = self.read_byte()?;
+        let is_vararg = self.read_byte()?;
+        let max_stack_size = self.read_byte()?;
+
+        let code_size = self.read_int()?;
+        let code = (0..code_size)
+            .map(|_| Ok(self.read_sized(4)? as u32))
+            .collect::<Result<Vec<_>>>()?;
+
+        let constant_count = self.read_int()?;
+        let constant_count = self.check_count(constant_count, "constant")?;
+        let mut constants = Vec::with_capacity(constant_count);
+
+        for index in 0..constant_count {
+            constants.push(match self.read_byte()? {
+                0 => Constant::Nil,
+                1 => Constant::Boolean(self.read_byte()? != 0),
+                3 => Constant::Number(self.read_number()?),
+                4 => Constant::String(self.read_string()?.unwrap_or_default()),
+                tag => bail!("constant {index} has unknown type {tag}"),
+            });
+        }
+
+        let prototype_count = self.read_int()?;
+        let prototypes = (0..prototype_count)
+            .map(|_| self.read_function(source.as_deref(), depth + 1))
+            .collect::<Result<Vec<_>>>()?;
+
+        let lineinfo_size = self.read_int()?;
+        let lineinfo = (0..lineinfo_size)
+            .map(|_| Ok(self.read_int()? as u32))
+            .collect::<Result<Vec<_>>>()?;
+
+        let local_count = self.read_int()?;
+        let locals = (0..local_count)
+            .map(|_| {
+                let name = self.read_name()?.unwrap_or_default();
+
+                Ok((name, self.read_int()?, self.read_int()?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let upvalue_name_count = self.read_int()?;
+        let upvalue_names = (0..upvalue_name_count)
+            .map(|_| Ok(self.read_name()?.unwrap_or_default()))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Prototype {
+            source,
+            line_defined,
+            last_line_defined,
+            upvalue_count,
+            param_count,
+            is_vararg,
+            max_stack_size,
+            code,
+            constants,
+            prototypes,
+            lineinfo,
+            locals,
+            upvalue_names,
+        })
+    }
+}
@@ -0,0 +1,228 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod deserializer;
+mod serializer;
+mod tests;
+
+pub use deserializer::*;
+pub use serializer::*;
+
+use derive_builder::Builder;
+
+/// The signature every Lua 5.1 binary chunk starts with.
+pub const SIGNATURE: &[u8; 4] = b"\x1bLua";
+
+/// The version byte of Lua 5.1 binary chunks.
+pub const VERSION: u8 = 0x51;
+
+/// The format byte of the official Lua 5.1 binary chunk format.
+pub const FORMAT: u8 = 0;
+
+/// The maximum number of registers a Lua 5.1 function may use.
+pub const MAX_STACK: usize = 250;
+
+/// The maximum number of upvalues a Lua 5.1 function may capture.
+pub const MAX_UPVALUES: usize = 60;
+
+/// The maximum number of array items a single `SETLIST` stores.
+pub const FIELDS_PER_FLUSH: usize = 50;
+
+/// Set in `is_vararg` when the parser allowed a function to use the `arg` table.
+pub(crate) const VARARG_HASARG: u8 = 1;
+/// Set in `is_vararg` when a function accepts extra arguments.
+pub(crate) const VARARG_ISVARARG: u8 = 2;
+/// Set in `is_vararg` when a function collects its extra arguments into the `arg` table.
+pub(crate) const VARARG_NEEDSARG: u8 = 4;
+
+pub(crate) const SIZE_OP: u32 = 6;
+pub(crate) const SIZE_A: u32 = 8;
+pub(crate) const SIZE_B: u32 = 9;
+pub(crate) const SIZE_C: u32 = 9;
+pub(crate) const SIZE_BX: u32 = SIZE_B + SIZE_C;
+
+pub(crate) const POS_A: u32 = SIZE_OP;
+pub(crate) const POS_C: u32 = POS_A + SIZE_A;
+pub(crate) const POS_B: u32 = POS_C + SIZE_C;
+pub(crate) const POS_BX: u32 = POS_C;
+
+pub(crate) const MAXARG_A: usize = (1 << SIZE_A) - 1;
+pub(crate) const MAXARG_B: usize = (1 << SIZE_B) - 1;
+pub(crate) const MAXARG_C: usize = (1 << SIZE_C) - 1;
+pub(crate) const MAXARG_BX: usize = (1 << SIZE_BX) - 1;
+pub(crate) const MAXARG_SBX: isize = (MAXARG_BX >> 1) as isize;
+
+/// Bit marking an `RK` operand as a constant table index rather than a register.
+pub(crate) const BITRK: u32 = 1 << (SIZE_B - 1);
+
+/// The largest constant table index an `RK` operand can encode.
+pub(crate) const MAXINDEXRK: usize = BITRK as usize - 1;
+
+/// All Lua 5.1 opcodes, in the order of the reference implementation's `lopcodes.h`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OpCode {
+    Move,
+    LoadK,
+    LoadBool,
+    LoadNil,
+    GetUpval,
+    GetGlobal,
+    GetTable,
+    SetGlobal,
+    SetUpval,
+    SetTable,
+    NewTable,
+    SelfOp,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Unm,
+    Not,
+    Len,
+    Concat,
+    Jmp,
+    Eq,
+    Lt,
+    Le,
+    Test,
+    TestSet,
+    Call,
+    TailCall,
+    Return,
+    ForLoop,
+    ForPrep,
+    TForLoop,
+    SetList,
+    Close,
+    Closure,
+    VarArg,
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if value <= OpCode::VarArg as u8 {
+            // SAFETY: `OpCode` is `repr(u8)` with contiguous discriminants up to `VarArg`
+            Ok(unsafe { std::mem::transmute::<u8, OpCode>(value) })
+        } else {
+            Err(value)
+        }
+    }
+}
+
+/// Encodes an instruction in the `iABC` format.
+pub(crate) fn encode_abc(op: OpCode, a: usize, b: u32, c: u32) -> u32 {
+    (op as u32) | ((a as u32) << POS_A) | (b << POS_B) | (c << POS_C)
+}
+
+/// Encodes an instruction in the `iABx` format.
+pub(crate) fn encode_abx(op: OpCode, a: usize, bx: usize) -> u32 {
+    (op as u32) | ((a as u32) << POS_A) | ((bx as u32) << POS_BX)
+}
+
+/// Encodes an instruction in the `iAsBx` format.
+pub(crate) fn encode_asbx(op: OpCode, a: usize, sbx: isize) -> u32 {
+    encode_abx(op, a, (sbx + MAXARG_SBX) as usize)
+}
+
+/// Converts an integer into the "floating point byte" representation used by `NEWTABLE`
+/// to encode table size hints, rounding up where it is inexact.
+pub(crate) fn int_to_fb(mut x: usize) -> u32 {
+    let mut e = 0;
+
+    while x >= 16 {
+        x = (x + 1) >> 1;
+        e += 1;
+    }
+
+    if x < 8 {
+        x as u32
+    } else {
+        ((e + 1) << 3) | (x as u32 - 8)
+    }
+}
+
+/// The parameters of a Lua 5.1 binary chunk header, these must match those of the
+/// virtual machine loading the chunk.
+#[derive(Builder, Clone, Debug, PartialEq, Eq)]
+#[builder(default, build_fn(validate = "Self::validate"))]
+pub struct Lua51Header {
+    /// Whether multi-byte values are stored in little endian byte order.
+    pub little_endian: bool,
+    /// The size of a C `int` in bytes.
+    pub int_size: u8,
+    /// The size of a C `size_t` in bytes.
+    pub size_t_size: u8,
+    /// The size of a VM instruction in bytes, the reference implementation only supports 4.
+    pub instruction_size: u8,
+    /// The size of a `lua_Number` in bytes.
+    pub number_size: u8,
+    /// Whether `lua_Number` is an integral type.
+    pub integral: bool,
+}
+
+impl Default for Lua51Header {
+    fn default() -> Self {
+        Self {
+            little_endian: true,
+            int_size: 4,
+            size_t_size: 8,
+            instruction_size: 4,
+            number_size: 8,
+            integral: false,
+        }
+    }
+}
+
+impl Lua51HeaderBuilder {
+    fn validate(&self) -> Result<(), String> {
+        for (name, size) in [
+            ("int_size", self.int_size),
+            ("size_t_size", self.size_t_size),
+            ("number_size", self.number_size),
+        ] {
+            if let Some(size) = size {
+                if size != 4 && size != 8 {
+                    return Err(format!("{name} must be either 4 or 8, got {size}"));
+                }
+            }
+        }
+
+        match self.instruction_size {
+            Some(4) | None => Ok(()),
+            Some(size) => Err(format!("instruction_size must be 4, got {size}")),
+        }
+    }
+}
+
+impl Lua51Header {
+    /// Creates a builder for a `Lua51Header`, any parameter left unset takes the value
+    /// used by a stock 64-bit build of Lua 5.1.
+    pub fn builder() -> Lua51HeaderBuilder {
+        Lua51HeaderBuilder::default()
+    }
+}
@@ -0,0 +1,1151 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use super::*;
+use crate::formats::{integer_to_number, numeric_for_window, pc_at, Serializer};
+use crate::ir::il::{
+    registers_used, resolve_labels, BinaryOpKind, ConditionKind, Constant, Function, Instruction,
+    Label, OptVariable, SetList, Table, UnaryOpKind, Value,
+};
+use anyhow::{bail, ensure, Context, Result};
+use derive_builder::Builder;
+use std::collections::HashMap;
+
+/// A constant as it is stored in a Lua 5.1 constant table, numbers are kept as their
+/// bit pattern so that constants can be deduplicated.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Lua51Constant {
+    Nil,
+    Boolean(bool),
+    Number(u64),
+    String(Vec<u8>),
+}
+
+/// Serializes LUNIR intermediate language into a Lua 5.1 binary chunk that can be loaded
+/// by `lua_load` or listed with `luac -l`.
+#[derive(Builder, Clone, Debug)]
+#[builder(default)]
+pub struct Lua51Serializer {
+    /// The header parameters of the emitted chunk.
+    header: Lua51Header,
+    /// The chunk name stored as the `source` of the main function, in place of the one in
+    /// its debug information.
+    #[builder(setter(into, strip_option))]
+    source: Option<String>,
+    /// The global holding the bitwise operation library that intrinsics are lowered to.
+    #[builder(setter(into))]
+    bit_library: String,
+    /// Whether line information and other debug information should be omitted from the
+    /// emitted chunk.
+    strip_debug: bool,
+}
+
+impl Default for Lua51Serializer {
+    fn default() -> Self {
+        Self {
+            header: Lua51Header::default(),
+            source: None,
+            bit_library: "bit".to_owned(),
+            strip_debug: false,
+        }
+    }
+}
+
+impl Lua51Serializer {
+    /// Creates a builder for a `Lua51Serializer`.
+    pub fn builder() -> Lua51SerializerBuilder {
+        Lua51SerializerBuilder::default()
+    }
+}
+
+impl Serializer for Lua51Serializer {
+    /// Serializes `function` as the main function of a binary chunk.
+    fn serialize(&self, function: &Function) -> Result<Vec<u8>> {
+        let mut writer = Writer {
+            header: &self.header,
+            buf: Vec::with_capacity(256),
+        };
+
+        writer.write_header();
+
+        let state = FunctionState::lower(self, function)?;
+        let source = match &self.source {
+            Some(source) => Some(source.as_str()),
+            None if self.strip_debug => None,
+            None => function.debug.source.as_deref(),
+        };
+
+        writer.write_function(&state, source)?;
+
+        Ok(writer.buf)
+    }
+}
+
+/// The state of a single function while it is being lowered to Lua 5.1 opcodes.
+struct FunctionState<'a> {
+    serializer: &'a Lua51Serializer,
+    prototype: &'a Function,
+
+    code: Vec<u32>,
+    lineinfo: Vec<u32>,
+    line: u32,
+
+    constants: Vec<Lua51Constant>,
+    constant_lookup: HashMap<Lua51Constant, usize>,
+    /// Maps the index of every constant in `prototype` to its index in `constants`, tables
+    /// have no mapping as they are built at the point of use instead.
+    constant_map: Vec<Option<usize>>,
+
+    /// The first register that is free to be used as a temporary.
+    base: usize,
+    /// The first register that is not currently holding a temporary.
+    top: usize,
+    max_stack_size: usize,
+
+    /// The program counter at which the lowering of each IL instruction begins.
+    pcs: Vec<usize>,
+    /// Jumps which need their offset patched, as (program counter, IL target) pairs.
+    fixups: Vec<(usize, usize)>,
+    /// The IL index of every label defined in the function.
+    labels: HashMap<Label, usize>,
+
+    /// The lowered prototypes of the functions nested in this one.
+    children: Vec<FunctionState<'a>>,
+}
+
+impl<'a> FunctionState<'a> {
+    fn lower(serializer: &'a Lua51Serializer, prototype: &'a Function) -> Result<Self> {
+        let instructions = prototype.chunk.inner();
+
+        ensure!(
+            prototype.upvalues.len() <= MAX_UPVALUES,
+            "function has {} upvalues but Lua 5.1 only allows {MAX_UPVALUES}",
+            prototype.upvalues.len()
+        );
+
+        let children = prototype
+            .prototypes
+            .iter()
+            .enumerate()
+            .map(|(index, child)| {
+                Self::lower(serializer, child)
+                    .with_context(|| format!("failed to lower prototype {index}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let base = instructions
+            .iter()
+            .map(registers_used)
+            .max()
+            .unwrap_or(0)
+            .max(prototype.max_stack_size as usize)
+            .max(prototype.arity.params as usize + 1);
+
+        let mut state = Self {
+            serializer,
+            prototype,
+            code: Vec::with_capacity(instructions.len()),
+            lineinfo: Vec::with_capacity(instructions.len()),
+            line: 0,
+            constants: Vec::with_capacity(prototype.constants.len()),
+            constant_lookup: HashMap::new(),
+            constant_map: Vec::with_capacity(prototype.constants.len()),
+            base,
+            top: base,
+            max_stack_size: base.max(2),
+            pcs: Vec::with_capacity(instructions.len() + 1),
+            fixups: Vec::new(),
+            labels: resolve_labels(instructions)?,
+            children,
+        };
+
+        for constant in &prototype.constants {
+            let mapped = match constant {
+                Constant::Nil => Some(state.constant(Lua51Constant::Nil)),
+                Constant::Boolean(b) => Some(state.constant(Lua51Constant::Boolean(*b))),
+                Constant::Number(n) => Some(state.constant(Lua51Constant::Number(n.to_bits()))),
+                Constant::Integer(n) => {
+                    let n = integer_to_number(*n)?;
+                    Some(state.constant(Lua51Constant::Number(n.to_bits())))
+                }
+                Constant::String(s) => Some(state.constant(Lua51Constant::String(s.clone()))),
+                Constant::Table(_) => None,
+            };
+
+            state.constant_map.push(mapped);
+        }
+
+        // instructions without a span keep the line of the instruction before them
+        for (index, (instruction, span)) in prototype.chunk.spanned().enumerate() {
+            state.pcs.push(state.code.len());
+
+            if let Some(span) = span {
+                state.line = span.line;
+            }
+
+            state
+                .lower_instruction(instruction)
+                .with_context(|| format!("failed to lower instruction {index}: {instruction:?}"))?;
+
+            state.top = state.base;
+        }
+
+        state.pcs.push(state.code.len());
+
+        // the reference implementation requires every function to end with a return, a
+        // trailing label means something jumps past the last one
+        if !matches!(instructions.last(), Some(Instruction::Return(_))) {
+            state.emit(encode_abc(OpCode::Return, 0, 1, 0));
+        }
+
+        state.patch_jumps()?;
+        state.check_open_calls()?;
+
+        ensure!(
+            state.max_stack_size <= MAX_STACK,
+            "function needs {} registers but Lua 5.1 only allows {MAX_STACK}",
+            state.max_stack_size
+        );
+
+        Ok(state)
+    }
+
+    fn emit(&mut self, instruction: u32) -> usize {
+        self.code.push(instruction);
+        self.lineinfo.push(self.line);
+
+        self.code.len() - 1
+    }
+
+    fn constant(&mut self, constant: Lua51Constant) -> usize {
+        if let Some(&index) = self.constant_lookup.get(&constant) {
+            return index;
+        }
+
+        let index = self.constants.len();
+        self.constants.push(constant.clone());
+        self.constant_lookup.insert(constant, index);
+
+        index
+    }
+
+    fn string_constant(&mut self, index: usize) -> Result<usize> {
+        match self.prototype.constants.get(index) {
+            Some(Constant::String(_)) => Ok(self.constant_map[index].unwrap()),
+            Some(constant) => bail!("constant {index} ({constant:?}) is not a string"),
+            None => bail!("constant {index} does not exist"),
+        }
+    }
+
+    fn temporary(&mut self) -> Result<usize> {
+        let register = self.top;
+
+        self.top += 1;
+        self.max_stack_size = self.max_stack_size.max(self.top);
+
+        ensure!(
+            self.top <= MAX_STACK,
+            "ran out of registers for temporaries"
+        );
+
+        Ok(register)
+    }
+
+    /// Loads the constant at `index` in the output constant table into `register`.
+    fn load_k(&mut self, register: usize, index: usize) -> Result<()> {
+        ensure!(
+            index <= MAXARG_BX,
+            "constant {index} is out of range of LOADK, Lua 5.1 has no LOADKX"
+        );
+
+        self.emit(encode_abx(OpCode::LoadK, register, index));
+
+        Ok(())
+    }
+
+    /// Emits the instructions needed to place `value` into `register`.
+    fn load_into(&mut self, register: usize, value: &Value) -> Result<()> {
+        match value {
+            Value::Nil => {
+                self.emit(encode_abc(OpCode::LoadNil, register, register as u32, 0));
+            }
+            Value::Boolean(b) => {
+                self.emit(encode_abc(OpCode::LoadBool, register, *b as u32, 0));
+            }
+            Value::Immediate(i) => {
+                let index = self.constant(Lua51Constant::Number((*i as f64).to_bits()));
+                self.load_k(register, index)?;
+            }
+            Value::StackIndex(source) => {
+                if *source != register {
+                    self.emit(encode_abc(OpCode::Move, register, *source as u32, 0));
+                }
+            }
+            Value::ConstantIndex(index) => {
+                let prototype = self.prototype;
+
+                match prototype.constants.get(*index) {
+                    Some(Constant::Table(table)) => self.load_table(register, table)?,
+                    Some(_) => self.load_k(register, self.constant_map[*index].unwrap())?,
+                    None => bail!("constant {index} does not exist"),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds a table constant in `register`, storing array items in batches of
+    /// `FIELDS_PER_FLUSH` with `SETLIST`.
+    fn load_table(&mut self, register: usize, table: &Table) -> Result<()> {
+        let mark = self.top;
+        let table_register = self.temporary()?;
+
+        match table {
+            Table::Array(items) => {
+                self.emit(encode_abc(
+                    OpCode::NewTable,
+                    table_register,
+                    int_to_fb(items.len()),
+                    0,
+                ));
+
+                for (batch, items) in items.chunks(FIELDS_PER_FLUSH).enumerate() {
+                    for item in items {
+                        let item_register = self.temporary()?;
+                        self.load_into(item_register, item)?;
+                        self.top = item_register + 1;
+                    }
+
+                    let batch = batch + 1;
+
+                    if batch <= MAXARG_C {
+                        self.emit(encode_abc(
+                            OpCode::SetList,
+                            table_register,
+                            items.len() as u32,
+                            batch as u32,
+                        ));
+                    } else {
+                        // the batch number does not fit into C and is stored in the next word
+                        self.emit(encode_abc(
+                            OpCode::SetList,
+                            table_register,
+                            items.len() as u32,
+                            0,
+                        ));
+                        self.emit(batch as u32);
+                    }
+
+                    self.top = table_register + 1;
+                }
+            }
+            Table::Map(map) => {
+                self.emit(encode_abc(
+                    OpCode::NewTable,
+                    table_register,
+                    0,
+                    int_to_fb(map.len()),
+                ));
+
+                for (key, value) in map {
+                    ensure!(*key != Value::Nil, "table constant has a nil key");
+
+                    let key = self.rk(key)?;
+                    let value = self.rk(value)?;
+
+                    self.emit(encode_abc(OpCode::SetTable, table_register, key, value));
+
+                    self.top = table_register + 1;
+                }
+            }
+        }
+
+        if register != table_register {
+            self.emit(encode_abc(OpCode::Move, register, table_register as u32, 0));
+        }
+
+        self.top = mark;
+
+        Ok(())
+    }
+
+    /// Returns a register holding `value`, loading it into a temporary if needed.
+    fn register(&mut self, value: &Value) -> Result<usize> {
+        match value {
+            Value::StackIndex(index) => Ok(*index),
+            _ => {
+                let register = self.temporary()?;
+                self.load_into(register, value)?;
+
+                Ok(register)
+            }
+        }
+    }
+
+    /// Encodes `value` as an `RK` operand, constants that do not fit into the operand are
+    /// loaded into a temporary register.
+    fn rk(&mut self, value: &Value) -> Result<u32> {
+        let constant = match value {
+            Value::StackIndex(index) => return Ok(*index as u32),
+            Value::Nil => self.constant(Lua51Constant::Nil),
+            Value::Boolean(b) => self.constant(Lua51Constant::Boolean(*b)),
+            Value::Immediate(i) => self.constant(Lua51Constant::Number((*i as f64).to_bits())),
+            Value::ConstantIndex(index) => match self.constant_map.get(*index) {
+                Some(Some(constant)) => *constant,
+                _ => return self.register(value).map(|register| register as u32),
+            },
+        };
+
+        self.rk_constant(constant)
+    }
+
+    /// Loads the function `name` of the global table `library` into `register`, leaving
+    /// the registers above it free.
+    fn load_library_function(&mut self, register: usize, library: &str, name: &str) -> Result<()> {
+        let library = self.constant(Lua51Constant::String(library.as_bytes().to_vec()));
+        let name = self.constant(Lua51Constant::String(name.as_bytes().to_vec()));
+
+        self.emit(encode_abx(OpCode::GetGlobal, register, library));
+
+        let name = self.rk_constant(name)?;
+        self.emit(encode_abc(
+            OpCode::GetTable,
+            register,
+            register as u32,
+            name,
+        ));
+
+        self.top = register + 1;
+
+        Ok(())
+    }
+
+    /// Encodes the constant at `index` in the output constant table as an `RK` operand.
+    fn rk_constant(&mut self, index: usize) -> Result<u32> {
+        if index <= MAXINDEXRK {
+            Ok(index as u32 | BITRK)
+        } else {
+            let register = self.temporary()?;
+            self.load_k(register, index)?;
+
+            Ok(register as u32)
+        }
+    }
+
+    fn jump(&mut self, target: usize) {
+        self.branch(OpCode::Jmp, 0, target);
+    }
+
+    /// Emits an instruction with an `sBx` offset to the IL instruction `target`.
+    fn branch(&mut self, op: OpCode, a: usize, target: usize) {
+        let pc = self.emit(encode_asbx(op, a, 0));
+        self.fixups.push((pc, target));
+    }
+
+    /// Returns the IL index of the instruction marking `label`.
+    fn target(&self, label: Label) -> usize {
+        self.labels[&label]
+    }
+
+    /// Returns the IL instruction at `offset` from the one currently being lowered.
+    fn neighbour(&self, offset: isize) -> Option<&'a Instruction> {
+        let index = usize::try_from(self.pcs.len() as isize - 1 + offset).ok()?;
+
+        self.prototype.chunk.inner().get(index)
+    }
+
+    fn lower_instruction(&mut self, instruction: &Instruction) -> Result<()> {
+        match instruction {
+            Instruction::Label(_) => {}
+            Instruction::Load(load) => self.load_into(load.dest, &load.src)?,
+            Instruction::Move(mv) => {
+                if mv.dest != mv.src {
+                    self.emit(encode_abc(OpCode::Move, mv.dest, mv.src as u32, 0));
+                }
+            }
+
+            Instruction::GetGlobal(get) => {
+                let constant = self.string_constant(get.constant)?;
+                self.emit(encode_abx(OpCode::GetGlobal, get.dest, constant));
+            }
+            Instruction::SetGlobal(set) => {
+                let constant = self.string_constant(set.constant)?;
+                self.emit(encode_abx(OpCode::SetGlobal, set.src, constant));
+            }
+
+            Instruction::GetTable(get) => {
+                let key = self.rk(&get.key)?;
+                self.emit(encode_abc(
+                    OpCode::GetTable,
+                    get.dest,
+                    get.source as u32,
+                    key,
+                ));
+            }
+
+            Instruction::SetTable(set) => {
+                let key = self.rk(&set.key)?;
+                let value = self.rk(&set.value)?;
+
+                self.emit(encode_abc(OpCode::SetTable, set.table, key, value));
+            }
+            Instruction::SelfLookup(lookup) => {
+                let key = self.rk(&lookup.key)?;
+
+                self.emit(encode_abc(
+                    OpCode::SelfOp,
+                    lookup.dest,
+                    lookup.object as u32,
+                    key,
+                ));
+            }
+
+            Instruction::Concat(concat) => {
+                ensure!(
+                    concat.start < concat.end,
+                    "CONCAT needs at least two operands"
+                );
+
+                self.emit(encode_abc(
+                    OpCode::Concat,
+                    concat.dest,
+                    concat.start as u32,
+                    concat.end as u32,
+                ));
+            }
+
+            Instruction::BinaryOp(op) => {
+                let opcode = match op.operator {
+                    BinaryOpKind::Add => OpCode::Add,
+                    BinaryOpKind::Sub => OpCode::Sub,
+                    BinaryOpKind::Mul => OpCode::Mul,
+                    BinaryOpKind::Div => OpCode::Div,
+                    BinaryOpKind::Mod => OpCode::Mod,
+                    BinaryOpKind::Pow => OpCode::Pow,
+                    BinaryOpKind::IDiv => {
+                        // Lua 5.1 has no floor division, so it is lowered to
+                        // `math.floor(a / b)`
+                        let function = self.temporary()?;
+                        self.load_library_function(function, "math", "floor")?;
+
+                        let quotient = self.temporary()?;
+                        let left = self.rk(&op.left)?;
+                        let right = self.rk(&op.right)?;
+
+                        self.emit(encode_abc(OpCode::Div, quotient, left, right));
+                        self.emit(encode_abc(OpCode::Call, function, 2, 2));
+                        self.emit(encode_abc(OpCode::Move, op.dest, function as u32, 0));
+
+                        return Ok(());
+                    }
+                    BinaryOpKind::Concat => {
+                        // CONCAT works on a range of registers, so both operands are
+                        // moved next to each other first
+                        let left = self.temporary()?;
+                        let right = self.temporary()?;
+
+                        self.load_into(left, &op.left)?;
+                        self.load_into(right, &op.right)?;
+
+                        self.emit(encode_abc(
+                            OpCode::Concat,
+                            op.dest,
+                            left as u32,
+                            right as u32,
+                        ));
+
+                        return Ok(());
+                    }
+                };
+
+                let left = self.rk(&op.left)?;
+                let right = self.rk(&op.right)?;
+
+                self.emit(encode_abc(opcode, op.dest, left, right));
+            }
+
+            Instruction::UnaryOp(op) => {
+                let opcode = match op.operator {
+                    UnaryOpKind::Len => OpCode::Len,
+                    UnaryOpKind::Not => OpCode::Not,
+                    UnaryOpKind::Neg => OpCode::Unm,
+                };
+
+                let operand = self.register(&op.left)?;
+                self.emit(encode_abc(opcode, op.dest, operand as u32, 0));
+            }
+
+            Instruction::Intrinsic(intrinsic) => {
+                // Lua 5.1 has no bitwise operators, so they are lowered to calls into a
+                // library such as LuaBitOp, e.g. `bit.band(a, b)`, unless the intrinsic
+                // already names the library it was called from
+                let operands = intrinsic.kind.operands();
+                let library = match intrinsic.source.library() {
+                    Some(library) => library.to_owned(),
+                    None => self.serializer.bit_library.clone(),
+                };
+
+                let function = self.temporary()?;
+                self.load_library_function(function, &library, intrinsic.kind.library_name())?;
+
+                for operand in &operands {
+                    let register = self.temporary()?;
+                    self.load_into(register, operand)?;
+                    self.top = register + 1;
+                }
+
+                self.emit(encode_abc(
+                    OpCode::Call,
+                    function,
+                    operands.len() as u32 + 1,
+                    2,
+                ));
+
+                self.emit(encode_abc(OpCode::Move, intrinsic.dest, function as u32, 0));
+            }
+
+            Instruction::Closure(closure) => {
+                let prototype = self
+                    .prototype
+                    .prototypes
+                    .get(closure.prototype)
+                    .with_context(|| format!("prototype {} does not exist", closure.prototype))?;
+
+                ensure!(
+                    closure.prototype <= MAXARG_BX,
+                    "prototype {} is out of range of CLOSURE",
+                    closure.prototype
+                );
+
+                self.emit(encode_abx(OpCode::Closure, closure.dest, closure.prototype));
+
+                // every upvalue of the new closure is described by a pseudo-instruction
+                // following CLOSURE, which the virtual machine never executes
+                for upvalue in &prototype.upvalues {
+                    let opcode = if upvalue.in_stack {
+                        OpCode::Move
+                    } else {
+                        OpCode::GetUpval
+                    };
+
+                    self.emit(encode_abc(opcode, 0, upvalue.index as u32, 0));
+                }
+            }
+            Instruction::GetUpvalue(get) => {
+                self.emit(encode_abc(
+                    OpCode::GetUpval,
+                    get.dest,
+                    get.upvalue as u32,
+                    0,
+                ));
+            }
+            Instruction::SetUpvalue(set) => {
+                self.emit(encode_abc(OpCode::SetUpval, set.src, set.upvalue as u32, 0));
+            }
+            Instruction::Close(close) => {
+                self.emit(encode_abc(OpCode::Close, close.start, 0, 0));
+            }
+
+            Instruction::Jump(jump) => self.jump(self.target(jump.target)),
+
+            Instruction::JumpNot(jump) => {
+                self.emit(encode_abc(OpCode::Test, jump.cond, 0, 0));
+                self.jump(self.target(jump.target));
+            }
+
+            Instruction::ConditionalJump(jump) => {
+                let condition = &jump.condition;
+                let target = self.target(jump.target);
+
+                match condition.kind {
+                    ConditionKind::And => {
+                        // if not left then skip; if right then jump
+                        let left = self.register(&condition.left)?;
+                        let right = self.register(&condition.right)?;
+
+                        self.emit(encode_abc(OpCode::Test, left, 0, 0));
+                        self.emit(encode_asbx(OpCode::Jmp, 0, 2));
+                        self.emit(encode_abc(OpCode::Test, right, 0, 1));
+                        self.jump(target);
+                    }
+                    ConditionKind::Or => {
+                        let left = self.register(&condition.left)?;
+                        let right = self.register(&condition.right)?;
+
+                        self.emit(encode_abc(OpCode::Test, left, 0, 1));
+                        self.jump(target);
+                        self.emit(encode_abc(OpCode::Test, right, 0, 1));
+                        self.jump(target);
+                    }
+                    _ => {
+                        // comparisons skip the following jump when their result does not
+                        // match A, so A = 1 makes the jump happen when the condition holds
+                        let (opcode, expected, swap) = match condition.kind {
+                            ConditionKind::Eq => (OpCode::Eq, 1, false),
+                            ConditionKind::Ne => (OpCode::Eq, 0, false),
+                            ConditionKind::Lt => (OpCode::Lt, 1, false),
+                            ConditionKind::Le => (OpCode::Le, 1, false),
+                            ConditionKind::Gt => (OpCode::Lt, 1, true),
+                            ConditionKind::Ge => (OpCode::Le, 1, true),
+                            ConditionKind::And | ConditionKind::Or => unreachable!(),
+                        };
+
+                        let mut left = self.rk(&condition.left)?;
+                        let mut right = self.rk(&condition.right)?;
+
+                        if swap {
+                            std::mem::swap(&mut left, &mut right);
+                        }
+
+                        self.emit(encode_abc(opcode, expected, left, right));
+                        self.jump(target);
+                    }
+                }
+            }
+
+            // FORPREP jumps to its FORLOOP, which decides whether the loop runs at all
+            Instruction::ForNumPrep(prep) => {
+                numeric_for_window(prep.index, prep.limit, prep.step, prep.var)?;
+
+                let instructions = self.prototype.chunk.inner();
+                let current = self.pcs.len() - 1;
+                let end = instructions[..self.target(prep.target)]
+                    .iter()
+                    .rposition(|instruction| !matches!(instruction, Instruction::Label(_)));
+
+                let body = match end.map(|end| &instructions[end]) {
+                    Some(Instruction::ForNumLoop(next)) if next.index == prep.index => {
+                        self.target(next.target)
+                    }
+                    _ => bail!("FORPREP must skip exactly to after the FORLOOP of its loop"),
+                };
+
+                ensure!(
+                    instructions[current + 1..body]
+                        .iter()
+                        .all(|instruction| matches!(instruction, Instruction::Label(_))),
+                    "FORLOOP must jump back to right after the FORPREP of its loop"
+                );
+
+                self.branch(OpCode::ForPrep, prep.index, end.unwrap());
+            }
+            Instruction::ForNumLoop(next) => {
+                numeric_for_window(next.index, next.limit, next.step, next.var)?;
+
+                self.branch(OpCode::ForLoop, next.index, self.target(next.target));
+            }
+            // the loop starts with a jump to TFORLOOP, which is followed by the jump back
+            Instruction::ForGenPrep(prep) => {
+                let start = self.target(prep.target);
+                let call = self.prototype.chunk.inner()[start..]
+                    .iter()
+                    .find(|instruction| !matches!(instruction, Instruction::Label(_)));
+
+                match call {
+                    Some(Instruction::ForGenCall(call)) if call.base == prep.base => {}
+                    _ => bail!("generic for loops must begin by calling their iterator"),
+                }
+
+                self.jump(self.target(prep.target));
+            }
+            Instruction::ForGenCall(call) => {
+                ensure!(
+                    call.dest == call.base + 3,
+                    "TFORLOOP stores its results directly above the control value"
+                );
+                ensure!(
+                    (1..=MAXARG_C).contains(&call.count),
+                    "TFORLOOP needs between 1 and {MAXARG_C} results"
+                );
+
+                match self.neighbour(1) {
+                    Some(Instruction::ForGenLoop(next))
+                        if next.base == call.base && next.var == call.dest => {}
+                    _ => bail!("TFORLOOP must be followed by the check of its loop"),
+                }
+
+                self.emit(encode_abc(
+                    OpCode::TForLoop,
+                    call.base,
+                    0,
+                    call.count as u32,
+                ));
+            }
+            Instruction::ForGenLoop(next) => {
+                match self.neighbour(-1) {
+                    Some(Instruction::ForGenCall(call)) if call.base == next.base => {}
+                    _ => bail!("generic for loops must call their iterator before the check"),
+                }
+
+                self.jump(self.target(next.target));
+            }
+
+            Instruction::NewTable(table) => {
+                self.emit(encode_abc(
+                    OpCode::NewTable,
+                    table.dest,
+                    int_to_fb(table.array_size),
+                    int_to_fb(table.table_size),
+                ));
+            }
+
+            Instruction::SetList(list) => self.set_list(list)?,
+
+            Instruction::Return(ret) => {
+                let results = match ret.result_count {
+                    OptVariable::Variable => 0,
+                    OptVariable::Number(n) => n + 1,
+                };
+
+                ensure!(results <= MAXARG_B, "too many results in return");
+
+                self.emit(encode_abc(
+                    OpCode::Return,
+                    ret.result_start,
+                    results as u32,
+                    0,
+                ));
+            }
+
+            Instruction::Call(call) => {
+                let arguments = match call.num_args {
+                    OptVariable::Variable => 0,
+                    OptVariable::Number(n) => n + 1 + call.self_call as usize,
+                };
+
+                let results = match call.num_returns {
+                    OptVariable::Variable => 0,
+                    OptVariable::Number(n) => n + 1,
+                };
+
+                ensure!(
+                    arguments <= MAXARG_B && results <= MAXARG_C,
+                    "too many arguments or results in call"
+                );
+
+                self.emit(encode_abc(
+                    OpCode::Call,
+                    call.callee,
+                    arguments as u32,
+                    results as u32,
+                ));
+            }
+            Instruction::VarArg(vararg) => {
+                ensure!(
+                    self.prototype.arity.is_vararg,
+                    "VARARG is only allowed in variadic functions"
+                );
+
+                let count = match vararg.count {
+                    OptVariable::Variable => 0,
+                    OptVariable::Number(n) => n + 1,
+                };
+
+                ensure!(count <= MAXARG_B, "too many values in VARARG");
+
+                self.emit(encode_abc(OpCode::VarArg, vararg.dest, count as u32, 0));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lowers a `SetList`, SETLIST only reads values from the registers directly above the
+    /// table and only starts at the beginning of a batch.
+    fn set_list(&mut self, list: &SetList) -> Result<()> {
+        ensure!(
+            list.offset % FIELDS_PER_FLUSH == 0,
+            "SETLIST can only store from a multiple of {FIELDS_PER_FLUSH}, not {}",
+            list.offset
+        );
+
+        let (table, count) = match list.count {
+            _ if list.start == list.table + 1 => (list.table, &list.count),
+            OptVariable::Number(count) => {
+                // the values are copied into temporaries above the table
+                let table = self.temporary()?;
+                self.emit(encode_abc(OpCode::Move, table, list.table as u32, 0));
+
+                for index in 0..count {
+                    let register = self.temporary()?;
+                    self.emit(encode_abc(
+                        OpCode::Move,
+                        register,
+                        (list.start + index) as u32,
+                        0,
+                    ));
+                }
+
+                (table, &list.count)
+            }
+            OptVariable::Variable => bail!("SETLIST cannot store a variable number of values that are not directly above the table"),
+        };
+
+        let count = match count {
+            OptVariable::Variable => 0,
+            OptVariable::Number(count) => *count,
+        };
+
+        ensure!(count <= MAXARG_B, "too many values in SETLIST");
+
+        let batch = list.offset / FIELDS_PER_FLUSH + 1;
+
+        if batch <= MAXARG_C {
+            self.emit(encode_abc(
+                OpCode::SetList,
+                table,
+                count as u32,
+                batch as u32,
+            ));
+        } else {
+            self.emit(encode_abc(OpCode::SetList, table, count as u32, 0));
+            self.emit(batch as u32);
+        }
+
+        Ok(())
+    }
+
+    fn patch_jumps(&mut self) -> Result<()> {
+        for &(pc, target) in &self.fixups {
+            let target_pc = *self.pcs.get(target).with_context(|| {
+                format!("jump at {pc} targets nonexistent instruction {target}")
+            })?;
+
+            let offset = target_pc as isize - (pc as isize + 1);
+
+            ensure!(
+                (-MAXARG_SBX..=MAXARG_SBX).contains(&offset),
+                "jump at {pc} is too long"
+            );
+
+            let word = self.code[pc];
+            let op = OpCode::try_from((word & 0x3f) as u8).unwrap();
+
+            self.code[pc] = encode_asbx(op, (word >> POS_A) as usize & MAXARG_A, offset);
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every call or `VARARG` with a variable number of results is followed by an
+    /// instruction consuming all of them, as the Lua 5.1 bytecode verifier demands.
+    fn check_open_calls(&self) -> Result<()> {
+        for (pc, pair) in self.code.windows(2).enumerate() {
+            let (call, next) = (pair[0], pair[1]);
+
+            let is_open = match call & 0x3f {
+                op if op == OpCode::Call as u32 => (call >> POS_C) & MAXARG_C as u32 == 0,
+                op if op == OpCode::VarArg as u32 => (call >> POS_B) & MAXARG_B as u32 == 0,
+                _ => false,
+            };
+
+            if !is_open {
+                continue;
+            }
+
+            let opcode = next & 0x3f;
+            let b = (next >> POS_B) & MAXARG_B as u32;
+
+            ensure!(
+                b == 0
+                    && [
+                        OpCode::Call,
+                        OpCode::TailCall,
+                        OpCode::Return,
+                        OpCode::SetList
+                    ]
+                    .iter()
+                    .any(|&op| op as u32 == opcode),
+                "results of the call at {pc} are never consumed"
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes the primitive types of a binary chunk according to a `Lua51Header`.
+struct Writer<'a> {
+    header: &'a Lua51Header,
+    buf: Vec<u8>,
+}
+
+impl Writer<'_> {
+    fn write_sized(&mut self, value: u64, size: u8) {
+        let bytes = value.to_le_bytes();
+        let bytes = &bytes[..size as usize];
+
+        if self.header.little_endian {
+            self.buf.extend_from_slice(bytes);
+        } else {
+            self.buf.extend(bytes.iter().rev());
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.buf.push(byte);
+    }
+
+    fn write_int(&mut self, value: usize) {
+        self.write_sized(value as u64, self.header.int_size);
+    }
+
+    fn write_size(&mut self, value: usize) {
+        self.write_sized(value as u64, self.header.size_t_size);
+    }
+
+    fn write_number(&mut self, number: f64) -> Result<()> {
+        match (self.header.integral, self.header.number_size) {
+            (false, 8) => self.write_sized(number.to_bits(), 8),
+            (false, _) => self.write_sized((number as f32).to_bits() as u64, 4),
+            (true, size) => {
+                ensure!(
+                    number.fract() == 0.0,
+                    "{number} cannot be represented with an integral lua_Number"
+                );
+
+                self.write_sized(number as i64 as u64, size)
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_string(&mut self, string: Option<&[u8]>) {
+        match string {
+            Some(string) => {
+                self.write_size(string.len() + 1);
+                self.buf.extend_from_slice(string);
+                self.buf.push(0);
+            }
+            None => self.write_size(0),
+        }
+    }
+
+    fn write_header(&mut self) {
+        self.buf.extend_from_slice(SIGNATURE);
+        self.write_byte(VERSION);
+        self.write_byte(FORMAT);
+        self.write_byte(self.header.little_endian as u8);
+        self.write_byte(self.header.int_size);
+        self.write_byte(self.header.size_t_size);
+        self.write_byte(self.header.instruction_size);
+        self.write_byte(self.header.number_size);
+        self.write_byte(self.header.integral as u8);
+    }
+
+    fn write_function(&mut self, state: &FunctionState, source: Option<&str>) -> Result<()> {
+        let prototype = state.prototype;
+        let strip = state.serializer.strip_debug;
+
+        self.write_string(source.map(str::as_bytes));
+
+        if strip {
+            self.write_int(0);
+            self.write_int(0);
+        } else {
+            self.write_int(prototype.debug.line_defined as usize);
+            self.write_int(prototype.debug.last_line_defined as usize);
+        }
+
+        let arity = prototype.arity;
+
+        let is_vararg = match (arity.is_vararg, arity.needs_arg) {
+            (false, _) => 0,
+            (true, false) => VARARG_ISVARARG,
+            (true, true) => VARARG_ISVARARG | VARARG_HASARG | VARARG_NEEDSARG,
+        };
+
+        self.write_byte(prototype.upvalues.len() as u8);
+        self.write_byte(arity.params);
+        self.write_byte(is_vararg);
+        self.write_byte(state.max_stack_size as u8);
+
+        self.write_int(state.code.len());
+        for &instruction in &state.code {
+            self.write_sized(instruction as u64, 4);
+        }
+
+        self.write_int(state.constants.len());
+        for constant in &state.constants {
+            match constant {
+                Lua51Constant::Nil => self.write_byte(0),
+                Lua51Constant::Boolean(b) => {
+                    self.write_byte(1);
+                    self.write_byte(*b as u8);
+                }
+                Lua51Constant::Number(n) => {
+                    self.write_byte(3);
+                    self.write_number(f64::from_bits(*n))?;
+                }
+                Lua51Constant::String(s) => {
+                    self.write_byte(4);
+                    self.write_string(Some(s));
+                }
+            }
+        }
+
+        // nested functions store no source when it is the same as their parent's
+        self.write_int(state.children.len());
+        for child in &state.children {
+            let source = child
+                .prototype
+                .debug
+                .source
+                .as_deref()
+                .filter(|&source| !strip && Some(source) != prototype.debug.source.as_deref());
+
+            self.write_function(child, source)?;
+        }
+
+        let has_lines = !strip && prototype.chunk.has_spans();
+
+        if has_lines {
+            self.write_int(state.lineinfo.len());
+            for &line in &state.lineinfo {
+                self.write_int(line as usize);
+            }
+        } else {
+            self.write_int(0);
+        }
+
+        if strip {
+            self.write_int(0);
+            self.write_int(0);
+        } else {
+            self.write_int(prototype.debug.locals.len());
+            for local in &prototype.debug.locals {
+                self.write_string(Some(local.name.as_bytes()));
+                self.write_int(pc_at(&state.pcs, local.start));
+                self.write_int(pc_at(&state.pcs, local.end));
+            }
+
+            self.write_int(prototype.debug.upvalue_names.len());
+            for name in &prototype.debug.upvalue_names {
+                self.write_string(Some(name.as_bytes()));
+            }
+        }
+
+        Ok(())
+    }
+}
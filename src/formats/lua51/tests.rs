@@ -0,0 +1,826 @@
+#![cfg(test)]
+use super::*;
+use crate::formats::{Deserializer, Serializer, MAX_NESTING};
+use crate::ir::il::{
+    Arity, BinaryOp, BinaryOpKind, Call, Close, Closure, Concat, Condition, ConditionKind,
+    ConditionalJump, Constant, DebugInfo, ForGenCall, ForGenLoop, ForGenPrep, ForNumLoop,
+    ForNumPrep, Function, GetUpvalue, IlChunk, Inst, Instruction, Intrinsic, IntrinsicKind,
+    IntrinsicSource, Jump, Label, Load, Local, Move, OptVariable, Return, SelfLookup, SetList,
+    SetTable, SetUpvalue, Table, Upvalue, Value, VarArg,
+};
+use crate::ir::span::Span;
+use cranelift_entity::EntityRef;
+
+fn function(constants: Vec<Constant>, chunk: IlChunk) -> Function {
+    Function {
+        chunk,
+        constants,
+        prototypes: vec![],
+        upvalues: vec![],
+        arity: Arity::vararg(0),
+        name: None,
+        max_stack_size: 2,
+        debug: DebugInfo::default(),
+    }
+}
+
+/// Extracts the code of the main function from a chunk using the default header.
+fn code(bytes: &[u8]) -> Vec<(OpCode, u32, u32, u32)> {
+    // header, empty source, linedefined, lastlinedefined and four single byte fields
+    let start = 12 + 8 + 4 + 4 + 4;
+    let count = u32::from_le_bytes(bytes[start..start + 4].try_into().unwrap()) as usize;
+
+    bytes[start + 4..start + 4 + count * 4]
+        .chunks(4)
+        .map(|word| {
+            let word = u32::from_le_bytes(word.try_into().unwrap());
+            let op = OpCode::try_from((word & 0x3f) as u8).unwrap();
+
+            (
+                op,
+                (word >> POS_A) & MAXARG_A as u32,
+                (word >> POS_B) & MAXARG_B as u32,
+                (word >> POS_C) & MAXARG_C as u32,
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn header() {
+    let bytes = Lua51Serializer::default()
+        .serialize(&function(vec![], IlChunk::new(vec![])))
+        .unwrap();
+
+    assert_eq!(&bytes[..12], b"\x1bLua\x51\x00\x01\x04\x08\x04\x08\x00");
+
+    let serializer = Lua51Serializer::builder()
+        .header(
+            Lua51Header::builder()
+                .little_endian(false)
+                .size_t_size(4)
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+
+    let bytes = serializer
+        .serialize(&function(vec![], IlChunk::new(vec![])))
+        .unwrap();
+
+    assert_eq!(&bytes[..12], b"\x1bLua\x51\x00\x00\x04\x04\x04\x08\x00");
+    assert!(Lua51Header::builder().instruction_size(8).build().is_err());
+}
+
+#[test]
+fn arithmetic_uses_rk_operands() {
+    let chunk = IlChunk::new(vec![
+        Instruction::BinaryOp(BinaryOp {
+            operator: BinaryOpKind::Add,
+            dest: 0,
+            left: Value::ConstantIndex(0),
+            right: Value::Immediate(2),
+        }),
+        Instruction::Return(Return {
+            result_start: 0,
+            result_count: OptVariable::Number(1),
+        }),
+    ]);
+
+    let bytes = Lua51Serializer::default()
+        .serialize(&function(vec![Constant::Number(1.0)], chunk))
+        .unwrap();
+
+    assert_eq!(
+        code(&bytes),
+        vec![
+            (OpCode::Add, 0, BITRK, BITRK | 1),
+            (OpCode::Return, 0, 2, 0)
+        ]
+    );
+}
+
+#[test]
+fn constant_overflow_uses_temporaries() {
+    let constants = (0..300).map(|n| Constant::Number(n as f64)).collect();
+
+    let chunk = IlChunk::new(vec![
+        Instruction::BinaryOp(BinaryOp {
+            operator: BinaryOpKind::Mul,
+            dest: 0,
+            left: Value::ConstantIndex(299),
+            right: Value::ConstantIndex(1),
+        }),
+        Instruction::Return(Return {
+            result_start: 0,
+            result_count: OptVariable::Number(1),
+        }),
+    ]);
+
+    let bytes = Lua51Serializer::default()
+        .serialize(&function(constants, chunk))
+        .unwrap();
+
+    let code = code(&bytes);
+
+    // the temporary is allocated above every register the chunk uses
+    assert_eq!(code[0].0, OpCode::LoadK);
+    assert_eq!(code[0].1, 2);
+    assert_eq!(code[1], (OpCode::Mul, 0, 2, BITRK | 1));
+}
+
+#[test]
+fn array_constants_are_batched() {
+    let items = (0..120).map(|_| Value::Boolean(true)).collect();
+
+    let chunk = IlChunk::new(vec![
+        Instruction::Load(Load {
+            dest: 0,
+            src: Value::ConstantIndex(0),
+        }),
+        Instruction::Return(Return {
+            result_start: 0,
+            result_count: OptVariable::Number(1),
+        }),
+    ]);
+
+    let bytes = Lua51Serializer::default()
+        .serialize(&function(vec![Constant::Table(Table::Array(items))], chunk))
+        .unwrap();
+
+    let setlists = code(&bytes)
+        .into_iter()
+        .filter(|(op, ..)| *op == OpCode::SetList)
+        .map(|(_, _, b, c)| (b, c))
+        .collect::<Vec<_>>();
+
+    assert_eq!(setlists, vec![(50, 1), (50, 2), (20, 3)]);
+}
+
+#[test]
+fn jumps_account_for_expansion() {
+    let chunk = IlChunk::new(vec![
+        Instruction::Jump(Jump { target: Label(1) }),
+        Instruction::Label(Label(0)),
+        Instruction::Load(Load {
+            dest: 0,
+            src: Value::ConstantIndex(0),
+        }),
+        Instruction::Label(Label(1)),
+        Instruction::ConditionalJump(ConditionalJump {
+            target: Label(0),
+            condition: Condition {
+                kind: ConditionKind::Gt,
+                left: Value::StackIndex(0),
+                right: Value::Immediate(1),
+            },
+        }),
+        Instruction::Return(Return {
+            result_start: 0,
+            result_count: OptVariable::Number(0),
+        }),
+    ]);
+
+    let items = vec![Value::Nil, Value::Nil];
+
+    let bytes = Lua51Serializer::default()
+        .serialize(&function(vec![Constant::Table(Table::Array(items))], chunk))
+        .unwrap();
+
+    let code = code(&bytes);
+    let sbx = |(_, _, b, c): (OpCode, u32, u32, u32)| (((b << SIZE_C) | c) as isize) - MAXARG_SBX;
+
+    // the table constant expands into NEWTABLE, two loads, SETLIST and MOVE
+    assert_eq!(code[0].0, OpCode::Jmp);
+    assert_eq!(sbx(code[0]), 5);
+    assert_eq!(code[6], (OpCode::Lt, 1, BITRK, 0));
+    assert_eq!(sbx(code[7]), -7);
+}
+
+#[test]
+fn open_calls_must_be_consumed() {
+    let chunk = IlChunk::new(vec![
+        Instruction::Call(Call {
+            callee: 0,
+            self_call: false,
+            num_args: OptVariable::Number(0),
+            num_returns: OptVariable::Variable,
+        }),
+        Instruction::Return(Return {
+            result_start: 0,
+            result_count: OptVariable::Number(1),
+        }),
+    ]);
+
+    assert!(Lua51Serializer::default()
+        .serialize(&function(vec![], chunk))
+        .is_err());
+}
+
+/// Builds a chunk with the default header around `code`, with no constants or debug
+/// information.
+fn binary_chunk(code: &[u32]) -> Vec<u8> {
+    let mut bytes = b"\x1bLua\x51\x00\x01\x04\x08\x04\x08\x00".to_vec();
+
+    // empty source, linedefined and lastlinedefined
+    bytes.extend_from_slice(&[0; 8 + 4 + 4]);
+
+    // upvalues, parameters, vararg flags and stack size
+    bytes.extend_from_slice(&[0, 0, 2, 2]);
+
+    bytes.extend_from_slice(&(code.len() as u32).to_le_bytes());
+    for instruction in code {
+        bytes.extend_from_slice(&instruction.to_le_bytes());
+    }
+
+    // constants, prototypes, lines, locals and upvalue names
+    bytes.extend_from_slice(&[0; 4 * 5]);
+
+    bytes
+}
+
+#[test]
+fn huge_counts_are_rejected() {
+    let mut bytes = binary_chunk(&[]);
+
+    // the constant count follows the header, the function fields and the code size
+    bytes[36..40].copy_from_slice(&u32::MAX.to_le_bytes());
+
+    let error = Lua51Deserializer.deserialize(&bytes).unwrap_err();
+    assert!(format!("{error:#}").contains("constant count"), "{error:#}");
+}
+
+#[test]
+fn comparisons_are_lifted_exactly() {
+    let bytes = binary_chunk(&[
+        encode_abc(OpCode::Lt, 1, 0, 1),
+        encode_asbx(OpCode::Jmp, 0, 1),
+        encode_abc(OpCode::LoadNil, 0, 0, 0),
+        encode_abc(OpCode::Return, 0, 1, 0),
+    ]);
+
+    let chunk = Lua51Deserializer.deserialize(&bytes).unwrap().chunk;
+
+    // `a < b` has no exact negation, so the taken path goes through the JMP
+    assert_eq!(
+        chunk.inner(),
+        &vec![
+            Instruction::ConditionalJump(ConditionalJump {
+                target: Label(0),
+                condition: Condition {
+                    kind: ConditionKind::Lt,
+                    left: Value::StackIndex(0),
+                    right: Value::StackIndex(1),
+                },
+            }),
+            Instruction::Jump(Jump { target: Label(1) }),
+            Instruction::Label(Label(0)),
+            Instruction::Jump(Jump { target: Label(2) }),
+            Instruction::Label(Label(1)),
+            Instruction::Load(Load {
+                dest: 0,
+                src: Value::Nil,
+            }),
+            Instruction::Label(Label(2)),
+            Instruction::Return(Return {
+                result_start: 0,
+                result_count: OptVariable::Number(0),
+            }),
+        ]
+    );
+}
+
+#[test]
+fn tail_calls_return_every_result() {
+    let bytes = binary_chunk(&[
+        encode_abc(OpCode::TailCall, 0, 1, 0),
+        encode_abc(OpCode::Return, 0, 0, 0),
+    ]);
+
+    let chunk = Lua51Deserializer.deserialize(&bytes).unwrap().chunk;
+    let ret = Instruction::Return(Return {
+        result_start: 0,
+        result_count: OptVariable::Variable,
+    });
+
+    assert_eq!(
+        chunk.inner(),
+        &vec![
+            Instruction::Call(Call {
+                callee: 0,
+                self_call: false,
+                num_args: OptVariable::Number(0),
+                num_returns: OptVariable::Variable,
+            }),
+            ret.clone(),
+            ret,
+        ]
+    );
+}
+
+#[test]
+fn inserted_instructions_keep_jumps_intact() {
+    let bytes = binary_chunk(&[
+        encode_asbx(OpCode::Jmp, 0, 1),
+        encode_abc(OpCode::LoadNil, 0, 0, 0),
+        encode_abc(OpCode::Return, 0, 1, 0),
+    ]);
+
+    let mut main = Lua51Deserializer.deserialize(&bytes).unwrap();
+
+    // the jump targets a label, so growing the skipped region needs no fixups
+    let mut instructions = main.chunk.inner().to_vec();
+    instructions.insert(
+        1,
+        Instruction::Load(Load {
+            dest: 1,
+            src: Value::Nil,
+        }),
+    );
+    main.chunk = IlChunk::new(instructions);
+
+    let code = code(&Lua51Serializer::default().serialize(&main).unwrap());
+
+    let (op, _, b, c) = code[0];
+
+    assert_eq!(op, OpCode::Jmp);
+    assert_eq!(((b << SIZE_C) | c) as isize - MAXARG_SBX, 2);
+    assert_eq!(code[3].0, OpCode::Return);
+}
+
+#[test]
+fn labels_must_be_defined_once() {
+    let jump = Instruction::Jump(Jump { target: Label(0) });
+    let ret = Instruction::Return(Return {
+        result_start: 0,
+        result_count: OptVariable::Number(0),
+    });
+
+    let undefined = IlChunk::new(vec![jump.clone(), ret.clone()]);
+    let duplicated = IlChunk::new(vec![
+        jump,
+        Instruction::Label(Label(0)),
+        Instruction::Label(Label(0)),
+        ret,
+    ]);
+
+    for chunk in [undefined, duplicated] {
+        assert!(Lua51Serializer::default()
+            .serialize(&function(vec![], chunk))
+            .is_err());
+    }
+}
+
+#[test]
+fn serialized_chunks_round_trip() {
+    let constants = vec![Constant::String(b"x\xff".to_vec()), Constant::Number(1.5)];
+
+    let mut chunk = IlChunk::default();
+
+    chunk.push_spanned(
+        Instruction::BinaryOp(BinaryOp {
+            operator: BinaryOpKind::Mul,
+            dest: 0,
+            left: Value::StackIndex(1),
+            right: Value::ConstantIndex(1),
+        }),
+        Some(Span::line(3)),
+    );
+    chunk.push_spanned(
+        Instruction::Return(Return {
+            result_start: 0,
+            result_count: OptVariable::Number(1),
+        }),
+        Some(Span::line(4)),
+    );
+
+    let prototype = function(constants.clone(), chunk.clone());
+
+    let bytes = Lua51Serializer::default().serialize(&prototype).unwrap();
+    let lifted = Lua51Deserializer.deserialize(&bytes).unwrap();
+
+    // the spans of the instructions become the line table and back
+    assert_eq!(lifted.chunk, chunk);
+    assert_eq!(lifted.max_stack_size, 2);
+
+    // strings keep bytes that are not valid UTF-8
+    assert_eq!(lifted.constants, constants);
+
+    // malformed instructions are reported instead of being dropped
+    let bytes = binary_chunk(&[
+        encode_abx(OpCode::Closure, 0, 0),
+        encode_abc(OpCode::Return, 0, 1, 0),
+    ]);
+
+    assert!(Lua51Deserializer.deserialize(&bytes).is_err());
+}
+
+#[test]
+fn table_stores_round_trip() {
+    let chunk = IlChunk::new(vec![
+        Instruction::SetTable(SetTable {
+            table: 0,
+            key: Value::ConstantIndex(0),
+            value: Value::StackIndex(1),
+        }),
+        Instruction::SelfLookup(SelfLookup {
+            dest: 2,
+            object: 0,
+            key: Value::ConstantIndex(0),
+        }),
+        Instruction::Concat(Concat {
+            dest: 1,
+            start: 2,
+            end: 3,
+        }),
+        Instruction::Move(Move { dest: 3, src: 1 }),
+        Instruction::Call(Call {
+            callee: 1,
+            self_call: false,
+            num_args: OptVariable::Number(0),
+            num_returns: OptVariable::Variable,
+        }),
+        Instruction::SetList(SetList {
+            table: 0,
+            start: 1,
+            count: OptVariable::Variable,
+            offset: 50,
+        }),
+        Instruction::Return(Return {
+            result_start: 0,
+            result_count: OptVariable::Number(1),
+        }),
+    ]);
+
+    let prototype = function(vec![Constant::String(b"x".to_vec())], chunk.clone());
+
+    let bytes = Lua51Serializer::default().serialize(&prototype).unwrap();
+    assert_eq!(code(&bytes)[5], (OpCode::SetList, 0, 0, 2));
+
+    let lifted = Lua51Deserializer.deserialize(&bytes).unwrap();
+    assert_eq!(lifted.chunk, chunk);
+
+    // values that are not directly above the table are copied there first
+    let chunk = IlChunk::new(vec![Instruction::SetList(SetList {
+        table: 0,
+        start: 2,
+        count: OptVariable::Number(1),
+        offset: 0,
+    })]);
+
+    let bytes = Lua51Serializer::default()
+        .serialize(&function(vec![], chunk))
+        .unwrap();
+
+    assert_eq!(
+        code(&bytes)[..3],
+        [
+            (OpCode::Move, 3, 0, 0),
+            (OpCode::Move, 4, 2, 0),
+            (OpCode::SetList, 3, 1, 1),
+        ]
+    );
+}
+
+#[test]
+fn loops_round_trip() {
+    let chunk = IlChunk::new(vec![
+        Instruction::ForNumPrep(ForNumPrep {
+            target: Label(1),
+            index: 0,
+            limit: 1,
+            step: 2,
+            var: 3,
+        }),
+        Instruction::Label(Label(0)),
+        Instruction::Move(Move { dest: 4, src: 3 }),
+        Instruction::ForNumLoop(ForNumLoop {
+            target: Label(0),
+            index: 0,
+            limit: 1,
+            step: 2,
+            var: 3,
+        }),
+        Instruction::Label(Label(1)),
+        Instruction::ForGenPrep(ForGenPrep {
+            target: Label(3),
+            base: 5,
+        }),
+        Instruction::Label(Label(2)),
+        Instruction::Move(Move { dest: 4, src: 8 }),
+        Instruction::Label(Label(3)),
+        Instruction::ForGenCall(ForGenCall {
+            base: 5,
+            dest: 8,
+            count: 2,
+        }),
+        Instruction::ForGenLoop(ForGenLoop {
+            target: Label(2),
+            base: 5,
+            var: 8,
+        }),
+        Instruction::Return(Return {
+            result_start: 4,
+            result_count: OptVariable::Number(1),
+        }),
+    ]);
+
+    let bytes = Lua51Serializer::default()
+        .serialize(&function(vec![], chunk.clone()))
+        .unwrap();
+
+    assert_eq!(Lua51Deserializer.deserialize(&bytes).unwrap().chunk, chunk);
+}
+
+#[test]
+fn closures_round_trip() {
+    // local x; function() x = x end
+    let mut child = function(
+        vec![],
+        IlChunk::new(vec![
+            Instruction::GetUpvalue(GetUpvalue {
+                dest: 0,
+                upvalue: 0,
+            }),
+            Instruction::SetUpvalue(SetUpvalue { src: 0, upvalue: 0 }),
+            Instruction::Return(Return {
+                result_start: 0,
+                result_count: OptVariable::Number(0),
+            }),
+        ]),
+    );
+    child.upvalues = vec![Upvalue {
+        in_stack: true,
+        index: 0,
+    }];
+    child.arity = Arity::fixed(0);
+
+    let chunk = IlChunk::new(vec![
+        Instruction::Load(Load {
+            dest: 0,
+            src: Value::Nil,
+        }),
+        Instruction::Closure(Closure {
+            dest: 1,
+            prototype: 0,
+        }),
+        Instruction::Close(Close { start: 0 }),
+        Instruction::Return(Return {
+            result_start: 0,
+            result_count: OptVariable::Number(0),
+        }),
+    ]);
+
+    let mut main = function(vec![], chunk.clone());
+    main.prototypes = vec![child];
+
+    let bytes = Lua51Serializer::default().serialize(&main).unwrap();
+
+    // the upvalue is described by a MOVE following CLOSURE
+    assert_eq!(
+        code(&bytes)[1..4],
+        [
+            (OpCode::Closure, 1, 0, 0),
+            (OpCode::Move, 0, 0, 0),
+            (OpCode::Close, 0, 0, 0)
+        ]
+    );
+
+    let lifted = Lua51Deserializer.deserialize(&bytes).unwrap();
+
+    assert_eq!(lifted.chunk, chunk);
+    assert_eq!(lifted.prototypes.len(), 1);
+    assert_eq!(lifted.prototypes[0].chunk, main.prototypes[0].chunk);
+    assert_eq!(lifted.prototypes[0].upvalues, main.prototypes[0].upvalues);
+    assert!(!lifted.prototypes[0].arity.is_vararg);
+}
+
+#[test]
+fn debug_info_round_trips() {
+    // local x; local function f() x = x end
+    let mut child = function(
+        vec![],
+        IlChunk::new(vec![
+            Instruction::GetUpvalue(GetUpvalue {
+                dest: 0,
+                upvalue: 0,
+            }),
+            Instruction::SetUpvalue(SetUpvalue { src: 0, upvalue: 0 }),
+            Instruction::Return(Return {
+                result_start: 0,
+                result_count: OptVariable::Number(0),
+            }),
+        ]),
+    );
+    child.upvalues = vec![Upvalue {
+        in_stack: true,
+        index: 0,
+    }];
+    child.debug = DebugInfo {
+        source: Some("@main.lua".to_owned()),
+        line_defined: 1,
+        last_line_defined: 1,
+        locals: vec![],
+        upvalue_names: vec!["x".to_owned()],
+    };
+
+    let mut main = function(
+        vec![],
+        IlChunk::new(vec![
+            Instruction::Load(Load {
+                dest: 0,
+                src: Value::Nil,
+            }),
+            Instruction::Closure(Closure {
+                dest: 1,
+                prototype: 0,
+            }),
+            Instruction::Close(Close { start: 0 }),
+            Instruction::Return(Return {
+                result_start: 0,
+                result_count: OptVariable::Number(0),
+            }),
+        ]),
+    );
+    main.prototypes = vec![child];
+
+    let local = |name: &str, register, start, end| Local {
+        name: name.to_owned(),
+        register,
+        start: Inst::new(start),
+        end: Inst::new(end),
+    };
+
+    main.debug = DebugInfo {
+        source: Some("@main.lua".to_owned()),
+        line_defined: 0,
+        last_line_defined: 0,
+        // `f` begins after the MOVE that follows CLOSURE, which is not lifted on its own
+        locals: vec![local("x", 0, 1, 4), local("f", 1, 2, 4)],
+        upvalue_names: vec![],
+    };
+
+    let bytes = Lua51Serializer::default().serialize(&main).unwrap();
+    let lifted = Lua51Deserializer.deserialize(&bytes).unwrap();
+
+    assert_eq!(lifted.debug, main.debug);
+    assert_eq!(lifted.prototypes[0].debug, main.prototypes[0].debug);
+
+    let bytes = Lua51Serializer::builder()
+        .strip_debug(true)
+        .build()
+        .unwrap()
+        .serialize(&main)
+        .unwrap();
+    let lifted = Lua51Deserializer.deserialize(&bytes).unwrap();
+
+    assert!(lifted.debug.is_empty());
+    assert!(lifted.prototypes[0].debug.is_empty());
+}
+
+#[test]
+fn varargs_round_trip() {
+    // function(a, ...) local b, c = ...; f(...) return ... end, with the `arg` table of
+    // Lua 5.0
+    let chunk = IlChunk::new(vec![
+        Instruction::VarArg(VarArg {
+            dest: 2,
+            count: OptVariable::Number(2),
+        }),
+        Instruction::VarArg(VarArg {
+            dest: 5,
+            count: OptVariable::Variable,
+        }),
+        Instruction::Call(Call {
+            callee: 4,
+            self_call: false,
+            num_args: OptVariable::Variable,
+            num_returns: OptVariable::Number(0),
+        }),
+        Instruction::VarArg(VarArg {
+            dest: 2,
+            count: OptVariable::Variable,
+        }),
+        Instruction::Return(Return {
+            result_start: 2,
+            result_count: OptVariable::Variable,
+        }),
+    ]);
+
+    let mut main = function(vec![], chunk.clone());
+    main.arity = Arity {
+        params: 1,
+        is_vararg: true,
+        needs_arg: true,
+    };
+
+    let bytes = Lua51Serializer::default().serialize(&main).unwrap();
+
+    assert_eq!(
+        code(&bytes)[..2],
+        [(OpCode::VarArg, 2, 3, 0), (OpCode::VarArg, 5, 0, 0)]
+    );
+    assert_eq!(
+        code(&bytes)[3..],
+        [(OpCode::VarArg, 2, 0, 0), (OpCode::Return, 2, 0, 0)]
+    );
+
+    let lifted = Lua51Deserializer.deserialize(&bytes).unwrap();
+
+    assert_eq!(lifted.chunk, chunk);
+    assert_eq!(lifted.arity, main.arity);
+    assert_eq!(format!("{:?}", lifted.arity), "(1, ..., arg)");
+
+    main.arity = Arity::fixed(1);
+
+    assert!(Lua51Serializer::default().serialize(&main).is_err());
+}
+
+#[test]
+fn intrinsics_call_their_library() {
+    let intrinsic = |source| {
+        Instruction::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::BitAnd(Value::StackIndex(0), Value::Immediate(1)),
+            dest: 0,
+            source,
+        })
+    };
+
+    let strings = |source| {
+        let chunk = IlChunk::new(vec![
+            intrinsic(source),
+            Instruction::Return(Return {
+                result_start: 0,
+                result_count: OptVariable::Number(1),
+            }),
+        ]);
+
+        let bytes = Lua51Serializer::default()
+            .serialize(&function(vec![], chunk))
+            .unwrap();
+
+        Lua51Deserializer
+            .deserialize(&bytes)
+            .unwrap()
+            .constants
+            .into_iter()
+            .filter_map(|constant| match constant {
+                Constant::String(s) => Some(s),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // operators are polyfilled with the configured library
+    assert_eq!(
+        strings(IntrinsicSource::Operator),
+        [b"bit".to_vec(), b"band".to_vec()]
+    );
+    assert_eq!(
+        strings(IntrinsicSource::Bit32),
+        [b"bit32".to_vec(), b"band".to_vec()]
+    );
+
+    assert_eq!(
+        format!("{:?}", intrinsic(IntrinsicSource::Bit32)),
+        "Intrinsic(0    = bit32.band(0, 1))"
+    );
+}
+
+/// Nests a function returning nothing `depth` functions deep in a main function.
+fn nested(depth: usize) -> Function {
+    let leaf = function(
+        vec![],
+        IlChunk::new(vec![Instruction::Return(Return {
+            result_start: 0,
+            result_count: OptVariable::Number(0),
+        })]),
+    );
+
+    (0..depth).fold(leaf.clone(), |inner, _| Function {
+        prototypes: vec![inner],
+        ..leaf.clone()
+    })
+}
+
+#[test]
+fn deep_nesting_is_rejected() {
+    let serialize = |depth| {
+        Lua51Serializer::default()
+            .serialize(&nested(depth))
+            .unwrap()
+    };
+
+    assert!(Lua51Deserializer
+        .deserialize(&serialize(MAX_NESTING))
+        .is_ok());
+
+    let error = Lua51Deserializer
+        .deserialize(&serialize(MAX_NESTING + 1))
+        .unwrap_err();
+    assert!(
+        format!("{error:#}").contains("nested more than"),
+        "{error:#}"
+    );
+}
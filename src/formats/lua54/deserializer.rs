@@ -0,0 +1,893 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use super::*;
+use crate::formats::{
+    check_nesting,
+    lift::{lift_locals, Lifter, Target},
+    Deserializer,
+};
+use crate::ir::il::{
+    Arity, BinaryOp, BinaryOpKind, Call, Close, Closure, Concat, Condition, ConditionKind,
+    Constant, DebugInfo, ForGenCall, ForGenLoop, ForGenPrep, ForNumLoop, ForNumPrep, Function,
+    GetGlobal, GetTable, GetUpvalue, Instruction, Intrinsic, IntrinsicKind, IntrinsicSource, Load,
+    Move, NewTable, OptVariable, Return, SelfLookup, SetGlobal, SetList, SetTable, SetUpvalue,
+    UnaryOp, UnaryOpKind, Upvalue, Value, VarArg,
+};
+use crate::ir::span::Span;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+/// A function prototype exactly as it is stored in a Lua 5.4 binary chunk.
+#[derive(Clone, Debug)]
+struct Prototype {
+    source: Option<String>,
+    line_defined: usize,
+    last_line_defined: usize,
+    param_count: u8,
+    is_vararg: bool,
+    max_stack_size: u8,
+    code: Vec<u32>,
+    constants: Vec<Constant>,
+    /// The upvalues of the function, as (instack, idx) pairs.
+    upvalues: Vec<(bool, usize)>,
+    prototypes: Vec<Prototype>,
+    lineinfo: Vec<u32>,
+    /// The local variables, as (name, startpc, endpc) triples.
+    locals: Vec<(String, usize, usize)>,
+    upvalue_names: Vec<String>,
+}
+
+/// Deserializes Lua 5.4 binary chunks, as produced by `luac` or `string.dump`, into
+/// LUNIR intermediate language.
+#[derive(Clone, Debug, Default)]
+pub struct Lua54Deserializer;
+
+impl Deserializer for Lua54Deserializer {
+    /// Deserializes the binary chunk `bytes`, returning the lifted prototype of its main
+    /// function.
+    fn deserialize(&self, bytes: &[u8]) -> Result<Function> {
+        let mut reader = Reader { bytes, position: 0 };
+
+        reader.read_header()?;
+
+        // the number of upvalues of the main closure, which is repeated by the function
+        reader.read_byte()?;
+
+        let main = reader.read_function(None, 0)?;
+
+        ensure!(
+            reader.position == bytes.len(),
+            "{} trailing bytes after the main function",
+            bytes.len() - reader.position
+        );
+
+        // the only upvalue the main function may have is `_ENV`, which the loading function
+        // places in its stack
+        let environment = match main.upvalues.as_slice() {
+            [] => None,
+            [(true, 0)] => Some(0),
+            _ => bail!("the main function can only have the `_ENV` upvalue"),
+        };
+
+        lift(&main, environment, Vec::new())
+    }
+}
+
+/// Lifts `prototype` and the prototypes nested in it into LUNIR intermediate language.
+/// `_ENV` is not an upvalue in the IL, so the upvalue at index `environment` is left out and
+/// `upvalues` describes what closures of `prototype` capture besides it.
+fn lift(
+    prototype: &Prototype,
+    environment: Option<usize>,
+    upvalues: Vec<Upvalue>,
+) -> Result<Function> {
+    // maps every upvalue to its index in the IL, `_ENV` has no index
+    let mut map = Vec::with_capacity(prototype.upvalues.len());
+    let mut next = 0;
+
+    for index in 0..prototype.upvalues.len() {
+        if Some(index) == environment {
+            map.push(None);
+        } else {
+            map.push(Some(next));
+            next += 1;
+        }
+    }
+
+    let prototypes = prototype
+        .prototypes
+        .iter()
+        .enumerate()
+        .map(|(index, child)| {
+            let child_environment = child
+                .upvalues
+                .iter()
+                .position(|&(in_stack, index)| !in_stack && Some(index) == environment);
+
+            let upvalues = child
+                .upvalues
+                .iter()
+                .enumerate()
+                .filter(|&(upvalue, _)| Some(upvalue) != child_environment)
+                .map(|(_, &(in_stack, index))| match (in_stack, map.get(index)) {
+                    (true, _) => Ok(Upvalue { in_stack, index }),
+                    (false, Some(&Some(index))) => Ok(Upvalue { in_stack, index }),
+                    _ => bail!("upvalue {index} of the parent cannot be captured"),
+                })
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("failed to lift the upvalues of prototype {index}"))?;
+
+            lift(child, child_environment, upvalues)
+                .with_context(|| format!("failed to lift prototype {index}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut lifter = Lifter::new(prototype.code.len());
+    let mut constants = prototype.constants.clone();
+
+    // registers past the end of the frame hold operands that Lua 5.4 encodes inline but the
+    // IL needs in a register
+    let temporary = prototype.max_stack_size as usize;
+    let mut uses_temporary = false;
+
+    for (pc, &instruction) in prototype.code.iter().enumerate() {
+        lifter.begin(prototype.lineinfo.get(pc).copied().map(Span::line));
+
+        lift_instruction(
+            &mut lifter,
+            prototype,
+            &mut constants,
+            &map,
+            temporary,
+            &mut uses_temporary,
+        )
+        .with_context(|| format!("failed to lift instruction {pc} ({instruction:#010x})"))?;
+    }
+
+    let (chunk, pcs) = lifter.finish()?;
+
+    let max_stack_size = if uses_temporary {
+        u8::try_from(temporary + 1).context("no register is left for temporaries")?
+    } else {
+        prototype.max_stack_size
+    };
+
+    // `_ENV` loses its name along with the upvalue, and unnamed upvalues are left unnamed
+    let mut upvalue_names = prototype
+        .upvalue_names
+        .iter()
+        .enumerate()
+        .filter(|&(index, _)| Some(index) != environment)
+        .map(|(_, name)| name.clone())
+        .collect::<Vec<_>>();
+
+    if upvalue_names.iter().all(String::is_empty) {
+        upvalue_names.clear();
+    }
+
+    Ok(Function {
+        chunk,
+        constants,
+        prototypes,
+        upvalues,
+        arity: Arity {
+            params: prototype.param_count,
+            is_vararg: prototype.is_vararg,
+            needs_arg: false,
+        },
+        name: None,
+        max_stack_size,
+        debug: DebugInfo {
+            source: prototype.source.clone(),
+            line_defined: prototype.line_defined as u32,
+            last_line_defined: prototype.last_line_defined as u32,
+            locals: lift_locals(&prototype.locals, &pcs),
+            upvalue_names,
+        },
+    })
+}
+
+fn opcode(instruction: u32) -> Result<OpCode> {
+    OpCode::try_from((instruction & 0x7f) as u8).map_err(|op| anyhow!("unknown opcode {op}"))
+}
+
+fn lift_instruction(
+    lifter: &mut Lifter,
+    prototype: &Prototype,
+    constants: &mut Vec<Constant>,
+    upvalues: &[Option<usize>],
+    temporary: usize,
+    uses_temporary: &mut bool,
+) -> Result<()> {
+    let pc = lifter.pc();
+    let code = &prototype.code;
+    let instruction = code[pc];
+    let op = opcode(instruction)?;
+
+    let a = ((instruction >> POS_A) & 0xff) as usize;
+    let k = (instruction >> POS_K) & 1 != 0;
+    let b = ((instruction >> POS_B) as usize) & MAXARG_B;
+    let c = ((instruction >> POS_C) as usize) & MAXARG_C;
+    let bx = (instruction >> POS_BX) as usize;
+    let sbx = bx as i64 - OFFSET_SBX;
+    let sb = b as i64 - OFFSET_SC;
+    let sc = c as i64 - OFFSET_SC;
+
+    let extra_arg = || -> Result<usize> {
+        match code.get(pc + 1) {
+            Some(&next) if opcode(next)? == OpCode::ExtraArg => Ok((next >> POS_AX) as usize),
+            _ => bail!("{op:?} is not followed by EXTRAARG"),
+        }
+    };
+
+    let load = |dest, src| Instruction::Load(Load { dest, src });
+    let mv = |dest, src| Instruction::Move(Move { dest, src });
+    let stack = Value::StackIndex;
+    let constant = Value::ConstantIndex;
+    let immediate = |value: i64| Value::Immediate(value as i32);
+    let rk = |operand: usize| if k { constant(operand) } else { stack(operand) };
+
+    // globals are fields of `_ENV`, which is the only upvalue that is not one in the IL
+    let ensure_env = |upvalue: usize| {
+        ensure!(
+            upvalues.get(upvalue) == Some(&None),
+            "indexing upvalue {upvalue} cannot be represented in LUNIR IL"
+        );
+
+        Ok(())
+    };
+
+    let upvalue = |upvalue: usize| match upvalues.get(upvalue) {
+        Some(Some(index)) => Ok(*index),
+        Some(None) => bail!("`_ENV` cannot be represented in LUNIR IL"),
+        None => bail!("upvalue {upvalue} does not exist"),
+    };
+
+    let binary = |operator, left, right| {
+        Instruction::BinaryOp(BinaryOp {
+            operator,
+            dest: a,
+            left,
+            right,
+        })
+    };
+
+    let intrinsic = |kind| {
+        Instruction::Intrinsic(Intrinsic {
+            kind,
+            dest: a,
+            source: IntrinsicSource::Operator,
+        })
+    };
+
+    let unary = |operator| {
+        Instruction::UnaryOp(UnaryOp {
+            operator,
+            dest: a,
+            left: stack(b),
+        })
+    };
+
+    let get_table = |key| {
+        Instruction::GetTable(GetTable {
+            dest: a,
+            source: b,
+            key,
+        })
+    };
+
+    let set_table = |key| {
+        Instruction::SetTable(SetTable {
+            table: a,
+            key,
+            value: rk(c),
+        })
+    };
+
+    // comparisons and tests skip the jump that follows them when their condition does not
+    // match `k`, so they are lifted as jumps over that jump
+    let skip = Target::Pc(pc + 2);
+    let follow = Target::Pc(pc + 1);
+
+    let compare = |lifter: &mut Lifter, kind, left, right| match (k, kind) {
+        // equality is the only comparison that can be negated exactly
+        (true, ConditionKind::Eq) => lifter.conditional_jump(
+            Condition {
+                kind: ConditionKind::Ne,
+                left,
+                right,
+            },
+            skip,
+        ),
+        (true, kind) => {
+            lifter.conditional_jump(Condition { kind, left, right }, follow);
+            lifter.jump(skip);
+        }
+        (false, kind) => lifter.conditional_jump(Condition { kind, left, right }, skip),
+    };
+
+    let arithmetic = |op: OpCode| match op {
+        OpCode::AddI | OpCode::AddK | OpCode::Add => Some(BinaryOpKind::Add),
+        OpCode::SubK | OpCode::Sub => Some(BinaryOpKind::Sub),
+        OpCode::MulK | OpCode::Mul => Some(BinaryOpKind::Mul),
+        OpCode::ModK | OpCode::Mod => Some(BinaryOpKind::Mod),
+        OpCode::PowK | OpCode::Pow => Some(BinaryOpKind::Pow),
+        OpCode::DivK | OpCode::Div => Some(BinaryOpKind::Div),
+        OpCode::IDivK | OpCode::IDiv => Some(BinaryOpKind::IDiv),
+        _ => None,
+    };
+
+    let bitwise = |op: OpCode, left, right| match op {
+        OpCode::BAndK | OpCode::BAnd => Some(IntrinsicKind::BitAnd(left, right)),
+        OpCode::BOrK | OpCode::BOr => Some(IntrinsicKind::BitOr(left, right)),
+        OpCode::BXorK | OpCode::BXor => Some(IntrinsicKind::BitXor(left, right)),
+        OpCode::Shl => Some(IntrinsicKind::LeftShift(left, right)),
+        OpCode::Shr => Some(IntrinsicKind::RightShift(left, right)),
+        _ => None,
+    };
+
+    match op {
+        // metamethod fallbacks only run when the preceding arithmetic instruction fails,
+        // which the IL operation already accounts for, and `EXTRAARG` is consumed by the
+        // instruction it belongs to
+        OpCode::VarArgPrep | OpCode::MmBin | OpCode::MmBinI | OpCode::MmBinK | OpCode::ExtraArg => {
+        }
+
+        OpCode::Close => lifter.push(Instruction::Close(Close { start: a })),
+        OpCode::Closure => lifter.push(Instruction::Closure(Closure {
+            dest: a,
+            prototype: bx,
+        })),
+        OpCode::GetUpval => lifter.push(Instruction::GetUpvalue(GetUpvalue {
+            dest: a,
+            upvalue: upvalue(b)?,
+        })),
+        OpCode::SetUpval => lifter.push(Instruction::SetUpvalue(SetUpvalue {
+            src: a,
+            upvalue: upvalue(b)?,
+        })),
+
+        OpCode::Move => lifter.push(mv(a, b)),
+        OpCode::LoadI => lifter.push(load(a, immediate(sbx))),
+        // the IL has no float immediates, so the value is loaded from the constant table
+        OpCode::LoadF => {
+            let value = sbx as f64;
+
+            let index = match constants.iter().position(|k| *k == Constant::Number(value)) {
+                Some(index) => index,
+                None => {
+                    constants.push(Constant::Number(value));
+                    constants.len() - 1
+                }
+            };
+
+            lifter.push(load(a, constant(index)));
+        }
+        OpCode::LoadK => lifter.push(load(a, constant(bx))),
+        OpCode::LoadKX => lifter.push(load(a, constant(extra_arg()?))),
+        OpCode::LoadFalse => lifter.push(load(a, Value::Boolean(false))),
+        OpCode::LFalseSkip => {
+            lifter.push(load(a, Value::Boolean(false)));
+            lifter.jump(skip);
+        }
+        OpCode::LoadTrue => lifter.push(load(a, Value::Boolean(true))),
+        OpCode::LoadNil => {
+            for dest in a..=a + b {
+                lifter.push(load(dest, Value::Nil));
+            }
+        }
+
+        OpCode::GetTabUp => {
+            ensure_env(b)?;
+
+            lifter.push(Instruction::GetGlobal(GetGlobal {
+                dest: a,
+                constant: c,
+            }));
+        }
+        OpCode::SetTabUp => {
+            ensure_env(a)?;
+
+            let src = if k {
+                *uses_temporary = true;
+                lifter.push(load(temporary, constant(c)));
+
+                temporary
+            } else {
+                c
+            };
+
+            lifter.push(Instruction::SetGlobal(SetGlobal { src, constant: b }));
+        }
+
+        OpCode::GetTable => lifter.push(get_table(stack(c))),
+        OpCode::GetI => lifter.push(get_table(immediate(c as i64))),
+        OpCode::GetField => lifter.push(get_table(constant(c))),
+        OpCode::SetTable => lifter.push(set_table(stack(b))),
+        OpCode::SetI => lifter.push(set_table(immediate(b as i64))),
+        OpCode::SetField => lifter.push(set_table(constant(b))),
+
+        OpCode::NewTable => {
+            let mut array_size = c;
+
+            if k {
+                array_size += extra_arg()? * (MAXARG_C + 1);
+            }
+
+            lifter.push(Instruction::NewTable(NewTable {
+                dest: a,
+                array_size,
+                table_size: if b == 0 { 0 } else { 1 << (b - 1) },
+            }));
+        }
+
+        OpCode::SelfOp => lifter.push(Instruction::SelfLookup(SelfLookup {
+            dest: a,
+            object: b,
+            key: rk(c),
+        })),
+
+        OpCode::AddI => lifter.push(binary(BinaryOpKind::Add, stack(b), immediate(sc))),
+        OpCode::AddK
+        | OpCode::SubK
+        | OpCode::MulK
+        | OpCode::ModK
+        | OpCode::PowK
+        | OpCode::DivK
+        | OpCode::IDivK => lifter.push(binary(arithmetic(op).unwrap(), stack(b), constant(c))),
+        OpCode::Add
+        | OpCode::Sub
+        | OpCode::Mul
+        | OpCode::Mod
+        | OpCode::Pow
+        | OpCode::Div
+        | OpCode::IDiv => lifter.push(binary(arithmetic(op).unwrap(), stack(b), stack(c))),
+
+        OpCode::BAndK | OpCode::BOrK | OpCode::BXorK => {
+            lifter.push(intrinsic(bitwise(op, stack(b), constant(c)).unwrap()))
+        }
+        OpCode::BAnd | OpCode::BOr | OpCode::BXor | OpCode::Shl | OpCode::Shr => {
+            lifter.push(intrinsic(bitwise(op, stack(b), stack(c)).unwrap()))
+        }
+        OpCode::ShrI => lifter.push(intrinsic(IntrinsicKind::RightShift(
+            stack(b),
+            immediate(sc),
+        ))),
+        // the immediate is the left operand of `SHLI`
+        OpCode::ShlI => lifter.push(intrinsic(IntrinsicKind::LeftShift(immediate(sc), stack(b)))),
+
+        OpCode::Unm => lifter.push(unary(UnaryOpKind::Neg)),
+        OpCode::Not => lifter.push(unary(UnaryOpKind::Not)),
+        OpCode::Len => lifter.push(unary(UnaryOpKind::Len)),
+        OpCode::BNot => lifter.push(intrinsic(IntrinsicKind::BitNot(stack(b)))),
+
+        OpCode::Concat => {
+            ensure!(b >= 2, "CONCAT needs at least two operands");
+
+            lifter.push(Instruction::Concat(Concat {
+                dest: a,
+                start: a,
+                end: a + b - 1,
+            }));
+        }
+
+        OpCode::Jmp => {
+            let sj = (instruction >> POS_SJ) as i64 - OFFSET_SJ;
+
+            lifter.jump(Target::Pc((pc as i64 + 1 + sj) as usize));
+        }
+
+        OpCode::Eq => compare(lifter, ConditionKind::Eq, stack(a), stack(b)),
+        OpCode::Lt => compare(lifter, ConditionKind::Lt, stack(a), stack(b)),
+        OpCode::Le => compare(lifter, ConditionKind::Le, stack(a), stack(b)),
+        OpCode::EqK => compare(lifter, ConditionKind::Eq, stack(a), constant(b)),
+        OpCode::EqI => compare(lifter, ConditionKind::Eq, stack(a), immediate(sb)),
+        OpCode::LtI => compare(lifter, ConditionKind::Lt, stack(a), immediate(sb)),
+        OpCode::LeI => compare(lifter, ConditionKind::Le, stack(a), immediate(sb)),
+        OpCode::GtI => compare(lifter, ConditionKind::Gt, stack(a), immediate(sb)),
+        OpCode::GeI => compare(lifter, ConditionKind::Ge, stack(a), immediate(sb)),
+
+        OpCode::Test => {
+            if k {
+                lifter.jump_not(a, skip);
+            } else {
+                lifter.jump_not(a, follow);
+                lifter.jump(skip);
+            }
+        }
+        OpCode::TestSet => {
+            if k {
+                lifter.jump_not(b, skip);
+            } else {
+                lifter.jump_not(b, Target::Local(2));
+                lifter.jump(skip);
+            }
+
+            lifter.push(mv(a, b));
+        }
+
+        // a tail call is lifted as a call whose results are all returned
+        OpCode::Call | OpCode::TailCall => {
+            let self_call = pc > 0 && {
+                let previous = code[pc - 1];
+
+                opcode(previous)? == OpCode::SelfOp && ((previous >> POS_A) & 0xff) as usize == a
+            };
+
+            let num_args = match b {
+                0 => OptVariable::Variable,
+                b => OptVariable::Number(b - 1 - self_call as usize),
+            };
+
+            // the C operand of a tail call is the parameter count of a variadic caller
+            let num_returns = match c {
+                _ if op == OpCode::TailCall => OptVariable::Variable,
+                0 => OptVariable::Variable,
+                c => OptVariable::Number(c - 1),
+            };
+
+            lifter.push(Instruction::Call(Call {
+                callee: a,
+                self_call,
+                num_args,
+                num_returns,
+            }));
+
+            if op == OpCode::TailCall {
+                lifter.push(Instruction::Return(Return {
+                    result_start: a,
+                    result_count: OptVariable::Variable,
+                }));
+            }
+        }
+
+        OpCode::Return | OpCode::Return0 | OpCode::Return1 => {
+            let result_count = match (op, b) {
+                (OpCode::Return0, _) => OptVariable::Number(0),
+                (OpCode::Return1, _) => OptVariable::Number(1),
+                (_, 0) => OptVariable::Variable,
+                (_, b) => OptVariable::Number(b - 1),
+            };
+
+            lifter.push(Instruction::Return(Return {
+                result_start: a,
+                result_count,
+            }));
+        }
+
+        OpCode::SetList => {
+            let mut offset = c;
+
+            if k {
+                offset += extra_arg()? * (MAXARG_C + 1);
+            }
+
+            lifter.push(Instruction::SetList(SetList {
+                table: a,
+                start: a + 1,
+                count: match b {
+                    0 => OptVariable::Variable,
+                    count => OptVariable::Number(count),
+                },
+                offset,
+            }));
+        }
+
+        // FORPREP skips the FORLOOP after the body as well when the loop does not run
+        OpCode::ForPrep => lifter.branch(Target::Pc(pc + 2 + bx), move |target| {
+            Instruction::ForNumPrep(ForNumPrep {
+                target,
+                index: a,
+                limit: a + 1,
+                step: a + 2,
+                var: a + 3,
+            })
+        }),
+        OpCode::ForLoop => {
+            ensure!(bx <= pc + 1, "FORLOOP at {pc} jumps before the function");
+
+            lifter.branch(Target::Pc(pc + 1 - bx), move |target| {
+                Instruction::ForNumLoop(ForNumLoop {
+                    target,
+                    index: a,
+                    limit: a + 1,
+                    step: a + 2,
+                    var: a + 3,
+                })
+            })
+        }
+        // the closing value at R(A + 3) is not represented in the IL
+        OpCode::TForPrep => lifter.branch(Target::Pc(pc + 1 + bx), move |target| {
+            Instruction::ForGenPrep(ForGenPrep { target, base: a })
+        }),
+        OpCode::TForCall => {
+            ensure!(
+                matches!(
+                    code.get(pc + 1).map(|&next| opcode(next)),
+                    Some(Ok(OpCode::TForLoop))
+                ),
+                "TFORCALL at {pc} is not followed by TFORLOOP"
+            );
+
+            lifter.push(Instruction::ForGenCall(ForGenCall {
+                base: a,
+                dest: a + 4,
+                count: c,
+            }));
+        }
+        OpCode::TForLoop => {
+            ensure!(bx <= pc + 1, "TFORLOOP at {pc} jumps before the function");
+
+            lifter.branch(Target::Pc(pc + 1 - bx), move |target| {
+                Instruction::ForGenLoop(ForGenLoop {
+                    target,
+                    base: a,
+                    var: a + 4,
+                })
+            })
+        }
+
+        OpCode::VarArg => lifter.push(Instruction::VarArg(VarArg {
+            dest: a,
+            count: match c {
+                0 => OptVariable::Variable,
+                c => OptVariable::Number(c - 1),
+            },
+        })),
+
+        OpCode::Tbc => bail!("{op:?} cannot be represented in LUNIR IL"),
+    }
+
+    Ok(())
+}
+
+/// Decodes the line of every instruction from the line deltas and the absolute line
+/// numbers that `encode_lines` produces.
+pub(super) fn decode_lines(
+    relative: &[i8],
+    absolute: &[(usize, u32)],
+    line_defined: u32,
+) -> Result<Vec<u32>> {
+    let mut absolute = absolute.iter();
+    let mut line = line_defined as i64;
+
+    relative
+        .iter()
+        .enumerate()
+        .map(|(pc, &delta)| {
+            if delta == ABSOLUTE_LINE {
+                line = match absolute.next() {
+                    Some(&(at, absolute)) if at == pc => absolute as i64,
+                    _ => bail!("missing absolute line for instruction {pc}"),
+                };
+            } else {
+                line += delta as i64;
+            }
+
+            Ok(line as u32)
+        })
+        .collect()
+}
+
+/// Reads the primitive types of a Lua 5.4 binary chunk.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.position..)
+            .and_then(|rest| rest.get(..count))
+            .with_context(|| format!("unexpected end of chunk at offset {}", self.position))?;
+
+        self.position += count;
+
+        Ok(bytes)
+    }
+
+    /// Checks that `count` items of at least one byte each fit in the rest of the chunk, so
+    /// a corrupted count cannot request an enormous allocation.
+    fn check_count(&self, count: usize, what: &str) -> Result<usize> {
+        let remaining = self.bytes.len() - self.position;
+        ensure!(
+            count <= remaining,
+            "{what} count {count} exceeds the {remaining} remaining bytes"
+        );
+
+        Ok(count)
+    }
+
+    fn read_byte(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Reads a size stored as 7 bit groups, most significant first, with the high bit set on
+    /// the last group.
+    fn read_size(&mut self) -> Result<usize> {
+        let mut value = 0usize;
+
+        loop {
+            ensure!(value.leading_zeros() >= 7, "size does not fit in usize");
+
+            let byte = self.read_byte()?;
+            value = (value << 7) | (byte & 0x7f) as usize;
+
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn read_string(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.read_size()? {
+            0 => Ok(None),
+            size => Ok(Some(self.read_bytes(size - 1)?.to_vec())),
+        }
+    }
+
+    /// Reads the source or variable name of the debug information, which is only used for
+    /// display, so any bytes that are not valid UTF-8 are replaced.
+    fn read_name(&mut self) -> Result<Option<String>> {
+        Ok(self
+            .read_string()?
+            .map(|name| String::from_utf8_lossy(&name).into_owned()))
+    }
+
+    fn read_header(&mut self) -> Result<()> {
+        ensure!(self.read_bytes(4)? == SIGNATURE, "not a Lua binary chunk");
+
+        let version = self.read_byte()?;
+        ensure!(
+            version == VERSION,
+            "expected a Lua 5.4 binary chunk, got version {version:#x}"
+        );
+
+        let format = self.read_byte()?;
+        ensure!(format == FORMAT, "unsupported chunk format {format}");
+
+        ensure!(
+            self.read_bytes(DATA.len())? == DATA,
+            "chunk was corrupted by a text mode conversion"
+        );
+
+        let sizes = self.read_bytes(3)?;
+        ensure!(
+            sizes == [4, 8, 8],
+            "unsupported instruction, integer or number sizes {sizes:?}"
+        );
+
+        ensure!(
+            self.read_u64()? as i64 == CHECK_INTEGER,
+            "unsupported integer format"
+        );
+        ensure!(
+            f64::from_bits(self.read_u64()?) == CHECK_NUMBER,
+            "unsupported number format"
+        );
+
+        Ok(())
+    }
+
+    /// Reads a function nested `depth` functions deep in the main one.
+    fn read_function(&mut self, parent_source: Option<&str>, depth: usize) -> Result<Prototype> {
+        check_nesting(depth)?;
+
+        // nested functions store no source when it is the same as their parent's
+        let source = self
+            .read_name()?
+            .or_else(|| parent_source.map(str::to_owned));
+
+        let line_defined = self.read_size()?;
+        let last_line_defined = self.read_size()?;
+
+        let param_count = self.read_byte()?;
+        let is_vararg = self.read_byte()? != 0;
+        let max_stack_size = self.read_byte()?;
+
+        let code_size = self.read_size()?;
+        let code = (0..code_size)
+            .map(|_| Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap())))
+            .collect::<Result<Vec<_>>>()?;
+
+        let constant_count = self.read_size()?;
+        let constant_count = self.check_count(constant_count, "constant")?;
+        let mut constants = Vec::with_capacity(constant_count);
+
+        for index in 0..constant_count {
+            constants.push(match self.read_byte()? {
+                0x00 => Constant::Nil,
+                0x01 => Constant::Boolean(false),
+                0x11 => Constant::Boolean(true),
+                0x03 => Constant::Integer(self.read_u64()? as i64),
+                0x13 => Constant::Number(f64::from_bits(self.read_u64()?)),
+                0x04 | 0x14 => Constant::String(self.read_string()?.unwrap_or_default()),
+                tag => bail!("constant {index} has unknown type {tag:#x}"),
+            });
+        }
+
+        // upvalues, as (instack, idx, kind) triples
+        let upvalue_count = self.read_size()?;
+        let upvalues = (0..upvalue_count)
+            .map(|_| {
+                let description = self.read_bytes(3)?;
+
+                Ok((description[0] != 0, description[1] as usize))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let prototype_count = self.read_size()?;
+        let prototypes = (0..prototype_count)
+            .map(|_| self.read_function(source.as_deref(), depth + 1))
+            .collect::<Result<Vec<_>>>()?;
+
+        let relative_size = self.read_size()?;
+        let relative = self
+            .read_bytes(relative_size)?
+            .iter()
+            .map(|&delta| delta as i8)
+            .collect::<Vec<_>>();
+
+        let absolute_size = self.read_size()?;
+        let absolute = (0..absolute_size)
+            .map(|_| Ok((self.read_size()?, self.read_size()? as u32)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let lineinfo = decode_lines(&relative, &absolute, line_defined as u32)?;
+
+        let local_count = self.read_size()?;
+        let locals = (0..local_count)
+            .map(|_| {
+                let name = self.read_name()?.unwrap_or_default();
+
+                Ok((name, self.read_size()?, self.read_size()?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let upvalue_name_count = self.read_size()?;
+        let upvalue_names = (0..upvalue_name_count)
+            .map(|_| Ok(self.read_name()?.unwrap_or_default()))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Prototype {
+            source,
+            line_defined,
+            last_line_defined,
+            param_count,
+            is_vararg,
+            max_stack_size,
+            code,
+            constants,
+            upvalues,
+            prototypes,
+            lineinfo,
+            locals,
+            upvalue_names,
+        })
+    }
+}
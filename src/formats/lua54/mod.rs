@@ -0,0 +1,260 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod deserializer;
+mod serializer;
+mod tests;
+
+pub use deserializer::*;
+pub use serializer::*;
+
+/// The signature every Lua 5.4 binary chunk starts with.
+pub const SIGNATURE: &[u8; 4] = b"\x1bLua";
+
+/// The version byte of Lua 5.4 binary chunks.
+pub const VERSION: u8 = 0x54;
+
+/// The format byte of the official Lua 5.4 binary chunk format.
+pub const FORMAT: u8 = 0;
+
+/// Bytes used to detect binary chunks that were corrupted by text mode conversions.
+pub const DATA: &[u8; 6] = b"\x19\x93\r\n\x1a\n";
+
+/// The integer written to the header to check the size and byte order of `lua_Integer`.
+pub const CHECK_INTEGER: i64 = 0x5678;
+
+/// The float written to the header to check the format of `lua_Number`.
+pub const CHECK_NUMBER: f64 = 370.5;
+
+/// The maximum number of registers a Lua 5.4 function may use.
+pub const MAX_STACK: usize = 255;
+
+/// The maximum number of upvalues a Lua 5.4 function may capture, including `_ENV`.
+pub const MAX_UPVALUES: usize = 255;
+
+/// The maximum number of array items a single `SETLIST` stores.
+pub const FIELDS_PER_FLUSH: usize = 50;
+
+/// The longest string the virtual machine interns as a short string, only short strings
+/// can be used as the key of `GETFIELD` and friends.
+pub const MAX_SHORT_STRING: usize = 40;
+
+pub(crate) const SIZE_OP: u32 = 7;
+pub(crate) const SIZE_A: u32 = 8;
+pub(crate) const SIZE_B: u32 = 8;
+pub(crate) const SIZE_C: u32 = 8;
+pub(crate) const SIZE_BX: u32 = SIZE_C + SIZE_B + 1;
+pub(crate) const SIZE_AX: u32 = SIZE_BX + SIZE_A;
+pub(crate) const SIZE_SJ: u32 = SIZE_AX;
+
+pub(crate) const POS_A: u32 = SIZE_OP;
+pub(crate) const POS_K: u32 = POS_A + SIZE_A;
+pub(crate) const POS_B: u32 = POS_K + 1;
+pub(crate) const POS_C: u32 = POS_B + SIZE_B;
+pub(crate) const POS_BX: u32 = POS_K;
+pub(crate) const POS_AX: u32 = POS_A;
+pub(crate) const POS_SJ: u32 = POS_A;
+
+pub(crate) const MAXARG_B: usize = (1 << SIZE_B) - 1;
+pub(crate) const MAXARG_C: usize = (1 << SIZE_C) - 1;
+pub(crate) const MAXARG_BX: usize = (1 << SIZE_BX) - 1;
+pub(crate) const MAXARG_AX: usize = (1 << SIZE_AX) - 1;
+pub(crate) const OFFSET_SBX: i64 = (MAXARG_BX >> 1) as i64;
+pub(crate) const OFFSET_SJ: i64 = ((1 << SIZE_SJ) - 1) >> 1;
+pub(crate) const OFFSET_SC: i64 = (MAXARG_C >> 1) as i64;
+
+/// All Lua 5.4 opcodes, in the order of the reference implementation's `lopcodes.h`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OpCode {
+    Move,
+    LoadI,
+    LoadF,
+    LoadK,
+    LoadKX,
+    LoadFalse,
+    LFalseSkip,
+    LoadTrue,
+    LoadNil,
+    GetUpval,
+    SetUpval,
+    GetTabUp,
+    GetTable,
+    GetI,
+    GetField,
+    SetTabUp,
+    SetTable,
+    SetI,
+    SetField,
+    NewTable,
+    SelfOp,
+    AddI,
+    AddK,
+    SubK,
+    MulK,
+    ModK,
+    PowK,
+    DivK,
+    IDivK,
+    BAndK,
+    BOrK,
+    BXorK,
+    ShrI,
+    ShlI,
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    MmBin,
+    MmBinI,
+    MmBinK,
+    Unm,
+    BNot,
+    Not,
+    Len,
+    Concat,
+    Close,
+    Tbc,
+    Jmp,
+    Eq,
+    Lt,
+    Le,
+    EqK,
+    EqI,
+    LtI,
+    LeI,
+    GtI,
+    GeI,
+    Test,
+    TestSet,
+    Call,
+    TailCall,
+    Return,
+    Return0,
+    Return1,
+    ForLoop,
+    ForPrep,
+    TForPrep,
+    TForCall,
+    TForLoop,
+    SetList,
+    Closure,
+    VarArg,
+    VarArgPrep,
+    ExtraArg,
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if value <= OpCode::ExtraArg as u8 {
+            // SAFETY: `OpCode` is `repr(u8)` with contiguous discriminants up to `ExtraArg`
+            Ok(unsafe { std::mem::transmute::<u8, OpCode>(value) })
+        } else {
+            Err(value)
+        }
+    }
+}
+
+/// The metamethod events named by `MMBIN` instructions, in the order of `ltm.h`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TagMethod {
+    Index,
+    NewIndex,
+    Gc,
+    Mode,
+    Len,
+    Eq,
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    Unm,
+    BNot,
+    Lt,
+    Le,
+    Concat,
+    Call,
+    Close,
+}
+
+/// Encodes an instruction in the `iABC` format.
+pub(crate) fn encode_abc(op: OpCode, a: usize, b: usize, c: usize, k: bool) -> u32 {
+    (op as u32)
+        | ((a as u32) << POS_A)
+        | ((k as u32) << POS_K)
+        | ((b as u32) << POS_B)
+        | ((c as u32) << POS_C)
+}
+
+/// Encodes an instruction in the `iABx` format.
+pub(crate) fn encode_abx(op: OpCode, a: usize, bx: usize) -> u32 {
+    (op as u32) | ((a as u32) << POS_A) | ((bx as u32) << POS_BX)
+}
+
+/// Encodes an instruction in the `iAsBx` format.
+pub(crate) fn encode_asbx(op: OpCode, a: usize, sbx: i64) -> u32 {
+    encode_abx(op, a, (sbx + OFFSET_SBX) as usize)
+}
+
+/// Encodes an instruction in the `iAx` format.
+pub(crate) fn encode_ax(op: OpCode, ax: usize) -> u32 {
+    (op as u32) | ((ax as u32) << POS_AX)
+}
+
+/// Encodes an instruction in the `isJ` format.
+pub(crate) fn encode_sj(op: OpCode, sj: i64) -> u32 {
+    (op as u32) | (((sj + OFFSET_SJ) as u32) << POS_SJ)
+}
+
+/// Returns whether `value` can be encoded in a signed `sB` or `sC` operand.
+pub(crate) fn fits_sc(value: i64) -> bool {
+    (-OFFSET_SC..=MAXARG_C as i64 - OFFSET_SC).contains(&value)
+}
+
+/// Returns whether `value` can be encoded in a signed `sBx` operand.
+pub(crate) fn fits_sbx(value: i64) -> bool {
+    (-OFFSET_SBX..=MAXARG_BX as i64 - OFFSET_SBX).contains(&value)
+}
+
+/// Encodes `value` as a signed `sB` or `sC` operand.
+pub(crate) fn int_to_sc(value: i64) -> usize {
+    (value + OFFSET_SC) as usize
+}
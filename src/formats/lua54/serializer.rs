@@ -0,0 +1,1511 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use super::*;
+use crate::formats::{numeric_for_window, pc_at, Feature, LoopLayout, Serializer};
+use crate::ir::il::{
+    registers_used, resolve_labels, BinaryOpKind, ConditionKind, Constant, Function, Instruction,
+    Intrinsic, IntrinsicKind, IntrinsicSource, Label, OptVariable, Table, UnaryOpKind, Value,
+};
+use anyhow::{bail, ensure, Context, Result};
+use derive_builder::Builder;
+use std::collections::HashMap;
+
+/// Marks an entry of `lineinfo` whose line is stored in `abslineinfo`.
+pub(super) const ABSOLUTE_LINE: i8 = -0x80;
+
+/// The largest line difference that can be stored in `lineinfo`.
+const MAX_LINE_DIFF: i64 = 0x80;
+
+/// The most instructions that may follow each other without absolute line information.
+const MAX_WITHOUT_ABSOLUTE: usize = 128;
+
+/// A constant as it is stored in a Lua 5.4 constant table, floats are kept as their bit
+/// pattern so that constants can be deduplicated.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Lua54Constant {
+    Nil,
+    Boolean(bool),
+    Float(u64),
+    Integer(i64),
+    String(Vec<u8>),
+}
+
+/// Serializes LUNIR intermediate language into a Lua 5.4 binary chunk that can be loaded
+/// by `lua_load`. Multi-byte values are written in little endian byte order with 8 byte
+/// integers and floats, as in a stock 64-bit build.
+#[derive(Builder, Clone, Debug, Default)]
+#[builder(default)]
+pub struct Lua54Serializer {
+    /// The chunk name stored as the `source` of the main function, in place of the one in
+    /// its debug information.
+    #[builder(setter(into, strip_option))]
+    source: Option<String>,
+    /// Whether line information and other debug information should be omitted from the
+    /// emitted chunk.
+    strip_debug: bool,
+}
+
+impl Lua54Serializer {
+    /// Creates a builder for a `Lua54Serializer`.
+    pub fn builder() -> Lua54SerializerBuilder {
+        Lua54SerializerBuilder::default()
+    }
+}
+
+impl Serializer for Lua54Serializer {
+    /// Serializes `function` as the main function of a binary chunk.
+    fn serialize(&self, function: &Function) -> Result<Vec<u8>> {
+        let state = FunctionState::lower(function)?;
+
+        let mut writer = Writer {
+            buf: Vec::with_capacity(256),
+        };
+
+        writer.write_header();
+
+        writer.write_byte(state.prototype.upvalues.len() as u8 + 1);
+        let source = self.source.as_ref().or(function.debug.source.as_ref());
+        writer.write_function(&state, source.map(String::as_str), self.strip_debug, true);
+
+        Ok(writer.buf)
+    }
+
+    fn supports(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::BitwiseOperators | Feature::IntegerDivision => true,
+        }
+    }
+
+    fn loop_layout(&self) -> LoopLayout {
+        LoopLayout::Lua54
+    }
+}
+
+/// Describes how an arithmetic or bitwise IL operation maps onto Lua 5.4 opcodes.
+struct Arithmetic {
+    op: OpCode,
+    /// The variant taking a constant as its second operand.
+    op_k: Option<OpCode>,
+    event: TagMethod,
+    commutative: bool,
+    /// Whether the constant variant only accepts integer constants.
+    integer_k: bool,
+}
+
+impl Arithmetic {
+    fn new(op: OpCode, op_k: Option<OpCode>, event: TagMethod) -> Self {
+        Self {
+            op,
+            op_k,
+            event,
+            commutative: matches!(
+                event,
+                TagMethod::Add
+                    | TagMethod::Mul
+                    | TagMethod::BAnd
+                    | TagMethod::BOr
+                    | TagMethod::BXor
+            ),
+            integer_k: matches!(event, TagMethod::BAnd | TagMethod::BOr | TagMethod::BXor),
+        }
+    }
+}
+
+/// The state of a single function while it is being lowered to Lua 5.4 opcodes.
+struct FunctionState<'a> {
+    prototype: &'a Function,
+
+    code: Vec<u32>,
+    lineinfo: Vec<u32>,
+    line: u32,
+
+    constants: Vec<Lua54Constant>,
+    constant_lookup: HashMap<Lua54Constant, usize>,
+    /// Maps the index of every constant in `prototype` to its index in `constants`, tables
+    /// have no mapping as they are built at the point of use instead.
+    constant_map: Vec<Option<usize>>,
+
+    /// The first register that is free to be used as a temporary.
+    base: usize,
+    /// The first register that is not currently holding a temporary.
+    top: usize,
+    max_stack_size: usize,
+
+    /// The program counter at which the lowering of each IL instruction begins.
+    pcs: Vec<usize>,
+    /// Jumps which need their offset patched, as (program counter, IL target) pairs.
+    fixups: Vec<(usize, usize)>,
+    /// The IL index of every label defined in the function.
+    labels: HashMap<Label, usize>,
+
+    /// The lowered prototypes of the functions nested in this one.
+    children: Vec<FunctionState<'a>>,
+    /// Whether a closure captures a register of this function, in which case returns have
+    /// to close its upvalues.
+    captures_registers: bool,
+}
+
+impl<'a> FunctionState<'a> {
+    fn lower(prototype: &'a Function) -> Result<Self> {
+        let instructions = prototype.chunk.inner();
+
+        // `_ENV` takes up one upvalue of every function
+        ensure!(
+            prototype.upvalues.len() < MAX_UPVALUES,
+            "function has {} upvalues but Lua 5.4 only allows {}",
+            prototype.upvalues.len(),
+            MAX_UPVALUES - 1
+        );
+
+        ensure!(
+            !prototype.arity.needs_arg,
+            "Lua 5.4 has no implicit `arg` table for variadic functions"
+        );
+
+        let children = prototype
+            .prototypes
+            .iter()
+            .enumerate()
+            .map(|(index, child)| {
+                Self::lower(child).with_context(|| format!("failed to lower prototype {index}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let captures_registers = prototype
+            .prototypes
+            .iter()
+            .flat_map(|child| &child.upvalues)
+            .any(|upvalue| upvalue.in_stack);
+
+        let base = instructions
+            .iter()
+            .map(registers_used)
+            .max()
+            .unwrap_or(0)
+            .max(prototype.max_stack_size as usize)
+            .max(prototype.arity.params as usize);
+
+        let mut state = Self {
+            prototype,
+            code: Vec::with_capacity(instructions.len()),
+            lineinfo: Vec::with_capacity(instructions.len()),
+            line: 0,
+            constants: Vec::with_capacity(prototype.constants.len()),
+            constant_lookup: HashMap::new(),
+            constant_map: Vec::with_capacity(prototype.constants.len()),
+            base,
+            top: base,
+            max_stack_size: base.max(2),
+            pcs: Vec::with_capacity(instructions.len() + 1),
+            fixups: Vec::new(),
+            labels: resolve_labels(instructions)?,
+            children,
+            captures_registers,
+        };
+
+        for constant in &prototype.constants {
+            let mapped = match constant {
+                Constant::Nil => Some(state.constant(Lua54Constant::Nil)),
+                Constant::Boolean(b) => Some(state.constant(Lua54Constant::Boolean(*b))),
+                Constant::Number(n) => Some(state.constant(Lua54Constant::Float(n.to_bits()))),
+                Constant::Integer(n) => Some(state.constant(Lua54Constant::Integer(*n))),
+                Constant::String(s) => Some(state.constant(Lua54Constant::String(s.clone()))),
+                Constant::Table(_) => None,
+            };
+
+            state.constant_map.push(mapped);
+        }
+
+        // instructions without a span keep the line of the instruction before them
+        if let Some(span) = prototype.chunk.spanned().find_map(|(_, span)| span) {
+            state.line = span.line;
+        }
+
+        if prototype.arity.is_vararg {
+            state.emit(encode_abc(
+                OpCode::VarArgPrep,
+                prototype.arity.params as usize,
+                0,
+                0,
+                false,
+            ));
+        }
+
+        for (index, (instruction, span)) in prototype.chunk.spanned().enumerate() {
+            state.pcs.push(state.code.len());
+
+            if let Some(span) = span {
+                state.line = span.line;
+            }
+
+            state
+                .lower_instruction(instruction)
+                .with_context(|| format!("failed to lower instruction {index}: {instruction:?}"))?;
+
+            state.top = state.base;
+        }
+
+        state.pcs.push(state.code.len());
+
+        if !matches!(instructions.last(), Some(Instruction::Return(_))) {
+            state.emit_return(0, &OptVariable::Number(0));
+        }
+
+        state.patch_jumps()?;
+
+        ensure!(
+            state.max_stack_size <= MAX_STACK,
+            "function needs {} registers but Lua 5.4 only allows {MAX_STACK}",
+            state.max_stack_size
+        );
+
+        Ok(state)
+    }
+
+    fn emit(&mut self, instruction: u32) -> usize {
+        self.code.push(instruction);
+        self.lineinfo.push(self.line);
+
+        self.code.len() - 1
+    }
+
+    fn constant(&mut self, constant: Lua54Constant) -> usize {
+        if let Some(&index) = self.constant_lookup.get(&constant) {
+            return index;
+        }
+
+        let index = self.constants.len();
+        self.constants.push(constant.clone());
+        self.constant_lookup.insert(constant, index);
+
+        index
+    }
+
+    fn temporary(&mut self) -> Result<usize> {
+        let register = self.top;
+
+        self.top += 1;
+        self.max_stack_size = self.max_stack_size.max(self.top);
+
+        ensure!(
+            self.top <= MAX_STACK,
+            "ran out of registers for temporaries"
+        );
+
+        Ok(register)
+    }
+
+    /// Returns the output index of a constant `value`, if it is a scalar constant.
+    fn scalar_constant(&mut self, value: &Value) -> Option<usize> {
+        match value {
+            Value::Nil => Some(self.constant(Lua54Constant::Nil)),
+            Value::Boolean(b) => Some(self.constant(Lua54Constant::Boolean(*b))),
+            Value::Immediate(i) => Some(self.constant(Lua54Constant::Integer(*i as i64))),
+            Value::ConstantIndex(index) => self.constant_map.get(*index).copied().flatten(),
+            Value::StackIndex(_) => None,
+        }
+    }
+
+    /// Returns the output index of `value` if it is a numeric constant.
+    fn number_constant(&mut self, value: &Value, integer_only: bool) -> Option<usize> {
+        match value {
+            Value::Immediate(i) => Some(self.constant(Lua54Constant::Integer(*i as i64))),
+            Value::ConstantIndex(index) => match self.prototype.constants.get(*index) {
+                Some(Constant::Integer(_)) => self.constant_map[*index],
+                Some(Constant::Number(_)) if !integer_only => self.constant_map[*index],
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns the output index of the IL constant `index` if it is a short string that
+    /// fits into a `B` or `C` operand.
+    fn short_string(&self, index: usize) -> Option<usize> {
+        match self.prototype.constants.get(index) {
+            Some(Constant::String(s)) if s.len() <= MAX_SHORT_STRING => {
+                self.constant_map[index].filter(|&k| k <= MAXARG_C)
+            }
+            _ => None,
+        }
+    }
+
+    fn load_k(&mut self, register: usize, index: usize) -> Result<()> {
+        if index <= MAXARG_BX {
+            self.emit(encode_abx(OpCode::LoadK, register, index));
+        } else {
+            ensure!(index <= MAXARG_AX, "constant {index} is out of range");
+
+            self.emit(encode_abx(OpCode::LoadKX, register, 0));
+            self.emit(encode_ax(OpCode::ExtraArg, index));
+        }
+
+        Ok(())
+    }
+
+    /// Emits the instructions needed to place `value` into `register`.
+    fn load_into(&mut self, register: usize, value: &Value) -> Result<()> {
+        match value {
+            Value::Nil => {
+                self.emit(encode_abc(OpCode::LoadNil, register, 0, 0, false));
+            }
+            Value::Boolean(true) => {
+                self.emit(encode_abc(OpCode::LoadTrue, register, 0, 0, false));
+            }
+            Value::Boolean(false) => {
+                self.emit(encode_abc(OpCode::LoadFalse, register, 0, 0, false));
+            }
+            Value::Immediate(i) => {
+                let i = *i as i64;
+
+                if fits_sbx(i) {
+                    self.emit(encode_asbx(OpCode::LoadI, register, i));
+                } else {
+                    let constant = self.constant(Lua54Constant::Integer(i));
+                    self.load_k(register, constant)?;
+                }
+            }
+            Value::StackIndex(source) => {
+                if *source != register {
+                    self.emit(encode_abc(OpCode::Move, register, *source, 0, false));
+                }
+            }
+            Value::ConstantIndex(index) => {
+                let prototype = self.prototype;
+
+                match prototype.constants.get(*index) {
+                    Some(Constant::Table(table)) => self.load_table(register, table)?,
+                    // the integer operand of `LOADF` would lose the sign of negative zero
+                    Some(Constant::Number(n))
+                        if n.fract() == 0.0
+                            && fits_sbx(*n as i64)
+                            && !(*n == 0.0 && n.is_sign_negative()) =>
+                    {
+                        self.emit(encode_asbx(OpCode::LoadF, register, *n as i64));
+                    }
+                    Some(_) => self.load_k(register, self.constant_map[*index].unwrap())?,
+                    None => bail!("constant {index} does not exist"),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds a table constant in `register`, storing array items in batches of
+    /// `FIELDS_PER_FLUSH` with `SETLIST`.
+    fn load_table(&mut self, register: usize, table: &Table) -> Result<()> {
+        let mark = self.top;
+        let table_register = self.temporary()?;
+
+        match table {
+            Table::Array(items) => {
+                self.new_table(table_register, items.len(), 0);
+
+                for (batch, items) in items.chunks(FIELDS_PER_FLUSH).enumerate() {
+                    for item in items {
+                        let item_register = self.temporary()?;
+                        self.load_into(item_register, item)?;
+                        self.top = item_register + 1;
+                    }
+
+                    self.set_list(table_register, items.len(), batch * FIELDS_PER_FLUSH)?;
+
+                    self.top = table_register + 1;
+                }
+            }
+            Table::Map(map) => {
+                self.new_table(table_register, 0, map.len());
+
+                for (key, value) in map {
+                    ensure!(*key != Value::Nil, "table constant has a nil key");
+
+                    self.store(table_register, key, value)?;
+                    self.top = table_register + 1;
+                }
+            }
+        }
+
+        if register != table_register {
+            self.emit(encode_abc(OpCode::Move, register, table_register, 0, false));
+        }
+
+        self.top = mark;
+
+        Ok(())
+    }
+
+    /// Stores `value` into `table[key]`, preferring the `SETFIELD` and `SETI` forms.
+    fn store(&mut self, table: usize, key: &Value, value: &Value) -> Result<()> {
+        let (value, is_constant) = self.rk(value)?;
+
+        match key {
+            Value::ConstantIndex(index) if self.short_string(*index).is_some() => {
+                let key = self.short_string(*index).unwrap();
+
+                self.emit(encode_abc(OpCode::SetField, table, key, value, is_constant));
+            }
+            Value::Immediate(n @ 0..=255) => {
+                self.emit(encode_abc(
+                    OpCode::SetI,
+                    table,
+                    *n as usize,
+                    value,
+                    is_constant,
+                ));
+            }
+            _ => {
+                let key = self.register(key)?;
+
+                self.emit(encode_abc(OpCode::SetTable, table, key, value, is_constant));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Emits a `SETLIST` storing `count` values above `table` from index `offset + 1`,
+    /// followed by an `EXTRAARG` when the offset does not fit into `C`.
+    fn set_list(&mut self, table: usize, count: usize, offset: usize) -> Result<()> {
+        ensure!(count <= MAXARG_B, "too many values in SETLIST");
+
+        if offset <= MAXARG_C {
+            self.emit(encode_abc(OpCode::SetList, table, count, offset, false));
+        } else {
+            ensure!(
+                offset / (MAXARG_C + 1) <= MAXARG_AX,
+                "SETLIST offset {offset} is out of range"
+            );
+
+            self.emit(encode_abc(
+                OpCode::SetList,
+                table,
+                count,
+                offset % (MAXARG_C + 1),
+                true,
+            ));
+            self.emit(encode_ax(OpCode::ExtraArg, offset / (MAXARG_C + 1)));
+        }
+
+        Ok(())
+    }
+
+    fn new_table(&mut self, register: usize, array_size: usize, hash_size: usize) {
+        let hash_size = if hash_size == 0 {
+            0
+        } else {
+            hash_size.next_power_of_two().trailing_zeros() as usize + 1
+        };
+
+        // NEWTABLE is always followed by an EXTRAARG holding the high bits of the array size
+        self.emit(encode_abc(
+            OpCode::NewTable,
+            register,
+            hash_size,
+            array_size % (MAXARG_C + 1),
+            array_size > MAXARG_C,
+        ));
+        self.emit(encode_ax(OpCode::ExtraArg, array_size / (MAXARG_C + 1)));
+    }
+
+    /// Returns a register holding `value`, loading it into a temporary if needed.
+    fn register(&mut self, value: &Value) -> Result<usize> {
+        match value {
+            Value::StackIndex(index) => Ok(*index),
+            _ => {
+                let register = self.temporary()?;
+                self.load_into(register, value)?;
+
+                Ok(register)
+            }
+        }
+    }
+
+    /// Encodes `value` as the `RK` operand of a store, returning the operand and whether it
+    /// refers to a constant.
+    fn rk(&mut self, value: &Value) -> Result<(usize, bool)> {
+        match self.scalar_constant(value) {
+            Some(constant) if constant <= MAXARG_C => Ok((constant, true)),
+            _ => Ok((self.register(value)?, false)),
+        }
+    }
+
+    fn jump(&mut self, target: usize) {
+        let pc = self.emit(encode_sj(OpCode::Jmp, 0));
+        self.fixups.push((pc, target));
+    }
+
+    /// Emits a loop instruction whose `Bx` operand leads to the IL instruction `target`.
+    fn branch(&mut self, op: OpCode, a: usize, target: usize) {
+        let pc = self.emit(encode_abx(op, a, 0));
+        self.fixups.push((pc, target));
+    }
+
+    /// Returns the IL index of the instruction marking `label`.
+    fn target(&self, label: Label) -> usize {
+        self.labels[&label]
+    }
+
+    /// Returns the IL instruction at `offset` from the one currently being lowered.
+    fn neighbour(&self, offset: isize) -> Option<&'a Instruction> {
+        let index = usize::try_from(self.pcs.len() as isize - 1 + offset).ok()?;
+
+        self.prototype.chunk.inner().get(index)
+    }
+
+    fn emit_return(&mut self, start: usize, count: &OptVariable) {
+        let results = match count {
+            OptVariable::Variable => 0,
+            OptVariable::Number(n) => n + 1,
+        };
+
+        // variadic functions restore their frame from the parameter count in C
+        let arity = self.prototype.arity;
+        let frame = if arity.is_vararg {
+            arity.params as usize + 1
+        } else {
+            0
+        };
+
+        self.emit(encode_abc(
+            OpCode::Return,
+            start,
+            results,
+            frame,
+            self.captures_registers,
+        ));
+    }
+
+    /// Lowers an arithmetic or bitwise operation, preferring the immediate and constant
+    /// forms, followed by the `MMBIN` instruction executed when a metamethod is needed.
+    fn arithmetic(
+        &mut self,
+        dest: usize,
+        arithmetic: Arithmetic,
+        left: &Value,
+        right: &Value,
+    ) -> Result<()> {
+        let immediate = |value: &Value| match value {
+            Value::Immediate(i) if fits_sc(*i as i64) => Some(*i as i64),
+            _ => None,
+        };
+
+        let event = arithmetic.event as usize;
+
+        let immediate_form = match (arithmetic.event, immediate(left), immediate(right)) {
+            (TagMethod::Add, _, Some(i)) => Some((OpCode::AddI, left, i, i, false)),
+            (TagMethod::Add, Some(i), _) => Some((OpCode::AddI, right, i, i, true)),
+            (TagMethod::Sub, _, Some(i)) if fits_sc(-i) => Some((OpCode::AddI, left, -i, i, false)),
+            (TagMethod::Shr, _, Some(i)) => Some((OpCode::ShrI, left, i, i, false)),
+            (TagMethod::Shl, Some(i), _) => Some((OpCode::ShlI, right, i, i, true)),
+            (TagMethod::Shl, _, Some(i)) if fits_sc(-i) => Some((OpCode::ShrI, left, -i, i, false)),
+            _ => None,
+        };
+
+        if let Some((op, operand, immediate, original, flip)) = immediate_form {
+            let operand = self.register(operand)?;
+
+            self.emit(encode_abc(op, dest, operand, int_to_sc(immediate), false));
+            self.emit(encode_abc(
+                OpCode::MmBinI,
+                operand,
+                int_to_sc(original),
+                event,
+                flip,
+            ));
+
+            return Ok(());
+        }
+
+        if let Some(op_k) = arithmetic.op_k {
+            let integer_only = arithmetic.integer_k;
+
+            let constant_form = match self
+                .number_constant(right, integer_only)
+                .filter(|&k| k <= MAXARG_C)
+            {
+                Some(k) => Some((left, k, false)),
+                None if arithmetic.commutative => self
+                    .number_constant(left, integer_only)
+                    .filter(|&k| k <= MAXARG_C)
+                    .map(|k| (right, k, true)),
+                None => None,
+            };
+
+            if let Some((operand, constant, flip)) = constant_form {
+                let operand = self.register(operand)?;
+
+                self.emit(encode_abc(op_k, dest, operand, constant, false));
+                self.emit(encode_abc(OpCode::MmBinK, operand, constant, event, flip));
+
+                return Ok(());
+            }
+        }
+
+        let left = self.register(left)?;
+        let right = self.register(right)?;
+
+        self.emit(encode_abc(arithmetic.op, dest, left, right, false));
+        self.emit(encode_abc(OpCode::MmBin, left, right, event, false));
+
+        Ok(())
+    }
+
+    /// Lowers the operation `arithmetic` on the register `source` and the integer `k`, which
+    /// is added to the constants.
+    fn arithmetic_k(
+        &mut self,
+        dest: usize,
+        source: usize,
+        arithmetic: Arithmetic,
+        k: i64,
+    ) -> Result<()> {
+        let constant = self.constant(Lua54Constant::Integer(k));
+        let event = arithmetic.event as usize;
+
+        match arithmetic.op_k.filter(|_| constant <= MAXARG_C) {
+            Some(op_k) => {
+                self.emit(encode_abc(op_k, dest, source, constant, false));
+                self.emit(encode_abc(OpCode::MmBinK, source, constant, event, false));
+            }
+            None => {
+                let register = self.temporary()?;
+                self.load_k(register, constant)?;
+
+                self.emit(encode_abc(arithmetic.op, dest, source, register, false));
+                self.emit(encode_abc(OpCode::MmBin, source, register, event, false));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Keeps the low 32 bits of `source` in `dest`.
+    fn truncate_32(&mut self, dest: usize, source: usize) -> Result<()> {
+        let band = Arithmetic::new(OpCode::BAnd, Some(OpCode::BAndK), TagMethod::BAnd);
+
+        self.arithmetic_k(dest, source, band, u32::MAX as i64)
+    }
+
+    /// Lowers a bitwise intrinsic to the 64 bit operators of Lua 5.4. The libraries work on
+    /// 32 bit integers instead, so their operands and results are truncated to 32 bits,
+    /// which `bit` also sign extends, and the shift counts of `bit` are masked to 5 bits.
+    fn intrinsic(&mut self, intrinsic: &Intrinsic) -> Result<()> {
+        let source = intrinsic.source;
+        let dest = intrinsic.dest;
+
+        let (arithmetic, left, right) = match &intrinsic.kind {
+            IntrinsicKind::BitAnd(l, r) => (
+                Arithmetic::new(OpCode::BAnd, Some(OpCode::BAndK), TagMethod::BAnd),
+                l,
+                r,
+            ),
+            IntrinsicKind::BitOr(l, r) => (
+                Arithmetic::new(OpCode::BOr, Some(OpCode::BOrK), TagMethod::BOr),
+                l,
+                r,
+            ),
+            IntrinsicKind::BitXor(l, r) => (
+                Arithmetic::new(OpCode::BXor, Some(OpCode::BXorK), TagMethod::BXor),
+                l,
+                r,
+            ),
+            IntrinsicKind::LeftShift(l, r) => {
+                (Arithmetic::new(OpCode::Shl, None, TagMethod::Shl), l, r)
+            }
+            IntrinsicKind::RightShift(l, r) => {
+                (Arithmetic::new(OpCode::Shr, None, TagMethod::Shr), l, r)
+            }
+            IntrinsicKind::BitNot(v) => {
+                let operand = self.register(v)?;
+                self.emit(encode_abc(OpCode::BNot, dest, operand, 0, false));
+
+                return self.truncate_result(source, dest);
+            }
+        };
+
+        let is_shift = matches!(arithmetic.event, TagMethod::Shl | TagMethod::Shr);
+
+        if source == IntrinsicSource::Operator || !is_shift {
+            self.arithmetic(dest, arithmetic, left, right)?;
+
+            return self.truncate_result(source, dest);
+        }
+
+        // the bits above the low 32 must not be shifted into them
+        let value = self.temporary()?;
+        let operand = self.register(left)?;
+        self.truncate_32(value, operand)?;
+
+        let count = match source {
+            IntrinsicSource::Bit => {
+                let count = self.temporary()?;
+                let operand = self.register(right)?;
+                let band = Arithmetic::new(OpCode::BAnd, Some(OpCode::BAndK), TagMethod::BAnd);
+                self.arithmetic_k(count, operand, band, 31)?;
+
+                Value::StackIndex(count)
+            }
+            _ => right.clone(),
+        };
+
+        self.arithmetic(dest, arithmetic, &Value::StackIndex(value), &count)?;
+        self.truncate_result(source, dest)
+    }
+
+    /// Truncates the result of an intrinsic called from `source` in place.
+    fn truncate_result(&mut self, source: IntrinsicSource, dest: usize) -> Result<()> {
+        match source {
+            IntrinsicSource::Operator => {}
+            IntrinsicSource::Bit32 => self.truncate_32(dest, dest)?,
+            IntrinsicSource::Bit => {
+                // (x & 0xffffffff ~ 0x80000000) - 0x80000000 sign extends the low 32 bits
+                let sign = 1 << 31;
+                let bxor = Arithmetic::new(OpCode::BXor, Some(OpCode::BXorK), TagMethod::BXor);
+                let sub = Arithmetic::new(OpCode::Sub, Some(OpCode::SubK), TagMethod::Sub);
+
+                self.truncate_32(dest, dest)?;
+                self.arithmetic_k(dest, dest, bxor, sign)?;
+                self.arithmetic_k(dest, dest, sub, sign)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn lower_instruction(&mut self, instruction: &Instruction) -> Result<()> {
+        match instruction {
+            Instruction::Label(_) => {}
+            Instruction::Load(load) => self.load_into(load.dest, &load.src)?,
+            Instruction::Move(mv) => {
+                if mv.dest != mv.src {
+                    self.emit(encode_abc(OpCode::Move, mv.dest, mv.src, 0, false));
+                }
+            }
+
+            Instruction::GetGlobal(get) => match self.short_string(get.constant) {
+                Some(key) => {
+                    self.emit(encode_abc(OpCode::GetTabUp, get.dest, 0, key, false));
+                }
+                None => {
+                    let environment = self.temporary()?;
+                    self.emit(encode_abc(OpCode::GetUpval, environment, 0, 0, false));
+
+                    let key = self.register(&Value::ConstantIndex(get.constant))?;
+                    self.emit(encode_abc(
+                        OpCode::GetTable,
+                        get.dest,
+                        environment,
+                        key,
+                        false,
+                    ));
+                }
+            },
+            Instruction::SetGlobal(set) => match self.short_string(set.constant) {
+                Some(key) => {
+                    self.emit(encode_abc(OpCode::SetTabUp, 0, key, set.src, false));
+                }
+                None => {
+                    let environment = self.temporary()?;
+                    self.emit(encode_abc(OpCode::GetUpval, environment, 0, 0, false));
+
+                    let key = self.register(&Value::ConstantIndex(set.constant))?;
+                    self.emit(encode_abc(
+                        OpCode::SetTable,
+                        environment,
+                        key,
+                        set.src,
+                        false,
+                    ));
+                }
+            },
+
+            Instruction::GetTable(get) => match &get.key {
+                Value::ConstantIndex(index) if self.short_string(*index).is_some() => {
+                    let key = self.short_string(*index).unwrap();
+
+                    self.emit(encode_abc(
+                        OpCode::GetField,
+                        get.dest,
+                        get.source,
+                        key,
+                        false,
+                    ));
+                }
+                Value::Immediate(n @ 0..=255) => {
+                    self.emit(encode_abc(
+                        OpCode::GetI,
+                        get.dest,
+                        get.source,
+                        *n as usize,
+                        false,
+                    ));
+                }
+                key => {
+                    let key = self.register(key)?;
+                    self.emit(encode_abc(
+                        OpCode::GetTable,
+                        get.dest,
+                        get.source,
+                        key,
+                        false,
+                    ));
+                }
+            },
+
+            Instruction::SetTable(set) => self.store(set.table, &set.key, &set.value)?,
+            Instruction::SelfLookup(lookup) => match &lookup.key {
+                // the key of SELF must be a string constant
+                Value::ConstantIndex(index) if self.short_string(*index).is_some() => {
+                    let key = self.short_string(*index).unwrap();
+
+                    self.emit(encode_abc(
+                        OpCode::SelfOp,
+                        lookup.dest,
+                        lookup.object,
+                        key,
+                        true,
+                    ));
+                }
+                key => {
+                    let key = self.register(key)?;
+                    let method = self.temporary()?;
+
+                    self.emit(encode_abc(
+                        OpCode::GetTable,
+                        method,
+                        lookup.object,
+                        key,
+                        false,
+                    ));
+                    self.emit(encode_abc(
+                        OpCode::Move,
+                        lookup.dest + 1,
+                        lookup.object,
+                        0,
+                        false,
+                    ));
+                    self.emit(encode_abc(OpCode::Move, lookup.dest, method, 0, false));
+                }
+            },
+
+            Instruction::Concat(concat) => {
+                ensure!(
+                    concat.start < concat.end,
+                    "CONCAT needs at least two operands"
+                );
+
+                // CONCAT always leaves its result in the first operand
+                self.emit(encode_abc(
+                    OpCode::Concat,
+                    concat.start,
+                    concat.end - concat.start + 1,
+                    0,
+                    false,
+                ));
+
+                if concat.dest != concat.start {
+                    self.emit(encode_abc(
+                        OpCode::Move,
+                        concat.dest,
+                        concat.start,
+                        0,
+                        false,
+                    ));
+                }
+            }
+
+            Instruction::BinaryOp(op) => {
+                let arithmetic = match op.operator {
+                    BinaryOpKind::Add => {
+                        Arithmetic::new(OpCode::Add, Some(OpCode::AddK), TagMethod::Add)
+                    }
+                    BinaryOpKind::Sub => {
+                        Arithmetic::new(OpCode::Sub, Some(OpCode::SubK), TagMethod::Sub)
+                    }
+                    BinaryOpKind::Mul => {
+                        Arithmetic::new(OpCode::Mul, Some(OpCode::MulK), TagMethod::Mul)
+                    }
+                    BinaryOpKind::Div => {
+                        Arithmetic::new(OpCode::Div, Some(OpCode::DivK), TagMethod::Div)
+                    }
+                    BinaryOpKind::IDiv => {
+                        Arithmetic::new(OpCode::IDiv, Some(OpCode::IDivK), TagMethod::IDiv)
+                    }
+                    BinaryOpKind::Mod => {
+                        Arithmetic::new(OpCode::Mod, Some(OpCode::ModK), TagMethod::Mod)
+                    }
+                    BinaryOpKind::Pow => {
+                        Arithmetic::new(OpCode::Pow, Some(OpCode::PowK), TagMethod::Pow)
+                    }
+                    BinaryOpKind::Concat => {
+                        // CONCAT works in place on a range of registers
+                        let left = self.temporary()?;
+                        let right = self.temporary()?;
+
+                        self.load_into(left, &op.left)?;
+                        self.load_into(right, &op.right)?;
+
+                        self.emit(encode_abc(OpCode::Concat, left, 2, 0, false));
+                        self.emit(encode_abc(OpCode::Move, op.dest, left, 0, false));
+
+                        return Ok(());
+                    }
+                };
+
+                self.arithmetic(op.dest, arithmetic, &op.left, &op.right)?;
+            }
+
+            Instruction::UnaryOp(op) => {
+                let opcode = match op.operator {
+                    UnaryOpKind::Len => OpCode::Len,
+                    UnaryOpKind::Not => OpCode::Not,
+                    UnaryOpKind::Neg => OpCode::Unm,
+                };
+
+                let operand = self.register(&op.left)?;
+                self.emit(encode_abc(opcode, op.dest, operand, 0, false));
+            }
+
+            // Lua 5.4 has no `bit32` library, so calls into it are lowered to operators too
+            Instruction::Intrinsic(intrinsic) => self.intrinsic(intrinsic)?,
+
+            Instruction::Jump(jump) => self.jump(self.target(jump.target)),
+
+            Instruction::JumpNot(jump) => {
+                self.emit(encode_abc(OpCode::Test, jump.cond, 0, 0, false));
+                self.jump(self.target(jump.target));
+            }
+
+            Instruction::ConditionalJump(jump) => {
+                let condition = &jump.condition;
+                let target = self.target(jump.target);
+
+                // a test executes the following jump when its result matches k
+                match condition.kind {
+                    ConditionKind::And => {
+                        let left = self.register(&condition.left)?;
+                        let right = self.register(&condition.right)?;
+
+                        self.emit(encode_abc(OpCode::Test, left, 0, 0, false));
+                        self.emit(encode_sj(OpCode::Jmp, 2));
+                        self.emit(encode_abc(OpCode::Test, right, 0, 0, true));
+                        self.jump(target);
+                    }
+                    ConditionKind::Or => {
+                        let left = self.register(&condition.left)?;
+                        let right = self.register(&condition.right)?;
+
+                        self.emit(encode_abc(OpCode::Test, left, 0, 0, true));
+                        self.jump(target);
+                        self.emit(encode_abc(OpCode::Test, right, 0, 0, true));
+                        self.jump(target);
+                    }
+                    ConditionKind::Eq | ConditionKind::Ne => {
+                        let k = condition.kind == ConditionKind::Eq;
+
+                        let immediate = |value: &Value| match value {
+                            Value::Immediate(i) if fits_sc(*i as i64) => Some(*i as i64),
+                            _ => None,
+                        };
+
+                        let test = if let Some(i) = immediate(&condition.right) {
+                            let left = self.register(&condition.left)?;
+                            encode_abc(OpCode::EqI, left, int_to_sc(i), 0, k)
+                        } else if let Some(i) = immediate(&condition.left) {
+                            let right = self.register(&condition.right)?;
+                            encode_abc(OpCode::EqI, right, int_to_sc(i), 0, k)
+                        } else if let Some(constant) = self
+                            .scalar_constant(&condition.right)
+                            .filter(|&c| c <= MAXARG_B)
+                        {
+                            let left = self.register(&condition.left)?;
+                            encode_abc(OpCode::EqK, left, constant, 0, k)
+                        } else if let Some(constant) = self
+                            .scalar_constant(&condition.left)
+                            .filter(|&c| c <= MAXARG_B)
+                        {
+                            let right = self.register(&condition.right)?;
+                            encode_abc(OpCode::EqK, right, constant, 0, k)
+                        } else {
+                            let left = self.register(&condition.left)?;
+                            let right = self.register(&condition.right)?;
+                            encode_abc(OpCode::Eq, left, right, 0, k)
+                        };
+
+                        self.emit(test);
+                        self.jump(target);
+                    }
+                    _ => {
+                        let (op, op_i, op_i_swapped) = match condition.kind {
+                            ConditionKind::Lt => (OpCode::Lt, OpCode::LtI, OpCode::GtI),
+                            ConditionKind::Le => (OpCode::Le, OpCode::LeI, OpCode::GeI),
+                            ConditionKind::Gt => (OpCode::Lt, OpCode::GtI, OpCode::LtI),
+                            ConditionKind::Ge => (OpCode::Le, OpCode::GeI, OpCode::LeI),
+                            _ => unreachable!(),
+                        };
+
+                        let swapped =
+                            matches!(condition.kind, ConditionKind::Gt | ConditionKind::Ge);
+
+                        let test = match (&condition.left, &condition.right) {
+                            (left, Value::Immediate(i)) if fits_sc(*i as i64) => {
+                                let left = self.register(left)?;
+                                encode_abc(op_i, left, int_to_sc(*i as i64), 0, true)
+                            }
+                            (Value::Immediate(i), right) if fits_sc(*i as i64) => {
+                                let right = self.register(right)?;
+                                encode_abc(op_i_swapped, right, int_to_sc(*i as i64), 0, true)
+                            }
+                            (left, right) => {
+                                let mut left = self.register(left)?;
+                                let mut right = self.register(right)?;
+
+                                if swapped {
+                                    std::mem::swap(&mut left, &mut right);
+                                }
+
+                                encode_abc(op, left, right, 0, true)
+                            }
+                        };
+
+                        self.emit(test);
+                        self.jump(target);
+                    }
+                }
+            }
+
+            Instruction::ForNumPrep(prep) => {
+                numeric_for_window(prep.index, prep.limit, prep.step, prep.var)?;
+
+                self.branch(OpCode::ForPrep, prep.index, self.target(prep.target));
+            }
+            Instruction::ForNumLoop(next) => {
+                numeric_for_window(next.index, next.limit, next.step, next.var)?;
+
+                self.branch(OpCode::ForLoop, next.index, self.target(next.target));
+            }
+            Instruction::ForGenPrep(prep) => {
+                self.branch(OpCode::TForPrep, prep.base, self.target(prep.target));
+            }
+            // TFORCALL always continues with the TFORLOOP that follows it
+            Instruction::ForGenCall(call) => {
+                ensure!(
+                    call.dest == call.base + 4,
+                    "TFORCALL stores its results above the control and closing values"
+                );
+                ensure!(
+                    (1..=MAXARG_C).contains(&call.count),
+                    "TFORCALL needs between 1 and {MAXARG_C} results"
+                );
+
+                match self.neighbour(1) {
+                    Some(Instruction::ForGenLoop(next))
+                        if next.base == call.base && next.var == call.dest => {}
+                    _ => bail!("TFORCALL must be followed by the check of its loop"),
+                }
+
+                self.emit(encode_abc(
+                    OpCode::TForCall,
+                    call.base,
+                    0,
+                    call.count,
+                    false,
+                ));
+            }
+            Instruction::ForGenLoop(next) => {
+                ensure!(
+                    next.var == next.base + 4,
+                    "TFORLOOP checks the value above the closing value"
+                );
+
+                self.branch(OpCode::TForLoop, next.base, self.target(next.target));
+            }
+
+            Instruction::NewTable(table) => {
+                self.new_table(table.dest, table.array_size, table.table_size);
+            }
+            Instruction::SetList(list) => {
+                let count = match list.count {
+                    OptVariable::Variable => 0,
+                    OptVariable::Number(count) => count,
+                };
+
+                if list.start == list.table + 1 {
+                    self.set_list(list.table, count, list.offset)?;
+                } else {
+                    ensure!(
+                        list.count != OptVariable::Variable,
+                        "SETLIST cannot store a variable number of values that are not directly above the table"
+                    );
+
+                    // the values are copied into temporaries above the table
+                    let table = self.temporary()?;
+                    self.emit(encode_abc(OpCode::Move, table, list.table, 0, false));
+
+                    for index in 0..count {
+                        let register = self.temporary()?;
+                        self.emit(encode_abc(
+                            OpCode::Move,
+                            register,
+                            list.start + index,
+                            0,
+                            false,
+                        ));
+                    }
+
+                    self.set_list(table, count, list.offset)?;
+                }
+            }
+
+            Instruction::Closure(closure) => {
+                ensure!(
+                    closure.prototype < self.children.len(),
+                    "prototype {} does not exist",
+                    closure.prototype
+                );
+
+                ensure!(
+                    closure.prototype <= MAXARG_BX,
+                    "prototype {} is out of range of CLOSURE",
+                    closure.prototype
+                );
+
+                self.emit(encode_abx(OpCode::Closure, closure.dest, closure.prototype));
+            }
+            // upvalue 0 is always `_ENV`, so upvalues of the IL are shifted up by one
+            Instruction::GetUpvalue(get) => {
+                self.emit(encode_abc(
+                    OpCode::GetUpval,
+                    get.dest,
+                    get.upvalue + 1,
+                    0,
+                    false,
+                ));
+            }
+            Instruction::SetUpvalue(set) => {
+                self.emit(encode_abc(
+                    OpCode::SetUpval,
+                    set.src,
+                    set.upvalue + 1,
+                    0,
+                    false,
+                ));
+            }
+            Instruction::Close(close) => {
+                self.emit(encode_abc(OpCode::Close, close.start, 0, 0, false));
+            }
+
+            Instruction::Return(ret) => {
+                ensure!(
+                    !matches!(ret.result_count, OptVariable::Number(n) if n >= MAXARG_B),
+                    "too many results in return"
+                );
+
+                self.emit_return(ret.result_start, &ret.result_count);
+            }
+
+            Instruction::Call(call) => {
+                let arguments = match call.num_args {
+                    OptVariable::Variable => 0,
+                    OptVariable::Number(n) => n + 1 + call.self_call as usize,
+                };
+
+                let results = match call.num_returns {
+                    OptVariable::Variable => 0,
+                    OptVariable::Number(n) => n + 1,
+                };
+
+                ensure!(
+                    arguments <= MAXARG_B && results <= MAXARG_C,
+                    "too many arguments or results in call"
+                );
+
+                self.emit(encode_abc(
+                    OpCode::Call,
+                    call.callee,
+                    arguments,
+                    results,
+                    false,
+                ));
+            }
+            Instruction::VarArg(vararg) => {
+                ensure!(
+                    self.prototype.arity.is_vararg,
+                    "VARARG is only allowed in variadic functions"
+                );
+
+                let count = match vararg.count {
+                    OptVariable::Variable => 0,
+                    OptVariable::Number(n) => n + 1,
+                };
+
+                ensure!(count <= MAXARG_C, "too many values in VARARG");
+
+                self.emit(encode_abc(OpCode::VarArg, vararg.dest, 0, count, false));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn patch_jumps(&mut self) -> Result<()> {
+        for &(pc, target) in &self.fixups {
+            let target_pc = *self.pcs.get(target).with_context(|| {
+                format!("jump at {pc} targets nonexistent instruction {target}")
+            })?;
+
+            let offset = target_pc as i64 - (pc as i64 + 1);
+            let word = self.code[pc];
+            let op = OpCode::try_from((word & 0x7f) as u8).unwrap();
+
+            // the loop instructions only jump in one direction, with an unsigned offset
+            let bx = match op {
+                OpCode::Jmp => {
+                    ensure!(
+                        (-OFFSET_SJ..=OFFSET_SJ).contains(&offset),
+                        "jump at {pc} is too long"
+                    );
+
+                    self.code[pc] = encode_sj(OpCode::Jmp, offset);
+                    continue;
+                }
+                // FORPREP skips the FORLOOP that follows the body as well
+                OpCode::ForPrep => offset - 1,
+                OpCode::TForPrep => offset,
+                _ => -offset,
+            };
+
+            ensure!(
+                (0..=MAXARG_BX as i64).contains(&bx),
+                "{op:?} at {pc} cannot reach instruction {target}"
+            );
+
+            self.code[pc] = encode_abx(op, (word >> POS_A) as usize & 0xff, bx as usize);
+        }
+
+        Ok(())
+    }
+}
+
+/// Splits per-instruction line numbers into the relative `lineinfo` and the
+/// `abslineinfo` pairs of program counter and line, as the reference compiler does.
+pub(super) fn encode_lines(lines: &[u32], line_defined: u32) -> (Vec<i8>, Vec<(usize, u32)>) {
+    let mut relative = Vec::with_capacity(lines.len());
+    let mut absolute = vec![];
+
+    let mut previous = line_defined as i64;
+    let mut without_absolute = 0;
+
+    for (pc, &line) in lines.iter().enumerate() {
+        let difference = line as i64 - previous;
+
+        if difference.abs() >= MAX_LINE_DIFF || without_absolute >= MAX_WITHOUT_ABSOLUTE {
+            absolute.push((pc, line));
+            relative.push(ABSOLUTE_LINE);
+            without_absolute = 1;
+        } else {
+            relative.push(difference as i8);
+            without_absolute += 1;
+        }
+
+        previous = line as i64;
+    }
+
+    (relative, absolute)
+}
+
+/// Writes the primitive types of a Lua 5.4 binary chunk.
+pub(super) struct Writer {
+    pub(super) buf: Vec<u8>,
+}
+
+impl Writer {
+    fn write_byte(&mut self, byte: u8) {
+        self.buf.push(byte);
+    }
+
+    /// Writes a size as 7 bit groups, most significant first, with the high bit set on the
+    /// last group.
+    pub(super) fn write_size(&mut self, mut value: usize) {
+        let mut groups = vec![];
+
+        loop {
+            groups.push((value & 0x7f) as u8);
+            value >>= 7;
+
+            if value == 0 {
+                break;
+            }
+        }
+
+        groups[0] |= 0x80;
+        self.buf.extend(groups.iter().rev());
+    }
+
+    fn write_string(&mut self, string: Option<&[u8]>) {
+        match string {
+            Some(string) => {
+                self.write_size(string.len() + 1);
+                self.buf.extend_from_slice(string);
+            }
+            None => self.write_size(0),
+        }
+    }
+
+    fn write_header(&mut self) {
+        self.buf.extend_from_slice(SIGNATURE);
+        self.write_byte(VERSION);
+        self.write_byte(FORMAT);
+        self.buf.extend_from_slice(DATA);
+        self.write_byte(4);
+        self.write_byte(8);
+        self.write_byte(8);
+        self.buf.extend_from_slice(&CHECK_INTEGER.to_le_bytes());
+        self.buf.extend_from_slice(&CHECK_NUMBER.to_le_bytes());
+    }
+
+    fn write_function(
+        &mut self,
+        state: &FunctionState,
+        source: Option<&str>,
+        strip: bool,
+        main: bool,
+    ) {
+        let prototype = state.prototype;
+
+        let debug = &prototype.debug;
+
+        self.write_string(source.filter(|_| !strip).map(str::as_bytes));
+
+        if strip {
+            self.write_size(0);
+            self.write_size(0);
+        } else {
+            self.write_size(debug.line_defined as usize);
+            self.write_size(debug.last_line_defined as usize);
+        }
+
+        self.write_byte(prototype.arity.params);
+        self.write_byte(prototype.arity.is_vararg as u8);
+        self.write_byte(state.max_stack_size as u8);
+
+        self.write_size(state.code.len());
+        for &instruction in &state.code {
+            self.buf.extend_from_slice(&instruction.to_le_bytes());
+        }
+
+        self.write_size(state.constants.len());
+        for constant in &state.constants {
+            match constant {
+                Lua54Constant::Nil => self.write_byte(0x00),
+                Lua54Constant::Boolean(false) => self.write_byte(0x01),
+                Lua54Constant::Boolean(true) => self.write_byte(0x11),
+                Lua54Constant::Float(n) => {
+                    self.write_byte(0x13);
+                    self.buf.extend_from_slice(&n.to_le_bytes());
+                }
+                Lua54Constant::Integer(n) => {
+                    self.write_byte(0x03);
+                    self.buf.extend_from_slice(&n.to_le_bytes());
+                }
+                Lua54Constant::String(s) => {
+                    self.write_byte(if s.len() <= MAX_SHORT_STRING {
+                        0x04
+                    } else {
+                        0x14
+                    });
+                    self.write_string(Some(s));
+                }
+            }
+        }
+
+        // `_ENV` comes first, held in the stack of the loading function for the main
+        // function and captured from the parent otherwise
+        self.write_size(prototype.upvalues.len() + 1);
+        self.write_byte(main as u8);
+        self.write_byte(0);
+        self.write_byte(0);
+
+        for upvalue in &prototype.upvalues {
+            let index = if upvalue.in_stack {
+                upvalue.index
+            } else {
+                upvalue.index + 1
+            };
+
+            self.write_byte(upvalue.in_stack as u8);
+            self.write_byte(index as u8);
+            self.write_byte(0);
+        }
+
+        // nested functions store no source when it is the same as their parent's
+        self.write_size(state.children.len());
+        for child in &state.children {
+            let source = child
+                .prototype
+                .debug
+                .source
+                .as_deref()
+                .filter(|&source| Some(source) != debug.source.as_deref());
+
+            self.write_function(child, source, strip, false);
+        }
+
+        if strip || !prototype.chunk.has_spans() {
+            self.write_size(0);
+            self.write_size(0);
+        } else {
+            let (relative, absolute) = encode_lines(&state.lineinfo, debug.line_defined);
+
+            self.write_size(relative.len());
+            self.buf.extend(relative.iter().map(|&line| line as u8));
+
+            self.write_size(absolute.len());
+            for (pc, line) in absolute {
+                self.write_size(pc);
+                self.write_size(line as usize);
+            }
+        }
+
+        if strip {
+            self.write_size(0);
+            self.write_size(0);
+        } else {
+            self.write_size(debug.locals.len());
+            for local in &debug.locals {
+                self.write_string(Some(local.name.as_bytes()));
+                self.write_size(pc_at(&state.pcs, local.start));
+                self.write_size(pc_at(&state.pcs, local.end));
+            }
+
+            // every upvalue is named when any is, `_ENV` comes first as it does above
+            self.write_size(prototype.upvalues.len() + 1);
+            self.write_string(Some(b"_ENV"));
+
+            for index in 0..prototype.upvalues.len() {
+                self.write_string(debug.upvalue_names.get(index).map(String::as_bytes));
+            }
+        }
+    }
+}
@@ -0,0 +1,625 @@
+#![cfg(test)]
+use super::deserializer::decode_lines;
+use super::serializer::{encode_lines, Writer, ABSOLUTE_LINE};
+use super::*;
+use crate::formats::{Deserializer, Serializer, MAX_NESTING};
+use crate::ir::il::interpreter::{Interpreter, LuaValue, Semantics};
+use crate::ir::il::{
+    Arity, BinaryOp, BinaryOpKind, Call, Closure, Concat, Constant, DebugInfo, ForGenCall,
+    ForGenLoop, ForGenPrep, ForNumLoop, ForNumPrep, Function, GetGlobal, GetUpvalue, IlChunk,
+    Instruction, Intrinsic, IntrinsicKind, IntrinsicSource, Label, Load, Move, OptVariable, Return,
+    SelfLookup, SetList, SetTable, Upvalue, Value, VarArg,
+};
+use crate::ir::span::Span;
+
+/// A function whose instructions are attributed to `lines`, in order.
+fn function(constants: Vec<Constant>, lines: Vec<u32>, mut chunk: IlChunk) -> Function {
+    let insts = chunk.iter().map(|(inst, _)| inst).collect::<Vec<_>>();
+
+    for (inst, line) in insts.into_iter().zip(lines) {
+        chunk.set_span(inst, Some(Span::line(line)));
+    }
+
+    Function {
+        chunk,
+        constants,
+        prototypes: vec![],
+        upvalues: vec![],
+        arity: Arity::vararg(0),
+        name: None,
+        max_stack_size: 2,
+        debug: DebugInfo::default(),
+    }
+}
+
+fn binary(operator: BinaryOpKind, left: Value, right: Value) -> Instruction {
+    Instruction::BinaryOp(BinaryOp {
+        operator,
+        dest: 0,
+        left,
+        right,
+    })
+}
+
+fn ret() -> Instruction {
+    Instruction::Return(Return {
+        result_start: 0,
+        result_count: OptVariable::Number(1),
+    })
+}
+
+fn read_size(bytes: &[u8], offset: &mut usize) -> usize {
+    let mut value = 0;
+
+    loop {
+        let byte = bytes[*offset];
+        *offset += 1;
+
+        value = (value << 7) | (byte & 0x7f) as usize;
+
+        if byte & 0x80 != 0 {
+            return value;
+        }
+    }
+}
+
+/// Extracts the code of the main function from a chunk without a source name.
+fn main_code(bytes: &[u8]) -> Vec<u32> {
+    // header, upvalue count, source, linedefined and lastlinedefined and three bytes
+    let mut offset = 31 + 1 + 3 + 3;
+    let count = read_size(bytes, &mut offset);
+
+    bytes[offset..offset + count * 4]
+        .chunks(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .collect()
+}
+
+fn serialize(chunk: Vec<Instruction>, constants: Vec<Constant>) -> Vec<u32> {
+    let bytes = Lua54Serializer::default()
+        .serialize(&function(constants, vec![], IlChunk::new(chunk)))
+        .unwrap();
+
+    main_code(&bytes)
+}
+
+#[test]
+fn header() {
+    let bytes = Lua54Serializer::default()
+        .serialize(&function(vec![], vec![], IlChunk::new(vec![])))
+        .unwrap();
+
+    assert_eq!(&bytes[..12], b"\x1bLua\x54\x00\x19\x93\r\n\x1a\n");
+    assert_eq!(&bytes[12..15], &[4, 8, 8]);
+    assert_eq!(&bytes[15..23], &0x5678i64.to_le_bytes());
+    assert_eq!(&bytes[23..31], &370.5f64.to_le_bytes());
+}
+
+#[test]
+fn sizes_are_big_endian_varints() {
+    let mut writer = Writer { buf: vec![] };
+
+    writer.write_size(0);
+    writer.write_size(300);
+
+    assert_eq!(writer.buf, vec![0x80, 0x02, 0xac]);
+}
+
+#[test]
+fn arithmetic_uses_immediates_and_constants() {
+    let code = serialize(
+        vec![
+            binary(BinaryOpKind::Add, Value::StackIndex(1), Value::Immediate(5)),
+            binary(BinaryOpKind::Sub, Value::StackIndex(1), Value::Immediate(5)),
+            binary(
+                BinaryOpKind::Mul,
+                Value::ConstantIndex(0),
+                Value::StackIndex(1),
+            ),
+            binary(
+                BinaryOpKind::Div,
+                Value::StackIndex(1),
+                Value::StackIndex(1),
+            ),
+            ret(),
+        ],
+        vec![Constant::Number(0.5)],
+    );
+
+    assert_eq!(code[0], encode_abc(OpCode::VarArgPrep, 0, 0, 0, false));
+
+    assert_eq!(code[1], encode_abc(OpCode::AddI, 0, 1, int_to_sc(5), false));
+    assert_eq!(
+        code[2],
+        encode_abc(
+            OpCode::MmBinI,
+            1,
+            int_to_sc(5),
+            TagMethod::Add as usize,
+            false
+        )
+    );
+
+    // subtracting an immediate adds its negation, the metamethod still sees the original
+    assert_eq!(
+        code[3],
+        encode_abc(OpCode::AddI, 0, 1, int_to_sc(-5), false)
+    );
+    assert_eq!(
+        code[4],
+        encode_abc(
+            OpCode::MmBinI,
+            1,
+            int_to_sc(5),
+            TagMethod::Sub as usize,
+            false
+        )
+    );
+
+    // commutative operations swap a leading constant and flag the swap
+    assert_eq!(code[5], encode_abc(OpCode::MulK, 0, 1, 0, false));
+    assert_eq!(
+        code[6],
+        encode_abc(OpCode::MmBinK, 1, 0, TagMethod::Mul as usize, true)
+    );
+
+    assert_eq!(code[7], encode_abc(OpCode::Div, 0, 1, 1, false));
+    assert_eq!(
+        code[8],
+        encode_abc(OpCode::MmBin, 1, 1, TagMethod::Div as usize, false)
+    );
+
+    assert_eq!(code[9], encode_abc(OpCode::Return, 0, 2, 1, false));
+}
+
+#[test]
+fn loads_pick_the_shortest_form() {
+    let code = serialize(
+        vec![
+            Instruction::Load(Load {
+                dest: 0,
+                src: Value::Immediate(-7),
+            }),
+            Instruction::Load(Load {
+                dest: 0,
+                src: Value::Immediate(1 << 20),
+            }),
+            Instruction::Load(Load {
+                dest: 0,
+                src: Value::ConstantIndex(0),
+            }),
+            Instruction::Load(Load {
+                dest: 0,
+                src: Value::ConstantIndex(1),
+            }),
+            ret(),
+        ],
+        vec![Constant::Number(2.0), Constant::Number(-0.0)],
+    );
+
+    assert_eq!(code[1], encode_asbx(OpCode::LoadI, 0, -7));
+    assert_eq!(code[2], encode_abx(OpCode::LoadK, 0, 2));
+    assert_eq!(code[3], encode_asbx(OpCode::LoadF, 0, 2));
+    assert_eq!(code[4], encode_abx(OpCode::LoadK, 0, 1));
+}
+
+#[test]
+fn line_info_falls_back_to_absolute_lines() {
+    let (relative, absolute) = encode_lines(&[1, 2, 2, 500, 501], 0);
+
+    assert_eq!(relative, vec![1, 1, 0, ABSOLUTE_LINE, 1]);
+    assert_eq!(absolute, vec![(3, 500)]);
+
+    // an absolute line is also forced after every 128 relative ones
+    let (relative, absolute) = encode_lines(&[1; 200], 1);
+
+    assert_eq!(relative[128], ABSOLUTE_LINE);
+    assert_eq!(absolute, vec![(128, 1)]);
+
+    let lines = [1, 2, 2, 500, 501];
+    let (relative, absolute) = encode_lines(&lines, 0);
+
+    assert_eq!(decode_lines(&relative, &absolute, 0).unwrap(), lines);
+}
+
+#[test]
+fn serialized_chunks_round_trip() {
+    let chunk = IlChunk::new(vec![
+        binary(BinaryOpKind::Add, Value::StackIndex(1), Value::Immediate(5)),
+        Instruction::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::LeftShift(Value::Immediate(1), Value::StackIndex(0)),
+            dest: 0,
+            source: IntrinsicSource::Operator,
+        }),
+        ret(),
+    ]);
+
+    let prototype = function(vec![], vec![1, 2, 3], chunk);
+    let bytes = Lua54Serializer::default().serialize(&prototype).unwrap();
+
+    // the metamethod fallbacks emitted after each operation are dropped again, and the
+    // line table is split back into spans
+    let lifted = Lua54Deserializer.deserialize(&bytes).unwrap();
+
+    assert_eq!(lifted.chunk, prototype.chunk);
+}
+
+#[test]
+fn tail_calls_return_every_result() {
+    let call = Instruction::Call(Call {
+        callee: 0,
+        self_call: false,
+        num_args: OptVariable::Number(0),
+        num_returns: OptVariable::Variable,
+    });
+    let ret = Instruction::Return(Return {
+        result_start: 0,
+        result_count: OptVariable::Variable,
+    });
+
+    let chunk = IlChunk::new(vec![call.clone(), ret.clone()]);
+    let mut bytes = Lua54Serializer::default()
+        .serialize(&function(vec![], vec![], chunk))
+        .unwrap();
+
+    // `return f()` compiles to a tail call, which is followed by a return that is never run
+    let word = encode_abc(OpCode::Call, 0, 1, 0, false).to_le_bytes();
+    let offset = bytes.windows(4).position(|window| window == word).unwrap();
+    let tail_call = encode_abc(OpCode::TailCall, 0, 1, 1, false);
+    bytes[offset..offset + 4].copy_from_slice(&tail_call.to_le_bytes());
+
+    let lifted = Lua54Deserializer.deserialize(&bytes).unwrap();
+
+    assert_eq!(lifted.chunk.inner(), &vec![call, ret.clone(), ret]);
+}
+
+#[test]
+fn library_intrinsics_keep_their_width() {
+    let function = "function (0)
+    stack 4
+    r0 = bit32.bnot 0
+    r1 = bit32.rshift -1, 4
+    r2 = bit.lshift 1, 31
+    r3 = bit.rshift -1, 36
+    return r0, 4
+end
+"
+    .parse::<Function>()
+    .unwrap();
+
+    let bytes = Lua54Serializer::default().serialize(&function).unwrap();
+    let lifted = Lua54Deserializer.deserialize(&bytes).unwrap();
+
+    // the native operators work on 64 bits, so the results are truncated to 32 bits
+    let results = Interpreter::new()
+        .semantics(Semantics::Lua53)
+        .run(&lifted, vec![])
+        .unwrap();
+
+    assert_eq!(
+        results,
+        [
+            LuaValue::Integer(0xffff_ffff),
+            LuaValue::Integer(0x0fff_ffff),
+            LuaValue::Integer(i32::MIN as i64),
+            LuaValue::Integer(0x0fff_ffff),
+        ]
+    );
+}
+
+#[test]
+fn table_stores_round_trip() {
+    let chunk = IlChunk::new(vec![
+        Instruction::SetTable(SetTable {
+            table: 0,
+            key: Value::ConstantIndex(0),
+            value: Value::StackIndex(1),
+        }),
+        Instruction::SetTable(SetTable {
+            table: 0,
+            key: Value::Immediate(3),
+            value: Value::ConstantIndex(0),
+        }),
+        Instruction::SelfLookup(SelfLookup {
+            dest: 2,
+            object: 0,
+            key: Value::ConstantIndex(0),
+        }),
+        Instruction::Concat(Concat {
+            dest: 2,
+            start: 2,
+            end: 3,
+        }),
+        Instruction::Move(Move { dest: 1, src: 2 }),
+        Instruction::Call(Call {
+            callee: 1,
+            self_call: false,
+            num_args: OptVariable::Number(0),
+            num_returns: OptVariable::Variable,
+        }),
+        // the offset does not fit into C, so it is extended by EXTRAARG
+        Instruction::SetList(SetList {
+            table: 0,
+            start: 1,
+            count: OptVariable::Variable,
+            offset: 300,
+        }),
+        ret(),
+    ]);
+
+    let code = serialize(
+        chunk.inner().to_vec(),
+        vec![Constant::String(b"x".to_vec())],
+    );
+    assert_eq!(code[1], encode_abc(OpCode::SetField, 0, 0, 1, false));
+    assert_eq!(code[2], encode_abc(OpCode::SetI, 0, 3, 0, true));
+
+    let bytes = Lua54Serializer::default()
+        .serialize(&function(
+            vec![Constant::String(b"x".to_vec())],
+            vec![],
+            chunk.clone(),
+        ))
+        .unwrap();
+
+    assert_eq!(Lua54Deserializer.deserialize(&bytes).unwrap().chunk, chunk);
+}
+
+#[test]
+fn loops_round_trip() {
+    let chunk = IlChunk::new(vec![
+        Instruction::ForNumPrep(ForNumPrep {
+            target: Label(1),
+            index: 0,
+            limit: 1,
+            step: 2,
+            var: 3,
+        }),
+        Instruction::Label(Label(0)),
+        Instruction::Move(Move { dest: 4, src: 3 }),
+        Instruction::ForNumLoop(ForNumLoop {
+            target: Label(0),
+            index: 0,
+            limit: 1,
+            step: 2,
+            var: 3,
+        }),
+        Instruction::Label(Label(1)),
+        Instruction::ForGenPrep(ForGenPrep {
+            target: Label(3),
+            base: 5,
+        }),
+        Instruction::Label(Label(2)),
+        Instruction::Move(Move { dest: 4, src: 9 }),
+        Instruction::Label(Label(3)),
+        Instruction::ForGenCall(ForGenCall {
+            base: 5,
+            dest: 9,
+            count: 2,
+        }),
+        Instruction::ForGenLoop(ForGenLoop {
+            target: Label(2),
+            base: 5,
+            var: 9,
+        }),
+        ret(),
+    ]);
+
+    let bytes = Lua54Serializer::default()
+        .serialize(&function(vec![], vec![], chunk.clone()))
+        .unwrap();
+
+    assert_eq!(Lua54Deserializer.deserialize(&bytes).unwrap().chunk, chunk);
+}
+
+#[test]
+fn environment_is_hidden_from_upvalues() {
+    let closure = |dest| Instruction::Closure(Closure { dest, prototype: 0 });
+
+    let get_upvalue = Instruction::GetUpvalue(GetUpvalue {
+        dest: 0,
+        upvalue: 0,
+    });
+
+    // the innermost function reads a global and the upvalue its parent captured
+    let mut inner = function(
+        vec![Constant::String(b"print".to_vec())],
+        vec![],
+        IlChunk::new(vec![
+            Instruction::GetGlobal(GetGlobal {
+                dest: 1,
+                constant: 0,
+            }),
+            get_upvalue.clone(),
+            ret(),
+        ]),
+    );
+    inner.upvalues = vec![Upvalue {
+        in_stack: false,
+        index: 0,
+    }];
+    inner.debug.upvalue_names = vec!["x".to_owned()];
+
+    let mut middle = function(
+        vec![],
+        vec![],
+        IlChunk::new(vec![get_upvalue, closure(1), ret()]),
+    );
+    middle.upvalues = vec![Upvalue {
+        in_stack: true,
+        index: 0,
+    }];
+    middle.prototypes = vec![inner];
+    middle.arity = Arity::fixed(0);
+    middle.debug.upvalue_names = vec!["x".to_owned()];
+    middle.debug.line_defined = 2;
+    middle.debug.last_line_defined = 4;
+
+    let mut main = function(
+        vec![],
+        vec![],
+        IlChunk::new(vec![
+            Instruction::Load(Load {
+                dest: 0,
+                src: Value::Immediate(1),
+            }),
+            closure(1),
+            ret(),
+        ]),
+    );
+    main.prototypes = vec![middle];
+
+    let bytes = Lua54Serializer::default().serialize(&main).unwrap();
+    let lifted = Lua54Deserializer.deserialize(&bytes).unwrap();
+
+    let (middle, lifted_middle) = (&main.prototypes[0], &lifted.prototypes[0]);
+    let (inner, lifted_inner) = (&middle.prototypes[0], &lifted_middle.prototypes[0]);
+
+    assert_eq!(lifted.chunk, main.chunk);
+    assert_eq!(lifted_middle.chunk, middle.chunk);
+    assert_eq!(lifted_middle.upvalues, middle.upvalues);
+    assert_eq!(lifted_inner.chunk, inner.chunk);
+    assert_eq!(lifted_inner.upvalues, inner.upvalues);
+
+    // `_ENV` is named in the bytecode but not in the IL
+    assert_eq!(lifted.debug, main.debug);
+    assert_eq!(lifted_middle.debug, middle.debug);
+    assert_eq!(lifted_inner.debug, inner.debug);
+    assert!(!lifted_middle.arity.is_vararg);
+}
+
+#[test]
+fn varargs_round_trip() {
+    // function(a, ...) local b, c = ...; f(...) end
+    let chunk = IlChunk::new(vec![
+        Instruction::VarArg(VarArg {
+            dest: 2,
+            count: OptVariable::Number(2),
+        }),
+        Instruction::VarArg(VarArg {
+            dest: 5,
+            count: OptVariable::Variable,
+        }),
+        Instruction::Call(Call {
+            callee: 4,
+            self_call: false,
+            num_args: OptVariable::Variable,
+            num_returns: OptVariable::Number(0),
+        }),
+        ret(),
+    ]);
+
+    let mut main = function(vec![], vec![], chunk.clone());
+    main.arity = Arity::vararg(1);
+
+    let bytes = Lua54Serializer::default().serialize(&main).unwrap();
+
+    assert_eq!(
+        main_code(&bytes)[..3],
+        [
+            encode_abc(OpCode::VarArgPrep, 1, 0, 0, false),
+            encode_abc(OpCode::VarArg, 2, 0, 3, false),
+            encode_abc(OpCode::VarArg, 5, 0, 0, false)
+        ]
+    );
+
+    let lifted = Lua54Deserializer.deserialize(&bytes).unwrap();
+
+    assert_eq!(lifted.chunk, chunk);
+    assert_eq!(lifted.arity, main.arity);
+
+    // Lua 5.4 has no `arg` table
+    main.arity.needs_arg = true;
+
+    assert!(Lua54Serializer::default().serialize(&main).is_err());
+}
+
+#[test]
+fn integers_round_trip() {
+    let constants = vec![
+        Constant::Integer(1),
+        Constant::Number(1.0),
+        Constant::Integer(1 << 40),
+        Constant::Number(0.5),
+    ];
+
+    // local a, b = 1.0, 1; a = b // 1; return a // b
+    let chunk = IlChunk::new(vec![
+        Instruction::Load(Load {
+            dest: 0,
+            src: Value::ConstantIndex(1),
+        }),
+        Instruction::Load(Load {
+            dest: 1,
+            src: Value::ConstantIndex(0),
+        }),
+        binary(
+            BinaryOpKind::IDiv,
+            Value::StackIndex(1),
+            Value::ConstantIndex(0),
+        ),
+        binary(
+            BinaryOpKind::IDiv,
+            Value::StackIndex(0),
+            Value::StackIndex(1),
+        ),
+        ret(),
+    ]);
+
+    let bytes = Lua54Serializer::default()
+        .serialize(&function(constants.clone(), vec![], chunk.clone()))
+        .unwrap();
+
+    let code = main_code(&bytes);
+
+    assert_eq!(code[1], encode_asbx(OpCode::LoadF, 0, 1));
+    assert_eq!(code[2], encode_abx(OpCode::LoadK, 1, 0));
+    assert_eq!(code[3], encode_abc(OpCode::IDivK, 0, 1, 0, false));
+
+    let lifted = Lua54Deserializer.deserialize(&bytes).unwrap();
+
+    assert_eq!(lifted.chunk, chunk);
+    assert_eq!(lifted.constants, constants);
+    assert_eq!(
+        format!("{:?}", lifted.constants),
+        "[1, 1.0, 1099511627776, 0.5]"
+    );
+}
+
+/// Nests a function returning nothing `depth` functions deep in a main function.
+fn nested(depth: usize) -> Function {
+    let leaf = function(
+        vec![],
+        vec![],
+        IlChunk::new(vec![Instruction::Return(Return {
+            result_start: 0,
+            result_count: OptVariable::Number(0),
+        })]),
+    );
+
+    (0..depth).fold(leaf.clone(), |inner, _| Function {
+        prototypes: vec![inner],
+        ..leaf.clone()
+    })
+}
+
+#[test]
+fn deep_nesting_is_rejected() {
+    let serialize = |depth| {
+        Lua54Serializer::default()
+            .serialize(&nested(depth))
+            .unwrap()
+    };
+
+    assert!(Lua54Deserializer
+        .deserialize(&serialize(MAX_NESTING))
+        .is_ok());
+
+    let error = Lua54Deserializer
+        .deserialize(&serialize(MAX_NESTING + 1))
+        .unwrap_err();
+    assert!(
+        format!("{error:#}").contains("nested more than"),
+        "{error:#}"
+    );
+}
@@ -0,0 +1,1033 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use super::*;
+use crate::formats::{
+    check_nesting,
+    lift::{inst_at, Lifter, Target},
+    Deserializer,
+};
+use crate::ir::il::{
+    Arity, BinaryOp, BinaryOpKind, Call, Close, Closure, Concat, Condition, ConditionKind,
+    Constant, DebugInfo, ForGenCall, ForGenLoop, ForGenPrep, ForNumLoop, ForNumPrep, Function,
+    GetGlobal, GetTable, GetUpvalue, Instruction, Load, Local, Move, NewTable, OptVariable, Return,
+    SelfLookup, SetGlobal, SetList, SetTable, SetUpvalue, Table, UnaryOp, UnaryOpKind, Upvalue,
+    Value, VarArg,
+};
+use crate::ir::span::Span;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+/// A constant exactly as it is stored in a Luau constant table.
+#[derive(Clone, Debug)]
+enum RawConstant {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    String(Vec<u8>),
+    /// An import path, which `GETIMPORT` repeats in its auxiliary word.
+    Import,
+    Table(Vec<usize>),
+    Closure(usize),
+    /// A vector, which has no counterpart in the IL.
+    Vector,
+}
+
+/// A function prototype exactly as it is stored in Luau bytecode.
+#[derive(Clone, Debug)]
+struct Prototype {
+    max_stack_size: u8,
+    param_count: u8,
+    upvalue_count: u8,
+    is_vararg: bool,
+    code: Vec<u32>,
+    constants: Vec<RawConstant>,
+    children: Vec<usize>,
+    name: Option<String>,
+    line_defined: u32,
+    lineinfo: Vec<u32>,
+    /// The local variables, as (name, startpc, endpc, register) tuples.
+    locals: Vec<(String, usize, usize, usize)>,
+    upvalue_names: Vec<String>,
+}
+
+/// Deserializes Luau bytecode, as produced by `luau-compile --binary` or
+/// `luau_compile`, into LUNIR intermediate language.
+#[derive(Clone, Debug, Default)]
+pub struct LuauDeserializer;
+
+impl Deserializer for LuauDeserializer {
+    /// Deserializes the bytecode `bytes`, returning the lifted prototype of its main
+    /// function.
+    fn deserialize(&self, bytes: &[u8]) -> Result<Function> {
+        let mut reader = Reader { bytes, position: 0 };
+
+        let version = reader.read_byte()?;
+
+        if version == 0 {
+            // a failed compilation stores its error message in place of the bytecode
+            bail!(
+                "bytecode contains a compilation error: {}",
+                String::from_utf8_lossy(&bytes[1..])
+            );
+        }
+
+        ensure!(
+            (VERSION_MIN..=VERSION_MAX).contains(&version),
+            "unsupported bytecode version {version}, expected {VERSION_MIN} to {VERSION_MAX}"
+        );
+
+        let types_version = if version >= 4 { reader.read_byte()? } else { 0 };
+        ensure!(
+            types_version <= 3,
+            "unsupported type information version {types_version}"
+        );
+
+        let string_count = reader.read_varint()?;
+        let string_count = reader.check_count(string_count, "string")?;
+        let mut strings = Vec::with_capacity(string_count);
+
+        for _ in 0..string_count {
+            let size = reader.read_varint()?;
+            strings.push(reader.read_bytes(size)?.to_vec());
+        }
+
+        if types_version == 3 {
+            // userdata type names, terminated by a zero index
+            while reader.read_byte()? != 0 {
+                reader.read_varint()?;
+            }
+        }
+
+        let prototype_count = reader.read_varint()?;
+        let prototypes = (0..prototype_count)
+            .map(|id| {
+                reader
+                    .read_function(version, &strings)
+                    .with_context(|| format!("failed to read function {id}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let main = reader.read_varint()?;
+
+        ensure!(
+            prototypes
+                .get(main)
+                .with_context(|| format!("main function {main} does not exist"))?
+                .upvalue_count
+                == 0,
+            "the main function cannot have upvalues"
+        );
+
+        ensure!(
+            reader.position == bytes.len(),
+            "{} trailing bytes after the main function id",
+            bytes.len() - reader.position
+        );
+
+        lift(&prototypes, main, Vec::new(), 0)
+    }
+}
+
+/// Whether `op` is followed by an auxiliary word.
+fn has_aux(op: OpCode) -> bool {
+    matches!(
+        op,
+        OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetImport
+            | OpCode::GetTableKS
+            | OpCode::SetTableKS
+            | OpCode::NameCall
+            | OpCode::JumpIfEq
+            | OpCode::JumpIfLe
+            | OpCode::JumpIfLt
+            | OpCode::JumpIfNotEq
+            | OpCode::JumpIfNotLe
+            | OpCode::JumpIfNotLt
+            | OpCode::NewTable
+            | OpCode::SetList
+            | OpCode::ForGLoop
+            | OpCode::LoadKX
+            | OpCode::FastCall2
+            | OpCode::FastCall2K
+            | OpCode::FastCall3
+            | OpCode::JumpXEqKNil
+            | OpCode::JumpXEqKB
+            | OpCode::JumpXEqKN
+            | OpCode::JumpXEqKS
+    )
+}
+
+fn opcode(instruction: u32) -> Result<OpCode> {
+    OpCode::try_from(instruction as u8).map_err(|op| anyhow!("unknown opcode {op}"))
+}
+
+/// Lifts the function `id` of the proto list `prototypes` and the functions nested in it into
+/// LUNIR intermediate language, `upvalues` describes what closures of it capture and `depth`
+/// how many functions it is nested in.
+fn lift(
+    prototypes: &[Prototype],
+    id: usize,
+    upvalues: Vec<Upvalue>,
+    depth: usize,
+) -> Result<Function> {
+    check_nesting(depth)?;
+
+    let prototype = &prototypes[id];
+
+    // the compiler writes functions before the functions they are nested in, which also
+    // rules out cycles
+    for &child in &prototype.children {
+        ensure!(
+            child < id,
+            "function {id} nests function {child}, which does not precede it"
+        );
+    }
+
+    // the upvalues a closure captures are described by the `CAPTURE` pseudo-instructions
+    // following its `NEWCLOSURE` or `DUPCLOSURE`
+    let mut captures = vec![None; prototype.children.len()];
+    let code = &prototype.code;
+    let mut pc = 0;
+
+    while pc < code.len() {
+        let op = opcode(code[pc])?;
+
+        if matches!(op, OpCode::NewClosure | OpCode::DupClosure) {
+            let index = closure_child(prototype, code[pc])
+                .with_context(|| format!("failed to lift instruction {pc} ({op:?})"))?;
+            let child = &prototypes[prototype.children[index]];
+
+            let mut descriptors = Vec::with_capacity(child.upvalue_count as usize);
+
+            for pc in pc + 1..=pc + child.upvalue_count as usize {
+                let capture = code
+                    .get(pc)
+                    .copied()
+                    .filter(|&word| opcode(word).ok() == Some(OpCode::Capture))
+                    .with_context(|| format!("instruction {pc} does not capture an upvalue"))?;
+
+                let in_stack = match CaptureType::try_from((capture >> 8) as u8) {
+                    Ok(CaptureType::Val | CaptureType::Ref) => true,
+                    Ok(CaptureType::Upval) => false,
+                    Err(kind) => bail!("instruction {pc} has unknown capture type {kind}"),
+                };
+
+                descriptors.push(Upvalue {
+                    in_stack,
+                    index: ((capture >> 16) & 0xff) as usize,
+                });
+            }
+
+            match &captures[index] {
+                Some(existing) if *existing != descriptors => {
+                    bail!("prototype {index} is instantiated with different upvalues")
+                }
+                _ => captures[index] = Some(descriptors),
+            }
+        }
+
+        pc += if has_aux(op) { 2 } else { 1 };
+    }
+
+    let children = prototype
+        .children
+        .iter()
+        .zip(captures)
+        .enumerate()
+        .map(|(index, (&child, upvalues))| {
+            let upvalues = match upvalues {
+                Some(upvalues) => upvalues,
+                None if prototypes[child].upvalue_count == 0 => Vec::new(),
+                None => {
+                    bail!("prototype {index} is never instantiated, so its upvalues are unknown")
+                }
+            };
+
+            lift(prototypes, child, upvalues, depth + 1)
+                .with_context(|| format!("failed to lift prototype {index}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // constants with no counterpart in the IL keep their slot as `nil` so that constant
+    // indices stay the same, instructions using them are rejected while lifting
+    let constants = prototype
+        .constants
+        .iter()
+        .map(|constant| match constant {
+            RawConstant::Nil
+            | RawConstant::Import
+            | RawConstant::Closure(_)
+            | RawConstant::Vector => Constant::Nil,
+            RawConstant::Boolean(b) => Constant::Boolean(*b),
+            RawConstant::Number(n) => Constant::Number(*n),
+            RawConstant::String(s) => Constant::String(s.clone()),
+            RawConstant::Table(keys) => Constant::Table(Table::Map(
+                keys.iter()
+                    .map(|&key| (Value::ConstantIndex(key), Value::Nil))
+                    .collect(),
+            )),
+        })
+        .collect();
+
+    let mut lifter = Lifter::new(code.len());
+    let mut pc = 0;
+
+    while pc < code.len() {
+        let op = opcode(code[pc])?;
+        let span = prototype.lineinfo.get(pc).copied().map(Span::line);
+
+        lifter.begin(span);
+
+        lift_instruction(&mut lifter, prototype, op)
+            .with_context(|| format!("failed to lift instruction {pc} ({op:?})"))?;
+
+        if has_aux(op) {
+            lifter.begin(span);
+            pc += 1;
+        }
+
+        pc += 1;
+    }
+
+    let (chunk, pcs) = lifter.finish()?;
+
+    let locals = prototype
+        .locals
+        .iter()
+        .map(|(name, start, end, register)| Local {
+            name: name.clone(),
+            register: *register,
+            start: inst_at(&pcs, *start),
+            end: inst_at(&pcs, *end),
+        })
+        .collect();
+
+    Ok(Function {
+        chunk,
+        constants,
+        prototypes: children,
+        upvalues,
+        arity: Arity {
+            params: prototype.param_count,
+            is_vararg: prototype.is_vararg,
+            needs_arg: false,
+        },
+        name: prototype.name.clone(),
+        max_stack_size: prototype.max_stack_size,
+        debug: DebugInfo {
+            source: None,
+            line_defined: prototype.line_defined,
+            last_line_defined: 0,
+            locals,
+            upvalue_names: prototype.upvalue_names.clone(),
+        },
+    })
+}
+
+/// Returns the index in the child list of `prototype` of the function instantiated by the
+/// `NEWCLOSURE` or `DUPCLOSURE` instruction `instruction`.
+fn closure_child(prototype: &Prototype, instruction: u32) -> Result<usize> {
+    let d = (instruction >> 16) as usize;
+
+    let index = if opcode(instruction)? == OpCode::NewClosure {
+        d
+    } else {
+        // `DUPCLOSURE` refers to a closure constant, which holds an index into the proto list
+        let id = match prototype.constants.get(d) {
+            Some(RawConstant::Closure(id)) => *id,
+            _ => bail!("constant {d} is not a closure"),
+        };
+
+        prototype
+            .children
+            .iter()
+            .position(|&child| child == id)
+            .with_context(|| format!("function {id} is not nested in this one"))?
+    };
+
+    ensure!(
+        index < prototype.children.len(),
+        "prototype {index} does not exist"
+    );
+
+    Ok(index)
+}
+
+fn lift_instruction(lifter: &mut Lifter, prototype: &Prototype, op: OpCode) -> Result<()> {
+    let pc = lifter.pc();
+    let code = &prototype.code;
+    let instruction = code[pc];
+
+    let a = ((instruction >> 8) & 0xff) as usize;
+    let b = ((instruction >> 16) & 0xff) as usize;
+    let c = (instruction >> 24) as usize;
+    let d = (instruction as i32) >> 16;
+    let e = (instruction as i32) >> 8;
+
+    let aux = || code.get(pc + 1).copied().context("missing auxiliary word");
+
+    let target = |offset: i32| Target::Pc((pc as isize + 1 + offset as isize) as usize);
+
+    // constants the IL can hold directly, anything else has been replaced with `nil`
+    let constant = |index: usize| -> Result<Value> {
+        match prototype.constants.get(index) {
+            Some(
+                RawConstant::Nil
+                | RawConstant::Boolean(_)
+                | RawConstant::Number(_)
+                | RawConstant::String(_),
+            ) => Ok(Value::ConstantIndex(index)),
+            Some(constant) => bail!("{constant:?} constants cannot be represented in LUNIR IL"),
+            None => bail!("constant {index} does not exist"),
+        }
+    };
+
+    let load = |dest, src| Instruction::Load(Load { dest, src });
+
+    let binary = |operator, dest, left, right| {
+        Instruction::BinaryOp(BinaryOp {
+            operator,
+            dest,
+            left,
+            right,
+        })
+    };
+
+    let unary = |operator| {
+        Instruction::UnaryOp(UnaryOp {
+            operator,
+            dest: a,
+            left: Value::StackIndex(b),
+        })
+    };
+
+    let get_table = |key| {
+        Instruction::GetTable(GetTable {
+            dest: a,
+            source: b,
+            key,
+        })
+    };
+
+    let set_table = |key| {
+        Instruction::SetTable(SetTable {
+            table: b,
+            key,
+            value: Value::StackIndex(a),
+        })
+    };
+
+    let compare = |lifter: &mut Lifter, kind, right, negated: bool| -> Result<()> {
+        let condition = Condition {
+            kind,
+            left: Value::StackIndex(a),
+            right,
+        };
+
+        if negated {
+            match condition.kind {
+                // equality is the only comparison that can be negated exactly
+                ConditionKind::Eq => lifter.conditional_jump(
+                    Condition {
+                        kind: ConditionKind::Ne,
+                        ..condition
+                    },
+                    target(d),
+                ),
+                _ => {
+                    lifter.conditional_jump(condition, target(1));
+                    lifter.jump(target(d));
+                }
+            }
+        } else {
+            lifter.conditional_jump(condition, target(d));
+        }
+
+        Ok(())
+    };
+
+    let arithmetic = |op: OpCode| match op {
+        OpCode::Add | OpCode::AddK => Some(BinaryOpKind::Add),
+        OpCode::Sub | OpCode::SubK | OpCode::SubRK => Some(BinaryOpKind::Sub),
+        OpCode::Mul | OpCode::MulK => Some(BinaryOpKind::Mul),
+        OpCode::Div | OpCode::DivK | OpCode::DivRK => Some(BinaryOpKind::Div),
+        OpCode::IDiv | OpCode::IDivK => Some(BinaryOpKind::IDiv),
+        OpCode::Mod | OpCode::ModK => Some(BinaryOpKind::Mod),
+        OpCode::Pow | OpCode::PowK => Some(BinaryOpKind::Pow),
+        _ => None,
+    };
+
+    match op {
+        // fast calls fall back to the regular call that follows them, which is lifted on
+        // its own, and captures are part of the closure they follow, so they can be ignored
+        // along with instructions without any effect
+        OpCode::Nop
+        | OpCode::Break
+        | OpCode::Coverage
+        | OpCode::PrepVarArgs
+        | OpCode::Capture
+        | OpCode::FastCall
+        | OpCode::FastCall1
+        | OpCode::FastCall2
+        | OpCode::FastCall2K
+        | OpCode::FastCall3 => {}
+
+        OpCode::LoadNil => lifter.push(load(a, Value::Nil)),
+        OpCode::LoadB => {
+            lifter.push(load(a, Value::Boolean(b != 0)));
+
+            if c != 0 {
+                lifter.jump(target(c as i32));
+            }
+        }
+        OpCode::LoadN => lifter.push(load(a, Value::Immediate(d))),
+        OpCode::LoadK => lifter.push(load(a, constant(d as u16 as usize)?)),
+        OpCode::LoadKX => lifter.push(load(a, constant(aux()? as usize)?)),
+        OpCode::Move => lifter.push(Instruction::Move(Move { dest: a, src: b })),
+
+        OpCode::GetGlobal => lifter.push(Instruction::GetGlobal(GetGlobal {
+            dest: a,
+            constant: aux()? as usize,
+        })),
+        OpCode::SetGlobal => lifter.push(Instruction::SetGlobal(SetGlobal {
+            src: a,
+            constant: aux()? as usize,
+        })),
+        OpCode::GetImport => {
+            let path = aux()?;
+            let count = (path >> 30) as usize;
+
+            ensure!(
+                (1..=3).contains(&count),
+                "import path has {count} components"
+            );
+
+            let component = |i: usize| ((path >> (20 - 10 * i)) & 0x3ff) as usize;
+
+            lifter.push(Instruction::GetGlobal(GetGlobal {
+                dest: a,
+                constant: component(0),
+            }));
+
+            for i in 1..count {
+                lifter.push(Instruction::GetTable(GetTable {
+                    dest: a,
+                    source: a,
+                    key: Value::ConstantIndex(component(i)),
+                }));
+            }
+        }
+
+        OpCode::GetTable => lifter.push(get_table(Value::StackIndex(c))),
+        OpCode::GetTableKS => lifter.push(get_table(constant(aux()? as usize)?)),
+        OpCode::GetTableN => lifter.push(get_table(Value::Immediate(c as i32 + 1))),
+        OpCode::SetTable => lifter.push(set_table(Value::StackIndex(c))),
+        OpCode::SetTableKS => lifter.push(set_table(constant(aux()? as usize)?)),
+        OpCode::SetTableN => lifter.push(set_table(Value::Immediate(c as i32 + 1))),
+
+        OpCode::NameCall => lifter.push(Instruction::SelfLookup(SelfLookup {
+            dest: a,
+            object: b,
+            key: constant(aux()? as usize)?,
+        })),
+
+        OpCode::Call => {
+            let self_call = pc >= 2 && {
+                let previous = code[pc - 2];
+
+                opcode(previous)? == OpCode::NameCall && ((previous >> 8) & 0xff) as usize == a
+            };
+
+            let num_args = match b {
+                0 => OptVariable::Variable,
+                b => OptVariable::Number(b - 1 - self_call as usize),
+            };
+
+            let num_returns = match c {
+                0 => OptVariable::Variable,
+                c => OptVariable::Number(c - 1),
+            };
+
+            lifter.push(Instruction::Call(Call {
+                callee: a,
+                self_call,
+                num_args,
+                num_returns,
+            }));
+        }
+
+        OpCode::Return => lifter.push(Instruction::Return(Return {
+            result_start: a,
+            result_count: match b {
+                0 => OptVariable::Variable,
+                b => OptVariable::Number(b - 1),
+            },
+        })),
+
+        OpCode::Jump | OpCode::JumpBack => lifter.jump(target(d)),
+        OpCode::JumpX => lifter.jump(target(e)),
+
+        OpCode::JumpIf => {
+            lifter.jump_not(a, target(0));
+            lifter.jump(target(d));
+        }
+        OpCode::JumpIfNot => lifter.jump_not(a, target(d)),
+
+        OpCode::JumpIfEq => compare(
+            lifter,
+            ConditionKind::Eq,
+            Value::StackIndex(aux()? as usize),
+            false,
+        )?,
+        OpCode::JumpIfLe => compare(
+            lifter,
+            ConditionKind::Le,
+            Value::StackIndex(aux()? as usize),
+            false,
+        )?,
+        OpCode::JumpIfLt => compare(
+            lifter,
+            ConditionKind::Lt,
+            Value::StackIndex(aux()? as usize),
+            false,
+        )?,
+        OpCode::JumpIfNotEq => compare(
+            lifter,
+            ConditionKind::Eq,
+            Value::StackIndex(aux()? as usize),
+            true,
+        )?,
+        OpCode::JumpIfNotLe => compare(
+            lifter,
+            ConditionKind::Le,
+            Value::StackIndex(aux()? as usize),
+            true,
+        )?,
+        OpCode::JumpIfNotLt => compare(
+            lifter,
+            ConditionKind::Lt,
+            Value::StackIndex(aux()? as usize),
+            true,
+        )?,
+
+        OpCode::JumpXEqKNil | OpCode::JumpXEqKB | OpCode::JumpXEqKN | OpCode::JumpXEqKS => {
+            let aux = aux()?;
+
+            let right = match op {
+                OpCode::JumpXEqKNil => Value::Nil,
+                OpCode::JumpXEqKB => Value::Boolean(aux & 1 != 0),
+                _ => constant((aux & 0xff_ffff) as usize)?,
+            };
+
+            compare(lifter, ConditionKind::Eq, right, aux >> 31 != 0)?;
+        }
+
+        OpCode::Add
+        | OpCode::Sub
+        | OpCode::Mul
+        | OpCode::Div
+        | OpCode::IDiv
+        | OpCode::Mod
+        | OpCode::Pow => lifter.push(binary(
+            arithmetic(op).unwrap(),
+            a,
+            Value::StackIndex(b),
+            Value::StackIndex(c),
+        )),
+        OpCode::AddK
+        | OpCode::SubK
+        | OpCode::MulK
+        | OpCode::DivK
+        | OpCode::IDivK
+        | OpCode::ModK
+        | OpCode::PowK => lifter.push(binary(
+            arithmetic(op).unwrap(),
+            a,
+            Value::StackIndex(b),
+            constant(c)?,
+        )),
+        OpCode::SubRK | OpCode::DivRK => lifter.push(binary(
+            arithmetic(op).unwrap(),
+            a,
+            constant(b)?,
+            Value::StackIndex(c),
+        )),
+
+        OpCode::And | OpCode::Or | OpCode::AndK | OpCode::OrK => {
+            let right = match op {
+                OpCode::And | OpCode::Or => Value::StackIndex(c),
+                _ => constant(c)?,
+            };
+
+            // `and` keeps the left operand when it is falsy, `or` when it is truthy
+            let (truthy, falsy) = match op {
+                OpCode::And | OpCode::AndK => (right, Value::StackIndex(b)),
+                _ => (Value::StackIndex(b), right),
+            };
+
+            lifter.jump_not(b, Target::Local(3));
+            lifter.push(load(a, truthy));
+            lifter.jump(target(0));
+            lifter.push(load(a, falsy));
+        }
+
+        OpCode::Concat => {
+            ensure!(b < c, "CONCAT needs at least two operands");
+
+            lifter.push(Instruction::Concat(Concat {
+                dest: a,
+                start: b,
+                end: c,
+            }));
+        }
+
+        OpCode::Not => lifter.push(unary(UnaryOpKind::Not)),
+        OpCode::Minus => lifter.push(unary(UnaryOpKind::Neg)),
+        OpCode::Length => lifter.push(unary(UnaryOpKind::Len)),
+
+        OpCode::NewTable => lifter.push(Instruction::NewTable(NewTable {
+            dest: a,
+            array_size: aux()? as usize,
+            table_size: if b == 0 { 0 } else { 1 << (b - 1) },
+        })),
+        OpCode::DupTable => {
+            let index = d as u16 as usize;
+
+            let keys = match prototype.constants.get(index) {
+                Some(RawConstant::Table(keys)) => keys.len(),
+                _ => bail!("constant {index} is not a table template"),
+            };
+
+            lifter.push(Instruction::NewTable(NewTable {
+                dest: a,
+                array_size: 0,
+                table_size: keys,
+            }));
+        }
+
+        OpCode::NewClosure | OpCode::DupClosure => lifter.push(Instruction::Closure(Closure {
+            dest: a,
+            prototype: closure_child(prototype, instruction)?,
+        })),
+        OpCode::GetUpval => lifter.push(Instruction::GetUpvalue(GetUpvalue {
+            dest: a,
+            upvalue: b,
+        })),
+        OpCode::SetUpval => lifter.push(Instruction::SetUpvalue(SetUpvalue { src: a, upvalue: b })),
+        OpCode::CloseUpvals => lifter.push(Instruction::Close(Close { start: a })),
+
+        OpCode::SetList => {
+            let aux = aux()? as usize;
+
+            ensure!(aux > 0, "SETLIST starts at index 0");
+
+            lifter.push(Instruction::SetList(SetList {
+                table: a,
+                start: b,
+                count: match c {
+                    0 => OptVariable::Variable,
+                    c => OptVariable::Number(c - 1),
+                },
+                offset: aux - 1,
+            }));
+        }
+
+        // the counter of a numeric loop is its loop variable as well
+        OpCode::ForNPrep => lifter.branch(target(d), move |target| {
+            Instruction::ForNumPrep(ForNumPrep {
+                target,
+                index: a + 2,
+                limit: a,
+                step: a + 1,
+                var: a + 2,
+            })
+        }),
+        OpCode::ForNLoop => lifter.branch(target(d), move |target| {
+            Instruction::ForNumLoop(ForNumLoop {
+                target,
+                index: a + 2,
+                limit: a,
+                step: a + 1,
+                var: a + 2,
+            })
+        }),
+        // the specialised preparations for `ipairs` and `pairs` only check the iterator
+        OpCode::ForGPrep | OpCode::ForGPrepINext | OpCode::ForGPrepNext => lifter
+            .branch(target(d), move |target| {
+                Instruction::ForGenPrep(ForGenPrep { target, base: a })
+            }),
+        OpCode::ForGLoop => {
+            // the top bit of the auxiliary word marks loops specialised for `ipairs`
+            let count = (aux()? & 0xff) as usize;
+
+            ensure!(count != 0, "FORGLOOP at {pc} stores no results");
+
+            lifter.push(Instruction::ForGenCall(ForGenCall {
+                base: a,
+                dest: a + 3,
+                count,
+            }));
+            lifter.branch(target(d), move |target| {
+                Instruction::ForGenLoop(ForGenLoop {
+                    target,
+                    base: a,
+                    var: a + 3,
+                })
+            });
+        }
+
+        OpCode::GetVarArgs => lifter.push(Instruction::VarArg(VarArg {
+            dest: a,
+            count: match b {
+                0 => OptVariable::Variable,
+                b => OptVariable::Number(b - 1),
+            },
+        })),
+
+        OpCode::NativeCall => bail!("{op:?} cannot be represented in LUNIR IL"),
+    }
+
+    Ok(())
+}
+
+/// Decodes the line of every instruction from a gap, per-instruction offsets and absolute
+/// line numbers for every interval of `1 << gap` instructions.
+fn decode_lines(gap: u8, offsets: &[u8], bases: &[u32]) -> Result<Vec<u32>> {
+    offsets
+        .iter()
+        .enumerate()
+        .map(|(pc, &offset)| {
+            let base = bases
+                .get(pc >> gap)
+                .context("line information is missing an interval")?;
+
+            Ok(base.wrapping_add(offset as u32))
+        })
+        .collect()
+}
+
+/// Reads the primitive types of Luau bytecode.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.position..)
+            .and_then(|rest| rest.get(..count))
+            .with_context(|| format!("unexpected end of bytecode at offset {}", self.position))?;
+
+        self.position += count;
+
+        Ok(bytes)
+    }
+
+    /// Checks that `count` items of at least one byte each fit in the rest of the bytecode, so
+    /// a corrupted count cannot request an enormous allocation.
+    fn check_count(&self, count: usize, what: &str) -> Result<usize> {
+        let remaining = self.bytes.len() - self.position;
+        ensure!(
+            count <= remaining,
+            "{what} count {count} exceeds the {remaining} remaining bytes"
+        );
+
+        Ok(count)
+    }
+
+    fn read_byte(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_varint(&mut self) -> Result<usize> {
+        let mut value = 0usize;
+        let mut shift = 0;
+
+        loop {
+            ensure!(shift < usize::BITS, "varint is too long");
+
+            let byte = self.read_byte()?;
+            value |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    fn read_string_ref(&mut self, strings: &[Vec<u8>]) -> Result<Option<Vec<u8>>> {
+        match self.read_varint()? {
+            0 => Ok(None),
+            id => strings
+                .get(id - 1)
+                .cloned()
+                .map(Some)
+                .with_context(|| format!("string {id} does not exist")),
+        }
+    }
+
+    /// Reads a reference to the debug name of a function or variable, which is only used
+    /// for display, so any bytes that are not valid UTF-8 are replaced.
+    fn read_name_ref(&mut self, strings: &[Vec<u8>]) -> Result<Option<String>> {
+        Ok(self
+            .read_string_ref(strings)?
+            .map(|name| String::from_utf8_lossy(&name).into_owned()))
+    }
+
+    fn read_function(&mut self, version: u8, strings: &[Vec<u8>]) -> Result<Prototype> {
+        let max_stack_size = self.read_byte()?;
+        let param_count = self.read_byte()?;
+        let upvalue_count = self.read_byte()?;
+        let is_vararg = self.read_byte()? != 0;
+
+        if version >= 4 {
+            // flags and type information
+            self.read_byte()?;
+
+            let size = self.read_varint()?;
+            self.read_bytes(size)?;
+        }
+
+        let code_size = self.read_varint()?;
+        let code = (0..code_size)
+            .map(|_| self.read_u32())
+            .collect::<Result<Vec<_>>>()?;
+
+        let constant_count = self.read_varint()?;
+        let constant_count = self.check_count(constant_count, "constant")?;
+        let mut constants = Vec::with_capacity(constant_count);
+
+        for index in 0..constant_count {
+            let tag = ConstantTag::try_from(self.read_byte()?)
+                .map_err(|tag| anyhow!("constant {index} has unknown tag {tag}"))?;
+
+            constants.push(match tag {
+                ConstantTag::Nil => RawConstant::Nil,
+                ConstantTag::Boolean => RawConstant::Boolean(self.read_byte()? != 0),
+                ConstantTag::Number => {
+                    RawConstant::Number(f64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+                }
+                ConstantTag::String => RawConstant::String(
+                    self.read_string_ref(strings)?
+                        .context("string constants cannot be empty references")?,
+                ),
+                ConstantTag::Import => {
+                    self.read_u32()?;
+                    RawConstant::Import
+                }
+                ConstantTag::Table => {
+                    let count = self.read_varint()?;
+
+                    RawConstant::Table(
+                        (0..count)
+                            .map(|_| self.read_varint())
+                            .collect::<Result<_>>()?,
+                    )
+                }
+                ConstantTag::Closure => RawConstant::Closure(self.read_varint()?),
+                ConstantTag::Vector => {
+                    self.read_bytes(16)?;
+                    RawConstant::Vector
+                }
+            });
+        }
+
+        let child_count = self.read_varint()?;
+        let children = (0..child_count)
+            .map(|_| self.read_varint())
+            .collect::<Result<Vec<_>>>()?;
+
+        let line_defined = self.read_varint()? as u32;
+
+        let name = self.read_name_ref(strings)?;
+
+        let mut lineinfo = Vec::new();
+        let mut locals = Vec::new();
+        let mut upvalue_names = Vec::new();
+
+        if self.read_byte()? != 0 {
+            let gap = self.read_byte()?;
+            ensure!(gap < 32, "line gap {gap} is out of range");
+
+            let intervals = if code_size == 0 {
+                0
+            } else {
+                ((code_size - 1) >> gap) + 1
+            };
+
+            let mut offsets = Vec::with_capacity(code_size);
+            let mut last_offset = 0u8;
+
+            for _ in 0..code_size {
+                last_offset = last_offset.wrapping_add(self.read_byte()?);
+                offsets.push(last_offset);
+            }
+
+            let mut bases = Vec::with_capacity(intervals);
+            let mut last_line = 0u32;
+
+            for _ in 0..intervals {
+                last_line = last_line.wrapping_add(self.read_u32()?);
+                bases.push(last_line);
+            }
+
+            lineinfo = decode_lines(gap, &offsets, &bases)?;
+        }
+
+        if self.read_byte()? != 0 {
+            for _ in 0..self.read_varint()? {
+                let name = self.read_name_ref(strings)?.unwrap_or_default();
+                let start = self.read_varint()?;
+                let end = self.read_varint()?;
+
+                locals.push((name, start, end, self.read_byte()? as usize));
+            }
+
+            for _ in 0..self.read_varint()? {
+                upvalue_names.push(self.read_name_ref(strings)?.unwrap_or_default());
+            }
+        }
+
+        Ok(Prototype {
+            max_stack_size,
+            param_count,
+            upvalue_count,
+            is_vararg,
+            code,
+            constants,
+            children,
+            name,
+            line_defined,
+            lineinfo,
+            locals,
+            upvalue_names,
+        })
+    }
+}
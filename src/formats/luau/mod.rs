@@ -0,0 +1,237 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+mod deserializer;
+mod serializer;
+mod tests;
+
+pub use deserializer::*;
+pub use serializer::*;
+
+/// The oldest bytecode version the Luau virtual machine still loads.
+pub const VERSION_MIN: u8 = 3;
+
+/// The newest bytecode version known to LUNIR.
+pub const VERSION_MAX: u8 = 6;
+
+/// The version of the type information encoding, written from bytecode version 4 onwards.
+pub const TYPES_VERSION: u8 = 1;
+
+/// Marks a function as compiled with `--!native`, requires bytecode version 4.
+pub const PROTO_FLAG_NATIVE_MODULE: u8 = 1 << 0;
+
+/// The largest constant index the `D` operand of `LOADK` can encode, larger indices need
+/// `LOADKX`.
+pub(crate) const MAX_D_CONSTANT: usize = i16::MAX as usize;
+
+/// The largest constant index a single import path component can have.
+pub(crate) const MAX_IMPORT_CONSTANT: usize = (1 << 10) - 1;
+
+/// The maximum number of registers a Luau function may use.
+pub const MAX_STACK: usize = 255;
+
+/// The maximum number of upvalues a Luau function may capture.
+pub const MAX_UPVALUES: usize = 200;
+
+/// The maximum number of array items a single `SETLIST` stores when building table
+/// constants.
+pub const FIELDS_PER_FLUSH: usize = 32;
+
+/// All Luau opcodes, in the order of `Bytecode.h`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OpCode {
+    Nop,
+    Break,
+    LoadNil,
+    LoadB,
+    LoadN,
+    LoadK,
+    Move,
+    GetGlobal,
+    SetGlobal,
+    GetUpval,
+    SetUpval,
+    CloseUpvals,
+    GetImport,
+    GetTable,
+    SetTable,
+    GetTableKS,
+    SetTableKS,
+    GetTableN,
+    SetTableN,
+    NewClosure,
+    NameCall,
+    Call,
+    Return,
+    Jump,
+    JumpBack,
+    JumpIf,
+    JumpIfNot,
+    JumpIfEq,
+    JumpIfLe,
+    JumpIfLt,
+    JumpIfNotEq,
+    JumpIfNotLe,
+    JumpIfNotLt,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    AddK,
+    SubK,
+    MulK,
+    DivK,
+    ModK,
+    PowK,
+    And,
+    Or,
+    AndK,
+    OrK,
+    Concat,
+    Not,
+    Minus,
+    Length,
+    NewTable,
+    DupTable,
+    SetList,
+    ForNPrep,
+    ForNLoop,
+    ForGLoop,
+    ForGPrepINext,
+    FastCall3,
+    ForGPrepNext,
+    NativeCall,
+    GetVarArgs,
+    DupClosure,
+    PrepVarArgs,
+    LoadKX,
+    JumpX,
+    FastCall,
+    Coverage,
+    Capture,
+    SubRK,
+    DivRK,
+    FastCall1,
+    FastCall2,
+    FastCall2K,
+    ForGPrep,
+    JumpXEqKNil,
+    JumpXEqKB,
+    JumpXEqKN,
+    JumpXEqKS,
+    IDiv,
+    IDivK,
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if value <= OpCode::IDivK as u8 {
+            // SAFETY: `OpCode` is `repr(u8)` with contiguous discriminants up to `IDivK`
+            Ok(unsafe { std::mem::transmute::<u8, OpCode>(value) })
+        } else {
+            Err(value)
+        }
+    }
+}
+
+/// The tags of the entries of a Luau constant table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ConstantTag {
+    Nil,
+    Boolean,
+    Number,
+    String,
+    Import,
+    Table,
+    Closure,
+    Vector,
+}
+
+impl TryFrom<u8> for ConstantTag {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if value <= ConstantTag::Vector as u8 {
+            // SAFETY: `ConstantTag` is `repr(u8)` with contiguous discriminants up to `Vector`
+            Ok(unsafe { std::mem::transmute::<u8, ConstantTag>(value) })
+        } else {
+            Err(value)
+        }
+    }
+}
+
+/// How a `CAPTURE` pseudo-instruction following `NEWCLOSURE` captures an upvalue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CaptureType {
+    /// A copy of a register that is never written to after the capture.
+    Val,
+    /// A reference to a register.
+    Ref,
+    /// An upvalue of the enclosing function.
+    Upval,
+}
+
+impl TryFrom<u8> for CaptureType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Val),
+            1 => Ok(Self::Ref),
+            2 => Ok(Self::Upval),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Encodes an instruction in the `ABC` format.
+pub(crate) fn encode_abc(op: OpCode, a: usize, b: usize, c: usize) -> u32 {
+    (op as u32) | ((a as u32 & 0xff) << 8) | ((b as u32 & 0xff) << 16) | ((c as u32 & 0xff) << 24)
+}
+
+/// Encodes an instruction in the `AD` format, `D` is a signed 16 bit operand.
+pub(crate) fn encode_ad(op: OpCode, a: usize, d: i32) -> u32 {
+    (op as u32) | ((a as u32 & 0xff) << 8) | ((d as u32 & 0xffff) << 16)
+}
+
+/// Encodes an instruction in the `E` format, `E` is a signed 24 bit operand.
+pub(crate) fn encode_e(op: OpCode, e: i32) -> u32 {
+    (op as u32) | ((e as u32 & 0xff_ffff) << 8)
+}
+
+/// Encodes the id of an import path, each component is the constant index of a string.
+pub(crate) fn encode_import(path: &[usize]) -> u32 {
+    let mut id = (path.len() as u32) << 30;
+
+    for (i, &component) in path.iter().enumerate() {
+        id |= (component as u32) << (20 - 10 * i);
+    }
+
+    id
+}
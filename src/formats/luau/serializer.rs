@@ -0,0 +1,1668 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use super::*;
+use crate::formats::{integer_to_number, pc_at, Feature, LoopLayout, Serializer};
+use crate::ir::il::{
+    registers_used, resolve_labels, BinaryOpKind, ConditionKind, Constant, Function, Instruction,
+    Label, OptVariable, Table, UnaryOpKind, Value,
+};
+use anyhow::{bail, ensure, Context, Result};
+use derive_builder::Builder;
+use std::collections::{HashMap, HashSet};
+
+/// A constant as it is stored in a Luau constant table, numbers are kept as their bit
+/// pattern so that constants can be deduplicated and strings refer to the string table.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum LuauConstant {
+    Nil,
+    Boolean(bool),
+    Number(u64),
+    String(usize),
+    Import(u32),
+}
+
+/// Serializes LUNIR intermediate language into Luau bytecode that can be loaded by
+/// `luau_load`.
+#[derive(Builder, Clone, Debug)]
+#[builder(default, build_fn(validate = "Self::validate"))]
+pub struct LuauSerializer {
+    /// The lowest bytecode version to emit, a newer one is picked when a feature in use
+    /// requires it.
+    min_version: u8,
+    /// The highest bytecode version accepted by the virtual machine loading the output.
+    max_version: u8,
+    /// Whether functions are flagged for native code generation.
+    native: bool,
+    /// Whether line information and debug names should be omitted.
+    strip_debug: bool,
+}
+
+impl Default for LuauSerializer {
+    fn default() -> Self {
+        Self {
+            min_version: VERSION_MIN,
+            max_version: VERSION_MAX,
+            native: false,
+            strip_debug: false,
+        }
+    }
+}
+
+impl LuauSerializerBuilder {
+    fn validate(&self) -> Result<(), String> {
+        let min = self.min_version.unwrap_or(VERSION_MIN);
+        let max = self.max_version.unwrap_or(VERSION_MAX);
+
+        if min < VERSION_MIN || max > VERSION_MAX || min > max {
+            return Err(format!(
+                "versions {min}..={max} are not within {VERSION_MIN}..={VERSION_MAX}"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl LuauSerializer {
+    /// Creates a builder for a `LuauSerializer`.
+    pub fn builder() -> LuauSerializerBuilder {
+        LuauSerializerBuilder::default()
+    }
+
+    /// Picks the oldest bytecode version that supports every feature in use, which are the
+    /// function flags and the opcodes emitted for `protos`.
+    fn version(&self, protos: &[FunctionState]) -> Result<u8> {
+        let mut required = protos
+            .iter()
+            .map(|state| state.required_version)
+            .fold(VERSION_MIN, u8::max);
+
+        // function flags were introduced in version 4
+        if self.native {
+            required = required.max(4);
+        }
+
+        let version = self.min_version.max(required);
+
+        ensure!(
+            version <= self.max_version,
+            "output requires bytecode version {version}, but the target only accepts up to {}",
+            self.max_version
+        );
+
+        Ok(version)
+    }
+}
+
+impl Serializer for LuauSerializer {
+    /// Serializes `function` as the main function of a bytecode blob.
+    fn serialize(&self, function: &Function) -> Result<Vec<u8>> {
+        let mut strings = Strings::default();
+        let mut protos = Vec::new();
+
+        let main = self.lower(function, &mut strings, &mut protos)?;
+
+        let version = self.version(&protos)?;
+
+        let mut writer = Writer {
+            buf: Vec::with_capacity(256),
+        };
+
+        writer.write_byte(version);
+
+        if version >= 4 {
+            writer.write_byte(TYPES_VERSION);
+        }
+
+        writer.write_varint(strings.strings.len());
+        for string in &strings.strings {
+            writer.write_varint(string.len());
+            writer.buf.extend_from_slice(string);
+        }
+
+        writer.write_varint(protos.len());
+        for state in &protos {
+            writer.write_function(state, version);
+        }
+
+        writer.write_varint(main);
+
+        Ok(writer.buf)
+    }
+
+    fn supports(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::BitwiseOperators => false,
+            // `IDIV` and `IDIVK` were introduced in version 4, older targets polyfill them
+            Feature::IntegerDivision => self.max_version >= 4,
+        }
+    }
+
+    fn loop_layout(&self) -> LoopLayout {
+        LoopLayout::Luau
+    }
+}
+
+impl LuauSerializer {
+    /// Lowers `function` after the functions nested in it, as the proto list of a bytecode
+    /// blob lists children before their parents, and returns its index in the list.
+    fn lower<'a>(
+        &'a self,
+        function: &'a Function,
+        strings: &mut Strings,
+        protos: &mut Vec<FunctionState<'a>>,
+    ) -> Result<usize> {
+        let children = function
+            .prototypes
+            .iter()
+            .enumerate()
+            .map(|(index, child)| {
+                self.lower(child, strings, protos)
+                    .with_context(|| format!("failed to lower prototype {index}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut long_jumps = HashSet::new();
+
+        // jumps are lowered to their short forms until they are known not to fit
+        let state = loop {
+            let mut state = FunctionState::lower(
+                self,
+                function,
+                std::mem::take(strings),
+                children.clone(),
+                long_jumps.clone(),
+            )?;
+
+            *strings = std::mem::take(&mut state.strings);
+
+            if state.overflowing.is_empty() {
+                break state;
+            }
+
+            long_jumps.extend(state.overflowing.iter().copied());
+        };
+
+        protos.push(state);
+
+        Ok(protos.len() - 1)
+    }
+}
+
+/// The string table shared by every function of a bytecode blob.
+#[derive(Default)]
+struct Strings {
+    strings: Vec<Vec<u8>>,
+    lookup: HashMap<Vec<u8>, usize>,
+}
+
+impl Strings {
+    fn intern(&mut self, string: &[u8]) -> usize {
+        if let Some(&index) = self.lookup.get(string) {
+            return index;
+        }
+
+        self.strings.push(string.to_vec());
+        self.lookup.insert(string.to_vec(), self.strings.len());
+
+        // string references are one-based, zero refers to no string
+        self.strings.len()
+    }
+}
+
+/// Instruction sequences that are lowered to a single Luau instruction.
+enum Fusion {
+    /// A global followed by up to two constant field lookups, lowered to `GETIMPORT`.
+    Import { constant: usize, id: u32 },
+    /// A method lookup and the placement of `self`, lowered to `NAMECALL`.
+    NameCall { object: usize, method: usize },
+}
+
+/// A conditional or unconditional branch, `aux` is the auxiliary word of comparisons.
+#[derive(Clone, Copy)]
+struct Branch {
+    op: OpCode,
+    a: usize,
+    aux: Option<u32>,
+}
+
+impl Branch {
+    fn always() -> Self {
+        Self {
+            op: OpCode::Jump,
+            a: 0,
+            aux: None,
+        }
+    }
+
+    fn inverse(self) -> Self {
+        let (op, aux) = match self.op {
+            OpCode::JumpIf => (OpCode::JumpIfNot, self.aux),
+            OpCode::JumpIfNot => (OpCode::JumpIf, self.aux),
+            OpCode::JumpIfEq => (OpCode::JumpIfNotEq, self.aux),
+            OpCode::JumpIfNotEq => (OpCode::JumpIfEq, self.aux),
+            OpCode::JumpIfLe => (OpCode::JumpIfNotLe, self.aux),
+            OpCode::JumpIfNotLe => (OpCode::JumpIfLe, self.aux),
+            OpCode::JumpIfLt => (OpCode::JumpIfNotLt, self.aux),
+            OpCode::JumpIfNotLt => (OpCode::JumpIfLt, self.aux),
+            // the constant comparisons keep their negation in the top bit of `aux`
+            op => (op, self.aux.map(|aux| aux ^ (1 << 31))),
+        };
+
+        Self { op, aux, ..self }
+    }
+}
+
+/// A jump which needs its offset patched once every instruction has been emitted.
+struct Fixup {
+    pc: usize,
+    target: usize,
+    instruction: usize,
+    long: bool,
+}
+
+/// The state of a single function while it is being lowered to Luau opcodes.
+struct FunctionState<'a> {
+    serializer: &'a LuauSerializer,
+    prototype: &'a Function,
+
+    code: Vec<u32>,
+    lineinfo: Vec<u32>,
+    line: u32,
+
+    /// The string table of the blob, which is handed from function to function.
+    strings: Strings,
+    /// The string reference of the debug name of the function.
+    debug_name: usize,
+    /// The string references of the names of the locals and of the upvalues.
+    local_names: Vec<usize>,
+    upvalue_names: Vec<usize>,
+
+    /// The oldest bytecode version that has every opcode emitted so far.
+    required_version: u8,
+
+    constants: Vec<LuauConstant>,
+    constant_lookup: HashMap<LuauConstant, usize>,
+    /// Maps the index of every constant in `prototype` to its index in `constants`, tables
+    /// have no mapping as they are built at the point of use instead.
+    constant_map: Vec<Option<usize>>,
+
+    /// The first register that is free to be used as a temporary.
+    base: usize,
+    /// The first register that is not currently holding a temporary.
+    top: usize,
+    max_stack_size: usize,
+
+    fusions: HashMap<usize, Fusion>,
+    /// IL instructions which are folded into a fusion and emit no code of their own.
+    skipped: Vec<bool>,
+
+    /// The IL instruction currently being lowered.
+    current: usize,
+    /// The program counter at which the lowering of each IL instruction begins.
+    pcs: Vec<usize>,
+    fixups: Vec<Fixup>,
+    /// The IL index of every label defined in the function.
+    labels: HashMap<Label, usize>,
+    /// IL instructions whose branches must use `JUMPX` as their offset does not fit into
+    /// 16 bits.
+    long_jumps: HashSet<usize>,
+    /// IL instructions whose short branches turned out not to fit.
+    overflowing: Vec<usize>,
+
+    /// The indices of the functions nested in this one in the proto list.
+    children: Vec<usize>,
+}
+
+impl<'a> FunctionState<'a> {
+    fn lower(
+        serializer: &'a LuauSerializer,
+        prototype: &'a Function,
+        strings: Strings,
+        children: Vec<usize>,
+        long_jumps: HashSet<usize>,
+    ) -> Result<Self> {
+        let instructions = prototype.chunk.inner();
+
+        ensure!(
+            prototype.upvalues.len() <= MAX_UPVALUES,
+            "function has {} upvalues but Luau only allows {MAX_UPVALUES}",
+            prototype.upvalues.len()
+        );
+
+        ensure!(
+            !prototype.arity.needs_arg,
+            "Luau has no implicit `arg` table for variadic functions"
+        );
+
+        let base = instructions
+            .iter()
+            .map(registers_used)
+            .max()
+            .unwrap_or(0)
+            .max(prototype.max_stack_size as usize)
+            .max(prototype.arity.params as usize);
+
+        let mut state = Self {
+            serializer,
+            prototype,
+            code: Vec::with_capacity(instructions.len()),
+            lineinfo: Vec::with_capacity(instructions.len()),
+            line: 0,
+            strings,
+            debug_name: 0,
+            local_names: Vec::new(),
+            upvalue_names: Vec::new(),
+            required_version: VERSION_MIN,
+            constants: Vec::with_capacity(prototype.constants.len()),
+            constant_lookup: HashMap::new(),
+            constant_map: Vec::with_capacity(prototype.constants.len()),
+            base,
+            top: base,
+            max_stack_size: base,
+            fusions: HashMap::new(),
+            skipped: vec![false; instructions.len()],
+            current: 0,
+            pcs: Vec::with_capacity(instructions.len() + 1),
+            fixups: Vec::new(),
+            labels: resolve_labels(instructions)?,
+            long_jumps,
+            overflowing: Vec::new(),
+            children,
+        };
+
+        for constant in &prototype.constants {
+            let mapped = match constant {
+                Constant::Nil => Some(state.constant(LuauConstant::Nil)),
+                Constant::Boolean(b) => Some(state.constant(LuauConstant::Boolean(*b))),
+                Constant::Number(n) => Some(state.constant(LuauConstant::Number(n.to_bits()))),
+                Constant::Integer(n) => {
+                    let n = integer_to_number(*n)?;
+                    Some(state.constant(LuauConstant::Number(n.to_bits())))
+                }
+                Constant::String(s) => {
+                    let string = state.string(s);
+                    Some(state.constant(LuauConstant::String(string)))
+                }
+                Constant::Table(_) => None,
+            };
+
+            state.constant_map.push(mapped);
+        }
+
+        if !serializer.strip_debug {
+            if let Some(name) = &prototype.name {
+                state.debug_name = state.string(name.as_bytes());
+            }
+
+            let debug = &prototype.debug;
+
+            state.local_names = debug
+                .locals
+                .iter()
+                .map(|local| state.string(local.name.as_bytes()))
+                .collect();
+
+            // every upvalue is named when any is, with an empty reference for a missing name
+            if !debug.upvalue_names.is_empty() {
+                state.upvalue_names = (0..prototype.upvalues.len())
+                    .map(|index| match debug.upvalue_names.get(index) {
+                        Some(name) => state.string(name.as_bytes()),
+                        None => 0,
+                    })
+                    .collect();
+            }
+        }
+
+        state.find_fusions(instructions);
+
+        // instructions without a span keep the line of the instruction before them
+        if let Some(span) = prototype.chunk.spanned().find_map(|(_, span)| span) {
+            state.line = span.line;
+        }
+
+        if prototype.arity.is_vararg {
+            state.emit(encode_abc(
+                OpCode::PrepVarArgs,
+                prototype.arity.params as usize,
+                0,
+                0,
+            ));
+        }
+
+        for (index, (instruction, span)) in prototype.chunk.spanned().enumerate() {
+            state.pcs.push(state.code.len());
+            state.current = index;
+
+            if let Some(span) = span {
+                state.line = span.line;
+            }
+
+            if state.skipped[index] {
+                continue;
+            }
+
+            state
+                .lower_instruction(instruction)
+                .with_context(|| format!("failed to lower instruction {index}: {instruction:?}"))?;
+
+            state.top = state.base;
+        }
+
+        state.pcs.push(state.code.len());
+
+        if !matches!(instructions.last(), Some(Instruction::Return(_))) {
+            state.emit(encode_abc(OpCode::Return, 0, 1, 0));
+        }
+
+        state.patch_jumps()?;
+
+        ensure!(
+            state.max_stack_size <= MAX_STACK,
+            "function needs {} registers but Luau only allows {MAX_STACK}",
+            state.max_stack_size
+        );
+
+        Ok(state)
+    }
+
+    /// Raises the bytecode version of the blob to at least `version`.
+    fn require_version(&mut self, version: u8) {
+        self.required_version = self.required_version.max(version);
+    }
+
+    fn emit(&mut self, instruction: u32) -> usize {
+        self.code.push(instruction);
+        self.lineinfo.push(self.line);
+
+        self.code.len() - 1
+    }
+
+    fn string(&mut self, string: &[u8]) -> usize {
+        self.strings.intern(string)
+    }
+
+    fn constant(&mut self, constant: LuauConstant) -> usize {
+        if let Some(&index) = self.constant_lookup.get(&constant) {
+            return index;
+        }
+
+        let index = self.constants.len();
+        self.constants.push(constant.clone());
+        self.constant_lookup.insert(constant, index);
+
+        index
+    }
+
+    fn string_constant(&mut self, string: &str) -> usize {
+        let string = self.string(string.as_bytes());
+        self.constant(LuauConstant::String(string))
+    }
+
+    /// Returns the output index of the IL constant `index` if it is a string.
+    fn il_string_constant(&self, index: usize) -> Option<usize> {
+        match self.prototype.constants.get(index) {
+            Some(Constant::String(_)) => self.constant_map[index],
+            _ => None,
+        }
+    }
+
+    /// Returns the output index of `value` if it is a number that is known at compile time.
+    fn number_constant(&mut self, value: &Value) -> Option<usize> {
+        match value {
+            Value::Immediate(i) => Some(self.constant(LuauConstant::Number((*i as f64).to_bits()))),
+            Value::ConstantIndex(index) => match self.prototype.constants.get(*index) {
+                Some(Constant::Number(_) | Constant::Integer(_)) => self.constant_map[*index],
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn find_fusions(&mut self, instructions: &[Instruction]) {
+        let is_method = |instruction: &Instruction, callee: usize| match instruction {
+            Instruction::GetTable(get) if get.dest == callee => match get.key {
+                Value::ConstantIndex(key) => {
+                    self.il_string_constant(key).map(|_| (get.source, key))
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+
+        let is_self = |instruction: &Instruction, callee: usize| match instruction {
+            Instruction::Move(mv) if mv.dest == callee + 1 => Some(mv.src),
+            Instruction::Load(load) if load.dest == callee + 1 => match load.src {
+                Value::StackIndex(object) => Some(object),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        let mut name_calls = vec![];
+
+        for (index, instruction) in instructions.iter().enumerate().skip(1) {
+            let call = match instruction {
+                Instruction::Call(call) if call.self_call => call,
+                _ => continue,
+            };
+
+            // NAMECALL must directly precede its CALL, so a label between them keeps them apart
+            if let Instruction::SelfLookup(lookup) = &instructions[index - 1] {
+                if let Value::ConstantIndex(key) = lookup.key {
+                    if lookup.dest == call.callee && self.il_string_constant(key).is_some() {
+                        name_calls.push((index, 1, lookup.object, key));
+                    }
+                }
+
+                continue;
+            }
+
+            if index < 2 {
+                continue;
+            }
+
+            let (first, second) = (&instructions[index - 2], &instructions[index - 1]);
+
+            let fused = match (is_method(first, call.callee), is_self(second, call.callee)) {
+                (Some((object, method)), Some(this)) if object == this && object != call.callee => {
+                    Some((object, method))
+                }
+                _ => match (is_self(first, call.callee), is_method(second, call.callee)) {
+                    (Some(this), Some((object, method)))
+                        if object == this && object != call.callee + 1 =>
+                    {
+                        Some((object, method))
+                    }
+                    _ => None,
+                },
+            };
+
+            if let Some((object, method)) = fused {
+                name_calls.push((index, 2, object, method));
+            }
+        }
+
+        for (index, length, object, method) in name_calls {
+            self.skipped[index - length..index].fill(true);
+            self.fusions
+                .insert(index, Fusion::NameCall { object, method });
+        }
+
+        let prototype = self.prototype;
+
+        // globals that are assigned to cannot be resolved when the chunk is loaded
+        let assigned = instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::SetGlobal(set) => match prototype.constants.get(set.constant) {
+                    Some(Constant::String(name)) => Some(name.as_slice()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+
+        for (index, instruction) in instructions.iter().enumerate() {
+            let get = match instruction {
+                Instruction::GetGlobal(get) if !self.skipped[index] => get,
+                _ => continue,
+            };
+
+            match prototype.constants.get(get.constant) {
+                Some(Constant::String(name)) if !assigned.contains(name.as_slice()) => {}
+                _ => continue,
+            }
+
+            let mut path = vec![self.constant_map[get.constant].unwrap()];
+
+            for (next, instruction) in instructions.iter().enumerate().skip(index + 1).take(2) {
+                if self.skipped[next] {
+                    break;
+                }
+
+                match instruction {
+                    Instruction::GetTable(field)
+                        if field.dest == get.dest && field.source == get.dest =>
+                    {
+                        match field.key {
+                            Value::ConstantIndex(key) => match self.il_string_constant(key) {
+                                Some(key) => path.push(key),
+                                None => break,
+                            },
+                            _ => break,
+                        }
+                    }
+                    _ => break,
+                }
+            }
+
+            if path
+                .iter()
+                .any(|&component| component > MAX_IMPORT_CONSTANT)
+            {
+                continue;
+            }
+
+            let id = encode_import(&path);
+            let constant = self.constant(LuauConstant::Import(id));
+
+            if constant > MAX_D_CONSTANT {
+                continue;
+            }
+
+            for skipped in &mut self.skipped[index + 1..index + path.len()] {
+                *skipped = true;
+            }
+
+            self.fusions.insert(index, Fusion::Import { constant, id });
+        }
+    }
+
+    fn temporary(&mut self) -> Result<usize> {
+        let register = self.top;
+
+        self.top += 1;
+        self.max_stack_size = self.max_stack_size.max(self.top);
+
+        ensure!(
+            self.top <= MAX_STACK,
+            "ran out of registers for temporaries"
+        );
+
+        Ok(register)
+    }
+
+    /// Loads the constant at `index` in the output constant table into `register`.
+    fn load_k(&mut self, register: usize, index: usize) {
+        if index <= MAX_D_CONSTANT {
+            self.emit(encode_ad(OpCode::LoadK, register, index as i32));
+        } else {
+            self.emit(encode_abc(OpCode::LoadKX, register, 0, 0));
+            self.emit(index as u32);
+        }
+    }
+
+    fn load_number(&mut self, register: usize, number: f64) {
+        let fits = number.fract() == 0.0
+            && (i16::MIN as f64..=i16::MAX as f64).contains(&number)
+            && !(number == 0.0 && number.is_sign_negative());
+
+        if fits {
+            self.emit(encode_ad(OpCode::LoadN, register, number as i32));
+        } else {
+            let constant = self.constant(LuauConstant::Number(number.to_bits()));
+            self.load_k(register, constant);
+        }
+    }
+
+    /// Emits the instructions needed to place `value` into `register`.
+    fn load_into(&mut self, register: usize, value: &Value) -> Result<()> {
+        match value {
+            Value::Nil => {
+                self.emit(encode_abc(OpCode::LoadNil, register, 0, 0));
+            }
+            Value::Boolean(b) => {
+                self.emit(encode_abc(OpCode::LoadB, register, *b as usize, 0));
+            }
+            Value::Immediate(i) => self.load_number(register, *i as f64),
+            Value::StackIndex(source) => {
+                if *source != register {
+                    self.emit(encode_abc(OpCode::Move, register, *source, 0));
+                }
+            }
+            Value::ConstantIndex(index) => {
+                let prototype = self.prototype;
+
+                match prototype.constants.get(*index) {
+                    Some(Constant::Table(table)) => self.load_table(register, table)?,
+                    Some(Constant::Number(n)) => self.load_number(register, *n),
+                    Some(Constant::Integer(n)) => self.load_number(register, *n as f64),
+                    Some(_) => self.load_k(register, self.constant_map[*index].unwrap()),
+                    None => bail!("constant {index} does not exist"),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds a table constant in `register`.
+    fn load_table(&mut self, register: usize, table: &Table) -> Result<()> {
+        let mark = self.top;
+        let table_register = self.temporary()?;
+
+        match table {
+            Table::Array(items) => {
+                self.emit(encode_abc(OpCode::NewTable, table_register, 0, 0));
+                self.emit(items.len() as u32);
+
+                for (batch, items) in items.chunks(FIELDS_PER_FLUSH).enumerate() {
+                    let first = self.top;
+
+                    for item in items {
+                        let item_register = self.temporary()?;
+                        self.load_into(item_register, item)?;
+                        self.top = item_register + 1;
+                    }
+
+                    self.set_list(
+                        table_register,
+                        first,
+                        &OptVariable::Number(items.len()),
+                        batch * FIELDS_PER_FLUSH,
+                    )?;
+
+                    self.top = table_register + 1;
+                }
+            }
+            Table::Map(map) => {
+                self.emit(encode_abc(
+                    OpCode::NewTable,
+                    table_register,
+                    encode_hash_size(map.len()),
+                    0,
+                ));
+                self.emit(0);
+
+                for (key, value) in map {
+                    ensure!(*key != Value::Nil, "table constant has a nil key");
+
+                    self.store(table_register, key, value)?;
+                    self.top = table_register + 1;
+                }
+            }
+        }
+
+        if register != table_register {
+            self.emit(encode_abc(OpCode::Move, register, table_register, 0));
+        }
+
+        self.top = mark;
+
+        Ok(())
+    }
+
+    /// Loads `table[key]` into `dest`, preferring the `GETTABLEKS` and `GETTABLEN` forms.
+    fn load_field(&mut self, dest: usize, table: usize, key: &Value) -> Result<()> {
+        match key {
+            Value::ConstantIndex(index) if self.il_string_constant(*index).is_some() => {
+                let key = self.il_string_constant(*index).unwrap();
+
+                self.emit(encode_abc(OpCode::GetTableKS, dest, table, 0));
+                self.emit(key as u32);
+            }
+            Value::Immediate(n @ 1..=256) => {
+                self.emit(encode_abc(OpCode::GetTableN, dest, table, *n as usize - 1));
+            }
+            _ => {
+                let key = self.register(key)?;
+                self.emit(encode_abc(OpCode::GetTable, dest, table, key));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stores `value` into `table[key]`, preferring the `SETTABLEKS` and `SETTABLEN` forms.
+    fn store(&mut self, table: usize, key: &Value, value: &Value) -> Result<()> {
+        let value = self.register(value)?;
+
+        match key {
+            Value::ConstantIndex(index) if self.il_string_constant(*index).is_some() => {
+                let key = self.il_string_constant(*index).unwrap();
+
+                self.emit(encode_abc(OpCode::SetTableKS, value, table, 0));
+                self.emit(key as u32);
+            }
+            Value::Immediate(n @ 1..=256) => {
+                self.emit(encode_abc(OpCode::SetTableN, value, table, *n as usize - 1));
+            }
+            _ => {
+                let key = self.register(key)?;
+
+                self.emit(encode_abc(OpCode::SetTable, value, table, key));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Emits a `SETLIST` storing `count` values from `start` into `table` from index
+    /// `offset + 1`.
+    fn set_list(
+        &mut self,
+        table: usize,
+        start: usize,
+        count: &OptVariable,
+        offset: usize,
+    ) -> Result<()> {
+        let count = match count {
+            OptVariable::Variable => 0,
+            OptVariable::Number(count) => count + 1,
+        };
+
+        ensure!(count <= 0xff, "too many values in SETLIST");
+        ensure!(
+            offset < u32::MAX as usize,
+            "SETLIST offset {offset} is out of range"
+        );
+
+        self.emit(encode_abc(OpCode::SetList, table, start, count));
+        self.emit((offset + 1) as u32);
+
+        Ok(())
+    }
+
+    /// Returns a register holding `value`, loading it into a temporary if needed.
+    fn register(&mut self, value: &Value) -> Result<usize> {
+        match value {
+            Value::StackIndex(index) => Ok(*index),
+            _ => {
+                let register = self.temporary()?;
+                self.load_into(register, value)?;
+
+                Ok(register)
+            }
+        }
+    }
+
+    /// Loads a global, or a field of a global, into `register`.
+    fn load_path(&mut self, register: usize, path: &[&str]) {
+        let components = path
+            .iter()
+            .map(|name| self.string_constant(name))
+            .collect::<Vec<_>>();
+
+        let id = encode_import(&components);
+        let constant = self.constant(LuauConstant::Import(id));
+
+        if components.iter().all(|&c| c <= MAX_IMPORT_CONSTANT) && constant <= MAX_D_CONSTANT {
+            self.emit(encode_ad(OpCode::GetImport, register, constant as i32));
+            self.emit(id);
+
+            return;
+        }
+
+        self.emit(encode_abc(OpCode::GetGlobal, register, 0, 0));
+        self.emit(components[0] as u32);
+
+        for &component in &components[1..] {
+            self.emit(encode_abc(OpCode::GetTableKS, register, register, 0));
+            self.emit(component as u32);
+        }
+    }
+
+    /// Returns the IL index of the instruction marking `label`.
+    fn target(&self, label: Label) -> usize {
+        self.labels[&label]
+    }
+
+    /// Emits `branch` towards the IL instruction `target`, using `JUMPX` behind an
+    /// inverted branch if the current instruction needs long jumps.
+    fn branch(&mut self, branch: Branch, target: usize) {
+        let long = self.long_jumps.contains(&self.current);
+
+        if long {
+            if branch.op != OpCode::Jump {
+                let inverse = branch.inverse();
+                let skip = 1 + inverse.aux.is_some() as i32;
+
+                self.emit(encode_ad(inverse.op, inverse.a, skip));
+
+                if let Some(aux) = inverse.aux {
+                    self.emit(aux);
+                }
+            }
+
+            let pc = self.emit(encode_e(OpCode::JumpX, 0));
+            self.fixup(pc, target, true);
+        } else {
+            let pc = self.emit(encode_ad(branch.op, branch.a, 0));
+
+            if let Some(aux) = branch.aux {
+                self.emit(aux);
+            }
+
+            self.fixup(pc, target, false);
+        }
+    }
+
+    /// Emits a loop instruction jumping to `target`, loops cannot be extended with `JUMPX`.
+    fn loop_branch(&mut self, op: OpCode, a: usize, aux: Option<u32>, target: usize) {
+        let pc = self.emit(encode_ad(op, a, 0));
+
+        if let Some(aux) = aux {
+            self.emit(aux);
+        }
+
+        self.fixup(pc, target, false);
+    }
+
+    fn fixup(&mut self, pc: usize, target: usize, long: bool) {
+        self.fixups.push(Fixup {
+            pc,
+            target,
+            instruction: self.current,
+            long,
+        });
+    }
+
+    /// Returns the branch taken when `left == right` holds, or its negation.
+    fn equality(&mut self, left: &Value, right: &Value, negated: bool) -> Result<Branch> {
+        let not = (negated as u32) << 31;
+
+        let constant = |state: &mut Self, value: &Value| -> Option<(OpCode, u32)> {
+            let (op, aux) = match value {
+                Value::Nil => (OpCode::JumpXEqKNil, 0),
+                Value::Boolean(b) => (OpCode::JumpXEqKB, *b as u32),
+                Value::Immediate(_) => (OpCode::JumpXEqKN, state.number_constant(value)? as u32),
+                Value::ConstantIndex(index) => match state.prototype.constants.get(*index)? {
+                    Constant::Nil => (OpCode::JumpXEqKNil, 0),
+                    Constant::Boolean(b) => (OpCode::JumpXEqKB, *b as u32),
+                    Constant::Number(_) | Constant::Integer(_) => {
+                        (OpCode::JumpXEqKN, state.constant_map[*index]? as u32)
+                    }
+                    Constant::String(_) => (OpCode::JumpXEqKS, state.constant_map[*index]? as u32),
+                    _ => return None,
+                },
+                Value::StackIndex(_) => return None,
+            };
+
+            // the constant index shares the auxiliary word with the negation flag
+            (aux < 1 << 24).then_some((op, aux))
+        };
+
+        let (register, constant) = match (constant(self, right), constant(self, left)) {
+            (Some(constant), _) => (left, constant),
+            (None, Some(constant)) => (right, constant),
+            (None, None) => {
+                let left = self.register(left)?;
+                let right = self.register(right)?;
+
+                let op = if negated {
+                    OpCode::JumpIfNotEq
+                } else {
+                    OpCode::JumpIfEq
+                };
+
+                return Ok(Branch {
+                    op,
+                    a: left,
+                    aux: Some(right as u32),
+                });
+            }
+        };
+
+        let (op, aux) = constant;
+
+        Ok(Branch {
+            op,
+            a: self.register(register)?,
+            aux: Some(aux | not),
+        })
+    }
+
+    fn lower_instruction(&mut self, instruction: &Instruction) -> Result<()> {
+        match instruction {
+            Instruction::Label(_) => {}
+            Instruction::Load(load) => self.load_into(load.dest, &load.src)?,
+            Instruction::Move(mv) => {
+                if mv.dest != mv.src {
+                    self.emit(encode_abc(OpCode::Move, mv.dest, mv.src, 0));
+                }
+            }
+
+            Instruction::GetGlobal(get) => match self.fusions.get(&self.current) {
+                Some(&Fusion::Import { constant, id }) => {
+                    self.emit(encode_ad(OpCode::GetImport, get.dest, constant as i32));
+                    self.emit(id);
+                }
+                _ => {
+                    let constant = self
+                        .il_string_constant(get.constant)
+                        .with_context(|| format!("constant {} is not a string", get.constant))?;
+
+                    self.emit(encode_abc(OpCode::GetGlobal, get.dest, 0, 0));
+                    self.emit(constant as u32);
+                }
+            },
+            Instruction::SetGlobal(set) => {
+                let constant = self
+                    .il_string_constant(set.constant)
+                    .with_context(|| format!("constant {} is not a string", set.constant))?;
+
+                self.emit(encode_abc(OpCode::SetGlobal, set.src, 0, 0));
+                self.emit(constant as u32);
+            }
+
+            Instruction::GetTable(get) => self.load_field(get.dest, get.source, &get.key)?,
+
+            Instruction::SetTable(set) => self.store(set.table, &set.key, &set.value)?,
+            // lookups that are fused with their call are lowered to NAMECALL by the call
+            Instruction::SelfLookup(lookup) => {
+                let method = self.temporary()?;
+                self.load_field(method, lookup.object, &lookup.key)?;
+
+                self.emit(encode_abc(OpCode::Move, lookup.dest + 1, lookup.object, 0));
+                self.emit(encode_abc(OpCode::Move, lookup.dest, method, 0));
+            }
+
+            Instruction::Concat(concat) => {
+                ensure!(
+                    concat.start < concat.end,
+                    "CONCAT needs at least two operands"
+                );
+
+                self.emit(encode_abc(
+                    OpCode::Concat,
+                    concat.dest,
+                    concat.start,
+                    concat.end,
+                ));
+            }
+
+            Instruction::BinaryOp(op) => {
+                let (opcode, opcode_k) = match op.operator {
+                    BinaryOpKind::Add => (OpCode::Add, OpCode::AddK),
+                    BinaryOpKind::Sub => (OpCode::Sub, OpCode::SubK),
+                    BinaryOpKind::Mul => (OpCode::Mul, OpCode::MulK),
+                    BinaryOpKind::Div => (OpCode::Div, OpCode::DivK),
+                    BinaryOpKind::IDiv if self.serializer.max_version < 4 => {
+                        // `IDIV` was introduced in version 4, so older targets get
+                        // `math.floor(a / b)`
+                        let function = self.temporary()?;
+                        self.load_path(function, &["math", "floor"]);
+
+                        let quotient = self.temporary()?;
+                        let left = self.register(&op.left)?;
+                        let right = self.register(&op.right)?;
+
+                        self.emit(encode_abc(OpCode::Div, quotient, left, right));
+                        self.emit(encode_abc(OpCode::Call, function, 2, 2));
+                        self.emit(encode_abc(OpCode::Move, op.dest, function, 0));
+
+                        return Ok(());
+                    }
+                    BinaryOpKind::IDiv => (OpCode::IDiv, OpCode::IDivK),
+                    BinaryOpKind::Mod => (OpCode::Mod, OpCode::ModK),
+                    BinaryOpKind::Pow => (OpCode::Pow, OpCode::PowK),
+                    BinaryOpKind::Concat => {
+                        let left = self.temporary()?;
+                        let right = self.temporary()?;
+
+                        self.load_into(left, &op.left)?;
+                        self.load_into(right, &op.right)?;
+
+                        self.emit(encode_abc(OpCode::Concat, op.dest, left, right));
+
+                        return Ok(());
+                    }
+                };
+
+                if op.operator == BinaryOpKind::IDiv {
+                    self.require_version(4);
+                }
+
+                // `SUBRK` and `DIVRK` were introduced in version 5, older targets load the
+                // constant into a register instead
+                let reverse_opcode = match op.operator {
+                    BinaryOpKind::Sub => Some(OpCode::SubRK),
+                    BinaryOpKind::Div => Some(OpCode::DivRK),
+                    _ => None,
+                }
+                .filter(|_| self.serializer.max_version >= 5);
+
+                let right = self.number_constant(&op.right).filter(|&k| k <= 0xff);
+                let left = self.number_constant(&op.left).filter(|&k| k <= 0xff);
+
+                match (right, left, reverse_opcode) {
+                    (Some(right), ..) => {
+                        let left = self.register(&op.left)?;
+                        self.emit(encode_abc(opcode_k, op.dest, left, right));
+                    }
+                    (None, Some(left), Some(reverse_opcode)) => {
+                        self.require_version(5);
+
+                        let right = self.register(&op.right)?;
+                        self.emit(encode_abc(reverse_opcode, op.dest, left, right));
+                    }
+                    _ => {
+                        let left = self.register(&op.left)?;
+                        let right = self.register(&op.right)?;
+                        self.emit(encode_abc(opcode, op.dest, left, right));
+                    }
+                }
+            }
+
+            Instruction::UnaryOp(op) => {
+                let opcode = match op.operator {
+                    UnaryOpKind::Len => OpCode::Length,
+                    UnaryOpKind::Not => OpCode::Not,
+                    UnaryOpKind::Neg => OpCode::Minus,
+                };
+
+                let operand = self.register(&op.left)?;
+                self.emit(encode_abc(opcode, op.dest, operand, 0));
+            }
+
+            Instruction::Intrinsic(intrinsic) => {
+                // Luau has no bitwise operators, so they are lowered to calls into `bit32`
+                let operands = intrinsic.kind.operands();
+                let library = intrinsic.source.library().unwrap_or("bit32");
+
+                let function = self.temporary()?;
+                self.load_path(function, &[library, intrinsic.kind.library_name()]);
+
+                for operand in &operands {
+                    let register = self.temporary()?;
+                    self.load_into(register, operand)?;
+                    self.top = register + 1;
+                }
+
+                self.emit(encode_abc(OpCode::Call, function, operands.len() + 1, 2));
+                self.emit(encode_abc(OpCode::Move, intrinsic.dest, function, 0));
+            }
+
+            Instruction::Jump(jump) => self.branch(Branch::always(), self.target(jump.target)),
+
+            Instruction::JumpNot(jump) => self.branch(
+                Branch {
+                    op: OpCode::JumpIfNot,
+                    a: jump.cond,
+                    aux: None,
+                },
+                self.target(jump.target),
+            ),
+
+            Instruction::ConditionalJump(jump) => {
+                let condition = &jump.condition;
+                let target = self.target(jump.target);
+
+                match condition.kind {
+                    ConditionKind::And => {
+                        let left = self.register(&condition.left)?;
+                        let right = self.register(&condition.right)?;
+
+                        let skip = self.emit(encode_ad(OpCode::JumpIfNot, left, 0));
+
+                        self.branch(
+                            Branch {
+                                op: OpCode::JumpIf,
+                                a: right,
+                                aux: None,
+                            },
+                            target,
+                        );
+
+                        let offset = self.code.len() - (skip + 1);
+                        self.code[skip] = encode_ad(OpCode::JumpIfNot, left, offset as i32);
+                    }
+                    ConditionKind::Or => {
+                        let left = self.register(&condition.left)?;
+                        let right = self.register(&condition.right)?;
+
+                        for register in [left, right] {
+                            self.branch(
+                                Branch {
+                                    op: OpCode::JumpIf,
+                                    a: register,
+                                    aux: None,
+                                },
+                                target,
+                            );
+                        }
+                    }
+                    ConditionKind::Eq | ConditionKind::Ne => {
+                        let negated = condition.kind == ConditionKind::Ne;
+                        let branch = self.equality(&condition.left, &condition.right, negated)?;
+
+                        self.branch(branch, target);
+                    }
+                    _ => {
+                        let (op, left, right) = match condition.kind {
+                            ConditionKind::Lt => {
+                                (OpCode::JumpIfLt, &condition.left, &condition.right)
+                            }
+                            ConditionKind::Le => {
+                                (OpCode::JumpIfLe, &condition.left, &condition.right)
+                            }
+                            ConditionKind::Gt => {
+                                (OpCode::JumpIfLt, &condition.right, &condition.left)
+                            }
+                            ConditionKind::Ge => {
+                                (OpCode::JumpIfLe, &condition.right, &condition.left)
+                            }
+                            _ => unreachable!(),
+                        };
+
+                        let left = self.register(left)?;
+                        let right = self.register(right)?;
+
+                        self.branch(
+                            Branch {
+                                op,
+                                a: left,
+                                aux: Some(right as u32),
+                            },
+                            target,
+                        );
+                    }
+                }
+            }
+
+            Instruction::ForNumPrep(prep) => {
+                numeric_window(prep.index, prep.limit, prep.step, prep.var)?;
+
+                self.loop_branch(OpCode::ForNPrep, prep.limit, None, self.target(prep.target));
+            }
+            Instruction::ForNumLoop(next) => {
+                numeric_window(next.index, next.limit, next.step, next.var)?;
+
+                self.loop_branch(OpCode::ForNLoop, next.limit, None, self.target(next.target));
+            }
+            Instruction::ForGenPrep(prep) => {
+                self.loop_branch(OpCode::ForGPrep, prep.base, None, self.target(prep.target));
+            }
+            // FORGLOOP both calls the iterator and checks its result, so the call is lowered
+            // together with the check that follows it
+            Instruction::ForGenCall(call) => {
+                ensure!(
+                    call.dest == call.base + 3,
+                    "FORGLOOP stores its results directly above the control value"
+                );
+                ensure!(
+                    (1..=0xff).contains(&call.count),
+                    "FORGLOOP needs between 1 and 255 results"
+                );
+
+                match self.prototype.chunk.inner().get(self.current + 1) {
+                    Some(Instruction::ForGenLoop(next))
+                        if next.base == call.base && next.var == call.dest => {}
+                    _ => bail!("FORGLOOP must be followed by the check of its loop"),
+                }
+            }
+            Instruction::ForGenLoop(next) => {
+                let previous = self.current.checked_sub(1);
+                let instructions = self.prototype.chunk.inner();
+
+                let count = match previous.map(|index| &instructions[index]) {
+                    Some(Instruction::ForGenCall(call)) if call.base == next.base => call.count,
+                    _ => bail!("generic for loops must call their iterator before the check"),
+                };
+
+                self.loop_branch(
+                    OpCode::ForGLoop,
+                    next.base,
+                    Some(count as u32),
+                    self.target(next.target),
+                );
+            }
+
+            Instruction::NewTable(table) => {
+                self.emit(encode_abc(
+                    OpCode::NewTable,
+                    table.dest,
+                    encode_hash_size(table.table_size),
+                    0,
+                ));
+                self.emit(table.array_size as u32);
+            }
+            Instruction::SetList(list) => {
+                self.set_list(list.table, list.start, &list.count, list.offset)?;
+            }
+
+            Instruction::Closure(closure) => {
+                let prototype = self
+                    .prototype
+                    .prototypes
+                    .get(closure.prototype)
+                    .with_context(|| format!("prototype {} does not exist", closure.prototype))?;
+
+                ensure!(
+                    closure.prototype <= i16::MAX as usize,
+                    "prototype {} is out of range of NEWCLOSURE",
+                    closure.prototype
+                );
+
+                self.emit(encode_ad(
+                    OpCode::NewClosure,
+                    closure.dest,
+                    closure.prototype as i32,
+                ));
+
+                // registers are captured by reference, as the IL does not tell whether they
+                // are written to after the closure is created
+                for upvalue in &prototype.upvalues {
+                    let capture = if upvalue.in_stack {
+                        CaptureType::Ref
+                    } else {
+                        CaptureType::Upval
+                    };
+
+                    self.emit(encode_abc(
+                        OpCode::Capture,
+                        capture as usize,
+                        upvalue.index,
+                        0,
+                    ));
+                }
+            }
+            Instruction::GetUpvalue(get) => {
+                self.emit(encode_abc(OpCode::GetUpval, get.dest, get.upvalue, 0));
+            }
+            Instruction::SetUpvalue(set) => {
+                self.emit(encode_abc(OpCode::SetUpval, set.src, set.upvalue, 0));
+            }
+            Instruction::Close(close) => {
+                self.emit(encode_abc(OpCode::CloseUpvals, close.start, 0, 0));
+            }
+
+            Instruction::Return(ret) => {
+                let results = match ret.result_count {
+                    OptVariable::Variable => 0,
+                    OptVariable::Number(n) => n + 1,
+                };
+
+                ensure!(results <= 0xff, "too many results in return");
+
+                self.emit(encode_abc(OpCode::Return, ret.result_start, results, 0));
+            }
+
+            Instruction::Call(call) => {
+                if let Some(&Fusion::NameCall { object, method }) = self.fusions.get(&self.current)
+                {
+                    let method = self.il_string_constant(method).unwrap();
+
+                    self.emit(encode_abc(OpCode::NameCall, call.callee, object, 0));
+                    self.emit(method as u32);
+                }
+
+                let arguments = match call.num_args {
+                    OptVariable::Variable => 0,
+                    OptVariable::Number(n) => n + 1 + call.self_call as usize,
+                };
+
+                let results = match call.num_returns {
+                    OptVariable::Variable => 0,
+                    OptVariable::Number(n) => n + 1,
+                };
+
+                ensure!(
+                    arguments <= 0xff && results <= 0xff,
+                    "too many arguments or results in call"
+                );
+
+                self.emit(encode_abc(OpCode::Call, call.callee, arguments, results));
+            }
+            Instruction::VarArg(vararg) => {
+                ensure!(
+                    self.prototype.arity.is_vararg,
+                    "GETVARARGS is only allowed in variadic functions"
+                );
+
+                let count = match vararg.count {
+                    OptVariable::Variable => 0,
+                    OptVariable::Number(n) => n + 1,
+                };
+
+                ensure!(count <= 0xff, "too many values in GETVARARGS");
+
+                self.emit(encode_abc(OpCode::GetVarArgs, vararg.dest, count, 0));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn patch_jumps(&mut self) -> Result<()> {
+        for fixup in &self.fixups {
+            let target_pc = *self.pcs.get(fixup.target).with_context(|| {
+                format!(
+                    "jump at {} targets nonexistent instruction {}",
+                    fixup.pc, fixup.target
+                )
+            })?;
+
+            let offset = target_pc as i32 - (fixup.pc as i32 + 1);
+            let word = self.code[fixup.pc];
+
+            if fixup.long {
+                ensure!(
+                    (-(1 << 23)..(1 << 23)).contains(&offset),
+                    "jump at {} is too long even for JUMPX",
+                    fixup.pc
+                );
+
+                self.code[fixup.pc] = encode_e(OpCode::JumpX, offset);
+            } else if (i16::MIN as i32..=i16::MAX as i32).contains(&offset) {
+                let mut op = OpCode::try_from(word as u8).unwrap();
+
+                // backward jumps check for interrupts so that loops can be stopped
+                if op == OpCode::Jump && offset < 0 {
+                    op = OpCode::JumpBack;
+                }
+
+                self.code[fixup.pc] = encode_ad(op, (word >> 8) as usize & 0xff, offset);
+            } else {
+                let op = OpCode::try_from(word as u8).unwrap();
+
+                ensure!(
+                    !matches!(
+                        op,
+                        OpCode::ForNPrep | OpCode::ForNLoop | OpCode::ForGPrep | OpCode::ForGLoop
+                    ),
+                    "{op:?} at {} is too long",
+                    fixup.pc
+                );
+
+                self.overflowing.push(fixup.instruction);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks that the registers of a numeric `for` loop are laid out as `FORNPREP` and
+/// `FORNLOOP` expect them, with the counter doubling as the loop variable.
+fn numeric_window(index: usize, limit: usize, step: usize, var: usize) -> Result<()> {
+    ensure!(
+        step == limit + 1 && index == limit + 2 && var == index,
+        "numeric for loops must keep their step and counter directly above the limit"
+    );
+
+    Ok(())
+}
+
+/// Encodes the size of the hash part of a table as `NEWTABLE` expects it.
+fn encode_hash_size(size: usize) -> usize {
+    if size == 0 {
+        0
+    } else {
+        size.next_power_of_two().trailing_zeros() as usize + 1
+    }
+}
+
+/// Splits per-instruction line numbers into a gap, per-instruction offsets and absolute
+/// line numbers for every interval of `1 << gap` instructions.
+fn encode_lines(lines: &[u32]) -> (u8, Vec<u8>, Vec<u32>) {
+    let mut gap = 24;
+
+    loop {
+        let bases = lines
+            .chunks(1 << gap)
+            .map(|interval| {
+                let min = *interval.iter().min().unwrap();
+                let max = *interval.iter().max().unwrap();
+
+                (max - min <= 0xff).then_some(min)
+            })
+            .collect::<Option<Vec<_>>>();
+
+        if let Some(bases) = bases {
+            let offsets = lines
+                .iter()
+                .enumerate()
+                .map(|(pc, &line)| (line - bases[pc >> gap]) as u8)
+                .collect();
+
+            return (gap as u8, offsets, bases);
+        }
+
+        gap -= 1;
+    }
+}
+
+/// Writes the primitive types of Luau bytecode.
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn write_byte(&mut self, byte: u8) {
+        self.buf.push(byte);
+    }
+
+    fn write_varint(&mut self, mut value: usize) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+
+            if value == 0 {
+                self.buf.push(byte);
+                break;
+            }
+
+            self.buf.push(byte | 0x80);
+        }
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_function(&mut self, state: &FunctionState, version: u8) {
+        let prototype = state.prototype;
+        let serializer = state.serializer;
+
+        self.write_byte(state.max_stack_size as u8);
+        self.write_byte(prototype.arity.params);
+        self.write_byte(prototype.upvalues.len() as u8);
+        self.write_byte(prototype.arity.is_vararg as u8);
+
+        if version >= 4 {
+            let flags = if serializer.native {
+                PROTO_FLAG_NATIVE_MODULE
+            } else {
+                0
+            };
+
+            self.write_byte(flags);
+
+            // no type information
+            self.write_varint(0);
+        }
+
+        self.write_varint(state.code.len());
+        for &instruction in &state.code {
+            self.write_u32(instruction);
+        }
+
+        self.write_varint(state.constants.len());
+        for constant in &state.constants {
+            match constant {
+                LuauConstant::Nil => self.write_byte(ConstantTag::Nil as u8),
+                LuauConstant::Boolean(b) => {
+                    self.write_byte(ConstantTag::Boolean as u8);
+                    self.write_byte(*b as u8);
+                }
+                LuauConstant::Number(n) => {
+                    self.write_byte(ConstantTag::Number as u8);
+                    self.buf.extend_from_slice(&n.to_le_bytes());
+                }
+                LuauConstant::String(s) => {
+                    self.write_byte(ConstantTag::String as u8);
+                    self.write_varint(*s);
+                }
+                LuauConstant::Import(id) => {
+                    self.write_byte(ConstantTag::Import as u8);
+                    self.write_u32(*id);
+                }
+            }
+        }
+
+        self.write_varint(state.children.len());
+        for &child in &state.children {
+            self.write_varint(child);
+        }
+
+        if serializer.strip_debug {
+            self.write_varint(0);
+        } else {
+            self.write_varint(prototype.debug.line_defined as usize);
+        }
+
+        self.write_varint(state.debug_name);
+
+        if serializer.strip_debug || !prototype.chunk.has_spans() {
+            self.write_byte(0);
+        } else {
+            let (gap, offsets, bases) = encode_lines(&state.lineinfo);
+
+            self.write_byte(1);
+            self.write_byte(gap);
+
+            let mut last_offset = 0u8;
+            for offset in offsets {
+                self.write_byte(offset.wrapping_sub(last_offset));
+                last_offset = offset;
+            }
+
+            let mut last_line = 0u32;
+            for base in bases {
+                self.write_u32(base.wrapping_sub(last_line));
+                last_line = base;
+            }
+        }
+
+        if state.local_names.is_empty() && state.upvalue_names.is_empty() {
+            self.write_byte(0);
+        } else {
+            self.write_byte(1);
+
+            self.write_varint(state.local_names.len());
+            for (local, &name) in prototype.debug.locals.iter().zip(&state.local_names) {
+                self.write_varint(name);
+                self.write_varint(pc_at(&state.pcs, local.start));
+                self.write_varint(pc_at(&state.pcs, local.end));
+                self.write_byte(local.register as u8);
+            }
+
+            self.write_varint(state.upvalue_names.len());
+            for &name in &state.upvalue_names {
+                self.write_varint(name);
+            }
+        }
+    }
+}
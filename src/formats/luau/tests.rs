@@ -0,0 +1,710 @@
+#![cfg(test)]
+use super::*;
+use crate::formats::{Deserializer, Feature, Serializer, MAX_NESTING};
+use crate::ir::il::{
+    Arity, BinaryOp, BinaryOpKind, Call, Close, Closure, Concat, Constant, DebugInfo, ForGenCall,
+    ForGenLoop, ForGenPrep, ForNumLoop, ForNumPrep, Function, GetGlobal, GetTable, GetUpvalue,
+    IlChunk, Inst, Instruction, Jump, JumpNot, Label, Load, Local, Move, OptVariable, Return,
+    SelfLookup, SetGlobal, SetList, SetTable, SetUpvalue, Upvalue, Value, VarArg,
+};
+use crate::ir::span::Span;
+use cranelift_entity::EntityRef;
+
+fn function(constants: Vec<Constant>, chunk: IlChunk) -> Function {
+    Function {
+        chunk,
+        constants,
+        prototypes: vec![],
+        upvalues: vec![],
+        arity: Arity::vararg(0),
+        name: None,
+        max_stack_size: 2,
+        debug: DebugInfo::default(),
+    }
+}
+
+fn ret() -> Instruction {
+    Instruction::Return(Return {
+        result_start: 0,
+        result_count: OptVariable::Number(1),
+    })
+}
+
+fn read_varint(bytes: &[u8], offset: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = bytes[*offset];
+        *offset += 1;
+
+        value |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return value;
+        }
+    }
+}
+
+/// Extracts the code of the main function from a blob containing a single function.
+fn main_code(bytes: &[u8]) -> Vec<u32> {
+    let version = bytes[0];
+    let mut offset = 1 + (version >= 4) as usize;
+
+    for _ in 0..read_varint(bytes, &mut offset) {
+        let len = read_varint(bytes, &mut offset);
+        offset += len;
+    }
+
+    assert_eq!(read_varint(bytes, &mut offset), 1);
+
+    offset += 4;
+
+    if version >= 4 {
+        offset += 1;
+        let types = read_varint(bytes, &mut offset);
+        offset += types;
+    }
+
+    let count = read_varint(bytes, &mut offset);
+
+    bytes[offset..offset + count * 4]
+        .chunks(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .collect()
+}
+
+fn op(word: u32) -> OpCode {
+    OpCode::try_from(word as u8).unwrap()
+}
+
+#[test]
+fn version_follows_features() {
+    let chunk = IlChunk::new(vec![ret()]);
+
+    let bytes = LuauSerializer::default()
+        .serialize(&function(vec![], chunk.clone()))
+        .unwrap();
+
+    assert_eq!(bytes[0], 3);
+
+    let bytes = LuauSerializer::builder()
+        .native(true)
+        .build()
+        .unwrap()
+        .serialize(&function(vec![], chunk.clone()))
+        .unwrap();
+
+    assert_eq!(&bytes[..2], &[4, TYPES_VERSION]);
+
+    let result = LuauSerializer::builder()
+        .native(true)
+        .max_version(3)
+        .build()
+        .unwrap()
+        .serialize(&function(vec![], chunk));
+
+    assert!(result.is_err());
+    assert!(LuauSerializer::builder().min_version(2).build().is_err());
+}
+
+#[test]
+fn version_follows_emitted_opcodes() {
+    let binary = |operator, left| {
+        Instruction::BinaryOp(BinaryOp {
+            operator,
+            dest: 0,
+            left,
+            right: Value::StackIndex(1),
+        })
+    };
+
+    let serialize = |max_version, instructions| {
+        LuauSerializer::builder()
+            .max_version(max_version)
+            .build()
+            .unwrap()
+            .serialize(&function(
+                vec![Constant::Number(1.0)],
+                IlChunk::new(instructions),
+            ))
+    };
+
+    // `1 - r1` uses SUBRK from version 5 on, and a register for the constant before it
+    let subtract = vec![binary(BinaryOpKind::Sub, Value::ConstantIndex(0)), ret()];
+
+    let bytes = serialize(VERSION_MAX, subtract.clone()).unwrap();
+    assert_eq!(bytes[0], 5);
+    assert!(main_code(&bytes)
+        .iter()
+        .any(|&word| op(word) == OpCode::SubRK));
+
+    let bytes = serialize(4, subtract).unwrap();
+    assert_eq!(bytes[0], 3);
+    assert!(main_code(&bytes)
+        .iter()
+        .any(|&word| op(word) == OpCode::Sub));
+
+    // floor division needs version 4, older targets have it polyfilled
+    let divide = vec![binary(BinaryOpKind::IDiv, Value::StackIndex(0)), ret()];
+
+    assert_eq!(serialize(VERSION_MAX, divide.clone()).unwrap()[0], 4);
+
+    let bytes = serialize(3, divide).unwrap();
+    let ops = main_code(&bytes).into_iter().map(op).collect::<Vec<_>>();
+
+    assert_eq!(bytes[0], 3);
+    assert!(!ops.contains(&OpCode::IDiv));
+    assert!(ops.contains(&OpCode::GetImport));
+    assert!(ops.contains(&OpCode::Div));
+    assert!(ops.contains(&OpCode::Call));
+
+    let serializer = LuauSerializer::builder().max_version(3).build().unwrap();
+    assert!(!serializer.supports(Feature::IntegerDivision));
+}
+
+#[test]
+fn global_chains_use_imports() {
+    let constants = vec![
+        Constant::String(b"math".to_vec()),
+        Constant::String(b"floor".to_vec()),
+    ];
+
+    let chunk = IlChunk::new(vec![
+        Instruction::GetGlobal(GetGlobal {
+            dest: 0,
+            constant: 0,
+        }),
+        Instruction::GetTable(GetTable {
+            dest: 0,
+            source: 0,
+            key: Value::ConstantIndex(1),
+        }),
+        ret(),
+    ]);
+
+    let code = main_code(
+        &LuauSerializer::default()
+            .serialize(&function(constants.clone(), chunk.clone()))
+            .unwrap(),
+    );
+
+    assert_eq!(op(code[0]), OpCode::PrepVarArgs);
+    assert_eq!(op(code[1]), OpCode::GetImport);
+    assert_eq!(code[2], encode_import(&[0, 1]));
+    assert_eq!(op(code[3]), OpCode::Return);
+
+    // once the global is assigned it has to be looked up at runtime
+    let mut instructions = chunk.inner().to_vec();
+    instructions.insert(
+        0,
+        Instruction::SetGlobal(SetGlobal {
+            src: 0,
+            constant: 0,
+        }),
+    );
+
+    let code = main_code(
+        &LuauSerializer::default()
+            .serialize(&function(constants, IlChunk::new(instructions)))
+            .unwrap(),
+    );
+
+    assert_eq!(op(code[3]), OpCode::GetGlobal);
+    assert_eq!(op(code[5]), OpCode::GetTableKS);
+}
+
+#[test]
+fn self_calls_use_namecall() {
+    let chunk = IlChunk::new(vec![
+        Instruction::GetTable(GetTable {
+            dest: 1,
+            source: 0,
+            key: Value::ConstantIndex(0),
+        }),
+        Instruction::Load(Load {
+            dest: 2,
+            src: Value::StackIndex(0),
+        }),
+        Instruction::Call(Call {
+            callee: 1,
+            self_call: true,
+            num_args: OptVariable::Number(0),
+            num_returns: OptVariable::Number(1),
+        }),
+        ret(),
+    ]);
+
+    let code = main_code(
+        &LuauSerializer::default()
+            .serialize(&function(vec![Constant::String(b"m".to_vec())], chunk))
+            .unwrap(),
+    );
+
+    assert_eq!(code[1], encode_abc(OpCode::NameCall, 1, 0, 0));
+    assert_eq!(code[2], 0);
+    assert_eq!(code[3], encode_abc(OpCode::Call, 1, 2, 2));
+}
+
+#[test]
+fn table_stores_round_trip() {
+    let chunk = IlChunk::new(vec![
+        Instruction::SetTable(SetTable {
+            table: 0,
+            key: Value::ConstantIndex(0),
+            value: Value::StackIndex(1),
+        }),
+        Instruction::SetTable(SetTable {
+            table: 0,
+            key: Value::Immediate(2),
+            value: Value::StackIndex(1),
+        }),
+        Instruction::Concat(Concat {
+            dest: 3,
+            start: 1,
+            end: 2,
+        }),
+        Instruction::SetList(SetList {
+            table: 0,
+            start: 3,
+            count: OptVariable::Number(2),
+            offset: 4,
+        }),
+        Instruction::Move(Move { dest: 2, src: 0 }),
+        Instruction::SelfLookup(SelfLookup {
+            dest: 1,
+            object: 2,
+            key: Value::ConstantIndex(0),
+        }),
+        Instruction::Call(Call {
+            callee: 1,
+            self_call: true,
+            num_args: OptVariable::Number(0),
+            num_returns: OptVariable::Number(1),
+        }),
+        ret(),
+    ]);
+
+    let prototype = function(vec![Constant::String(b"m".to_vec())], chunk.clone());
+    let bytes = LuauSerializer::default().serialize(&prototype).unwrap();
+    let code = main_code(&bytes);
+
+    assert_eq!(code[5], encode_abc(OpCode::SetList, 0, 3, 3));
+    assert_eq!(code[6], 5);
+    assert_eq!(code[8], encode_abc(OpCode::NameCall, 1, 2, 0));
+
+    assert_eq!(LuauDeserializer.deserialize(&bytes).unwrap().chunk, chunk);
+
+    // lookups that are not called directly are lowered without NAMECALL
+    let chunk = IlChunk::new(vec![
+        Instruction::SelfLookup(SelfLookup {
+            dest: 1,
+            object: 1,
+            key: Value::ConstantIndex(0),
+        }),
+        ret(),
+    ]);
+
+    let code = main_code(
+        &LuauSerializer::default()
+            .serialize(&function(vec![Constant::String(b"m".to_vec())], chunk))
+            .unwrap(),
+    );
+
+    assert_eq!(op(code[1]), OpCode::GetTableKS);
+    assert_eq!(code[3], encode_abc(OpCode::Move, 2, 1, 0));
+}
+
+#[test]
+fn long_constants_use_aux() {
+    let constants = (0..40_000)
+        .map(|n| Constant::String(n.to_string().into_bytes()))
+        .collect();
+
+    let chunk = IlChunk::new(vec![
+        Instruction::Load(Load {
+            dest: 0,
+            src: Value::ConstantIndex(39_999),
+        }),
+        ret(),
+    ]);
+
+    let code = main_code(
+        &LuauSerializer::default()
+            .serialize(&function(constants, chunk))
+            .unwrap(),
+    );
+
+    assert_eq!(code[1], encode_abc(OpCode::LoadKX, 0, 0, 0));
+    assert_eq!(code[2], 39_999);
+}
+
+#[test]
+fn long_jumps_use_jumpx() {
+    let filler: usize = 40_000;
+
+    let mut instructions = vec![
+        Instruction::JumpNot(JumpNot {
+            target: Label(1),
+            cond: 0,
+        }),
+        Instruction::Jump(Jump { target: Label(0) }),
+        Instruction::Label(Label(0)),
+    ];
+
+    instructions.extend((0..filler).map(|_| {
+        Instruction::Load(Load {
+            dest: 0,
+            src: Value::Nil,
+        })
+    }));
+    instructions.push(Instruction::Label(Label(1)));
+    instructions.push(ret());
+
+    let code = main_code(
+        &LuauSerializer::default()
+            .serialize(&function(vec![], IlChunk::new(instructions)))
+            .unwrap(),
+    );
+
+    // the long branch is inverted to skip over a JUMPX
+    assert_eq!(code[1], encode_ad(OpCode::JumpIf, 0, 1));
+    assert_eq!(code[2], encode_e(OpCode::JumpX, filler as i32 + 1));
+    assert_eq!(code[3], encode_ad(OpCode::Jump, 0, 0));
+}
+
+/// Builds version 3 bytecode with a single variadic main function around `code`, with no
+/// constants or debug information.
+fn blob(code: &[u32]) -> Vec<u8> {
+    // version, no strings, one function with a stack size of 3 and no parameters or upvalues
+    let mut bytes = vec![3, 0, 1, 3, 0, 0, 1, code.len() as u8];
+
+    for instruction in code {
+        bytes.extend_from_slice(&instruction.to_le_bytes());
+    }
+
+    // constants, children, linedefined, debug name, line and debug information, main id
+    bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0]);
+
+    bytes
+}
+
+#[test]
+fn huge_counts_are_rejected() {
+    let huge = [0xff, 0xff, 0xff, 0xff, 0x0f];
+
+    let mut strings = vec![3];
+    strings.extend_from_slice(&huge);
+
+    let error = LuauDeserializer.deserialize(&strings).unwrap_err();
+    assert!(format!("{error:#}").contains("string count"), "{error:#}");
+
+    let mut constants = blob(&[]);
+    constants.splice(8..9, huge);
+
+    let error = LuauDeserializer.deserialize(&constants).unwrap_err();
+    assert!(format!("{error:#}").contains("constant count"), "{error:#}");
+}
+
+#[test]
+fn missing_children_are_rejected() {
+    let code = [
+        encode_ad(OpCode::NewClosure, 0, 0),
+        encode_abc(OpCode::Return, 0, 1, 0),
+    ];
+
+    // the main function nests function 20, but there is only one function
+    let mut bytes = blob(&code);
+    let children = 9 + code.len() * 4;
+    bytes.splice(children..children + 1, [1, 20]);
+
+    let error = LuauDeserializer.deserialize(&bytes).unwrap_err();
+    assert!(
+        format!("{error:#}").contains("nests function 20"),
+        "{error:#}"
+    );
+}
+
+#[test]
+fn logical_operators_are_lifted_to_branches() {
+    let bytes = blob(&[
+        encode_abc(OpCode::PrepVarArgs, 0, 0, 0),
+        encode_abc(OpCode::And, 0, 1, 2),
+        encode_abc(OpCode::Return, 0, 2, 0),
+    ]);
+
+    let chunk = LuauDeserializer.deserialize(&bytes).unwrap().chunk;
+
+    let load = |dest, src| Instruction::Load(Load { dest, src });
+
+    assert_eq!(
+        chunk.inner(),
+        &vec![
+            Instruction::JumpNot(JumpNot {
+                target: Label(0),
+                cond: 1,
+            }),
+            load(0, Value::StackIndex(2)),
+            Instruction::Jump(Jump { target: Label(1) }),
+            Instruction::Label(Label(0)),
+            load(0, Value::StackIndex(1)),
+            Instruction::Label(Label(1)),
+            ret(),
+        ]
+    );
+}
+
+#[test]
+fn serialized_bytecode_round_trips() {
+    let constants = vec![
+        Constant::String(b"math".to_vec()),
+        Constant::String(b"p\xffi".to_vec()),
+    ];
+
+    let mut chunk = IlChunk::default();
+
+    chunk.push_spanned(
+        Instruction::GetGlobal(GetGlobal {
+            dest: 0,
+            constant: 0,
+        }),
+        Some(Span::line(1)),
+    );
+    chunk.push_spanned(
+        Instruction::GetTable(GetTable {
+            dest: 0,
+            source: 0,
+            key: Value::ConstantIndex(1),
+        }),
+        Some(Span::line(1)),
+    );
+    let end = chunk.push_spanned(ret(), Some(Span::line(2)));
+
+    let mut prototype = function(constants, chunk.clone());
+    prototype.debug.locals = vec![Local {
+        name: "t".to_owned(),
+        register: 0,
+        start: Inst::new(0),
+        end,
+    }];
+
+    let bytes = LuauSerializer::default().serialize(&prototype).unwrap();
+    let lifted = LuauDeserializer.deserialize(&bytes).unwrap();
+
+    // the import is split back into a global and a field lookup
+    assert_eq!(lifted.chunk, chunk);
+    assert_eq!(lifted.debug, prototype.debug);
+
+    // strings keep bytes that are not valid UTF-8
+    assert_eq!(lifted.constants[1], Constant::String(b"p\xffi".to_vec()));
+
+    let bytes = blob(&[
+        encode_abc(OpCode::IDiv, 0, 1, 2),
+        encode_abc(OpCode::Return, 0, 1, 0),
+    ]);
+
+    let lifted = LuauDeserializer.deserialize(&bytes).unwrap();
+
+    assert_eq!(
+        lifted.chunk.inner()[0],
+        Instruction::BinaryOp(BinaryOp {
+            operator: BinaryOpKind::IDiv,
+            dest: 0,
+            left: Value::StackIndex(1),
+            right: Value::StackIndex(2),
+        })
+    );
+}
+
+#[test]
+fn loops_round_trip() {
+    let chunk = IlChunk::new(vec![
+        Instruction::ForNumPrep(ForNumPrep {
+            target: Label(1),
+            index: 2,
+            limit: 0,
+            step: 1,
+            var: 2,
+        }),
+        Instruction::Label(Label(0)),
+        Instruction::Move(Move { dest: 4, src: 2 }),
+        Instruction::ForNumLoop(ForNumLoop {
+            target: Label(0),
+            index: 2,
+            limit: 0,
+            step: 1,
+            var: 2,
+        }),
+        Instruction::Label(Label(1)),
+        Instruction::ForGenPrep(ForGenPrep {
+            target: Label(3),
+            base: 5,
+        }),
+        Instruction::Label(Label(2)),
+        Instruction::Move(Move { dest: 4, src: 8 }),
+        Instruction::Label(Label(3)),
+        Instruction::ForGenCall(ForGenCall {
+            base: 5,
+            dest: 8,
+            count: 2,
+        }),
+        Instruction::ForGenLoop(ForGenLoop {
+            target: Label(2),
+            base: 5,
+            var: 8,
+        }),
+        ret(),
+    ]);
+
+    let bytes = LuauSerializer::default()
+        .serialize(&function(vec![], chunk.clone()))
+        .unwrap();
+
+    assert_eq!(LuauDeserializer.deserialize(&bytes).unwrap().chunk, chunk);
+}
+
+#[test]
+fn closures_capture_registers_and_upvalues() {
+    let closure = |dest| Instruction::Closure(Closure { dest, prototype: 0 });
+
+    let mut inner = function(
+        vec![],
+        IlChunk::new(vec![
+            Instruction::SetUpvalue(SetUpvalue { src: 0, upvalue: 0 }),
+            ret(),
+        ]),
+    );
+    inner.upvalues = vec![Upvalue {
+        in_stack: false,
+        index: 0,
+    }];
+    inner.arity = Arity::fixed(1);
+
+    let mut middle = function(
+        vec![],
+        IlChunk::new(vec![
+            Instruction::GetUpvalue(GetUpvalue {
+                dest: 0,
+                upvalue: 0,
+            }),
+            closure(0),
+            ret(),
+        ]),
+    );
+    middle.upvalues = vec![Upvalue {
+        in_stack: true,
+        index: 1,
+    }];
+    middle.prototypes = vec![inner];
+
+    let mut main = function(
+        vec![],
+        IlChunk::new(vec![
+            closure(0),
+            Instruction::Close(Close { start: 1 }),
+            ret(),
+        ]),
+    );
+    main.prototypes = vec![middle];
+
+    let bytes = LuauSerializer::default().serialize(&main).unwrap();
+    let lifted = LuauDeserializer.deserialize(&bytes).unwrap();
+
+    let (middle, lifted_middle) = (&main.prototypes[0], &lifted.prototypes[0]);
+    let (inner, lifted_inner) = (&middle.prototypes[0], &lifted_middle.prototypes[0]);
+
+    assert_eq!(lifted.chunk, main.chunk);
+    assert_eq!(lifted_middle.chunk, middle.chunk);
+    assert_eq!(lifted_middle.upvalues, middle.upvalues);
+    assert_eq!(lifted_inner.chunk, inner.chunk);
+    assert_eq!(lifted_inner.upvalues, inner.upvalues);
+    assert_eq!(lifted_inner.arity, Arity::fixed(1));
+}
+
+#[test]
+fn varargs_round_trip() {
+    // function(a, ...) local b, c = ...; f(...) return ... end
+    let chunk = IlChunk::new(vec![
+        Instruction::VarArg(VarArg {
+            dest: 2,
+            count: OptVariable::Number(2),
+        }),
+        Instruction::VarArg(VarArg {
+            dest: 5,
+            count: OptVariable::Variable,
+        }),
+        Instruction::Call(Call {
+            callee: 4,
+            self_call: false,
+            num_args: OptVariable::Variable,
+            num_returns: OptVariable::Number(0),
+        }),
+        Instruction::VarArg(VarArg {
+            dest: 2,
+            count: OptVariable::Variable,
+        }),
+        Instruction::Return(Return {
+            result_start: 2,
+            result_count: OptVariable::Variable,
+        }),
+    ]);
+
+    let mut main = function(vec![], chunk.clone());
+    main.arity = Arity::vararg(1);
+
+    let bytes = LuauSerializer::default().serialize(&main).unwrap();
+    let code = main_code(&bytes);
+
+    assert_eq!(
+        code[..3],
+        [
+            encode_abc(OpCode::PrepVarArgs, 1, 0, 0),
+            encode_abc(OpCode::GetVarArgs, 2, 3, 0),
+            encode_abc(OpCode::GetVarArgs, 5, 0, 0)
+        ]
+    );
+    assert_eq!(code[code.len() - 1], encode_abc(OpCode::Return, 2, 0, 0));
+
+    let lifted = LuauDeserializer.deserialize(&bytes).unwrap();
+
+    assert_eq!(lifted.chunk, chunk);
+    assert_eq!(lifted.arity, main.arity);
+
+    main.arity = Arity::fixed(1);
+
+    assert!(LuauSerializer::default().serialize(&main).is_err());
+}
+
+/// Nests a function returning nothing `depth` functions deep in a main function.
+fn nested(depth: usize) -> Function {
+    let leaf = function(
+        vec![],
+        IlChunk::new(vec![Instruction::Return(Return {
+            result_start: 0,
+            result_count: OptVariable::Number(0),
+        })]),
+    );
+
+    (0..depth).fold(leaf.clone(), |inner, _| Function {
+        prototypes: vec![inner],
+        ..leaf.clone()
+    })
+}
+
+#[test]
+fn deep_nesting_is_rejected() {
+    let serialize = |depth| LuauSerializer::default().serialize(&nested(depth)).unwrap();
+
+    assert!(LuauDeserializer
+        .deserialize(&serialize(MAX_NESTING))
+        .is_ok());
+
+    let error = LuauDeserializer
+        .deserialize(&serialize(MAX_NESTING + 1))
+        .unwrap_err();
+    assert!(
+        format!("{error:#}").contains("nested more than"),
+        "{error:#}"
+    );
+}
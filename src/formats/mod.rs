@@ -0,0 +1,291 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::ir::il::{Function, Inst};
+use anyhow::{ensure, Context, Result};
+use cranelift_entity::EntityRef;
+use std::{fmt::Display, str::FromStr};
+
+mod lift;
+
+/// The `.lir` container of LUNIR intermediate language.
+pub mod lir;
+
+/// The PUC-Rio Lua 5.1 bytecode format.
+pub mod lua51;
+
+/// The PUC-Rio Lua 5.4 bytecode format.
+pub mod lua54;
+
+/// The Luau bytecode format.
+pub mod luau;
+
+/// Language constructs that only some bytecode formats have native instructions for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    /// Bitwise operators, polyfilled with calls into `bit` in Lua 5.1 and `bit32` in Luau.
+    BitwiseOperators,
+    /// Floor division, polyfilled with `math.floor(a / b)` in Lua 5.1 and in Luau bytecode
+    /// before version 4.
+    IntegerDivision,
+}
+
+/// How a bytecode format lays out the registers of `for` loops, which the IL of a loop has
+/// to follow for the format to serialize it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LoopLayout {
+    /// Numeric loops keep the limit, step and loop variable directly above the counter, and
+    /// generic loops store the results of the iterator directly above its control value.
+    #[default]
+    Lua51,
+    /// As in Lua 5.1, except that generic loops keep a closing value between the control
+    /// value and the results of the iterator.
+    Lua54,
+    /// Numeric loops keep the step and counter directly above the limit, and the counter is
+    /// the loop variable itself.
+    Luau,
+}
+
+/// Lowers LUNIR intermediate language into the bytecode of a target format. Options of the
+/// target, such as header parameters or whether debug information is kept, are carried by
+/// the implementor.
+pub trait Serializer {
+    /// Serializes `function` as the main function of a chunk, along with every prototype
+    /// nested in it.
+    fn serialize(&self, function: &Function) -> Result<Vec<u8>>;
+
+    /// Returns whether the target format has native instructions for `feature`, constructs
+    /// it has none for are polyfilled by `serialize` or rejected.
+    fn supports(&self, _feature: Feature) -> bool {
+        false
+    }
+
+    /// Returns how the target format lays out the registers of `for` loops.
+    fn loop_layout(&self) -> LoopLayout {
+        LoopLayout::default()
+    }
+}
+
+/// Lifts the bytecode of a source format into LUNIR intermediate language.
+pub trait Deserializer {
+    /// Deserializes `bytes`, returning the lifted prototype of its main function.
+    fn deserialize(&self, bytes: &[u8]) -> Result<Function>;
+}
+
+impl<S: Serializer + ?Sized> Serializer for &S {
+    fn serialize(&self, function: &Function) -> Result<Vec<u8>> {
+        (**self).serialize(function)
+    }
+
+    fn supports(&self, feature: Feature) -> bool {
+        (**self).supports(feature)
+    }
+
+    fn loop_layout(&self) -> LoopLayout {
+        (**self).loop_layout()
+    }
+}
+
+impl<S: Serializer + ?Sized> Serializer for Box<S> {
+    fn serialize(&self, function: &Function) -> Result<Vec<u8>> {
+        (**self).serialize(function)
+    }
+
+    fn supports(&self, feature: Feature) -> bool {
+        (**self).supports(feature)
+    }
+
+    fn loop_layout(&self) -> LoopLayout {
+        (**self).loop_layout()
+    }
+}
+
+impl<D: Deserializer + ?Sized> Deserializer for &D {
+    fn deserialize(&self, bytes: &[u8]) -> Result<Function> {
+        (**self).deserialize(bytes)
+    }
+}
+
+impl<D: Deserializer + ?Sized> Deserializer for Box<D> {
+    fn deserialize(&self, bytes: &[u8]) -> Result<Function> {
+        (**self).deserialize(bytes)
+    }
+}
+
+/// The bytecode formats built into LUNIR, each serializes and deserializes with the default
+/// options of its format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Lua51,
+    Lua54,
+    Luau,
+    /// LUNIR intermediate language in a `.lir` container.
+    Lir,
+}
+
+impl Format {
+    /// Guesses the format of the bytecode `bytes` from its header.
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0x1b, b'L', b'u', b'a', lua51::VERSION, ..] => Some(Self::Lua51),
+            [0x1b, b'L', b'u', b'a', lua54::VERSION, ..] => Some(Self::Lua54),
+            [0x1b, b'L', b'I', b'R', ..] => Some(Self::Lir),
+            [version, ..] if (luau::VERSION_MIN..=luau::VERSION_MAX).contains(version) => {
+                Some(Self::Luau)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Lua51 => write!(f, "Lua 5.1"),
+            Self::Lua54 => write!(f, "Lua 5.4"),
+            Self::Luau => write!(f, "Luau"),
+            Self::Lir => write!(f, "LUNIR IL"),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    /// Parses the short name of a format, one of `lua51`, `lua54`, `luau` and `lir`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "lua51" | "lua5.1" => Ok(Self::Lua51),
+            "lua54" | "lua5.4" => Ok(Self::Lua54),
+            "luau" => Ok(Self::Luau),
+            "lir" => Ok(Self::Lir),
+            _ => Err(anyhow::anyhow!(
+                "unknown format `{s}`, expected one of lua51, lua54, luau and lir"
+            )),
+        }
+    }
+}
+
+impl Serializer for Format {
+    fn serialize(&self, function: &Function) -> Result<Vec<u8>> {
+        match self {
+            Self::Lua51 => lua51::Lua51Serializer::default().serialize(function),
+            Self::Lua54 => lua54::Lua54Serializer::default().serialize(function),
+            Self::Luau => luau::LuauSerializer::default().serialize(function),
+            Self::Lir => lir::LirSerializer::default().serialize(function),
+        }
+    }
+
+    fn supports(&self, feature: Feature) -> bool {
+        match self {
+            Self::Lua51 => lua51::Lua51Serializer::default().supports(feature),
+            Self::Lua54 => lua54::Lua54Serializer::default().supports(feature),
+            Self::Luau => luau::LuauSerializer::default().supports(feature),
+            Self::Lir => lir::LirSerializer::default().supports(feature),
+        }
+    }
+
+    fn loop_layout(&self) -> LoopLayout {
+        match self {
+            Self::Lua51 => lua51::Lua51Serializer::default().loop_layout(),
+            Self::Lua54 => lua54::Lua54Serializer::default().loop_layout(),
+            Self::Luau => luau::LuauSerializer::default().loop_layout(),
+            Self::Lir => lir::LirSerializer::default().loop_layout(),
+        }
+    }
+}
+
+impl Deserializer for Format {
+    fn deserialize(&self, bytes: &[u8]) -> Result<Function> {
+        match self {
+            Self::Lua51 => lua51::Lua51Deserializer.deserialize(bytes),
+            Self::Lua54 => lua54::Lua54Deserializer.deserialize(bytes),
+            Self::Luau => luau::LuauDeserializer.deserialize(bytes),
+            Self::Lir => lir::LirDeserializer.deserialize(bytes),
+        }
+    }
+}
+
+/// Deserializes bytecode of any built-in format, picking the format with `Format::detect`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Detect;
+
+impl Deserializer for Detect {
+    fn deserialize(&self, bytes: &[u8]) -> Result<Function> {
+        let format = Format::detect(bytes).context("could not detect the bytecode format")?;
+
+        format
+            .deserialize(bytes)
+            .with_context(|| format!("failed to read {format} bytecode"))
+    }
+}
+
+/// Checks that the registers of a numeric `for` loop are laid out as the reference Lua
+/// implementation expects them, with the limit, step and loop variable above the counter.
+pub(crate) fn numeric_for_window(
+    index: usize,
+    limit: usize,
+    step: usize,
+    var: usize,
+) -> Result<()> {
+    ensure!(
+        limit == index + 1 && step == index + 2 && var == index + 3,
+        "numeric for loops must keep their limit, step and variable directly above the counter"
+    );
+
+    Ok(())
+}
+
+/// Converts an integer constant for formats whose only number type is a float, failing when
+/// the integer has no exact float representation.
+pub(crate) fn integer_to_number(n: i64) -> Result<f64> {
+    let number = n as f64;
+
+    // `f64` to `i64` casts saturate, so the comparison is done in a wider type
+    ensure!(
+        number as i128 == n as i128,
+        "integer {n} cannot be represented exactly as a float"
+    );
+
+    Ok(number)
+}
+
+/// How deeply functions may be nested, the `LUAI_MAXCCALLS` limit of the reference
+/// implementation, so that crafted input fails to deserialize rather than overflowing the
+/// stack.
+pub(crate) const MAX_NESTING: usize = 200;
+
+/// Checks that a function nested `depth` functions deep stays within `MAX_NESTING`.
+pub(crate) fn check_nesting(depth: usize) -> Result<()> {
+    ensure!(
+        depth <= MAX_NESTING,
+        "functions are nested more than {MAX_NESTING} levels deep"
+    );
+
+    Ok(())
+}
+
+/// The program counter of the first bytecode instruction lowered from `inst`, where `pcs`
+/// holds the program counter of every IL instruction followed by the end of the code.
+pub(crate) fn pc_at(pcs: &[usize], inst: Inst) -> usize {
+    pcs[inst.index().min(pcs.len() - 1)]
+}
@@ -0,0 +1,662 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Splits Lua source code into tokens.
+
+use super::{Diagnostic, DiagnosticKind, Dialect};
+use crate::ir::span::Span;
+use std::fmt::Display;
+
+/// The keywords of every dialect, `goto` is a keyword from Lua 5.2 on.
+const KEYWORDS: [&str; 22] = [
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+/// Punctuation, longer symbols before their prefixes so that the longest one matches.
+const PUNCTUATION: [&str; 44] = [
+    "...", "..=", "//=", "..", "==", "~=", "<=", ">=", "<<", ">>", "//", "::", "+=", "-=", "*=",
+    "/=", "%=", "^=", "->", "+", "-", "*", "/", "%", "^", "#", "&", "~", "|", "<", ">", "=", "(",
+    ")", "{", "}", "[", "]", ";", ":", ",", ".", "?", "@",
+];
+
+/// Whether `symbol` is punctuation in `dialect`, otherwise its characters are read apart.
+fn is_punctuation(symbol: &str, dialect: Dialect) -> bool {
+    match symbol {
+        "+=" | "-=" | "*=" | "/=" | "//=" | "%=" | "^=" | "..=" => {
+            dialect.has_compound_assignment()
+        }
+        "->" | "?" => dialect.has_types(),
+        "@" => dialect.has_function_attributes(),
+        // so that `>>` can close two lists of type arguments
+        "<<" | ">>" => !dialect.has_types(),
+        _ => true,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum TokenKind {
+    Name(String),
+    /// A keyword or punctuation.
+    Symbol(&'static str),
+    Number(f64),
+    Integer(i64),
+    String(Vec<u8>),
+    /// A backquoted string without interpolations, `` `a` ``.
+    InterpolationSimple(Vec<u8>),
+    /// The start of a backquoted string up to its first interpolation, `` `a{ ``.
+    InterpolationBegin(Vec<u8>),
+    /// The part of a backquoted string between two interpolations, `}b{`.
+    InterpolationMiddle(Vec<u8>),
+    /// The end of a backquoted string after its last interpolation, `` }c` ``.
+    InterpolationEnd(Vec<u8>),
+    Eof,
+}
+
+impl Display for TokenKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "`{name}`"),
+            Self::Symbol(symbol) => write!(f, "`{symbol}`"),
+            Self::Number(_) | Self::Integer(_) => write!(f, "a number"),
+            Self::String(_) => write!(f, "a string"),
+            Self::InterpolationSimple(_)
+            | Self::InterpolationBegin(_)
+            | Self::InterpolationMiddle(_)
+            | Self::InterpolationEnd(_) => write!(f, "an interpolated string"),
+            Self::Eof => write!(f, "the end of the input"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+    /// The line the token ends on, which is a later one than that of `span` for long
+    /// strings spanning several lines.
+    pub end_line: u32,
+}
+
+/// Splits `source` into tokens ending with `TokenKind::Eof`, reporting malformed tokens to
+/// `diagnostics` and leaving them out.
+pub(crate) fn tokenize(
+    source: &str,
+    dialect: Dialect,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Vec<Token> {
+    let mut lexer = Lexer {
+        chars: source.chars().collect(),
+        position: 0,
+        line: 1,
+        column: 1,
+        dialect,
+        diagnostics,
+        interpolations: vec![],
+    };
+
+    // a first line starting with `#` is skipped, so that scripts can start with a shebang
+    if lexer.peek() == Some('#') {
+        while !matches!(lexer.peek(), None | Some('\n')) {
+            lexer.bump();
+        }
+    }
+
+    let mut tokens = Vec::new();
+
+    loop {
+        lexer.skip_trivia();
+
+        let span = lexer.span();
+        match lexer.token() {
+            Some(TokenKind::Eof) => {
+                tokens.push(Token {
+                    kind: TokenKind::Eof,
+                    span,
+                    end_line: lexer.line,
+                });
+                return tokens;
+            }
+            Some(kind) => tokens.push(Token {
+                kind,
+                span,
+                end_line: lexer.line,
+            }),
+            None => {}
+        }
+    }
+}
+
+struct Lexer<'d> {
+    chars: Vec<char>,
+    position: usize,
+    line: u32,
+    column: u32,
+    dialect: Dialect,
+    diagnostics: &'d mut Vec<Diagnostic>,
+    /// The number of unclosed braces inside of each interpolation being read, the `}` that
+    /// closes an interpolation resumes its string.
+    interpolations: Vec<usize>,
+}
+
+impl<'d> Lexer<'d> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.position + offset).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += 1;
+
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+
+        Some(c)
+    }
+
+    fn span(&self) -> Span {
+        Span::new(self.line, self.column)
+    }
+
+    fn report(&mut self, span: Span, kind: DiagnosticKind) {
+        self.diagnostics.push(Diagnostic { kind, span });
+    }
+
+    fn skip_trivia(&mut self) {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => {
+                    self.bump();
+                }
+                Some('-') if self.peek_at(1) == Some('-') => {
+                    let span = self.span();
+                    self.bump();
+                    self.bump();
+
+                    if let Some(level) = self.long_bracket() {
+                        if self.long_string(level).is_none() {
+                            self.report(span, DiagnosticKind::UnfinishedComment);
+                        }
+                    } else {
+                        while !matches!(self.peek(), None | Some('\n')) {
+                            self.bump();
+                        }
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Consumes the opening bracket of a long string, such as `[==[`, returning its level.
+    fn long_bracket(&mut self) -> Option<usize> {
+        if self.peek() != Some('[') {
+            return None;
+        }
+
+        let mut level = 0;
+        while self.peek_at(level + 1) == Some('=') {
+            level += 1;
+        }
+
+        if self.peek_at(level + 1) != Some('[') {
+            return None;
+        }
+
+        for _ in 0..level + 2 {
+            self.bump();
+        }
+
+        Some(level)
+    }
+
+    /// Reads the contents of a long string up to its closing bracket, after its opening one
+    /// was consumed. Returns `None` when the input ends first.
+    fn long_string(&mut self, level: usize) -> Option<String> {
+        // a newline directly after the opening bracket is not part of the string
+        if self.peek() == Some('\r') {
+            self.bump();
+        }
+        if self.peek() == Some('\n') {
+            self.bump();
+        }
+
+        let mut contents = String::new();
+
+        loop {
+            match self.bump()? {
+                ']' if (0..level).all(|i| self.peek_at(i) == Some('='))
+                    && self.peek_at(level) == Some(']') =>
+                {
+                    for _ in 0..=level {
+                        self.bump();
+                    }
+
+                    return Some(contents);
+                }
+                c => contents.push(c),
+            }
+        }
+    }
+
+    /// Reads the next token, or returns `None` after reporting a malformed one.
+    fn token(&mut self) -> Option<TokenKind> {
+        let span = self.span();
+
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Some(TokenKind::Eof),
+        };
+
+        if c.is_ascii_alphabetic() || c == '_' {
+            let mut name = String::new();
+
+            while let Some(c) = self
+                .peek()
+                .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+            {
+                name.push(c);
+                self.bump();
+            }
+
+            return Some(match KEYWORDS.iter().find(|keyword| **keyword == name) {
+                Some(keyword) if *keyword != "goto" || self.dialect.has_goto() => {
+                    TokenKind::Symbol(keyword)
+                }
+                _ => TokenKind::Name(name),
+            });
+        }
+
+        if c.is_ascii_digit() || (c == '.' && self.peek_at(1).map_or(false, |c| c.is_ascii_digit()))
+        {
+            return self.number(span);
+        }
+
+        if c == '"' || c == '\'' {
+            return self.string(span);
+        }
+
+        if c == '`' && self.dialect.has_interpolated_strings() {
+            self.bump();
+            return Some(self.interpolated_string(span, true));
+        }
+
+        if c == '[' {
+            if let Some(level) = self.long_bracket() {
+                return match self.long_string(level) {
+                    Some(contents) => Some(TokenKind::String(contents.into_bytes())),
+                    None => {
+                        self.report(span, DiagnosticKind::UnfinishedLongString);
+                        Some(TokenKind::Eof)
+                    }
+                };
+            }
+        }
+
+        for symbol in PUNCTUATION {
+            if is_punctuation(symbol, self.dialect)
+                && symbol
+                    .chars()
+                    .enumerate()
+                    .all(|(i, c)| self.peek_at(i) == Some(c))
+            {
+                for _ in 0..symbol.len() {
+                    self.bump();
+                }
+
+                match symbol {
+                    "{" => {
+                        if let Some(depth) = self.interpolations.last_mut() {
+                            *depth += 1;
+                        }
+                    }
+                    "}" => {
+                        if let Some(depth) = self.interpolations.last_mut() {
+                            if *depth == 0 {
+                                self.interpolations.pop();
+                                return Some(self.interpolated_string(span, false));
+                            }
+
+                            *depth -= 1;
+                        }
+                    }
+                    _ => {}
+                }
+
+                return Some(TokenKind::Symbol(symbol));
+            }
+        }
+
+        self.bump();
+        self.report(span, DiagnosticKind::UnexpectedCharacter(c));
+
+        None
+    }
+
+    fn number(&mut self, span: Span) -> Option<TokenKind> {
+        let mut text = String::new();
+
+        // like the reference lexer, a numeral runs as long as it could be part of one, so
+        // that `3x` is one malformed numeral rather than a number followed by a name
+        let hex = self.peek() == Some('0') && matches!(self.peek_at(1), Some('x' | 'X'));
+        let exponent = if hex { ['p', 'P'] } else { ['e', 'E'] };
+
+        while let Some(c) = self.peek() {
+            let signed = matches!(c, '+' | '-') && text.ends_with(exponent);
+
+            if !(c.is_ascii_alphanumeric() || c == '.' || c == '_' || signed) {
+                break;
+            }
+
+            text.push(c);
+            self.bump();
+        }
+
+        match parse_number(&text, self.dialect) {
+            Some(kind) => Some(kind),
+            None => {
+                self.report(span, DiagnosticKind::MalformedNumber(text));
+                Some(TokenKind::Number(0.0))
+            }
+        }
+    }
+
+    fn string(&mut self, span: Span) -> Option<TokenKind> {
+        let quote = self.bump().unwrap();
+        let mut bytes = Vec::new();
+
+        loop {
+            let c = match self.peek() {
+                None | Some('\n') => {
+                    self.report(span, DiagnosticKind::UnfinishedString);
+                    break;
+                }
+                Some(c) => c,
+            };
+
+            self.bump();
+
+            if c == quote {
+                break;
+            }
+
+            self.character(c, &mut bytes);
+        }
+
+        Some(TokenKind::String(bytes))
+    }
+
+    /// Reads a part of a backquoted string after the backquote, or after the `}` that ends
+    /// an interpolation when it is not the `first` part.
+    fn interpolated_string(&mut self, span: Span, first: bool) -> TokenKind {
+        let mut bytes = Vec::new();
+
+        let interpolates = loop {
+            let c = match self.peek() {
+                None | Some('\n') => {
+                    self.report(span, DiagnosticKind::UnfinishedString);
+                    break false;
+                }
+                Some(c) => c,
+            };
+
+            self.bump();
+
+            match c {
+                '`' => break false,
+                '{' => break true,
+                '\\' if matches!(self.peek(), Some('`' | '{')) => {
+                    bytes.push(self.bump().unwrap() as u8);
+                }
+                c => self.character(c, &mut bytes),
+            }
+        };
+
+        if interpolates {
+            self.interpolations.push(0);
+        }
+
+        match (first, interpolates) {
+            (true, false) => TokenKind::InterpolationSimple(bytes),
+            (true, true) => TokenKind::InterpolationBegin(bytes),
+            (false, true) => TokenKind::InterpolationMiddle(bytes),
+            (false, false) => TokenKind::InterpolationEnd(bytes),
+        }
+    }
+
+    /// Appends a character of a string to `bytes`, reading the escape sequence it starts.
+    fn character(&mut self, c: char, bytes: &mut Vec<u8>) {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            return;
+        }
+
+        let escape = self.span();
+        match self.escape() {
+            Ok(escaped) => bytes.extend_from_slice(&escaped),
+            Err(sequence) => self.report(escape, DiagnosticKind::InvalidEscape(sequence)),
+        }
+    }
+
+    /// Reads the escape sequence after a `\`, returning the bytes it stands for or the
+    /// malformed sequence.
+    fn escape(&mut self) -> Result<Vec<u8>, String> {
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Err("\\".to_owned()),
+        };
+
+        let simple = match c {
+            'a' => Some(0x07),
+            'b' => Some(0x08),
+            'f' => Some(0x0c),
+            'n' | '\n' => Some(b'\n'),
+            'r' => Some(b'\r'),
+            't' => Some(b'\t'),
+            'v' => Some(0x0b),
+            '\\' | '"' | '\'' => Some(c as u8),
+            _ => None,
+        };
+
+        if let Some(byte) = simple {
+            self.bump();
+            return Ok(vec![byte]);
+        }
+
+        match c {
+            'x' if self.dialect.has_extended_escapes() => {
+                self.bump();
+
+                let digits = (0..2)
+                    .map_while(|_| {
+                        let digit = self.peek()?.to_digit(16)?;
+                        self.bump();
+                        Some(digit)
+                    })
+                    .collect::<Vec<_>>();
+
+                match digits[..] {
+                    [high, low] => Ok(vec![(high * 16 + low) as u8]),
+                    _ => Err(format!("\\x{}", hex_digits(&digits))),
+                }
+            }
+            'z' if self.dialect.has_extended_escapes() => {
+                self.bump();
+
+                while self.peek().map_or(false, char::is_whitespace) {
+                    self.bump();
+                }
+
+                Ok(vec![])
+            }
+            'u' if self.dialect.has_unicode_escapes() => {
+                self.bump();
+
+                if self.peek() != Some('{') {
+                    return Err("\\u".to_owned());
+                }
+                self.bump();
+
+                let mut code = 0u32;
+                let mut digits = String::new();
+
+                while let Some(digit) = self.peek().and_then(|c| c.to_digit(16)) {
+                    digits.push(self.bump().unwrap());
+                    code = code.saturating_mul(16).saturating_add(digit);
+                }
+
+                if digits.is_empty() || self.peek() != Some('}') {
+                    return Err(format!("\\u{{{digits}"));
+                }
+                self.bump();
+
+                let mut buf = [0; 4];
+                char::from_u32(code)
+                    .map(|c| c.encode_utf8(&mut buf).as_bytes().to_vec())
+                    .ok_or_else(|| format!("\\u{{{digits}}}"))
+            }
+            c if c.is_ascii_digit() => {
+                let mut value = 0u32;
+                let mut digits = String::new();
+
+                while digits.len() < 3 {
+                    match self.peek().filter(char::is_ascii_digit) {
+                        Some(c) => {
+                            digits.push(c);
+                            value = value * 10 + c.to_digit(10).unwrap();
+                            self.bump();
+                        }
+                        None => break,
+                    }
+                }
+
+                u8::try_from(value)
+                    .map(|byte| vec![byte])
+                    .map_err(|_| format!("\\{digits}"))
+            }
+            c => {
+                self.bump();
+                Err(format!("\\{c}"))
+            }
+        }
+    }
+}
+
+fn hex_digits(digits: &[u32]) -> String {
+    digits
+        .iter()
+        .map(|digit| char::from_digit(*digit, 16).unwrap())
+        .collect()
+}
+
+/// Converts the text of a numeral into a number as the reference implementation of
+/// `dialect` does, or `None` if it is malformed.
+pub(crate) fn parse_number(text: &str, dialect: Dialect) -> Option<TokenKind> {
+    if dialect.has_binary_numerals() {
+        if text.contains('_') {
+            return parse_number(&text.replace('_', ""), dialect);
+        }
+
+        if let Some(binary) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+            if binary.is_empty() || !binary.chars().all(|c| matches!(c, '0' | '1')) {
+                return None;
+            }
+
+            return u64::from_str_radix(binary, 2)
+                .ok()
+                .map(|n| TokenKind::Number(n as f64));
+        }
+    }
+
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        return parse_hex(hex, dialect);
+    }
+
+    if !text
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-'))
+    {
+        return None;
+    }
+
+    let integral = text.chars().all(|c| c.is_ascii_digit());
+
+    if integral && dialect.has_integers() {
+        // decimal integers that do not fit are read as floats
+        if let Ok(n) = text.parse::<i64>() {
+            return Some(TokenKind::Integer(n));
+        }
+    }
+
+    text.parse::<f64>().ok().map(TokenKind::Number)
+}
+
+fn parse_hex(text: &str, dialect: Dialect) -> Option<TokenKind> {
+    let (mantissa, exponent) = match text.find(['p', 'P']) {
+        Some(index) if dialect.has_hex_floats() => {
+            (&text[..index], Some(text[index + 1..].parse::<i32>().ok()?))
+        }
+        Some(_) => return None,
+        None => (text, None),
+    };
+
+    let (whole, fraction) = match mantissa.split_once('.') {
+        Some(_) if !dialect.has_hex_floats() => return None,
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (mantissa, None),
+    };
+
+    if whole.is_empty() && fraction.map_or(true, str::is_empty) {
+        return None;
+    }
+
+    let digits = whole
+        .chars()
+        .chain(fraction.unwrap_or("").chars())
+        .map(|c| c.to_digit(16).map(u64::from))
+        .collect::<Option<Vec<_>>>()?;
+
+    if fraction.is_none() && exponent.is_none() && dialect.has_integers() {
+        // hexadecimal integers wrap around instead of turning into floats
+        let n = digits
+            .iter()
+            .fold(0u64, |n, digit| n.wrapping_mul(16).wrapping_add(*digit));
+
+        return Some(TokenKind::Integer(n as i64));
+    }
+
+    let value = digits
+        .iter()
+        .fold(0.0f64, |n, digit| n * 16.0 + *digit as f64);
+    let scale = exponent.unwrap_or(0) - 4 * fraction.map_or(0, str::len) as i32;
+
+    Some(TokenKind::Number(value * 2f64.powi(scale)))
+}
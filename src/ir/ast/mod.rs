@@ -0,0 +1,280 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The abstract syntax tree of Lua source code, and the parser that builds it from source
+//! text of any Lua dialect.
+
+mod lexer;
+mod parser;
+mod tests;
+pub mod tree;
+
+use crate::ir::span::Span;
+use std::{error::Error, fmt::Display};
+use tree::Node;
+
+/// The dialects of Lua that source code can be parsed as.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Dialect {
+    Lua51,
+    Lua52,
+    Lua53,
+    #[default]
+    Lua54,
+    /// The dialect of Roblox, based on Lua 5.1 with gradual typing and other extensions.
+    Luau,
+}
+
+impl Dialect {
+    /// Whether `goto` and labels exist, which makes `goto` a keyword.
+    pub fn has_goto(&self) -> bool {
+        !matches!(self, Self::Lua51 | Self::Luau)
+    }
+
+    /// Whether numerals without a fraction or an exponent are integers.
+    pub fn has_integers(&self) -> bool {
+        matches!(self, Self::Lua53 | Self::Lua54)
+    }
+
+    /// Whether the bitwise operators exist.
+    pub fn has_bitwise_operators(&self) -> bool {
+        matches!(self, Self::Lua53 | Self::Lua54)
+    }
+
+    /// Whether the floor division operator `//` exists.
+    pub fn has_floor_division(&self) -> bool {
+        matches!(self, Self::Lua53 | Self::Lua54 | Self::Luau)
+    }
+
+    /// Whether locals can be declared with attributes such as `<const>`.
+    pub fn has_attributes(&self) -> bool {
+        matches!(self, Self::Lua54)
+    }
+
+    /// Whether hexadecimal numerals can have a fraction and a binary exponent.
+    pub fn has_hex_floats(&self) -> bool {
+        matches!(self, Self::Lua52 | Self::Lua53 | Self::Lua54)
+    }
+
+    /// Whether numerals can be binary, as in `0b101`, and contain `_` separators.
+    pub fn has_binary_numerals(&self) -> bool {
+        matches!(self, Self::Luau)
+    }
+
+    /// Whether strings can contain the `\x` and `\z` escapes.
+    pub fn has_extended_escapes(&self) -> bool {
+        !matches!(self, Self::Lua51)
+    }
+
+    /// Whether strings can contain the `\u{XXX}` escape.
+    pub fn has_unicode_escapes(&self) -> bool {
+        matches!(self, Self::Lua53 | Self::Lua54 | Self::Luau)
+    }
+
+    /// Whether backquoted strings can interpolate expressions, as in `` `a{b}` ``.
+    pub fn has_interpolated_strings(&self) -> bool {
+        matches!(self, Self::Luau)
+    }
+
+    /// Whether type annotations, type aliases, type assertions and generic functions exist.
+    pub fn has_types(&self) -> bool {
+        matches!(self, Self::Luau)
+    }
+
+    /// Whether compound assignments such as `a += b` exist.
+    pub fn has_compound_assignment(&self) -> bool {
+        matches!(self, Self::Luau)
+    }
+
+    /// Whether `continue` exists, which is not a keyword but a statement of its own.
+    pub fn has_continue(&self) -> bool {
+        matches!(self, Self::Luau)
+    }
+
+    /// Whether `if a then b else c` is an expression.
+    pub fn has_if_expressions(&self) -> bool {
+        matches!(self, Self::Luau)
+    }
+
+    /// Whether functions can be declared with attributes such as `@native`.
+    pub fn has_function_attributes(&self) -> bool {
+        matches!(self, Self::Luau)
+    }
+
+    /// Whether the arguments of a call cannot start on a new line with `(`, which could as
+    /// well start a new statement. Lua 5.1 and Luau reject these calls.
+    pub fn rejects_ambiguous_calls(&self) -> bool {
+        matches!(self, Self::Lua51 | Self::Luau)
+    }
+}
+
+impl Display for Dialect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Lua51 => write!(f, "Lua 5.1"),
+            Self::Lua52 => write!(f, "Lua 5.2"),
+            Self::Lua53 => write!(f, "Lua 5.3"),
+            Self::Lua54 => write!(f, "Lua 5.4"),
+            Self::Luau => write!(f, "Luau"),
+        }
+    }
+}
+
+/// The ways in which source code can be malformed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    /// Something else was found where `expected` should be.
+    Expected { expected: String, found: String },
+    /// The construct `opener` that starts at `start` is not closed by `closer`.
+    Unclosed {
+        opener: &'static str,
+        closer: &'static str,
+        start: Span,
+        found: String,
+    },
+    /// The character cannot start any token.
+    UnexpectedCharacter(char),
+    /// A quoted string runs into the end of its line.
+    UnfinishedString,
+    /// A long string runs into the end of the input.
+    UnfinishedLongString,
+    /// A long comment runs into the end of the input.
+    UnfinishedComment,
+    /// The escape sequence is not valid in the dialect.
+    InvalidEscape(String),
+    /// The numeral is not a valid number in the dialect.
+    MalformedNumber(String),
+    /// The construct does not exist in the dialect.
+    Unsupported {
+        construct: &'static str,
+        dialect: Dialect,
+    },
+    /// The left side of an assignment is not a name, an index or a field.
+    NotAssignable,
+    /// A call or assignment was expected, but an expression was found on its own.
+    NotAStatement,
+    /// A `return` is followed by more statements.
+    ReturnNotLast,
+    /// A `break` is not inside of a loop.
+    BreakOutsideLoop,
+    /// A `continue` is not inside of a loop.
+    ContinueOutsideLoop,
+    /// A `...` is used inside of a function that takes no extra arguments.
+    VarArgOutsideVararg,
+    /// The attribute of a local is neither `const` nor `close`, or that of a function is not
+    /// one of Luau.
+    UnknownAttribute(String),
+    /// The `(` of a call starts a new line, so that it could also start a new statement.
+    AmbiguousCall,
+}
+
+impl Display for DiagnosticKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Expected { expected, found } => write!(f, "expected {expected}, found {found}"),
+            Self::Unclosed {
+                opener,
+                closer,
+                start,
+                found,
+            } => write!(
+                f,
+                "expected `{closer}` to close `{opener}` at {start}, found {found}"
+            ),
+            Self::UnexpectedCharacter(c) => write!(f, "unexpected character {c:?}"),
+            Self::UnfinishedString => write!(f, "unfinished string"),
+            Self::UnfinishedLongString => write!(f, "unfinished long string"),
+            Self::UnfinishedComment => write!(f, "unfinished long comment"),
+            Self::InvalidEscape(sequence) => write!(f, "invalid escape sequence `{sequence}`"),
+            Self::MalformedNumber(text) => write!(f, "malformed number `{text}`"),
+            Self::Unsupported { construct, dialect } => {
+                write!(f, "{dialect} does not support {construct}")
+            }
+            Self::NotAssignable => write!(f, "cannot assign to this expression"),
+            Self::NotAStatement => write!(f, "expected a call or an assignment"),
+            Self::ReturnNotLast => write!(f, "`return` must be the last statement of its block"),
+            Self::BreakOutsideLoop => write!(f, "`break` outside of a loop"),
+            Self::ContinueOutsideLoop => write!(f, "`continue` outside of a loop"),
+            Self::VarArgOutsideVararg => {
+                write!(f, "cannot use `...` outside of a vararg function")
+            }
+            Self::UnknownAttribute(name) => write!(f, "unknown attribute `{name}`"),
+            Self::AmbiguousCall => write!(f, "ambiguous syntax (function call x new statement)"),
+        }
+    }
+}
+
+/// A problem with source code found by the parser.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub span: Span,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.span, self.kind)
+    }
+}
+
+/// Every problem found while parsing source code, in the order of the source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyntaxError(pub Vec<Diagnostic>);
+
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let diagnostics = self
+            .0
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        write!(f, "invalid source code: {diagnostics}")
+    }
+}
+
+impl Error for SyntaxError {}
+
+/// Parses `source` as a chunk of `dialect`, failing with every problem found in it.
+pub fn parse(source: &str, dialect: Dialect) -> Result<Node, SyntaxError> {
+    let (tree, diagnostics) = parse_recovering(source, dialect);
+
+    if diagnostics.is_empty() {
+        Ok(tree)
+    } else {
+        Err(SyntaxError(diagnostics))
+    }
+}
+
+/// Parses `source` as a chunk of `dialect`, continuing after every problem. The tree holds
+/// `Error` statements and expressions where the source could not be parsed, and is only
+/// meaningful when there are no diagnostics.
+pub fn parse_recovering(source: &str, dialect: Dialect) -> (Node, Vec<Diagnostic>) {
+    let mut diagnostics = vec![];
+    let tokens = lexer::tokenize(source, dialect, &mut diagnostics);
+
+    let tree = parser::Parser::new(tokens, dialect, &mut diagnostics).chunk();
+    diagnostics.sort_by_key(|diagnostic| diagnostic.span);
+
+    (tree, diagnostics)
+}
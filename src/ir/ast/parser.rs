@@ -0,0 +1,1599 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Builds syntax trees from tokens by recursive descent, following the grammar of the
+//! reference implementation. Problems are reported as diagnostics, after which the parser
+//! skips to the next statement and carries on.
+
+use super::{
+    lexer::{Token, TokenKind},
+    tree::*,
+    Diagnostic, DiagnosticKind, Dialect,
+};
+use crate::ir::span::Span;
+
+/// The keywords that start a statement, where parsing resumes after a problem.
+const STATEMENT_STARTERS: [&str; 11] = [
+    "local", "function", "if", "while", "for", "repeat", "do", "return", "break", "goto", "::",
+];
+
+/// The keywords that open a block closed by `end` or `until`. Loops are counted by their
+/// `do`, as it is the keyword that their `end` pairs with.
+const BLOCK_OPENERS: [&str; 4] = ["if", "function", "repeat", "do"];
+
+/// The compound assignments of Luau and the operators they apply.
+const COMPOUND_OPERATORS: [(&str, BinaryOperator); 8] = [
+    ("+=", BinaryOperator::Add),
+    ("-=", BinaryOperator::Sub),
+    ("*=", BinaryOperator::Mul),
+    ("/=", BinaryOperator::Div),
+    ("//=", BinaryOperator::IDiv),
+    ("%=", BinaryOperator::Mod),
+    ("^=", BinaryOperator::Pow),
+    ("..=", BinaryOperator::Concat),
+];
+
+/// The attributes that functions can be declared with in Luau.
+const FUNCTION_ATTRIBUTES: [&str; 3] = ["checked", "deprecated", "native"];
+
+/// The priority of unary operators, which bind tighter than every binary operator but `^`.
+const UNARY_PRIORITY: u8 = 12;
+
+/// Unwinds parsing to the enclosing statement after a problem was reported.
+struct Recover;
+
+type Parsed<T> = Result<T, Recover>;
+
+/// What the parser tracks about each function it is inside of.
+struct FunctionScope {
+    is_vararg: bool,
+    /// The number of loops around the current position within the function.
+    loops: usize,
+}
+
+pub(crate) struct Parser<'d> {
+    tokens: Vec<Token>,
+    position: usize,
+    dialect: Dialect,
+    diagnostics: &'d mut Vec<Diagnostic>,
+    functions: Vec<FunctionScope>,
+    /// The number of blocks opened but not yet closed, so that recovery can skip the rest
+    /// of a construct that failed to parse.
+    depth: usize,
+}
+
+fn binary_operator(token: &TokenKind) -> Option<BinaryOperator> {
+    let symbol = match token {
+        TokenKind::Symbol(symbol) => *symbol,
+        _ => return None,
+    };
+
+    Some(match symbol {
+        "+" => BinaryOperator::Add,
+        "-" => BinaryOperator::Sub,
+        "*" => BinaryOperator::Mul,
+        "/" => BinaryOperator::Div,
+        "//" => BinaryOperator::IDiv,
+        "%" => BinaryOperator::Mod,
+        "^" => BinaryOperator::Pow,
+        ".." => BinaryOperator::Concat,
+        "==" => BinaryOperator::Eq,
+        "~=" => BinaryOperator::Ne,
+        "<" => BinaryOperator::Lt,
+        "<=" => BinaryOperator::Le,
+        ">" => BinaryOperator::Gt,
+        ">=" => BinaryOperator::Ge,
+        "and" => BinaryOperator::And,
+        "or" => BinaryOperator::Or,
+        "&" => BinaryOperator::BitAnd,
+        "|" => BinaryOperator::BitOr,
+        "~" => BinaryOperator::BitXor,
+        "<<" => BinaryOperator::LeftShift,
+        ">>" => BinaryOperator::RightShift,
+        _ => return None,
+    })
+}
+
+fn unary_operator(token: &TokenKind) -> Option<UnaryOperator> {
+    match token {
+        TokenKind::Symbol("-") => Some(UnaryOperator::Neg),
+        TokenKind::Symbol("not") => Some(UnaryOperator::Not),
+        TokenKind::Symbol("#") => Some(UnaryOperator::Len),
+        TokenKind::Symbol("~") => Some(UnaryOperator::BitNot),
+        _ => None,
+    }
+}
+
+fn compound_operator(token: &TokenKind) -> Option<BinaryOperator> {
+    COMPOUND_OPERATORS
+        .iter()
+        .find(|(symbol, _)| *token == TokenKind::Symbol(symbol))
+        .map(|(_, operator)| *operator)
+}
+
+/// Whether `token` continues an expression started by a name, so that the name `continue`
+/// is a variable rather than a statement.
+fn continues_expression(token: &TokenKind) -> bool {
+    match token {
+        TokenKind::Symbol(symbol) => {
+            matches!(*symbol, "(" | "." | "[" | ":" | "=" | "," | "{")
+                || compound_operator(token).is_some()
+        }
+        TokenKind::String(_) => true,
+        _ => false,
+    }
+}
+
+/// The left and right priorities of a binary operator, as in the reference implementation.
+/// An operator whose right priority is lower than its left one is right associative.
+fn priority(operator: BinaryOperator) -> (u8, u8) {
+    use BinaryOperator::*;
+
+    match operator {
+        Or => (1, 1),
+        And => (2, 2),
+        Eq | Ne | Lt | Le | Gt | Ge => (3, 3),
+        BitOr => (4, 4),
+        BitXor => (5, 5),
+        BitAnd => (6, 6),
+        LeftShift | RightShift => (7, 7),
+        Concat => (9, 8),
+        Add | Sub => (10, 10),
+        Mul | Div | IDiv | Mod => (11, 11),
+        Pow => (14, 13),
+    }
+}
+
+impl<'d> Parser<'d> {
+    pub(crate) fn new(
+        tokens: Vec<Token>,
+        dialect: Dialect,
+        diagnostics: &'d mut Vec<Diagnostic>,
+    ) -> Self {
+        Self {
+            tokens,
+            position: 0,
+            dialect,
+            diagnostics,
+            functions: vec![],
+            depth: 0,
+        }
+    }
+
+    /// Parses every token as the main function of a chunk, which takes extra arguments.
+    pub(crate) fn chunk(mut self) -> Node {
+        self.functions.push(FunctionScope {
+            is_vararg: true,
+            loops: 0,
+        });
+
+        let mut block = self.block();
+
+        // a closing keyword without a construct to close, parsing resumes after it
+        while *self.peek() != TokenKind::Eof {
+            self.report(
+                self.span(),
+                DiagnosticKind::Expected {
+                    expected: "a statement".to_owned(),
+                    found: self.found(),
+                },
+            );
+            self.advance();
+
+            block.statements.extend(self.block().statements);
+        }
+
+        Node { block }
+    }
+
+    fn peek(&self) -> &TokenKind {
+        &self.tokens[self.position].kind
+    }
+
+    fn peek_at(&self, offset: usize) -> &TokenKind {
+        let last = self.tokens.len() - 1;
+
+        &self.tokens[(self.position + offset).min(last)].kind
+    }
+
+    fn span(&self) -> Span {
+        self.tokens[self.position].span
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.position].clone();
+
+        if token.kind != TokenKind::Eof {
+            self.position += 1;
+        }
+
+        token
+    }
+
+    fn check(&self, symbol: &str) -> bool {
+        matches!(self.peek(), TokenKind::Symbol(s) if *s == symbol)
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        let found = self.check(symbol);
+
+        if found {
+            self.advance();
+        }
+
+        found
+    }
+
+    /// Describes the current token for diagnostics.
+    fn found(&self) -> String {
+        self.peek().to_string()
+    }
+
+    fn report(&mut self, span: Span, kind: DiagnosticKind) {
+        self.diagnostics.push(Diagnostic { kind, span });
+    }
+
+    /// Reports that `expected` should be at the current token.
+    fn expected<T>(&mut self, expected: &str) -> Parsed<T> {
+        self.report(
+            self.span(),
+            DiagnosticKind::Expected {
+                expected: expected.to_owned(),
+                found: self.found(),
+            },
+        );
+
+        Err(Recover)
+    }
+
+    fn expect(&mut self, symbol: &str) -> Parsed<()> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            self.expected(&format!("`{symbol}`"))
+        }
+    }
+
+    /// Consumes the keyword that opens a block.
+    fn open(&mut self, keyword: &str) -> Parsed<()> {
+        self.expect(keyword)?;
+        self.depth += 1;
+
+        Ok(())
+    }
+
+    /// Consumes the keyword that closes the block of the construct `opener` at `start`.
+    fn close(&mut self, closer: &'static str, opener: &'static str, start: Span) -> Parsed<()> {
+        if self.eat(closer) {
+            self.depth -= 1;
+            return Ok(());
+        }
+
+        self.report(
+            self.span(),
+            DiagnosticKind::Unclosed {
+                opener,
+                closer,
+                start,
+                found: self.found(),
+            },
+        );
+
+        Err(Recover)
+    }
+
+    /// Reports `construct` when the dialect lacks it, parsing carries on regardless.
+    fn require(&mut self, supported: bool, span: Span, construct: &'static str) {
+        if !supported {
+            self.report(
+                span,
+                DiagnosticKind::Unsupported {
+                    construct,
+                    dialect: self.dialect,
+                },
+            );
+        }
+    }
+
+    fn function_scope(&mut self) -> &mut FunctionScope {
+        self.functions.last_mut().expect("the chunk is a function")
+    }
+
+    fn name(&mut self) -> Parsed<Name> {
+        match self.peek().clone() {
+            TokenKind::Name(name) => {
+                let span = self.advance().span;
+                Ok(Name { name, span })
+            }
+            _ => self.expected("a name"),
+        }
+    }
+
+    /// Whether the current token ends a block.
+    fn block_follows(&self) -> bool {
+        matches!(
+            self.peek(),
+            TokenKind::Eof | TokenKind::Symbol("end" | "else" | "elseif" | "until")
+        )
+    }
+
+    fn block(&mut self) -> Block {
+        let mut statements = vec![];
+
+        loop {
+            if self.eat(";") {
+                continue;
+            }
+
+            if self.block_follows() {
+                break;
+            }
+
+            let start = self.position;
+            let depth = self.depth;
+            let span = self.span();
+            let is_return = self.check("return");
+
+            match self.statement() {
+                Ok(statement) => statements.push(statement),
+                Err(Recover) => {
+                    statements.push(Statement {
+                        kind: StatementKind::Error,
+                        span,
+                    });
+
+                    let unclosed = self.depth - depth;
+                    self.depth = depth;
+                    self.synchronize(start, unclosed);
+                }
+            }
+
+            if is_return {
+                while self.eat(";") {}
+
+                if !self.block_follows() {
+                    self.report(self.span(), DiagnosticKind::ReturnNotLast);
+                }
+            }
+        }
+
+        Block { statements }
+    }
+
+    /// Skips to where the next statement starts after the statement at `start` failed to
+    /// parse, past the end of the `unclosed` blocks it opened.
+    fn synchronize(&mut self, start: usize, mut unclosed: usize) {
+        if self.position == start && unclosed == 0 {
+            self.advance();
+        }
+
+        loop {
+            match self.peek() {
+                TokenKind::Eof => return,
+                TokenKind::Symbol(symbol)
+                    if unclosed == 0
+                        && (STATEMENT_STARTERS.contains(symbol)
+                            || *symbol == ";"
+                            || self.block_follows()) =>
+                {
+                    return
+                }
+                TokenKind::Symbol("end" | "until") => {
+                    unclosed -= 1;
+
+                    // the failed statement ends with the block it opened
+                    if unclosed == 0 {
+                        self.advance();
+                        return;
+                    }
+                }
+                TokenKind::Symbol(symbol) if BLOCK_OPENERS.contains(symbol) => unclosed += 1,
+                _ => {}
+            }
+
+            self.advance();
+        }
+    }
+
+    fn statement(&mut self) -> Parsed<Statement> {
+        let span = self.span();
+
+        let kind = match self.peek() {
+            TokenKind::Symbol("if") => self.if_statement(span)?,
+            TokenKind::Symbol("while") => {
+                self.advance();
+                let condition = self.expression()?;
+
+                self.open("do")?;
+                let block = self.loop_block();
+                self.close("end", "while", span)?;
+
+                StatementKind::While { condition, block }
+            }
+            TokenKind::Symbol("do") => {
+                self.open("do")?;
+                let block = self.block();
+                self.close("end", "do", span)?;
+
+                StatementKind::Do(block)
+            }
+            TokenKind::Symbol("for") => self.for_statement(span)?,
+            TokenKind::Symbol("repeat") => {
+                self.open("repeat")?;
+                let block = self.loop_block();
+                self.close("until", "repeat", span)?;
+
+                StatementKind::Repeat {
+                    block,
+                    condition: self.expression()?,
+                }
+            }
+            TokenKind::Symbol("function") => self.function_statement(span, vec![])?,
+            TokenKind::Symbol("local") => {
+                self.advance();
+
+                if self.check("function") {
+                    self.local_function(span, vec![])?
+                } else {
+                    self.local_statement()?
+                }
+            }
+            TokenKind::Symbol("@") => {
+                let attributes = self.attributes()?;
+
+                if self.eat("local") {
+                    self.local_function(span, attributes)?
+                } else {
+                    self.function_statement(span, attributes)?
+                }
+            }
+            TokenKind::Symbol("::") => {
+                self.advance();
+                self.require(self.dialect.has_goto(), span, "labels");
+
+                let name = self.name()?;
+                self.expect("::")?;
+
+                StatementKind::Label(name)
+            }
+            TokenKind::Symbol("return") => {
+                self.advance();
+
+                let values = if self.block_follows() || self.check(";") {
+                    vec![]
+                } else {
+                    self.expression_list()?
+                };
+
+                StatementKind::Return(values)
+            }
+            TokenKind::Symbol("break") => {
+                self.advance();
+
+                if self.function_scope().loops == 0 {
+                    self.report(span, DiagnosticKind::BreakOutsideLoop);
+                }
+
+                StatementKind::Break
+            }
+            TokenKind::Symbol("goto") => {
+                self.advance();
+
+                StatementKind::Goto(self.name()?)
+            }
+            TokenKind::Name(name)
+                if name == "continue"
+                    && self.dialect.has_continue()
+                    && !continues_expression(self.peek_at(1)) =>
+            {
+                self.advance();
+
+                if self.function_scope().loops == 0 {
+                    self.report(span, DiagnosticKind::ContinueOutsideLoop);
+                }
+
+                StatementKind::Continue
+            }
+            TokenKind::Name(name)
+                if name == "type"
+                    && self.dialect.has_types()
+                    && matches!(self.peek_at(1), TokenKind::Name(_)) =>
+            {
+                self.advance();
+                self.type_alias(false)?
+            }
+            TokenKind::Name(name)
+                if name == "export"
+                    && self.dialect.has_types()
+                    && matches!(self.peek_at(1), TokenKind::Name(name) if name == "type")
+                    && matches!(self.peek_at(2), TokenKind::Name(_)) =>
+            {
+                self.advance();
+                self.advance();
+                self.type_alias(true)?
+            }
+            _ => self.expression_statement()?,
+        };
+
+        Ok(Statement { kind, span })
+    }
+
+    fn function_statement(&mut self, span: Span, attributes: Vec<Name>) -> Parsed<StatementKind> {
+        self.open("function")?;
+
+        let mut path = vec![self.name()?];
+        while self.eat(".") {
+            path.push(self.name()?);
+        }
+
+        let method = if self.eat(":") {
+            Some(self.name()?)
+        } else {
+            None
+        };
+
+        Ok(StatementKind::Function {
+            name: FunctionName { path, method },
+            function: self.function_body(span, attributes)?,
+        })
+    }
+
+    fn local_function(&mut self, span: Span, attributes: Vec<Name>) -> Parsed<StatementKind> {
+        self.open("function")?;
+
+        Ok(StatementKind::LocalFunction {
+            name: self.name()?,
+            function: self.function_body(span, attributes)?,
+        })
+    }
+
+    /// Parses the attributes of a function, such as `@native`.
+    fn attributes(&mut self) -> Parsed<Vec<Name>> {
+        let mut attributes = vec![];
+
+        while self.eat("@") {
+            let attribute = self.name()?;
+
+            if !FUNCTION_ATTRIBUTES.contains(&&*attribute.name) {
+                self.report(
+                    attribute.span,
+                    DiagnosticKind::UnknownAttribute(attribute.name.clone()),
+                );
+            }
+
+            attributes.push(attribute);
+        }
+
+        Ok(attributes)
+    }
+
+    fn type_alias(&mut self, is_exported: bool) -> Parsed<StatementKind> {
+        let name = self.name()?;
+
+        let generics = if self.check("<") {
+            self.generic_params(true)?
+        } else {
+            vec![]
+        };
+
+        self.expect("=")?;
+
+        Ok(StatementKind::TypeAlias {
+            is_exported,
+            name,
+            generics,
+            value: self.type_annotation()?,
+        })
+    }
+
+    /// Parses the body of a loop, inside of which `break` is allowed.
+    fn loop_block(&mut self) -> Block {
+        self.function_scope().loops += 1;
+        let block = self.block();
+        self.function_scope().loops -= 1;
+
+        block
+    }
+
+    fn if_statement(&mut self, span: Span) -> Parsed<StatementKind> {
+        self.open("if")?;
+
+        let mut branches = vec![];
+
+        loop {
+            let condition = self.expression()?;
+            self.expect("then")?;
+
+            branches.push(Branch {
+                condition,
+                block: self.block(),
+            });
+
+            if !self.eat("elseif") {
+                break;
+            }
+        }
+
+        let otherwise = if self.eat("else") {
+            Some(self.block())
+        } else {
+            None
+        };
+
+        self.close("end", "if", span)?;
+
+        Ok(StatementKind::If {
+            branches,
+            otherwise,
+        })
+    }
+
+    fn for_statement(&mut self, span: Span) -> Parsed<StatementKind> {
+        self.advance();
+
+        let first = self.typed_name()?;
+
+        if self.eat("=") {
+            let start = self.expression()?;
+            self.expect(",")?;
+            let limit = self.expression()?;
+
+            let step = if self.eat(",") {
+                Some(self.expression()?)
+            } else {
+                None
+            };
+
+            self.open("do")?;
+            let block = self.loop_block();
+            self.close("end", "for", span)?;
+
+            return Ok(StatementKind::NumericFor {
+                variable: first,
+                start,
+                limit,
+                step,
+                block,
+            });
+        }
+
+        if !self.check(",") && !self.check("in") {
+            return self.expected("`=` or `in`");
+        }
+
+        let mut names = vec![first];
+        while self.eat(",") {
+            names.push(self.typed_name()?);
+        }
+
+        self.expect("in")?;
+        let values = self.expression_list()?;
+
+        self.open("do")?;
+        let block = self.loop_block();
+        self.close("end", "for", span)?;
+
+        Ok(StatementKind::GenericFor {
+            names,
+            values,
+            block,
+        })
+    }
+
+    fn local_statement(&mut self) -> Parsed<StatementKind> {
+        let mut names = vec![];
+
+        loop {
+            let name = self.name()?;
+            let annotation = self.annotation()?;
+
+            let attribute = if self.check("<") {
+                let span = self.advance().span;
+                self.require(self.dialect.has_attributes(), span, "attributes");
+
+                let attribute = self.name()?;
+                self.expect(">")?;
+
+                if self.dialect.has_attributes() && !matches!(&*attribute.name, "const" | "close") {
+                    self.report(
+                        attribute.span,
+                        DiagnosticKind::UnknownAttribute(attribute.name.clone()),
+                    );
+                }
+
+                Some(attribute)
+            } else {
+                None
+            };
+
+            names.push(LocalName {
+                name,
+                attribute,
+                annotation,
+            });
+
+            if !self.eat(",") {
+                break;
+            }
+        }
+
+        let values = if self.eat("=") {
+            self.expression_list()?
+        } else {
+            vec![]
+        };
+
+        Ok(StatementKind::Local { names, values })
+    }
+
+    fn expression_statement(&mut self) -> Parsed<StatementKind> {
+        let expression = self.suffixed_expression()?;
+
+        if let Some(operator) = compound_operator(self.peek()) {
+            self.advance();
+            let value = self.expression()?;
+
+            if !expression.is_assignable() {
+                self.report(expression.span, DiagnosticKind::NotAssignable);
+            }
+
+            return Ok(StatementKind::CompoundAssign {
+                operator,
+                target: expression,
+                value,
+            });
+        }
+
+        if !self.check("=") && !self.check(",") {
+            if expression.is_call() {
+                return Ok(StatementKind::Call(expression));
+            }
+
+            self.report(expression.span, DiagnosticKind::NotAStatement);
+            return Err(Recover);
+        }
+
+        let mut targets = vec![expression];
+        while self.eat(",") {
+            targets.push(self.suffixed_expression()?);
+        }
+
+        self.expect("=")?;
+        let values = self.expression_list()?;
+
+        for target in &targets {
+            if !target.is_assignable() {
+                self.report(target.span, DiagnosticKind::NotAssignable);
+            }
+        }
+
+        Ok(StatementKind::Assign { targets, values })
+    }
+
+    /// Parses the parameters and body of a function after the keyword `function`, and the
+    /// name in statements, which open the function at `start`.
+    fn function_body(&mut self, start: Span, attributes: Vec<Name>) -> Parsed<FunctionBody> {
+        let span = self.span();
+
+        let generics = if self.dialect.has_types() && self.check("<") {
+            self.generic_params(false)?
+        } else {
+            vec![]
+        };
+
+        self.expect("(")?;
+
+        let mut params = vec![];
+        let mut is_vararg = false;
+        let mut vararg_annotation = None;
+
+        if !self.check(")") {
+            loop {
+                if self.eat("...") {
+                    is_vararg = true;
+
+                    if self.dialect.has_types() && self.eat(":") {
+                        vararg_annotation = Some(if self.is_generic_pack() {
+                            self.pack_tail()?
+                        } else {
+                            PackTail::Variadic(Box::new(self.type_annotation()?))
+                        });
+                    }
+
+                    break;
+                }
+
+                match self.peek() {
+                    TokenKind::Name(_) => params.push(self.typed_name()?),
+                    _ => return self.expected("a name or `...`"),
+                }
+
+                if !self.eat(",") {
+                    break;
+                }
+            }
+        }
+
+        self.expect(")")?;
+
+        let returns = if self.dialect.has_types() && self.eat(":") {
+            Some(self.return_type()?)
+        } else {
+            None
+        };
+
+        self.functions.push(FunctionScope {
+            is_vararg,
+            loops: 0,
+        });
+        let block = self.block();
+        self.functions.pop();
+
+        self.close("end", "function", start)?;
+
+        Ok(FunctionBody {
+            attributes,
+            generics,
+            params,
+            is_vararg,
+            vararg_annotation,
+            returns,
+            block,
+            span,
+        })
+    }
+
+    fn expression_list(&mut self) -> Parsed<Vec<Expression>> {
+        let mut expressions = vec![self.expression()?];
+
+        while self.eat(",") {
+            expressions.push(self.expression()?);
+        }
+
+        Ok(expressions)
+    }
+
+    fn expression(&mut self) -> Parsed<Expression> {
+        self.sub_expression(0)
+    }
+
+    /// Parses an expression whose binary operators bind tighter than `limit`.
+    fn sub_expression(&mut self, limit: u8) -> Parsed<Expression> {
+        let span = self.span();
+
+        let mut left = match unary_operator(self.peek()) {
+            Some(operator) => {
+                self.advance();
+
+                if operator == UnaryOperator::BitNot {
+                    self.require(
+                        self.dialect.has_bitwise_operators(),
+                        span,
+                        "bitwise operators",
+                    );
+                }
+
+                let operand = self.sub_expression(UNARY_PRIORITY)?;
+
+                Expression {
+                    kind: ExpressionKind::Unary {
+                        operator,
+                        operand: Box::new(operand),
+                    },
+                    span,
+                }
+            }
+            None => self.simple_expression()?,
+        };
+
+        // type assertions bind tighter than any operator
+        while self.dialect.has_types() && self.check("::") {
+            self.advance();
+            let annotation = self.type_annotation()?;
+
+            left = Expression {
+                span: left.span,
+                kind: ExpressionKind::TypeAssertion {
+                    expression: Box::new(left),
+                    annotation: Box::new(annotation),
+                },
+            };
+        }
+
+        while let Some(operator) = binary_operator(self.peek()) {
+            let (left_priority, right_priority) = priority(operator);
+
+            if left_priority <= limit {
+                break;
+            }
+
+            let operator_span = self.advance().span;
+
+            match operator {
+                BinaryOperator::IDiv => self.require(
+                    self.dialect.has_floor_division(),
+                    operator_span,
+                    "floor division",
+                ),
+                BinaryOperator::BitAnd
+                | BinaryOperator::BitOr
+                | BinaryOperator::BitXor
+                | BinaryOperator::LeftShift
+                | BinaryOperator::RightShift => self.require(
+                    self.dialect.has_bitwise_operators(),
+                    operator_span,
+                    "bitwise operators",
+                ),
+                _ => {}
+            }
+
+            let right = self.sub_expression(right_priority)?;
+
+            left = Expression {
+                span: left.span,
+                kind: ExpressionKind::Binary {
+                    operator,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                },
+            };
+        }
+
+        Ok(left)
+    }
+
+    fn simple_expression(&mut self) -> Parsed<Expression> {
+        let span = self.span();
+
+        let kind = match self.peek().clone() {
+            TokenKind::Number(n) => ExpressionKind::Number(n),
+            TokenKind::Integer(n) => ExpressionKind::Integer(n),
+            TokenKind::String(s) => ExpressionKind::String(s),
+            TokenKind::InterpolationSimple(s) => ExpressionKind::InterpolatedString {
+                strings: vec![s],
+                expressions: vec![],
+            },
+            TokenKind::InterpolationBegin(_) => return self.interpolated_string(),
+            TokenKind::Symbol("if") if self.dialect.has_if_expressions() => {
+                return self.if_expression()
+            }
+            TokenKind::Symbol("nil") => ExpressionKind::Nil,
+            TokenKind::Symbol("true") => ExpressionKind::Boolean(true),
+            TokenKind::Symbol("false") => ExpressionKind::Boolean(false),
+            TokenKind::Symbol("...") => {
+                if !self.function_scope().is_vararg {
+                    self.report(span, DiagnosticKind::VarArgOutsideVararg);
+                }
+
+                ExpressionKind::VarArg
+            }
+            TokenKind::Symbol("{") => return self.table(),
+            TokenKind::Symbol("function" | "@") => {
+                let attributes = self.attributes()?;
+                self.open("function")?;
+
+                return Ok(Expression {
+                    kind: ExpressionKind::Function(Box::new(self.function_body(span, attributes)?)),
+                    span,
+                });
+            }
+            _ => return self.suffixed_expression(),
+        };
+
+        self.advance();
+
+        Ok(Expression { kind, span })
+    }
+
+    fn if_expression(&mut self) -> Parsed<Expression> {
+        let span = self.advance().span;
+        let mut branches = vec![];
+
+        loop {
+            let condition = self.expression()?;
+            self.expect("then")?;
+
+            branches.push(ExpressionBranch {
+                condition,
+                value: self.expression()?,
+            });
+
+            if !self.eat("elseif") {
+                break;
+            }
+        }
+
+        self.expect("else")?;
+
+        Ok(Expression {
+            kind: ExpressionKind::IfElse {
+                branches,
+                otherwise: Box::new(self.expression()?),
+            },
+            span,
+        })
+    }
+
+    /// Parses a backquoted string from its first part up to its last one, which the lexer
+    /// splits at each interpolation.
+    fn interpolated_string(&mut self) -> Parsed<Expression> {
+        let span = self.span();
+        let mut strings = vec![];
+        let mut expressions = vec![];
+
+        if let TokenKind::InterpolationBegin(s) = self.advance().kind {
+            strings.push(s);
+        }
+
+        loop {
+            expressions.push(self.expression()?);
+
+            match self.peek().clone() {
+                TokenKind::InterpolationMiddle(s) => {
+                    self.advance();
+                    strings.push(s);
+                }
+                TokenKind::InterpolationEnd(s) => {
+                    self.advance();
+                    strings.push(s);
+                    break;
+                }
+                _ => return self.expected("`}`"),
+            }
+        }
+
+        Ok(Expression {
+            kind: ExpressionKind::InterpolatedString {
+                strings,
+                expressions,
+            },
+            span,
+        })
+    }
+
+    fn primary_expression(&mut self) -> Parsed<Expression> {
+        let span = self.span();
+
+        match self.peek() {
+            TokenKind::Name(_) => Ok(Expression {
+                kind: ExpressionKind::Name(self.name()?),
+                span,
+            }),
+            TokenKind::Symbol("(") => {
+                self.advance();
+                let inner = self.expression()?;
+                self.expect(")")?;
+
+                Ok(Expression {
+                    kind: ExpressionKind::Paren(Box::new(inner)),
+                    span,
+                })
+            }
+            _ => self.expected("an expression"),
+        }
+    }
+
+    /// Parses a primary expression followed by any number of fields, indices and calls.
+    fn suffixed_expression(&mut self) -> Parsed<Expression> {
+        let mut expression = self.primary_expression()?;
+
+        loop {
+            let span = expression.span;
+
+            let kind = match self.peek() {
+                TokenKind::Symbol(".") => {
+                    self.advance();
+
+                    ExpressionKind::Field {
+                        object: Box::new(expression),
+                        name: self.name()?,
+                    }
+                }
+                TokenKind::Symbol("[") => {
+                    self.advance();
+                    let key = self.expression()?;
+                    self.expect("]")?;
+
+                    ExpressionKind::Index {
+                        object: Box::new(expression),
+                        key: Box::new(key),
+                    }
+                }
+                TokenKind::Symbol(":") => {
+                    self.advance();
+                    let method = self.name()?;
+
+                    ExpressionKind::MethodCall {
+                        object: Box::new(expression),
+                        method,
+                        args: self.call_arguments()?,
+                    }
+                }
+                TokenKind::Symbol("(" | "{") | TokenKind::String(_) => ExpressionKind::Call {
+                    callee: Box::new(expression),
+                    args: self.call_arguments()?,
+                },
+                _ => return Ok(expression),
+            };
+
+            expression = Expression { kind, span };
+        }
+    }
+
+    fn call_arguments(&mut self) -> Parsed<Vec<Expression>> {
+        let span = self.span();
+
+        match self.peek().clone() {
+            TokenKind::String(s) => {
+                self.advance();
+
+                Ok(vec![Expression {
+                    kind: ExpressionKind::String(s),
+                    span,
+                }])
+            }
+            TokenKind::Symbol("{") => Ok(vec![self.table()?]),
+            TokenKind::Symbol("(") => {
+                // `a\n(b)()` could be meant as two statements, and Lua 5.1 refuses to guess
+                let previous = &self.tokens[self.position - 1];
+                if self.dialect.rejects_ambiguous_calls() && previous.end_line != span.line {
+                    self.report(span, DiagnosticKind::AmbiguousCall);
+                }
+
+                self.advance();
+
+                if self.eat(")") {
+                    return Ok(vec![]);
+                }
+
+                let args = self.expression_list()?;
+                self.expect(")")?;
+
+                Ok(args)
+            }
+            _ => self.expected("function arguments"),
+        }
+    }
+
+    fn table(&mut self) -> Parsed<Expression> {
+        let span = self.span();
+        self.expect("{")?;
+
+        let mut fields = vec![];
+
+        while !self.check("}") {
+            let field = if self.eat("[") {
+                let key = self.expression()?;
+                self.expect("]")?;
+                self.expect("=")?;
+
+                Field::Keyed {
+                    key,
+                    value: self.expression()?,
+                }
+            } else if matches!(self.peek(), TokenKind::Name(_))
+                && *self.peek_at(1) == TokenKind::Symbol("=")
+            {
+                let name = self.name()?;
+                self.advance();
+
+                Field::Named {
+                    name,
+                    value: self.expression()?,
+                }
+            } else {
+                Field::Positional(self.expression()?)
+            };
+
+            fields.push(field);
+
+            if !self.eat(",") && !self.eat(";") {
+                break;
+            }
+        }
+
+        self.expect("}")?;
+
+        Ok(Expression {
+            kind: ExpressionKind::Table(fields),
+            span,
+        })
+    }
+
+    fn typed_name(&mut self) -> Parsed<TypedName> {
+        Ok(TypedName {
+            name: self.name()?,
+            annotation: self.annotation()?,
+        })
+    }
+
+    /// Parses the type annotation after a name, as in `name: type`.
+    fn annotation(&mut self) -> Parsed<Option<Type>> {
+        if self.dialect.has_types() && self.eat(":") {
+            Ok(Some(self.type_annotation()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Whether a generic type pack such as `T...` follows.
+    fn is_generic_pack(&self) -> bool {
+        matches!(self.peek(), TokenKind::Name(_)) && *self.peek_at(1) == TokenKind::Symbol("...")
+    }
+
+    /// Parses a type, which is a union or an intersection of simple types.
+    fn type_annotation(&mut self) -> Parsed<Type> {
+        let span = self.span();
+
+        // a leading separator is allowed, for types that span several lines
+        let leading = if self.eat("|") {
+            Some("|")
+        } else if self.eat("&") {
+            Some("&")
+        } else {
+            None
+        };
+
+        let first = self.optional_type()?;
+
+        let separator = match leading {
+            Some(separator) => separator,
+            None if self.check("|") => "|",
+            None if self.check("&") => "&",
+            None => return Ok(first),
+        };
+
+        let mut types = vec![first];
+        while self.eat(separator) {
+            types.push(self.optional_type()?);
+        }
+
+        let kind = match separator {
+            "|" => TypeKind::Union(types),
+            _ => TypeKind::Intersection(types),
+        };
+
+        Ok(Type { kind, span })
+    }
+
+    fn optional_type(&mut self) -> Parsed<Type> {
+        let mut annotation = self.simple_type()?;
+
+        while self.eat("?") {
+            annotation = Type {
+                span: annotation.span,
+                kind: TypeKind::Optional(Box::new(annotation)),
+            };
+        }
+
+        Ok(annotation)
+    }
+
+    fn simple_type(&mut self) -> Parsed<Type> {
+        let span = self.span();
+
+        let kind = match self.peek().clone() {
+            TokenKind::Symbol("nil") => {
+                self.advance();
+                TypeKind::Nil
+            }
+            TokenKind::Symbol(symbol @ ("true" | "false")) => {
+                self.advance();
+                TypeKind::Boolean(symbol == "true")
+            }
+            TokenKind::String(s) => {
+                self.advance();
+                TypeKind::String(s)
+            }
+            TokenKind::Name(name)
+                if name == "typeof" && *self.peek_at(1) == TokenKind::Symbol("(") =>
+            {
+                self.advance();
+                self.advance();
+                let expression = self.expression()?;
+                self.expect(")")?;
+
+                TypeKind::Typeof(Box::new(expression))
+            }
+            TokenKind::Name(_) => {
+                let mut name = self.name()?;
+                let mut module = None;
+
+                if self.eat(".") {
+                    module = Some(name);
+                    name = self.name()?;
+                }
+
+                let args = if self.check("<") {
+                    self.type_arguments()?
+                } else {
+                    vec![]
+                };
+
+                TypeKind::Reference { module, name, args }
+            }
+            TokenKind::Symbol("{") => return self.table_type(),
+            TokenKind::Symbol("<") => {
+                let generics = self.generic_params(false)?;
+                return self.function_type(span, generics);
+            }
+            TokenKind::Symbol("(") => return self.function_type(span, vec![]),
+            _ => return self.expected("a type"),
+        };
+
+        Ok(Type { kind, span })
+    }
+
+    fn table_type(&mut self) -> Parsed<Type> {
+        let span = self.span();
+        self.expect("{")?;
+
+        let is_property =
+            matches!(self.peek(), TokenKind::Name(_)) && *self.peek_at(1) == TokenKind::Symbol(":");
+
+        if !self.check("}") && !self.check("[") && !is_property {
+            let element = self.type_annotation()?;
+            self.expect("}")?;
+
+            return Ok(Type {
+                kind: TypeKind::Array(Box::new(element)),
+                span,
+            });
+        }
+
+        let mut fields = vec![];
+
+        while !self.check("}") {
+            let field = if self.eat("[") {
+                let key = self.type_annotation()?;
+                self.expect("]")?;
+                self.expect(":")?;
+
+                TableTypeField::Indexer {
+                    key,
+                    value: self.type_annotation()?,
+                }
+            } else {
+                let name = self.name()?;
+                self.expect(":")?;
+
+                TableTypeField::Property {
+                    name,
+                    value: self.type_annotation()?,
+                }
+            };
+
+            fields.push(field);
+
+            if !self.eat(",") && !self.eat(";") {
+                break;
+            }
+        }
+
+        self.expect("}")?;
+
+        Ok(Type {
+            kind: TypeKind::Table(fields),
+            span,
+        })
+    }
+
+    /// Parses a function type after its generics, or a parenthesized type without them.
+    fn function_type(&mut self, span: Span, generics: Vec<GenericParam>) -> Parsed<Type> {
+        let (mut params, vararg) = self.type_list()?;
+
+        if self.eat("->") {
+            return Ok(Type {
+                kind: TypeKind::Function {
+                    generics,
+                    params,
+                    vararg,
+                    returns: self.return_type()?,
+                },
+                span,
+            });
+        }
+
+        if generics.is_empty() && vararg.is_none() && params.len() == 1 && params[0].name.is_none()
+        {
+            return Ok(Type {
+                kind: TypeKind::Paren(Box::new(params.remove(0).annotation)),
+                span,
+            });
+        }
+
+        self.expected("`->`")
+    }
+
+    /// Parses the parenthesized parameters of a function type, whose names are optional.
+    fn type_list(&mut self) -> Parsed<(Vec<FunctionTypeParam>, Option<PackTail>)> {
+        self.expect("(")?;
+
+        let mut params = vec![];
+        let mut vararg = None;
+
+        if !self.check(")") {
+            loop {
+                if self.check("...") || self.is_generic_pack() {
+                    vararg = Some(self.pack_tail()?);
+                    break;
+                }
+
+                let name = if matches!(self.peek(), TokenKind::Name(_))
+                    && *self.peek_at(1) == TokenKind::Symbol(":")
+                {
+                    let name = self.name()?;
+                    self.advance();
+                    Some(name)
+                } else {
+                    None
+                };
+
+                params.push(FunctionTypeParam {
+                    name,
+                    annotation: self.type_annotation()?,
+                });
+
+                if !self.eat(",") {
+                    break;
+                }
+            }
+        }
+
+        self.expect(")")?;
+
+        Ok((params, vararg))
+    }
+
+    /// Parses a list of types in parentheses, which is a function type when `->` follows
+    /// and a type pack otherwise.
+    fn parenthesized_types(&mut self) -> Parsed<TypeArgument> {
+        let span = self.span();
+        let (params, vararg) = self.type_list()?;
+
+        if self.eat("->") {
+            return Ok(TypeArgument::Type(Type {
+                kind: TypeKind::Function {
+                    generics: vec![],
+                    params,
+                    vararg,
+                    returns: self.return_type()?,
+                },
+                span,
+            }));
+        }
+
+        Ok(TypeArgument::Pack(TypePack {
+            types: params.into_iter().map(|param| param.annotation).collect(),
+            tail: vararg,
+        }))
+    }
+
+    /// Parses `...T` or `T...`.
+    fn pack_tail(&mut self) -> Parsed<PackTail> {
+        if self.eat("...") {
+            return Ok(PackTail::Variadic(Box::new(self.type_annotation()?)));
+        }
+
+        let name = self.name()?;
+        self.expect("...")?;
+
+        Ok(PackTail::Generic(name))
+    }
+
+    /// Parses the results of a function, a type or a type pack.
+    fn return_type(&mut self) -> Parsed<TypePack> {
+        if self.check("...") || self.is_generic_pack() {
+            return Ok(TypePack {
+                types: vec![],
+                tail: Some(self.pack_tail()?),
+            });
+        }
+
+        if !self.check("(") {
+            return Ok(TypePack {
+                types: vec![self.type_annotation()?],
+                tail: None,
+            });
+        }
+
+        Ok(match self.parenthesized_types()? {
+            TypeArgument::Type(function) => TypePack {
+                types: vec![function],
+                tail: None,
+            },
+            TypeArgument::Pack(pack) => pack,
+        })
+    }
+
+    fn type_arguments(&mut self) -> Parsed<Vec<TypeArgument>> {
+        self.expect("<")?;
+
+        let mut args = vec![];
+
+        if !self.check(">") {
+            loop {
+                let arg = if self.check("...") || self.is_generic_pack() {
+                    TypeArgument::Pack(TypePack {
+                        types: vec![],
+                        tail: Some(self.pack_tail()?),
+                    })
+                } else if self.check("(") {
+                    self.parenthesized_types()?
+                } else {
+                    TypeArgument::Type(self.type_annotation()?)
+                };
+
+                args.push(arg);
+
+                if !self.eat(",") {
+                    break;
+                }
+            }
+        }
+
+        self.expect(">")?;
+
+        Ok(args)
+    }
+
+    /// Parses generic parameters such as `<T, U...>`, with defaults in type aliases.
+    fn generic_params(&mut self, with_defaults: bool) -> Parsed<Vec<GenericParam>> {
+        self.expect("<")?;
+
+        let mut generics = vec![];
+
+        loop {
+            let name = self.name()?;
+            let is_pack = self.eat("...");
+
+            let default = if with_defaults && self.eat("=") {
+                Some(if !is_pack {
+                    TypeArgument::Type(self.type_annotation()?)
+                } else if self.check("(") {
+                    self.parenthesized_types()?
+                } else {
+                    TypeArgument::Pack(TypePack {
+                        types: vec![],
+                        tail: Some(self.pack_tail()?),
+                    })
+                })
+            } else {
+                None
+            };
+
+            generics.push(GenericParam {
+                name,
+                is_pack,
+                default,
+            });
+
+            if !self.eat(",") {
+                break;
+            }
+        }
+
+        self.expect(">")?;
+
+        Ok(generics)
+    }
+}
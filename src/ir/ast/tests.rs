@@ -0,0 +1,433 @@
+#![cfg(test)]
+use super::{tree::*, *};
+use crate::ir::span::Span;
+
+fn statements(source: &str, dialect: Dialect) -> Vec<Statement> {
+    parse(source, dialect).unwrap().block.statements
+}
+
+fn returned(source: &str, dialect: Dialect) -> Expression {
+    match statements(source, dialect).pop().unwrap().kind {
+        StatementKind::Return(mut values) => values.remove(0),
+        kind => panic!("expected a return, found {kind:?}"),
+    }
+}
+
+fn kinds(source: &str, dialect: Dialect) -> Vec<DiagnosticKind> {
+    parse_recovering(source, dialect)
+        .1
+        .into_iter()
+        .map(|diagnostic| diagnostic.kind)
+        .collect()
+}
+
+#[test]
+fn operators_follow_lua_precedence() {
+    // 1 + (2 * (-x ^ 2)) .. ("a" .. "b") < 3 or (not y and z)
+    let expression = returned(
+        "return 1 + 2 * -x ^ 2 .. 'a' .. 'b' < 3 or not y and z",
+        Dialect::Lua54,
+    );
+
+    let (left, right) = match expression.kind {
+        ExpressionKind::Binary {
+            operator: BinaryOperator::Or,
+            left,
+            right,
+        } => (left, right),
+        kind => panic!("expected `or` at the root, found {kind:?}"),
+    };
+
+    assert!(matches!(
+        right.kind,
+        ExpressionKind::Binary {
+            operator: BinaryOperator::And,
+            ref left,
+            ..
+        } if matches!(left.kind, ExpressionKind::Unary { operator: UnaryOperator::Not, .. })
+    ));
+
+    let concat = match left.kind {
+        ExpressionKind::Binary {
+            operator: BinaryOperator::Lt,
+            left,
+            ..
+        } => left,
+        kind => panic!("expected `<`, found {kind:?}"),
+    };
+
+    match concat.kind {
+        ExpressionKind::Binary {
+            operator: BinaryOperator::Concat,
+            left,
+            right,
+        } => {
+            assert!(matches!(
+                left.kind,
+                ExpressionKind::Binary {
+                    operator: BinaryOperator::Add,
+                    ..
+                }
+            ));
+            // concatenation is right associative
+            assert!(matches!(
+                right.kind,
+                ExpressionKind::Binary {
+                    operator: BinaryOperator::Concat,
+                    ..
+                }
+            ));
+        }
+        kind => panic!("expected `..`, found {kind:?}"),
+    }
+
+    // unary operators bind looser than `^`
+    assert!(matches!(
+        returned("return -x ^ 2", Dialect::Lua54).kind,
+        ExpressionKind::Unary {
+            operator: UnaryOperator::Neg,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn statements_carry_their_spans() {
+    let source = "local t = {1, x = 2, [3] = 4}\n\nfunction t.a.b:c(...)\n  return ...\nend\n";
+    let statements = statements(source, Dialect::Lua51);
+
+    assert_eq!(statements.len(), 2);
+    assert_eq!(statements[0].span, Span::new(1, 1));
+    assert_eq!(statements[1].span, Span::new(3, 1));
+
+    match &statements[1].kind {
+        StatementKind::Function { name, function } => {
+            let path: Vec<_> = name.path.iter().map(|name| name.name.as_str()).collect();
+            assert_eq!(path, ["t", "a", "b"]);
+            assert_eq!(name.method.as_ref().unwrap().span, Span::new(3, 16));
+            assert!(function.is_vararg);
+            assert_eq!(function.block.statements[0].span, Span::new(4, 3));
+        }
+        kind => panic!("expected a function, found {kind:?}"),
+    }
+}
+
+#[test]
+fn dialects_gate_newer_syntax() {
+    let source = "local x <const> = 1 // 2 | 3\n::top:: goto top";
+
+    assert!(parse(source, Dialect::Lua54).is_ok());
+    assert_eq!(
+        kinds(source, Dialect::Lua53),
+        [DiagnosticKind::Unsupported {
+            construct: "attributes",
+            dialect: Dialect::Lua53
+        }]
+    );
+
+    let errors = kinds(source, Dialect::Lua51);
+    assert!(errors.contains(&DiagnosticKind::Unsupported {
+        construct: "floor division",
+        dialect: Dialect::Lua51
+    }));
+    assert!(errors.contains(&DiagnosticKind::Unsupported {
+        construct: "labels",
+        dialect: Dialect::Lua51
+    }));
+
+    // without goto, `goto` is a name
+    assert!(parse("goto = 1", Dialect::Lua51).is_ok());
+    assert!(parse("goto = 1", Dialect::Lua52).is_err());
+}
+
+#[test]
+fn calls_across_lines_are_ambiguous_in_lua51_and_luau() {
+    let source = "local a = f\n(g or h)()";
+
+    assert_eq!(
+        kinds(source, Dialect::Lua51),
+        [DiagnosticKind::AmbiguousCall]
+    );
+    assert_eq!(
+        kinds(source, Dialect::Luau),
+        [DiagnosticKind::AmbiguousCall]
+    );
+    assert!(parse(source, Dialect::Lua54).is_ok());
+
+    // only the line the callee ends on counts
+    assert!(parse("local a = f [[\n]](g)\nf(\ng)", Dialect::Lua51).is_ok());
+    assert_eq!(
+        kinds("f [[\n]]\n(g)", Dialect::Lua51),
+        [DiagnosticKind::AmbiguousCall]
+    );
+}
+
+#[test]
+fn parser_recovers_after_errors() {
+    let source = "local a = \nif x = 1 then print(1) end\nprint(2 +)\nwhile true do break end\nreturn 1\nx()";
+    let (node, diagnostics) = parse_recovering(source, Dialect::Lua54);
+
+    let lines: Vec<_> = diagnostics.iter().map(|d| d.span.line).collect();
+    assert_eq!(lines, [2, 2, 3, 6]);
+    assert_eq!(diagnostics[3].kind, DiagnosticKind::ReturnNotLast);
+
+    // the loop after the errors is parsed, and its `break` is inside of it
+    assert!(node
+        .block
+        .statements
+        .iter()
+        .any(|statement| matches!(statement.kind, StatementKind::While { .. })));
+
+    let error = parse("do x = 1", Dialect::Lua54).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "invalid source code: 1:9: expected `end` to close `do` at 1:1, found the end of the input"
+    );
+}
+
+#[test]
+fn misplaced_constructs_are_reported() {
+    assert_eq!(
+        kinds(
+            "break\nlocal function f() return ... end\nf() = 1\nx",
+            Dialect::Lua54
+        ),
+        [
+            DiagnosticKind::BreakOutsideLoop,
+            DiagnosticKind::VarArgOutsideVararg,
+            DiagnosticKind::NotAssignable,
+            DiagnosticKind::NotAStatement,
+        ]
+    );
+}
+
+#[test]
+fn literals_are_decoded_per_dialect() {
+    let literal = |source: &str, dialect| returned(&format!("return {source}"), dialect).kind;
+
+    assert_eq!(literal("0x10", Dialect::Lua54), ExpressionKind::Integer(16));
+    assert_eq!(
+        literal("0x10", Dialect::Lua51),
+        ExpressionKind::Number(16.0)
+    );
+    assert_eq!(
+        literal("1e2", Dialect::Lua54),
+        ExpressionKind::Number(100.0)
+    );
+    assert_eq!(
+        literal("0x1p4", Dialect::Lua54),
+        ExpressionKind::Number(16.0)
+    );
+    assert_eq!(
+        literal(r#""a\tb\65\x41\u{48}\z   c""#, Dialect::Lua54),
+        ExpressionKind::String(b"a\tbAAHc".to_vec())
+    );
+    assert_eq!(
+        literal(r#""caf\xe9\255""#, Dialect::Lua54),
+        ExpressionKind::String(b"caf\xe9\xff".to_vec())
+    );
+    assert_eq!(
+        literal("[==[\nline ]] ]==]", Dialect::Lua51),
+        ExpressionKind::String(b"line ]] ".to_vec())
+    );
+
+    assert_eq!(
+        kinds(r#"return "\x41""#, Dialect::Lua51),
+        [DiagnosticKind::InvalidEscape("\\x".to_owned())]
+    );
+    assert_eq!(
+        kinds("return 3..2", Dialect::Lua54),
+        [DiagnosticKind::MalformedNumber("3..2".to_owned())]
+    );
+}
+
+const LUAU: &str = r#"
+export type Map<K, V = string> = { [K]: V, size: number }
+type Callback<T...> = (name: string, T...) -> ...any
+local Array: { Map<string, { number }>? } = {}
+
+@native
+local function reduce<T, U>(list: { T }, f: (U, T) -> U, initial: U): U
+    local result = initial
+    for _, value: T in list do
+        if value == nil then continue end
+        result = f(result, value)
+    end
+    return result
+end
+
+local total = 0
+total += 2
+total //= 2
+local label = if total > 1 then `{total} items of {#Array}` elseif total == 1 then "one" else "none"
+local n = (reduce :: any)({}, nil, 0b1010_1010)
+"#;
+
+#[test]
+fn luau_syntax_parses() {
+    let statements = statements(LUAU, Dialect::Luau);
+    assert_eq!(statements.len(), 9);
+
+    match &statements[0].kind {
+        StatementKind::TypeAlias {
+            is_exported,
+            generics,
+            value,
+            ..
+        } => {
+            assert!(is_exported);
+            assert!(generics[1].default.is_some());
+            assert!(matches!(&value.kind, TypeKind::Table(fields) if fields.len() == 2));
+        }
+        kind => panic!("expected a type alias, found {kind:?}"),
+    }
+
+    match &statements[3].kind {
+        StatementKind::LocalFunction { function, .. } => {
+            assert_eq!(function.attributes[0].name, "native");
+            assert_eq!(function.generics.len(), 2);
+            assert!(function
+                .params
+                .iter()
+                .all(|param| param.annotation.is_some()));
+            assert!(function.returns.is_some());
+
+            let body = match &function.block.statements[1].kind {
+                StatementKind::GenericFor { names, block, .. } => {
+                    assert!(names[1].annotation.is_some());
+                    block
+                }
+                kind => panic!("expected a loop, found {kind:?}"),
+            };
+
+            assert!(matches!(
+                &body.statements[0].kind,
+                StatementKind::If { branches, .. }
+                    if branches[0].block.statements[0].kind == StatementKind::Continue
+            ));
+        }
+        kind => panic!("expected a local function, found {kind:?}"),
+    }
+
+    assert!(matches!(
+        &statements[6].kind,
+        StatementKind::CompoundAssign {
+            operator: BinaryOperator::IDiv,
+            ..
+        }
+    ));
+
+    match &statements[7].kind {
+        StatementKind::Local { values, .. } => match &values[0].kind {
+            ExpressionKind::IfElse { branches, .. } => {
+                assert_eq!(branches.len(), 2);
+                assert!(matches!(
+                    &branches[0].value.kind,
+                    ExpressionKind::InterpolatedString { strings, expressions }
+                        if strings == &[&b""[..], b" items of ", b""] && expressions.len() == 2
+                ));
+            }
+            kind => panic!("expected an if-expression, found {kind:?}"),
+        },
+        kind => panic!("expected a local, found {kind:?}"),
+    }
+
+    for source in [
+        "local f: <T>(T) -> T = nil",
+        "type F = () -> ()",
+        "type G = | 'a' | 'b'",
+        "function f(...: number): (number, ...string) end",
+        "function f(...: T...): T... end",
+        "local x = y :: Foo<Bar<T>> + 1",
+        "local t: typeof(x) & { read: boolean } = x",
+    ] {
+        assert!(parse(source, Dialect::Luau).is_ok(), "{source}");
+    }
+
+    assert_eq!(
+        returned("return 0b1010_1010, 1_000", Dialect::Luau).kind,
+        ExpressionKind::Number(170.0)
+    );
+}
+
+#[test]
+fn luau_keywords_are_contextual() {
+    let statements = statements(
+        "local type, export = 1, 2\ncontinue = type\ncontinue()\nlocal s = `{ {`}`} }`",
+        Dialect::Luau,
+    );
+
+    assert!(matches!(statements[1].kind, StatementKind::Assign { .. }));
+    assert!(matches!(statements[2].kind, StatementKind::Call(_)));
+
+    // braces inside of an interpolation do not end it, nor do those of nested strings
+    assert!(matches!(
+        &statements[3].kind,
+        StatementKind::Local { values, .. } if matches!(
+            &values[0].kind,
+            ExpressionKind::InterpolatedString { expressions, .. }
+                if matches!(&expressions[0].kind, ExpressionKind::Table(fields) if fields.len() == 1)
+        )
+    ));
+
+    assert_eq!(
+        kinds("continue\n@inline function f() end", Dialect::Luau),
+        [
+            DiagnosticKind::ContinueOutsideLoop,
+            DiagnosticKind::UnknownAttribute("inline".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn luau_syntax_is_rejected_in_lua() {
+    assert!(parse(LUAU, Dialect::Lua54).is_err());
+    assert!(parse("x += 1", Dialect::Lua54).is_err());
+    assert!(parse("local x = `a`", Dialect::Lua54).is_err());
+
+    // `continue` is an ordinary name outside of Luau
+    assert!(parse("while true do continue() end", Dialect::Lua51).is_ok());
+    assert_eq!(
+        kinds("x = a & b", Dialect::Luau),
+        [DiagnosticKind::Unsupported {
+            construct: "bitwise operators",
+            dialect: Dialect::Luau
+        }]
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn trees_round_trip_through_json() {
+    let node = parse(
+        "local t = {f = function(a, ...) return a end}",
+        Dialect::Lua54,
+    )
+    .unwrap();
+
+    let json = serde_json::to_string(&node).unwrap();
+    assert_eq!(serde_json::from_str::<Node>(&json).unwrap(), node);
+
+    let node = parse("return `a{b}\\xff`, 1e999", Dialect::Luau).unwrap();
+    let name = serde_json::json!({ "name": "b", "span": { "line": 1, "column": 11 } });
+
+    assert_eq!(
+        serde_json::to_value(&node).unwrap(),
+        serde_json::json!({ "block": { "statements": [{
+            "kind": { "Return": [
+                {
+                    "kind": { "InterpolatedString": {
+                        "strings": ["a", [255]],
+                        "expressions": [{ "kind": { "Name": name }, "span": name["span"] }]
+                    } },
+                    "span": { "line": 1, "column": 8 }
+                },
+                { "kind": { "Number": "inf" }, "span": { "line": 1, "column": 20 } }
+            ] },
+            "span": { "line": 1, "column": 1 }
+        }] } })
+    );
+
+    let json = serde_json::to_string(&node).unwrap();
+    assert_eq!(serde_json::from_str::<Node>(&json).unwrap(), node);
+}
@@ -0,0 +1,451 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The syntax tree of Lua source code, along with the type annotations of Luau. Every
+//! statement, expression and name carries the span of the source it was parsed from, which
+//! is where it starts.
+
+use crate::ir::span::Span;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// A syntax tree, the chunk of one source file.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Node {
+    pub block: Block,
+}
+
+/// A sequence of statements, which is also a scope for the locals declared in it.
+#[derive(Clone, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Block {
+    pub statements: Vec<Statement>,
+}
+
+/// An identifier as it is written in the source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Name {
+    pub name: String,
+    pub span: Span,
+}
+
+/// A statement along with where it starts.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Span,
+}
+
+/// A local declared by a `local` statement, with the attribute of Lua 5.4 such as `const`
+/// or the type annotation of Luau.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LocalName {
+    pub name: Name,
+    pub attribute: Option<Name>,
+    pub annotation: Option<Type>,
+}
+
+/// A parameter or loop variable, with the type annotation of Luau.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TypedName {
+    pub name: Name,
+    pub annotation: Option<Type>,
+}
+
+/// The name a function statement assigns to, such as `a.b.c:d`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FunctionName {
+    /// The variable followed by the fields leading to the function.
+    pub path: Vec<Name>,
+    /// The name after `:`, which makes the function take `self` first.
+    pub method: Option<Name>,
+}
+
+/// One `if` or `elseif` condition and the block it guards.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Branch {
+    pub condition: Expression,
+    pub block: Block,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum StatementKind {
+    /// `local a, b = c, d`
+    Local {
+        names: Vec<LocalName>,
+        values: Vec<Expression>,
+    },
+    /// `a, b.c = d, e`, every target is a name, an index or a field.
+    Assign {
+        targets: Vec<Expression>,
+        values: Vec<Expression>,
+    },
+    /// `a += b` in Luau, which evaluates the target once.
+    CompoundAssign {
+        operator: BinaryOperator,
+        target: Expression,
+        value: Expression,
+    },
+    /// A call, either `f()` or `o:m()`, whose results are discarded.
+    Call(Expression),
+    /// `do ... end`
+    Do(Block),
+    /// `while condition do ... end`
+    While {
+        condition: Expression,
+        block: Block,
+    },
+    /// `repeat ... until condition`, the condition can see the locals of the block.
+    Repeat {
+        block: Block,
+        condition: Expression,
+    },
+    /// `if a then ... elseif b then ... else ... end`
+    If {
+        branches: Vec<Branch>,
+        otherwise: Option<Block>,
+    },
+    /// `for i = start, limit, step do ... end`
+    NumericFor {
+        variable: TypedName,
+        start: Expression,
+        limit: Expression,
+        step: Option<Expression>,
+        block: Block,
+    },
+    /// `for a, b in values do ... end`
+    GenericFor {
+        names: Vec<TypedName>,
+        values: Vec<Expression>,
+        block: Block,
+    },
+    /// `function a.b:c() ... end`
+    Function {
+        name: FunctionName,
+        function: FunctionBody,
+    },
+    /// `local function f() ... end`, which can refer to itself.
+    LocalFunction {
+        name: Name,
+        function: FunctionBody,
+    },
+    /// `return a, b`, which may only be the last statement of a block.
+    Return(Vec<Expression>),
+    Break,
+    /// `continue` in Luau, which skips to the next iteration of the innermost loop.
+    Continue,
+    /// `goto name`
+    Goto(Name),
+    /// `::name::`
+    Label(Name),
+    /// `export type Name<T> = type` in Luau.
+    TypeAlias {
+        is_exported: bool,
+        name: Name,
+        generics: Vec<GenericParam>,
+        value: Type,
+    },
+    /// A statement that could not be parsed, its problem is reported by the parser.
+    Error,
+}
+
+/// The parameters and body of a function, along with the attributes, generics and types
+/// of Luau.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FunctionBody {
+    /// Attributes such as `@native`, without the `@`.
+    pub attributes: Vec<Name>,
+    pub generics: Vec<GenericParam>,
+    pub params: Vec<TypedName>,
+    pub is_vararg: bool,
+    /// The type of the extra arguments, as in `...: number`.
+    pub vararg_annotation: Option<PackTail>,
+    pub returns: Option<TypePack>,
+    pub block: Block,
+    pub span: Span,
+}
+
+/// An expression along with where it starts.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+
+impl Expression {
+    /// Whether the expression can be assigned to.
+    pub fn is_assignable(&self) -> bool {
+        matches!(
+            self.kind,
+            ExpressionKind::Name(_) | ExpressionKind::Index { .. } | ExpressionKind::Field { .. }
+        )
+    }
+
+    /// Whether the expression is a call, which may produce any number of values.
+    pub fn is_call(&self) -> bool {
+        matches!(
+            self.kind,
+            ExpressionKind::Call { .. } | ExpressionKind::MethodCall { .. }
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum BinaryOperator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    IDiv,
+    Mod,
+    Pow,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    BitAnd,
+    BitOr,
+    BitXor,
+    LeftShift,
+    RightShift,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum UnaryOperator {
+    Neg,
+    Not,
+    Len,
+    BitNot,
+}
+
+/// One `if` or `elseif` condition of an if-expression and the value it guards.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ExpressionBranch {
+    pub condition: Expression,
+    pub value: Expression,
+}
+
+/// An entry of a table constructor.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Field {
+    /// `name = value`
+    Named { name: Name, value: Expression },
+    /// `[key] = value`
+    Keyed { key: Expression, value: Expression },
+    /// `value`, stored at the next array index.
+    Positional(Expression),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ExpressionKind {
+    Nil,
+    Boolean(bool),
+    /// A number literal with a fraction or an exponent, or any number in dialects without
+    /// integers.
+    #[cfg_attr(feature = "serde", serde(with = "crate::ir::il::schema::number"))]
+    Number(f64),
+    /// A number literal without a fraction or an exponent in dialects with integers.
+    Integer(i64),
+    /// A string literal with its escapes resolved, which holds arbitrary bytes and is not
+    /// necessarily valid UTF-8.
+    #[cfg_attr(feature = "serde", serde(with = "crate::ir::il::schema::bytes"))]
+    String(Vec<u8>),
+    /// `...`
+    VarArg,
+    /// `function() ... end`
+    Function(Box<FunctionBody>),
+    /// `{a, b = c, [d] = e}`
+    Table(Vec<Field>),
+    /// A variable, either local, upvalue or global.
+    Name(Name),
+    /// `object[key]`
+    Index {
+        object: Box<Expression>,
+        key: Box<Expression>,
+    },
+    /// `object.name`
+    Field {
+        object: Box<Expression>,
+        name: Name,
+    },
+    /// `callee(args)`, also `callee "string"` and `callee {table}`.
+    Call {
+        callee: Box<Expression>,
+        args: Vec<Expression>,
+    },
+    /// `object:method(args)`
+    MethodCall {
+        object: Box<Expression>,
+        method: Name,
+        args: Vec<Expression>,
+    },
+    /// `(expression)`, which truncates the values of a call or vararg to one.
+    Paren(Box<Expression>),
+    /// `if a then b elseif c then d else e` in Luau.
+    IfElse {
+        branches: Vec<ExpressionBranch>,
+        otherwise: Box<Expression>,
+    },
+    /// `` `a{b}c` `` in Luau, the strings surround the expressions so there is one more of
+    /// them.
+    InterpolatedString {
+        #[cfg_attr(feature = "serde", serde(with = "crate::ir::il::schema::byte_strings"))]
+        strings: Vec<Vec<u8>>,
+        expressions: Vec<Expression>,
+    },
+    /// `expression :: type` in Luau.
+    TypeAssertion {
+        expression: Box<Expression>,
+        annotation: Box<Type>,
+    },
+    Binary {
+        operator: BinaryOperator,
+        left: Box<Expression>,
+        right: Box<Expression>,
+    },
+    Unary {
+        operator: UnaryOperator,
+        operand: Box<Expression>,
+    },
+    /// An expression that could not be parsed, its problem is reported by the parser.
+    Error,
+}
+
+/// A Luau type annotation along with where it starts.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Type {
+    pub kind: TypeKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TypeKind {
+    /// `name` or `module.name`, with type arguments as in `name<A, B...>`.
+    Reference {
+        module: Option<Name>,
+        name: Name,
+        args: Vec<TypeArgument>,
+    },
+    /// `typeof(expression)`
+    Typeof(Box<Expression>),
+    Nil,
+    /// `true` or `false`, the type of that value only.
+    Boolean(bool),
+    /// A string literal, the type of that value only.
+    #[cfg_attr(feature = "serde", serde(with = "crate::ir::il::schema::bytes"))]
+    String(Vec<u8>),
+    /// `{name: T, [K]: V}`
+    Table(Vec<TableTypeField>),
+    /// `{T}`, short for `{[number]: T}`.
+    Array(Box<Type>),
+    /// `<T>(a: A, B, ...C) -> R`
+    Function {
+        generics: Vec<GenericParam>,
+        params: Vec<FunctionTypeParam>,
+        vararg: Option<PackTail>,
+        returns: TypePack,
+    },
+    /// `A | B`
+    Union(Vec<Type>),
+    /// `A & B`
+    Intersection(Vec<Type>),
+    /// `T?`, short for `T | nil`.
+    Optional(Box<Type>),
+    /// `(T)`
+    Paren(Box<Type>),
+}
+
+/// An entry of a table type.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TableTypeField {
+    /// `name: T`
+    Property { name: Name, value: Type },
+    /// `[K]: V`
+    Indexer { key: Type, value: Type },
+}
+
+/// A parameter of a function type, whose name is only documentation.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FunctionTypeParam {
+    pub name: Option<Name>,
+    pub annotation: Type,
+}
+
+/// The types of a list of values, such as the results of a function.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TypePack {
+    pub types: Vec<Type>,
+    pub tail: Option<PackTail>,
+}
+
+/// The end of a type pack, which stands for any number of values.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum PackTail {
+    /// `...T`, values of type `T`.
+    Variadic(Box<Type>),
+    /// `T...`, a generic type pack.
+    Generic(Name),
+}
+
+/// An argument of a generic type.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TypeArgument {
+    Type(Type),
+    /// `(A, B)`, `...T` or `T...`, for generic type packs.
+    Pack(TypePack),
+}
+
+/// A generic parameter, `T` or the type pack `T...`. Only type aliases give defaults.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct GenericParam {
+    pub name: Name,
+    pub is_pack: bool,
+    pub default: Option<TypeArgument>,
+}
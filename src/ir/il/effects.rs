@@ -0,0 +1,345 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! What each instruction reads, writes and may cause besides, so that analyses share one
+//! description of the instruction set instead of each matching over it.
+
+use super::*;
+use std::ops::{BitAnd, BitOr, BitOrAssign};
+use tinyvec::TinyVec;
+
+/// The stack slots an instruction reads or writes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Slots {
+    /// The individual stack indices, in the order of the operands they belong to.
+    pub fixed: TinyVec<[usize; 4]>,
+    /// The first of the slots up to the top of the stack, for instructions which read or
+    /// write a variable number of values.
+    pub variable: Option<usize>,
+}
+
+impl Slots {
+    fn new(fixed: impl IntoIterator<Item = usize>) -> Self {
+        Self {
+            fixed: fixed.into_iter().collect(),
+            variable: None,
+        }
+    }
+
+    /// The slots from `start` up to but excluding `start + count`, or up to the top when
+    /// `count` is variable.
+    fn window(start: usize, count: &OptVariable) -> Self {
+        match count {
+            OptVariable::Number(count) => Self::new(start..start + count),
+            OptVariable::Variable => Self {
+                fixed: TinyVec::new(),
+                variable: Some(start),
+            },
+        }
+    }
+
+    /// Adds the slot `value` refers to, if it refers to one.
+    fn with_value(mut self, value: &Value) -> Self {
+        if let Value::StackIndex(index) = value {
+            self.fixed.push(*index);
+        }
+
+        self
+    }
+
+    /// Whether the stack index `index` is one of the slots.
+    pub fn contains(&self, index: usize) -> bool {
+        self.fixed.contains(&index) || self.variable.map_or(false, |start| index >= start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fixed.is_empty() && self.variable.is_none()
+    }
+
+    /// One past the highest stack index that must exist for the slots to be accessed. A
+    /// variable number of values needs at least the slot it starts at.
+    pub fn end(&self) -> usize {
+        let fixed = self.fixed.iter().map(|index| index + 1).max();
+        let variable = self.variable.map(|start| start + 1);
+
+        fixed.max(variable).unwrap_or_default()
+    }
+}
+
+/// A set of side effects an instruction may have besides writing its stack slots.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Effects(u16);
+
+impl Effects {
+    /// Calls a function value directly, which may run any code and so have any effect.
+    pub const CALL: Self = Self(1 << 0);
+    /// May invoke a metamethod of an operand, which may run any code and so have any effect.
+    pub const METAMETHOD: Self = Self(1 << 1);
+    /// May raise an error, such as when performing arithmetic on a table.
+    pub const THROW: Self = Self(1 << 2);
+    /// Reads the globals table.
+    pub const READ_GLOBAL: Self = Self(1 << 3);
+    /// Writes the globals table.
+    pub const WRITE_GLOBAL: Self = Self(1 << 4);
+    /// Reads a field of a table.
+    pub const READ_TABLE: Self = Self(1 << 5);
+    /// Writes a field of a table.
+    pub const WRITE_TABLE: Self = Self(1 << 6);
+    /// Reads an upvalue of the running closure.
+    pub const READ_UPVALUE: Self = Self(1 << 7);
+    /// Writes an upvalue of the running closure.
+    pub const WRITE_UPVALUE: Self = Self(1 << 8);
+    /// Creates a new table, closure or string.
+    pub const ALLOCATE: Self = Self(1 << 9);
+    /// Closes the upvalues captured from stack slots.
+    pub const CLOSE: Self = Self(1 << 10);
+
+    const NAMES: [(Self, &'static str); 11] = [
+        (Self::CALL, "CALL"),
+        (Self::METAMETHOD, "METAMETHOD"),
+        (Self::THROW, "THROW"),
+        (Self::READ_GLOBAL, "READ_GLOBAL"),
+        (Self::WRITE_GLOBAL, "WRITE_GLOBAL"),
+        (Self::READ_TABLE, "READ_TABLE"),
+        (Self::WRITE_TABLE, "WRITE_TABLE"),
+        (Self::READ_UPVALUE, "READ_UPVALUE"),
+        (Self::WRITE_UPVALUE, "WRITE_UPVALUE"),
+        (Self::ALLOCATE, "ALLOCATE"),
+        (Self::CLOSE, "CLOSE"),
+    ];
+
+    /// The set without any effects.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether every effect of `other` is in this set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether any effect of `other` is in this set.
+    pub const fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// Whether the instruction may run code of the program, after which nothing can be
+    /// assumed about tables, globals or upvalues.
+    pub const fn runs_code(self) -> bool {
+        self.intersects(Self(Self::CALL.0 | Self::METAMETHOD.0))
+    }
+}
+
+impl BitOr for Effects {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Effects {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl BitAnd for Effects {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl Debug for Effects {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "(empty)");
+        }
+
+        let names = Self::NAMES
+            .iter()
+            .filter(|(effect, _)| self.contains(*effect))
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>();
+
+        write!(f, "{}", names.join(" | "))
+    }
+}
+
+impl Instruction {
+    /// The stack slots this instruction writes. Closures that capture a slot are not
+    /// described by the instruction, but by the upvalues of the prototype it instantiates.
+    pub fn defs(&self) -> Slots {
+        match self {
+            Self::Load(load) => Slots::new([load.dest]),
+            Self::Move(mv) => Slots::new([mv.dest]),
+            Self::Intrinsic(intrinsic) => Slots::new([intrinsic.dest]),
+            Self::GetGlobal(get) => Slots::new([get.dest]),
+            Self::GetTable(get) => Slots::new([get.dest]),
+            Self::SelfLookup(lookup) => Slots::new([lookup.dest, lookup.dest + 1]),
+            Self::BinaryOp(op) => Slots::new([op.dest]),
+            Self::UnaryOp(op) => Slots::new([op.dest]),
+            Self::Concat(concat) => Slots::new([concat.dest]),
+            Self::ForNumPrep(prep) => Slots::new([prep.index, prep.limit, prep.step, prep.var]),
+            Self::ForNumLoop(next) => Slots::new([next.index, next.var]),
+            Self::ForGenCall(call) => Slots::new(call.dest..call.dest + call.count),
+            Self::ForGenLoop(next) => Slots::new([next.base + 2]),
+            Self::NewTable(table) => Slots::new([table.dest]),
+            Self::Call(call) => Slots::window(call.callee, &call.num_returns),
+            Self::VarArg(vararg) => Slots::window(vararg.dest, &vararg.count),
+            Self::Closure(closure) => Slots::new([closure.dest]),
+            Self::GetUpvalue(get) => Slots::new([get.dest]),
+            Self::Label(_)
+            | Self::SetGlobal(_)
+            | Self::SetTable(_)
+            | Self::Jump(_)
+            | Self::JumpNot(_)
+            | Self::ConditionalJump(_)
+            | Self::ForGenPrep(_)
+            | Self::SetList(_)
+            | Self::Return(_)
+            | Self::SetUpvalue(_)
+            | Self::Close(_) => Slots::default(),
+        }
+    }
+
+    /// The stack slots this instruction reads.
+    pub fn uses(&self) -> Slots {
+        let none = Slots::default();
+
+        match self {
+            Self::Load(load) => none.with_value(&load.src),
+            Self::Move(mv) => Slots::new([mv.src]),
+            Self::Intrinsic(intrinsic) => intrinsic
+                .kind
+                .operands()
+                .into_iter()
+                .fold(none, Slots::with_value),
+            Self::SetGlobal(set) => Slots::new([set.src]),
+            Self::GetTable(get) => Slots::new([get.source]).with_value(&get.key),
+            Self::SetTable(set) => Slots::new([set.table])
+                .with_value(&set.key)
+                .with_value(&set.value),
+            Self::SelfLookup(lookup) => Slots::new([lookup.object]).with_value(&lookup.key),
+            Self::BinaryOp(op) => none.with_value(&op.left).with_value(&op.right),
+            Self::UnaryOp(op) => none.with_value(&op.left),
+            Self::Concat(concat) => Slots::new(concat.start..concat.end + 1),
+            Self::JumpNot(jump) => Slots::new([jump.cond]),
+            Self::ConditionalJump(jump) => none
+                .with_value(&jump.condition.left)
+                .with_value(&jump.condition.right),
+            Self::ForNumPrep(prep) => Slots::new([prep.index, prep.limit, prep.step]),
+            Self::ForNumLoop(next) => Slots::new([next.index, next.limit, next.step]),
+            Self::ForGenPrep(prep) => Slots::new(prep.base..prep.base + 3),
+            Self::ForGenCall(call) => Slots::new(call.base..call.base + 3),
+            Self::ForGenLoop(next) => Slots::new([next.var]),
+            Self::SetList(list) => {
+                let mut slots = Slots::window(list.start, &list.count);
+                slots.fixed.insert(0, list.table);
+
+                slots
+            }
+            Self::Return(ret) => Slots::window(ret.result_start, &ret.result_count),
+            Self::Call(call) => {
+                let arguments = match call.num_args {
+                    OptVariable::Number(count) => {
+                        OptVariable::Number(count + call.self_call as usize)
+                    }
+                    OptVariable::Variable => OptVariable::Variable,
+                };
+
+                let mut slots = Slots::window(call.callee + 1, &arguments);
+                slots.fixed.insert(0, call.callee);
+
+                slots
+            }
+            Self::SetUpvalue(set) => Slots::new([set.src]),
+            Self::Label(_)
+            | Self::GetGlobal(_)
+            | Self::Jump(_)
+            | Self::NewTable(_)
+            | Self::VarArg(_)
+            | Self::Closure(_)
+            | Self::GetUpvalue(_)
+            | Self::Close(_) => none,
+        }
+    }
+
+    /// The side effects this instruction may have besides writing the slots of `defs`.
+    pub fn effects(&self) -> Effects {
+        // operations that a metamethod may take over can fail when there is none
+        let overloadable = Effects::METAMETHOD | Effects::THROW;
+
+        match self {
+            Self::Intrinsic(intrinsic) => match intrinsic.source {
+                IntrinsicSource::Operator => overloadable,
+                IntrinsicSource::Bit32 | IntrinsicSource::Bit => {
+                    Effects::READ_GLOBAL | Effects::READ_TABLE | Effects::CALL | Effects::THROW
+                }
+            },
+            Self::GetGlobal(_) => Effects::READ_GLOBAL | overloadable,
+            Self::SetGlobal(_) => Effects::WRITE_GLOBAL | overloadable,
+            Self::GetTable(_) | Self::SelfLookup(_) => Effects::READ_TABLE | overloadable,
+            Self::SetTable(_) => Effects::WRITE_TABLE | overloadable,
+            Self::BinaryOp(op) if op.operator == BinaryOpKind::Concat => {
+                Effects::ALLOCATE | overloadable
+            }
+            Self::BinaryOp(_) => overloadable,
+            Self::UnaryOp(op) if op.operator == UnaryOpKind::Not => Effects::empty(),
+            Self::UnaryOp(_) => overloadable,
+            Self::Concat(_) => Effects::ALLOCATE | overloadable,
+            Self::ConditionalJump(jump) => match jump.condition.kind {
+                ConditionKind::And | ConditionKind::Or => Effects::empty(),
+                _ => overloadable,
+            },
+            Self::ForNumPrep(_) => Effects::THROW,
+            Self::ForGenCall(_) => Effects::CALL | Effects::THROW,
+            Self::NewTable(_) | Self::Closure(_) => Effects::ALLOCATE,
+            Self::SetList(_) => Effects::WRITE_TABLE,
+            Self::Call(_) => Effects::CALL | overloadable,
+            Self::GetUpvalue(_) => Effects::READ_UPVALUE,
+            Self::SetUpvalue(_) => Effects::WRITE_UPVALUE,
+            Self::Close(_) => Effects::CLOSE,
+            Self::Label(_)
+            | Self::Load(_)
+            | Self::Move(_)
+            | Self::Jump(_)
+            | Self::JumpNot(_)
+            | Self::ForNumLoop(_)
+            | Self::ForGenPrep(_)
+            | Self::ForGenLoop(_)
+            | Self::Return(_)
+            | Self::VarArg(_) => Effects::empty(),
+        }
+    }
+}
+
+/// Returns one past the highest register an instruction refers to.
+pub(crate) fn registers_used(instruction: &Instruction) -> usize {
+    instruction.defs().end().max(instruction.uses().end())
+}
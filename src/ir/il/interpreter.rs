@@ -0,0 +1,1806 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! A reference interpreter for the intermediate language, so that the behaviour of lifted,
+//! transformed or recompiled code can be compared with the original without a Lua VM.
+//!
+//! Values follow a small model of Lua: tables with metatables, closures with shared
+//! upvalues, native functions, varargs and multiple results. The globals table is supplied
+//! by the caller, and `with_base_library` fills it with the basic functions that most code
+//! relies on. Numbers either follow Lua 5.1, where every number is a float, or Lua 5.3,
+//! which adds the integer subtype along with its arithmetic rules.
+
+use super::*;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{Debug, Display},
+    hash::{Hash, Hasher},
+    rc::Rc,
+};
+
+/// The rules numbers follow while interpreting.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Semantics {
+    /// Every number is a float, as in Lua 5.1 and Luau.
+    #[default]
+    Lua51,
+    /// Numbers are either integers or floats, as in Lua 5.3 and later.
+    Lua53,
+}
+
+/// A table shared between every value referring to it.
+pub type TableRef = Rc<RefCell<LuaTable>>;
+
+/// A function implemented in Rust, which receives its arguments and returns its results.
+pub type NativeFunction = dyn Fn(&mut Interpreter, Vec<LuaValue>) -> Result<Vec<LuaValue>>;
+
+/// A value of the interpreted program.
+#[derive(Clone, Default)]
+pub enum LuaValue {
+    #[default]
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    /// A string, which holds arbitrary bytes like strings in Lua.
+    String(Rc<[u8]>),
+    Table(TableRef),
+    Function(LuaFunction),
+}
+
+/// A callable value, either a closure of an IL function or a native function.
+#[derive(Clone)]
+pub enum LuaFunction {
+    Closure(Rc<LuaClosure>),
+    Native(Rc<NativeFunction>),
+}
+
+/// An instance of an IL function along with the upvalues it captured.
+pub struct LuaClosure {
+    prototype: Rc<Prototype>,
+    upvalues: Vec<Rc<RefCell<LuaValue>>>,
+}
+
+impl LuaValue {
+    /// Creates a string value.
+    pub fn string(s: impl AsRef<[u8]>) -> Self {
+        Self::String(s.as_ref().into())
+    }
+
+    /// Creates a new empty table.
+    pub fn table() -> Self {
+        Self::Table(Rc::new(RefCell::new(LuaTable::default())))
+    }
+
+    /// Wraps `f` into a function value.
+    pub fn native(
+        f: impl Fn(&mut Interpreter, Vec<LuaValue>) -> Result<Vec<LuaValue>> + 'static,
+    ) -> Self {
+        Self::Function(LuaFunction::Native(Rc::new(f)))
+    }
+
+    /// The name of the type of this value, as returned by `type`.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Nil => "nil",
+            Self::Boolean(_) => "boolean",
+            Self::Integer(_) | Self::Number(_) => "number",
+            Self::String(_) => "string",
+            Self::Table(_) => "table",
+            Self::Function(_) => "function",
+        }
+    }
+
+    /// Whether this value is neither `nil` nor `false`.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Self::Nil | Self::Boolean(false))
+    }
+
+    fn is_nil(&self) -> bool {
+        matches!(self, Self::Nil)
+    }
+
+    /// The numeric value of this number, converting integers to floats.
+    fn as_float(&self) -> Option<f64> {
+        match self {
+            Self::Integer(n) => Some(*n as f64),
+            Self::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// The address of the object a reference value points to.
+    fn address(&self) -> Option<usize> {
+        match self {
+            Self::Table(table) => Some(Rc::as_ptr(table) as *const u8 as usize),
+            Self::Function(LuaFunction::Closure(closure)) => {
+                Some(Rc::as_ptr(closure) as *const u8 as usize)
+            }
+            Self::Function(LuaFunction::Native(native)) => {
+                Some(Rc::as_ptr(native) as *const u8 as usize)
+            }
+            _ => None,
+        }
+    }
+
+    fn metatable(&self) -> Option<TableRef> {
+        match self {
+            Self::Table(table) => table.borrow().metatable.clone(),
+            _ => None,
+        }
+    }
+}
+
+/// Values compare like `rawequal`, so integers equal the floats with exactly the same value
+/// and tables and functions are equal only to themselves.
+impl PartialEq for LuaValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Nil, Self::Nil) => true,
+            (Self::Boolean(a), Self::Boolean(b)) => a == b,
+            (Self::Integer(a), Self::Integer(b)) => a == b,
+            (Self::Integer(_) | Self::Number(_), Self::Integer(_) | Self::Number(_)) => {
+                compare_numbers(self, other) == Some(std::cmp::Ordering::Equal)
+            }
+            (Self::String(a), Self::String(b)) => a == b,
+            _ => match (self.address(), other.address()) {
+                (Some(a), Some(b)) => a == b,
+                _ => false,
+            },
+        }
+    }
+}
+
+impl Debug for LuaValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::String(s) => write!(f, "{:?}", String::from_utf8_lossy(s)),
+            _ => write!(f, "{self}"),
+        }
+    }
+}
+
+/// Values display like `tostring` without metamethods.
+impl Display for LuaValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Nil => write!(f, "nil"),
+            Self::Boolean(b) => write!(f, "{b}"),
+            Self::Integer(n) => write!(f, "{n}"),
+            Self::Number(n) => write!(f, "{}", format_number(*n)),
+            Self::String(s) => write!(f, "{}", String::from_utf8_lossy(s)),
+            _ => write!(
+                f,
+                "{}: {:#x}",
+                self.type_name(),
+                self.address().unwrap_or_default()
+            ),
+        }
+    }
+}
+
+/// Formats a float like the `%.14g` format of the reference implementation.
+fn format_number(n: f64) -> String {
+    if n.is_nan() {
+        return if n.is_sign_negative() { "-nan" } else { "nan" }.to_owned();
+    }
+
+    if n.is_infinite() {
+        return if n < 0.0 { "-inf" } else { "inf" }.to_owned();
+    }
+
+    if n == 0.0 {
+        return if n.is_sign_negative() { "-0" } else { "0" }.to_owned();
+    }
+
+    let scientific = format!("{n:.13e}");
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent = exponent.parse::<i32>().unwrap();
+
+    let trim = |digits: &str| {
+        if digits.contains('.') {
+            digits
+                .trim_end_matches('0')
+                .trim_end_matches('.')
+                .to_owned()
+        } else {
+            digits.to_owned()
+        }
+    };
+
+    if !(-4..14).contains(&exponent) {
+        let sign = if exponent < 0 { '-' } else { '+' };
+
+        format!("{}e{sign}{:02}", trim(mantissa), exponent.abs())
+    } else {
+        trim(&format!("{n:.*}", (13 - exponent) as usize))
+    }
+}
+
+/// A key of a table, which hashes and compares like the value it holds. Floats with an
+/// integer value are stored as integers, so that `t[1]` and `t[1.0]` are the same entry.
+#[derive(Clone, Debug)]
+struct Key(LuaValue);
+
+impl Key {
+    fn new(value: &LuaValue) -> Self {
+        match *value {
+            LuaValue::Number(n) => match float_to_integer(n) {
+                Some(n) => Self(LuaValue::Integer(n)),
+                None => Self(value.clone()),
+            },
+            _ => Self(value.clone()),
+        }
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            // NaN is never a key, so floats can compare by their bits
+            (LuaValue::Number(a), LuaValue::Number(b)) => a.to_bits() == b.to_bits(),
+            (a, b) => a == b,
+        }
+    }
+}
+
+impl Eq for Key {}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(&self.0).hash(state);
+
+        match &self.0 {
+            LuaValue::Nil => {}
+            LuaValue::Boolean(b) => b.hash(state),
+            LuaValue::Integer(n) => n.hash(state),
+            LuaValue::Number(n) => n.to_bits().hash(state),
+            LuaValue::String(s) => s.hash(state),
+            value => value.address().hash(state),
+        }
+    }
+}
+
+/// Returns the integer with the same value as `n`, if there is one.
+fn float_to_integer(n: f64) -> Option<i64> {
+    // 2^63 is exactly representable, unlike `i64::MAX`
+    if n.fract() == 0.0 && (-9_223_372_036_854_775_808.0..9_223_372_036_854_775_808.0).contains(&n)
+    {
+        Some(n as i64)
+    } else {
+        None
+    }
+}
+
+/// A table of the interpreted program. Entries keep the order they were inserted in, and
+/// assigning `nil` to an entry keeps its slot so that traversals with `next` may clear
+/// fields as they go.
+#[derive(Default)]
+pub struct LuaTable {
+    entries: IndexMap<Key, LuaValue>,
+    metatable: Option<TableRef>,
+}
+
+impl LuaTable {
+    /// Reads the entry at `key` without invoking metamethods.
+    pub fn get(&self, key: &LuaValue) -> LuaValue {
+        self.entries
+            .get(&Key::new(key))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Writes the entry at `key` without invoking metamethods.
+    pub fn set(&mut self, key: LuaValue, value: LuaValue) -> Result<()> {
+        match key {
+            LuaValue::Nil => bail!("table index is nil"),
+            LuaValue::Number(n) if n.is_nan() => bail!("table index is NaN"),
+            _ => {}
+        }
+
+        let key = Key::new(&key);
+
+        if value.is_nil() {
+            if let Some(slot) = self.entries.get_mut(&key) {
+                *slot = LuaValue::Nil;
+            }
+        } else {
+            self.entries.insert(key, value);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the entry following `key` in a traversal of the table, starting with the first
+    /// entry when `key` is `nil`.
+    pub fn next(&self, key: &LuaValue) -> Result<Option<(LuaValue, LuaValue)>> {
+        let start = if key.is_nil() {
+            0
+        } else {
+            self.entries
+                .get_index_of(&Key::new(key))
+                .context("invalid key to 'next'")?
+                + 1
+        };
+
+        Ok(self
+            .entries
+            .iter()
+            .skip(start)
+            .find(|(_, value)| !value.is_nil())
+            .map(|(key, value)| (key.0.clone(), value.clone())))
+    }
+
+    /// The length of the sequence stored from index 1, a border of the table.
+    pub fn length(&self) -> i64 {
+        let mut length = 0;
+
+        while !self.get(&LuaValue::Integer(length + 1)).is_nil() {
+            length += 1;
+        }
+
+        length
+    }
+
+    pub fn metatable(&self) -> Option<TableRef> {
+        self.metatable.clone()
+    }
+
+    pub fn set_metatable(&mut self, metatable: Option<TableRef>) {
+        self.metatable = metatable;
+    }
+}
+
+/// An IL function prepared for interpretation, with its labels resolved.
+struct Prototype {
+    chunk: Vec<Instruction>,
+    constants: Vec<Constant>,
+    upvalues: Vec<Upvalue>,
+    arity: Arity,
+    max_stack_size: usize,
+    labels: HashMap<Label, usize>,
+    children: Vec<Rc<Prototype>>,
+}
+
+impl Prototype {
+    fn new(function: &Function, chunk: &IlChunk) -> Result<Rc<Self>> {
+        let children = function
+            .prototypes
+            .iter()
+            .enumerate()
+            .map(|(index, child)| {
+                Self::new(child, &child.chunk)
+                    .with_context(|| format!("failed to prepare prototype {index}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Rc::new(Self {
+            chunk: chunk.inner().to_vec(),
+            constants: function.constants.clone(),
+            upvalues: function.upvalues.clone(),
+            arity: function.arity,
+            max_stack_size: function.max_stack_size as usize,
+            labels: resolve_labels(chunk.inner())?,
+            children,
+        }))
+    }
+}
+
+/// The registers and extra arguments of a running function.
+struct Frame {
+    registers: Vec<LuaValue>,
+    /// The upvalues that closures captured from each register while it is open.
+    captured: Vec<Option<Rc<RefCell<LuaValue>>>>,
+    varargs: Vec<LuaValue>,
+    /// One past the last value produced by an instruction with a variable number of results.
+    top: usize,
+}
+
+impl Frame {
+    fn get(&self, index: usize) -> LuaValue {
+        match self.captured.get(index) {
+            Some(Some(cell)) => cell.borrow().clone(),
+            _ => self.registers.get(index).cloned().unwrap_or_default(),
+        }
+    }
+
+    fn set(&mut self, index: usize, value: LuaValue) {
+        if index >= self.registers.len() {
+            self.registers.resize(index + 1, LuaValue::Nil);
+            self.captured.resize(index + 1, None);
+        }
+
+        match &self.captured[index] {
+            Some(cell) => *cell.borrow_mut() = value,
+            None => self.registers[index] = value,
+        }
+    }
+
+    /// Stores `values` from register `start`, filling `count` registers if it is known.
+    fn set_results(&mut self, start: usize, values: Vec<LuaValue>, count: &OptVariable) {
+        match count {
+            OptVariable::Number(count) => {
+                let mut values = values.into_iter();
+
+                for index in start..start + count {
+                    self.set(index, values.next().unwrap_or_default());
+                }
+            }
+            OptVariable::Variable => {
+                self.top = start + values.len();
+
+                for (index, value) in (start..).zip(values) {
+                    self.set(index, value);
+                }
+            }
+        }
+    }
+
+    /// The values from register `start` up to `count` or the top.
+    fn values(&self, start: usize, count: &OptVariable) -> Vec<LuaValue> {
+        let end = match count {
+            OptVariable::Number(count) => start + count,
+            OptVariable::Variable => self.top.max(start),
+        };
+
+        (start..end).map(|index| self.get(index)).collect()
+    }
+
+    fn capture(&mut self, index: usize) -> Rc<RefCell<LuaValue>> {
+        if index >= self.registers.len() {
+            self.set(index, LuaValue::Nil);
+        }
+
+        let value = self.registers[index].clone();
+
+        self.captured[index]
+            .get_or_insert_with(|| Rc::new(RefCell::new(value)))
+            .clone()
+    }
+
+    /// Closes the upvalues captured from register `start` and above.
+    fn close(&mut self, start: usize) {
+        for index in start..self.captured.len() {
+            if let Some(cell) = self.captured[index].take() {
+                self.registers[index] = cell.borrow().clone();
+            }
+        }
+    }
+}
+
+/// What happens after an instruction has run.
+enum Flow {
+    Next,
+    Jump(Label),
+    Return(Vec<LuaValue>),
+}
+
+/// Runs IL functions, keeping the globals and limits shared by every function it runs.
+pub struct Interpreter {
+    semantics: Semantics,
+    globals: TableRef,
+    max_steps: Option<u64>,
+    max_depth: usize,
+    steps: u64,
+    depth: usize,
+    output: Vec<String>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    /// Creates an interpreter with Lua 5.1 semantics, an empty globals table and no step
+    /// limit.
+    pub fn new() -> Self {
+        Self {
+            semantics: Semantics::default(),
+            globals: Rc::new(RefCell::new(LuaTable::default())),
+            max_steps: None,
+            max_depth: 200,
+            steps: 0,
+            depth: 0,
+            output: Vec::new(),
+        }
+    }
+
+    /// Sets the rules numbers follow.
+    pub fn semantics(mut self, semantics: Semantics) -> Self {
+        self.semantics = semantics;
+
+        self
+    }
+
+    /// Replaces the globals table.
+    pub fn globals(mut self, globals: TableRef) -> Self {
+        self.globals = globals;
+
+        self
+    }
+
+    /// Sets the number of instructions after which interpretation fails, which stops code
+    /// that never terminates.
+    pub fn max_steps(mut self, max_steps: Option<u64>) -> Self {
+        self.max_steps = max_steps;
+
+        self
+    }
+
+    /// Sets the number of nested calls after which interpretation fails.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+
+        self
+    }
+
+    /// Adds the basic functions of Lua to the globals table, where `print` appends a line to
+    /// the output of the interpreter instead of writing to the standard output.
+    pub fn with_base_library(self) -> Self {
+        base_library(&mut self.globals.borrow_mut());
+
+        self
+    }
+
+    pub fn global_table(&self) -> &TableRef {
+        &self.globals
+    }
+
+    /// The number of instructions run so far.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// The lines printed so far, with any bytes that are not valid UTF-8 replaced.
+    pub fn output(&self) -> &[String] {
+        &self.output
+    }
+
+    /// Runs `function` as a main chunk with the arguments `args`, returning its results.
+    pub fn run(&mut self, function: &Function, args: Vec<LuaValue>) -> Result<Vec<LuaValue>> {
+        self.run_chunk(&function.chunk, function, args)
+    }
+
+    /// Runs `chunk` in place of the code of `function`, whose constants, nested functions and
+    /// arity it uses. Any upvalues of the main chunk start out as `nil`.
+    pub fn run_chunk(
+        &mut self,
+        chunk: &IlChunk,
+        function: &Function,
+        args: Vec<LuaValue>,
+    ) -> Result<Vec<LuaValue>> {
+        let closure = LuaClosure {
+            prototype: Prototype::new(function, chunk)?,
+            upvalues: function
+                .upvalues
+                .iter()
+                .map(|_| Rc::new(RefCell::new(LuaValue::Nil)))
+                .collect(),
+        };
+
+        self.call(
+            &LuaValue::Function(LuaFunction::Closure(Rc::new(closure))),
+            args,
+        )
+    }
+
+    /// Calls `callee` with `args`, invoking its `__call` metamethod if it is not a function.
+    pub fn call(&mut self, callee: &LuaValue, mut args: Vec<LuaValue>) -> Result<Vec<LuaValue>> {
+        let function = match callee {
+            LuaValue::Function(function) => function.clone(),
+            _ => match self.metamethod(callee, "__call") {
+                Some(handler) => {
+                    args.insert(0, callee.clone());
+
+                    return self.call(&handler, args);
+                }
+                None => bail!("attempt to call a {} value", callee.type_name()),
+            },
+        };
+
+        ensure!(self.depth < self.max_depth, "stack overflow");
+
+        self.depth += 1;
+        let results = match function {
+            LuaFunction::Closure(closure) => self.execute(&closure, args),
+            LuaFunction::Native(native) => native(self, args),
+        };
+        self.depth -= 1;
+
+        results
+    }
+
+    fn execute(&mut self, closure: &LuaClosure, args: Vec<LuaValue>) -> Result<Vec<LuaValue>> {
+        let prototype = &closure.prototype;
+        let params = prototype.arity.params as usize;
+
+        let mut frame = Frame {
+            registers: vec![LuaValue::Nil; prototype.max_stack_size],
+            captured: vec![None; prototype.max_stack_size],
+            varargs: vec![],
+            top: 0,
+        };
+
+        let mut args = args.into_iter();
+        for index in 0..params {
+            frame.set(index, args.next().unwrap_or_default());
+        }
+
+        if prototype.arity.is_vararg {
+            frame.varargs = args.collect();
+
+            // Lua 5.0 compatible functions find their extra arguments in the `arg` table
+            if prototype.arity.needs_arg {
+                let mut arg = LuaTable::default();
+
+                for (index, value) in frame.varargs.iter().enumerate() {
+                    arg.set(self.integer(index as i64 + 1), value.clone())?;
+                }
+                arg.set(
+                    LuaValue::string("n"),
+                    self.integer(frame.varargs.len() as i64),
+                )?;
+
+                frame.set(params, LuaValue::Table(Rc::new(RefCell::new(arg))));
+            }
+        }
+
+        let mut pc = 0;
+
+        while let Some(instruction) = prototype.chunk.get(pc) {
+            pc += 1;
+
+            if let Instruction::Label(_) = instruction {
+                continue;
+            }
+
+            if let Some(max_steps) = self.max_steps {
+                if self.steps >= max_steps {
+                    return Err(LimitExceeded(max_steps).into());
+                }
+            }
+            self.steps += 1;
+
+            let flow = self
+                .instruction(closure, &mut frame, instruction)
+                .with_context(|| {
+                    format!("failed to run instruction {}: {instruction:?}", pc - 1)
+                })?;
+
+            match flow {
+                Flow::Next => {}
+                Flow::Jump(label) => pc = prototype.labels[&label],
+                Flow::Return(results) => {
+                    frame.close(0);
+
+                    return Ok(results);
+                }
+            }
+        }
+
+        frame.close(0);
+
+        Ok(vec![])
+    }
+
+    fn instruction(
+        &mut self,
+        closure: &LuaClosure,
+        frame: &mut Frame,
+        instruction: &Instruction,
+    ) -> Result<Flow> {
+        let prototype = &closure.prototype;
+
+        match instruction {
+            Instruction::Label(_) => {}
+            Instruction::Load(load) => {
+                let value = self.operand(prototype, frame, &load.src)?;
+                frame.set(load.dest, value);
+            }
+            Instruction::Move(mv) => frame.set(mv.dest, frame.get(mv.src)),
+            Instruction::Intrinsic(intrinsic) => {
+                let operands = intrinsic
+                    .kind
+                    .operands()
+                    .into_iter()
+                    .map(|operand| self.operand(prototype, frame, operand))
+                    .collect::<Result<Vec<_>>>()?;
+
+                let value = self.intrinsic(intrinsic, &operands)?;
+                frame.set(intrinsic.dest, value);
+            }
+            Instruction::GetGlobal(get) => {
+                let key = self.constant(prototype, frame, get.constant)?;
+                let globals = LuaValue::Table(self.globals.clone());
+                let value = self.index(&globals, &key)?;
+                frame.set(get.dest, value);
+            }
+            Instruction::SetGlobal(set) => {
+                let key = self.constant(prototype, frame, set.constant)?;
+                let globals = LuaValue::Table(self.globals.clone());
+                self.new_index(&globals, key, frame.get(set.src))?;
+            }
+            Instruction::GetTable(get) => {
+                let key = self.operand(prototype, frame, &get.key)?;
+                let value = self.index(&frame.get(get.source), &key)?;
+                frame.set(get.dest, value);
+            }
+            Instruction::SetTable(set) => {
+                let key = self.operand(prototype, frame, &set.key)?;
+                let value = self.operand(prototype, frame, &set.value)?;
+                self.new_index(&frame.get(set.table), key, value)?;
+            }
+            Instruction::SelfLookup(lookup) => {
+                let object = frame.get(lookup.object);
+                let key = self.operand(prototype, frame, &lookup.key)?;
+                let method = self.index(&object, &key)?;
+
+                frame.set(lookup.dest + 1, object);
+                frame.set(lookup.dest, method);
+            }
+            Instruction::BinaryOp(op) => {
+                let left = self.operand(prototype, frame, &op.left)?;
+                let right = self.operand(prototype, frame, &op.right)?;
+
+                let value = match op.operator {
+                    BinaryOpKind::Concat => self.concat(left, right)?,
+                    _ => self.arithmetic(&op.operator, left, right)?,
+                };
+                frame.set(op.dest, value);
+            }
+            Instruction::UnaryOp(op) => {
+                let operand = self.operand(prototype, frame, &op.left)?;
+
+                let value = match op.operator {
+                    UnaryOpKind::Not => LuaValue::Boolean(!operand.is_truthy()),
+                    UnaryOpKind::Neg => self.negate(operand)?,
+                    UnaryOpKind::Len => self.length(operand)?,
+                };
+                frame.set(op.dest, value);
+            }
+            Instruction::Concat(concat) => {
+                let mut value = frame.get(concat.end);
+
+                for index in (concat.start..concat.end).rev() {
+                    value = self.concat(frame.get(index), value)?;
+                }
+
+                frame.set(concat.dest, value);
+            }
+            Instruction::Jump(jump) => return Ok(Flow::Jump(jump.target)),
+            Instruction::JumpNot(jump) => {
+                if !frame.get(jump.cond).is_truthy() {
+                    return Ok(Flow::Jump(jump.target));
+                }
+            }
+            Instruction::ConditionalJump(jump) => {
+                let condition = &jump.condition;
+                let left = self.operand(prototype, frame, &condition.left)?;
+                let right = self.operand(prototype, frame, &condition.right)?;
+
+                let holds = match condition.kind {
+                    ConditionKind::Eq => self.equals(&left, &right)?,
+                    ConditionKind::Ne => !self.equals(&left, &right)?,
+                    ConditionKind::Lt => self.less_than(&left, &right)?,
+                    ConditionKind::Le => self.less_equal(&left, &right)?,
+                    ConditionKind::Gt => self.less_than(&right, &left)?,
+                    ConditionKind::Ge => self.less_equal(&right, &left)?,
+                    ConditionKind::And => left.is_truthy() && right.is_truthy(),
+                    ConditionKind::Or => left.is_truthy() || right.is_truthy(),
+                };
+
+                if holds {
+                    return Ok(Flow::Jump(jump.target));
+                }
+            }
+            Instruction::ForNumPrep(prep) => {
+                let (index, limit, step) = self.for_prep(
+                    frame.get(prep.index),
+                    frame.get(prep.limit),
+                    frame.get(prep.step),
+                )?;
+
+                frame.set(prep.index, index.clone());
+                frame.set(prep.limit, limit.clone());
+                frame.set(prep.step, step.clone());
+
+                if !for_continues(&index, &limit, &step) {
+                    return Ok(Flow::Jump(prep.target));
+                }
+
+                frame.set(prep.var, index);
+            }
+            Instruction::ForNumLoop(next) => {
+                let step = frame.get(next.step);
+                let limit = frame.get(next.limit);
+
+                let index = match (frame.get(next.index), &step) {
+                    (LuaValue::Integer(index), LuaValue::Integer(step)) => {
+                        match index.checked_add(*step) {
+                            Some(index) => LuaValue::Integer(index),
+                            None => return Ok(Flow::Next),
+                        }
+                    }
+                    (index, step) => LuaValue::Number(
+                        index.as_float().unwrap_or_default() + step.as_float().unwrap_or_default(),
+                    ),
+                };
+
+                frame.set(next.index, index.clone());
+
+                if for_continues(&index, &limit, &step) {
+                    frame.set(next.var, index);
+
+                    return Ok(Flow::Jump(next.target));
+                }
+            }
+            Instruction::ForGenPrep(prep) => return Ok(Flow::Jump(prep.target)),
+            Instruction::ForGenCall(call) => {
+                let args = vec![frame.get(call.base + 1), frame.get(call.base + 2)];
+                let results = self.call(&frame.get(call.base), args)?;
+
+                frame.set_results(call.dest, results, &OptVariable::Number(call.count));
+            }
+            Instruction::ForGenLoop(next) => {
+                let value = frame.get(next.var);
+
+                if !value.is_nil() {
+                    frame.set(next.base + 2, value);
+
+                    return Ok(Flow::Jump(next.target));
+                }
+            }
+            Instruction::NewTable(table) => frame.set(table.dest, LuaValue::table()),
+            Instruction::SetList(list) => {
+                let table = match frame.get(list.table) {
+                    LuaValue::Table(table) => table,
+                    value => bail!("attempt to set the list of a {} value", value.type_name()),
+                };
+
+                for (index, value) in frame
+                    .values(list.start, &list.count)
+                    .into_iter()
+                    .enumerate()
+                {
+                    let key = self.integer((list.offset + index + 1) as i64);
+                    table.borrow_mut().set(key, value)?;
+                }
+            }
+            Instruction::Return(ret) => {
+                return Ok(Flow::Return(
+                    frame.values(ret.result_start, &ret.result_count),
+                ));
+            }
+            Instruction::Call(call) => {
+                let args = match call.num_args {
+                    OptVariable::Number(count) => {
+                        let count = OptVariable::Number(count + call.self_call as usize);
+
+                        frame.values(call.callee + 1, &count)
+                    }
+                    OptVariable::Variable => frame.values(call.callee + 1, &OptVariable::Variable),
+                };
+
+                let results = self.call(&frame.get(call.callee), args)?;
+                frame.set_results(call.callee, results, &call.num_returns);
+            }
+            Instruction::VarArg(vararg) => {
+                let values = frame.varargs.clone();
+                frame.set_results(vararg.dest, values, &vararg.count);
+            }
+            Instruction::Closure(new) => {
+                let child = prototype
+                    .children
+                    .get(new.prototype)
+                    .with_context(|| format!("prototype {} does not exist", new.prototype))?
+                    .clone();
+
+                let upvalues = child
+                    .upvalues
+                    .iter()
+                    .map(|upvalue| {
+                        if upvalue.in_stack {
+                            Ok(frame.capture(upvalue.index))
+                        } else {
+                            upvalue_cell(&closure.upvalues, upvalue.index)
+                        }
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                let value = LuaValue::Function(LuaFunction::Closure(Rc::new(LuaClosure {
+                    prototype: child,
+                    upvalues,
+                })));
+
+                frame.set(new.dest, value);
+            }
+            Instruction::GetUpvalue(get) => {
+                let value = upvalue_cell(&closure.upvalues, get.upvalue)?
+                    .borrow()
+                    .clone();
+                frame.set(get.dest, value);
+            }
+            Instruction::SetUpvalue(set) => {
+                *upvalue_cell(&closure.upvalues, set.upvalue)?.borrow_mut() = frame.get(set.src);
+            }
+            Instruction::Close(close) => frame.close(close.start),
+        }
+
+        Ok(Flow::Next)
+    }
+
+    /// The number `n`, which is an integer only when integers exist.
+    fn integer(&self, n: i64) -> LuaValue {
+        match self.semantics {
+            Semantics::Lua51 => LuaValue::Number(n as f64),
+            Semantics::Lua53 => LuaValue::Integer(n),
+        }
+    }
+
+    fn operand(&self, prototype: &Prototype, frame: &Frame, value: &Value) -> Result<LuaValue> {
+        Ok(match *value {
+            Value::Nil => LuaValue::Nil,
+            Value::Boolean(b) => LuaValue::Boolean(b),
+            Value::Immediate(n) => self.integer(n.into()),
+            Value::StackIndex(index) => frame.get(index),
+            Value::ConstantIndex(index) => self.constant(prototype, frame, index)?,
+        })
+    }
+
+    /// Loads constant `index`, expanding table constants into a fresh table each time.
+    fn constant(&self, prototype: &Prototype, frame: &Frame, index: usize) -> Result<LuaValue> {
+        let constant = prototype
+            .constants
+            .get(index)
+            .with_context(|| format!("constant {index} does not exist"))?;
+
+        Ok(match constant {
+            Constant::Nil => LuaValue::Nil,
+            Constant::Boolean(b) => LuaValue::Boolean(*b),
+            Constant::Number(n) => LuaValue::Number(*n),
+            Constant::Integer(n) => self.integer(*n),
+            Constant::String(s) => LuaValue::string(s),
+            Constant::Table(table) => {
+                let mut new = LuaTable::default();
+
+                match table {
+                    Table::Array(values) => {
+                        for (index, value) in values.iter().enumerate() {
+                            let value = self.operand(prototype, frame, value)?;
+                            new.set(self.integer(index as i64 + 1), value)?;
+                        }
+                    }
+                    Table::Map(entries) => {
+                        for (key, value) in entries {
+                            let key = self.operand(prototype, frame, key)?;
+                            let value = self.operand(prototype, frame, value)?;
+                            new.set(key, value)?;
+                        }
+                    }
+                }
+
+                LuaValue::Table(Rc::new(RefCell::new(new)))
+            }
+        })
+    }
+
+    fn metamethod(&self, value: &LuaValue, event: &str) -> Option<LuaValue> {
+        let handler = value.metatable()?.borrow().get(&LuaValue::string(event));
+
+        (!handler.is_nil()).then_some(handler)
+    }
+
+    /// Calls `handler` and keeps only its first result.
+    fn call_metamethod(&mut self, handler: &LuaValue, args: Vec<LuaValue>) -> Result<LuaValue> {
+        Ok(self
+            .call(handler, args)?
+            .into_iter()
+            .next()
+            .unwrap_or_default())
+    }
+
+    /// Reads `object[key]`, following `__index` metamethods.
+    pub fn index(&mut self, object: &LuaValue, key: &LuaValue) -> Result<LuaValue> {
+        let mut object = object.clone();
+
+        for _ in 0..MAX_META_CHAIN {
+            if let LuaValue::Table(table) = &object {
+                let value = table.borrow().get(key);
+
+                if !value.is_nil() {
+                    return Ok(value);
+                }
+            }
+
+            let handler = match self.metamethod(&object, "__index") {
+                Some(handler) => handler,
+                None if matches!(object, LuaValue::Table(_)) => return Ok(LuaValue::Nil),
+                None => bail!("attempt to index a {} value", object.type_name()),
+            };
+
+            if let LuaValue::Function(_) = handler {
+                return self.call_metamethod(&handler, vec![object, key.clone()]);
+            }
+
+            object = handler;
+        }
+
+        bail!("'__index' chain too long; possible loop")
+    }
+
+    /// Writes `object[key] = value`, following `__newindex` metamethods.
+    pub fn new_index(&mut self, object: &LuaValue, key: LuaValue, value: LuaValue) -> Result<()> {
+        let mut object = object.clone();
+
+        for _ in 0..MAX_META_CHAIN {
+            if let LuaValue::Table(table) = &object {
+                if !table.borrow().get(&key).is_nil() {
+                    return table.borrow_mut().set(key, value);
+                }
+            }
+
+            let handler = match self.metamethod(&object, "__newindex") {
+                Some(handler) => handler,
+                None => match &object {
+                    LuaValue::Table(table) => return table.borrow_mut().set(key, value),
+                    _ => bail!("attempt to index a {} value", object.type_name()),
+                },
+            };
+
+            if let LuaValue::Function(_) = handler {
+                return self.call(&handler, vec![object, key, value]).map(drop);
+            }
+
+            object = handler;
+        }
+
+        bail!("'__newindex' chain too long; possible loop")
+    }
+
+    /// Converts strings holding numerals into numbers, as arithmetic does.
+    fn to_number(&self, value: &LuaValue) -> Option<LuaValue> {
+        match value {
+            LuaValue::Integer(_) | LuaValue::Number(_) => Some(value.clone()),
+            LuaValue::String(s) => self.parse_number(s),
+            _ => None,
+        }
+    }
+
+    fn parse_number(&self, s: &[u8]) -> Option<LuaValue> {
+        let s = std::str::from_utf8(s).ok()?.trim();
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+
+        if let Some(hex) = digits
+            .strip_prefix("0x")
+            .or_else(|| digits.strip_prefix("0X"))
+        {
+            let n = u64::from_str_radix(hex, 16).ok()? as i64;
+
+            return Some(self.integer(if negative { n.wrapping_neg() } else { n }));
+        }
+
+        // `f64::from_str` also accepts words such as `inf` and `nan`
+        if !digits.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+            || digits.contains(|c: char| c.is_alphabetic() && c != 'e' && c != 'E')
+        {
+            return None;
+        }
+
+        if self.semantics == Semantics::Lua53 {
+            if let Ok(n) = s.parse::<i64>() {
+                return Some(LuaValue::Integer(n));
+            }
+        }
+
+        s.parse::<f64>().ok().map(LuaValue::Number)
+    }
+
+    /// Converts `value` to a string like `tostring`, using its `__tostring` metamethod.
+    pub fn tostring(&mut self, value: &LuaValue) -> Result<Vec<u8>> {
+        if let Some(handler) = self.metamethod(value, "__tostring") {
+            return match self.call_metamethod(&handler, vec![value.clone()])? {
+                LuaValue::String(s) => Ok(s.to_vec()),
+                _ => bail!("'__tostring' must return a string"),
+            };
+        }
+
+        Ok(self.raw_to_string(value))
+    }
+
+    fn raw_to_string(&self, value: &LuaValue) -> Vec<u8> {
+        let string = match value {
+            LuaValue::String(s) => return s.to_vec(),
+            LuaValue::Number(n) if self.semantics == Semantics::Lua53 => {
+                let formatted = format_number(*n);
+
+                // floats are told apart from integers from Lua 5.3 onwards
+                if formatted.bytes().all(|b| b.is_ascii_digit() || b == b'-') {
+                    formatted + ".0"
+                } else {
+                    formatted
+                }
+            }
+            _ => value.to_string(),
+        };
+
+        string.into_bytes()
+    }
+
+    fn arithmetic(
+        &mut self,
+        operator: &BinaryOpKind,
+        left: LuaValue,
+        right: LuaValue,
+    ) -> Result<LuaValue> {
+        if let (Some(a), Some(b)) = (self.to_number(&left), self.to_number(&right)) {
+            return self.arithmetic_numbers(operator, a, b);
+        }
+
+        let event = match operator {
+            BinaryOpKind::Add => "__add",
+            BinaryOpKind::Sub => "__sub",
+            BinaryOpKind::Mul => "__mul",
+            BinaryOpKind::Div => "__div",
+            BinaryOpKind::IDiv => "__idiv",
+            BinaryOpKind::Mod => "__mod",
+            BinaryOpKind::Pow => "__pow",
+            BinaryOpKind::Concat => "__concat",
+        };
+
+        match self
+            .metamethod(&left, event)
+            .or_else(|| self.metamethod(&right, event))
+        {
+            Some(handler) => self.call_metamethod(&handler, vec![left, right]),
+            None => {
+                let culprit = if self.to_number(&left).is_none() {
+                    left
+                } else {
+                    right
+                };
+
+                bail!(
+                    "attempt to perform arithmetic on a {} value",
+                    culprit.type_name()
+                )
+            }
+        }
+    }
+
+    fn arithmetic_numbers(
+        &self,
+        operator: &BinaryOpKind,
+        left: LuaValue,
+        right: LuaValue,
+    ) -> Result<LuaValue> {
+        if let (LuaValue::Integer(a), LuaValue::Integer(b)) = (&left, &right) {
+            let (a, b) = (*a, *b);
+
+            let result = match operator {
+                BinaryOpKind::Add => Some(a.wrapping_add(b)),
+                BinaryOpKind::Sub => Some(a.wrapping_sub(b)),
+                BinaryOpKind::Mul => Some(a.wrapping_mul(b)),
+                BinaryOpKind::IDiv => {
+                    ensure!(b != 0, "attempt to perform 'n//0'");
+
+                    let quotient = a.wrapping_div(b);
+
+                    // round towards negative infinity rather than zero
+                    Some(if a.wrapping_rem(b) != 0 && (a < 0) != (b < 0) {
+                        quotient - 1
+                    } else {
+                        quotient
+                    })
+                }
+                BinaryOpKind::Mod => {
+                    ensure!(b != 0, "attempt to perform 'n%0'");
+
+                    let remainder = a.wrapping_rem(b);
+
+                    // the result takes the sign of the divisor
+                    Some(if remainder != 0 && (remainder < 0) != (b < 0) {
+                        remainder + b
+                    } else {
+                        remainder
+                    })
+                }
+                _ => None,
+            };
+
+            if let Some(result) = result {
+                return Ok(LuaValue::Integer(result));
+            }
+        }
+
+        let a = left.as_float().unwrap_or_default();
+        let b = right.as_float().unwrap_or_default();
+
+        Ok(LuaValue::Number(match operator {
+            BinaryOpKind::Add => a + b,
+            BinaryOpKind::Sub => a - b,
+            BinaryOpKind::Mul => a * b,
+            BinaryOpKind::Div => a / b,
+            BinaryOpKind::IDiv => (a / b).floor(),
+            BinaryOpKind::Pow => a.powf(b),
+            BinaryOpKind::Mod => match self.semantics {
+                Semantics::Lua51 => a - (a / b).floor() * b,
+                Semantics::Lua53 => {
+                    let remainder = a % b;
+
+                    if remainder != 0.0 && (remainder < 0.0) != (b < 0.0) {
+                        remainder + b
+                    } else {
+                        remainder
+                    }
+                }
+            },
+            BinaryOpKind::Concat => unreachable!("concatenation is not arithmetic"),
+        }))
+    }
+
+    fn negate(&mut self, operand: LuaValue) -> Result<LuaValue> {
+        match self.to_number(&operand) {
+            Some(LuaValue::Integer(n)) => Ok(LuaValue::Integer(n.wrapping_neg())),
+            Some(n) => Ok(LuaValue::Number(-n.as_float().unwrap_or_default())),
+            None => match self.metamethod(&operand, "__unm") {
+                Some(handler) => self.call_metamethod(&handler, vec![operand.clone(), operand]),
+                None => bail!(
+                    "attempt to perform arithmetic on a {} value",
+                    operand.type_name()
+                ),
+            },
+        }
+    }
+
+    fn length(&mut self, operand: LuaValue) -> Result<LuaValue> {
+        // tables only consult `__len` from Lua 5.2 onwards
+        let handler = match (&operand, self.semantics) {
+            (LuaValue::String(s), _) => return Ok(self.integer(s.len() as i64)),
+            (LuaValue::Table(table), Semantics::Lua51) => {
+                return Ok(self.integer(table.borrow().length()))
+            }
+            _ => self.metamethod(&operand, "__len"),
+        };
+
+        match (handler, &operand) {
+            (Some(handler), _) => self.call_metamethod(&handler, vec![operand]),
+            (None, LuaValue::Table(table)) => Ok(self.integer(table.borrow().length())),
+            (None, _) => bail!("attempt to get length of a {} value", operand.type_name()),
+        }
+    }
+
+    fn concat(&mut self, left: LuaValue, right: LuaValue) -> Result<LuaValue> {
+        let is_string = |value: &LuaValue| {
+            matches!(
+                value,
+                LuaValue::String(_) | LuaValue::Integer(_) | LuaValue::Number(_)
+            )
+        };
+
+        if is_string(&left) && is_string(&right) {
+            let mut joined = self.raw_to_string(&left);
+            joined.extend_from_slice(&self.raw_to_string(&right));
+
+            return Ok(LuaValue::string(joined));
+        }
+
+        match self
+            .metamethod(&left, "__concat")
+            .or_else(|| self.metamethod(&right, "__concat"))
+        {
+            Some(handler) => self.call_metamethod(&handler, vec![left, right]),
+            None => {
+                let culprit = if is_string(&left) { right } else { left };
+
+                bail!("attempt to concatenate a {} value", culprit.type_name())
+            }
+        }
+    }
+
+    fn equals(&mut self, left: &LuaValue, right: &LuaValue) -> Result<bool> {
+        if left == right {
+            return Ok(true);
+        }
+
+        if let (LuaValue::Table(_), LuaValue::Table(_)) = (left, right) {
+            if let Some(handler) = self
+                .metamethod(left, "__eq")
+                .or_else(|| self.metamethod(right, "__eq"))
+            {
+                let result = self.call_metamethod(&handler, vec![left.clone(), right.clone()])?;
+
+                return Ok(result.is_truthy());
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn less_than(&mut self, left: &LuaValue, right: &LuaValue) -> Result<bool> {
+        self.compare(left, right, "__lt", |ordering| ordering.is_lt())
+    }
+
+    fn less_equal(&mut self, left: &LuaValue, right: &LuaValue) -> Result<bool> {
+        self.compare(left, right, "__le", |ordering| ordering.is_le())
+    }
+
+    fn compare(
+        &mut self,
+        left: &LuaValue,
+        right: &LuaValue,
+        event: &str,
+        holds: fn(std::cmp::Ordering) -> bool,
+    ) -> Result<bool> {
+        match (left, right) {
+            (LuaValue::String(a), LuaValue::String(b)) => return Ok(holds(a.cmp(b))),
+            _ => {
+                if let Some(ordering) = compare_numbers(left, right) {
+                    return Ok(holds(ordering));
+                }
+
+                // comparisons involving NaN are always false
+                if left.as_float().is_some() && right.as_float().is_some() {
+                    return Ok(false);
+                }
+            }
+        }
+
+        match self
+            .metamethod(left, event)
+            .or_else(|| self.metamethod(right, event))
+        {
+            Some(handler) => Ok(self
+                .call_metamethod(&handler, vec![left.clone(), right.clone()])?
+                .is_truthy()),
+            None if left.type_name() == right.type_name() => {
+                bail!("attempt to compare two {} values", left.type_name())
+            }
+            None => bail!(
+                "attempt to compare {} with {}",
+                left.type_name(),
+                right.type_name()
+            ),
+        }
+    }
+
+    /// Checks and converts the control values of a numeric for loop. Loops whose start and
+    /// step are integers count with integers from Lua 5.3 onwards, rounding the limit to the
+    /// last integer the loop reaches.
+    fn for_prep(
+        &self,
+        index: LuaValue,
+        limit: LuaValue,
+        step: LuaValue,
+    ) -> Result<(LuaValue, LuaValue, LuaValue)> {
+        let index = self
+            .to_number(&index)
+            .context("'for' initial value must be a number")?;
+        let limit = self
+            .to_number(&limit)
+            .context("'for' limit must be a number")?;
+        let step = self
+            .to_number(&step)
+            .context("'for' step must be a number")?;
+
+        if self.semantics == Semantics::Lua51 {
+            return Ok((index, limit, step));
+        }
+
+        ensure!(step.as_float() != Some(0.0), "'for' step is zero");
+
+        match (&index, &limit, &step) {
+            (LuaValue::Integer(_), LuaValue::Number(n), LuaValue::Integer(s)) => {
+                let rounded = if *s > 0 { n.floor() } else { n.ceil() };
+
+                let limit = if rounded.is_nan() {
+                    limit.clone()
+                } else if rounded >= 9_223_372_036_854_775_808.0 {
+                    LuaValue::Integer(i64::MAX)
+                } else if rounded < -9_223_372_036_854_775_808.0 {
+                    LuaValue::Integer(i64::MIN)
+                } else {
+                    LuaValue::Integer(rounded as i64)
+                };
+
+                Ok((index, limit, step))
+            }
+            (LuaValue::Integer(_), LuaValue::Integer(_), LuaValue::Integer(_)) => {
+                Ok((index, limit, step))
+            }
+            _ => Ok((
+                LuaValue::Number(index.as_float().unwrap_or_default()),
+                LuaValue::Number(limit.as_float().unwrap_or_default()),
+                LuaValue::Number(step.as_float().unwrap_or_default()),
+            )),
+        }
+    }
+
+    /// Converts `value` for a bitwise operation of `source`, returning its bits.
+    fn bits(&self, value: &LuaValue, source: IntrinsicSource) -> Result<i64> {
+        let number = self.to_number(value).with_context(|| {
+            format!(
+                "attempt to perform bitwise operation on a {} value",
+                value.type_name()
+            )
+        })?;
+
+        match (source, number) {
+            (IntrinsicSource::Operator, LuaValue::Integer(n)) => Ok(n),
+            (IntrinsicSource::Operator, LuaValue::Number(n)) => {
+                float_to_integer(n).context("number has no integer representation")
+            }
+            // the libraries take their operands modulo 2^32
+            (_, LuaValue::Integer(n)) => Ok(n as u32 as i64),
+            (_, n) => {
+                let n = n.as_float().unwrap_or_default().floor();
+
+                Ok(n.rem_euclid(4_294_967_296.0) as i64)
+            }
+        }
+    }
+
+    fn intrinsic(&self, intrinsic: &Intrinsic, operands: &[LuaValue]) -> Result<LuaValue> {
+        let operands = operands
+            .iter()
+            .map(|operand| self.bits(operand, intrinsic.source))
+            .collect::<Result<Vec<_>>>()?;
+
+        let a = operands[0];
+        let b = operands.get(1).copied().unwrap_or_default();
+
+        let result = match intrinsic.source {
+            IntrinsicSource::Operator => match intrinsic.kind {
+                IntrinsicKind::BitAnd(..) => a & b,
+                IntrinsicKind::BitOr(..) => a | b,
+                IntrinsicKind::BitXor(..) => a ^ b,
+                IntrinsicKind::BitNot(_) => !a,
+                IntrinsicKind::LeftShift(..) => shift_left(a as u64, b, 64) as i64,
+                IntrinsicKind::RightShift(..) => shift_left(a as u64, b.wrapping_neg(), 64) as i64,
+            },
+            IntrinsicSource::Bit32 => {
+                let result = match intrinsic.kind {
+                    IntrinsicKind::BitAnd(..) => a & b,
+                    IntrinsicKind::BitOr(..) => a | b,
+                    IntrinsicKind::BitXor(..) => a ^ b,
+                    IntrinsicKind::BitNot(_) => !a,
+                    IntrinsicKind::LeftShift(..) => shift_left(a as u64, b, 32) as i64,
+                    IntrinsicKind::RightShift(..) => {
+                        shift_left(a as u64, b.wrapping_neg(), 32) as i64
+                    }
+                };
+
+                result as u32 as i64
+            }
+            // LuaBitOp works on signed 32 bit integers and masks its shift counts
+            IntrinsicSource::Bit => {
+                let (a, b) = (a as u32, (b & 31) as u32);
+
+                let result = match intrinsic.kind {
+                    IntrinsicKind::BitAnd(..) => a & operands[1] as u32,
+                    IntrinsicKind::BitOr(..) => a | operands[1] as u32,
+                    IntrinsicKind::BitXor(..) => a ^ operands[1] as u32,
+                    IntrinsicKind::BitNot(_) => !a,
+                    IntrinsicKind::LeftShift(..) => a << b,
+                    IntrinsicKind::RightShift(..) => a >> b,
+                };
+
+                result as i32 as i64
+            }
+        };
+
+        Ok(self.integer(result))
+    }
+}
+
+/// The number of `__index` or `__newindex` metamethods followed before giving up.
+const MAX_META_CHAIN: usize = 100;
+
+/// Shifts the low `width` bits of `n` left by `by`, or right when `by` is negative.
+fn shift_left(n: u64, by: i64, width: u32) -> u64 {
+    let mask = if width == 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    };
+
+    if by <= -(width as i64) || by >= width as i64 {
+        0
+    } else if by >= 0 {
+        (n << by) & mask
+    } else {
+        (n & mask) >> -by
+    }
+}
+
+fn upvalue_cell(upvalues: &[Rc<RefCell<LuaValue>>], index: usize) -> Result<Rc<RefCell<LuaValue>>> {
+    upvalues
+        .get(index)
+        .cloned()
+        .with_context(|| format!("upvalue {index} does not exist"))
+}
+
+/// Compares two numbers, comparing integers exactly.
+fn compare_numbers(left: &LuaValue, right: &LuaValue) -> Option<std::cmp::Ordering> {
+    match (left, right) {
+        (LuaValue::Integer(a), LuaValue::Integer(b)) => Some(a.cmp(b)),
+        (LuaValue::Integer(a), LuaValue::Number(b)) => compare_integer_float(*a, *b),
+        (LuaValue::Number(a), LuaValue::Integer(b)) => {
+            compare_integer_float(*b, *a).map(std::cmp::Ordering::reverse)
+        }
+        _ => left.as_float()?.partial_cmp(&right.as_float()?),
+    }
+}
+
+/// Compares an integer with a float without converting the integer, which could round it.
+fn compare_integer_float(integer: i64, float: f64) -> Option<std::cmp::Ordering> {
+    if float.is_nan() {
+        return None;
+    }
+
+    let floor = float.floor();
+
+    match float_to_integer(floor) {
+        // a float with a fraction is above its floor, so an integer equal to the floor is less
+        Some(floor_integer) => Some(integer.cmp(&floor_integer).then(if floor == float {
+            std::cmp::Ordering::Equal
+        } else {
+            std::cmp::Ordering::Less
+        })),
+        // beyond the range of integers, only the sign matters
+        None if float > 0.0 => Some(std::cmp::Ordering::Less),
+        None => Some(std::cmp::Ordering::Greater),
+    }
+}
+
+/// Whether a numeric for loop runs with the control value `index`.
+fn for_continues(index: &LuaValue, limit: &LuaValue, step: &LuaValue) -> bool {
+    let ascending = step.as_float().map_or(false, |step| step > 0.0);
+
+    match compare_numbers(index, limit) {
+        Some(ordering) if ascending => ordering.is_le(),
+        Some(ordering) => ordering.is_ge(),
+        None => false,
+    }
+}
+
+/// Takes argument `index` of a native function, which starts from 1.
+fn argument(args: &[LuaValue], index: usize) -> LuaValue {
+    args.get(index - 1).cloned().unwrap_or_default()
+}
+
+fn table_argument(args: &[LuaValue], index: usize, function: &str) -> Result<TableRef> {
+    match argument(args, index) {
+        LuaValue::Table(table) => Ok(table),
+        value => bail!(
+            "bad argument #{index} to '{function}' (table expected, got {})",
+            value.type_name()
+        ),
+    }
+}
+
+fn base_library(globals: &mut LuaTable) {
+    let mut define =
+        |name: &str, f: fn(&mut Interpreter, Vec<LuaValue>) -> Result<Vec<LuaValue>>| {
+            globals
+                .set(LuaValue::string(name), LuaValue::native(f))
+                .unwrap();
+        };
+
+    define("print", |interpreter, args| {
+        let line = args
+            .iter()
+            .map(|arg| interpreter.tostring(arg))
+            .collect::<Result<Vec<_>>>()?
+            .join(&b'\t');
+
+        interpreter
+            .output
+            .push(String::from_utf8_lossy(&line).into_owned());
+
+        Ok(vec![])
+    });
+
+    define("type", |_, args| {
+        let value = args
+            .first()
+            .context("bad argument #1 to 'type' (value expected)")?;
+
+        Ok(vec![LuaValue::string(value.type_name())])
+    });
+
+    define("tostring", |interpreter, args| {
+        let string = interpreter.tostring(&argument(&args, 1))?;
+
+        Ok(vec![LuaValue::string(string)])
+    });
+
+    define("tonumber", |interpreter, args| {
+        Ok(vec![interpreter
+            .to_number(&argument(&args, 1))
+            .unwrap_or_default()])
+    });
+
+    define("select", |interpreter, args| {
+        let count = args.len() as i64 - 1;
+
+        let index = match argument(&args, 1) {
+            LuaValue::String(s) if &*s == b"#" => return Ok(vec![interpreter.integer(count)]),
+            value => match interpreter.to_number(&value).and_then(|n| n.as_float()) {
+                Some(n) if n < 0.0 && -n <= count as f64 => count + n as i64 + 1,
+                Some(n) if n >= 1.0 => n as i64,
+                _ => bail!("bad argument #1 to 'select' (index out of range)"),
+            },
+        };
+
+        Ok(args.into_iter().skip(index as usize).collect())
+    });
+
+    define("next", |_, args| {
+        let table = table_argument(&args, 1, "next")?;
+        let entry = table.borrow().next(&argument(&args, 2))?;
+
+        Ok(match entry {
+            Some((key, value)) => vec![key, value],
+            None => vec![LuaValue::Nil],
+        })
+    });
+
+    define("pairs", |interpreter, args| {
+        table_argument(&args, 1, "pairs")?;
+
+        let next = interpreter.globals.borrow().get(&LuaValue::string("next"));
+
+        Ok(vec![next, argument(&args, 1), LuaValue::Nil])
+    });
+
+    define("ipairs", |interpreter, args| {
+        table_argument(&args, 1, "ipairs")?;
+
+        let iterator = LuaValue::native(|interpreter, args| {
+            let index = argument(&args, 2).as_float().unwrap_or_default() as i64 + 1;
+            let key = interpreter.integer(index);
+            let value = interpreter.index(&argument(&args, 1), &key)?;
+
+            Ok(if value.is_nil() {
+                vec![LuaValue::Nil]
+            } else {
+                vec![key, value]
+            })
+        });
+
+        Ok(vec![iterator, argument(&args, 1), interpreter.integer(0)])
+    });
+
+    define("setmetatable", |_, args| {
+        let table = table_argument(&args, 1, "setmetatable")?;
+
+        let metatable = match argument(&args, 2) {
+            LuaValue::Nil => None,
+            LuaValue::Table(metatable) => Some(metatable),
+            _ => bail!("bad argument #2 to 'setmetatable' (nil or table expected)"),
+        };
+
+        table.borrow_mut().set_metatable(metatable);
+
+        Ok(vec![argument(&args, 1)])
+    });
+
+    define("getmetatable", |_, args| {
+        let metatable = match argument(&args, 1).metatable() {
+            Some(metatable) => metatable,
+            None => return Ok(vec![LuaValue::Nil]),
+        };
+
+        let protected = metatable.borrow().get(&LuaValue::string("__metatable"));
+
+        Ok(vec![if protected.is_nil() {
+            LuaValue::Table(metatable)
+        } else {
+            protected
+        }])
+    });
+
+    define("rawequal", |_, args| {
+        Ok(vec![LuaValue::Boolean(
+            argument(&args, 1) == argument(&args, 2),
+        )])
+    });
+
+    define("rawget", |_, args| {
+        let table = table_argument(&args, 1, "rawget")?;
+        let value = table.borrow().get(&argument(&args, 2));
+
+        Ok(vec![value])
+    });
+
+    define("rawset", |_, args| {
+        let table = table_argument(&args, 1, "rawset")?;
+        table
+            .borrow_mut()
+            .set(argument(&args, 2), argument(&args, 3))?;
+
+        Ok(vec![argument(&args, 1)])
+    });
+
+    define("error", |interpreter, args| {
+        let message = interpreter.tostring(&argument(&args, 1))?;
+
+        Err(anyhow!(String::from_utf8_lossy(&message).into_owned()))
+    });
+
+    define("assert", |interpreter, args| {
+        if argument(&args, 1).is_truthy() {
+            return Ok(args);
+        }
+
+        match args.get(1) {
+            Some(message) => {
+                let message = interpreter.tostring(message)?;
+
+                Err(anyhow!(String::from_utf8_lossy(&message).into_owned()))
+            }
+            None => bail!("assertion failed!"),
+        }
+    });
+
+    define("pcall", |interpreter, mut args| {
+        ensure!(
+            !args.is_empty(),
+            "bad argument #1 to 'pcall' (value expected)"
+        );
+
+        let callee = args.remove(0);
+
+        match interpreter.call(&callee, args) {
+            Ok(mut results) => {
+                results.insert(0, LuaValue::Boolean(true));
+
+                Ok(results)
+            }
+            // running out of steps must stop the whole program
+            Err(error) if error.chain().any(|cause| cause.is::<LimitExceeded>()) => Err(error),
+            Err(error) => Ok(vec![
+                LuaValue::Boolean(false),
+                LuaValue::string(error.root_cause().to_string()),
+            ]),
+        }
+    });
+}
+
+/// The error raised when a program exceeds the step limit, which `pcall` does not catch.
+#[derive(Debug)]
+struct LimitExceeded(u64);
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "exceeded the limit of {} steps", self.0)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
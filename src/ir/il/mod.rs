@@ -23,28 +23,84 @@
 // TODO: remove once everything is used
 #![allow(unused)]
 
+mod effects;
+pub mod interpreter;
+#[cfg(feature = "serde")]
+pub mod schema;
+mod tests;
+pub mod text;
+mod verify;
+pub mod visit;
+
+pub(crate) use effects::registers_used;
+pub use effects::{Effects, Slots};
+pub use verify::{Problem, ProblemKind, VerifyError};
+pub use visit::{IlRewriter, IlVisitor};
+
+use crate::ir::span::Span;
+use anyhow::{ensure, Result};
+use cranelift_entity::{entity_impl, EntityRef, PrimaryMap, SecondaryMap};
+use indexmap::IndexMap;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     fmt::Debug,
+    hash::{Hash, Hasher},
+    ops::{Index, Range},
 };
 
 /// Represents the two states of a table, array (index-value pairs) and hashmap
-/// (key-value pairs).
+/// (key-value pairs). Maps keep their keys in insertion order, so that tables are
+/// constructed and printed the same way every time.
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Table {
-    Map(HashMap<Value, Value>),
+    #[cfg_attr(feature = "serde", serde(with = "schema::pairs"))]
+    Map(IndexMap<Value, Value>),
     Array(Vec<Value>),
 }
 
+// two maps are only equal when their keys are in the same order, as `IndexMap` itself
+// ignores the order but it is kept when tables are constructed
+impl PartialEq for Table {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Map(a), Self::Map(b)) => a.iter().eq(b.iter()),
+            (Self::Array(a), Self::Array(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Table {}
+
+impl Hash for Table {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+
+        match self {
+            Self::Map(map) => {
+                state.write_usize(map.len());
+
+                for entry in map {
+                    entry.hash(state);
+                }
+            }
+            Self::Array(array) => array.hash(state),
+        }
+    }
+}
+
 impl Debug for Table {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut buf = String::with_capacity(32);
 
         buf.push('{');
         match self {
-            Self::Map(hash_map) => {
-                for v in hash_map.iter().enumerate() {
-                    buf.push_str(format!("[{:?}] {_eq:>4} {:?}, ", v.0, v.1, _eq = "=").as_str());
+            Self::Map(map) => {
+                for (key, value) in map {
+                    buf.push_str(format!("[{:?}] {_eq:>4} {:?}, ", key, value, _eq = "=").as_str());
                 }
             }
             Self::Array(array) => {
@@ -60,6 +116,7 @@ impl Debug for Table {
 
 /// Represents the types of operands that can be used within an IL instruction.
 #[derive(Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Value {
     Nil,
     Boolean(bool),
@@ -79,14 +136,22 @@ impl Debug for Value {
     }
 }
 
-/// Represents the types of values that can be present in the constant table.
+/// Represents the types of values that can be present in the constant table. Numbers are
+/// compared and hashed by their bit pattern, so `0.0` and `-0.0` are distinct constants
+/// while every NaN is the same one.
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Constant {
     Nil,
     Boolean(bool),
-    Function(Function),
+    /// A floating point number, which is the only number type before Lua 5.3 and in Luau.
+    #[cfg_attr(feature = "serde", serde(with = "schema::number"))]
     Number(f64),
-    String(String),
+    /// An integer, the subtype of numbers added in Lua 5.3.
+    Integer(i64),
+    /// A string, which holds arbitrary bytes and is not necessarily valid UTF-8.
+    #[cfg_attr(feature = "serde", serde(with = "schema::bytes"))]
+    String(Vec<u8>),
     Table(Table),
 }
 // L
@@ -95,16 +160,62 @@ impl Debug for Constant {
         match self {
             Self::Nil => write!(f, "nil"),
             Self::Boolean(b) => write!(f, "{b}"),
-            Self::Function(s) => write!(f, "{s:?}"),
-            Self::Number(n) => write!(f, "{n}"),
-            Self::String(s) => write!(f, "{s}"),
+            Self::Number(n) => write!(f, "{n:?}"),
+            Self::Integer(n) => write!(f, "{n}"),
+            Self::String(s) => match std::str::from_utf8(s) {
+                Ok(s) => write!(f, "{s}"),
+                Err(_) => write!(f, "{}", s.escape_ascii()),
+            },
             Self::Table(t) => write!(f, "{t:?}"),
         }
     }
 }
 
+impl Constant {
+    /// The bit pattern a number is compared and hashed by, with every NaN collapsed into one.
+    fn number_bits(n: f64) -> u64 {
+        if n.is_nan() {
+            f64::NAN.to_bits()
+        } else {
+            n.to_bits()
+        }
+    }
+}
+
+impl PartialEq for Constant {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Nil, Self::Nil) => true,
+            (Self::Boolean(a), Self::Boolean(b)) => a == b,
+            (Self::Number(a), Self::Number(b)) => Self::number_bits(*a) == Self::number_bits(*b),
+            (Self::Integer(a), Self::Integer(b)) => a == b,
+            (Self::String(a), Self::String(b)) => a == b,
+            (Self::Table(a), Self::Table(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Constant {}
+
+impl Hash for Constant {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+
+        match self {
+            Self::Nil => {}
+            Self::Boolean(b) => b.hash(state),
+            Self::Number(n) => Self::number_bits(*n).hash(state),
+            Self::Integer(n) => n.hash(state),
+            Self::String(s) => s.hash(state),
+            Self::Table(t) => t.hash(state),
+        }
+    }
+}
+
 /// A load operation with a destination index and a source value.
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Load {
     pub dest: usize,
     pub src: Value,
@@ -116,8 +227,23 @@ impl Debug for Load {
     }
 }
 
+/// Copies the value at stack index `src` into stack index `dest`.
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Move {
+    pub dest: usize,
+    pub src: usize,
+}
+
+impl Debug for Move {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {_eq:>4} {}", self.dest, self.src, _eq = "=")
+    }
+}
+
 /// A get global operation with a destination index and a source constant table index.
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct GetGlobal {
     pub dest: usize,
     pub constant: usize,
@@ -130,6 +256,7 @@ impl Debug for GetGlobal {
 }
 
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct SetGlobal {
     pub src: usize,
     pub constant: usize,
@@ -144,6 +271,7 @@ impl Debug for SetGlobal {
 /// Perfoms a table index operation on the value at stack index `source` using the value
 /// at stack index `key`, then stores the result in stack index `dest`.
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct GetTable {
     pub dest: usize,
     pub source: usize,
@@ -163,12 +291,60 @@ impl Debug for GetTable {
     }
 }
 
+/// Stores `value` into the table at stack index `table` using `key`.
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SetTable {
+    pub table: usize,
+    pub key: Value,
+    pub value: Value,
+}
+
+impl Debug for SetTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}[{:?}] {_eq:>4} {:?}",
+            self.table,
+            self.key,
+            self.value,
+            _eq = "="
+        )
+    }
+}
+
+/// Prepares a method call by copying the object at stack index `object` into stack index
+/// `dest + 1`, then storing the method found by indexing it with `key` in stack index
+/// `dest`.
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SelfLookup {
+    pub dest: usize,
+    pub object: usize,
+    pub key: Value,
+}
+
+impl Debug for SelfLookup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {_eq:>4} {}:{:?}",
+            self.dest,
+            self.object,
+            self.key,
+            _eq = "="
+        )
+    }
+}
+
 /// Represents the kinds of supported binary operations.
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum BinaryOpKind {
     Add,
     Concat,
     Div,
+    IDiv,
     Mod,
     Mul,
     Pow,
@@ -190,6 +366,9 @@ impl Debug for BinaryOpKind {
             BinaryOpKind::Div => {
                 write!(f, "/")
             }
+            BinaryOpKind::IDiv => {
+                write!(f, "//")
+            }
             BinaryOpKind::Mod => {
                 write!(f, "%")
             }
@@ -205,6 +384,7 @@ impl Debug for BinaryOpKind {
 
 /// A binary operation with an operator, a destination index, and left and right operands.
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct BinaryOp {
     pub operator: BinaryOpKind,
     pub dest: usize,
@@ -227,8 +407,33 @@ impl Debug for BinaryOp {
     }
 }
 
+/// Concatenates the values from stack index `start` up to and including stack index `end`,
+/// then stores the result in stack index `dest`. The registers in the range may be
+/// clobbered.
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Concat {
+    pub dest: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Debug for Concat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {_eq:>4} concat {}..{}",
+            self.dest,
+            self.start,
+            self.end,
+            _eq = "="
+        )
+    }
+}
+
 /// Represents the kinds of supported unary operations.
 #[derive(Clone, PartialEq, PartialOrd, Eq, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum UnaryOpKind {
     Len,
     Not,
@@ -253,6 +458,7 @@ impl Debug for UnaryOpKind {
 
 /// A unary operation with an operator, a destination index, and a single operand.
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct UnaryOp {
     pub operator: UnaryOpKind,
     pub dest: usize,
@@ -275,6 +481,7 @@ impl Debug for UnaryOp {
 
 /// Represents the kinds of supported conditions.
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ConditionKind {
     Eq,
     Ge,
@@ -320,6 +527,7 @@ impl Debug for ConditionKind {
 
 // A condition with a kind, a destination index, and left and right operands.
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Condition {
     pub kind: ConditionKind,
 
@@ -335,6 +543,7 @@ impl Debug for Condition {
 
 /// All possible intrinsic operations
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum IntrinsicKind {
     BitAnd(Value, Value),
     BitOr(Value, Value),
@@ -370,66 +579,259 @@ impl Debug for IntrinsicKind {
     }
 }
 
+impl IntrinsicKind {
+    /// The name of the function implementing this operation in the `bit` and `bit32`
+    /// libraries.
+    pub fn library_name(&self) -> &'static str {
+        match self {
+            Self::BitAnd(..) => "band",
+            Self::BitOr(..) => "bor",
+            Self::BitXor(..) => "bxor",
+            Self::BitNot(_) => "bnot",
+            Self::LeftShift(..) => "lshift",
+            Self::RightShift(..) => "rshift",
+        }
+    }
+
+    /// The operands of this operation, in order.
+    pub fn operands(&self) -> Vec<&Value> {
+        match self {
+            Self::BitAnd(l, r)
+            | Self::BitOr(l, r)
+            | Self::BitXor(l, r)
+            | Self::LeftShift(l, r)
+            | Self::RightShift(l, r) => vec![l, r],
+            Self::BitNot(v) => vec![v],
+        }
+    }
+}
+
+/// Where an intrinsic operation originates from, as Lua versions without bitwise operators
+/// provide them through a library instead.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum IntrinsicSource {
+    /// A native operator of Lua 5.3 and later, such as `a & b`.
+    #[default]
+    Operator,
+    /// A call into the `bit32` library of Lua 5.2 and Luau, such as `bit32.band(a, b)`.
+    Bit32,
+    /// A call into the `bit` library of LuaBitOp and LuaJIT, such as `bit.band(a, b)`.
+    Bit,
+}
+
+impl IntrinsicSource {
+    /// The global holding the library this intrinsic is called from, if it is a call.
+    pub fn library(&self) -> Option<&'static str> {
+        match self {
+            Self::Operator => None,
+            Self::Bit32 => Some("bit32"),
+            Self::Bit => Some("bit"),
+        }
+    }
+}
+
 // Instruction to declare intrinsic.
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Intrinsic {
     pub kind: IntrinsicKind,
     pub dest: usize,
+    pub source: IntrinsicSource,
 }
 
 impl Debug for Intrinsic {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} {_eq:>4} {:?}", self.dest, self.kind, _eq = "=")
+        match self.source.library() {
+            None => write!(f, "{} {_eq:>4} {:?}", self.dest, self.kind, _eq = "="),
+            Some(library) => {
+                let operands = self
+                    .kind
+                    .operands()
+                    .iter()
+                    .map(|operand| format!("{operand:?}"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+
+                write!(
+                    f,
+                    "{} {_eq:>4} {library}.{}({operands})",
+                    self.dest,
+                    self.kind.library_name(),
+                    _eq = "="
+                )
+            }
+        }
     }
 }
 
-/// Information about a program-counter-relative-jump, `start` is the program counter,
-/// `end` is the offset added to the counter, and `offset` is the total number of
-/// instructions skipped for the branch.
-#[derive(PartialEq, Debug, Clone)]
-pub struct JumpBranch {
-    pub start: usize,
-    pub end: usize,
-    pub offset: isize,
+/// A symbolic jump target, defined by the `Instruction::Label` marking its position in a
+/// chunk. Jumps refer to labels rather than instruction indices so that instructions can be
+/// inserted or removed without fixing up every branch, and are only lowered to program
+/// counter offsets by serializers.
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Label(pub usize);
+
+impl Debug for Label {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "L{}", self.0)
+    }
 }
 
 /// An unconditional jump.
-#[derive(PartialEq, Debug, Clone)]
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Jump {
-    pub branch: JumpBranch,
+    pub target: Label,
+}
+
+impl Debug for Jump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "jump {:?}", self.target)
+    }
 }
 
 /// A conditional jump that only jumps if the `NOT` of the value at stack index `cond`
 /// evaluates to true.
-#[derive(PartialEq, Debug, Clone)]
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct JumpNot {
-    pub branch: JumpBranch,
+    pub target: Label,
     pub cond: usize,
 }
 
+impl Debug for JumpNot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "jumpnot {} {:?}", self.cond, self.target)
+    }
+}
+
 /// A jump with an attached condition.
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ConditionalJump {
-    pub branch: JumpBranch,
+    pub target: Label,
     pub condition: Condition,
 }
 
 impl Debug for ConditionalJump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "jump{:?} {:?}", self.condition, self.target)
+    }
+}
+
+/// Prepares a numeric `for` loop over the counter at stack index `index`, which holds the
+/// initial value, the limit at `limit` and the step at `step`. Jumps past the loop if it
+/// would not run at all, otherwise copies the counter into the loop variable at `var`.
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ForNumPrep {
+    pub target: Label,
+
+    pub index: usize,
+    pub limit: usize,
+    pub step: usize,
+    pub var: usize,
+}
+
+impl Debug for ForNumPrep {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "jump{:?} {} {_com:>12} {}",
-            self.condition,
-            self.branch.end,
-            self.branch.offset,
-            _com = ";"
+            "fornprep {} {}, {}, {} {:?}",
+            self.var, self.index, self.limit, self.step, self.target
+        )
+    }
+}
+
+/// Adds the step to the counter of a numeric `for` loop set up by `ForNumPrep`. While the
+/// counter has not passed the limit, copies it into the loop variable and jumps back to the
+/// body of the loop.
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ForNumLoop {
+    pub target: Label,
+
+    pub index: usize,
+    pub limit: usize,
+    pub step: usize,
+    pub var: usize,
+}
+
+impl Debug for ForNumLoop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "fornloop {} {}, {}, {} {:?}",
+            self.var, self.index, self.limit, self.step, self.target
+        )
+    }
+}
+
+/// Prepares a generic `for` loop over the iterator function, state and control value at
+/// stack indices `base`, `base + 1` and `base + 2`, then jumps to the `ForGenCall` which
+/// produces the first values.
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ForGenPrep {
+    pub target: Label,
+
+    pub base: usize,
+}
+
+impl Debug for ForGenPrep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "forgprep {} {:?}", self.base, self.target)
+    }
+}
+
+/// Calls the iterator function of a generic `for` loop at stack index `base` with the state
+/// and control value, storing `count` results from stack index `dest`.
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ForGenCall {
+    pub base: usize,
+    pub dest: usize,
+    pub count: usize,
+}
+
+impl Debug for ForGenCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}..{} {_eq:>4} {}({}, {})",
+            self.dest,
+            self.dest + self.count,
+            self.base,
+            self.base + 1,
+            self.base + 2,
+            _eq = "="
         )
     }
 }
 
+/// Continues a generic `for` loop while the value at stack index `var` is not `nil`, by
+/// copying it into the control value at `base + 2` and jumping back to the body of the loop.
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ForGenLoop {
+    pub target: Label,
+
+    pub base: usize,
+    pub var: usize,
+}
+
+impl Debug for ForGenLoop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "forgloop {} {} {:?}", self.base, self.var, self.target)
+    }
+}
+
 /// Creates a new table at stack index `dest` with an initial size of `table_size` and
 /// `array_size` array elements.
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct NewTable {
     pub dest: usize,
 
@@ -454,6 +856,7 @@ impl Debug for NewTable {
 /// Represents either a number or a variable number of values that approach the top of the
 ///  stack.
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum OptVariable {
     Variable,
     Number(usize),
@@ -468,9 +871,35 @@ impl Debug for OptVariable {
     }
 }
 
+/// Stores `count` values starting at stack index `start` into the array part of the table
+/// at stack index `table`, the first one at index `offset + 1`.
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SetList {
+    pub table: usize,
+    pub start: usize,
+    pub count: OptVariable,
+    pub offset: usize,
+}
+
+impl Debug for SetList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}[{}..] {_eq:>4} {}, {:?}",
+            self.table,
+            self.offset + 1,
+            self.start,
+            self.count,
+            _eq = "="
+        )
+    }
+}
+
 /// Calls the function at stack index `callee` with `num_args` ahead of it on the stack,
 /// then returns a `num_returns` number of results.
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Call {
     pub callee: usize,
 
@@ -493,98 +922,473 @@ impl Debug for Call {
     }
 }
 
+/// Loads `count` of the extra arguments passed to a variadic function into the stack indices
+/// from `dest`, or all of them when the count is variable.
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VarArg {
+    pub dest: usize,
+    pub count: OptVariable,
+}
+
+impl Debug for VarArg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.count {
+            OptVariable::Variable => write!(f, "{}..top {_eq:>4} ...", self.dest, _eq = "="),
+            OptVariable::Number(count) => write!(
+                f,
+                "{}..{} {_eq:>4} ...",
+                self.dest,
+                self.dest + count,
+                _eq = "="
+            ),
+        }
+    }
+}
+
 /// Performs a return from the current chunk, passing all values fr om stack index
-/// `result_start` up to `result_start + result_count` to the caller.
+/// `result_start` up to `result_start + result_count` to the caller, or up to the top set
+/// by the previous call or vararg when the count is variable.
 #[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Return {
     pub result_start: usize,
-    pub result_count: usize,
+    pub result_count: OptVariable,
 }
 
 impl Debug for Return {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "return {}..{}", self.result_start, self.result_count)
+        match self.result_count {
+            OptVariable::Variable => write!(f, "return {}..top", self.result_start),
+            OptVariable::Number(count) => write!(f, "return {}..{}", self.result_start, count),
+        }
     }
 }
 
-/// Describes the arity of a function.
-#[derive(Clone)]
-pub enum Vararg {
-    HasArg,
-    IsVararg,
-    NeedsArg,
+/// Creates a closure of the child prototype at index `prototype` of the current function
+/// and stores it in stack index `dest`, capturing upvalues as described by the prototype.
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Closure {
+    pub dest: usize,
+    pub prototype: usize,
 }
 
-impl Debug for Vararg {
-    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::HasArg => todo!(),
-            Self::IsVararg => todo!("variadic"),
-            Self::NeedsArg => todo!(),
+impl Debug for Closure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {_eq:>4} closure {}",
+            self.dest,
+            self.prototype,
+            _eq = "="
+        )
+    }
+}
+
+/// Loads the upvalue at index `upvalue` of the current function into stack index `dest`.
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct GetUpvalue {
+    pub dest: usize,
+    pub upvalue: usize,
+}
+
+impl Debug for GetUpvalue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {_eq:>4} up{}", self.dest, self.upvalue, _eq = "=")
+    }
+}
+
+/// Stores the value at stack index `src` into the upvalue at index `upvalue` of the current
+/// function.
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SetUpvalue {
+    pub src: usize,
+    pub upvalue: usize,
+}
+
+impl Debug for SetUpvalue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "up{} {_eq:>4} {}", self.upvalue, self.src, _eq = "=")
+    }
+}
+
+/// Closes every upvalue that refers to stack index `start` or above, so that closures which
+/// captured them keep their values once the registers are reused.
+#[derive(PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Close {
+    pub start: usize,
+}
+
+impl Debug for Close {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "close {}..", self.start)
+    }
+}
+
+/// Describes where a closure captures one of its upvalues from when it is created.
+#[derive(PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Upvalue {
+    /// Whether the upvalue is a register of the enclosing function, rather than one of its
+    /// upvalues.
+    pub in_stack: bool,
+    /// The stack index or upvalue index in the enclosing function.
+    pub index: usize,
+}
+
+impl Debug for Upvalue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.in_stack {
+            write!(f, "{}", self.index)
+        } else {
+            write!(f, "up{}", self.index)
+        }
+    }
+}
+
+/// Describes the arguments a function accepts.
+#[derive(PartialEq, Eq, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Arity {
+    /// The number of fixed parameters, which are passed in the first stack indices.
+    pub params: u8,
+    /// Whether extra arguments are accepted and can be loaded with `VarArg`.
+    pub is_vararg: bool,
+    /// Whether extra arguments are also collected into the local `arg` table, as Lua 5.1 does
+    /// for compatibility with Lua 5.0. Only meaningful when `is_vararg` is set.
+    pub needs_arg: bool,
+}
+
+impl Arity {
+    /// The arity of a function taking exactly `params` arguments.
+    pub fn fixed(params: u8) -> Self {
+        Self {
+            params,
+            ..Self::default()
+        }
+    }
+
+    /// The arity of a function taking `params` arguments followed by `...`.
+    pub fn vararg(params: u8) -> Self {
+        Self {
+            params,
+            is_vararg: true,
+            needs_arg: false,
+        }
+    }
+}
+
+impl Debug for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({}", self.params)?;
+
+        if self.is_vararg {
+            write!(f, ", ...")?;
+        }
+
+        if self.needs_arg {
+            write!(f, ", arg")?;
         }
+
+        write!(f, ")")
+    }
+}
+
+/// A local variable named by debug information, which lives in `register` from the
+/// instruction `start` up to but excluding the instruction `end`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Local {
+    pub name: String,
+    pub register: usize,
+    pub start: Inst,
+    pub end: Inst,
+}
+
+impl Local {
+    /// Whether the local is live at `inst`.
+    pub fn contains(&self, inst: Inst) -> bool {
+        self.start <= inst && inst < self.end
     }
 }
 
-/// A function in LUNIR intermediate language.
+/// The debug information of a function beyond its name and the spans of its instructions,
+/// all of which is left out of stripped bytecode.
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DebugInfo {
+    /// The name of the chunk the function was compiled from, such as `@main.lua`.
+    pub source: Option<String>,
+    /// The line the definition of the function starts on, 0 for the main function.
+    pub line_defined: u32,
+    /// The line the definition of the function ends on, 0 for the main function.
+    pub last_line_defined: u32,
+    /// The named local variables, ordered by where they are declared. Their instructions
+    /// are positions in the chunk, which passes that move instructions must keep updated.
+    pub locals: Vec<Local>,
+    /// The names of the upvalues in order, or no names at all.
+    pub upvalue_names: Vec<String>,
+}
+
+impl DebugInfo {
+    /// Whether there is no debug information at all.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The local that lives in `register` at `inst`, if debug information names one. A
+    /// local declared later shadows the ones before it.
+    ///
+    /// The decompiler names the locals it recovers with this, and generates names only for
+    /// registers it finds nothing for.
+    pub fn local_at(&self, register: usize, inst: Inst) -> Option<&Local> {
+        self.locals
+            .iter()
+            .rev()
+            .find(|local| local.register == register && local.contains(inst))
+    }
+}
+
+/// A function prototype in LUNIR intermediate language, along with the prototypes of the
+/// functions nested in it.
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Function {
+    pub chunk: IlChunk,
     pub constants: Vec<Constant>,
-    pub code: Vec<u8>,
-    pub is_variadic: Vararg,
-    pub lineinfo: Vec<u32>,
+    pub prototypes: Vec<Function>,
+    pub upvalues: Vec<Upvalue>,
+    pub arity: Arity,
     pub name: Option<String>,
-    pub upvalue_count: u8,
-    pub param_count: u8,
     pub max_stack_size: u8,
+    pub debug: DebugInfo,
 }
 
 impl Debug for Function {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?} fn {:?}", self.is_variadic, self.name)
+        write!(f, "fn {:?}{:?}", self.name, self.arity)
     }
 }
 
 /// All possible LUNIR intermediate language instructions.
 #[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Instruction {
-    Load(Box<Load>),
+    /// Marks the position of a label, it performs no operation.
+    Label(Label),
+
+    Load(Load),
+    Move(Move),
+
+    Intrinsic(Intrinsic),
 
-    Intrinsic(Box<Intrinsic>),
+    GetGlobal(GetGlobal),
+    GetTable(GetTable),
+    SetTable(SetTable),
+    SelfLookup(SelfLookup),
 
-    GetGlobal(Box<GetGlobal>),
-    GetTable(Box<GetTable>),
+    BinaryOp(BinaryOp),
+    UnaryOp(UnaryOp),
+    Concat(Concat),
 
-    BinaryOp(Box<BinaryOp>),
-    UnaryOp(Box<UnaryOp>),
+    Jump(Jump),
+    JumpNot(JumpNot),
+    ConditionalJump(ConditionalJump),
 
-    Jump(Box<Jump>),
-    JumpNot(Box<JumpNot>),
-    ConditionalJump(Box<ConditionalJump>),
+    ForNumPrep(ForNumPrep),
+    ForNumLoop(ForNumLoop),
+    ForGenPrep(ForGenPrep),
+    ForGenCall(ForGenCall),
+    ForGenLoop(ForGenLoop),
 
-    NewTable(Box<NewTable>),
-    Return(Box<Return>),
+    NewTable(NewTable),
+    SetList(SetList),
+    Return(Return),
 
-    Call(Box<Call>),
-    SetGlobal(Box<SetGlobal>),
+    Call(Call),
+    VarArg(VarArg),
+    SetGlobal(SetGlobal),
+
+    Closure(Closure),
+    GetUpvalue(GetUpvalue),
+    SetUpvalue(SetUpvalue),
+    Close(Close),
 }
 
-/// A chunk of code in LUNIR's intermediate language.
-#[derive(PartialEq, Clone)]
-pub struct IlChunk(Vec<Instruction>);
+impl Instruction {
+    /// Returns the label that any instruction that may jump jumps to.
+    pub fn target(&self) -> Option<Label> {
+        match self {
+            Self::Jump(jump) => Some(jump.target),
+            Self::JumpNot(jump) => Some(jump.target),
+            Self::ConditionalJump(jump) => Some(jump.target),
+            Self::ForNumPrep(prep) => Some(prep.target),
+            Self::ForNumLoop(next) => Some(next.target),
+            Self::ForGenPrep(prep) => Some(prep.target),
+            Self::ForGenLoop(next) => Some(next.target),
+            _ => None,
+        }
+    }
+
+    /// The labels this instruction may continue at besides the next instruction.
+    pub fn branch_targets(&self) -> impl Iterator<Item = Label> {
+        self.target().into_iter()
+    }
+
+    /// Whether control never continues at the next instruction, so that this instruction
+    /// ends a basic block without falling through.
+    pub fn is_terminator(&self) -> bool {
+        matches!(self, Self::Jump(_) | Self::ForGenPrep(_) | Self::Return(_))
+    }
+}
+
+/// Maps every label defined in `instructions` to the index of its `Label` instruction,
+/// checking that each label is defined once and that every jump targets a defined label.
+pub(crate) fn resolve_labels(instructions: &[Instruction]) -> Result<HashMap<Label, usize>> {
+    let mut labels = HashMap::new();
+
+    for (index, instruction) in instructions.iter().enumerate() {
+        if let Instruction::Label(label) = instruction {
+            ensure!(
+                labels.insert(*label, index).is_none(),
+                "label {label:?} is defined more than once"
+            );
+        }
+    }
+
+    for (index, instruction) in instructions.iter().enumerate() {
+        if let Some(target) = instruction.target() {
+            ensure!(
+                labels.contains_key(&target),
+                "instruction {index} jumps to undefined label {target:?}"
+            );
+        }
+    }
+
+    Ok(labels)
+}
+
+/// A handle to an instruction of an `IlChunk`, which is its position in the chunk.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Inst(u32);
+entity_impl!(Inst, "inst");
+
+/// A chunk of code in LUNIR's intermediate language. Instructions are stored inline in one
+/// arena and referred to by `Inst` handles, so that passes can describe parts of a chunk
+/// with ranges of handles rather than copies. Each instruction may carry the span of the
+/// source code it was compiled from, which stays attached to its handle.
+#[derive(PartialEq, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(into = "schema::Chunk", try_from = "schema::Chunk")
+)]
+pub struct IlChunk {
+    instructions: PrimaryMap<Inst, Instruction>,
+    spans: SecondaryMap<Inst, Option<Span>>,
+}
 
 impl IlChunk {
-    pub fn inner(&self) -> &Vec<Instruction> {
-        &self.0
+    pub fn inner(&self) -> &[Instruction] {
+        self.instructions.values().as_slice()
     }
 
     pub fn new(inner: Vec<Instruction>) -> Self {
-        Self(inner)
+        Self {
+            instructions: inner.into_iter().collect(),
+            spans: SecondaryMap::new(),
+        }
+    }
+
+    /// Takes the instructions out of the chunk, dropping their spans.
+    pub fn into_inner(self) -> Vec<Instruction> {
+        self.instructions
+            .into_iter()
+            .map(|(_, instruction)| instruction)
+            .collect()
+    }
+
+    /// Takes the instructions out of the chunk along with their spans.
+    pub fn into_spanned(self) -> Vec<(Instruction, Option<Span>)> {
+        let spans = self.spans;
+
+        self.instructions
+            .into_iter()
+            .map(|(inst, instruction)| (instruction, spans[inst]))
+            .collect()
+    }
+
+    /// Appends `instruction` to the end of the chunk, returning its handle.
+    pub fn push(&mut self, instruction: Instruction) -> Inst {
+        self.instructions.push(instruction)
+    }
+
+    /// Appends `instruction` to the end of the chunk, attributed to `span`.
+    pub fn push_spanned(&mut self, instruction: Instruction, span: Option<Span>) -> Inst {
+        let inst = self.instructions.push(instruction);
+
+        if span.is_some() {
+            self.spans[inst] = span;
+        }
+
+        inst
+    }
+
+    pub fn get(&self, inst: Inst) -> Option<&Instruction> {
+        self.instructions.get(inst)
+    }
+
+    /// The span of the source code that `inst` was compiled from, if it is known.
+    pub fn span(&self, inst: Inst) -> Option<Span> {
+        self.spans[inst]
+    }
+
+    pub fn set_span(&mut self, inst: Inst, span: Option<Span>) {
+        self.spans[inst] = span;
+    }
+
+    /// Whether any instruction of the chunk has a span.
+    pub fn has_spans(&self) -> bool {
+        self.spans.values().any(Option::is_some)
+    }
+
+    /// The number of instructions in the chunk.
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    /// The handles of every instruction in order, along with the instructions.
+    pub fn iter(&self) -> impl Iterator<Item = (Inst, &Instruction)> {
+        self.instructions.iter()
+    }
+
+    /// Every instruction in order along with its span.
+    pub fn spanned(&self) -> impl Iterator<Item = (&Instruction, Option<Span>)> {
+        self.instructions
+            .iter()
+            .map(move |(inst, instruction)| (instruction, self.spans[inst]))
+    }
+
+    /// The instructions from `range.start` up to but excluding `range.end`.
+    pub fn range(&self, range: Range<Inst>) -> &[Instruction] {
+        &self.inner()[range.start.index()..range.end.index()]
     }
 }
 
-impl IlChunk {
-    pub(crate) fn inner_mut(&mut self) -> &mut Vec<Instruction> {
-        &mut self.0
+impl Index<Inst> for IlChunk {
+    type Output = Instruction;
+
+    fn index(&self, inst: Inst) -> &Instruction {
+        &self.instructions[inst]
     }
 }
 
@@ -592,7 +1396,7 @@ impl Debug for IlChunk {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut buf = String::with_capacity(256);
 
-        for item in &self.0 {
+        for item in self.inner() {
             buf.push_str(format!("{item:?}\n").as_str())
         }
 
@@ -602,6 +1406,6 @@ impl Debug for IlChunk {
 
 impl From<&[Instruction]> for IlChunk {
     fn from(slice: &[Instruction]) -> Self {
-        IlChunk(slice.to_vec())
+        IlChunk::new(slice.to_vec())
     }
 }
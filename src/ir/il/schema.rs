@@ -0,0 +1,272 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The shape of the intermediate language and the syntax tree when they are serialized with
+//! the `serde` feature, which is meant to be read and written by tools outside of Rust. The
+//! shape is described here in terms of JSON and only changes along with the types themselves.
+//!
+//! - Structs are objects keyed by their field names, such as `{"dest": 0, "src": "Nil"}`
+//!   for a `Load`, and tuple structs with one field are that field, so `Label(2)` is `2`.
+//! - Enums are externally tagged by the name of the variant, unit variants are that name.
+//!   A `Value` is one of `"Nil"`, `{"Boolean": true}`, `{"ConstantIndex": 0}`,
+//!   `{"Immediate": -3}` and `{"StackIndex": 1}`, and an instruction is written as
+//!   `{"Move": {"dest": 0, "src": 1}}`.
+//! - A `Constant::Number` is a JSON number, or one of the strings `"inf"`, `"-inf"` and
+//!   `"nan"` for the numbers JSON cannot hold.
+//! - A `Constant::String` is a JSON string when its bytes are valid UTF-8, and an array of
+//!   the bytes otherwise.
+//! - A `Table::Map` is an array of `[key, value]` pairs in the order of the map, as keys
+//!   are values rather than strings.
+//! - An `IlChunk` is `{"instructions": [...], "spans": [...]}`, where every span is either
+//!   `null` or `{"line": 3, "column": 0}` and belongs to the instruction at the same
+//!   position. There may be fewer spans than instructions, the rest have none.
+//! - An `Inst` is the position of the instruction in its chunk.
+//! - A `CirGraph` is `{"chunk": ..., "blocks": [...], "edges": [...]}`. Every block is
+//!   `{"start": 0, "end": 2}`, the range of the instructions it holds, and the blocks
+//!   partition the chunk in order. Every edge is `{"from": 0, "to": 1, "label": true}`
+//!   between the blocks at those positions. An edge is labeled `false` when it is taken
+//!   because the condition of a `JumpNot` is falsy, or when a loop or another conditional
+//!   jump falls through.
+//!
+//! A function is written as
+//!
+//! ```json
+//! {
+//!     "chunk": {
+//!         "instructions": [{"Return": {"result_start": 0, "result_count": {"Number": 0}}}],
+//!         "spans": []
+//!     },
+//!     "constants": [{"String": "print"}, {"Number": 1.5}],
+//!     "prototypes": [],
+//!     "upvalues": [{"in_stack": true, "index": 0}],
+//!     "arity": {"params": 0, "is_vararg": true, "needs_arg": false},
+//!     "name": null,
+//!     "max_stack_size": 2,
+//!     "debug": {
+//!         "source": "@main.lua",
+//!         "line_defined": 0,
+//!         "last_line_defined": 0,
+//!         "locals": [{"name": "x", "register": 0, "start": 0, "end": 1}],
+//!         "upvalue_names": []
+//!     }
+//! }
+//! ```
+//!
+//! The syntax tree of [`ast::tree`](crate::ir::ast::tree) follows the same rules.
+//!
+//! - A `Node` is `{"block": {"statements": [...]}}`.
+//! - A `Statement`, an `Expression` and a `Type` are `{"kind": ..., "span": ...}`, where the
+//!   span is where they start, and a `Name` is `{"name": "x", "span": ...}`. Spans of the
+//!   syntax tree are never `null`.
+//! - Operators are the names of their variants, such as `"Add"` and `"Not"`.
+//! - `ExpressionKind::Number` is written like `Constant::Number`, and the strings of
+//!   `ExpressionKind::String`, `ExpressionKind::InterpolatedString` and `TypeKind::String`
+//!   like `Constant::String`.
+//!
+//! The Luau statement ``return `a{b}\xff`, 1e999`` is written as
+//!
+//! ```json
+//! {"block": {"statements": [{
+//!     "kind": {"Return": [
+//!         {
+//!             "kind": {"InterpolatedString": {
+//!                 "strings": ["a", [255]],
+//!                 "expressions": [{
+//!                     "kind": {"Name": {"name": "b", "span": {"line": 1, "column": 11}}},
+//!                     "span": {"line": 1, "column": 11}
+//!                 }]
+//!             }},
+//!             "span": {"line": 1, "column": 8}
+//!         },
+//!         {"kind": {"Number": "inf"}, "span": {"line": 1, "column": 20}}
+//!     ]},
+//!     "span": {"line": 1, "column": 1}
+//! }]}}
+//! ```
+
+use super::{IlChunk, Instruction};
+use crate::ir::span::Span;
+use anyhow::{ensure, Error, Result};
+use serde::{Deserialize, Serialize};
+
+/// The serialized form of an `IlChunk`.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Chunk {
+    instructions: Vec<Instruction>,
+    #[serde(default)]
+    spans: Vec<Option<Span>>,
+}
+
+impl From<IlChunk> for Chunk {
+    fn from(chunk: IlChunk) -> Self {
+        let (instructions, spans) = chunk.into_spanned().into_iter().unzip();
+
+        Self {
+            instructions,
+            spans,
+        }
+    }
+}
+
+impl TryFrom<Chunk> for IlChunk {
+    type Error = Error;
+
+    fn try_from(chunk: Chunk) -> Result<Self> {
+        ensure!(
+            chunk.spans.len() <= chunk.instructions.len(),
+            "{} spans were given for {} instructions",
+            chunk.spans.len(),
+            chunk.instructions.len()
+        );
+
+        let mut spans = chunk.spans.into_iter();
+        let mut result = IlChunk::default();
+
+        for instruction in chunk.instructions {
+            result.push_spanned(instruction, spans.next().flatten());
+        }
+
+        Ok(result)
+    }
+}
+
+/// Writes numbers that JSON cannot hold as strings.
+pub(crate) mod number {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Number {
+        Finite(f64),
+        Named(String),
+    }
+
+    pub(crate) fn serialize<S: Serializer>(n: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        let number = match *n {
+            n if n.is_nan() => Number::Named("nan".to_owned()),
+            n if n == f64::INFINITY => Number::Named("inf".to_owned()),
+            n if n == f64::NEG_INFINITY => Number::Named("-inf".to_owned()),
+            n => Number::Finite(n),
+        };
+
+        number.serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        match Number::deserialize(deserializer)? {
+            Number::Finite(n) => Ok(n),
+            Number::Named(name) => match name.as_str() {
+                "nan" => Ok(f64::NAN),
+                "inf" => Ok(f64::INFINITY),
+                "-inf" => Ok(f64::NEG_INFINITY),
+                _ => Err(D::Error::custom(format!("`{name}` is not a number"))),
+            },
+        }
+    }
+}
+
+/// Writes byte strings as strings when they are valid UTF-8 and as arrays of bytes otherwise.
+pub(crate) mod bytes {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Bytes {
+        Text(String),
+        Binary(Vec<u8>),
+    }
+
+    pub(crate) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => serializer.serialize_str(text),
+            Err(_) => serializer.collect_seq(bytes),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        Ok(match Bytes::deserialize(deserializer)? {
+            Bytes::Text(text) => text.into_bytes(),
+            Bytes::Binary(bytes) => bytes,
+        })
+    }
+}
+
+/// Writes a list of byte strings like `bytes` writes each of them.
+pub(crate) mod byte_strings {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    struct Borrowed<'a>(&'a [u8]);
+
+    impl Serialize for Borrowed<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            super::bytes::serialize(self.0, serializer)
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct Owned(#[serde(deserialize_with = "super::bytes::deserialize")] Vec<u8>);
+
+    pub(crate) fn serialize<S: Serializer>(
+        strings: &[Vec<u8>],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(strings.iter().map(|string| Borrowed(string)))
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Vec<u8>>, D::Error> {
+        Ok(Vec::<Owned>::deserialize(deserializer)?
+            .into_iter()
+            .map(|string| string.0)
+            .collect())
+    }
+}
+
+/// Writes maps whose keys are not strings as arrays of key and value pairs.
+pub(crate) mod pairs {
+    use indexmap::IndexMap;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::hash::Hash;
+
+    pub(crate) fn serialize<K, V, S>(map: &IndexMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map)
+    }
+
+    pub(crate) fn deserialize<'de, K, V, D>(deserializer: D) -> Result<IndexMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Hash + Eq,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Ok(Vec::<(K, V)>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
@@ -0,0 +1,764 @@
+#![cfg(test)]
+use super::{
+    interpreter::{Interpreter, LuaValue, Semantics},
+    *,
+};
+use crate::ir::span::Span;
+use std::collections::{hash_map::DefaultHasher, HashSet};
+
+fn hash(constant: &Constant) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    constant.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[test]
+fn numbers_compare_by_bits() {
+    assert_eq!(Constant::Number(f64::NAN), Constant::Number(-f64::NAN));
+    assert_eq!(
+        hash(&Constant::Number(f64::NAN)),
+        hash(&Constant::Number(-f64::NAN))
+    );
+
+    assert_ne!(Constant::Number(0.0), Constant::Number(-0.0));
+    assert_ne!(Constant::Number(1.0), Constant::Integer(1));
+
+    let constants = [
+        Constant::Number(1.0),
+        Constant::Integer(1),
+        Constant::Number(1.0),
+        Constant::String(b"1".to_vec()),
+    ]
+    .into_iter()
+    .collect::<HashSet<_>>();
+
+    assert_eq!(constants.len(), 3);
+}
+
+#[test]
+fn table_maps_keep_their_order() {
+    let table = |keys: &[i32]| {
+        Table::Map(
+            keys.iter()
+                .map(|&key| (Value::Immediate(key), Value::Boolean(true)))
+                .collect(),
+        )
+    };
+
+    let forward = table(&[3, 1, 2]);
+
+    assert_eq!(
+        format!("{forward:?}"),
+        "{[3]    = true, [1]    = true, [2]    = true, }"
+    );
+
+    assert_eq!(forward, table(&[3, 1, 2]));
+    assert_ne!(forward, table(&[1, 2, 3]));
+    assert_eq!(
+        hash(&Constant::Table(forward)),
+        hash(&Constant::Table(table(&[3, 1, 2])))
+    );
+}
+
+const LISTING: &str = r#"function "main" (0, ...)
+    stack 12
+    source "@main.lua"
+    upvalue r0 "a"
+    upvalue u1 "b"
+    k0 = nil
+    k1 = true
+    k2 = 1.5
+    k3 = -0.0
+    k4 = 1e100
+    k5 = inf
+    k6 = -7
+    k7 = "say \"hi\"; bye\n"
+    k8 = [nil, k2, r1, 3]
+    k9 = {k7 = true, -1 = k2}
+    k10 = []
+    k11 = {}
+    k12 = "caf\xe9 \\ \0"
+    local "x" r0 1..2
+    local "y" r1 2..56
+    L0: @1
+    r0 = nil @1
+    r0 = k1
+    r0 = -3
+    r0 = move r1
+    r0 = global k7
+    global k7 = r0
+    r0 = r1[k7]
+    r0[r1] = false
+    r0 = r1:k7 @2:9
+    r0 = r1 + k2
+    r0 = r1 - -2
+    r0 = r1 // r2
+    r0 = r1 .. r2
+    r0 = not r1
+    r0 = neg k2
+    r0 = len r1
+    r0 = concat r1..r3
+    r0 = band r1, 7
+    r0 = bit32.bnot r1
+    r0 = bit.rshift r1, r2
+    jump L1
+    jumpnot r0 L0
+    jumpif r0 lt k2 L1
+    fornprep r0, r1, r2, r3 L1
+    fornloop r0, r1, r2, r3 L0
+    forgprep r4 L1
+    forgcall r4, r7, 2
+    forgloop r4, r7 L0
+    r0 = newtable 4, 2
+    setlist r0, r1, top, 50
+    call r0, 2, top
+    selfcall r0, top, 1
+    vararg r0, top
+    r0 = closure f1
+    r0 = u1
+    u1 = r0
+    close r2
+    L1:
+    return r0, 1 @3
+    function (1, ..., arg)
+        stack 2
+        return r0, 0
+        function (0)
+            stack 0
+            return r0, 0
+        end
+    end
+    function "f" (2)
+        stack 2
+        defined 3, 5
+        upvalue u0 "x"
+        return r0, 0
+    end
+end
+"#;
+
+#[test]
+fn text_round_trips() {
+    let function = LISTING.parse::<Function>().unwrap();
+
+    assert_eq!(function.to_string(), LISTING);
+
+    assert_eq!(function.name.as_deref(), Some("main"));
+    assert_eq!(function.arity, Arity::vararg(0));
+    assert_eq!(function.constants[3], Constant::Number(-0.0));
+    assert_eq!(function.constants[6], Constant::Integer(-7));
+    assert_eq!(
+        function.constants[7],
+        Constant::String(b"say \"hi\"; bye\n".to_vec())
+    );
+    assert_eq!(
+        function.constants[12],
+        Constant::String(b"caf\xe9 \\ \0".to_vec())
+    );
+    assert_eq!(
+        function.upvalues[1],
+        Upvalue {
+            in_stack: false,
+            index: 1
+        }
+    );
+    assert_eq!(function.prototypes.len(), 2);
+    assert_eq!(function.prototypes[0].prototypes.len(), 1);
+    assert_eq!(function.prototypes[1].name.as_deref(), Some("f"));
+
+    assert_eq!(function.debug.source.as_deref(), Some("@main.lua"));
+    assert_eq!(function.debug.upvalue_names, vec!["a", "b"]);
+    assert_eq!(function.prototypes[1].debug.line_defined, 3);
+    assert_eq!(
+        function.debug.local_at(1, Inst::new(10)),
+        Some(&Local {
+            name: "y".to_owned(),
+            register: 1,
+            start: Inst::new(2),
+            end: Inst::new(56),
+        })
+    );
+    assert_eq!(function.debug.local_at(0, Inst::new(2)), None);
+
+    let spans = function
+        .chunk
+        .spanned()
+        .map(|(_, span)| span)
+        .collect::<Vec<_>>();
+
+    assert_eq!(spans[1], Some(Span::line(1)));
+    assert_eq!(spans[2], None);
+    assert_eq!(spans[9], Some(Span::new(2, 9)));
+
+    let chunk = function.chunk.inner();
+
+    assert_eq!(chunk[0], Instruction::Label(Label(0)));
+    assert_eq!(
+        chunk[11],
+        Instruction::BinaryOp(BinaryOp {
+            operator: BinaryOpKind::Sub,
+            dest: 0,
+            left: Value::StackIndex(1),
+            right: Value::Immediate(-2),
+        })
+    );
+    assert_eq!(
+        chunk[19],
+        Instruction::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::BitNot(Value::StackIndex(1)),
+            dest: 0,
+            source: IntrinsicSource::Bit32,
+        })
+    );
+    assert_eq!(
+        chunk[23],
+        Instruction::ConditionalJump(ConditionalJump {
+            target: Label(1),
+            condition: Condition {
+                kind: ConditionKind::Lt,
+                left: Value::StackIndex(0),
+                right: Value::ConstantIndex(2),
+            },
+        })
+    );
+}
+
+#[test]
+fn chunks_parse_without_a_function() {
+    let chunk = "
+        ; comments and blank lines are skipped
+        r0 = r0
+        jump L0
+
+        L0:
+        return r0, 0 ; trailing comment
+    "
+    .parse::<IlChunk>()
+    .unwrap();
+
+    assert_eq!(
+        chunk.inner(),
+        &vec![
+            Instruction::Load(Load {
+                dest: 0,
+                src: Value::StackIndex(0),
+            }),
+            Instruction::Jump(Jump { target: Label(0) }),
+            Instruction::Label(Label(0)),
+            Instruction::Return(Return {
+                result_start: 0,
+                result_count: OptVariable::Number(0),
+            }),
+        ]
+    );
+    assert_eq!(chunk.to_string().parse::<IlChunk>().unwrap(), chunk);
+}
+
+#[test]
+fn text_errors_name_their_line() {
+    let error = |text: &str| text.parse::<Function>().unwrap_err().to_string();
+
+    assert_eq!(
+        error("function (0)\n    r0 = r1 +\nend"),
+        "failed to parse line 2:     r0 = r1 +"
+    );
+    assert_eq!(
+        error("function (0)\n    function (0)\n        k1 = nil\n    end\nend"),
+        "failed to parse line 3:         k1 = nil"
+    );
+    assert!(error("function (0)\n    return r0, 0").contains("expected `end`"));
+    assert!("jump 0".parse::<IlChunk>().is_err());
+    assert!("r0 = k1 k2".parse::<IlChunk>().is_err());
+}
+
+#[test]
+fn verifier_reports_every_problem() {
+    let function = r#"function (0)
+    stack 2
+    k0 = "x"
+    r0 = k1
+    r2 = r3 + r1
+    jump L1
+    L0:
+    L0:
+    r1 = u0
+    r0 = closure f0
+    r0 = r1 + r0
+end
+"#
+    .parse::<Function>()
+    .unwrap();
+
+    let problems = function.chunk.verify(&function).unwrap_err().0;
+    let kinds = problems
+        .iter()
+        .map(|problem| (problem.instruction, problem.kind.clone()))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        kinds,
+        vec![
+            (0, ProblemKind::ConstantIndex { index: 1, count: 1 }),
+            (
+                1,
+                ProblemKind::StackIndex {
+                    index: 2,
+                    max_stack_size: 2
+                }
+            ),
+            (
+                1,
+                ProblemKind::StackIndex {
+                    index: 3,
+                    max_stack_size: 2
+                }
+            ),
+            (2, ProblemKind::UndefinedLabel(Label(1))),
+            (4, ProblemKind::DuplicateLabel(Label(0))),
+            (5, ProblemKind::UpvalueIndex { index: 0, count: 0 }),
+            (6, ProblemKind::PrototypeIndex { index: 0, count: 0 }),
+            (8, ProblemKind::MissingReturn),
+        ]
+    );
+}
+
+#[test]
+fn verifier_walks_nested_functions() {
+    let function = "function (0)
+    stack 1
+    r0 = closure f0
+    return r0, 1
+    function (0)
+        stack 1
+        return r0, 1
+    end
+    function (0)
+        stack 1
+        jump L0
+        return r0, 1
+    end
+end
+"
+    .parse::<Function>()
+    .unwrap();
+
+    assert_eq!(function.chunk.verify(&function), Ok(()));
+    assert_eq!(
+        function.verify().unwrap_err().to_string(),
+        "malformed intermediate language: instruction 0 of prototype 1: jump to undefined label L0"
+    );
+}
+
+fn interpret(semantics: Semantics, text: &str) -> (Interpreter, anyhow::Result<Vec<LuaValue>>) {
+    let function = text.parse::<Function>().unwrap();
+    let mut interpreter = Interpreter::new()
+        .semantics(semantics)
+        .max_steps(Some(1000))
+        .with_base_library();
+
+    let results = interpreter.run(&function, vec![LuaValue::Integer(1), LuaValue::Integer(2)]);
+
+    (interpreter, results)
+}
+
+#[test]
+fn closures_share_their_upvalues() {
+    let (_, results) = interpret(
+        Semantics::Lua51,
+        "function (0)
+    stack 3
+    r0 = 0
+    r1 = closure f0
+    r2 = move r1
+    call r2, 0, 0
+    r2 = move r1
+    call r2, 0, 1
+    return r0, 3
+    function (0)
+        stack 1
+        upvalue r0
+        r0 = u0
+        r0 = r0 + 1
+        u0 = r0
+        return r0, 1
+    end
+end
+",
+    );
+
+    let results = results.unwrap();
+    assert_eq!(results[0], LuaValue::Number(2.0));
+    assert_eq!(results[2], LuaValue::Number(2.0));
+}
+
+#[test]
+fn varargs_and_results_fill_the_stack() {
+    let (interpreter, results) = interpret(
+        Semantics::Lua51,
+        r#"function (0, ...)
+    stack 6
+    k0 = "print"
+    r0 = closure f0
+    vararg r1, top
+    call r0, top, top
+    r3 = global k0
+    r4 = move r0
+    r5 = move r1
+    call r3, 2, 0
+    return r0, 2
+    function (0, ...)
+        stack 3
+        vararg r0, 2
+        r2 = move r0
+        return r1, 2
+    end
+end
+"#,
+    );
+
+    assert_eq!(
+        results.unwrap(),
+        vec![LuaValue::Integer(2), LuaValue::Integer(1)]
+    );
+    assert_eq!(interpreter.output(), ["2\t1"]);
+}
+
+#[test]
+fn metatables_handle_missing_operations() {
+    let (_, results) = interpret(
+        Semantics::Lua51,
+        r#"function (0)
+    stack 6
+    k0 = "setmetatable"
+    k1 = "__index"
+    k2 = "__add"
+    k3 = "x"
+    r0 = newtable 0, 0
+    r1 = newtable 0, 2
+    r2 = closure f0
+    r1[k1] = r2
+    r1[k2] = r2
+    r2 = global k0
+    r3 = move r0
+    r4 = move r1
+    call r2, 2, 0
+    r1 = r0[k3]
+    r2 = r0 + 5
+    return r1, 2
+    function (2)
+        stack 2
+        return r1, 1
+    end
+end
+"#,
+    );
+
+    assert_eq!(
+        results.unwrap(),
+        vec![LuaValue::string("x"), LuaValue::Number(5.0)]
+    );
+}
+
+const NUMBERS: &str = r#"function (0)
+    stack 4
+    k0 = 7
+    k1 = 2
+    k2 = ""
+    r0 = k0 // k1
+    r1 = k0 / k1
+    r2 = -7 % k1
+    r3 = k1 / k1
+    r3 = r3 .. k2
+    return r0, 4
+end
+"#;
+
+#[test]
+fn numbers_follow_the_version() {
+    let (_, results) = interpret(Semantics::Lua51, NUMBERS);
+    let results = results.unwrap();
+
+    assert!(matches!(results[0], LuaValue::Number(n) if n == 3.0));
+    assert!(matches!(results[2], LuaValue::Number(n) if n == 1.0));
+    assert_eq!(results[3], LuaValue::string("1"));
+
+    let (_, results) = interpret(Semantics::Lua53, NUMBERS);
+    let results = results.unwrap();
+
+    assert!(matches!(results[0], LuaValue::Integer(3)));
+    assert!(matches!(results[1], LuaValue::Number(n) if n == 3.5));
+    assert!(matches!(results[2], LuaValue::Integer(1)));
+    assert_eq!(results[3], LuaValue::string("1.0"));
+
+    let (_, results) = interpret(Semantics::Lua53, &NUMBERS.replace("k0 // k1", "k0 // 0"));
+    assert!(format!("{:#}", results.unwrap_err()).ends_with("attempt to perform 'n//0'"));
+
+    let (_, results) = interpret(Semantics::Lua53, &NUMBERS.replace("-7 % k1", "-7 % 0"));
+    assert!(format!("{:#}", results.unwrap_err()).ends_with("attempt to perform 'n%0'"));
+}
+
+#[test]
+fn integers_and_floats_compare_exactly() {
+    // 2^53 + 1 rounds to 2^53 as a float
+    let float = LuaValue::Number(9_007_199_254_740_992.0);
+    let integer = LuaValue::Integer(9_007_199_254_740_993);
+
+    assert_ne!(float, integer);
+    assert_eq!(float, LuaValue::Integer(9_007_199_254_740_992));
+    assert_ne!(LuaValue::Number(0.5), LuaValue::Integer(0));
+
+    let (_, results) = interpret(
+        Semantics::Lua53,
+        r#"function (0)
+    stack 4
+    k0 = 9007199254740992.0
+    k1 = 9007199254740993
+    k2 = 1e100
+    r0 = false
+    r1 = false
+    r2 = false
+    r3 = false
+    jumpif k0 ge k1 L0
+    r0 = true
+    L0:
+    jumpif k1 le k0 L1
+    r1 = true
+    L1:
+    jumpif k2 le k1 L2
+    r2 = true
+    L2:
+    jumpif k0 ne k1 L3
+    r3 = true
+    L3:
+    return r0, 4
+end
+"#,
+    );
+
+    assert_eq!(
+        results.unwrap(),
+        vec![
+            LuaValue::Boolean(true),
+            LuaValue::Boolean(true),
+            LuaValue::Boolean(true),
+            LuaValue::Boolean(false),
+        ]
+    );
+}
+
+#[test]
+fn strings_hold_bytes() {
+    let (_, results) = interpret(
+        Semantics::Lua53,
+        r#"function (0)
+    stack 2
+    k0 = "\xff"
+    r0 = len k0
+    r1 = k0 .. k0
+    return r0, 2
+end
+"#,
+    );
+    let results = results.unwrap();
+
+    assert!(matches!(results[0], LuaValue::Integer(1)));
+    assert_eq!(results[1], LuaValue::string(b"\xff\xff"));
+}
+
+#[test]
+fn loops_stop_at_the_step_limit() {
+    let (interpreter, results) = interpret(
+        Semantics::Lua53,
+        r#"function (0)
+    stack 6
+    k0 = "print"
+    r0 = 1
+    r1 = 3
+    r2 = 1
+    fornprep r0, r1, r2, r3 L1
+    L0:
+    r4 = global k0
+    r5 = move r3
+    call r4, 1, 0
+    fornloop r0, r1, r2, r3 L0
+    L1:
+    L2:
+    jump L2
+end
+"#,
+    );
+
+    assert_eq!(interpreter.output(), ["1", "2", "3"]);
+    assert_eq!(interpreter.steps(), 1000);
+    assert!(format!("{:#}", results.unwrap_err()).ends_with("exceeded the limit of 1000 steps"));
+}
+
+#[test]
+fn instructions_describe_their_slots_and_effects() {
+    let chunk = "r0 = r1 + k0
+selfcall r2, 1, top
+r4 = bit32.band r1, 3
+jumpnot r0 L0
+L0:
+return r0, 2
+return r2, top
+"
+    .parse::<IlChunk>()
+    .unwrap();
+    let instructions = chunk.inner();
+
+    let add = &instructions[0];
+    assert_eq!(add.defs().fixed.as_slice(), [0]);
+    assert_eq!(add.uses().fixed.as_slice(), [1]);
+    assert_eq!(format!("{:?}", add.effects()), "METAMETHOD | THROW");
+
+    let call = &instructions[1];
+    assert_eq!(call.uses().fixed.as_slice(), [2, 3, 4]);
+    assert_eq!(call.defs().variable, Some(2));
+    assert!(call.defs().contains(9));
+    assert!(call.effects().runs_code());
+
+    let library = instructions[2].effects();
+    assert!(library.contains(Effects::READ_GLOBAL | Effects::CALL));
+    assert!(!library.intersects(Effects::WRITE_GLOBAL));
+
+    let jump = &instructions[3];
+    assert_eq!(jump.branch_targets().collect::<Vec<_>>(), [Label(0)]);
+    assert!(!jump.is_terminator());
+    assert!(instructions[5].is_terminator());
+    assert!(instructions[4].effects().is_empty());
+
+    assert_eq!(instructions[5].uses().fixed.as_slice(), [0, 1]);
+    assert_eq!(instructions[6].uses().variable, Some(2));
+    assert_eq!(format!("{:?}", instructions[6]), "Return(return 2..top)");
+}
+
+#[test]
+fn visitors_reach_nested_functions_and_tables() {
+    #[derive(Default)]
+    struct Constants(Vec<usize>);
+
+    impl IlVisitor for Constants {
+        fn visit_constant_index(&mut self, index: usize) {
+            self.0.push(index);
+        }
+    }
+
+    let function = "function (0)
+    stack 2
+    k0 = \"x\"
+    k1 = [k0, 2]
+    r0 = global k0
+    return r0, 1
+    function (0)
+        stack 2
+        k0 = 1
+        r0 = r1 + k0
+        return r0, 1
+    end
+end
+"
+    .parse::<Function>()
+    .unwrap();
+
+    let mut constants = Constants::default();
+    constants.visit_function(&function);
+
+    assert_eq!(constants.0, [0, 0, 0]);
+}
+
+#[test]
+fn rewriters_renumber_remap_and_instrument() {
+    /// Moves every register up by one, swaps the two constants and stores every result.
+    struct Shift;
+
+    impl IlRewriter for Shift {
+        fn rewrite_register(&mut self, index: &mut usize) {
+            *index += 1;
+        }
+
+        fn rewrite_constant_index(&mut self, index: &mut usize) {
+            *index = 1 - *index;
+        }
+
+        fn insert_before(&mut self, instruction: &Instruction) -> Vec<Instruction> {
+            match instruction {
+                // inserted instructions are not rewritten themselves
+                Instruction::Return(_) => vec![Instruction::SetGlobal(SetGlobal {
+                    src: 1,
+                    constant: 0,
+                })],
+                _ => vec![],
+            }
+        }
+    }
+
+    let mut function = "function (0)
+    stack 3
+    k0 = \"x\"
+    k1 = {k0 = k1}
+    r0 = r1[k1] @2:3
+    jumpnot r0 L0
+    L0:
+    return r0, 1 @4
+end
+"
+    .parse::<Function>()
+    .unwrap();
+
+    Shift.rewrite_function(&mut function);
+
+    assert_eq!(
+        function.to_string(),
+        "function (0)
+    stack 3
+    k0 = \"x\"
+    k1 = {k1 = k0}
+    r1 = r2[k0] @2:3
+    jumpnot r1 L0
+    L0:
+    global k0 = r1 @4
+    return r1, 1 @4
+end
+"
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn functions_round_trip_through_json() {
+    let function = LISTING.parse::<Function>().unwrap();
+
+    let json = serde_json::to_string(&function).unwrap();
+    let back = serde_json::from_str::<Function>(&json).unwrap();
+
+    assert_eq!(back.to_string(), LISTING);
+
+    let value = serde_json::to_value(&function).unwrap();
+
+    assert_eq!(
+        value["constants"][5],
+        serde_json::json!({ "Number": "inf" })
+    );
+    assert_eq!(
+        value["constants"][12],
+        serde_json::json!({ "String": [99, 97, 102, 233, 32, 92, 32, 0] })
+    );
+    assert_eq!(
+        value["chunk"]["instructions"][0],
+        serde_json::json!({ "Label": 0 })
+    );
+    assert_eq!(
+        value["chunk"]["spans"][9],
+        serde_json::json!({ "line": 2, "column": 9 })
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn chunks_reject_spans_without_instructions() {
+    let json = r#"{"instructions": [], "spans": [null]}"#;
+
+    assert!(serde_json::from_str::<IlChunk>(json).is_err());
+    assert!(serde_json::from_str::<IlChunk>(r#"{"instructions": []}"#).is_ok());
+}
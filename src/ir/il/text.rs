@@ -0,0 +1,1356 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The textual form of the intermediate language, which `IlChunk` and `Function` print with
+//! `Display` and parse with `FromStr`. Unlike the `Debug` listing it names every operand
+//! unambiguously, so that printing and parsing round-trip exactly.
+//!
+//! Every line holds one item, and `;` starts a comment that runs to the end of the line.
+//! Operands are written as
+//!
+//! - `r0` for stack index 0, `k0` for constant 0, `u0` for upvalue 0, `f0` for the nested
+//!   function 0 and `L0` for label 0,
+//! - `nil`, `true`, `false` or an integer such as `-3` for the remaining values,
+//! - a number or `top` for counts that may be variable.
+//!
+//! Strings are quoted and escaped like Rust strings, and may also hold arbitrary bytes
+//! written as `\x` followed by two hex digits, such as `"\xff"`.
+//!
+//! Instructions are written as follows, where `a` and `b` are any values.
+//!
+//! ```text
+//! L0:                          ; label
+//! r0 = a                       ; load
+//! r0 = move r1
+//! r0 = global k0
+//! global k0 = r0
+//! r0 = r1[a]                   ; table index
+//! r0[a] = b                    ; table store
+//! r0 = r1:a                    ; method lookup
+//! r0 = a + b                   ; also - * / // % ^ and ..
+//! r0 = not a                   ; also neg and len
+//! r0 = concat r1..r3
+//! r0 = band a, b               ; also bor, bxor, bnot a, lshift and rshift
+//! r0 = bit32.band a, b         ; the same operation called from a library
+//! jump L0
+//! jumpnot r0 L0
+//! jumpif a lt b L0             ; also eq, ne, le, gt, ge, and and or
+//! fornprep r0, r1, r2, r3 L0   ; counter, limit, step and loop variable
+//! fornloop r0, r1, r2, r3 L0
+//! forgprep r0 L0
+//! forgcall r0, r3, 2           ; iterator, first result and result count
+//! forgloop r0, r3 L0           ; iterator and loop variable
+//! r0 = newtable 4, 2           ; array and hash sizes
+//! setlist r0, r1, 3, 50        ; table, first value, count and offset
+//! call r0, 2, top              ; callee, argument count and result count
+//! selfcall r0, 2, 1            ; a call whose self argument was set up by a lookup
+//! vararg r0, top
+//! return r0, 1                 ; first result and result count, also top
+//! r0 = closure f0
+//! r0 = u0
+//! u0 = r0
+//! close r0
+//! ```
+//!
+//! Any instruction may be followed by the span of the source code it was compiled from,
+//! such as `r0 = global k0 @3` for line 3 or `@3:5` for line 3, column 5.
+//!
+//! A function is a block that begins with its name and arity and ends with `end`. Its
+//! stack size, debug information, upvalues and constants precede its code, and the
+//! functions nested in it follow it, in order.
+//!
+//! ```text
+//! function "main" (0, ...)
+//!     stack 2
+//!     source "@main.lua"
+//!     defined 0, 0              ; the lines the definition starts and ends on
+//!     upvalue r0                ; captured from stack index 0 of the enclosing function
+//!     upvalue u1 "x"            ; captured from upvalue 1 of the enclosing function, named x
+//!     k0 = "print"
+//!     k1 = 1.5                  ; integers have no fractional part, such as k2 = 1
+//!     k2 = [nil, k0]            ; an array, maps are written as {k0 = true}
+//!     local "f" r1 2..3         ; lives in r1 from instruction 2 up to instruction 3
+//!     r0 = global k0 @1
+//!     r1 = closure f0 @1
+//!     return r0, 0 @2
+//!     function (1, ..., arg)     ; unnamed, with the implicit `arg` table of Lua 5.1
+//!         return r0, 0
+//!     end
+//! end
+//! ```
+
+use super::*;
+use crate::ir::span::Span;
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+use std::{
+    fmt::{Display, Write},
+    str::FromStr,
+};
+
+fn value(value: &Value) -> String {
+    match value {
+        Value::Nil => "nil".to_owned(),
+        Value::Boolean(b) => b.to_string(),
+        Value::ConstantIndex(index) => format!("k{index}"),
+        Value::Immediate(n) => n.to_string(),
+        Value::StackIndex(index) => format!("r{index}"),
+    }
+}
+
+fn count(count: &OptVariable) -> String {
+    match count {
+        OptVariable::Variable => "top".to_owned(),
+        OptVariable::Number(n) => n.to_string(),
+    }
+}
+
+/// Quotes a byte string, escaping the bytes that are not valid UTF-8.
+fn quote(s: &[u8]) -> String {
+    if let Ok(s) = std::str::from_utf8(s) {
+        return format!("{s:?}");
+    }
+
+    let mut quoted = String::from('"');
+
+    for &byte in s {
+        match byte {
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            b'\0' => quoted.push_str("\\0"),
+            b' '..=b'~' => quoted.push(byte as char),
+            _ => write!(quoted, "\\x{byte:02x}").unwrap(),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+fn constant(constant: &Constant) -> String {
+    match constant {
+        Constant::Nil => "nil".to_owned(),
+        Constant::Boolean(b) => b.to_string(),
+        Constant::Number(n) => format!("{n:?}"),
+        Constant::Integer(n) => n.to_string(),
+        Constant::String(s) => quote(s),
+        Constant::Table(Table::Array(array)) => {
+            let items = array.iter().map(value).collect::<Vec<_>>();
+
+            format!("[{}]", items.join(", "))
+        }
+        Constant::Table(Table::Map(map)) => {
+            let entries = map
+                .iter()
+                .map(|(key, item)| format!("{} = {}", value(key), value(item)))
+                .collect::<Vec<_>>();
+
+            format!("{{{}}}", entries.join(", "))
+        }
+    }
+}
+
+fn condition(kind: &ConditionKind) -> &'static str {
+    match kind {
+        ConditionKind::Eq => "eq",
+        ConditionKind::Ge => "ge",
+        ConditionKind::Gt => "gt",
+        ConditionKind::Ne => "ne",
+        ConditionKind::Lt => "lt",
+        ConditionKind::Le => "le",
+        ConditionKind::And => "and",
+        ConditionKind::Or => "or",
+    }
+}
+
+fn unary(kind: &UnaryOpKind) -> &'static str {
+    match kind {
+        UnaryOpKind::Len => "len",
+        UnaryOpKind::Not => "not",
+        UnaryOpKind::Neg => "neg",
+    }
+}
+
+fn instruction(instruction: &Instruction) -> String {
+    match instruction {
+        Instruction::Label(label) => format!("{label:?}:"),
+        Instruction::Load(load) => format!("r{} = {}", load.dest, value(&load.src)),
+        Instruction::Move(mv) => format!("r{} = move r{}", mv.dest, mv.src),
+        Instruction::Intrinsic(intrinsic) => {
+            let operands = intrinsic
+                .kind
+                .operands()
+                .into_iter()
+                .map(value)
+                .collect::<Vec<_>>();
+
+            let library = match intrinsic.source.library() {
+                Some(library) => format!("{library}."),
+                None => String::new(),
+            };
+
+            format!(
+                "r{} = {library}{} {}",
+                intrinsic.dest,
+                intrinsic.kind.library_name(),
+                operands.join(", ")
+            )
+        }
+        Instruction::GetGlobal(get) => format!("r{} = global k{}", get.dest, get.constant),
+        Instruction::SetGlobal(set) => format!("global k{} = r{}", set.constant, set.src),
+        Instruction::GetTable(get) => {
+            format!("r{} = r{}[{}]", get.dest, get.source, value(&get.key))
+        }
+        Instruction::SetTable(set) => {
+            format!(
+                "r{}[{}] = {}",
+                set.table,
+                value(&set.key),
+                value(&set.value)
+            )
+        }
+        Instruction::SelfLookup(lookup) => {
+            format!(
+                "r{} = r{}:{}",
+                lookup.dest,
+                lookup.object,
+                value(&lookup.key)
+            )
+        }
+        Instruction::BinaryOp(op) => format!(
+            "r{} = {} {:?} {}",
+            op.dest,
+            value(&op.left),
+            op.operator,
+            value(&op.right)
+        ),
+        Instruction::UnaryOp(op) => {
+            format!("r{} = {} {}", op.dest, unary(&op.operator), value(&op.left))
+        }
+        Instruction::Concat(concat) => {
+            format!(
+                "r{} = concat r{}..r{}",
+                concat.dest, concat.start, concat.end
+            )
+        }
+        Instruction::Jump(jump) => format!("jump {:?}", jump.target),
+        Instruction::JumpNot(jump) => format!("jumpnot r{} {:?}", jump.cond, jump.target),
+        Instruction::ConditionalJump(jump) => format!(
+            "jumpif {} {} {} {:?}",
+            value(&jump.condition.left),
+            condition(&jump.condition.kind),
+            value(&jump.condition.right),
+            jump.target
+        ),
+        Instruction::ForNumPrep(prep) => format!(
+            "fornprep r{}, r{}, r{}, r{} {:?}",
+            prep.index, prep.limit, prep.step, prep.var, prep.target
+        ),
+        Instruction::ForNumLoop(next) => format!(
+            "fornloop r{}, r{}, r{}, r{} {:?}",
+            next.index, next.limit, next.step, next.var, next.target
+        ),
+        Instruction::ForGenPrep(prep) => format!("forgprep r{} {:?}", prep.base, prep.target),
+        Instruction::ForGenCall(call) => {
+            format!("forgcall r{}, r{}, {}", call.base, call.dest, call.count)
+        }
+        Instruction::ForGenLoop(next) => {
+            format!("forgloop r{}, r{} {:?}", next.base, next.var, next.target)
+        }
+        Instruction::NewTable(table) => format!(
+            "r{} = newtable {}, {}",
+            table.dest, table.array_size, table.table_size
+        ),
+        Instruction::SetList(list) => format!(
+            "setlist r{}, r{}, {}, {}",
+            list.table,
+            list.start,
+            count(&list.count),
+            list.offset
+        ),
+        Instruction::Return(ret) => {
+            format!("return r{}, {}", ret.result_start, count(&ret.result_count))
+        }
+        Instruction::Call(call) => format!(
+            "{} r{}, {}, {}",
+            if call.self_call { "selfcall" } else { "call" },
+            call.callee,
+            count(&call.num_args),
+            count(&call.num_returns)
+        ),
+        Instruction::VarArg(vararg) => format!("vararg r{}, {}", vararg.dest, count(&vararg.count)),
+        Instruction::Closure(closure) => {
+            format!("r{} = closure f{}", closure.dest, closure.prototype)
+        }
+        Instruction::GetUpvalue(get) => format!("r{} = u{}", get.dest, get.upvalue),
+        Instruction::SetUpvalue(set) => format!("u{} = r{}", set.upvalue, set.src),
+        Instruction::Close(close) => format!("close r{}", close.start),
+    }
+}
+
+/// An instruction followed by its span, if it has one.
+fn spanned(item: &Instruction, span: Option<Span>) -> String {
+    match span {
+        Some(span) => format!("{} @{span}", instruction(item)),
+        None => instruction(item),
+    }
+}
+
+fn write_function(f: &mut impl Write, function: &Function, depth: usize) -> std::fmt::Result {
+    let indent = "    ".repeat(depth);
+    let body = "    ".repeat(depth + 1);
+
+    write!(f, "{indent}function ")?;
+    if let Some(name) = &function.name {
+        write!(f, "{name:?} ")?;
+    }
+    writeln!(f, "{:?}", function.arity)?;
+
+    writeln!(f, "{body}stack {}", function.max_stack_size)?;
+
+    let debug = &function.debug;
+
+    if let Some(source) = &debug.source {
+        writeln!(f, "{body}source {source:?}")?;
+    }
+
+    if debug.line_defined != 0 || debug.last_line_defined != 0 {
+        writeln!(
+            f,
+            "{body}defined {}, {}",
+            debug.line_defined, debug.last_line_defined
+        )?;
+    }
+
+    for (index, upvalue) in function.upvalues.iter().enumerate() {
+        let prefix = if upvalue.in_stack { "r" } else { "u" };
+
+        write!(f, "{body}upvalue {prefix}{}", upvalue.index)?;
+
+        if let Some(name) = debug.upvalue_names.get(index) {
+            write!(f, " {name:?}")?;
+        }
+
+        writeln!(f)?;
+    }
+
+    for (index, k) in function.constants.iter().enumerate() {
+        writeln!(f, "{body}k{index} = {}", constant(k))?;
+    }
+
+    for local in &debug.locals {
+        writeln!(
+            f,
+            "{body}local {:?} r{} {}..{}",
+            local.name,
+            local.register,
+            local.start.index(),
+            local.end.index()
+        )?;
+    }
+
+    for (item, span) in function.chunk.spanned() {
+        writeln!(f, "{body}{}", spanned(item, span))?;
+    }
+
+    for prototype in &function.prototypes {
+        write_function(f, prototype, depth + 1)?;
+    }
+
+    writeln!(f, "{indent}end")
+}
+
+impl Display for IlChunk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (item, span) in self.spanned() {
+            writeln!(f, "{}", spanned(item, span))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_function(f, self, 0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(String),
+    String(Vec<u8>),
+    Punct(&'static str),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ident(s) | Self::Number(s) => write!(f, "`{s}`"),
+            Self::String(s) => write!(f, "{}", quote(s)),
+            Self::Punct(p) => write!(f, "`{p}`"),
+        }
+    }
+}
+
+// longer punctuation comes first so that it is not split up
+const PUNCTUATION: [&str; 20] = [
+    "...", "..", "//", ".", "=", ",", "[", "]", "{", "}", "(", ")", ":", "+", "-", "*", "/", "%",
+    "^", "@",
+];
+
+fn string(chars: &mut std::iter::Peekable<std::str::CharIndices>) -> Result<Vec<u8>> {
+    let mut s = vec![];
+
+    loop {
+        let c = match chars.next() {
+            Some((_, '"')) => return Ok(s),
+            Some((_, '\\')) => match chars.next().map(|(_, c)| c) {
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some(c @ ('\\' | '"' | '\'')) => c,
+                Some('x') => {
+                    let digits = (0..2)
+                        .filter_map(|_| chars.next().map(|(_, c)| c))
+                        .collect::<String>();
+
+                    let byte = Some(&digits)
+                        .filter(|digits| digits.len() == 2)
+                        .filter(|digits| digits.chars().all(|c| c.is_ascii_hexdigit()))
+                        .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                        .with_context(|| format!("invalid byte escape `\\x{digits}`"))?;
+
+                    s.push(byte);
+                    continue;
+                }
+                Some('u') => {
+                    ensure!(
+                        matches!(chars.next(), Some((_, '{'))),
+                        "expected `{{` after `\\u`"
+                    );
+
+                    let mut digits = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '}')) => break,
+                            Some((_, c)) => digits.push(c),
+                            None => bail!("unterminated unicode escape"),
+                        }
+                    }
+
+                    u32::from_str_radix(&digits, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .with_context(|| format!("invalid unicode escape `{digits}`"))?
+                }
+                Some(c) => bail!("unknown escape `\\{c}`"),
+                None => bail!("unterminated string"),
+            },
+            Some((_, c)) => c,
+            None => bail!("unterminated string"),
+        };
+
+        s.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    }
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = line.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        let rest = &line[start..];
+        let starts_number = |rest: &str| {
+            rest.starts_with(|c: char| c.is_ascii_digit())
+                || rest.starts_with("inf")
+                || rest.starts_with("NaN")
+        };
+
+        if c == ';' {
+            break;
+        } else if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            tokens.push(Token::String(string(&mut chars)?));
+        } else if starts_number(rest) || (c == '-' && starts_number(&rest[1..])) {
+            let mut end = start + c.len_utf8();
+            let mut previous = c;
+            chars.next();
+
+            // numbers may have exponents such as `1e-5`, but stop before `..`
+            while let Some(&(index, c)) = chars.peek() {
+                let continues = c.is_ascii_alphanumeric()
+                    || (c == '.' && !line[index..].starts_with(".."))
+                    || (matches!(c, '+' | '-') && matches!(previous, 'e' | 'E'));
+
+                if !continues {
+                    break;
+                }
+
+                end = index + c.len_utf8();
+                previous = c;
+                chars.next();
+            }
+
+            tokens.push(Token::Number(line[start..end].to_owned()));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut end = start;
+
+            while let Some(&(index, c)) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_') {
+                    break;
+                }
+
+                end = index + c.len_utf8();
+                chars.next();
+            }
+
+            tokens.push(Token::Ident(line[start..end].to_owned()));
+        } else {
+            let punct = PUNCTUATION
+                .iter()
+                .find(|p| rest.starts_with(**p))
+                .with_context(|| format!("unexpected character `{c}`"))?;
+
+            for _ in 0..punct.len() {
+                chars.next();
+            }
+
+            tokens.push(Token::Punct(punct));
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Returns `n` if `ident` is `prefix` followed by the decimal number `n`.
+fn indexed(ident: &str, prefix: char) -> Option<usize> {
+    let digits = ident.strip_prefix(prefix)?;
+
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    digits.parse().ok()
+}
+
+struct Cursor {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Cursor {
+    fn new(line: &str) -> Result<Self> {
+        Ok(Self {
+            tokens: tokenize(line)?,
+            position: 0,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&Token> {
+        self.tokens.get(self.position + offset)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let token = self.peek().cloned().context("unexpected end of line")?;
+        self.position += 1;
+
+        Ok(token)
+    }
+
+    fn is_ident(&self, ident: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(s)) if s == ident)
+    }
+
+    fn is_punct(&self, punct: &str) -> bool {
+        matches!(self.peek(), Some(Token::Punct(p)) if *p == punct)
+    }
+
+    /// Returns the index of the operand written as `prefix` followed by a number, if the next
+    /// token is one, without consuming it.
+    fn peek_indexed(&self, prefix: char) -> Option<usize> {
+        match self.peek() {
+            Some(Token::Ident(ident)) => indexed(ident, prefix),
+            _ => None,
+        }
+    }
+
+    fn ident(&mut self) -> Result<String> {
+        match self.next()? {
+            Token::Ident(ident) => Ok(ident),
+            token => bail!("expected a name, found {token}"),
+        }
+    }
+
+    fn expect_ident(&mut self, ident: &str) -> Result<()> {
+        match self.next()? {
+            Token::Ident(s) if s == ident => Ok(()),
+            token => bail!("expected `{ident}`, found {token}"),
+        }
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<()> {
+        match self.next()? {
+            Token::Punct(p) if p == punct => Ok(()),
+            token => bail!("expected `{punct}`, found {token}"),
+        }
+    }
+
+    fn indexed(&mut self, prefix: char, what: &str) -> Result<usize> {
+        match self.next()? {
+            Token::Ident(ident) => indexed(&ident, prefix),
+            _ => None,
+        }
+        .with_context(|| format!("expected {what} such as `{prefix}0`"))
+    }
+
+    fn register(&mut self) -> Result<usize> {
+        self.indexed('r', "a stack index")
+    }
+
+    fn label(&mut self) -> Result<Label> {
+        self.indexed('L', "a label").map(Label)
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>> {
+        match self.next()? {
+            Token::String(s) => Ok(s),
+            token => bail!("expected a string, found {token}"),
+        }
+    }
+
+    /// Parses a string that names something, which must be valid UTF-8.
+    fn name(&mut self) -> Result<String> {
+        String::from_utf8(self.string()?).context("names must be valid UTF-8")
+    }
+
+    fn number<T: FromStr>(&mut self) -> Result<T> {
+        match self.next()? {
+            Token::Number(n) => n.parse().map_err(|_| anyhow!("`{n}` is out of range")),
+            token => bail!("expected a number, found {token}"),
+        }
+    }
+
+    fn count(&mut self) -> Result<OptVariable> {
+        if self.is_ident("top") {
+            self.position += 1;
+
+            Ok(OptVariable::Variable)
+        } else {
+            self.number().map(OptVariable::Number)
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.next()? {
+            Token::Ident(ident) => match ident.as_str() {
+                "nil" => Ok(Value::Nil),
+                "true" => Ok(Value::Boolean(true)),
+                "false" => Ok(Value::Boolean(false)),
+                _ => {
+                    if let Some(index) = indexed(&ident, 'r') {
+                        Ok(Value::StackIndex(index))
+                    } else if let Some(index) = indexed(&ident, 'k') {
+                        Ok(Value::ConstantIndex(index))
+                    } else {
+                        bail!("expected a value, found `{ident}`")
+                    }
+                }
+            },
+            Token::Number(n) => n
+                .parse()
+                .map(Value::Immediate)
+                .map_err(|_| anyhow!("`{n}` is not a 32 bit integer")),
+            token => bail!("expected a value, found {token}"),
+        }
+    }
+
+    fn constant(&mut self) -> Result<Constant> {
+        let constant = match self.next()? {
+            Token::Ident(ident) => match ident.as_str() {
+                "nil" => Constant::Nil,
+                "true" => Constant::Boolean(true),
+                "false" => Constant::Boolean(false),
+                _ => bail!("expected a constant, found `{ident}`"),
+            },
+            Token::Number(n) => {
+                let is_float = n.contains(['.', 'e', 'E']) || n.ends_with("inf") || n == "NaN";
+
+                if is_float {
+                    Constant::Number(n.parse().map_err(|_| anyhow!("invalid number `{n}`"))?)
+                } else {
+                    Constant::Integer(n.parse().map_err(|_| anyhow!("`{n}` is out of range"))?)
+                }
+            }
+            Token::String(s) => Constant::String(s),
+            Token::Punct("[") => {
+                let mut array = vec![];
+
+                while !self.is_punct("]") {
+                    array.push(self.value()?);
+
+                    if !self.is_punct("]") {
+                        self.expect(",")?;
+                    }
+                }
+                self.expect("]")?;
+
+                Constant::Table(Table::Array(array))
+            }
+            Token::Punct("{") => {
+                let mut map = IndexMap::new();
+
+                while !self.is_punct("}") {
+                    let key = self.value()?;
+                    self.expect("=")?;
+                    let item = self.value()?;
+
+                    ensure!(
+                        map.insert(key.clone(), item).is_none(),
+                        "duplicate key `{}`",
+                        value(&key)
+                    );
+
+                    if !self.is_punct("}") {
+                        self.expect(",")?;
+                    }
+                }
+                self.expect("}")?;
+
+                Constant::Table(Table::Map(map))
+            }
+            token => bail!("expected a constant, found {token}"),
+        };
+
+        Ok(constant)
+    }
+
+    /// Parses the span that may end an instruction, such as `@3` or `@3:5`.
+    fn span(&mut self) -> Result<Option<Span>> {
+        if !self.is_punct("@") {
+            return Ok(None);
+        }
+
+        self.position += 1;
+        let line = self.number()?;
+
+        let column = if self.is_punct(":") {
+            self.position += 1;
+            self.number()?
+        } else {
+            0
+        };
+
+        Ok(Some(Span::new(line, column)))
+    }
+
+    fn finish(&self) -> Result<()> {
+        match self.peek() {
+            Some(token) => bail!("unexpected {token} at the end of the line"),
+            None => Ok(()),
+        }
+    }
+}
+
+fn intrinsic(name: &str, cursor: &mut Cursor) -> Result<IntrinsicKind> {
+    let left = cursor.value()?;
+
+    if name == "bnot" {
+        return Ok(IntrinsicKind::BitNot(left));
+    }
+
+    cursor.expect(",")?;
+    let right = cursor.value()?;
+
+    Ok(match name {
+        "band" => IntrinsicKind::BitAnd(left, right),
+        "bor" => IntrinsicKind::BitOr(left, right),
+        "bxor" => IntrinsicKind::BitXor(left, right),
+        "lshift" => IntrinsicKind::LeftShift(left, right),
+        "rshift" => IntrinsicKind::RightShift(left, right),
+        _ => bail!("unknown intrinsic `{name}`"),
+    })
+}
+
+const INTRINSICS: [&str; 6] = ["band", "bor", "bxor", "bnot", "lshift", "rshift"];
+
+/// Parses the right hand side of an instruction storing into stack index `dest`.
+fn assignment(dest: usize, cursor: &mut Cursor) -> Result<Instruction> {
+    let keyword = match cursor.peek() {
+        Some(Token::Ident(ident)) => ident.clone(),
+        _ => String::new(),
+    };
+
+    let instruction = match keyword.as_str() {
+        "move" => {
+            cursor.position += 1;
+            let src = cursor.register()?;
+
+            Instruction::Move(Move { dest, src })
+        }
+        "global" => {
+            cursor.position += 1;
+            let constant = cursor.indexed('k', "a constant")?;
+
+            Instruction::GetGlobal(GetGlobal { dest, constant })
+        }
+        "concat" => {
+            cursor.position += 1;
+            let start = cursor.register()?;
+            cursor.expect("..")?;
+            let end = cursor.register()?;
+
+            Instruction::Concat(Concat { dest, start, end })
+        }
+        "newtable" => {
+            cursor.position += 1;
+            let array_size = cursor.number()?;
+            cursor.expect(",")?;
+            let table_size = cursor.number()?;
+
+            Instruction::NewTable(NewTable {
+                dest,
+                array_size,
+                table_size,
+            })
+        }
+        "closure" => {
+            cursor.position += 1;
+            let prototype = cursor.indexed('f', "a nested function")?;
+
+            Instruction::Closure(Closure { dest, prototype })
+        }
+        "not" | "neg" | "len" => {
+            cursor.position += 1;
+            let operator = match keyword.as_str() {
+                "not" => UnaryOpKind::Not,
+                "neg" => UnaryOpKind::Neg,
+                _ => UnaryOpKind::Len,
+            };
+            let left = cursor.value()?;
+
+            Instruction::UnaryOp(UnaryOp {
+                operator,
+                dest,
+                left,
+            })
+        }
+        "bit32" | "bit" if matches!(cursor.peek_at(1), Some(Token::Punct("."))) => {
+            cursor.position += 2;
+            let source = if keyword == "bit32" {
+                IntrinsicSource::Bit32
+            } else {
+                IntrinsicSource::Bit
+            };
+            let name = cursor.ident()?;
+            let kind = intrinsic(&name, cursor)?;
+
+            Instruction::Intrinsic(Intrinsic { kind, dest, source })
+        }
+        name if INTRINSICS.contains(&name) => {
+            cursor.position += 1;
+            let kind = intrinsic(name, cursor)?;
+
+            Instruction::Intrinsic(Intrinsic {
+                kind,
+                dest,
+                source: IntrinsicSource::Operator,
+            })
+        }
+        _ => {
+            if let Some(upvalue) = cursor.peek_indexed('u') {
+                cursor.position += 1;
+
+                return Ok(Instruction::GetUpvalue(GetUpvalue { dest, upvalue }));
+            }
+
+            if let Some(source) = cursor.peek_indexed('r') {
+                if matches!(cursor.peek_at(1), Some(Token::Punct("["))) {
+                    cursor.position += 2;
+                    let key = cursor.value()?;
+                    cursor.expect("]")?;
+
+                    return Ok(Instruction::GetTable(GetTable { dest, source, key }));
+                }
+
+                if matches!(cursor.peek_at(1), Some(Token::Punct(":"))) {
+                    cursor.position += 2;
+                    let key = cursor.value()?;
+
+                    return Ok(Instruction::SelfLookup(SelfLookup {
+                        dest,
+                        object: source,
+                        key,
+                    }));
+                }
+            }
+
+            let left = cursor.value()?;
+
+            let operator = match cursor.peek() {
+                None | Some(Token::Punct("@")) => {
+                    return Ok(Instruction::Load(Load { dest, src: left }))
+                }
+                Some(Token::Punct(p)) => match *p {
+                    "+" => BinaryOpKind::Add,
+                    "-" => BinaryOpKind::Sub,
+                    "*" => BinaryOpKind::Mul,
+                    "/" => BinaryOpKind::Div,
+                    "//" => BinaryOpKind::IDiv,
+                    "%" => BinaryOpKind::Mod,
+                    "^" => BinaryOpKind::Pow,
+                    ".." => BinaryOpKind::Concat,
+                    _ => bail!("expected a binary operator, found `{p}`"),
+                },
+                Some(token) => bail!("expected a binary operator, found {token}"),
+            };
+            cursor.position += 1;
+            let right = cursor.value()?;
+
+            Instruction::BinaryOp(BinaryOp {
+                operator,
+                dest,
+                left,
+                right,
+            })
+        }
+    };
+
+    Ok(instruction)
+}
+
+fn condition_kind(name: &str) -> Result<ConditionKind> {
+    Ok(match name {
+        "eq" => ConditionKind::Eq,
+        "ge" => ConditionKind::Ge,
+        "gt" => ConditionKind::Gt,
+        "ne" => ConditionKind::Ne,
+        "lt" => ConditionKind::Lt,
+        "le" => ConditionKind::Le,
+        "and" => ConditionKind::And,
+        "or" => ConditionKind::Or,
+        _ => bail!("unknown condition `{name}`"),
+    })
+}
+
+fn parse_instruction(cursor: &mut Cursor) -> Result<Instruction> {
+    let keyword = match cursor.peek() {
+        Some(Token::Ident(ident)) => ident.clone(),
+        Some(token) => bail!("expected an instruction, found {token}"),
+        None => bail!("expected an instruction"),
+    };
+
+    if let Some(label) = indexed(&keyword, 'L') {
+        cursor.position += 1;
+        cursor.expect(":")?;
+
+        return Ok(Instruction::Label(Label(label)));
+    }
+
+    if let Some(dest) = indexed(&keyword, 'r') {
+        cursor.position += 1;
+
+        if cursor.is_punct("[") {
+            cursor.position += 1;
+            let key = cursor.value()?;
+            cursor.expect("]")?;
+            cursor.expect("=")?;
+            let value = cursor.value()?;
+
+            return Ok(Instruction::SetTable(SetTable {
+                table: dest,
+                key,
+                value,
+            }));
+        }
+
+        cursor.expect("=")?;
+
+        return assignment(dest, cursor);
+    }
+
+    if let Some(upvalue) = indexed(&keyword, 'u') {
+        cursor.position += 1;
+        cursor.expect("=")?;
+        let src = cursor.register()?;
+
+        return Ok(Instruction::SetUpvalue(SetUpvalue { src, upvalue }));
+    }
+
+    cursor.position += 1;
+
+    let instruction = match keyword.as_str() {
+        "global" => {
+            let constant = cursor.indexed('k', "a constant")?;
+            cursor.expect("=")?;
+            let src = cursor.register()?;
+
+            Instruction::SetGlobal(SetGlobal { src, constant })
+        }
+        "jump" => Instruction::Jump(Jump {
+            target: cursor.label()?,
+        }),
+        "jumpnot" => {
+            let cond = cursor.register()?;
+            let target = cursor.label()?;
+
+            Instruction::JumpNot(JumpNot { target, cond })
+        }
+        "jumpif" => {
+            let left = cursor.value()?;
+            let kind = condition_kind(&cursor.ident()?)?;
+            let right = cursor.value()?;
+            let target = cursor.label()?;
+
+            Instruction::ConditionalJump(ConditionalJump {
+                target,
+                condition: Condition { kind, left, right },
+            })
+        }
+        "fornprep" | "fornloop" => {
+            let index = cursor.register()?;
+            cursor.expect(",")?;
+            let limit = cursor.register()?;
+            cursor.expect(",")?;
+            let step = cursor.register()?;
+            cursor.expect(",")?;
+            let var = cursor.register()?;
+            let target = cursor.label()?;
+
+            if keyword == "fornprep" {
+                Instruction::ForNumPrep(ForNumPrep {
+                    target,
+                    index,
+                    limit,
+                    step,
+                    var,
+                })
+            } else {
+                Instruction::ForNumLoop(ForNumLoop {
+                    target,
+                    index,
+                    limit,
+                    step,
+                    var,
+                })
+            }
+        }
+        "forgprep" => {
+            let base = cursor.register()?;
+            let target = cursor.label()?;
+
+            Instruction::ForGenPrep(ForGenPrep { target, base })
+        }
+        "forgcall" => {
+            let base = cursor.register()?;
+            cursor.expect(",")?;
+            let dest = cursor.register()?;
+            cursor.expect(",")?;
+            let count = cursor.number()?;
+
+            Instruction::ForGenCall(ForGenCall { base, dest, count })
+        }
+        "forgloop" => {
+            let base = cursor.register()?;
+            cursor.expect(",")?;
+            let var = cursor.register()?;
+            let target = cursor.label()?;
+
+            Instruction::ForGenLoop(ForGenLoop { target, base, var })
+        }
+        "setlist" => {
+            let table = cursor.register()?;
+            cursor.expect(",")?;
+            let start = cursor.register()?;
+            cursor.expect(",")?;
+            let count = cursor.count()?;
+            cursor.expect(",")?;
+            let offset = cursor.number()?;
+
+            Instruction::SetList(SetList {
+                table,
+                start,
+                count,
+                offset,
+            })
+        }
+        "call" | "selfcall" => {
+            let callee = cursor.register()?;
+            cursor.expect(",")?;
+            let num_args = cursor.count()?;
+            cursor.expect(",")?;
+            let num_returns = cursor.count()?;
+
+            Instruction::Call(Call {
+                callee,
+                self_call: keyword == "selfcall",
+                num_args,
+                num_returns,
+            })
+        }
+        "vararg" => {
+            let dest = cursor.register()?;
+            cursor.expect(",")?;
+            let count = cursor.count()?;
+
+            Instruction::VarArg(VarArg { dest, count })
+        }
+        "return" => {
+            let result_start = cursor.register()?;
+            cursor.expect(",")?;
+            let result_count = cursor.count()?;
+
+            Instruction::Return(Return {
+                result_start,
+                result_count,
+            })
+        }
+        "close" => Instruction::Close(Close {
+            start: cursor.register()?,
+        }),
+        _ => bail!("unknown instruction `{keyword}`"),
+    };
+
+    Ok(instruction)
+}
+
+/// The non-empty lines of a listing, with their line numbers and text.
+type Lines<'s> = std::vec::IntoIter<(usize, Cursor, &'s str)>;
+
+fn lines(s: &str) -> Result<Lines<'_>> {
+    let mut lines = vec![];
+
+    for (index, line) in s.lines().enumerate() {
+        let cursor = Cursor::new(line)
+            .with_context(|| format!("failed to parse line {}: {line}", index + 1))?;
+
+        if !cursor.is_empty() {
+            lines.push((index + 1, cursor, line));
+        }
+    }
+
+    Ok(lines.into_iter())
+}
+
+fn arity(cursor: &mut Cursor) -> Result<Arity> {
+    cursor.expect("(")?;
+    let mut arity = Arity::fixed(cursor.number()?);
+
+    if cursor.is_punct(",") {
+        cursor.position += 1;
+        cursor.expect("...")?;
+        arity.is_vararg = true;
+
+        if cursor.is_punct(",") {
+            cursor.position += 1;
+            cursor.expect_ident("arg")?;
+            arity.needs_arg = true;
+        }
+    }
+
+    cursor.expect(")")?;
+
+    Ok(arity)
+}
+
+fn function_header(cursor: &mut Cursor) -> Result<Function> {
+    cursor.expect_ident("function")?;
+
+    let name = match cursor.peek() {
+        Some(Token::String(_)) => Some(cursor.name()?),
+        _ => None,
+    };
+
+    let arity = arity(cursor)?;
+    cursor.finish()?;
+
+    Ok(Function {
+        chunk: IlChunk::new(vec![]),
+        constants: vec![],
+        prototypes: vec![],
+        upvalues: vec![],
+        arity,
+        name,
+        max_stack_size: 0,
+        debug: DebugInfo::default(),
+    })
+}
+
+/// Parses a line of the body of `function`, returning whether it is the `end` of it.
+fn function_line(function: &mut Function, cursor: &mut Cursor) -> Result<bool> {
+    if cursor.is_ident("end") {
+        cursor.position += 1;
+        cursor.finish()?;
+
+        return Ok(true);
+    }
+
+    if cursor.is_ident("stack") {
+        cursor.position += 1;
+        function.max_stack_size = cursor.number()?;
+    } else if cursor.is_ident("upvalue") {
+        cursor.position += 1;
+
+        let upvalue = match cursor.peek_indexed('u') {
+            Some(index) => {
+                cursor.position += 1;
+
+                Upvalue {
+                    in_stack: false,
+                    index,
+                }
+            }
+            None => Upvalue {
+                in_stack: true,
+                index: cursor.register()?,
+            },
+        };
+
+        function.upvalues.push(upvalue);
+
+        if cursor.peek().is_some() {
+            ensure!(
+                function.debug.upvalue_names.len() + 1 == function.upvalues.len(),
+                "either every upvalue or none is named"
+            );
+
+            function.debug.upvalue_names.push(cursor.name()?);
+        }
+    } else if cursor.is_ident("source") {
+        cursor.position += 1;
+        function.debug.source = Some(cursor.name()?);
+    } else if cursor.is_ident("defined") {
+        cursor.position += 1;
+        function.debug.line_defined = cursor.number()?;
+        cursor.expect(",")?;
+        function.debug.last_line_defined = cursor.number()?;
+    } else if cursor.is_ident("local") {
+        cursor.position += 1;
+
+        let name = cursor.name()?;
+        let register = cursor.register()?;
+        let start = cursor.number()?;
+        cursor.expect("..")?;
+        let end = cursor.number()?;
+
+        function.debug.locals.push(Local {
+            name,
+            register,
+            start: Inst::new(start),
+            end: Inst::new(end),
+        });
+    } else if let (Some(index), Some(Token::Punct("="))) =
+        (cursor.peek_indexed('k'), cursor.peek_at(1))
+    {
+        ensure!(
+            index == function.constants.len(),
+            "expected constant k{}, found k{index}",
+            function.constants.len()
+        );
+
+        cursor.position += 2;
+        function.constants.push(cursor.constant()?);
+    } else {
+        let instruction = parse_instruction(cursor)?;
+        function.chunk.push_spanned(instruction, cursor.span()?);
+    }
+
+    cursor.finish()?;
+
+    Ok(false)
+}
+
+/// Parses the lines following the header of `function`, up to and including its `end`.
+fn function_body(function: &mut Function, lines: &mut Lines) -> Result<()> {
+    while let Some((number, mut cursor, line)) = lines.next() {
+        let context = || format!("failed to parse line {number}: {line}");
+
+        if cursor.is_ident("function") {
+            let mut prototype = function_header(&mut cursor).with_context(context)?;
+            function_body(&mut prototype, lines)?;
+            function.prototypes.push(prototype);
+
+            continue;
+        }
+
+        let ended = function_line(function, &mut cursor).with_context(context)?;
+
+        if ended {
+            return Ok(());
+        }
+    }
+
+    bail!("expected `end` before the end of the listing")
+}
+
+impl FromStr for IlChunk {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut chunk = Self::default();
+
+        for (number, mut cursor, line) in lines(s)? {
+            let (instruction, span) = parse_instruction(&mut cursor)
+                .and_then(|instruction| Ok((instruction, cursor.span()?)))
+                .and_then(|spanned| cursor.finish().map(|_| spanned))
+                .with_context(|| format!("failed to parse line {number}: {line}"))?;
+
+            chunk.push_spanned(instruction, span);
+        }
+
+        Ok(chunk)
+    }
+}
+
+impl FromStr for Function {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut lines = lines(s)?;
+
+        let (number, mut cursor, line) = lines.next().context("expected a function")?;
+        let mut function = function_header(&mut cursor)
+            .with_context(|| format!("failed to parse line {number}: {line}"))?;
+
+        function_body(&mut function, &mut lines)?;
+
+        if let Some((number, _, line)) = lines.next() {
+            bail!("unexpected line {number} after the end of the function: {line}");
+        }
+
+        Ok(function)
+    }
+}
@@ -0,0 +1,279 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Checks that a chunk of intermediate language is well-formed, so that malformed chunks are
+//! caught where they are produced rather than deep inside a serializer.
+
+use super::*;
+use std::{collections::HashSet, error::Error, fmt::Display};
+
+/// The ways in which an instruction can be malformed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProblemKind {
+    /// The stack index `index` is not below the stack size of the function.
+    StackIndex { index: usize, max_stack_size: u8 },
+    /// The constant `index` is not in the constant table of the function.
+    ConstantIndex { index: usize, count: usize },
+    /// The upvalue `index` is not one of the upvalues of the function.
+    UpvalueIndex { index: usize, count: usize },
+    /// The nested function `index` is not one of the prototypes of the function.
+    PrototypeIndex { index: usize, count: usize },
+    /// The instruction jumps to a label that is not defined in the chunk.
+    UndefinedLabel(Label),
+    /// The label is defined again by this instruction.
+    DuplicateLabel(Label),
+    /// The chunk does not end with a `Return`, reported at the index past its end.
+    MissingReturn,
+}
+
+impl Display for ProblemKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::StackIndex {
+                index,
+                max_stack_size,
+            } => write!(
+                f,
+                "stack index {index} is beyond the stack size of {max_stack_size}"
+            ),
+            Self::ConstantIndex { index, count } => {
+                write!(f, "constant {index} is beyond the {count} constants")
+            }
+            Self::UpvalueIndex { index, count } => {
+                write!(f, "upvalue {index} is beyond the {count} upvalues")
+            }
+            Self::PrototypeIndex { index, count } => {
+                write!(
+                    f,
+                    "prototype {index} is beyond the {count} nested functions"
+                )
+            }
+            Self::UndefinedLabel(label) => write!(f, "jump to undefined label {label:?}"),
+            Self::DuplicateLabel(label) => write!(f, "label {label:?} is already defined"),
+            Self::MissingReturn => write!(f, "the chunk does not end with a return"),
+        }
+    }
+}
+
+/// A malformed instruction found by `IlChunk::verify`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    /// The indices of the child prototypes leading from the verified function to the
+    /// function holding the instruction, empty for the verified function itself.
+    pub prototype: Vec<usize>,
+    /// The index of the instruction in its chunk.
+    pub instruction: usize,
+    pub kind: ProblemKind,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "instruction {}", self.instruction)?;
+
+        for index in self.prototype.iter().rev() {
+            write!(f, " of prototype {index}")?;
+        }
+
+        write!(f, ": {}", self.kind)
+    }
+}
+
+/// Every problem found while verifying a chunk, in the order of the instructions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifyError(pub Vec<Problem>);
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let problems = self
+            .0
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        write!(f, "malformed intermediate language: {problems}")
+    }
+}
+
+impl Error for VerifyError {}
+
+/// The constants, upvalues and nested functions an instruction refers to.
+#[derive(Default)]
+struct References {
+    constants: Vec<usize>,
+    upvalues: Vec<usize>,
+    prototypes: Vec<usize>,
+}
+
+impl IlVisitor for References {
+    fn visit_constant_index(&mut self, index: usize) {
+        self.constants.push(index);
+    }
+
+    fn visit_upvalue_index(&mut self, index: usize) {
+        self.upvalues.push(index);
+    }
+
+    fn visit_prototype_index(&mut self, index: usize) {
+        self.prototypes.push(index);
+    }
+}
+
+fn verify_chunk(chunk: &IlChunk, function: &Function, path: &[usize], problems: &mut Vec<Problem>) {
+    let instructions = chunk.inner();
+    let start = problems.len();
+    let mut report = |instruction, kind| {
+        problems.push(Problem {
+            prototype: path.to_vec(),
+            instruction,
+            kind,
+        })
+    };
+
+    let mut labels = HashSet::new();
+
+    for (index, instruction) in instructions.iter().enumerate() {
+        if let Instruction::Label(label) = instruction {
+            if !labels.insert(*label) {
+                report(index, ProblemKind::DuplicateLabel(*label));
+            }
+        }
+    }
+
+    for (index, instruction) in instructions.iter().enumerate() {
+        // a variable number of values needs at least the slot it starts at
+        let (defs, uses) = (instruction.defs(), instruction.uses());
+        let slots = || {
+            (defs.fixed.iter().chain(&uses.fixed))
+                .chain(defs.variable.iter().chain(&uses.variable))
+                .copied()
+        };
+
+        for (position, slot) in slots().enumerate() {
+            // a slot that is both read and written is reported once
+            if slot >= function.max_stack_size as usize
+                && !slots().take(position).any(|earlier| earlier == slot)
+            {
+                report(
+                    index,
+                    ProblemKind::StackIndex {
+                        index: slot,
+                        max_stack_size: function.max_stack_size,
+                    },
+                );
+            }
+        }
+
+        let mut references = References::default();
+        references.visit_instruction(instruction);
+
+        for constant in references.constants {
+            if constant >= function.constants.len() {
+                report(
+                    index,
+                    ProblemKind::ConstantIndex {
+                        index: constant,
+                        count: function.constants.len(),
+                    },
+                );
+            }
+        }
+
+        for upvalue in references.upvalues {
+            if upvalue >= function.upvalues.len() {
+                report(
+                    index,
+                    ProblemKind::UpvalueIndex {
+                        index: upvalue,
+                        count: function.upvalues.len(),
+                    },
+                );
+            }
+        }
+
+        for prototype in references.prototypes {
+            if prototype >= function.prototypes.len() {
+                report(
+                    index,
+                    ProblemKind::PrototypeIndex {
+                        index: prototype,
+                        count: function.prototypes.len(),
+                    },
+                );
+            }
+        }
+
+        match instruction.target() {
+            Some(target) if !labels.contains(&target) => {
+                report(index, ProblemKind::UndefinedLabel(target))
+            }
+            _ => {}
+        }
+    }
+
+    if !matches!(instructions.last(), Some(Instruction::Return(_))) {
+        report(instructions.len(), ProblemKind::MissingReturn);
+    }
+
+    // labels are all collected before the instructions are checked, so duplicates are found
+    // out of order
+    problems[start..].sort_by_key(|problem| problem.instruction);
+}
+
+fn verify_function(function: &Function, path: &mut Vec<usize>, problems: &mut Vec<Problem>) {
+    verify_chunk(&function.chunk, function, path, problems);
+
+    for (index, prototype) in function.prototypes.iter().enumerate() {
+        path.push(index);
+        verify_function(prototype, path, problems);
+        path.pop();
+    }
+}
+
+fn result(problems: Vec<Problem>) -> Result<(), VerifyError> {
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(VerifyError(problems))
+    }
+}
+
+impl IlChunk {
+    /// Checks that this chunk is well-formed as the code of `function`, reporting every
+    /// malformed instruction. The functions nested in `function` are not checked.
+    pub fn verify(&self, function: &Function) -> Result<(), VerifyError> {
+        let mut problems = vec![];
+        verify_chunk(self, function, &[], &mut problems);
+
+        result(problems)
+    }
+}
+
+impl Function {
+    /// Verifies the chunk of this function and of every function nested in it.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        let mut problems = vec![];
+        verify_function(self, &mut vec![], &mut problems);
+
+        result(problems)
+    }
+}
@@ -0,0 +1,860 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Traversals of the intermediate language. `IlVisitor` reads and `IlRewriter` modifies a
+//! function, with a hook for every kind of instruction and for every kind of operand. The
+//! hooks recurse into the operands, constant tables and nested functions by default through
+//! the `walk_*` functions, which an overriding hook calls to keep recursing.
+//!
+//! Hooks are called in the order of the fields of each instruction. Label definitions and
+//! jump targets both go through the label hook.
+
+use super::*;
+
+/// Reads a function of intermediate language. Every hook recurses by default, so an
+/// implementation only overrides the hooks for what it is interested in.
+pub trait IlVisitor {
+    /// Visits the code of `function`, then its constants, its upvalues and its nested
+    /// functions.
+    fn visit_function(&mut self, function: &Function) {
+        walk_function(self, function);
+    }
+
+    fn visit_chunk(&mut self, chunk: &IlChunk) {
+        walk_chunk(self, chunk);
+    }
+
+    fn visit_instruction(&mut self, instruction: &Instruction) {
+        walk_instruction(self, instruction);
+    }
+
+    fn visit_constant(&mut self, constant: &Constant) {
+        walk_constant(self, constant);
+    }
+
+    fn visit_table(&mut self, table: &Table) {
+        walk_table(self, table);
+    }
+
+    /// Visits an upvalue descriptor of the function being visited. Its index refers to the
+    /// enclosing function, so it is not passed to `visit_register` or `visit_upvalue_index`.
+    fn visit_upvalue(&mut self, _upvalue: &Upvalue) {}
+
+    fn visit_value(&mut self, value: &Value) {
+        walk_value(self, value);
+    }
+
+    /// Visits an operand naming a stack slot.
+    fn visit_register(&mut self, _index: usize) {}
+
+    /// Visits an operand naming an entry of the constant table.
+    fn visit_constant_index(&mut self, _index: usize) {}
+
+    /// Visits an operand naming an upvalue of the running closure.
+    fn visit_upvalue_index(&mut self, _index: usize) {}
+
+    /// Visits an operand naming a nested function.
+    fn visit_prototype_index(&mut self, _index: usize) {}
+
+    /// Visits a label, either where it is defined or where it is jumped to.
+    fn visit_label(&mut self, _label: Label) {}
+
+    fn visit_load(&mut self, load: &Load) {
+        walk_load(self, load);
+    }
+
+    fn visit_move(&mut self, mv: &Move) {
+        walk_move(self, mv);
+    }
+
+    fn visit_intrinsic(&mut self, intrinsic: &Intrinsic) {
+        walk_intrinsic(self, intrinsic);
+    }
+
+    fn visit_get_global(&mut self, get: &GetGlobal) {
+        walk_get_global(self, get);
+    }
+
+    fn visit_set_global(&mut self, set: &SetGlobal) {
+        walk_set_global(self, set);
+    }
+
+    fn visit_get_table(&mut self, get: &GetTable) {
+        walk_get_table(self, get);
+    }
+
+    fn visit_set_table(&mut self, set: &SetTable) {
+        walk_set_table(self, set);
+    }
+
+    fn visit_self_lookup(&mut self, lookup: &SelfLookup) {
+        walk_self_lookup(self, lookup);
+    }
+
+    fn visit_binary_op(&mut self, op: &BinaryOp) {
+        walk_binary_op(self, op);
+    }
+
+    fn visit_unary_op(&mut self, op: &UnaryOp) {
+        walk_unary_op(self, op);
+    }
+
+    fn visit_concat(&mut self, concat: &Concat) {
+        walk_concat(self, concat);
+    }
+
+    fn visit_jump(&mut self, jump: &Jump) {
+        walk_jump(self, jump);
+    }
+
+    fn visit_jump_not(&mut self, jump: &JumpNot) {
+        walk_jump_not(self, jump);
+    }
+
+    fn visit_conditional_jump(&mut self, jump: &ConditionalJump) {
+        walk_conditional_jump(self, jump);
+    }
+
+    fn visit_for_num_prep(&mut self, prep: &ForNumPrep) {
+        walk_for_num_prep(self, prep);
+    }
+
+    fn visit_for_num_loop(&mut self, next: &ForNumLoop) {
+        walk_for_num_loop(self, next);
+    }
+
+    fn visit_for_gen_prep(&mut self, prep: &ForGenPrep) {
+        walk_for_gen_prep(self, prep);
+    }
+
+    fn visit_for_gen_call(&mut self, call: &ForGenCall) {
+        walk_for_gen_call(self, call);
+    }
+
+    fn visit_for_gen_loop(&mut self, next: &ForGenLoop) {
+        walk_for_gen_loop(self, next);
+    }
+
+    fn visit_new_table(&mut self, table: &NewTable) {
+        walk_new_table(self, table);
+    }
+
+    fn visit_set_list(&mut self, list: &SetList) {
+        walk_set_list(self, list);
+    }
+
+    fn visit_return(&mut self, ret: &Return) {
+        walk_return(self, ret);
+    }
+
+    fn visit_call(&mut self, call: &Call) {
+        walk_call(self, call);
+    }
+
+    fn visit_var_arg(&mut self, vararg: &VarArg) {
+        walk_var_arg(self, vararg);
+    }
+
+    fn visit_closure(&mut self, closure: &Closure) {
+        walk_closure(self, closure);
+    }
+
+    fn visit_get_upvalue(&mut self, get: &GetUpvalue) {
+        walk_get_upvalue(self, get);
+    }
+
+    fn visit_set_upvalue(&mut self, set: &SetUpvalue) {
+        walk_set_upvalue(self, set);
+    }
+
+    fn visit_close(&mut self, close: &Close) {
+        walk_close(self, close);
+    }
+}
+
+/// Modifies a function of intermediate language in place. Every hook recurses by default,
+/// so an implementation only overrides the hooks for what it changes.
+pub trait IlRewriter {
+    /// Rewrites the code of `function`, then its constants, its upvalues and its nested
+    /// functions.
+    fn rewrite_function(&mut self, function: &mut Function) {
+        walk_function_mut(self, function);
+    }
+
+    fn rewrite_chunk(&mut self, chunk: &mut IlChunk) {
+        walk_chunk_mut(self, chunk);
+    }
+
+    /// Returns the instructions to insert before `instruction`, which is rewritten after
+    /// them. Code inserted before a `Label` runs before the label is reached rather than
+    /// when it is jumped to. Inserted instructions take the span of `instruction`.
+    fn insert_before(&mut self, _instruction: &Instruction) -> Vec<Instruction> {
+        Vec::new()
+    }
+
+    fn rewrite_instruction(&mut self, instruction: &mut Instruction) {
+        walk_instruction_mut(self, instruction);
+    }
+
+    fn rewrite_constant(&mut self, constant: &mut Constant) {
+        walk_constant_mut(self, constant);
+    }
+
+    fn rewrite_table(&mut self, table: &mut Table) {
+        walk_table_mut(self, table);
+    }
+
+    /// Rewrites an upvalue descriptor of the function being rewritten. Its index refers to
+    /// the enclosing function, so it is not passed to `rewrite_register` or
+    /// `rewrite_upvalue_index`.
+    fn rewrite_upvalue(&mut self, _upvalue: &mut Upvalue) {}
+
+    fn rewrite_value(&mut self, value: &mut Value) {
+        walk_value_mut(self, value);
+    }
+
+    /// Rewrites an operand naming a stack slot.
+    fn rewrite_register(&mut self, _index: &mut usize) {}
+
+    /// Rewrites an operand naming an entry of the constant table.
+    fn rewrite_constant_index(&mut self, _index: &mut usize) {}
+
+    /// Rewrites an operand naming an upvalue of the running closure.
+    fn rewrite_upvalue_index(&mut self, _index: &mut usize) {}
+
+    /// Rewrites an operand naming a nested function.
+    fn rewrite_prototype_index(&mut self, _index: &mut usize) {}
+
+    /// Rewrites a label, either where it is defined or where it is jumped to.
+    fn rewrite_label(&mut self, _label: &mut Label) {}
+
+    fn rewrite_load(&mut self, load: &mut Load) {
+        walk_load_mut(self, load);
+    }
+
+    fn rewrite_move(&mut self, mv: &mut Move) {
+        walk_move_mut(self, mv);
+    }
+
+    fn rewrite_intrinsic(&mut self, intrinsic: &mut Intrinsic) {
+        walk_intrinsic_mut(self, intrinsic);
+    }
+
+    fn rewrite_get_global(&mut self, get: &mut GetGlobal) {
+        walk_get_global_mut(self, get);
+    }
+
+    fn rewrite_set_global(&mut self, set: &mut SetGlobal) {
+        walk_set_global_mut(self, set);
+    }
+
+    fn rewrite_get_table(&mut self, get: &mut GetTable) {
+        walk_get_table_mut(self, get);
+    }
+
+    fn rewrite_set_table(&mut self, set: &mut SetTable) {
+        walk_set_table_mut(self, set);
+    }
+
+    fn rewrite_self_lookup(&mut self, lookup: &mut SelfLookup) {
+        walk_self_lookup_mut(self, lookup);
+    }
+
+    fn rewrite_binary_op(&mut self, op: &mut BinaryOp) {
+        walk_binary_op_mut(self, op);
+    }
+
+    fn rewrite_unary_op(&mut self, op: &mut UnaryOp) {
+        walk_unary_op_mut(self, op);
+    }
+
+    fn rewrite_concat(&mut self, concat: &mut Concat) {
+        walk_concat_mut(self, concat);
+    }
+
+    fn rewrite_jump(&mut self, jump: &mut Jump) {
+        walk_jump_mut(self, jump);
+    }
+
+    fn rewrite_jump_not(&mut self, jump: &mut JumpNot) {
+        walk_jump_not_mut(self, jump);
+    }
+
+    fn rewrite_conditional_jump(&mut self, jump: &mut ConditionalJump) {
+        walk_conditional_jump_mut(self, jump);
+    }
+
+    fn rewrite_for_num_prep(&mut self, prep: &mut ForNumPrep) {
+        walk_for_num_prep_mut(self, prep);
+    }
+
+    fn rewrite_for_num_loop(&mut self, next: &mut ForNumLoop) {
+        walk_for_num_loop_mut(self, next);
+    }
+
+    fn rewrite_for_gen_prep(&mut self, prep: &mut ForGenPrep) {
+        walk_for_gen_prep_mut(self, prep);
+    }
+
+    fn rewrite_for_gen_call(&mut self, call: &mut ForGenCall) {
+        walk_for_gen_call_mut(self, call);
+    }
+
+    fn rewrite_for_gen_loop(&mut self, next: &mut ForGenLoop) {
+        walk_for_gen_loop_mut(self, next);
+    }
+
+    fn rewrite_new_table(&mut self, table: &mut NewTable) {
+        walk_new_table_mut(self, table);
+    }
+
+    fn rewrite_set_list(&mut self, list: &mut SetList) {
+        walk_set_list_mut(self, list);
+    }
+
+    fn rewrite_return(&mut self, ret: &mut Return) {
+        walk_return_mut(self, ret);
+    }
+
+    fn rewrite_call(&mut self, call: &mut Call) {
+        walk_call_mut(self, call);
+    }
+
+    fn rewrite_var_arg(&mut self, vararg: &mut VarArg) {
+        walk_var_arg_mut(self, vararg);
+    }
+
+    fn rewrite_closure(&mut self, closure: &mut Closure) {
+        walk_closure_mut(self, closure);
+    }
+
+    fn rewrite_get_upvalue(&mut self, get: &mut GetUpvalue) {
+        walk_get_upvalue_mut(self, get);
+    }
+
+    fn rewrite_set_upvalue(&mut self, set: &mut SetUpvalue) {
+        walk_set_upvalue_mut(self, set);
+    }
+
+    fn rewrite_close(&mut self, close: &mut Close) {
+        walk_close_mut(self, close);
+    }
+}
+
+pub fn walk_function<V: IlVisitor + ?Sized>(visitor: &mut V, function: &Function) {
+    visitor.visit_chunk(&function.chunk);
+
+    for constant in &function.constants {
+        visitor.visit_constant(constant);
+    }
+
+    for upvalue in &function.upvalues {
+        visitor.visit_upvalue(upvalue);
+    }
+
+    for prototype in &function.prototypes {
+        visitor.visit_function(prototype);
+    }
+}
+
+pub fn walk_chunk<V: IlVisitor + ?Sized>(visitor: &mut V, chunk: &IlChunk) {
+    for instruction in chunk.inner() {
+        visitor.visit_instruction(instruction);
+    }
+}
+
+pub fn walk_instruction<V: IlVisitor + ?Sized>(visitor: &mut V, instruction: &Instruction) {
+    match instruction {
+        Instruction::Label(label) => visitor.visit_label(*label),
+        Instruction::Load(load) => visitor.visit_load(load),
+        Instruction::Move(mv) => visitor.visit_move(mv),
+        Instruction::Intrinsic(intrinsic) => visitor.visit_intrinsic(intrinsic),
+        Instruction::GetGlobal(get) => visitor.visit_get_global(get),
+        Instruction::SetGlobal(set) => visitor.visit_set_global(set),
+        Instruction::GetTable(get) => visitor.visit_get_table(get),
+        Instruction::SetTable(set) => visitor.visit_set_table(set),
+        Instruction::SelfLookup(lookup) => visitor.visit_self_lookup(lookup),
+        Instruction::BinaryOp(op) => visitor.visit_binary_op(op),
+        Instruction::UnaryOp(op) => visitor.visit_unary_op(op),
+        Instruction::Concat(concat) => visitor.visit_concat(concat),
+        Instruction::Jump(jump) => visitor.visit_jump(jump),
+        Instruction::JumpNot(jump) => visitor.visit_jump_not(jump),
+        Instruction::ConditionalJump(jump) => visitor.visit_conditional_jump(jump),
+        Instruction::ForNumPrep(prep) => visitor.visit_for_num_prep(prep),
+        Instruction::ForNumLoop(next) => visitor.visit_for_num_loop(next),
+        Instruction::ForGenPrep(prep) => visitor.visit_for_gen_prep(prep),
+        Instruction::ForGenCall(call) => visitor.visit_for_gen_call(call),
+        Instruction::ForGenLoop(next) => visitor.visit_for_gen_loop(next),
+        Instruction::NewTable(table) => visitor.visit_new_table(table),
+        Instruction::SetList(list) => visitor.visit_set_list(list),
+        Instruction::Return(ret) => visitor.visit_return(ret),
+        Instruction::Call(call) => visitor.visit_call(call),
+        Instruction::VarArg(vararg) => visitor.visit_var_arg(vararg),
+        Instruction::Closure(closure) => visitor.visit_closure(closure),
+        Instruction::GetUpvalue(get) => visitor.visit_get_upvalue(get),
+        Instruction::SetUpvalue(set) => visitor.visit_set_upvalue(set),
+        Instruction::Close(close) => visitor.visit_close(close),
+    }
+}
+
+pub fn walk_constant<V: IlVisitor + ?Sized>(visitor: &mut V, constant: &Constant) {
+    if let Constant::Table(table) = constant {
+        visitor.visit_table(table);
+    }
+}
+
+pub fn walk_table<V: IlVisitor + ?Sized>(visitor: &mut V, table: &Table) {
+    match table {
+        Table::Map(map) => {
+            for (key, value) in map {
+                visitor.visit_value(key);
+                visitor.visit_value(value);
+            }
+        }
+        Table::Array(array) => {
+            for value in array {
+                visitor.visit_value(value);
+            }
+        }
+    }
+}
+
+pub fn walk_value<V: IlVisitor + ?Sized>(visitor: &mut V, value: &Value) {
+    match *value {
+        Value::StackIndex(index) => visitor.visit_register(index),
+        Value::ConstantIndex(index) => visitor.visit_constant_index(index),
+        Value::Nil | Value::Boolean(_) | Value::Immediate(_) => {}
+    }
+}
+
+pub fn walk_load<V: IlVisitor + ?Sized>(visitor: &mut V, load: &Load) {
+    visitor.visit_register(load.dest);
+    visitor.visit_value(&load.src);
+}
+
+pub fn walk_move<V: IlVisitor + ?Sized>(visitor: &mut V, mv: &Move) {
+    visitor.visit_register(mv.dest);
+    visitor.visit_register(mv.src);
+}
+
+pub fn walk_intrinsic<V: IlVisitor + ?Sized>(visitor: &mut V, intrinsic: &Intrinsic) {
+    visitor.visit_register(intrinsic.dest);
+    for operand in intrinsic.kind.operands() {
+        visitor.visit_value(operand);
+    }
+}
+
+pub fn walk_get_global<V: IlVisitor + ?Sized>(visitor: &mut V, get: &GetGlobal) {
+    visitor.visit_register(get.dest);
+    visitor.visit_constant_index(get.constant);
+}
+
+pub fn walk_set_global<V: IlVisitor + ?Sized>(visitor: &mut V, set: &SetGlobal) {
+    visitor.visit_register(set.src);
+    visitor.visit_constant_index(set.constant);
+}
+
+pub fn walk_get_table<V: IlVisitor + ?Sized>(visitor: &mut V, get: &GetTable) {
+    visitor.visit_register(get.dest);
+    visitor.visit_register(get.source);
+    visitor.visit_value(&get.key);
+}
+
+pub fn walk_set_table<V: IlVisitor + ?Sized>(visitor: &mut V, set: &SetTable) {
+    visitor.visit_register(set.table);
+    visitor.visit_value(&set.key);
+    visitor.visit_value(&set.value);
+}
+
+pub fn walk_self_lookup<V: IlVisitor + ?Sized>(visitor: &mut V, lookup: &SelfLookup) {
+    visitor.visit_register(lookup.dest);
+    visitor.visit_register(lookup.object);
+    visitor.visit_value(&lookup.key);
+}
+
+pub fn walk_binary_op<V: IlVisitor + ?Sized>(visitor: &mut V, op: &BinaryOp) {
+    visitor.visit_register(op.dest);
+    visitor.visit_value(&op.left);
+    visitor.visit_value(&op.right);
+}
+
+pub fn walk_unary_op<V: IlVisitor + ?Sized>(visitor: &mut V, op: &UnaryOp) {
+    visitor.visit_register(op.dest);
+    visitor.visit_value(&op.left);
+}
+
+pub fn walk_concat<V: IlVisitor + ?Sized>(visitor: &mut V, concat: &Concat) {
+    visitor.visit_register(concat.dest);
+    visitor.visit_register(concat.start);
+    visitor.visit_register(concat.end);
+}
+
+pub fn walk_jump<V: IlVisitor + ?Sized>(visitor: &mut V, jump: &Jump) {
+    visitor.visit_label(jump.target);
+}
+
+pub fn walk_jump_not<V: IlVisitor + ?Sized>(visitor: &mut V, jump: &JumpNot) {
+    visitor.visit_label(jump.target);
+    visitor.visit_register(jump.cond);
+}
+
+pub fn walk_conditional_jump<V: IlVisitor + ?Sized>(visitor: &mut V, jump: &ConditionalJump) {
+    visitor.visit_label(jump.target);
+    visitor.visit_value(&jump.condition.left);
+    visitor.visit_value(&jump.condition.right);
+}
+
+pub fn walk_for_num_prep<V: IlVisitor + ?Sized>(visitor: &mut V, prep: &ForNumPrep) {
+    visitor.visit_label(prep.target);
+    visitor.visit_register(prep.index);
+    visitor.visit_register(prep.limit);
+    visitor.visit_register(prep.step);
+    visitor.visit_register(prep.var);
+}
+
+pub fn walk_for_num_loop<V: IlVisitor + ?Sized>(visitor: &mut V, next: &ForNumLoop) {
+    visitor.visit_label(next.target);
+    visitor.visit_register(next.index);
+    visitor.visit_register(next.limit);
+    visitor.visit_register(next.step);
+    visitor.visit_register(next.var);
+}
+
+pub fn walk_for_gen_prep<V: IlVisitor + ?Sized>(visitor: &mut V, prep: &ForGenPrep) {
+    visitor.visit_label(prep.target);
+    visitor.visit_register(prep.base);
+}
+
+pub fn walk_for_gen_call<V: IlVisitor + ?Sized>(visitor: &mut V, call: &ForGenCall) {
+    visitor.visit_register(call.base);
+    visitor.visit_register(call.dest);
+}
+
+pub fn walk_for_gen_loop<V: IlVisitor + ?Sized>(visitor: &mut V, next: &ForGenLoop) {
+    visitor.visit_label(next.target);
+    visitor.visit_register(next.base);
+    visitor.visit_register(next.var);
+}
+
+pub fn walk_new_table<V: IlVisitor + ?Sized>(visitor: &mut V, table: &NewTable) {
+    visitor.visit_register(table.dest);
+}
+
+pub fn walk_set_list<V: IlVisitor + ?Sized>(visitor: &mut V, list: &SetList) {
+    visitor.visit_register(list.table);
+    visitor.visit_register(list.start);
+}
+
+pub fn walk_return<V: IlVisitor + ?Sized>(visitor: &mut V, ret: &Return) {
+    visitor.visit_register(ret.result_start);
+}
+
+pub fn walk_call<V: IlVisitor + ?Sized>(visitor: &mut V, call: &Call) {
+    visitor.visit_register(call.callee);
+}
+
+pub fn walk_var_arg<V: IlVisitor + ?Sized>(visitor: &mut V, vararg: &VarArg) {
+    visitor.visit_register(vararg.dest);
+}
+
+pub fn walk_closure<V: IlVisitor + ?Sized>(visitor: &mut V, closure: &Closure) {
+    visitor.visit_register(closure.dest);
+    visitor.visit_prototype_index(closure.prototype);
+}
+
+pub fn walk_get_upvalue<V: IlVisitor + ?Sized>(visitor: &mut V, get: &GetUpvalue) {
+    visitor.visit_register(get.dest);
+    visitor.visit_upvalue_index(get.upvalue);
+}
+
+pub fn walk_set_upvalue<V: IlVisitor + ?Sized>(visitor: &mut V, set: &SetUpvalue) {
+    visitor.visit_register(set.src);
+    visitor.visit_upvalue_index(set.upvalue);
+}
+
+pub fn walk_close<V: IlVisitor + ?Sized>(visitor: &mut V, close: &Close) {
+    visitor.visit_register(close.start);
+}
+
+pub fn walk_function_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, function: &mut Function) {
+    rewriter.rewrite_chunk(&mut function.chunk);
+
+    for constant in &mut function.constants {
+        rewriter.rewrite_constant(constant);
+    }
+
+    for upvalue in &mut function.upvalues {
+        rewriter.rewrite_upvalue(upvalue);
+    }
+
+    for prototype in &mut function.prototypes {
+        rewriter.rewrite_function(prototype);
+    }
+}
+
+pub fn walk_chunk_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, chunk: &mut IlChunk) {
+    let instructions = std::mem::take(chunk).into_spanned();
+
+    // inserted instructions are attributed to the instruction they are inserted before
+    for (mut instruction, span) in instructions {
+        for inserted in rewriter.insert_before(&instruction) {
+            chunk.push_spanned(inserted, span);
+        }
+
+        rewriter.rewrite_instruction(&mut instruction);
+        chunk.push_spanned(instruction, span);
+    }
+}
+
+pub fn walk_instruction_mut<R: IlRewriter + ?Sized>(
+    rewriter: &mut R,
+    instruction: &mut Instruction,
+) {
+    match instruction {
+        Instruction::Label(label) => rewriter.rewrite_label(label),
+        Instruction::Load(load) => rewriter.rewrite_load(load),
+        Instruction::Move(mv) => rewriter.rewrite_move(mv),
+        Instruction::Intrinsic(intrinsic) => rewriter.rewrite_intrinsic(intrinsic),
+        Instruction::GetGlobal(get) => rewriter.rewrite_get_global(get),
+        Instruction::SetGlobal(set) => rewriter.rewrite_set_global(set),
+        Instruction::GetTable(get) => rewriter.rewrite_get_table(get),
+        Instruction::SetTable(set) => rewriter.rewrite_set_table(set),
+        Instruction::SelfLookup(lookup) => rewriter.rewrite_self_lookup(lookup),
+        Instruction::BinaryOp(op) => rewriter.rewrite_binary_op(op),
+        Instruction::UnaryOp(op) => rewriter.rewrite_unary_op(op),
+        Instruction::Concat(concat) => rewriter.rewrite_concat(concat),
+        Instruction::Jump(jump) => rewriter.rewrite_jump(jump),
+        Instruction::JumpNot(jump) => rewriter.rewrite_jump_not(jump),
+        Instruction::ConditionalJump(jump) => rewriter.rewrite_conditional_jump(jump),
+        Instruction::ForNumPrep(prep) => rewriter.rewrite_for_num_prep(prep),
+        Instruction::ForNumLoop(next) => rewriter.rewrite_for_num_loop(next),
+        Instruction::ForGenPrep(prep) => rewriter.rewrite_for_gen_prep(prep),
+        Instruction::ForGenCall(call) => rewriter.rewrite_for_gen_call(call),
+        Instruction::ForGenLoop(next) => rewriter.rewrite_for_gen_loop(next),
+        Instruction::NewTable(table) => rewriter.rewrite_new_table(table),
+        Instruction::SetList(list) => rewriter.rewrite_set_list(list),
+        Instruction::Return(ret) => rewriter.rewrite_return(ret),
+        Instruction::Call(call) => rewriter.rewrite_call(call),
+        Instruction::VarArg(vararg) => rewriter.rewrite_var_arg(vararg),
+        Instruction::Closure(closure) => rewriter.rewrite_closure(closure),
+        Instruction::GetUpvalue(get) => rewriter.rewrite_get_upvalue(get),
+        Instruction::SetUpvalue(set) => rewriter.rewrite_set_upvalue(set),
+        Instruction::Close(close) => rewriter.rewrite_close(close),
+    }
+}
+
+pub fn walk_constant_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, constant: &mut Constant) {
+    if let Constant::Table(table) = constant {
+        rewriter.rewrite_table(table);
+    }
+}
+
+/// Rewrites the keys and values of `table`. Keys of maps are taken out and inserted again,
+/// so rewritten keys keep their order.
+pub fn walk_table_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, table: &mut Table) {
+    match table {
+        Table::Map(map) => {
+            *map = std::mem::take(map)
+                .into_iter()
+                .map(|(mut key, mut value)| {
+                    rewriter.rewrite_value(&mut key);
+                    rewriter.rewrite_value(&mut value);
+
+                    (key, value)
+                })
+                .collect();
+        }
+        Table::Array(array) => {
+            for value in array {
+                rewriter.rewrite_value(value);
+            }
+        }
+    }
+}
+
+pub fn walk_value_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, value: &mut Value) {
+    match value {
+        Value::StackIndex(index) => rewriter.rewrite_register(index),
+        Value::ConstantIndex(index) => rewriter.rewrite_constant_index(index),
+        Value::Nil | Value::Boolean(_) | Value::Immediate(_) => {}
+    }
+}
+
+pub fn walk_load_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, load: &mut Load) {
+    rewriter.rewrite_register(&mut load.dest);
+    rewriter.rewrite_value(&mut load.src);
+}
+
+pub fn walk_move_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, mv: &mut Move) {
+    rewriter.rewrite_register(&mut mv.dest);
+    rewriter.rewrite_register(&mut mv.src);
+}
+
+pub fn walk_intrinsic_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, intrinsic: &mut Intrinsic) {
+    rewriter.rewrite_register(&mut intrinsic.dest);
+    match &mut intrinsic.kind {
+        IntrinsicKind::BitAnd(left, right)
+        | IntrinsicKind::BitOr(left, right)
+        | IntrinsicKind::BitXor(left, right)
+        | IntrinsicKind::LeftShift(left, right)
+        | IntrinsicKind::RightShift(left, right) => {
+            rewriter.rewrite_value(left);
+            rewriter.rewrite_value(right);
+        }
+        IntrinsicKind::BitNot(operand) => rewriter.rewrite_value(operand),
+    }
+}
+
+pub fn walk_get_global_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, get: &mut GetGlobal) {
+    rewriter.rewrite_register(&mut get.dest);
+    rewriter.rewrite_constant_index(&mut get.constant);
+}
+
+pub fn walk_set_global_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, set: &mut SetGlobal) {
+    rewriter.rewrite_register(&mut set.src);
+    rewriter.rewrite_constant_index(&mut set.constant);
+}
+
+pub fn walk_get_table_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, get: &mut GetTable) {
+    rewriter.rewrite_register(&mut get.dest);
+    rewriter.rewrite_register(&mut get.source);
+    rewriter.rewrite_value(&mut get.key);
+}
+
+pub fn walk_set_table_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, set: &mut SetTable) {
+    rewriter.rewrite_register(&mut set.table);
+    rewriter.rewrite_value(&mut set.key);
+    rewriter.rewrite_value(&mut set.value);
+}
+
+pub fn walk_self_lookup_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, lookup: &mut SelfLookup) {
+    rewriter.rewrite_register(&mut lookup.dest);
+    rewriter.rewrite_register(&mut lookup.object);
+    rewriter.rewrite_value(&mut lookup.key);
+}
+
+pub fn walk_binary_op_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, op: &mut BinaryOp) {
+    rewriter.rewrite_register(&mut op.dest);
+    rewriter.rewrite_value(&mut op.left);
+    rewriter.rewrite_value(&mut op.right);
+}
+
+pub fn walk_unary_op_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, op: &mut UnaryOp) {
+    rewriter.rewrite_register(&mut op.dest);
+    rewriter.rewrite_value(&mut op.left);
+}
+
+pub fn walk_concat_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, concat: &mut Concat) {
+    rewriter.rewrite_register(&mut concat.dest);
+    rewriter.rewrite_register(&mut concat.start);
+    rewriter.rewrite_register(&mut concat.end);
+}
+
+pub fn walk_jump_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, jump: &mut Jump) {
+    rewriter.rewrite_label(&mut jump.target);
+}
+
+pub fn walk_jump_not_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, jump: &mut JumpNot) {
+    rewriter.rewrite_label(&mut jump.target);
+    rewriter.rewrite_register(&mut jump.cond);
+}
+
+pub fn walk_conditional_jump_mut<R: IlRewriter + ?Sized>(
+    rewriter: &mut R,
+    jump: &mut ConditionalJump,
+) {
+    rewriter.rewrite_label(&mut jump.target);
+    rewriter.rewrite_value(&mut jump.condition.left);
+    rewriter.rewrite_value(&mut jump.condition.right);
+}
+
+pub fn walk_for_num_prep_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, prep: &mut ForNumPrep) {
+    rewriter.rewrite_label(&mut prep.target);
+    rewriter.rewrite_register(&mut prep.index);
+    rewriter.rewrite_register(&mut prep.limit);
+    rewriter.rewrite_register(&mut prep.step);
+    rewriter.rewrite_register(&mut prep.var);
+}
+
+pub fn walk_for_num_loop_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, next: &mut ForNumLoop) {
+    rewriter.rewrite_label(&mut next.target);
+    rewriter.rewrite_register(&mut next.index);
+    rewriter.rewrite_register(&mut next.limit);
+    rewriter.rewrite_register(&mut next.step);
+    rewriter.rewrite_register(&mut next.var);
+}
+
+pub fn walk_for_gen_prep_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, prep: &mut ForGenPrep) {
+    rewriter.rewrite_label(&mut prep.target);
+    rewriter.rewrite_register(&mut prep.base);
+}
+
+pub fn walk_for_gen_call_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, call: &mut ForGenCall) {
+    rewriter.rewrite_register(&mut call.base);
+    rewriter.rewrite_register(&mut call.dest);
+}
+
+pub fn walk_for_gen_loop_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, next: &mut ForGenLoop) {
+    rewriter.rewrite_label(&mut next.target);
+    rewriter.rewrite_register(&mut next.base);
+    rewriter.rewrite_register(&mut next.var);
+}
+
+pub fn walk_new_table_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, table: &mut NewTable) {
+    rewriter.rewrite_register(&mut table.dest);
+}
+
+pub fn walk_set_list_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, list: &mut SetList) {
+    rewriter.rewrite_register(&mut list.table);
+    rewriter.rewrite_register(&mut list.start);
+}
+
+pub fn walk_return_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, ret: &mut Return) {
+    rewriter.rewrite_register(&mut ret.result_start);
+}
+
+pub fn walk_call_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, call: &mut Call) {
+    rewriter.rewrite_register(&mut call.callee);
+}
+
+pub fn walk_var_arg_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, vararg: &mut VarArg) {
+    rewriter.rewrite_register(&mut vararg.dest);
+}
+
+pub fn walk_closure_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, closure: &mut Closure) {
+    rewriter.rewrite_register(&mut closure.dest);
+    rewriter.rewrite_prototype_index(&mut closure.prototype);
+}
+
+pub fn walk_get_upvalue_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, get: &mut GetUpvalue) {
+    rewriter.rewrite_register(&mut get.dest);
+    rewriter.rewrite_upvalue_index(&mut get.upvalue);
+}
+
+pub fn walk_set_upvalue_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, set: &mut SetUpvalue) {
+    rewriter.rewrite_register(&mut set.src);
+    rewriter.rewrite_upvalue_index(&mut set.upvalue);
+}
+
+pub fn walk_close_mut<R: IlRewriter + ?Sized>(rewriter: &mut R, close: &mut Close) {
+    rewriter.rewrite_register(&mut close.start);
+}
@@ -1 +1,4 @@
-// TODO: AIR representation
\ No newline at end of file
+// TODO: AIR representation
+// TODO: carry the `Span` of every instruction into AIR nodes once they exist. Until then
+// spans go from IL and CIR blocks straight into the statements of decompiled syntax trees,
+// and nothing in between drops them.
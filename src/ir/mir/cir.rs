@@ -1,261 +1,243 @@
-
 // TODO: remove once everything is used
 #![allow(unused)]
 
-use std::collections::HashMap;
+use std::ops::Range;
+
+use anyhow::{anyhow, Result};
+use cranelift_entity::{entity_impl, EntityRef, PrimaryMap};
+use petgraph::{graph::NodeIndex, prelude::DiGraph, visit::EdgeRef};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use petgraph::{dot::Dot, graph::NodeIndex, prelude::DiGraph, visit::EdgeRef};
+use crate::ir::{
+    il::{resolve_labels, IlChunk, Inst, Instruction},
+    span::Span,
+};
 
-use crate::ir::il::{IlChunk, Instruction};
+/// A handle to a basic block of a `CirGraph`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Block(u32);
+entity_impl!(Block, "block");
 
+/// The control flow graph of an `IlChunk`, whose nodes are blocks. A block is a range of
+/// the instructions of the chunk, which the graph owns, rather than a copy of them.
 #[derive(Default)]
-pub(crate) struct CirGraph(DiGraph<IlChunk, bool, usize>);
+pub struct CirGraph {
+    chunk: IlChunk,
+    blocks: PrimaryMap<Block, Range<Inst>>,
+    graph: DiGraph<Block, bool, usize>,
+}
 
 impl CirGraph {
-    pub(crate) fn inner(&self) -> &DiGraph<IlChunk, bool, usize> {
-        &self.0
+    pub fn inner(&self) -> &DiGraph<Block, bool, usize> {
+        &self.graph
     }
 
-    pub(crate) fn inner_mut(&mut self) -> &mut DiGraph<IlChunk, bool, usize> {
-        &mut self.0
+    pub(crate) fn inner_mut(&mut self) -> &mut DiGraph<Block, bool, usize> {
+        &mut self.graph
     }
 
-    pub(crate) fn new() -> Self {
-        Self::default()
+    pub(crate) fn new(chunk: IlChunk) -> Self {
+        Self {
+            chunk,
+            ..Self::default()
+        }
     }
-}
 
-fn graph_get_or_insert(
-    graph: &mut DiGraph<IlChunk, bool, usize>,
-    weight: IlChunk,
-) -> NodeIndex<usize> {
-    match graph
-        .node_weights()
-        .zip(graph.node_indices())
-        .find(|&(node_weight, _)| node_weight == &weight)
-    {
-        Some((_, idx)) => idx,
-        None => graph.add_node(weight).index().into(),
+    pub fn chunk(&self) -> &IlChunk {
+        &self.chunk
     }
-}
 
-fn split_blocks(
-    blocks: &HashMap<usize, &[Instruction]>,
-    src_block_index: usize,
-    target_block_index: usize,
-    graph: &mut DiGraph<IlChunk, bool, usize>,
-    removals: &mut Vec<(usize, usize, usize)>,
-    is_negated: bool,
-) -> NodeIndex<usize> {
-    let src_block = blocks.get(&src_block_index).unwrap();
-    for (&block_index, &curr_block) in blocks
-        .iter()
-        .filter(|&(&block_index, _)| block_index < target_block_index)
-    {
-        if (block_index..block_index + src_block.len()).contains(&target_block_index) {
-            let relative_target_index = target_block_index - block_index;
-
-            let src_node_index = graph_get_or_insert(graph, IlChunk::from(*src_block));
-
-            let leading_block_index =
-                graph_get_or_insert(graph, IlChunk::from(&curr_block[..relative_target_index]));
-
-            let trailing_block_index =
-                graph_get_or_insert(graph, IlChunk::from(&curr_block[relative_target_index..]));
-
-            graph.add_edge(
-                leading_block_index.into(),
-                trailing_block_index.into(),
-                true && !is_negated,
-            );
-
-            graph.add_edge(src_node_index.into(), trailing_block_index.into(), true);
-
-            removals.push((
-                target_block_index.into(),
-                leading_block_index.index(),
-                trailing_block_index.index(),
-            ));
-
-            return src_node_index;
-        }
+    /// The instructions of `block`.
+    pub fn block(&self, block: Block) -> &[Instruction] {
+        self.chunk.range(self.blocks[block].clone())
     }
 
-    panic!("Branch attempts to jump to invalid location");
-}
-
-pub(crate) fn into_cir_graph(instructions: Vec<Instruction>) {
-    let mut blocks: HashMap<usize, &[Instruction]> = HashMap::new();
+    /// The handles of the instructions of `block`, through which their spans are found.
+    pub fn insts(&self, block: Block) -> impl Iterator<Item = Inst> {
+        let range = &self.blocks[block];
 
-    let mut start = 0;
-
-    for (index, instruction) in instructions.iter().enumerate() {
-        if let Instruction::Jump(_) | Instruction::ConditionalJump(_) | Instruction::JumpNot(_) =
-            *instruction
-        {
-            blocks.insert(start, &instructions[start..index + 1]);
-            start = index + 1;
-        }
+        (range.start.index()..range.end.index()).map(Inst::new)
     }
 
-    blocks.insert(start, &instructions[start..]);
-
-    // println!("{blocks:#?}");
-
-    let mut cfg = CirGraph::new();
-
-    let graph = cfg.inner_mut();
+    /// The span of the first instruction of `block` that has one.
+    pub fn span(&self, block: Block) -> Option<Span> {
+        self.insts(block).find_map(|inst| self.chunk.span(inst))
+    }
+}
 
-    let mut to_be_removed = vec![];
+/// A block of a serialized `CirGraph`, as the range of the instructions it holds.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct BlockRepr {
+    start: usize,
+    end: usize,
+}
 
-    for (pc, src_block) in blocks.iter() {
-        match src_block.last() {
-            Some(Instruction::Jump(data)) => {
-                let target_block_index = data.branch.end;
+/// An edge of a serialized `CirGraph` between the blocks at the positions `from` and `to`.
+/// It is labeled `false` when it is taken because the condition of a `JumpNot` is falsy, or
+/// when a loop or another conditional jump falls through.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct EdgeRepr {
+    from: usize,
+    to: usize,
+    label: bool,
+}
 
-                match blocks.get(&target_block_index) {
-                    Some(target_block) => {
-                        let from_block = graph_get_or_insert(graph, IlChunk::from(*src_block));
+/// The serialized form of a `CirGraph`, which is
+/// `{"chunk": ..., "blocks": [{"start": 0, "end": 2}], "edges": [{"from": 0, "to": 1, "label": true}]}`
+/// with the chunk as described in `ir::il::schema`.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct CirGraphRepr {
+    chunk: IlChunk,
+    blocks: Vec<BlockRepr>,
+    edges: Vec<EdgeRepr>,
+}
 
-                        let to_block = graph_get_or_insert(graph, IlChunk::from(*target_block));
+#[cfg(feature = "serde")]
+impl Serialize for CirGraph {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let blocks = self
+            .graph
+            .node_weights()
+            .map(|&block| BlockRepr {
+                start: self.blocks[block].start.index(),
+                end: self.blocks[block].end.index(),
+            })
+            .collect();
+
+        let edges = self
+            .graph
+            .edge_references()
+            .map(|edge| EdgeRepr {
+                from: edge.source().index(),
+                to: edge.target().index(),
+                label: *edge.weight(),
+            })
+            .collect();
+
+        CirGraphRepr {
+            chunk: self.chunk.clone(),
+            blocks,
+            edges,
+        }
+        .serialize(serializer)
+    }
+}
 
-                        graph.add_edge(from_block, to_block, true);
-                    }
-                    None => {
-                        split_blocks(
-                            &blocks,
-                            *pc,
-                            target_block_index,
-                            graph,
-                            &mut to_be_removed,
-                            false,
-                        );
-                    }
-                };
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for CirGraph {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let repr = CirGraphRepr::deserialize(deserializer)?;
+        let mut cfg = CirGraph::new(repr.chunk);
+
+        for block in repr.blocks {
+            if block.start > block.end || block.end > cfg.chunk.len() {
+                return Err(D::Error::custom(format!(
+                    "block {}..{} is not within the {} instructions of the chunk",
+                    block.start,
+                    block.end,
+                    cfg.chunk.len()
+                )));
             }
 
-            Some(Instruction::ConditionalJump(data)) => {
-                let target_block_index = data.branch.end;
-
-                let source = match blocks.get(&target_block_index) {
-                    Some(target_block) => {
-                        let src_node = graph_get_or_insert(graph, IlChunk::from(*src_block));
-
-                        let to_node = graph_get_or_insert(graph, IlChunk::from(*target_block));
-
-                        graph.add_edge(src_node.into(), to_node.into(), true);
-
-                        src_node
-                    }
-                    None => {
-                        let src_node = split_blocks(
-                            &blocks,
-                            *pc,
-                            target_block_index,
-                            graph,
-                            &mut to_be_removed,
-                            false,
-                        );
-
-                        src_node
-                    }
-                };
-
-                let non_divergent = blocks.get(&(data.branch.start + 1)).unwrap();
+            let block = cfg
+                .blocks
+                .push(Inst::new(block.start)..Inst::new(block.end));
+            cfg.graph.add_node(block);
+        }
 
-                let next = graph_get_or_insert(graph, IlChunk::from(*non_divergent));
+        for edge in repr.edges {
+            let count = cfg.graph.node_count();
 
-                graph.add_edge(source, next, false);
+            if edge.from >= count || edge.to >= count {
+                return Err(D::Error::custom(format!(
+                    "edge from {} to {} leaves the {count} blocks",
+                    edge.from, edge.to
+                )));
             }
 
-            Some(Instruction::JumpNot(data)) => {
-                let target_block_index = data.branch.end;
-
-                let source = match blocks.get(&target_block_index) {
-                    Some(target_block) => {
-                        let original_source = graph_get_or_insert(graph, IlChunk::from(*src_block));
-
-                        let to = graph_get_or_insert(graph, IlChunk::from(*target_block));
-
-                        graph.add_edge(original_source.into(), to.into(), false);
+            cfg.graph
+                .add_edge(edge.from.into(), edge.to.into(), edge.label);
+        }
 
-                        original_source
-                    }
-                    None => {
-                        let original_source = split_blocks(
-                            &blocks,
-                            *pc,
-                            target_block_index,
-                            graph,
-                            &mut to_be_removed,
-                            true,
-                        );
+        Ok(cfg)
+    }
+}
 
-                        original_source.into()
-                    }
-                };
+/// Builds the control flow graph of `chunk`. Its blocks partition the chunk, in order: a
+/// block starts at the first instruction, at every jump target and after every instruction
+/// that may jump or never falls through.
+pub fn into_cir_graph(chunk: IlChunk) -> Result<CirGraph> {
+    let labels = resolve_labels(chunk.inner())?;
+    let len = chunk.len();
 
-                let non_divergent = blocks.get(&(data.branch.start + 1)).unwrap();
+    let mut leaders = vec![false; len + 1];
+    leaders[0] = true;
 
-                let next = graph_get_or_insert(graph, IlChunk::from(*non_divergent));
+    for (index, instruction) in chunk.inner().iter().enumerate() {
+        if let Some(target) = instruction.target() {
+            leaders[labels[&target]] = true;
+        }
 
-                graph.add_edge(source, next, true);
-            }
-            _ => continue,
+        if instruction.target().is_some() || instruction.is_terminator() {
+            leaders[index + 1] = true;
         }
     }
 
-    for (block_index, leading_block_index, trailing_block_index) in to_be_removed {
-        let mut edge_data = vec![];
+    let starts = (0..len).filter(|&index| leaders[index]).collect::<Vec<_>>();
+    let end = |block: usize| starts.get(block + 1).copied().unwrap_or(len);
 
-        for edge in graph.edges(block_index.into()) {
-            edge_data.push((
-                edge.id(),
-                edge.source().index(),
-                edge.target().index(),
-                edge.weight().clone(),
-            ));
-        }
+    let mut cfg = CirGraph::new(chunk);
 
-        for datum in edge_data {
-            let (index, source, target, weight) = datum;
-
-            graph.remove_edge(index.into());
-
-            if source == block_index {
-                if target == source {
-                    dbg!(graph.node_weight(block_index.into()));
-                    graph
-                        .add_edge(
-                            trailing_block_index.into(),
-                            dbg!(leading_block_index.into()),
-                            weight,
-                        )
-                        .index();
-                } else {
-                    graph.add_edge(trailing_block_index.into(), dbg!(target.into()), weight);
-                }
-            } else {
-                graph.add_edge(source.into(), leading_block_index.into(), weight);
-            }
+    // nodes are added in the order of the blocks, so a block's position is its node index
+    for (position, &start) in starts.iter().enumerate() {
+        let block = cfg.blocks.push(Inst::new(start)..Inst::new(end(position)));
+        cfg.graph.add_node(block);
+    }
 
-            graph.remove_edge(index);
+    let node = |index: usize| {
+        starts
+            .binary_search(&index)
+            .map(NodeIndex::new)
+            .map_err(|_| anyhow!("instruction {index} does not start a block"))
+    };
+
+    for block in 0..starts.len() {
+        let last = &cfg.chunk.inner()[end(block) - 1];
+        let next = Some(block + 1)
+            .filter(|&next| next < starts.len())
+            .map(NodeIndex::new);
+        let target = match last.target() {
+            Some(target) => Some(node(labels[&target])?),
+            None => None,
+        };
+
+        let edges = match last {
+            Instruction::JumpNot(_) => [(target, false), (next, true)],
+            last if last.is_terminator() => [(target, true), (None, true)],
+            // loops and conditional jumps jump while they continue and fall through once
+            // they are done
+            _ if target.is_some() => [(target, true), (next, false)],
+            _ => [(next, true), (None, true)],
+        };
+
+        for (to, label) in edges {
+            if let Some(to) = to {
+                cfg.graph.add_edge(NodeIndex::new(block), to, label);
+            }
         }
-
-        graph.remove_node(block_index.into());
     }
 
-    let graph = cfg.inner();
-
-    // dbg!(graph);
-
-    println!("{:#?}", Dot::with_config(graph, &[]));
+    Ok(cfg)
 }
 
 impl From<IlChunk> for CirGraph {
-    fn from(_chunk: IlChunk) -> Self {
-        let _cfg = CirGraph::new();
-
-        Self::default()
+    fn from(chunk: IlChunk) -> Self {
+        CirGraph::new(chunk)
     }
 }
@@ -1,63 +1,204 @@
 #![cfg(test)]
-use crate::ir::il::{
-    BinaryOp, BinaryOpKind, Condition, ConditionKind, ConditionalJump, Instruction, Jump,
-    JumpBranch, Load, Return, Value,
-};
+use crate::ir::{il::IlChunk, span::Span};
+use cranelift_entity::EntityRef;
+use std::ops::Range;
 
 use super::cir::*;
 
+fn chunk(text: &str) -> IlChunk {
+    text.parse().unwrap()
+}
+
+/// The instruction ranges of the blocks of a graph in node order, and its edges as pairs of
+/// block positions along with their labels.
+type Shape = (Vec<Range<usize>>, Vec<(usize, usize, bool)>);
+
+fn shape(cfg: &CirGraph) -> Shape {
+    let blocks = cfg
+        .inner()
+        .node_weights()
+        .map(|&block| {
+            let mut insts = cfg.insts(block).map(|inst| inst.index());
+            let start = insts.next().unwrap();
+
+            start..insts.last().unwrap_or(start) + 1
+        })
+        .collect();
+
+    let mut edges = cfg
+        .inner()
+        .raw_edges()
+        .iter()
+        .map(|edge| (edge.source().index(), edge.target().index(), edge.weight))
+        .collect::<Vec<_>>();
+    edges.sort();
+
+    (blocks, edges)
+}
+
 #[test]
 fn numeric_while_loop() {
-    let code = vec![
-        Instruction::Load(Box::new(Load {
-            dest: 0,
-            src: Value::StackIndex(0),
-        })),
-        Instruction::Jump(Box::new(Jump {
-            branch: JumpBranch {
-                start: 1,
-                end: 3,
-                offset: 2,
-            },
-        })),
-        Instruction::BinaryOp(Box::new(BinaryOp {
-            operator: BinaryOpKind::Add,
-            dest: 0,
-            left: Value::ConstantIndex(0),
-            right: Value::ConstantIndex(0),
-        })),
-        Instruction::ConditionalJump(Box::new(ConditionalJump {
-            branch: JumpBranch {
-                start: 3,
-                end: 2,
-                offset: -1,
-            },
-            condition: Condition {
-                kind: ConditionKind::Lt,
-                left: Value::ConstantIndex(0),
-                right: Value::ConstantIndex(251),
-            },
-        })),
-        Instruction::Return(Box::new(Return {
-            result_count: 0,
-            result_start: 0,
-        })),
-    ];
-    into_cir_graph(code)
+    let code = chunk(
+        "
+        r0 = r0
+        jump L1
+        L0:
+        r0 = k0 + k0
+        L1:
+        jumpif k0 lt k251 L0
+        return r0, 0
+        ",
+    );
+    let (blocks, edges) = shape(&into_cir_graph(code).unwrap());
+
+    assert_eq!(blocks, [0..2, 2..4, 4..6, 6..7]);
+    assert_eq!(
+        edges,
+        [(0, 2, true), (1, 2, true), (2, 1, true), (2, 3, false)]
+    );
+}
+
+#[test]
+fn numeric_for_loop() {
+    let code = chunk(
+        "
+        fornprep r0, r1, r2, r3 L1
+        L0:
+        r4 = r4 + r3
+        fornloop r0, r1, r2, r3 L0
+        L1:
+        return r4, 1
+        ",
+    );
+    let (blocks, edges) = shape(&into_cir_graph(code).unwrap());
+
+    // the body loops onto itself until the loop is done
+    assert_eq!(blocks, [0..1, 1..4, 4..6]);
+    assert_eq!(
+        edges,
+        [(0, 1, false), (0, 2, true), (1, 1, true), (1, 2, false)]
+    );
+}
+
+#[test]
+fn straight_line_code_is_one_block() {
+    let (blocks, edges) = shape(&into_cir_graph(chunk("r0 = k0\nreturn r0, 1")).unwrap());
+
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0], 0..2);
+    assert!(edges.is_empty());
 }
 
 #[test]
 fn some_other_code() {
     // let code2 = vec![
-    //     Instruction::Load(Box::new(Load {
+    //     Instruction::Load(Load {
     //         dest: 0,
     //         src: Value::StackIndex(0)
-    //     })),
-    //     Instruction::SetGlobal(Box::new(SetGlobal {
+    //     }),
+    //     Instruction::SetGlobal(SetGlobal {
     //         src: -1,
     //         constant: 0
     //     },
 
-    // ))
+    // )
     // ];
 }
+
+#[test]
+fn blocks_are_ranges_of_the_chunk() {
+    let code = chunk(
+        "
+        fornprep r0, r1, r2, r3 L1
+        L0:
+        r4 = r4 + r3
+        fornloop r0, r1, r2, r3 L0
+        L1:
+        return r4, 1
+        ",
+    );
+    let cfg = into_cir_graph(code).unwrap();
+
+    // blocks borrow from the chunk, so ordering them by address orders them by position
+    let mut blocks = cfg
+        .inner()
+        .node_weights()
+        .map(|&block| cfg.block(block))
+        .collect::<Vec<_>>();
+    blocks.sort_by_key(|block| block.as_ptr());
+
+    assert_eq!(blocks.len(), 3);
+    assert_eq!(blocks.concat(), cfg.chunk().inner());
+}
+
+#[test]
+fn blocks_keep_their_spans() {
+    let code = chunk(
+        "
+        r0 = k0 @1
+        jumpnot r0 L0
+        r0 = k1 @2:5
+        L0:
+        return r0, 1 @3
+        ",
+    );
+    let cfg = into_cir_graph(code).unwrap();
+
+    let mut spans = cfg
+        .inner()
+        .node_weights()
+        .map(|&block| cfg.span(block))
+        .collect::<Vec<_>>();
+    spans.sort();
+
+    assert_eq!(
+        spans,
+        vec![
+            Some(Span::line(1)),
+            Some(Span::new(2, 5)),
+            Some(Span::line(3))
+        ]
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn graphs_round_trip_through_json() {
+    let code = chunk(
+        "
+        r0 = k0 @1
+        jumpnot r0 L0
+        r0 = k1
+        L0:
+        return r0, 1
+        ",
+    );
+    let cfg = into_cir_graph(code).unwrap();
+
+    let json = serde_json::to_string(&cfg).unwrap();
+    let back = serde_json::from_str::<CirGraph>(&json).unwrap();
+
+    assert_eq!(back.chunk().inner(), cfg.chunk().inner());
+    assert_eq!(back.inner().node_count(), cfg.inner().node_count());
+
+    let edges = |cfg: &CirGraph| {
+        cfg.inner()
+            .raw_edges()
+            .iter()
+            .map(|edge| {
+                (
+                    cfg.block(cfg.inner()[edge.source()]).to_vec(),
+                    cfg.block(cfg.inner()[edge.target()]).to_vec(),
+                    edge.weight,
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(edges(&back), edges(&cfg));
+    assert!(edges(&cfg).iter().any(|&(_, _, label)| !label));
+
+    let mut broken = serde_json::to_value(&cfg).unwrap();
+    broken["edges"][0]["to"] = serde_json::json!(9);
+    assert!(serde_json::from_value::<CirGraph>(broken).is_err());
+}
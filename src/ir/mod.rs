@@ -1,3 +1,6 @@
+/// The abstract syntax tree of Lua source code and its parser.
+pub mod ast;
+
 /// The LUNIR high-level intermediate representation.
 pub mod hir;
 
@@ -6,3 +9,6 @@ pub mod il;
 
 /// The LUNIR mid-level intermediate representations.
 pub mod mir;
+
+/// Positions in source code, which every representation carries along.
+pub mod span;
@@ -22,14 +22,17 @@
 
 #![doc = include_str!("../README.md")]
 
+/// The LUNIR intermediate representations, requires the `ir` feature to be enabled, which
+/// every pipeline enables.
 #[cfg(feature = "ir")]
 pub mod ir;
 
-#[cfg(not(feature = "ir"))]
-pub(crate) mod ir;
+/// Serializers and deserializers for the bytecode formats supported by LUNIR.
+#[cfg(feature = "ir")]
+pub mod formats;
 
-/// The LUNIR compilation and decompilation pipelines, requires the `compile` or `decompile` features to be enabled..
-#[cfg(any(feature = "compile", feature = "decompile"))]
+/// The LUNIR compilation, decompilation and transpilation pipelines, requires the `compile`, `decompile` or `transpile` features to be enabled.
+#[cfg(any(feature = "compile", feature = "decompile", feature = "transpile"))]
 pub mod pipelines;
 
 /// Commonly used types and functions.
@@ -0,0 +1,2155 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Code generation, which compiles a syntax tree into LUNIR intermediate language.
+//!
+//! Locals live in consecutive registers from 0 up in the order they are declared, so the
+//! register of a local is its position among the locals in scope. Temporaries are allocated
+//! above them and released at the end of every statement.
+//!
+//! Folding arithmetic on number literals is the only optimisation made, so
+//! `OptimizationLevel::All` compiles the same as `OptimizationLevel::Moderate`.
+
+use crate::formats::LoopLayout;
+use crate::ir::ast::tree::{
+    BinaryOperator, Block, Expression, ExpressionKind, Field, FunctionBody, FunctionName,
+    LocalName, Node, Statement, StatementKind, UnaryOperator,
+};
+use crate::ir::il::{
+    Arity, BinaryOp, BinaryOpKind, Call, Close, Closure, Concat, Condition, ConditionKind,
+    ConditionalJump, Constant, DebugInfo, ForGenCall, ForGenLoop, ForGenPrep, ForNumLoop,
+    ForNumPrep, Function, GetGlobal, GetTable, GetUpvalue, IlChunk, Inst, Instruction, Intrinsic,
+    IntrinsicKind, IntrinsicSource, Jump, JumpNot, Label, Load, Local, Move, NewTable, OptVariable,
+    Return, SelfLookup, SetGlobal, SetList, SetTable, SetUpvalue, UnaryOp, UnaryOpKind, Upvalue,
+    Value, VarArg,
+};
+use crate::ir::span::Span;
+use anyhow::{bail, ensure, Result};
+use cranelift_entity::EntityRef;
+use std::collections::{HashMap, HashSet};
+
+/// The most registers a function may use, the `MAXSTACK` limit of Lua 5.1.
+const MAX_REGISTERS: usize = 250;
+
+/// How many array items a table constructor stores with one `SetList`, the
+/// `LFIELDS_PER_FLUSH` of the reference implementation.
+const FIELDS_PER_FLUSH: usize = 50;
+
+/// Compiles `tree` into the main function of a chunk, laying out `for` loops as `layout`
+/// and folding constants when `fold` is set.
+pub(crate) fn compile(tree: &Node, layout: LoopLayout, fold: bool) -> Result<Function> {
+    let mut generator = Generator {
+        layout,
+        fold,
+        functions: vec![FunctionState::new(None, 0)],
+    };
+
+    let function = generator.function_mut();
+    function.arity = Arity::vararg(0);
+
+    generator.enter();
+    generator.statements(&tree.block.statements)?;
+    generator.leave(false)?;
+    generator.emit(Instruction::Return(Return {
+        result_start: 0,
+        result_count: OptVariable::Number(0),
+    }));
+
+    generator.functions.pop().unwrap().finish()
+}
+
+/// A local declared in a function.
+struct LocalState {
+    name: String,
+    register: usize,
+    /// Whether a closure captures the local, which requires closing it when it goes out of
+    /// scope.
+    captured: bool,
+    /// The instruction the local is in scope from.
+    start: usize,
+    /// The instruction the local goes out of scope at, unless it is still in scope.
+    end: Option<usize>,
+}
+
+/// A block of the function being compiled.
+struct Scope {
+    /// The number of locals in scope when the block was entered.
+    active: usize,
+    /// The number of visible labels when the block was entered.
+    labels: usize,
+}
+
+/// A loop that `break` and `continue` can leave.
+struct Loop {
+    exit: Label,
+    next: Label,
+    /// The number of locals in scope outside the body of the loop.
+    active: usize,
+}
+
+/// A `goto` label visible from the statement being compiled.
+struct LabelState {
+    name: String,
+    label: Label,
+    /// The number of locals in scope at the label.
+    active: usize,
+}
+
+/// A `goto` whose label has not been found yet.
+struct Goto {
+    name: String,
+    /// The instruction jumping to the label once it is found.
+    jump: usize,
+    /// The close of the locals the jump leaves, as an index into `FunctionState::closes`.
+    close: usize,
+    /// The locals in scope at the `goto`.
+    locals: Vec<usize>,
+    /// The number of scopes the `goto` is nested in, lowered as they are left.
+    depth: usize,
+    /// The number of locals the `goto` keeps in scope, lowered as scopes are left.
+    active: usize,
+}
+
+/// A `Close` of the locals left by a jump or the end of a block, which is only kept if a
+/// closure captures one of them. Whether one does is only known once the function is done.
+struct PendingClose {
+    instruction: usize,
+    locals: Vec<usize>,
+}
+
+/// How a name resolves in the function being compiled.
+enum Variable {
+    Local(usize),
+    Upvalue(usize),
+    /// A global, named by a string constant.
+    Global(usize),
+}
+
+/// Where an assignment stores a value.
+enum Place {
+    Local(usize),
+    Upvalue(usize),
+    Global(usize),
+    Index { table: usize, key: Value },
+}
+
+/// A function being compiled.
+struct FunctionState {
+    code: Vec<(Instruction, Option<Span>)>,
+    constants: Vec<Constant>,
+    constant_indices: HashMap<Constant, usize>,
+    prototypes: Vec<Function>,
+    upvalues: Vec<Upvalue>,
+    upvalue_names: Vec<String>,
+    arity: Arity,
+    name: Option<String>,
+    line_defined: u32,
+    last_line: u32,
+    /// The span attributed to the instructions being emitted.
+    span: Option<Span>,
+
+    locals: Vec<LocalState>,
+    /// The locals in scope, whose registers are their positions in this list.
+    active: Vec<usize>,
+    /// The first register that is not in use.
+    free: usize,
+    max_stack_size: usize,
+    scopes: Vec<Scope>,
+    loops: Vec<Loop>,
+    next_label: usize,
+    labels: Vec<LabelState>,
+    gotos: Vec<Goto>,
+    closes: Vec<PendingClose>,
+}
+
+impl FunctionState {
+    fn new(name: Option<String>, line_defined: u32) -> Self {
+        Self {
+            code: vec![],
+            constants: vec![],
+            constant_indices: HashMap::new(),
+            prototypes: vec![],
+            upvalues: vec![],
+            upvalue_names: vec![],
+            arity: Arity::default(),
+            name,
+            line_defined,
+            last_line: line_defined,
+            span: None,
+            locals: vec![],
+            active: vec![],
+            free: 0,
+            max_stack_size: 0,
+            scopes: vec![],
+            loops: vec![],
+            next_label: 0,
+            labels: vec![],
+            gotos: vec![],
+            closes: vec![],
+        }
+    }
+
+    /// The local in scope named `name`, the one declared last if several are.
+    fn find_local(&self, name: &str) -> Option<usize> {
+        self.active
+            .iter()
+            .rev()
+            .copied()
+            .find(|&id| self.locals[id].name == name)
+    }
+
+    /// Turns the compiled code into a function, dropping the closes of locals that no
+    /// closure captured.
+    fn finish(self) -> Result<Function> {
+        if let Some(goto) = self.gotos.first() {
+            bail!("no visible label '{}' for goto", goto.name);
+        }
+
+        let locals = &self.locals;
+        let dropped = self
+            .closes
+            .iter()
+            .filter(|close| !close.locals.iter().any(|&id| locals[id].captured))
+            .map(|close| close.instruction)
+            .collect::<HashSet<_>>();
+
+        // where every instruction ends up once the dropped ones are gone
+        let mut positions = Vec::with_capacity(self.code.len() + 1);
+        let mut chunk = IlChunk::default();
+
+        for (index, (instruction, span)) in self.code.into_iter().enumerate() {
+            positions.push(chunk.len());
+
+            if !dropped.contains(&index) {
+                chunk.push_spanned(instruction, span);
+            }
+        }
+
+        positions.push(chunk.len());
+
+        let locals = self
+            .locals
+            .into_iter()
+            .map(|local| Local {
+                name: local.name,
+                register: local.register,
+                start: Inst::new(positions[local.start]),
+                end: Inst::new(positions[local.end.unwrap_or(positions.len() - 1)]),
+            })
+            .collect();
+
+        Ok(Function {
+            chunk,
+            constants: self.constants,
+            prototypes: self.prototypes,
+            upvalues: self.upvalues,
+            arity: self.arity,
+            name: self.name,
+            max_stack_size: self.max_stack_size.max(2) as u8,
+            debug: DebugInfo {
+                source: None,
+                line_defined: self.line_defined,
+                last_line_defined: if self.line_defined == 0 {
+                    0
+                } else {
+                    self.last_line
+                },
+                locals,
+                upvalue_names: self.upvalue_names,
+            },
+        })
+    }
+}
+
+/// Compiles the functions of a syntax tree, the innermost one being compiled last.
+struct Generator {
+    layout: LoopLayout,
+    fold: bool,
+    functions: Vec<FunctionState>,
+}
+
+impl Generator {
+    fn function_mut(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn function(&self) -> &FunctionState {
+        self.functions.last().unwrap()
+    }
+
+    /// Appends `instruction` to the function being compiled, returning its index.
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        let function = self.function_mut();
+        function.code.push((instruction, function.span));
+
+        function.code.len() - 1
+    }
+
+    fn new_label(&mut self) -> Label {
+        let function = self.function_mut();
+        function.next_label += 1;
+
+        Label(function.next_label - 1)
+    }
+
+    /// Marks the position of `label`.
+    fn mark(&mut self, label: Label) {
+        self.emit(Instruction::Label(label));
+    }
+
+    fn jump(&mut self, target: Label) {
+        self.emit(Instruction::Jump(Jump { target }));
+    }
+
+    fn load(&mut self, dest: usize, src: Value) {
+        self.emit(Instruction::Load(Load { dest, src }));
+    }
+
+    fn constant(&mut self, constant: Constant) -> usize {
+        let function = self.function_mut();
+
+        if let Some(&index) = function.constant_indices.get(&constant) {
+            return index;
+        }
+
+        function.constants.push(constant.clone());
+        function
+            .constant_indices
+            .insert(constant, function.constants.len() - 1);
+
+        function.constants.len() - 1
+    }
+
+    fn string(&mut self, string: &[u8]) -> usize {
+        self.constant(Constant::String(string.to_vec()))
+    }
+
+    /// Marks the registers up to `free` as in use.
+    fn set_free(&mut self, free: usize) -> Result<()> {
+        ensure!(
+            free <= MAX_REGISTERS,
+            "function needs more than {MAX_REGISTERS} registers"
+        );
+
+        let function = self.function_mut();
+        function.free = free;
+        function.max_stack_size = function.max_stack_size.max(free);
+
+        Ok(())
+    }
+
+    /// Allocates `count` registers above the ones in use, returning the first of them.
+    fn reserve(&mut self, count: usize) -> Result<usize> {
+        let first = self.function().free;
+        self.set_free(first + count)?;
+
+        Ok(first)
+    }
+
+    /// Declares a local named `name`, which lives in the next register. The register must
+    /// already be allocated.
+    fn declare(&mut self, name: &str) -> usize {
+        let function = self.function_mut();
+        let register = function.active.len();
+
+        function.locals.push(LocalState {
+            name: name.to_owned(),
+            register,
+            captured: false,
+            start: function.code.len(),
+            end: None,
+        });
+        function.active.push(function.locals.len() - 1);
+
+        register
+    }
+
+    fn enter(&mut self) {
+        let function = self.function_mut();
+
+        function.scopes.push(Scope {
+            active: function.active.len(),
+            labels: function.labels.len(),
+        });
+    }
+
+    /// Leaves the innermost scope, closing its locals first if `close` is set.
+    fn leave(&mut self, close: bool) -> Result<()> {
+        let active = self.function().scopes.last().unwrap().active;
+
+        if close {
+            self.close(active);
+        }
+
+        let function = self.function_mut();
+        let scope = function.scopes.pop().unwrap();
+        let end = function.code.len();
+
+        for id in function.active.drain(scope.active..) {
+            function.locals[id].end = Some(end);
+        }
+
+        function.labels.truncate(scope.labels);
+        function.free = function.active.len();
+
+        // the gotos left pending now jump out of the scope
+        let depth = function.scopes.len();
+        for goto in &mut function.gotos {
+            if goto.depth > depth {
+                goto.depth = depth;
+                goto.active = goto.active.min(scope.active);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Closes the locals in scope from the register `start` up.
+    fn close(&mut self, start: usize) {
+        let locals = self.function().active[start.min(self.function().active.len())..].to_vec();
+
+        if locals.is_empty() {
+            return;
+        }
+
+        let instruction = self.emit(Instruction::Close(Close { start }));
+        self.function_mut().closes.push(PendingClose {
+            instruction,
+            locals,
+        });
+    }
+
+    /// Resolves `name`, capturing it from the enclosing functions if it is one of their
+    /// locals.
+    fn variable(&mut self, name: &str) -> Variable {
+        if let Some(id) = self.function().find_local(name) {
+            return Variable::Local(self.function().locals[id].register);
+        }
+
+        match self.upvalue(self.functions.len() - 1, name) {
+            Some(index) => Variable::Upvalue(index),
+            None => Variable::Global(self.string(name.as_bytes())),
+        }
+    }
+
+    /// The upvalue of the function at `depth` named `name`, added to it if needed. `None`
+    /// when no enclosing function has a local with the name.
+    fn upvalue(&mut self, depth: usize, name: &str) -> Option<usize> {
+        let function = &self.functions[depth];
+
+        if let Some(index) = function.upvalue_names.iter().position(|n| n == name) {
+            return Some(index);
+        }
+
+        let parent = depth.checked_sub(1)?;
+        let upvalue = match self.functions[parent].find_local(name) {
+            Some(id) => {
+                let local = &mut self.functions[parent].locals[id];
+                local.captured = true;
+
+                Upvalue {
+                    in_stack: true,
+                    index: local.register,
+                }
+            }
+            None => Upvalue {
+                in_stack: false,
+                index: self.upvalue(parent, name)?,
+            },
+        };
+
+        let function = &mut self.functions[depth];
+        function.upvalues.push(upvalue);
+        function.upvalue_names.push(name.to_owned());
+
+        Some(function.upvalues.len() - 1)
+    }
+
+    fn statements(&mut self, statements: &[Statement]) -> Result<()> {
+        for (index, statement) in statements.iter().enumerate() {
+            self.statement(statement, &statements[index + 1..])?;
+
+            let function = self.function_mut();
+            function.free = function.active.len();
+        }
+
+        Ok(())
+    }
+
+    fn block(&mut self, block: &Block) -> Result<()> {
+        self.enter();
+        self.statements(&block.statements)?;
+        self.leave(true)
+    }
+
+    /// Compiles `statement`, which is followed by `rest` in its block.
+    fn statement(&mut self, statement: &Statement, rest: &[Statement]) -> Result<()> {
+        let function = self.function_mut();
+        function.span = Some(statement.span);
+        function.last_line = function.last_line.max(statement.span.line);
+
+        match &statement.kind {
+            StatementKind::Local { names, values } => self.local(names, values)?,
+            StatementKind::Assign { targets, values } => self.assign(targets, values)?,
+            StatementKind::CompoundAssign {
+                operator,
+                target,
+                value,
+            } => self.compound_assign(*operator, target, value)?,
+            StatementKind::Call(call) => {
+                self.call(call, OptVariable::Number(0))?;
+            }
+            StatementKind::Do(block) => self.block(block)?,
+            StatementKind::While { condition, block } => {
+                let start = self.new_label();
+                let exit = self.new_label();
+                let next = self.new_label();
+
+                self.mark(start);
+                self.jump_if_false(condition, exit)?;
+
+                let active = self.function().active.len();
+                self.body(block, Loop { exit, next, active })?;
+                self.mark(next);
+                self.jump(start);
+                self.mark(exit);
+            }
+            StatementKind::Repeat { block, condition } => self.repeat(block, condition)?,
+            StatementKind::If {
+                branches,
+                otherwise,
+            } => {
+                let end = self.new_label();
+
+                for (index, branch) in branches.iter().enumerate() {
+                    let next = self.new_label();
+
+                    self.jump_if_false(&branch.condition, next)?;
+                    self.block(&branch.block)?;
+
+                    if index + 1 < branches.len() || otherwise.is_some() {
+                        self.jump(end);
+                    }
+
+                    self.mark(next);
+                }
+
+                if let Some(otherwise) = otherwise {
+                    self.block(otherwise)?;
+                }
+
+                self.mark(end);
+            }
+            StatementKind::NumericFor {
+                variable,
+                start,
+                limit,
+                step,
+                block,
+            } => self.numeric_for(&variable.name.name, start, limit, step.as_ref(), block)?,
+            StatementKind::GenericFor {
+                names,
+                values,
+                block,
+            } => {
+                let names = names
+                    .iter()
+                    .map(|name| name.name.name.as_str())
+                    .collect::<Vec<_>>();
+
+                self.generic_for(&names, values, block)?;
+            }
+            StatementKind::Function { name, function } => {
+                self.function_statement(name, function)?
+            }
+            StatementKind::LocalFunction { name, function } => {
+                let register = self.reserve(1)?;
+                self.declare(&name.name);
+                self.closure(function, Some(name.name.clone()), false, register)?;
+            }
+            StatementKind::Return(values) => self.return_statement(values)?,
+            StatementKind::Break => {
+                let (exit, active) = match self.function().loops.last() {
+                    Some(innermost) => (innermost.exit, innermost.active),
+                    None => bail!("break outside a loop at {}", statement.span),
+                };
+
+                self.close(active);
+                self.jump(exit);
+            }
+            StatementKind::Continue => {
+                let (next, active) = match self.function().loops.last() {
+                    Some(innermost) => (innermost.next, innermost.active),
+                    None => bail!("continue outside a loop at {}", statement.span),
+                };
+
+                self.close(active);
+                self.jump(next);
+            }
+            StatementKind::Goto(name) => self.goto(&name.name),
+            StatementKind::Label(name) => self.label(&name.name, rest)?,
+            StatementKind::TypeAlias { .. } => {}
+            StatementKind::Error => bail!("syntax error at {}", statement.span),
+        }
+
+        Ok(())
+    }
+
+    fn local(&mut self, names: &[LocalName], values: &[Expression]) -> Result<()> {
+        for name in names {
+            if let Some(attribute) = &name.attribute {
+                ensure!(
+                    attribute.name == "const",
+                    "local '{}' has the attribute <{}>, which cannot be compiled",
+                    name.name.name,
+                    attribute.name
+                );
+            }
+        }
+
+        let base = self.function().free;
+        self.expression_list(values, Some(names.len()))?;
+        self.set_free(base + names.len())?;
+
+        for name in names {
+            self.declare(&name.name.name);
+        }
+
+        Ok(())
+    }
+
+    fn assign(&mut self, targets: &[Expression], values: &[Expression]) -> Result<()> {
+        if let ([target], [value]) = (targets, values) {
+            return match self.place(target, &[])? {
+                Place::Local(register) => self.assign_local(value, register),
+                Place::Upvalue(upvalue) => {
+                    let src = self.register(value)?;
+                    self.emit(Instruction::SetUpvalue(SetUpvalue { src, upvalue }));
+
+                    Ok(())
+                }
+                Place::Global(constant) => {
+                    let src = self.register(value)?;
+                    self.emit(Instruction::SetGlobal(SetGlobal { src, constant }));
+
+                    Ok(())
+                }
+                Place::Index { table, key } => {
+                    let value = self.value(value)?;
+                    self.emit(Instruction::SetTable(SetTable { table, key, value }));
+
+                    Ok(())
+                }
+            };
+        }
+
+        // the values are assigned once all of them are evaluated, so the tables and keys
+        // must not live in the locals being assigned
+        let mut assigned = vec![];
+        for target in targets {
+            if let ExpressionKind::Name(name) = &target.kind {
+                if let Some(id) = self.function().find_local(&name.name) {
+                    assigned.push(self.function().locals[id].register);
+                }
+            }
+        }
+
+        let places = targets
+            .iter()
+            .map(|target| self.place(target, &assigned))
+            .collect::<Result<Vec<_>>>()?;
+
+        let (base, _) = self.expression_list(values, Some(targets.len()))?;
+
+        for (index, place) in places.into_iter().enumerate().rev() {
+            self.store(place, base + index);
+        }
+
+        Ok(())
+    }
+
+    /// Evaluates where `target` stores a value, copying any register of `assigned` it needs
+    /// into a temporary.
+    fn place(&mut self, target: &Expression, assigned: &[usize]) -> Result<Place> {
+        let (object, key) = match &target.kind {
+            ExpressionKind::Name(name) => {
+                return Ok(match self.variable(&name.name) {
+                    Variable::Local(register) => Place::Local(register),
+                    Variable::Upvalue(upvalue) => Place::Upvalue(upvalue),
+                    Variable::Global(constant) => Place::Global(constant),
+                })
+            }
+            ExpressionKind::Index { object, key } => {
+                let object = self.register(object)?;
+                (object, self.value(key)?)
+            }
+            ExpressionKind::Field { object, name } => {
+                let object = self.register(object)?;
+                (
+                    object,
+                    Value::ConstantIndex(self.string(name.name.as_bytes())),
+                )
+            }
+            _ => bail!("cannot assign to the expression at {}", target.span),
+        };
+
+        let table = self.unshared(object, assigned)?;
+        let key = match key {
+            Value::StackIndex(register) => Value::StackIndex(self.unshared(register, assigned)?),
+            key => key,
+        };
+
+        Ok(Place::Index { table, key })
+    }
+
+    /// `register`, or a copy of it if it is one of the `assigned` locals.
+    fn unshared(&mut self, register: usize, assigned: &[usize]) -> Result<usize> {
+        if !assigned.contains(&register) {
+            return Ok(register);
+        }
+
+        let dest = self.reserve(1)?;
+        self.emit(Instruction::Move(Move {
+            dest,
+            src: register,
+        }));
+
+        Ok(dest)
+    }
+
+    /// Stores the value in the register `src` into `place`.
+    fn store(&mut self, place: Place, src: usize) {
+        self.emit(match place {
+            Place::Local(dest) => Instruction::Move(Move { dest, src }),
+            Place::Upvalue(upvalue) => Instruction::SetUpvalue(SetUpvalue { src, upvalue }),
+            Place::Global(constant) => Instruction::SetGlobal(SetGlobal { src, constant }),
+            Place::Index { table, key } => Instruction::SetTable(SetTable {
+                table,
+                key,
+                value: Value::StackIndex(src),
+            }),
+        });
+    }
+
+    /// Assigns `value` to the local in `register`, going through a temporary if evaluating
+    /// it would overwrite the local before reading it.
+    fn assign_local(&mut self, value: &Expression, register: usize) -> Result<()> {
+        if !writes_early(value) {
+            return self.expression_into(value, register);
+        }
+
+        let src = self.push(value)?;
+        self.emit(Instruction::Move(Move {
+            dest: register,
+            src,
+        }));
+
+        Ok(())
+    }
+
+    fn compound_assign(
+        &mut self,
+        operator: BinaryOperator,
+        target: &Expression,
+        value: &Expression,
+    ) -> Result<()> {
+        let place = self.place(target, &[])?;
+
+        let dest = match place {
+            Place::Local(register) => register,
+            _ => self.reserve(1)?,
+        };
+
+        match &place {
+            Place::Local(_) => {}
+            Place::Upvalue(upvalue) => {
+                self.emit(Instruction::GetUpvalue(GetUpvalue {
+                    dest,
+                    upvalue: *upvalue,
+                }));
+            }
+            Place::Global(constant) => {
+                self.emit(Instruction::GetGlobal(GetGlobal {
+                    dest,
+                    constant: *constant,
+                }));
+            }
+            Place::Index { table, key } => {
+                self.emit(Instruction::GetTable(GetTable {
+                    dest,
+                    source: *table,
+                    key: key.clone(),
+                }));
+            }
+        }
+
+        if operator == BinaryOperator::Concat {
+            let start = self.reserve(1)?;
+            self.emit(Instruction::Move(Move {
+                dest: start,
+                src: dest,
+            }));
+            let end = self.push(value)?;
+
+            self.emit(Instruction::Concat(Concat { dest, start, end }));
+        } else {
+            let right = self.value(value)?;
+            self.arithmetic(operator, dest, Value::StackIndex(dest), right)?;
+        }
+
+        if !matches!(place, Place::Local(_)) {
+            self.store(place, dest);
+        }
+
+        Ok(())
+    }
+
+    fn repeat(&mut self, block: &Block, condition: &Expression) -> Result<()> {
+        let start = self.new_label();
+        let exit = self.new_label();
+        let next = self.new_label();
+        let done = self.new_label();
+
+        self.mark(start);
+        self.enter();
+
+        let active = self.function().active.len();
+        self.function_mut().loops.push(Loop { exit, next, active });
+
+        self.statements(&block.statements)?;
+
+        // the condition can see the locals of the body, which are closed on either path
+        self.mark(next);
+        self.jump_if_true(condition, done)?;
+        self.close(active);
+        self.jump(start);
+        self.mark(done);
+
+        self.function_mut().loops.pop();
+        self.leave(true)?;
+        self.mark(exit);
+
+        Ok(())
+    }
+
+    /// Compiles the body of `innermost`, the loop `break` and `continue` apply to.
+    fn body(&mut self, block: &Block, innermost: Loop) -> Result<()> {
+        self.function_mut().loops.push(innermost);
+
+        self.block(block)?;
+
+        self.function_mut().loops.pop();
+
+        Ok(())
+    }
+
+    fn numeric_for(
+        &mut self,
+        name: &str,
+        start: &Expression,
+        limit: &Expression,
+        step: Option<&Expression>,
+        block: &Block,
+    ) -> Result<()> {
+        self.enter();
+
+        let base = self.reserve(3)?;
+        let (index, limit_register, step_register) = match self.layout {
+            LoopLayout::Lua51 | LoopLayout::Lua54 => (base, base + 1, base + 2),
+            LoopLayout::Luau => (base + 2, base, base + 1),
+        };
+
+        self.expression_into(start, index)?;
+        self.set_free(base + 3)?;
+        self.expression_into(limit, limit_register)?;
+        self.set_free(base + 3)?;
+        match step {
+            Some(step) => self.expression_into(step, step_register)?,
+            None => self.load(step_register, Value::Immediate(1)),
+        }
+        self.set_free(base + 3)?;
+
+        for register in base..base + 3 {
+            let name = match register {
+                _ if register == index => "(for index)",
+                _ if register == limit_register => "(for limit)",
+                _ => "(for step)",
+            };
+
+            self.declare(name);
+        }
+
+        // Luau counts with the loop variable itself, so the body gets a copy it can assign
+        let var = match self.layout {
+            LoopLayout::Lua51 | LoopLayout::Lua54 => base + 3,
+            LoopLayout::Luau => index,
+        };
+        let local = self.reserve(1)?;
+
+        let body = self.new_label();
+        let exit = self.new_label();
+        let next = self.new_label();
+
+        self.emit(Instruction::ForNumPrep(ForNumPrep {
+            target: exit,
+            index,
+            limit: limit_register,
+            step: step_register,
+            var,
+        }));
+        self.mark(body);
+
+        if local != var {
+            self.emit(Instruction::Move(Move {
+                dest: local,
+                src: var,
+            }));
+        }
+
+        let active = self.function().active.len();
+
+        self.enter();
+        self.declare(name);
+        self.body(block, Loop { exit, next, active })?;
+        self.leave(true)?;
+
+        self.mark(next);
+        self.emit(Instruction::ForNumLoop(ForNumLoop {
+            target: body,
+            index,
+            limit: limit_register,
+            step: step_register,
+            var,
+        }));
+        self.mark(exit);
+
+        self.leave(false)
+    }
+
+    fn generic_for(&mut self, names: &[&str], values: &[Expression], block: &Block) -> Result<()> {
+        self.enter();
+
+        let state = match self.layout {
+            LoopLayout::Lua51 | LoopLayout::Luau => 3,
+            LoopLayout::Lua54 => 4,
+        };
+
+        let (base, _) = self.expression_list(values, Some(state))?;
+        self.set_free(base + state)?;
+
+        for name in [
+            "(for generator)",
+            "(for state)",
+            "(for control)",
+            "(for closing)",
+        ]
+        .into_iter()
+        .take(state)
+        {
+            self.declare(name);
+        }
+
+        let dest = self.reserve(names.len())?;
+
+        let call = self.new_label();
+        let body = self.new_label();
+        let exit = self.new_label();
+        let next = self.new_label();
+
+        self.emit(Instruction::ForGenPrep(ForGenPrep { target: call, base }));
+        self.mark(body);
+
+        let active = self.function().active.len();
+
+        self.enter();
+        for name in names {
+            self.declare(name);
+        }
+        self.body(block, Loop { exit, next, active })?;
+        self.leave(true)?;
+
+        self.mark(next);
+        self.mark(call);
+        self.emit(Instruction::ForGenCall(ForGenCall {
+            base,
+            dest,
+            count: names.len(),
+        }));
+        self.emit(Instruction::ForGenLoop(ForGenLoop {
+            target: body,
+            base,
+            var: dest,
+        }));
+        self.mark(exit);
+
+        self.leave(false)
+    }
+
+    fn function_statement(&mut self, name: &FunctionName, function: &FunctionBody) -> Result<()> {
+        let is_method = name.method.is_some();
+        let (first, fields) = name.path.split_first().unwrap();
+        let last = name
+            .method
+            .as_ref()
+            .unwrap_or_else(|| name.path.last().unwrap());
+
+        if fields.is_empty() && !is_method {
+            let dest = match self.variable(&first.name) {
+                Variable::Local(register) => register,
+                _ => self.reserve(1)?,
+            };
+
+            self.closure(function, Some(last.name.clone()), false, dest)?;
+
+            match self.variable(&first.name) {
+                Variable::Local(_) => {}
+                Variable::Upvalue(upvalue) => {
+                    self.emit(Instruction::SetUpvalue(SetUpvalue { src: dest, upvalue }));
+                }
+                Variable::Global(constant) => {
+                    self.emit(Instruction::SetGlobal(SetGlobal {
+                        src: dest,
+                        constant,
+                    }));
+                }
+            }
+
+            return Ok(());
+        }
+
+        let mut table = self.variable_register(&first.name)?;
+        let keys = match &name.method {
+            Some(_) => fields,
+            None => &fields[..fields.len() - 1],
+        };
+
+        for key in keys {
+            let dest = self.reserve(1)?;
+            let key = Value::ConstantIndex(self.string(key.name.as_bytes()));
+
+            self.emit(Instruction::GetTable(GetTable {
+                dest,
+                source: table,
+                key,
+            }));
+            table = dest;
+        }
+
+        let value = self.reserve(1)?;
+        self.closure(function, Some(last.name.clone()), is_method, value)?;
+
+        let key = Value::ConstantIndex(self.string(last.name.as_bytes()));
+        self.emit(Instruction::SetTable(SetTable {
+            table,
+            key,
+            value: Value::StackIndex(value),
+        }));
+
+        Ok(())
+    }
+
+    fn return_statement(&mut self, values: &[Expression]) -> Result<()> {
+        if let [value] = values {
+            if let ExpressionKind::Name(name) = &value.kind {
+                if let Some(id) = self.function().find_local(&name.name) {
+                    let result_start = self.function().locals[id].register;
+
+                    self.emit(Instruction::Return(Return {
+                        result_start,
+                        result_count: OptVariable::Number(1),
+                    }));
+
+                    return Ok(());
+                }
+            }
+        }
+
+        let (result_start, result_count) = self.expression_list(values, None)?;
+        self.emit(Instruction::Return(Return {
+            result_start,
+            result_count,
+        }));
+
+        Ok(())
+    }
+
+    fn goto(&mut self, name: &str) {
+        let function = self.function();
+        let label = function
+            .labels
+            .iter()
+            .rev()
+            .find(|label| label.name == name)
+            .map(|label| (label.label, label.active));
+
+        if let Some((label, active)) = label {
+            self.close(active);
+            self.jump(label);
+
+            return;
+        }
+
+        // the label comes later, so what the jump leaves is only known once it is found
+        let function = self.function_mut();
+        let locals = function.active.clone();
+        let active = locals.len();
+        let depth = function.scopes.len();
+
+        let instruction = self.emit(Instruction::Close(Close { start: active }));
+        let jump = self.emit(Instruction::Jump(Jump {
+            target: Label(usize::MAX),
+        }));
+
+        let function = self.function_mut();
+        function.closes.push(PendingClose {
+            instruction,
+            locals: vec![],
+        });
+        function.gotos.push(Goto {
+            name: name.to_owned(),
+            jump,
+            close: function.closes.len() - 1,
+            locals,
+            depth,
+            active,
+        });
+    }
+
+    /// Places the label `name`, which is followed by `rest` in its block.
+    fn label(&mut self, name: &str, rest: &[Statement]) -> Result<()> {
+        ensure!(
+            !self
+                .function()
+                .labels
+                .iter()
+                .any(|label| label.name == name),
+            "label '{name}' is already defined"
+        );
+
+        // a label at the end of a block is outside the scope of the locals of the block
+        let function = self.function();
+        let at_end = rest
+            .iter()
+            .all(|statement| matches!(statement.kind, StatementKind::Label(_)));
+        let active = if at_end {
+            function.scopes.last().unwrap().active
+        } else {
+            function.active.len()
+        };
+        let depth = function.scopes.len();
+
+        let label = self.new_label();
+        self.mark(label);
+
+        let function = self.function_mut();
+        function.labels.push(LabelState {
+            name: name.to_owned(),
+            label,
+            active,
+        });
+
+        let mut index = 0;
+        while index < function.gotos.len() {
+            let goto = &function.gotos[index];
+
+            if goto.name != name || goto.depth != depth {
+                index += 1;
+                continue;
+            }
+
+            ensure!(
+                goto.active >= active,
+                "goto '{name}' jumps into the scope of local '{}'",
+                function.locals[function.active[goto.active]].name
+            );
+
+            let goto = function.gotos.remove(index);
+            function.code[goto.jump].0 = Instruction::Jump(Jump { target: label });
+            function.code[function.closes[goto.close].instruction].0 =
+                Instruction::Close(Close { start: active });
+            function.closes[goto.close].locals = goto.locals[active..].to_vec();
+        }
+
+        Ok(())
+    }
+
+    /// Compiles `body` as a function named `name` into a closure in `dest`. Methods take
+    /// `self` before their parameters.
+    fn closure(
+        &mut self,
+        body: &FunctionBody,
+        name: Option<String>,
+        is_method: bool,
+        dest: usize,
+    ) -> Result<()> {
+        let params = body.params.len() + is_method as usize;
+        ensure!(
+            params <= u8::MAX as usize,
+            "function at {} has more than {} parameters",
+            body.span,
+            u8::MAX
+        );
+
+        ensure!(
+            self.functions.len() <= crate::formats::MAX_NESTING,
+            "functions are nested more than {} levels deep",
+            crate::formats::MAX_NESTING
+        );
+
+        self.functions
+            .push(FunctionState::new(name, body.span.line));
+
+        let function = self.function_mut();
+        function.arity = Arity {
+            params: params as u8,
+            is_vararg: body.is_vararg,
+            needs_arg: false,
+        };
+        function.span = Some(body.span);
+
+        self.enter();
+        self.reserve(params)?;
+
+        if is_method {
+            self.declare("self");
+        }
+
+        for param in &body.params {
+            self.declare(&param.name.name);
+        }
+
+        self.statements(&body.block.statements)?;
+        self.leave(false)?;
+        self.emit(Instruction::Return(Return {
+            result_start: 0,
+            result_count: OptVariable::Number(0),
+        }));
+
+        let function = self.functions.pop().unwrap().finish()?;
+        let parent = self.function_mut();
+        parent.prototypes.push(function);
+
+        let prototype = parent.prototypes.len() - 1;
+        self.emit(Instruction::Closure(Closure { dest, prototype }));
+
+        Ok(())
+    }
+
+    /// The register holding the variable `name`, loaded into a temporary unless it is a
+    /// local.
+    fn variable_register(&mut self, name: &str) -> Result<usize> {
+        let dest = match self.variable(name) {
+            Variable::Local(register) => return Ok(register),
+            Variable::Upvalue(upvalue) => {
+                let dest = self.reserve(1)?;
+                self.emit(Instruction::GetUpvalue(GetUpvalue { dest, upvalue }));
+
+                dest
+            }
+            Variable::Global(constant) => {
+                let dest = self.reserve(1)?;
+                self.emit(Instruction::GetGlobal(GetGlobal { dest, constant }));
+
+                dest
+            }
+        };
+
+        Ok(dest)
+    }
+
+    /// Evaluates `expressions` into consecutive new registers, adjusting them to `count`
+    /// values. Without a count, the values of a call or vararg at the end are left open up
+    /// to the top. Returns the first register and the number of values.
+    fn expression_list(
+        &mut self,
+        expressions: &[Expression],
+        count: Option<usize>,
+    ) -> Result<(usize, OptVariable)> {
+        let base = self.function().free;
+
+        for (index, expression) in expressions.iter().enumerate() {
+            if index + 1 == expressions.len() && is_multiple(expression) {
+                return Ok(match count {
+                    Some(count) => {
+                        let results = count.saturating_sub(index);
+                        self.multiple(expression, OptVariable::Number(results))?;
+                        self.set_free(base + count.max(index))?;
+
+                        (base, OptVariable::Number(count))
+                    }
+                    None => {
+                        self.multiple(expression, OptVariable::Variable)?;
+
+                        (base, OptVariable::Variable)
+                    }
+                });
+            }
+
+            self.push(expression)?;
+        }
+
+        match count {
+            Some(count) => {
+                for _ in expressions.len()..count {
+                    let dest = self.reserve(1)?;
+                    self.load(dest, Value::Nil);
+                }
+
+                Ok((base, OptVariable::Number(count)))
+            }
+            None => Ok((base, OptVariable::Number(expressions.len()))),
+        }
+    }
+
+    /// Evaluates the call or vararg `expression` into new registers, producing `results`
+    /// values.
+    fn multiple(&mut self, expression: &Expression, results: OptVariable) -> Result<()> {
+        if let ExpressionKind::VarArg = expression.kind {
+            let dest = self.function().free;
+
+            // open values need at least their first register
+            match results {
+                OptVariable::Number(count) => self.set_free(dest + count)?,
+                OptVariable::Variable => {
+                    self.set_free(dest + 1)?;
+                    self.function_mut().free = dest;
+                }
+            }
+
+            self.emit(Instruction::VarArg(VarArg {
+                dest,
+                count: results,
+            }));
+
+            return Ok(());
+        }
+
+        self.call(expression, results)?;
+
+        Ok(())
+    }
+
+    /// Compiles the call `expression` into the first free register, producing `results`
+    /// values from there. Returns the register the results start at.
+    fn call(&mut self, expression: &Expression, results: OptVariable) -> Result<usize> {
+        let callee = self.reserve(1)?;
+
+        let (self_call, args) = match &expression.kind {
+            ExpressionKind::Call {
+                callee: function,
+                args,
+            } => {
+                self.expression_into(function, callee)?;
+                self.set_free(callee + 1)?;
+
+                (false, args)
+            }
+            ExpressionKind::MethodCall {
+                object,
+                method,
+                args,
+            } => {
+                self.reserve(1)?;
+
+                let object = match self.value(object)? {
+                    Value::StackIndex(register) => register,
+                    value => {
+                        self.load(callee, value);
+                        callee
+                    }
+                };
+                let key = Value::ConstantIndex(self.string(method.name.as_bytes()));
+
+                self.emit(Instruction::SelfLookup(SelfLookup {
+                    dest: callee,
+                    object,
+                    key,
+                }));
+                self.set_free(callee + 2)?;
+
+                (true, args)
+            }
+            _ => bail!("expression at {} is not a call", expression.span),
+        };
+
+        let (_, num_args) = self.expression_list(args, None)?;
+
+        self.emit(Instruction::Call(Call {
+            callee,
+            self_call,
+            num_args,
+            num_returns: results.clone(),
+        }));
+
+        let results = match results {
+            OptVariable::Number(count) => count,
+            OptVariable::Variable => 0,
+        };
+        self.set_free(callee + results)?;
+
+        Ok(callee)
+    }
+
+    /// Evaluates `expression` into a new register at the top of the stack, releasing the
+    /// temporaries used on the way.
+    fn push(&mut self, expression: &Expression) -> Result<usize> {
+        let dest = self.reserve(1)?;
+        self.expression_into(expression, dest)?;
+        self.set_free(dest + 1)?;
+
+        Ok(dest)
+    }
+
+    /// Evaluates `expression` into an operand, which is a constant or the register of a
+    /// local when that takes no code, and a new temporary otherwise.
+    fn value(&mut self, expression: &Expression) -> Result<Value> {
+        if let Some(constant) = self.fold(expression) {
+            return Ok(Value::ConstantIndex(self.constant(constant)));
+        }
+
+        match &expression.kind {
+            ExpressionKind::Nil => return Ok(Value::Nil),
+            ExpressionKind::Boolean(b) => return Ok(Value::Boolean(*b)),
+            ExpressionKind::Number(n) => {
+                return Ok(Value::ConstantIndex(self.constant(Constant::Number(*n))))
+            }
+            ExpressionKind::Integer(n) => {
+                return Ok(Value::ConstantIndex(self.constant(Constant::Integer(*n))))
+            }
+            ExpressionKind::String(s) => return Ok(Value::ConstantIndex(self.string(s))),
+            ExpressionKind::Name(name) => {
+                if let Some(id) = self.function().find_local(&name.name) {
+                    return Ok(Value::StackIndex(self.function().locals[id].register));
+                }
+            }
+            ExpressionKind::TypeAssertion { expression, .. } => return self.value(expression),
+            ExpressionKind::Paren(inner) if !is_multiple(inner) => return self.value(inner),
+            _ => {}
+        }
+
+        let dest = self.reserve(1)?;
+        self.expression_into(expression, dest)?;
+
+        Ok(Value::StackIndex(dest))
+    }
+
+    /// Evaluates `expression` into a register, which is a new temporary unless it is a
+    /// local.
+    fn register(&mut self, expression: &Expression) -> Result<usize> {
+        match self.value(expression)? {
+            Value::StackIndex(register) => Ok(register),
+            value => {
+                let dest = self.reserve(1)?;
+                self.load(dest, value);
+
+                Ok(dest)
+            }
+        }
+    }
+
+    /// Evaluates `expression` into the register `dest`.
+    fn expression_into(&mut self, expression: &Expression, dest: usize) -> Result<()> {
+        if let Some(constant) = self.fold(expression) {
+            let index = self.constant(constant);
+            self.load(dest, Value::ConstantIndex(index));
+
+            return Ok(());
+        }
+
+        match &expression.kind {
+            ExpressionKind::Nil => self.load(dest, Value::Nil),
+            ExpressionKind::Boolean(b) => self.load(dest, Value::Boolean(*b)),
+            ExpressionKind::Number(n) => {
+                let index = self.constant(Constant::Number(*n));
+                self.load(dest, Value::ConstantIndex(index));
+            }
+            ExpressionKind::Integer(n) => {
+                let index = self.constant(Constant::Integer(*n));
+                self.load(dest, Value::ConstantIndex(index));
+            }
+            ExpressionKind::String(s) => {
+                let index = self.string(s);
+                self.load(dest, Value::ConstantIndex(index));
+            }
+            ExpressionKind::VarArg => {
+                self.emit(Instruction::VarArg(VarArg {
+                    dest,
+                    count: OptVariable::Number(1),
+                }));
+            }
+            ExpressionKind::Function(body) => self.closure(body, None, false, dest)?,
+            ExpressionKind::Table(fields) => self.table(fields, dest)?,
+            ExpressionKind::Name(name) => match self.variable(&name.name) {
+                Variable::Local(src) => {
+                    if src != dest {
+                        self.emit(Instruction::Move(Move { dest, src }));
+                    }
+                }
+                Variable::Upvalue(upvalue) => {
+                    self.emit(Instruction::GetUpvalue(GetUpvalue { dest, upvalue }));
+                }
+                Variable::Global(constant) => {
+                    self.emit(Instruction::GetGlobal(GetGlobal { dest, constant }));
+                }
+            },
+            ExpressionKind::Index { object, key } => {
+                let source = self.register(object)?;
+                let key = self.value(key)?;
+
+                self.emit(Instruction::GetTable(GetTable { dest, source, key }));
+            }
+            ExpressionKind::Field { object, name } => {
+                let source = self.register(object)?;
+                let key = Value::ConstantIndex(self.string(name.name.as_bytes()));
+
+                self.emit(Instruction::GetTable(GetTable { dest, source, key }));
+            }
+            ExpressionKind::Call { .. } | ExpressionKind::MethodCall { .. } => {
+                self.single(dest, |generator| {
+                    generator.call(expression, OptVariable::Number(1))
+                })?;
+            }
+            ExpressionKind::Paren(inner) => self.expression_into(inner, dest)?,
+            ExpressionKind::IfElse {
+                branches,
+                otherwise,
+            } => {
+                let end = self.new_label();
+                let free = self.function().free;
+
+                for branch in branches {
+                    let next = self.new_label();
+
+                    self.jump_if_false(&branch.condition, next)?;
+                    self.expression_into(&branch.value, dest)?;
+                    self.function_mut().free = free;
+                    self.jump(end);
+                    self.mark(next);
+                }
+
+                self.expression_into(otherwise, dest)?;
+                self.mark(end);
+            }
+            ExpressionKind::InterpolatedString {
+                strings,
+                expressions,
+            } => self.interpolated_string(strings, expressions, dest)?,
+            ExpressionKind::TypeAssertion { expression, .. } => {
+                self.expression_into(expression, dest)?
+            }
+            ExpressionKind::Binary {
+                operator,
+                left,
+                right,
+            } => self.binary(*operator, left, right, dest)?,
+            ExpressionKind::Unary { operator, operand } => {
+                let operand = self.value(operand)?;
+
+                let operator = match operator {
+                    UnaryOperator::Neg => UnaryOpKind::Neg,
+                    UnaryOperator::Not => UnaryOpKind::Not,
+                    UnaryOperator::Len => UnaryOpKind::Len,
+                    UnaryOperator::BitNot => {
+                        self.emit(Instruction::Intrinsic(Intrinsic {
+                            kind: IntrinsicKind::BitNot(operand),
+                            dest,
+                            source: IntrinsicSource::Operator,
+                        }));
+
+                        return Ok(());
+                    }
+                };
+
+                self.emit(Instruction::UnaryOp(UnaryOp {
+                    operator,
+                    dest,
+                    left: operand,
+                }));
+            }
+            ExpressionKind::Error => bail!("syntax error at {}", expression.span),
+        }
+
+        Ok(())
+    }
+
+    /// Runs `call`, which compiles a call producing one value in the first free register,
+    /// and moves its result into `dest`. When `dest` is the temporary at the top of the
+    /// stack, the call is made there directly.
+    fn single(&mut self, dest: usize, call: impl FnOnce(&mut Self) -> Result<usize>) -> Result<()> {
+        let function = self.function_mut();
+
+        if dest + 1 == function.free && dest >= function.active.len() {
+            function.free = dest;
+        }
+
+        let src = call(self)?;
+
+        if src != dest {
+            self.emit(Instruction::Move(Move { dest, src }));
+        }
+
+        Ok(())
+    }
+
+    fn table(&mut self, fields: &[Field], dest: usize) -> Result<()> {
+        let array_size = fields
+            .iter()
+            .enumerate()
+            .filter(|(index, field)| match field {
+                Field::Positional(value) => index + 1 < fields.len() || !is_multiple(value),
+                _ => false,
+            })
+            .count();
+        let table_size = fields
+            .iter()
+            .filter(|field| !matches!(field, Field::Positional(_)))
+            .count();
+
+        // the array items are stored from the register above the table
+        let has_items = fields
+            .iter()
+            .any(|field| matches!(field, Field::Positional(_)));
+        let table = if dest + 1 == self.function().free || !has_items {
+            dest
+        } else {
+            self.reserve(1)?
+        };
+
+        self.emit(Instruction::NewTable(NewTable {
+            dest: table,
+            array_size,
+            table_size,
+        }));
+        self.set_free(table + 1)?;
+
+        let mut pending = 0;
+        let mut offset = 0;
+
+        for (index, field) in fields.iter().enumerate() {
+            match field {
+                Field::Positional(value) if index + 1 == fields.len() && is_multiple(value) => {
+                    self.multiple(value, OptVariable::Variable)?;
+                    self.emit(Instruction::SetList(SetList {
+                        table,
+                        start: table + 1,
+                        count: OptVariable::Variable,
+                        offset,
+                    }));
+
+                    pending = 0;
+                }
+                Field::Positional(value) => {
+                    self.push(value)?;
+                    pending += 1;
+
+                    if pending == FIELDS_PER_FLUSH {
+                        self.set_list(table, pending, offset);
+                        offset += pending;
+                        pending = 0;
+                    }
+                }
+                Field::Named { name, value } => {
+                    let key = Value::ConstantIndex(self.string(name.name.as_bytes()));
+                    let value = self.value(value)?;
+
+                    self.emit(Instruction::SetTable(SetTable { table, key, value }));
+                }
+                Field::Keyed { key, value } => {
+                    let key = self.value(key)?;
+                    let value = self.value(value)?;
+
+                    self.emit(Instruction::SetTable(SetTable { table, key, value }));
+                }
+            }
+
+            self.set_free(table + 1 + pending)?;
+        }
+
+        if pending > 0 {
+            self.set_list(table, pending, offset);
+        }
+
+        self.set_free(table + 1)?;
+
+        if table != dest {
+            self.emit(Instruction::Move(Move { dest, src: table }));
+        }
+
+        Ok(())
+    }
+
+    fn set_list(&mut self, table: usize, count: usize, offset: usize) {
+        self.emit(Instruction::SetList(SetList {
+            table,
+            start: table + 1,
+            count: OptVariable::Number(count),
+            offset,
+        }));
+    }
+
+    /// Compiles `` `a{b}c` `` as `("a%*c"):format(b)`, which is how Luau implements it.
+    fn interpolated_string(
+        &mut self,
+        strings: &[Vec<u8>],
+        expressions: &[Expression],
+        dest: usize,
+    ) -> Result<()> {
+        let mut format = vec![];
+
+        for (index, string) in strings.iter().enumerate() {
+            if index > 0 {
+                format.extend_from_slice(b"%*");
+            }
+
+            for &byte in string {
+                if byte == b'%' {
+                    format.push(b'%');
+                }
+
+                format.push(byte);
+            }
+        }
+
+        let format = self.string(&format);
+
+        if expressions.is_empty() {
+            self.load(dest, Value::ConstantIndex(format));
+
+            return Ok(());
+        }
+
+        self.single(dest, |generator| {
+            let callee = generator.reserve(2)?;
+            let key = Value::ConstantIndex(generator.string(b"format"));
+
+            generator.load(callee, Value::ConstantIndex(format));
+            generator.emit(Instruction::SelfLookup(SelfLookup {
+                dest: callee,
+                object: callee,
+                key,
+            }));
+
+            for expression in expressions {
+                generator.push(expression)?;
+            }
+
+            generator.emit(Instruction::Call(Call {
+                callee,
+                self_call: true,
+                num_args: OptVariable::Number(expressions.len()),
+                num_returns: OptVariable::Number(1),
+            }));
+            generator.set_free(callee + 1)?;
+
+            Ok(callee)
+        })
+    }
+
+    fn binary(
+        &mut self,
+        operator: BinaryOperator,
+        left: &Expression,
+        right: &Expression,
+        dest: usize,
+    ) -> Result<()> {
+        match operator {
+            BinaryOperator::And => {
+                let end = self.new_label();
+
+                self.expression_into(left, dest)?;
+                self.emit(Instruction::JumpNot(JumpNot {
+                    target: end,
+                    cond: dest,
+                }));
+                self.expression_into(right, dest)?;
+                self.mark(end);
+            }
+            BinaryOperator::Or => {
+                let other = self.new_label();
+                let end = self.new_label();
+
+                self.expression_into(left, dest)?;
+                self.emit(Instruction::JumpNot(JumpNot {
+                    target: other,
+                    cond: dest,
+                }));
+                self.jump(end);
+                self.mark(other);
+                self.expression_into(right, dest)?;
+                self.mark(end);
+            }
+            BinaryOperator::Concat => {
+                let mut operands = vec![left];
+                let mut rest = right;
+
+                // `a .. b .. c` is right associative, and is concatenated at once
+                while let ExpressionKind::Binary {
+                    operator: BinaryOperator::Concat,
+                    left,
+                    right,
+                } = &rest.kind
+                {
+                    operands.push(left);
+                    rest = right;
+                }
+                operands.push(rest);
+
+                let start = self.function().free;
+                for operand in operands {
+                    self.push(operand)?;
+                }
+                let end = self.function().free - 1;
+
+                self.emit(Instruction::Concat(Concat { dest, start, end }));
+            }
+            _ => {
+                let left = self.value(left)?;
+                let right = self.value(right)?;
+
+                match condition_kind(operator) {
+                    Some(kind) => {
+                        let holds = self.new_label();
+                        let end = self.new_label();
+
+                        self.emit(Instruction::ConditionalJump(ConditionalJump {
+                            target: holds,
+                            condition: Condition { kind, left, right },
+                        }));
+                        self.load(dest, Value::Boolean(false));
+                        self.jump(end);
+                        self.mark(holds);
+                        self.load(dest, Value::Boolean(true));
+                        self.mark(end);
+                    }
+                    None => self.arithmetic(operator, dest, left, right)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Emits the arithmetic or bitwise `operator` on operands that are already evaluated.
+    fn arithmetic(
+        &mut self,
+        operator: BinaryOperator,
+        dest: usize,
+        left: Value,
+        right: Value,
+    ) -> Result<()> {
+        let operator = match operator {
+            BinaryOperator::Add => BinaryOpKind::Add,
+            BinaryOperator::Sub => BinaryOpKind::Sub,
+            BinaryOperator::Mul => BinaryOpKind::Mul,
+            BinaryOperator::Div => BinaryOpKind::Div,
+            BinaryOperator::IDiv => BinaryOpKind::IDiv,
+            BinaryOperator::Mod => BinaryOpKind::Mod,
+            BinaryOperator::Pow => BinaryOpKind::Pow,
+            _ => {
+                let kind = match operator {
+                    BinaryOperator::BitAnd => IntrinsicKind::BitAnd(left, right),
+                    BinaryOperator::BitOr => IntrinsicKind::BitOr(left, right),
+                    BinaryOperator::BitXor => IntrinsicKind::BitXor(left, right),
+                    BinaryOperator::LeftShift => IntrinsicKind::LeftShift(left, right),
+                    BinaryOperator::RightShift => IntrinsicKind::RightShift(left, right),
+                    _ => bail!("{operator:?} is not an arithmetic operator"),
+                };
+
+                self.emit(Instruction::Intrinsic(Intrinsic {
+                    kind,
+                    dest,
+                    source: IntrinsicSource::Operator,
+                }));
+
+                return Ok(());
+            }
+        };
+
+        self.emit(Instruction::BinaryOp(BinaryOp {
+            operator,
+            dest,
+            left,
+            right,
+        }));
+
+        Ok(())
+    }
+
+    /// Jumps to `target` when `condition` is false or `nil`.
+    fn jump_if_false(&mut self, condition: &Expression, target: Label) -> Result<()> {
+        let free = self.function().free;
+
+        match &condition.kind {
+            ExpressionKind::Nil | ExpressionKind::Boolean(false) if self.fold => self.jump(target),
+            ExpressionKind::Boolean(true)
+            | ExpressionKind::Number(_)
+            | ExpressionKind::Integer(_)
+            | ExpressionKind::String(_)
+                if self.fold => {}
+            ExpressionKind::Paren(inner)
+            | ExpressionKind::TypeAssertion {
+                expression: inner, ..
+            } => self.jump_if_false(inner, target)?,
+            ExpressionKind::Unary {
+                operator: UnaryOperator::Not,
+                operand,
+            } => self.jump_if_true(operand, target)?,
+            ExpressionKind::Binary {
+                operator: BinaryOperator::And,
+                left,
+                right,
+            } => {
+                self.jump_if_false(left, target)?;
+                self.jump_if_false(right, target)?;
+            }
+            ExpressionKind::Binary {
+                operator: BinaryOperator::Or,
+                left,
+                right,
+            } => {
+                let skip = self.new_label();
+
+                self.jump_if_true(left, skip)?;
+                self.jump_if_false(right, target)?;
+                self.mark(skip);
+            }
+            ExpressionKind::Binary {
+                operator,
+                left,
+                right,
+            } if condition_kind(*operator).is_some() => {
+                let kind = condition_kind(*operator).unwrap();
+                let left = self.value(left)?;
+                let right = self.value(right)?;
+
+                // only equality can be negated, as comparisons with NaN are never true
+                let negated = match kind {
+                    ConditionKind::Eq => Some(ConditionKind::Ne),
+                    ConditionKind::Ne => Some(ConditionKind::Eq),
+                    _ => None,
+                };
+
+                match negated {
+                    Some(kind) => {
+                        self.emit(Instruction::ConditionalJump(ConditionalJump {
+                            target,
+                            condition: Condition { kind, left, right },
+                        }));
+                    }
+                    None => {
+                        let skip = self.new_label();
+
+                        self.emit(Instruction::ConditionalJump(ConditionalJump {
+                            target: skip,
+                            condition: Condition { kind, left, right },
+                        }));
+                        self.jump(target);
+                        self.mark(skip);
+                    }
+                }
+            }
+            _ => {
+                let cond = self.register(condition)?;
+                self.emit(Instruction::JumpNot(JumpNot { target, cond }));
+            }
+        }
+
+        self.function_mut().free = free;
+
+        Ok(())
+    }
+
+    /// Jumps to `target` when `condition` is neither false nor `nil`.
+    fn jump_if_true(&mut self, condition: &Expression, target: Label) -> Result<()> {
+        let free = self.function().free;
+
+        match &condition.kind {
+            ExpressionKind::Nil | ExpressionKind::Boolean(false) if self.fold => {}
+            ExpressionKind::Boolean(true)
+            | ExpressionKind::Number(_)
+            | ExpressionKind::Integer(_)
+            | ExpressionKind::String(_)
+                if self.fold =>
+            {
+                self.jump(target)
+            }
+            ExpressionKind::Paren(inner)
+            | ExpressionKind::TypeAssertion {
+                expression: inner, ..
+            } => self.jump_if_true(inner, target)?,
+            ExpressionKind::Unary {
+                operator: UnaryOperator::Not,
+                operand,
+            } => self.jump_if_false(operand, target)?,
+            ExpressionKind::Binary {
+                operator: BinaryOperator::And,
+                left,
+                right,
+            } => {
+                let skip = self.new_label();
+
+                self.jump_if_false(left, skip)?;
+                self.jump_if_true(right, target)?;
+                self.mark(skip);
+            }
+            ExpressionKind::Binary {
+                operator: BinaryOperator::Or,
+                left,
+                right,
+            } => {
+                self.jump_if_true(left, target)?;
+                self.jump_if_true(right, target)?;
+            }
+            ExpressionKind::Binary {
+                operator,
+                left,
+                right,
+            } if condition_kind(*operator).is_some() => {
+                let kind = condition_kind(*operator).unwrap();
+                let left = self.value(left)?;
+                let right = self.value(right)?;
+
+                self.emit(Instruction::ConditionalJump(ConditionalJump {
+                    target,
+                    condition: Condition { kind, left, right },
+                }));
+            }
+            _ => {
+                let cond = self.register(condition)?;
+                let skip = self.new_label();
+
+                self.emit(Instruction::JumpNot(JumpNot { target: skip, cond }));
+                self.jump(target);
+                self.mark(skip);
+            }
+        }
+
+        self.function_mut().free = free;
+
+        Ok(())
+    }
+
+    /// The constant `expression` evaluates to, if it is arithmetic on number literals and
+    /// constants are folded.
+    fn fold(&self, expression: &Expression) -> Option<Constant> {
+        if !self.fold {
+            return None;
+        }
+
+        match &expression.kind {
+            ExpressionKind::Unary { .. } | ExpressionKind::Binary { .. } => fold(expression),
+            _ => None,
+        }
+    }
+}
+
+/// The constant the number arithmetic `expression` evaluates to. Operations whose result
+/// depends on the runtime, such as division by zero or producing NaN, are left alone.
+fn fold(expression: &Expression) -> Option<Constant> {
+    match &expression.kind {
+        ExpressionKind::Number(n) => Some(Constant::Number(*n)),
+        ExpressionKind::Integer(n) => Some(Constant::Integer(*n)),
+        ExpressionKind::Paren(inner) => fold(inner),
+        ExpressionKind::Unary {
+            operator: UnaryOperator::Neg,
+            operand,
+        } => match fold(operand)? {
+            Constant::Number(n) => Some(Constant::Number(-n)),
+            Constant::Integer(n) => Some(Constant::Integer(n.wrapping_neg())),
+            _ => None,
+        },
+        ExpressionKind::Binary {
+            operator,
+            left,
+            right,
+        } => {
+            let (left, right) = (fold(left)?, fold(right)?);
+
+            if let (Constant::Integer(a), Constant::Integer(b)) = (&left, &right) {
+                match operator {
+                    BinaryOperator::Add => return Some(Constant::Integer(a.wrapping_add(*b))),
+                    BinaryOperator::Sub => return Some(Constant::Integer(a.wrapping_sub(*b))),
+                    BinaryOperator::Mul => return Some(Constant::Integer(a.wrapping_mul(*b))),
+                    _ => {}
+                }
+            }
+
+            let float = |constant: &Constant| match constant {
+                Constant::Number(n) => *n,
+                Constant::Integer(n) => *n as f64,
+                _ => unreachable!(),
+            };
+            let (a, b) = (float(&left), float(&right));
+
+            let result = match operator {
+                BinaryOperator::Add => a + b,
+                BinaryOperator::Sub => a - b,
+                BinaryOperator::Mul => a * b,
+                BinaryOperator::Div if b != 0.0 => a / b,
+                BinaryOperator::Pow => a.powf(b),
+                _ => return None,
+            };
+
+            (!result.is_nan()).then_some(Constant::Number(result))
+        }
+        _ => None,
+    }
+}
+
+/// Whether `expression` produces any number of values.
+fn is_multiple(expression: &Expression) -> bool {
+    expression.is_call() || matches!(expression.kind, ExpressionKind::VarArg)
+}
+
+/// Whether evaluating `expression` into a register writes to it before reading every
+/// operand, so that it cannot be evaluated into a local it reads.
+fn writes_early(expression: &Expression) -> bool {
+    match &expression.kind {
+        ExpressionKind::Table(_) => true,
+        ExpressionKind::Binary {
+            operator: BinaryOperator::And | BinaryOperator::Or,
+            ..
+        } => true,
+        ExpressionKind::Paren(inner)
+        | ExpressionKind::TypeAssertion {
+            expression: inner, ..
+        } => writes_early(inner),
+        ExpressionKind::IfElse {
+            branches,
+            otherwise,
+        } => branches.iter().any(|branch| writes_early(&branch.value)) || writes_early(otherwise),
+        _ => false,
+    }
+}
+
+/// The condition a comparison operator tests.
+fn condition_kind(operator: BinaryOperator) -> Option<ConditionKind> {
+    Some(match operator {
+        BinaryOperator::Eq => ConditionKind::Eq,
+        BinaryOperator::Ne => ConditionKind::Ne,
+        BinaryOperator::Lt => ConditionKind::Lt,
+        BinaryOperator::Le => ConditionKind::Le,
+        BinaryOperator::Gt => ConditionKind::Gt,
+        BinaryOperator::Ge => ConditionKind::Ge,
+        _ => return None,
+    })
+}
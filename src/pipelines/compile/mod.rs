@@ -20,8 +20,17 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+//! Source to bytecode compilation. The syntax tree is compiled into LUNIR intermediate
+//! language, whose registers are laid out as the target format expects, and lowered by the
+//! serializer of the target format.
+
+mod codegen;
+mod tests;
+
 use super::OptimizationLevel;
-use crate::ir::{ast::tree::*, il::IlChunk};
+use crate::formats::Serializer;
+use crate::ir::ast::tree::*;
+use anyhow::{Context, Result};
 use std::sync::{Arc, Weak};
 
 #[doc(hidden)]
@@ -29,7 +38,7 @@ use std::sync::{Arc, Weak};
 pub struct NoSerializer;
 #[doc(hidden)]
 #[derive(Clone, Debug)]
-pub struct WithSerializer<S: Fn(IlChunk) -> Vec<u8>>(S);
+pub struct WithSerializer<S: Serializer>(S);
 
 #[doc(hidden)]
 #[derive(Clone, Debug)]
@@ -55,12 +64,9 @@ impl<T, F> CompilationJob<T, F> {
         self
     }
 }
-impl<'a, T> CompilationJob<T, NoSerializer> {
-    /// Adds a target format serializer function to this `CompilationJob` to allow it to produce a final bytecode.
-    pub fn serializer<S: Fn(IlChunk) -> Vec<u8>>(
-        self,
-        serializer: S,
-    ) -> CompilationJob<T, WithSerializer<S>> {
+impl<T> CompilationJob<T, NoSerializer> {
+    /// Adds a target format serializer to this `CompilationJob` to allow it to produce a final bytecode.
+    pub fn serializer<S: Serializer>(self, serializer: S) -> CompilationJob<T, WithSerializer<S>> {
         CompilationJob {
             optimization_level: self.optimization_level,
             _reference: self._reference,
@@ -82,11 +88,25 @@ impl<'a, F> CompilationJob<NoTree, F> {
     }
 }
 
-impl<'a, S: Fn(IlChunk) -> Vec<u8>> CompilationJob<WithTree<'a>, WithSerializer<S>> {
+impl<'a, S: Serializer> CompilationJob<WithTree<'a>, WithSerializer<S>> {
     /// Invokes LUNIR's compilation pipeline with the parameters passed through the `CompilationJob`. This will consume the job.
     #[must_use = "The result of compilation should be used."]
-    pub fn run(self) -> Vec<u8> {
-        todo!()
+    pub fn run(self) -> Result<Vec<u8>> {
+        let serializer = self.serializer.0;
+        let fold = !matches!(self.optimization_level, OptimizationLevel::None);
+
+        let function = codegen::compile(self.tree.0, serializer.loop_layout(), fold)?;
+
+        // malformed IL would otherwise surface as an obscure error of the serializer
+        if cfg!(debug_assertions) {
+            function
+                .verify()
+                .context("the syntax tree was compiled into malformed IL")?;
+        }
+
+        serializer
+            .serialize(&function)
+            .context("failed to write the bytecode")
     }
 }
 
@@ -95,6 +115,12 @@ pub struct Compiler {
     handle: Arc<()>,
 }
 
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Compiler {
     /// Creates a new `Compiler`.
     pub fn new() -> Self {
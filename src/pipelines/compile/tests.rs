@@ -0,0 +1,252 @@
+#![cfg(test)]
+use super::*;
+use crate::formats::{Deserializer, Format};
+use crate::ir::ast::{parse, Dialect};
+use crate::ir::il::interpreter::{Interpreter, Semantics};
+
+/// Compiles `source` into `format`, then reads the bytecode back and runs it, returning
+/// what it printed.
+fn run(source: &str, dialect: Dialect, format: Format) -> Vec<String> {
+    let tree = parse(source, dialect).unwrap();
+    let bytecode = Compiler::new()
+        .create_job()
+        .tree(&tree)
+        .serializer(format)
+        .run()
+        .unwrap_or_else(|error| panic!("failed to compile into {format}: {error:?}"));
+
+    let function = format.deserialize(&bytecode).unwrap();
+    let semantics = if dialect.has_integers() {
+        Semantics::Lua53
+    } else {
+        Semantics::Lua51
+    };
+
+    let mut interpreter = Interpreter::new().semantics(semantics).with_base_library();
+    interpreter
+        .run(&function, vec![])
+        .unwrap_or_else(|error| panic!("failed to run {format} bytecode: {error:?}"));
+
+    interpreter.output().to_vec()
+}
+
+/// Runs `source` compiled into every format that can hold `dialect`, checking that each
+/// prints `expected`.
+fn check(source: &str, dialect: Dialect, expected: &[&str]) {
+    let formats: &[Format] = match dialect {
+        Dialect::Lua51 => &[Format::Lua51, Format::Lua54, Format::Luau, Format::Lir],
+        Dialect::Luau => &[Format::Luau, Format::Lir],
+        _ => &[Format::Lua54, Format::Lir],
+    };
+
+    for &format in formats {
+        assert_eq!(
+            run(source, dialect, format),
+            expected,
+            "compiled into {format}"
+        );
+    }
+}
+
+#[test]
+fn statements_run() {
+    let source = r#"
+        local function fib(n)
+            if n < 2 then
+                return n
+            end
+            return fib(n - 1) + fib(n - 2)
+        end
+
+        local total = 0
+        for i = 10, 1, -3 do
+            total = total + i
+        end
+
+        local t = {}
+        local i = 0
+        while true do
+            i = i + 1
+            if i > 5 then break end
+            t[#t + 1] = i * i
+        end
+
+        repeat
+            local last = t[#t]
+            t[#t] = nil
+        until last < 20
+
+        print(fib(15), total, #t, t[3])
+
+        for index, value in ipairs({"a", "b", [4] = "d"}) do
+            print(index, value)
+        end
+    "#;
+
+    check(source, Dialect::Lua51, &["610\t22\t3\t9", "1\ta", "2\tb"]);
+}
+
+#[test]
+fn expressions_run() {
+    let source = r##"
+        local a, b = 1, 2
+        a, b = b, a
+
+        local t = {x = {y = 1}, 10, 20, 30}
+        t.x.y, t[1] = t.x.y + 1, "first"
+
+        local function pack(...)
+            return {n = select("#", ...), ...}
+        end
+        local packed = pack(nil, 2, nil)
+
+        local object = {value = 5}
+        function object:get(extra)
+            return self.value + extra
+        end
+
+        print(a, b, t.x.y, t[1], #t, packed.n, packed[2])
+        print(object:get(1), nil or "default", false and 1, 1 and nil, 2 < 3, "a" .. "b" .. 1)
+        print(not nil, -(2 ^ 3), 7 % 3, 2 == 2.0, a ~= b, ("paren"))
+    "##;
+
+    check(
+        source,
+        Dialect::Lua51,
+        &[
+            "2\t1\t2\tfirst\t3\t3\t2",
+            "6\tdefault\tfalse\tnil\ttrue\tab1",
+            "true\t-8\t1\ttrue\ttrue\tparen",
+        ],
+    );
+}
+
+#[test]
+fn closures_capture_each_iteration() {
+    let source = r#"
+        local functions = {}
+        for i = 1, 3 do
+            local j = i * 10
+            functions[i] = function() return i + j end
+            if i == 2 then break end
+        end
+
+        local function counter()
+            local count = 0
+            return function()
+                count = count + 1
+                return count
+            end
+        end
+        local next = counter()
+        next()
+
+        local k = 0
+        while k < 3 do
+            k = k + 1
+            local captured = k
+            functions[#functions + 1] = function() return captured end
+        end
+
+        print(functions[1](), functions[2](), next(), functions[3](), functions[5]())
+    "#;
+
+    check(source, Dialect::Lua51, &["11\t22\t2\t1\t3"]);
+}
+
+#[test]
+fn lua54_constructs_run() {
+    let source = r#"
+        local x <const> = 7
+        print(x // 2, x & 3, x | 8, 1 << 4, x / 2, 3 // 0.0)
+
+        for i = 1, 3 do
+            if i == 2 then goto continue end
+            print(i)
+            ::continue::
+        end
+
+        do
+            local n = 0
+            ::top::
+            n = n + 1
+            if n < 3 then goto top end
+            print(n)
+        end
+    "#;
+
+    check(
+        source,
+        Dialect::Lua54,
+        &["3\t3\t15\t16\t3.5\tinf", "1", "3", "3"],
+    );
+}
+
+#[test]
+fn luau_constructs_run() {
+    let source = r#"
+        local total: number = 0
+        for i = 1, 3 do
+            if i == 2 then continue end
+            total += i
+            i = 10
+        end
+
+        local name = "x"
+        name ..= "y"
+
+        local t = {n = 1}
+        t.n *= 5
+
+        local value = if total > 3 then "big" elseif total > 1 then "small" else "none"
+        print(total, name, t.n, value, (1 :: any))
+    "#;
+
+    check(source, Dialect::Luau, &["4\txy\t5\tbig\t1"]);
+}
+
+#[test]
+fn gotos_cannot_jump_into_locals() {
+    let source = "goto skip\nlocal x = 1\n::skip::\nprint(x)";
+    let tree = parse(source, Dialect::Lua54).unwrap();
+
+    let error = Compiler::new()
+        .create_job()
+        .tree(&tree)
+        .serializer(Format::Lua54)
+        .run()
+        .unwrap_err();
+
+    assert!(format!("{error:?}").contains("jumps into the scope of local 'x'"));
+}
+
+#[test]
+fn constants_are_folded_unless_disabled() {
+    let tree = parse("return 2 * 3 + -1", Dialect::Lua51).unwrap();
+    let compile = |level| codegen::compile(&tree, Default::default(), level).unwrap();
+
+    let folded = compile(true);
+    assert_eq!(folded.constants, [crate::ir::il::Constant::Number(5.0)]);
+
+    let unfolded = compile(false);
+    assert_eq!(unfolded.constants.len(), 3);
+
+    for function in [folded, unfolded] {
+        let results = Interpreter::new().run(&function, vec![]).unwrap();
+        assert_eq!(format!("{results:?}"), "[5]");
+    }
+}
+
+#[test]
+fn interpolated_strings_call_format() {
+    let tree = parse("local n = 1\nreturn `{n}% of {n}`", Dialect::Luau).unwrap();
+    let function = codegen::compile(&tree, Default::default(), true).unwrap();
+
+    assert_eq!(
+        function.constants[1..],
+        [
+            crate::ir::il::Constant::String(b"%*%% of %*".to_vec()),
+            crate::ir::il::Constant::String(b"format".to_vec()),
+        ]
+    );
+}
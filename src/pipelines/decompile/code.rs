@@ -0,0 +1,504 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The code a function is decompiled into before it becomes a syntax tree. Registers are
+//! replaced by variables, and statements are nested once control flow is structured, but
+//! locals are not declared and nothing is named yet.
+
+use crate::ir::ast::tree::{BinaryOperator, UnaryOperator};
+use crate::ir::span::Span;
+
+/// A variable of a decompiled function, which stands for the definitions of a register
+/// whose values reach the same uses. It indexes the `vars` of its `Function`.
+pub(super) type Var = usize;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Expr {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    Integer(i64),
+    String(Vec<u8>),
+    /// `...`, which only produces more than one value when `multiple` is set.
+    VarArg {
+        multiple: bool,
+    },
+    Var(Var),
+    /// An upvalue of the function, by its index.
+    Upvalue(usize),
+    /// A global, by its name.
+    Global(Vec<u8>),
+    Index(Box<Expr>, Box<Expr>),
+    /// A call, which only produces more than one value when `multiple` is set.
+    Call {
+        callee: Box<Expr>,
+        args: Vec<Expr>,
+        multiple: bool,
+    },
+    MethodCall {
+        object: Box<Expr>,
+        method: Vec<u8>,
+        args: Vec<Expr>,
+        multiple: bool,
+    },
+    Binary(BinaryOperator, Box<Expr>, Box<Expr>),
+    Unary(UnaryOperator, Box<Expr>),
+    Table(Vec<Field>),
+    Function(Box<Function>),
+}
+
+impl Expr {
+    pub fn binary(operator: BinaryOperator, left: Expr, right: Expr) -> Self {
+        Self::Binary(operator, Box::new(left), Box::new(right))
+    }
+
+    /// The negation of a condition, which is only tested for whether it is truthy.
+    pub fn not(self) -> Self {
+        match self {
+            Self::Unary(UnaryOperator::Not, operand) => *operand,
+            Self::Binary(BinaryOperator::Eq, left, right) => {
+                Self::Binary(BinaryOperator::Ne, left, right)
+            }
+            Self::Binary(BinaryOperator::Ne, left, right) => {
+                Self::Binary(BinaryOperator::Eq, left, right)
+            }
+            Self::Boolean(b) => Self::Boolean(!b),
+            Self::Nil => Self::Boolean(true),
+            other => Self::Unary(UnaryOperator::Not, Box::new(other)),
+        }
+    }
+
+    /// Whether the expression always evaluates to a boolean.
+    pub fn is_boolean(&self) -> bool {
+        match self {
+            Self::Boolean(_) | Self::Unary(UnaryOperator::Not, _) => true,
+            Self::Binary(operator, left, right) => match operator {
+                BinaryOperator::Eq
+                | BinaryOperator::Ne
+                | BinaryOperator::Lt
+                | BinaryOperator::Le
+                | BinaryOperator::Gt
+                | BinaryOperator::Ge => true,
+                BinaryOperator::And | BinaryOperator::Or => left.is_boolean() && right.is_boolean(),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Calls `visit` with this expression and every expression nested in it, in the order
+    /// they are evaluated. Functions are not entered, their upvalues are found through the
+    /// captures instead.
+    pub fn walk(&self, visit: &mut impl FnMut(&Expr)) {
+        visit(self);
+
+        match self {
+            Self::Index(object, key) => {
+                object.walk(visit);
+                key.walk(visit);
+            }
+            Self::Call { callee, args, .. } => {
+                callee.walk(visit);
+                args.iter().for_each(|arg| arg.walk(visit));
+            }
+            Self::MethodCall { object, args, .. } => {
+                object.walk(visit);
+                args.iter().for_each(|arg| arg.walk(visit));
+            }
+            Self::Binary(_, left, right) => {
+                left.walk(visit);
+                right.walk(visit);
+            }
+            Self::Unary(_, operand) => operand.walk(visit),
+            Self::Table(fields) => {
+                for field in fields {
+                    if let Field::Keyed(key, _) = field {
+                        key.walk(visit);
+                    }
+                    field.value().walk(visit);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Like `walk`, but allows the expressions to be changed.
+    pub fn walk_mut(&mut self, visit: &mut impl FnMut(&mut Expr)) {
+        visit(self);
+
+        match self {
+            Self::Index(object, key) => {
+                object.walk_mut(visit);
+                key.walk_mut(visit);
+            }
+            Self::Call { callee, args, .. } => {
+                callee.walk_mut(visit);
+                args.iter_mut().for_each(|arg| arg.walk_mut(visit));
+            }
+            Self::MethodCall { object, args, .. } => {
+                object.walk_mut(visit);
+                args.iter_mut().for_each(|arg| arg.walk_mut(visit));
+            }
+            Self::Binary(_, left, right) => {
+                left.walk_mut(visit);
+                right.walk_mut(visit);
+            }
+            Self::Unary(_, operand) => operand.walk_mut(visit),
+            Self::Table(fields) => {
+                for field in fields {
+                    match field {
+                        Field::Keyed(key, value) => {
+                            key.walk_mut(visit);
+                            value.walk_mut(visit);
+                        }
+                        Field::Positional(value) => value.walk_mut(visit),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The variables the expression reads, including those captured by its functions.
+    pub fn vars(&self, vars: &mut Vec<Var>) {
+        self.walk(&mut |expr| match expr {
+            Self::Var(var) => vars.push(*var),
+            Self::Function(function) => vars.extend(function.captured()),
+            _ => {}
+        });
+    }
+}
+
+/// Whether `name` is a name in Lua, which can follow `.` or `:` and name a variable.
+pub(super) fn is_identifier(name: &[u8]) -> bool {
+    const KEYWORDS: &[&[u8]] = &[
+        b"and",
+        b"break",
+        b"do",
+        b"else",
+        b"elseif",
+        b"end",
+        b"false",
+        b"for",
+        b"function",
+        b"goto",
+        b"if",
+        b"in",
+        b"local",
+        b"nil",
+        b"not",
+        b"or",
+        b"repeat",
+        b"return",
+        b"then",
+        b"true",
+        b"until",
+        b"while",
+    ];
+
+    matches!(name.first(), Some(c) if c.is_ascii_alphabetic() || *c == b'_')
+        && name.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_')
+        && !KEYWORDS.contains(&name)
+}
+
+/// An entry of a table constructor.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Field {
+    Keyed(Expr, Expr),
+    Positional(Expr),
+}
+
+impl Field {
+    pub fn value(&self) -> &Expr {
+        match self {
+            Self::Keyed(_, value) | Self::Positional(value) => value,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct Stmt {
+    pub kind: StmtKind,
+    pub span: Option<Span>,
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Option<Span>) -> Self {
+        Self { kind, span }
+    }
+
+    /// Whether control never continues after the statement.
+    pub fn is_jump(&self) -> bool {
+        matches!(
+            self.kind,
+            StmtKind::Return(_) | StmtKind::Break | StmtKind::Continue | StmtKind::Goto(_)
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum StmtKind {
+    /// Assigns `values` to `targets`, which are variables, upvalues, globals or indices.
+    Assign {
+        targets: Vec<Expr>,
+        values: Vec<Expr>,
+    },
+    Call(Expr),
+    /// Looks up the method `key` of `value` into `function`, and `value` itself into
+    /// `object`, for a call that passes the object as its first argument.
+    SelfLookup {
+        function: Var,
+        object: Var,
+        value: Expr,
+        key: Expr,
+    },
+    /// Stores `values` into the table from index `offset + 1`, which only a table
+    /// constructor can express.
+    SetList {
+        table: Var,
+        offset: usize,
+        values: Vec<Expr>,
+    },
+    Return(Vec<Expr>),
+    If {
+        condition: Expr,
+        then: Vec<Stmt>,
+        otherwise: Vec<Stmt>,
+    },
+    /// A loop of any kind. `touches` are the variables whose upvalues stay open from one
+    /// iteration to the next, which must be declared outside of the loop.
+    Loop {
+        kind: LoopKind,
+        body: Vec<Stmt>,
+        touches: Vec<Var>,
+    },
+    Break,
+    Continue,
+    Goto(String),
+    Label(String),
+    /// Declares `vars`, initialized to `values`.
+    Local {
+        vars: Vec<Var>,
+        values: Vec<Expr>,
+    },
+    /// Declares `var` as a function, which can refer to itself.
+    LocalFunction {
+        var: Var,
+        function: Box<Function>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum LoopKind {
+    While(Expr),
+    Repeat(Expr),
+    NumericFor {
+        var: Var,
+        start: Expr,
+        limit: Expr,
+        step: Expr,
+    },
+    GenericFor {
+        vars: Vec<Var>,
+        values: Vec<Expr>,
+    },
+}
+
+impl StmtKind {
+    /// The expressions of the statement itself, in the order they are evaluated, leaving
+    /// out those of the statements nested in it.
+    pub fn exprs(&self) -> Vec<&Expr> {
+        match self {
+            Self::Assign { targets, values } => targets.iter().chain(values).collect(),
+            Self::Call(call) => vec![call],
+            Self::SelfLookup { value, key, .. } => vec![value, key],
+            Self::SetList { values, .. } | Self::Return(values) => values.iter().collect(),
+            Self::If { condition, .. } => vec![condition],
+            Self::Loop { kind, .. } => match kind {
+                LoopKind::While(condition) | LoopKind::Repeat(condition) => vec![condition],
+                LoopKind::NumericFor {
+                    start, limit, step, ..
+                } => vec![start, limit, step],
+                LoopKind::GenericFor { values, .. } => values.iter().collect(),
+            },
+            Self::Local { values, .. } => values.iter().collect(),
+            Self::Break
+            | Self::Continue
+            | Self::Goto(_)
+            | Self::Label(_)
+            | Self::LocalFunction { .. } => vec![],
+        }
+    }
+
+    /// Like `exprs`, but allows the expressions to be changed.
+    pub fn exprs_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Self::Assign { targets, values } => targets.iter_mut().chain(values).collect(),
+            Self::Call(call) => vec![call],
+            Self::SelfLookup { value, key, .. } => vec![value, key],
+            Self::SetList { values, .. } | Self::Return(values) => values.iter_mut().collect(),
+            Self::If { condition, .. } => vec![condition],
+            Self::Loop { kind, .. } => match kind {
+                LoopKind::While(condition) | LoopKind::Repeat(condition) => vec![condition],
+                LoopKind::NumericFor {
+                    start, limit, step, ..
+                } => vec![start, limit, step],
+                LoopKind::GenericFor { values, .. } => values.iter_mut().collect(),
+            },
+            Self::Local { values, .. } => values.iter_mut().collect(),
+            Self::Break
+            | Self::Continue
+            | Self::Goto(_)
+            | Self::Label(_)
+            | Self::LocalFunction { .. } => vec![],
+        }
+    }
+
+    /// The variables the statement defines, leaving out nested statements.
+    pub fn defs(&self) -> Vec<Var> {
+        match self {
+            Self::Assign { targets, .. } => targets
+                .iter()
+                .filter_map(|target| match target {
+                    Expr::Var(var) => Some(*var),
+                    _ => None,
+                })
+                .collect(),
+            Self::SelfLookup {
+                function, object, ..
+            } => vec![*function, *object],
+            Self::Loop {
+                kind: LoopKind::NumericFor { var, .. },
+                ..
+            } => vec![*var],
+            Self::Loop {
+                kind: LoopKind::GenericFor { vars, .. },
+                ..
+            } => vars.clone(),
+            Self::Local { vars, .. } => vars.clone(),
+            Self::LocalFunction { var, .. } => vec![*var],
+            _ => vec![],
+        }
+    }
+
+    /// The variables the statement reads, leaving out nested statements. Variables that
+    /// are assigned to are not read, but those in the indices they assign to are.
+    pub fn uses(&self) -> Vec<Var> {
+        let mut vars = vec![];
+
+        match self {
+            Self::Assign { targets, values } => {
+                for target in targets {
+                    if !matches!(target, Expr::Var(_)) {
+                        target.vars(&mut vars);
+                    }
+                }
+                values.iter().for_each(|value| value.vars(&mut vars));
+            }
+            Self::SetList { table, values, .. } => {
+                vars.push(*table);
+                values.iter().for_each(|value| value.vars(&mut vars));
+            }
+            Self::LocalFunction { function, .. } => vars.extend(function.captured()),
+            _ => self.exprs().iter().for_each(|expr| expr.vars(&mut vars)),
+        }
+
+        vars
+    }
+
+    /// The statement blocks nested in the statement.
+    pub fn blocks(&self) -> Vec<&Vec<Stmt>> {
+        match self {
+            Self::If {
+                then, otherwise, ..
+            } => vec![then, otherwise],
+            Self::Loop { body, .. } => vec![body],
+            _ => vec![],
+        }
+    }
+
+    pub fn blocks_mut(&mut self) -> Vec<&mut Vec<Stmt>> {
+        match self {
+            Self::If {
+                then, otherwise, ..
+            } => vec![then, otherwise],
+            Self::Loop { body, .. } => vec![body],
+            _ => vec![],
+        }
+    }
+}
+
+/// Where a closure finds one of its upvalues in the function that creates it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Capture {
+    Var(Var),
+    Upvalue(usize),
+}
+
+/// What is known about a variable before it is declared and named.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(super) struct VarInfo {
+    /// The name debug information gives the variable.
+    pub name: Option<String>,
+    /// Whether the variable is declared by the function or a loop rather than by a
+    /// `local` statement.
+    pub implicit: bool,
+    /// What to name the variable after when debug information does not name it.
+    pub hint: Hint,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(super) enum Hint {
+    #[default]
+    Local,
+    Parameter,
+    /// The `arg` table of a Lua 5.1 vararg function.
+    Arg,
+    Counter,
+    Key,
+    Value,
+}
+
+/// A decompiled function.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct Function {
+    pub params: Vec<Var>,
+    pub is_vararg: bool,
+    pub body: Vec<Stmt>,
+    pub vars: Vec<VarInfo>,
+    /// How each upvalue is captured from the enclosing function.
+    pub captures: Vec<Capture>,
+    /// The names debug information gives the upvalues, which only matter for the main
+    /// function as the others are named after what they capture.
+    pub upvalue_names: Vec<String>,
+    pub span: Option<Span>,
+}
+
+impl Function {
+    /// The variables of the enclosing function that the function captures.
+    pub fn captured(&self) -> impl Iterator<Item = Var> + '_ {
+        self.captures.iter().filter_map(|capture| match capture {
+            Capture::Var(var) => Some(*var),
+            Capture::Upvalue(_) => None,
+        })
+    }
+}
@@ -0,0 +1,924 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Emitting a decompiled function as a syntax tree. Every variable is declared by a
+//! `local` statement in the innermost block that holds all of its occurrences, and named
+//! after its debug information when it has any. Names never shadow one another, so that
+//! every name refers to the variable it was given to wherever it occurs.
+
+use super::code::{self, *};
+use crate::ir::ast::tree::{
+    self, BinaryOperator, Block, Branch, Expression, ExpressionKind, FunctionBody, FunctionName,
+    LocalName, Name, Statement, StatementKind, TypedName, UnaryOperator,
+};
+use crate::ir::ast::Dialect;
+use crate::ir::span::Span;
+use anyhow::{anyhow, bail, Result};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// The position of a block, as the index of each statement on the way to it and which of
+/// the blocks of that statement it is.
+type Path = Vec<(usize, usize)>;
+
+/// Adds where each variable occurs in `block` to `found`, as the path of the block and the
+/// index of the statement in it.
+fn occurrences(block: &[Stmt], path: &mut Path, found: &mut HashMap<Var, Vec<(Path, usize)>>) {
+    for (index, statement) in block.iter().enumerate() {
+        let mut vars = statement.kind.defs();
+
+        match &statement.kind {
+            // the condition of `repeat` is inside the scope of its body
+            StmtKind::Loop {
+                kind: LoopKind::Repeat(condition),
+                body,
+                touches,
+            } => {
+                vars.extend(touches);
+
+                let mut condition_vars = vec![];
+                condition.vars(&mut condition_vars);
+
+                path.push((index, 0));
+                for var in condition_vars {
+                    found
+                        .entry(var)
+                        .or_default()
+                        .push((path.clone(), body.len()));
+                }
+                path.pop();
+            }
+            StmtKind::Loop { touches, .. } => {
+                vars.extend(statement.kind.uses());
+                vars.extend(touches);
+            }
+            _ => vars.extend(statement.kind.uses()),
+        }
+
+        for var in vars {
+            found.entry(var).or_default().push((path.clone(), index));
+        }
+
+        for (position, inner) in statement.kind.blocks().into_iter().enumerate() {
+            path.push((index, position));
+            occurrences(inner, path, found);
+            path.pop();
+        }
+    }
+}
+
+/// Declares `vars` at `statement`, which is the first to refer to them in their block.
+fn declare(statement: Stmt, vars: &[Var]) -> Vec<Stmt> {
+    let span = statement.span;
+    let local = |vars: Vec<Var>| {
+        Stmt::new(
+            StmtKind::Local {
+                vars,
+                values: vec![],
+            },
+            span,
+        )
+    };
+
+    match statement.kind {
+        // the name of a local function is in scope of its body, so it may refer to itself
+        StmtKind::Assign {
+            targets,
+            mut values,
+        } if matches!((&targets[..], &values[..]), ([Expr::Var(var)], [Expr::Function(_)]) if vars.contains(var)) =>
+        {
+            let var = match targets[..] {
+                [Expr::Var(var)] => var,
+                _ => unreachable!("the target was matched as a variable"),
+            };
+            let function = match values.remove(0) {
+                Expr::Function(function) => function,
+                _ => unreachable!("the value was matched as a function"),
+            };
+            let rest = vars
+                .iter()
+                .copied()
+                .filter(|&other| other != var)
+                .collect::<Vec<_>>();
+
+            let mut statements = vec![];
+            if !rest.is_empty() {
+                statements.push(local(rest));
+            }
+            statements.push(Stmt::new(StmtKind::LocalFunction { var, function }, span));
+
+            statements
+        }
+        StmtKind::Assign { targets, values }
+            if targets.iter().all(|target| match target {
+                Expr::Var(var) => {
+                    vars.contains(var)
+                        && !values.iter().any(|value| {
+                            let mut referenced = vec![];
+                            value.vars(&mut referenced);
+                            targets.iter().any(
+                            |target| matches!(target, Expr::Var(var) if referenced.contains(var)),
+                        )
+                        })
+                }
+                _ => false,
+            }) =>
+        {
+            let assigned = targets
+                .iter()
+                .filter_map(|target| match target {
+                    Expr::Var(var) => Some(*var),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            let rest = vars
+                .iter()
+                .copied()
+                .filter(|var| !assigned.contains(var))
+                .collect::<Vec<_>>();
+
+            let mut statements = vec![];
+            if !rest.is_empty() {
+                statements.push(local(rest));
+            }
+
+            let kind = StmtKind::Local {
+                vars: assigned,
+                values,
+            };
+            statements.push(Stmt::new(kind, span));
+
+            statements
+        }
+        kind => vec![local(vars.to_vec()), Stmt::new(kind, span)],
+    }
+}
+
+/// Inserts the declarations of `decls` into `block` and the blocks nested in it.
+fn rebuild(
+    block: Vec<Stmt>,
+    path: &mut Path,
+    decls: &HashMap<Path, BTreeMap<usize, Vec<Var>>>,
+) -> Vec<Stmt> {
+    let positions = decls.get(path).cloned().unwrap_or_default();
+    let mut rebuilt = Vec::with_capacity(block.len());
+
+    for (index, mut statement) in block.into_iter().enumerate() {
+        for (position, inner) in statement.kind.blocks_mut().into_iter().enumerate() {
+            path.push((index, position));
+            *inner = rebuild(std::mem::take(inner), path, decls);
+            path.pop();
+        }
+
+        match positions.get(&index) {
+            Some(vars) => rebuilt.extend(declare(statement, vars)),
+            None => rebuilt.push(statement),
+        }
+    }
+
+    rebuilt
+}
+
+/// Places the declarations of the locals of `function` into its body.
+fn declarations(function: &Function) -> Vec<Stmt> {
+    let mut found = HashMap::new();
+    occurrences(&function.body, &mut vec![], &mut found);
+
+    let mut decls: HashMap<Path, BTreeMap<usize, Vec<Var>>> = HashMap::new();
+    let mut vars = found.into_iter().collect::<Vec<_>>();
+    vars.sort_unstable_by_key(|(var, _)| *var);
+
+    for (var, occurrences) in vars {
+        if function.vars[var].implicit || function.params.contains(&var) {
+            continue;
+        }
+
+        // the innermost block holding every occurrence
+        let mut common = occurrences[0].0.clone();
+        for (path, _) in &occurrences[1..] {
+            let shared = common.iter().zip(path).take_while(|(a, b)| a == b).count();
+            common.truncate(shared);
+        }
+
+        let index = occurrences
+            .iter()
+            .map(|(path, index)| path.get(common.len()).map_or(*index, |(index, _)| *index))
+            .min()
+            .unwrap_or_default();
+
+        decls
+            .entry(common)
+            .or_default()
+            .entry(index)
+            .or_default()
+            .push(var);
+    }
+
+    rebuild(function.body.clone(), &mut vec![], &decls)
+}
+
+/// Adds the names of the globals that `block` and the functions in it refer to.
+fn globals(block: &[Stmt], names: &mut HashSet<String>) {
+    for statement in block {
+        for expr in statement.kind.exprs() {
+            expr.walk(&mut |expr| match expr {
+                Expr::Global(name) => {
+                    names.insert(String::from_utf8_lossy(name).into_owned());
+                }
+                Expr::Function(function) => globals(&function.body, names),
+                _ => {}
+            });
+        }
+
+        if let StmtKind::LocalFunction { function, .. } = &statement.kind {
+            globals(&function.body, names);
+        }
+
+        for inner in statement.kind.blocks() {
+            globals(inner, names);
+        }
+    }
+}
+
+struct Emitter<'a> {
+    dialect: Dialect,
+    reserved: &'a HashSet<String>,
+    /// Numbers the generated names of the whole chunk.
+    counter: &'a Cell<usize>,
+    function: &'a Function,
+    names: Vec<Option<String>>,
+    upvalues: Vec<String>,
+    /// The names declared in each enclosing scope, outermost first.
+    scopes: Vec<Vec<String>>,
+    /// The span of the statement being emitted, which spans of nested parts default to.
+    span: Span,
+}
+
+impl<'a> Emitter<'a> {
+    fn name(&self, name: &str) -> Name {
+        Name {
+            name: name.to_owned(),
+            span: self.span,
+        }
+    }
+
+    fn expression(&self, kind: ExpressionKind) -> Expression {
+        Expression {
+            kind,
+            span: self.span,
+        }
+    }
+
+    fn statement(&self, kind: StatementKind) -> Statement {
+        Statement {
+            kind,
+            span: self.span,
+        }
+    }
+
+    fn is_taken(&self, name: &str) -> bool {
+        !is_identifier(name.as_bytes())
+            || matches!(name, "continue" | "_ENV" | "_G")
+            || self.reserved.contains(name)
+            || self.scopes.iter().flatten().any(|taken| taken == name)
+    }
+
+    /// Gives `var` a name that no variable in scope has, and brings it into scope.
+    fn declare(&mut self, var: Var) -> Name {
+        let info = &self.function.vars[var];
+        let generated = || {
+            self.counter.set(self.counter.get() + 1);
+            self.counter.get()
+        };
+
+        let base = match (&info.name, info.hint) {
+            (Some(name), _) if is_identifier(name.as_bytes()) => name.clone(),
+            (_, Hint::Arg) => "arg".to_owned(),
+            (_, Hint::Counter) => "i".to_owned(),
+            (_, Hint::Key) => "k".to_owned(),
+            (_, Hint::Value) => "v".to_owned(),
+            (_, Hint::Parameter) => format!("p{}", generated()),
+            (_, Hint::Local) => format!("v{}", generated()),
+        };
+
+        let mut name = base.clone();
+        let mut suffix = 1;
+        while self.is_taken(&name) {
+            suffix += 1;
+            name = format!("{base}_{suffix}");
+        }
+
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.push(name.clone());
+        }
+        self.names[var] = Some(name.clone());
+
+        self.name(&name)
+    }
+
+    fn var(&self, var: Var) -> Result<String> {
+        self.names[var]
+            .clone()
+            .ok_or_else(|| anyhow!("a variable is used before it is declared"))
+    }
+
+    fn number(&self, number: f64) -> Expression {
+        if number.is_sign_negative() && !number.is_nan() {
+            let operand = self.expression(ExpressionKind::Number(-number));
+
+            self.expression(ExpressionKind::Unary {
+                operator: UnaryOperator::Neg,
+                operand: Box::new(operand),
+            })
+        } else {
+            self.expression(ExpressionKind::Number(number))
+        }
+    }
+
+    fn integer(&self, integer: i64) -> Expression {
+        if !self.dialect.has_integers() {
+            return self.number(integer as f64);
+        }
+
+        let negate = |operand: Expression| {
+            self.expression(ExpressionKind::Unary {
+                operator: UnaryOperator::Neg,
+                operand: Box::new(operand),
+            })
+        };
+
+        match integer {
+            // the literal of the smallest integer would be read as a float
+            i64::MIN => self.expression(ExpressionKind::Binary {
+                operator: BinaryOperator::Sub,
+                left: Box::new(negate(self.expression(ExpressionKind::Integer(i64::MAX)))),
+                right: Box::new(self.expression(ExpressionKind::Integer(1))),
+            }),
+            integer if integer < 0 => negate(self.expression(ExpressionKind::Integer(-integer))),
+            integer => self.expression(ExpressionKind::Integer(integer)),
+        }
+    }
+
+    fn global(&self, name: &[u8]) -> Expression {
+        if is_identifier(name) {
+            return self.expression(ExpressionKind::Name(
+                self.name(&String::from_utf8_lossy(name)),
+            ));
+        }
+
+        let globals = if self.dialect.has_goto() {
+            "_ENV"
+        } else {
+            "_G"
+        };
+
+        self.expression(ExpressionKind::Index {
+            object: Box::new(self.expression(ExpressionKind::Name(self.name(globals)))),
+            key: Box::new(self.expression(ExpressionKind::String(name.to_vec()))),
+        })
+    }
+
+    fn index(&self, object: &Expr, key: &Expr) -> Result<Expression> {
+        let object = Box::new(self.expr(object)?);
+
+        Ok(match key {
+            Expr::String(name) if is_identifier(name) => self.expression(ExpressionKind::Field {
+                object,
+                name: self.name(&String::from_utf8_lossy(name)),
+            }),
+            key => self.expression(ExpressionKind::Index {
+                object,
+                key: Box::new(self.expr(key)?),
+            }),
+        })
+    }
+
+    /// Whether `expr` is a call or `...` that produces exactly one value, which must be
+    /// parenthesized where it could produce more.
+    fn is_truncated(expr: &Expr) -> bool {
+        matches!(
+            expr,
+            Expr::Call {
+                multiple: false,
+                ..
+            } | Expr::MethodCall {
+                multiple: false,
+                ..
+            } | Expr::VarArg { multiple: false }
+        )
+    }
+
+    /// The expressions of a list, whose last expression may produce more than one value.
+    fn list(&self, exprs: &[Expr]) -> Result<Vec<Expression>> {
+        exprs
+            .iter()
+            .enumerate()
+            .map(|(index, expr)| {
+                let expression = self.expr(expr)?;
+
+                Ok(if index + 1 == exprs.len() && Self::is_truncated(expr) {
+                    self.expression(ExpressionKind::Paren(Box::new(expression)))
+                } else {
+                    expression
+                })
+            })
+            .collect()
+    }
+
+    /// The values assigned to `count` targets, whose last value only needs to be limited to
+    /// one value when there are targets left for more.
+    fn values(&self, exprs: &[Expr], count: usize) -> Result<Vec<Expression>> {
+        if count > exprs.len() {
+            self.list(exprs)
+        } else {
+            exprs.iter().map(|expr| self.expr(expr)).collect()
+        }
+    }
+
+    fn expr(&self, expr: &Expr) -> Result<Expression> {
+        let kind = match expr {
+            Expr::Nil => ExpressionKind::Nil,
+            Expr::Boolean(b) => ExpressionKind::Boolean(*b),
+            Expr::Number(number) => return Ok(self.number(*number)),
+            Expr::Integer(integer) => return Ok(self.integer(*integer)),
+            Expr::String(string) => ExpressionKind::String(string.clone()),
+            Expr::VarArg { .. } => ExpressionKind::VarArg,
+            Expr::Var(var) => ExpressionKind::Name(self.name(&self.var(*var)?)),
+            Expr::Upvalue(index) => {
+                let name = self
+                    .upvalues
+                    .get(*index)
+                    .ok_or_else(|| anyhow!("upvalue {index} does not exist"))?;
+
+                ExpressionKind::Name(self.name(name))
+            }
+            Expr::Global(name) => return Ok(self.global(name)),
+            Expr::Index(object, key) => return self.index(object, key),
+            Expr::Call { callee, args, .. } => ExpressionKind::Call {
+                callee: Box::new(self.expr(callee)?),
+                args: self.list(args)?,
+            },
+            Expr::MethodCall {
+                object,
+                method,
+                args,
+                ..
+            } => ExpressionKind::MethodCall {
+                object: Box::new(self.expr(object)?),
+                method: self.name(&String::from_utf8_lossy(method)),
+                args: self.list(args)?,
+            },
+            Expr::Binary(operator, left, right) => ExpressionKind::Binary {
+                operator: *operator,
+                left: Box::new(self.expr(left)?),
+                right: Box::new(self.expr(right)?),
+            },
+            Expr::Unary(operator, operand) => ExpressionKind::Unary {
+                operator: *operator,
+                operand: Box::new(self.expr(operand)?),
+            },
+            Expr::Table(fields) => ExpressionKind::Table(
+                fields
+                    .iter()
+                    .enumerate()
+                    .map(|(index, field)| {
+                        Ok(match field {
+                            code::Field::Keyed(Expr::String(name), value)
+                                if is_identifier(name) =>
+                            {
+                                tree::Field::Named {
+                                    name: self.name(&String::from_utf8_lossy(name)),
+                                    value: self.expr(value)?,
+                                }
+                            }
+                            code::Field::Keyed(key, value) => tree::Field::Keyed {
+                                key: self.expr(key)?,
+                                value: self.expr(value)?,
+                            },
+                            code::Field::Positional(value) => {
+                                let last = index + 1 == fields.len();
+                                let mut list = self.list(std::slice::from_ref(value))?;
+                                let value = list.remove(0);
+
+                                tree::Field::Positional(if last {
+                                    value
+                                } else {
+                                    self.expr(field.value())?
+                                })
+                            }
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+            Expr::Function(function) => {
+                ExpressionKind::Function(Box::new(self.function(function)?))
+            }
+        };
+
+        Ok(self.expression(kind))
+    }
+
+    /// Emits a function created in this one, whose upvalues are named after what they
+    /// capture.
+    fn function(&self, function: &Function) -> Result<FunctionBody> {
+        let upvalues = function
+            .captures
+            .iter()
+            .map(|capture| match capture {
+                Capture::Var(var) => self.var(*var),
+                Capture::Upvalue(index) => self
+                    .upvalues
+                    .get(*index)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("upvalue {index} does not exist")),
+            })
+            .collect::<Result<_>>()?;
+
+        let mut emitter = Emitter {
+            dialect: self.dialect,
+            reserved: self.reserved,
+            counter: self.counter,
+            function,
+            names: vec![None; function.vars.len()],
+            upvalues,
+            scopes: vec![self.scopes.iter().flatten().cloned().collect()],
+            span: function.span.unwrap_or(self.span),
+        };
+
+        emitter.body()
+    }
+
+    fn body(&mut self) -> Result<FunctionBody> {
+        let span = self.span;
+        self.scopes.push(vec![]);
+
+        let function = self.function;
+        let params = function
+            .params
+            .iter()
+            .map(|&param| TypedName {
+                name: self.declare(param),
+                annotation: None,
+            })
+            .collect();
+
+        if let Some(arg) =
+            (0..self.function.vars.len()).find(|&var| self.function.vars[var].hint == Hint::Arg)
+        {
+            self.declare(arg);
+        }
+
+        let mut statements = self.statements(declarations(self.function))?;
+        self.scopes.pop();
+
+        // the return every function ends with need not be written
+        if let Some(Statement {
+            kind: StatementKind::Return(values),
+            ..
+        }) = statements.last()
+        {
+            if values.is_empty() {
+                statements.pop();
+            }
+        }
+
+        Ok(FunctionBody {
+            attributes: vec![],
+            generics: vec![],
+            params,
+            is_vararg: self.function.is_vararg,
+            vararg_annotation: None,
+            returns: None,
+            block: Block { statements },
+            span,
+        })
+    }
+
+    fn block(&mut self, statements: Vec<Stmt>) -> Result<Block> {
+        self.scopes.push(vec![]);
+        let statements = self.statements(statements)?;
+        self.scopes.pop();
+
+        Ok(Block { statements })
+    }
+
+    fn statements(&mut self, statements: Vec<Stmt>) -> Result<Vec<Statement>> {
+        let mut emitted = vec![];
+        for statement in statements {
+            emitted.extend(self.stmt(statement)?);
+        }
+
+        // only the last statement of a block may leave it
+        let count = emitted.len();
+        for (index, statement) in emitted.iter_mut().enumerate() {
+            if index + 1 < count
+                && matches!(
+                    statement.kind,
+                    StatementKind::Return(_) | StatementKind::Break | StatementKind::Continue
+                )
+            {
+                let span = statement.span;
+                let inner = std::mem::replace(&mut statement.kind, StatementKind::Error);
+
+                statement.kind = StatementKind::Do(Block {
+                    statements: vec![Statement { kind: inner, span }],
+                });
+            }
+        }
+
+        Ok(emitted)
+    }
+
+    /// The names leading to where a function is assigned, for a function statement.
+    fn function_path(&self, target: &Expr) -> Option<Vec<String>> {
+        match target {
+            Expr::Global(name) if is_identifier(name) => {
+                Some(vec![String::from_utf8_lossy(name).into_owned()])
+            }
+            Expr::Var(var) => self.names[*var].clone().map(|name| vec![name]),
+            Expr::Upvalue(index) => self.upvalues.get(*index).map(|name| vec![name.clone()]),
+            Expr::Index(object, key) => match &**key {
+                Expr::String(key) if is_identifier(key) => {
+                    let mut path = self.function_path(object)?;
+                    path.push(String::from_utf8_lossy(key).into_owned());
+
+                    Some(path)
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn target(&self, target: &Expr) -> Result<Expression> {
+        match target {
+            Expr::Var(_) | Expr::Upvalue(_) | Expr::Global(_) | Expr::Index(..) => {
+                self.expr(target)
+            }
+            _ => bail!("a value is assigned to an expression that cannot be assigned to"),
+        }
+    }
+
+    fn stmt(&mut self, statement: Stmt) -> Result<Vec<Statement>> {
+        if let Some(span) = statement.span {
+            self.span = span;
+        }
+
+        let kind = match statement.kind {
+            StmtKind::Assign { targets, values } => {
+                if let ([target], [Expr::Function(function)]) = (&targets[..], &values[..]) {
+                    if let Some(mut path) = self.function_path(target) {
+                        let mut function = self.function(function)?;
+
+                        let is_method = path.len() > 1
+                            && function
+                                .params
+                                .first()
+                                .map_or(false, |param| param.name.name == "self");
+                        let method = if is_method {
+                            function.params.remove(0);
+                            path.pop().map(|method| self.name(&method))
+                        } else {
+                            None
+                        };
+
+                        let name = FunctionName {
+                            path: path.iter().map(|name| self.name(name)).collect(),
+                            method,
+                        };
+
+                        return Ok(vec![
+                            self.statement(StatementKind::Function { name, function })
+                        ]);
+                    }
+                }
+
+                StatementKind::Assign {
+                    targets: targets
+                        .iter()
+                        .map(|target| self.target(target))
+                        .collect::<Result<_>>()?,
+                    values: self.values(&values, targets.len())?,
+                }
+            }
+            StmtKind::Call(call) => StatementKind::Call(self.expr(&call)?),
+            StmtKind::SetList {
+                table,
+                offset,
+                values,
+            } => {
+                if values.last().map_or(false, |value| {
+                    matches!(
+                        value,
+                        Expr::Call { multiple: true, .. }
+                            | Expr::MethodCall { multiple: true, .. }
+                            | Expr::VarArg { multiple: true }
+                    )
+                }) {
+                    bail!("a table is filled in a way that only a constructor can express");
+                }
+
+                let targets = (0..values.len())
+                    .map(|index| {
+                        let key = Expr::Integer((offset + index + 1) as i64);
+                        self.index(&Expr::Var(table), &key)
+                    })
+                    .collect::<Result<_>>()?;
+
+                StatementKind::Assign {
+                    targets,
+                    values: self.values(&values, values.len())?,
+                }
+            }
+            StmtKind::SelfLookup { .. } => {
+                bail!("a method is looked up without being called")
+            }
+            StmtKind::Return(values) => StatementKind::Return(self.list(&values)?),
+            StmtKind::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let mut branches = vec![Branch {
+                    condition: self.expr(&condition)?,
+                    block: self.block(then)?,
+                }];
+                let mut otherwise = otherwise;
+
+                // an `if` that is all there is to the `else` block continues the chain
+                while let [Stmt {
+                    kind:
+                        StmtKind::If {
+                            condition,
+                            then,
+                            otherwise: rest,
+                        },
+                    ..
+                }] = &mut otherwise[..]
+                {
+                    let condition = self.expr(condition)?;
+                    let then = std::mem::take(then);
+                    let rest = std::mem::take(rest);
+
+                    branches.push(Branch {
+                        condition,
+                        block: self.block(then)?,
+                    });
+                    otherwise = rest;
+                }
+
+                StatementKind::If {
+                    branches,
+                    otherwise: if otherwise.is_empty() {
+                        None
+                    } else {
+                        Some(self.block(otherwise)?)
+                    },
+                }
+            }
+            StmtKind::Loop { kind, body, .. } => match kind {
+                LoopKind::While(condition) => StatementKind::While {
+                    condition: self.expr(&condition)?,
+                    block: self.block(body)?,
+                },
+                LoopKind::Repeat(condition) => {
+                    self.scopes.push(vec![]);
+                    let statements = self.statements(body)?;
+                    let condition = self.expr(&condition)?;
+                    self.scopes.pop();
+
+                    StatementKind::Repeat {
+                        block: Block { statements },
+                        condition,
+                    }
+                }
+                LoopKind::NumericFor {
+                    var,
+                    start,
+                    limit,
+                    step,
+                } => {
+                    let start = self.expr(&start)?;
+                    let limit = self.expr(&limit)?;
+                    let step = match step {
+                        Expr::Integer(1) => None,
+                        Expr::Number(step) if step == 1.0 && !self.dialect.has_integers() => None,
+                        step => Some(self.expr(&step)?),
+                    };
+
+                    self.scopes.push(vec![]);
+                    let variable = TypedName {
+                        name: self.declare(var),
+                        annotation: None,
+                    };
+                    let statements = self.statements(body)?;
+                    self.scopes.pop();
+
+                    StatementKind::NumericFor {
+                        variable,
+                        start,
+                        limit,
+                        step,
+                        block: Block { statements },
+                    }
+                }
+                LoopKind::GenericFor { vars, values } => {
+                    let values = self.list(&values)?;
+
+                    self.scopes.push(vec![]);
+                    let names = vars
+                        .iter()
+                        .map(|&var| TypedName {
+                            name: self.declare(var),
+                            annotation: None,
+                        })
+                        .collect();
+                    let statements = self.statements(body)?;
+                    self.scopes.pop();
+
+                    StatementKind::GenericFor {
+                        names,
+                        values,
+                        block: Block { statements },
+                    }
+                }
+            },
+            StmtKind::Break => StatementKind::Break,
+            StmtKind::Continue => StatementKind::Continue,
+            StmtKind::Goto(label) => StatementKind::Goto(self.name(&label)),
+            StmtKind::Label(label) => StatementKind::Label(self.name(&label)),
+            StmtKind::Local { vars, values } => {
+                let values = self.values(&values, vars.len())?;
+                let names = vars
+                    .iter()
+                    .map(|&var| LocalName {
+                        name: self.declare(var),
+                        attribute: None,
+                        annotation: None,
+                    })
+                    .collect();
+
+                StatementKind::Local { names, values }
+            }
+            StmtKind::LocalFunction { var, function } => {
+                let name = self.declare(var);
+
+                StatementKind::LocalFunction {
+                    name,
+                    function: self.function(&function)?,
+                }
+            }
+        };
+
+        Ok(vec![self.statement(kind)])
+    }
+}
+
+/// Emits the main function of a chunk as a syntax tree.
+pub(super) fn emit(function: &Function, dialect: Dialect) -> Result<tree::Node> {
+    let mut reserved = HashSet::new();
+    globals(&function.body, &mut reserved);
+
+    let upvalues = (0..function.captures.len())
+        .map(|index| match function.upvalue_names.get(index) {
+            Some(name) if is_identifier(name.as_bytes()) => name.clone(),
+            _ if index == 0 && dialect.has_goto() => "_ENV".to_owned(),
+            _ => format!("up{index}"),
+        })
+        .collect();
+
+    let counter = Cell::new(0);
+    let mut emitter = Emitter {
+        dialect,
+        reserved: &reserved,
+        counter: &counter,
+        function,
+        names: vec![None; function.vars.len()],
+        upvalues,
+        scopes: vec![],
+        span: function.span.unwrap_or_else(|| Span::line(0)),
+    };
+
+    let body = emitter.body()?;
+
+    Ok(tree::Node { block: body.block })
+}
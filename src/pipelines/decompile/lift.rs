@@ -0,0 +1,1182 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Lifting the IL of a function into nodes of statements, one for every basic block of its
+//! control flow graph.
+//!
+//! Registers are split into variables first. Every definition of a register starts a web,
+//! and the webs of definitions that reach the same use are joined, so that each variable
+//! holds the values that flow into the same uses. A closure that captures a register also
+//! joins the definitions made while the upvalue is open, as they are visible through it.
+
+use super::code::*;
+use super::Options;
+use crate::ir::ast::tree::{BinaryOperator, UnaryOperator};
+use crate::ir::il::{
+    BinaryOpKind, Close, Constant, Function as IlFunction, Inst, Instruction, IntrinsicKind,
+    IntrinsicSource, Label, OptVariable, Table, UnaryOpKind, Value,
+};
+use crate::ir::il::{ConditionKind, Return};
+use crate::ir::mir::cir::into_cir_graph;
+use crate::ir::span::Span;
+use anyhow::{anyhow, bail, Context, Result};
+use cranelift_entity::EntityRef;
+use petgraph::visit::EdgeRef;
+use std::collections::{HashMap, HashSet};
+
+/// A basic block of a function being decompiled.
+pub(super) struct Node {
+    pub statements: Vec<Stmt>,
+    pub exit: Exit,
+    /// The span of the instruction that ends the node.
+    pub span: Option<Span>,
+    /// The instruction the node starts at, which orders the nodes as the chunk does.
+    pub first: usize,
+    /// Whether the node only exists to be the header of a numeric `for` loop, which must
+    /// be kept even though it is empty.
+    pub synthetic: bool,
+    /// Whether the node is still part of the function, rather than merged into another.
+    pub alive: bool,
+}
+
+/// How control leaves a node.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Exit {
+    Jump(usize),
+    /// Continues at `then` when `condition` is truthy, otherwise at `otherwise`.
+    Branch {
+        condition: Expr,
+        then: usize,
+        otherwise: usize,
+    },
+    /// The function returns by the last statement of the node.
+    Return,
+    /// Enters a numeric `for` loop, whose synthetic header is `body`.
+    NumericFor {
+        var: Var,
+        start: Expr,
+        limit: Expr,
+        step: Expr,
+        body: usize,
+        exit: usize,
+    },
+    /// Steps a numeric `for` loop, back to its header `body` or out to `exit`.
+    NumericLoop {
+        body: usize,
+        exit: usize,
+    },
+    /// Enters a generic `for` loop, whose header `call` calls the iterator.
+    GenericFor {
+        values: Vec<Expr>,
+        call: usize,
+    },
+    /// Calls the iterator of a generic `for` loop into `vars`.
+    GenericLoop {
+        vars: Vec<Var>,
+        body: usize,
+        exit: usize,
+    },
+}
+
+impl Exit {
+    pub fn successors(&self) -> Vec<usize> {
+        match self {
+            Self::Jump(next) => vec![*next],
+            Self::Branch {
+                then, otherwise, ..
+            } => vec![*then, *otherwise],
+            Self::Return => vec![],
+            Self::NumericFor { body, exit, .. }
+            | Self::NumericLoop { body, exit }
+            | Self::GenericLoop { body, exit, .. } => vec![*body, *exit],
+            Self::GenericFor { call, .. } => vec![*call],
+        }
+    }
+
+    pub fn successors_mut(&mut self) -> Vec<&mut usize> {
+        match self {
+            Self::Jump(next) => vec![next],
+            Self::Branch {
+                then, otherwise, ..
+            } => vec![then, otherwise],
+            Self::Return => vec![],
+            Self::NumericFor { body, exit, .. }
+            | Self::NumericLoop { body, exit }
+            | Self::GenericLoop { body, exit, .. } => vec![body, exit],
+            Self::GenericFor { call, .. } => vec![call],
+        }
+    }
+
+    /// The expressions the exit evaluates, in order.
+    pub fn exprs(&self) -> Vec<&Expr> {
+        match self {
+            Self::Branch { condition, .. } => vec![condition],
+            Self::NumericFor {
+                start, limit, step, ..
+            } => vec![start, limit, step],
+            Self::GenericFor { values, .. } => values.iter().collect(),
+            _ => vec![],
+        }
+    }
+
+    pub fn exprs_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Self::Branch { condition, .. } => vec![condition],
+            Self::NumericFor {
+                start, limit, step, ..
+            } => vec![start, limit, step],
+            Self::GenericFor { values, .. } => values.iter_mut().collect(),
+            _ => vec![],
+        }
+    }
+
+    /// The variables the exit defines.
+    pub fn defs(&self) -> Vec<Var> {
+        match self {
+            Self::NumericFor { var, .. } => vec![*var],
+            Self::GenericLoop { vars, .. } => vars.clone(),
+            _ => vec![],
+        }
+    }
+}
+
+/// A function lifted into nodes, the first of which is its entry.
+pub(super) struct Lifted {
+    pub nodes: Vec<Node>,
+    pub params: Vec<Var>,
+    pub vars: Vec<VarInfo>,
+    /// The instructions at which the upvalue of each captured variable is open.
+    pub open: Vec<(Var, HashSet<usize>)>,
+}
+
+/// The definition of a register by an instruction, or on entry to the function when there
+/// is no instruction.
+type Def = (Option<usize>, usize);
+
+/// The registers an instruction reads and writes, as far as decompilation is concerned.
+/// Loops hide the registers that hold their state, and values up to the top of the stack
+/// are passed from the instruction producing them to the next one without registers.
+fn operands(
+    function: &IlFunction,
+    instruction: &Instruction,
+    open: Option<usize>,
+) -> (Vec<usize>, Vec<usize>) {
+    let fixed = |slots: crate::ir::il::Slots| slots.fixed.to_vec();
+
+    match instruction {
+        Instruction::ForNumPrep(prep) => (vec![prep.index, prep.limit, prep.step], vec![prep.var]),
+        Instruction::ForNumLoop(next) => (vec![], vec![next.var]),
+        Instruction::ForGenPrep(prep) => ((prep.base..prep.base + 3).collect(), vec![]),
+        Instruction::ForGenCall(call) => (vec![], (call.dest..call.dest + call.count).collect()),
+        Instruction::ForGenLoop(_) => (vec![], vec![]),
+        Instruction::Closure(closure) => {
+            let uses = function
+                .prototypes
+                .get(closure.prototype)
+                .map(|prototype| {
+                    prototype
+                        .upvalues
+                        .iter()
+                        .filter(|upvalue| upvalue.in_stack && upvalue.index != closure.dest)
+                        .map(|upvalue| upvalue.index)
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            (uses, vec![closure.dest])
+        }
+        Instruction::Call(call) => {
+            let mut uses = fixed(instruction.uses());
+            if call.num_args == OptVariable::Variable {
+                uses.extend(call.callee + 1..open.unwrap_or(call.callee + 1));
+            }
+
+            (uses, fixed(instruction.defs()))
+        }
+        Instruction::SetList(list) if list.count == OptVariable::Variable => {
+            let mut uses = vec![list.table];
+            uses.extend(list.start..open.unwrap_or(list.start));
+
+            (uses, vec![])
+        }
+        Instruction::Return(Return {
+            result_start,
+            result_count: OptVariable::Variable,
+        }) => (
+            (*result_start..open.unwrap_or(*result_start)).collect(),
+            vec![],
+        ),
+        _ => (fixed(instruction.uses()), fixed(instruction.defs())),
+    }
+}
+
+/// The register that values up to the top of the stack start at, if the instruction
+/// produces them.
+fn opens(instruction: &Instruction) -> Option<usize> {
+    match instruction {
+        Instruction::Call(call) if call.num_returns == OptVariable::Variable => Some(call.callee),
+        Instruction::VarArg(vararg) if vararg.count == OptVariable::Variable => Some(vararg.dest),
+        _ => None,
+    }
+}
+
+/// Whether the instruction consumes the values up to the top of the stack.
+fn consumes_open(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::Call(call) => call.num_args == OptVariable::Variable,
+        Instruction::SetList(list) => list.count == OptVariable::Variable,
+        Instruction::Return(ret) => ret.result_count == OptVariable::Variable,
+        _ => false,
+    }
+}
+
+/// Finds the root of `def` in the union-find forest `parents`.
+fn find(parents: &mut [usize], mut def: usize) -> usize {
+    while parents[def] != def {
+        parents[def] = parents[parents[def]];
+        def = parents[def];
+    }
+
+    def
+}
+
+fn union(parents: &mut [usize], a: usize, b: usize) {
+    let a = find(parents, a);
+    let b = find(parents, b);
+
+    // the earlier definition names the web, which keeps parameters first
+    parents[a.max(b)] = a.min(b);
+}
+
+struct Lifter<'f> {
+    function: &'f IlFunction,
+    options: Options,
+    instructions: &'f [Instruction],
+    /// The open producer each instruction consuming values up to the top is paired with.
+    open: Vec<Option<usize>>,
+    defs: HashMap<Def, usize>,
+    parents: Vec<usize>,
+    /// The definition each use of a register by an instruction is reached by.
+    reaching: HashMap<(usize, usize), usize>,
+    vars: HashMap<usize, Var>,
+    infos: Vec<VarInfo>,
+    /// The call or vararg producing values up to the top of the stack, which the next
+    /// instruction consuming them takes.
+    pending: Option<Expr>,
+}
+
+impl<'f> Lifter<'f> {
+    fn def(&mut self, def: Def) -> usize {
+        let next = self.parents.len();
+        let id = *self.defs.entry(def).or_insert(next);
+
+        if id == next {
+            self.parents.push(next);
+        }
+
+        id
+    }
+
+    /// The variable of the web that `def` belongs to.
+    fn var_of(&mut self, def: usize) -> Var {
+        let root = find(&mut self.parents, def);
+        let next = self.infos.len();
+        let var = *self.vars.entry(root).or_insert(next);
+
+        if var == next {
+            self.infos.push(VarInfo::default());
+        }
+
+        var
+    }
+
+    fn def_var(&mut self, inst: usize, register: usize) -> Var {
+        let def = self.def((Some(inst), register));
+        let var = self.var_of(def);
+        self.name(var, register, inst + 1);
+
+        var
+    }
+
+    fn use_var(&mut self, inst: usize, register: usize) -> Var {
+        let def = match self.reaching.get(&(inst, register)) {
+            Some(&def) => def,
+            None => self.def((None, register)),
+        };
+        let var = self.var_of(def);
+        self.name(var, register, inst);
+
+        var
+    }
+
+    /// Names `var` after the local that debug information places in `register` at `inst`.
+    /// Internal locals such as `(for state)` hold no variable of the source, and leave it
+    /// unnamed.
+    fn name(&mut self, var: Var, register: usize, inst: usize) {
+        if self.infos[var].name.is_some() || inst >= self.instructions.len() {
+            return;
+        }
+
+        let local = self.function.debug.local_at(register, Inst::new(inst));
+        self.infos[var].name = local
+            .filter(|local| is_identifier(local.name.as_bytes()))
+            .map(|local| local.name.clone());
+    }
+
+    /// Splits the registers into webs of definitions.
+    fn dataflow(&mut self, blocks: &[std::ops::Range<usize>], preds: &[Vec<usize>]) -> Result<()> {
+        let mut operands_of = Vec::with_capacity(self.instructions.len());
+        let mut registers = self.function.arity.params as usize + 1;
+
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            let (uses, defs) = operands(self.function, instruction, self.open[index]);
+            registers = registers.max(uses.iter().chain(&defs).map(|r| r + 1).max().unwrap_or(0));
+            operands_of.push((uses, defs));
+        }
+
+        for register in 0..registers {
+            // the definitions of the register reaching the start of each block
+            let mut entry = vec![Vec::<usize>::new(); blocks.len()];
+            let last = blocks
+                .iter()
+                .map(|block| {
+                    block
+                        .clone()
+                        .rev()
+                        .find(|&index| operands_of[index].1.contains(&register))
+                })
+                .collect::<Vec<_>>();
+
+            if !blocks.is_empty() {
+                entry[0].push(self.def((None, register)));
+            }
+
+            let mut changed = true;
+            while changed {
+                changed = false;
+
+                for block in 0..blocks.len() {
+                    let mut reaching = entry[block].clone();
+
+                    for &pred in &preds[block] {
+                        match last[pred] {
+                            Some(index) => reaching.push(self.def((Some(index), register))),
+                            None => reaching.extend(entry[pred].iter().copied()),
+                        }
+                    }
+
+                    reaching.sort_unstable();
+                    reaching.dedup();
+
+                    if reaching != entry[block] {
+                        entry[block] = reaching;
+                        changed = true;
+                    }
+                }
+            }
+
+            for (block, range) in blocks.iter().enumerate() {
+                let mut current = entry[block].clone();
+
+                for index in range.clone() {
+                    let (uses, defs) = &operands_of[index];
+
+                    if uses.contains(&register) {
+                        if current.is_empty() {
+                            current.push(self.def((None, register)));
+                        }
+
+                        for &def in &current[1..] {
+                            union(&mut self.parents, current[0], def);
+                        }
+                        self.reaching.insert((index, register), current[0]);
+                    }
+
+                    if defs.contains(&register) {
+                        current = vec![self.def((Some(index), register))];
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Joins the definitions of a register that debug information places in the same local,
+    /// such as both sides of a swap, which would otherwise become variables of their own.
+    /// A local starts once all of its initial values are evaluated, so the definition of
+    /// its initial value belongs to it as well.
+    fn locals(&mut self) {
+        let locals = &self.function.debug.locals;
+        let mut defs = self
+            .defs
+            .iter()
+            .map(|(&def, &id)| (def, id))
+            .collect::<Vec<_>>();
+        defs.sort_unstable();
+
+        let mut first = HashMap::new();
+        for ((inst, register), id) in defs {
+            let after = inst.map_or(0, |inst| inst + 1);
+            let local = locals
+                .iter()
+                .rposition(|local| local.register == register && local.contains(Inst::new(after)))
+                .or_else(|| {
+                    locals.iter().position(|local| {
+                        local.register == register
+                            && inst.is_some()
+                            && local.start.index() >= after
+                            && self.instructions[after..local.start.index()]
+                                .iter()
+                                .all(|instruction| !instruction.defs().fixed.contains(&register))
+                    })
+                });
+
+            match local {
+                Some(local) if is_identifier(locals[local].name.as_bytes()) => {
+                    let web = *first.entry(local).or_insert(id);
+                    union(&mut self.parents, web, id);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Joins the definitions made while the upvalues of a closure are open, returning the
+    /// instructions at which each captured definition is open.
+    fn captures(&mut self, successors: &[Vec<usize>]) -> Vec<(usize, HashSet<usize>)> {
+        let mut open = vec![];
+
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            let closure = match instruction {
+                Instruction::Closure(closure) => closure,
+                _ => continue,
+            };
+            let prototype = match self.function.prototypes.get(closure.prototype) {
+                Some(prototype) => prototype,
+                None => continue,
+            };
+
+            for upvalue in prototype.upvalues.iter().filter(|upvalue| upvalue.in_stack) {
+                let register = upvalue.index;
+                let captured = if register == closure.dest {
+                    self.def((Some(index), register))
+                } else {
+                    match self.reaching.get(&(index, register)) {
+                        Some(&def) => def,
+                        None => self.def((None, register)),
+                    }
+                };
+
+                let mut visited = HashSet::new();
+                let mut pending = successors[index].clone();
+
+                while let Some(next) = pending.pop() {
+                    if !visited.insert(next) {
+                        continue;
+                    }
+
+                    match &self.instructions[next] {
+                        Instruction::Close(Close { start }) if *start <= register => continue,
+                        Instruction::Return(_) => continue,
+                        _ => {}
+                    }
+
+                    let (_, defs) = operands(self.function, &self.instructions[next], None);
+                    if defs.contains(&register) {
+                        let def = self.def((Some(next), register));
+                        union(&mut self.parents, captured, def);
+                    }
+
+                    pending.extend(successors[next].iter().copied());
+                }
+
+                open.push((captured, visited));
+            }
+        }
+
+        open
+    }
+}
+
+/// Lifts `function` into nodes. The functions it creates are decompiled as they are found.
+pub(super) fn lift(function: &IlFunction, options: Options) -> Result<Lifted> {
+    let cfg = into_cir_graph(function.chunk.clone())?;
+    let instructions = function.chunk.inner();
+    let graph = cfg.inner();
+    let count = graph.node_count();
+
+    let blocks = graph
+        .node_indices()
+        .map(|node| {
+            let mut insts = cfg.insts(graph[node]).map(|inst| inst.index());
+            let start = insts.next().unwrap_or_default();
+            start..insts.last().map_or(start + 1, |last| last + 1)
+        })
+        .collect::<Vec<_>>();
+
+    let mut preds = vec![vec![]; count];
+    for edge in graph.edge_references() {
+        preds[edge.target().index()].push(edge.source().index());
+    }
+
+    let mut open = vec![None; instructions.len()];
+    for block in &blocks {
+        let mut producer = None;
+
+        for index in block.clone() {
+            if consumes_open(&instructions[index]) {
+                open[index] = Some(producer.take().ok_or_else(|| {
+                    anyhow!("instruction {index} takes values up to the top of the stack that nothing before it produced")
+                })?);
+            }
+
+            if let Some(start) = opens(&instructions[index]) {
+                producer = Some(start);
+            }
+        }
+    }
+
+    let labels = crate::ir::il::resolve_labels(instructions)?;
+    let successors = instructions
+        .iter()
+        .enumerate()
+        .map(|(index, instruction)| {
+            let mut successors = instruction
+                .target()
+                .map(|target| labels[&target])
+                .into_iter()
+                .collect::<Vec<_>>();
+
+            if !instruction.is_terminator() && index + 1 < instructions.len() {
+                successors.push(index + 1);
+            }
+
+            successors
+        })
+        .collect::<Vec<_>>();
+
+    let mut lifter = Lifter {
+        function,
+        options,
+        instructions,
+        open,
+        defs: HashMap::new(),
+        parents: vec![],
+        reaching: HashMap::new(),
+        vars: HashMap::new(),
+        infos: vec![],
+        pending: None,
+    };
+
+    lifter.dataflow(&blocks, &preds)?;
+    lifter.locals();
+    let captures = lifter.captures(&successors);
+
+    // parameters come first, in the order of their registers
+    let mut params = vec![];
+    for register in 0..function.arity.params as usize {
+        let def = lifter.def((None, register));
+        let var = lifter.var_of(def);
+        lifter.name(var, register, 0);
+        lifter.infos[var].implicit = true;
+        lifter.infos[var].hint = Hint::Parameter;
+        params.push(var);
+    }
+
+    if function.arity.needs_arg {
+        let def = lifter.def((None, function.arity.params as usize));
+        let var = lifter.var_of(def);
+        lifter.infos[var].implicit = true;
+        lifter.infos[var].hint = Hint::Arg;
+    }
+
+    let block_of_label = labels
+        .iter()
+        .map(|(label, &index)| {
+            let block = blocks
+                .iter()
+                .position(|block| block.contains(&index))
+                .unwrap_or_default();
+
+            (*label, block)
+        })
+        .collect::<HashMap<Label, usize>>();
+
+    let mut nodes = blocks
+        .iter()
+        .map(|block| Node {
+            statements: vec![],
+            exit: Exit::Return,
+            span: None,
+            first: block.start,
+            synthetic: false,
+            alive: true,
+        })
+        .collect::<Vec<_>>();
+
+    let mut numeric_entries = HashMap::new();
+
+    for (block, range) in blocks.iter().enumerate() {
+        let mut current = block;
+        let mut exit = None;
+        let mut generic_vars = vec![];
+        let target = |label: &Label| {
+            block_of_label
+                .get(label)
+                .copied()
+                .ok_or_else(|| anyhow!("jump to undefined label {label:?}"))
+        };
+        let next = || {
+            Some(block + 1)
+                .filter(|&next| next < count)
+                .ok_or_else(|| anyhow!("control flows past the end of the chunk"))
+        };
+
+        for index in range.clone() {
+            let instruction = &instructions[index];
+            let span = function.chunk.span(Inst::new(index));
+
+            if matches!(
+                instruction,
+                Instruction::ForNumLoop(_) | Instruction::ForGenCall(_)
+            ) && !nodes[current].statements.is_empty()
+            {
+                let split = nodes.len();
+                nodes.push(Node {
+                    statements: vec![],
+                    exit: Exit::Return,
+                    span: None,
+                    first: index,
+                    synthetic: false,
+                    alive: true,
+                });
+                nodes[current].exit = Exit::Jump(split);
+                current = split;
+            }
+
+            let (statements, ended) = lifter.instruction(index, &mut generic_vars)?;
+            nodes[current]
+                .statements
+                .extend(statements.into_iter().map(|kind| Stmt::new(kind, span)));
+
+            let node_exit = match instruction {
+                Instruction::Jump(jump) => Exit::Jump(target(&jump.target)?),
+                Instruction::JumpNot(jump) => Exit::Branch {
+                    condition: Expr::Var(lifter.use_var(index, jump.cond)),
+                    then: next()?,
+                    otherwise: target(&jump.target)?,
+                },
+                Instruction::ConditionalJump(jump) => {
+                    let operator = match jump.condition.kind {
+                        ConditionKind::Eq => BinaryOperator::Eq,
+                        ConditionKind::Ne => BinaryOperator::Ne,
+                        ConditionKind::Lt => BinaryOperator::Lt,
+                        ConditionKind::Le => BinaryOperator::Le,
+                        ConditionKind::Gt => BinaryOperator::Gt,
+                        ConditionKind::Ge => BinaryOperator::Ge,
+                        ConditionKind::And => BinaryOperator::And,
+                        ConditionKind::Or => BinaryOperator::Or,
+                    };
+                    let left = lifter.value(index, &jump.condition.left)?;
+                    let right = lifter.value(index, &jump.condition.right)?;
+
+                    Exit::Branch {
+                        condition: Expr::binary(operator, left, right),
+                        then: target(&jump.target)?,
+                        otherwise: next()?,
+                    }
+                }
+                Instruction::ForNumPrep(prep) => {
+                    let body = next()?;
+                    let entry = nodes.len();
+                    nodes.push(Node {
+                        statements: vec![],
+                        exit: Exit::Jump(body),
+                        span,
+                        first: nodes[body].first,
+                        synthetic: true,
+                        alive: true,
+                    });
+                    numeric_entries.insert(body, entry);
+
+                    Exit::NumericFor {
+                        start: Expr::Var(lifter.use_var(index, prep.index)),
+                        limit: Expr::Var(lifter.use_var(index, prep.limit)),
+                        step: Expr::Var(lifter.use_var(index, prep.step)),
+                        var: lifter.def_var(index, prep.var),
+                        body: entry,
+                        exit: target(&prep.target)?,
+                    }
+                }
+                Instruction::ForNumLoop(next_loop) => {
+                    let body = target(&next_loop.target)?;
+                    let entry = *numeric_entries
+                        .get(&body)
+                        .context("numeric for loop steps back to a loop it did not prepare")?;
+
+                    Exit::NumericLoop {
+                        body: entry,
+                        exit: next()?,
+                    }
+                }
+                Instruction::ForGenPrep(prep) => Exit::GenericFor {
+                    values: (prep.base..prep.base + 3)
+                        .map(|register| Expr::Var(lifter.use_var(index, register)))
+                        .collect(),
+                    call: target(&prep.target)?,
+                },
+                Instruction::ForGenLoop(next_loop) => Exit::GenericLoop {
+                    vars: std::mem::take(&mut generic_vars),
+                    body: target(&next_loop.target)?,
+                    exit: next()?,
+                },
+                _ if ended => Exit::Return,
+                _ => continue,
+            };
+
+            nodes[current].span = span;
+            exit = Some(node_exit);
+            break;
+        }
+
+        nodes[current].exit = match exit {
+            Some(exit) => exit,
+            None => match next() {
+                Ok(next) => Exit::Jump(next),
+                Err(_) => {
+                    let ret = Stmt::new(StmtKind::Return(vec![]), None);
+                    nodes[current].statements.push(ret);
+                    Exit::Return
+                }
+            },
+        };
+    }
+
+    let open = captures
+        .into_iter()
+        .map(|(def, insts)| (lifter.var_of(def), insts))
+        .collect();
+
+    Ok(Lifted {
+        nodes,
+        params,
+        vars: lifter.infos,
+        open,
+    })
+}
+
+impl<'f> Lifter<'f> {
+    fn constant(&self, index: usize) -> Result<Expr> {
+        let constant = self
+            .function
+            .constants
+            .get(index)
+            .ok_or_else(|| anyhow!("constant {index} does not exist"))?;
+
+        Ok(match constant {
+            Constant::Nil => Expr::Nil,
+            Constant::Boolean(b) => Expr::Boolean(*b),
+            Constant::Number(n) => Expr::Number(*n),
+            Constant::Integer(n) => Expr::Integer(*n),
+            Constant::String(s) => Expr::String(s.clone()),
+            Constant::Table(table) => {
+                let fields = match table {
+                    Table::Map(map) => map
+                        .iter()
+                        .map(|(key, value)| {
+                            Ok(Field::Keyed(
+                                self.constant_value(key)?,
+                                self.constant_value(value)?,
+                            ))
+                        })
+                        .collect::<Result<_>>()?,
+                    Table::Array(array) => array
+                        .iter()
+                        .map(|value| Ok(Field::Positional(self.constant_value(value)?)))
+                        .collect::<Result<_>>()?,
+                };
+
+                Expr::Table(fields)
+            }
+        })
+    }
+
+    /// An operand of a constant table, which cannot refer to registers.
+    fn constant_value(&self, value: &Value) -> Result<Expr> {
+        match value {
+            Value::Nil => Ok(Expr::Nil),
+            Value::Boolean(b) => Ok(Expr::Boolean(*b)),
+            Value::ConstantIndex(index) => self.constant(*index),
+            Value::Immediate(n) => Ok(Expr::Integer((*n).into())),
+            Value::StackIndex(_) => bail!("a constant table refers to a register"),
+        }
+    }
+
+    fn value(&mut self, inst: usize, value: &Value) -> Result<Expr> {
+        match value {
+            Value::StackIndex(register) => Ok(Expr::Var(self.use_var(inst, *register))),
+            _ => self.constant_value(value),
+        }
+    }
+
+    /// The name of the global held by the string constant `index`.
+    fn global(&self, index: usize) -> Result<Vec<u8>> {
+        match self.function.constants.get(index) {
+            Some(Constant::String(name)) => Ok(name.clone()),
+            _ => bail!("the name of a global is not a string constant"),
+        }
+    }
+
+    /// `library.name(args)`, a call of a standard library function.
+    fn library(library: &str, name: &str, args: Vec<Expr>) -> Expr {
+        Expr::Call {
+            callee: Box::new(Expr::Index(
+                Box::new(Expr::Global(library.as_bytes().to_vec())),
+                Box::new(Expr::String(name.as_bytes().to_vec())),
+            )),
+            args,
+            multiple: false,
+        }
+    }
+
+    /// The statements `index` lifts into, and whether it returns from the function.
+    /// Instructions that only move control are lifted into the exit of their node instead.
+    fn instruction(
+        &mut self,
+        index: usize,
+        generic_vars: &mut Vec<Var>,
+    ) -> Result<(Vec<StmtKind>, bool)> {
+        let assign = |target: Expr, value: Expr| StmtKind::Assign {
+            targets: vec![target],
+            values: vec![value],
+        };
+        let instruction = &self.instructions[index];
+
+        let statement = match instruction {
+            Instruction::Load(load) => {
+                let value = self.value(index, &load.src)?;
+                assign(Expr::Var(self.def_var(index, load.dest)), value)
+            }
+            Instruction::Move(mv) => {
+                let value = Expr::Var(self.use_var(index, mv.src));
+                assign(Expr::Var(self.def_var(index, mv.dest)), value)
+            }
+            Instruction::Intrinsic(intrinsic) => {
+                let operands = intrinsic
+                    .kind
+                    .operands()
+                    .into_iter()
+                    .map(|operand| self.value(index, operand))
+                    .collect::<Result<Vec<_>>>()?;
+                let library = match intrinsic.source {
+                    IntrinsicSource::Operator if self.options.dialect.has_bitwise_operators() => {
+                        None
+                    }
+                    IntrinsicSource::Operator => Some("bit32"),
+                    source => source.library(),
+                };
+
+                let value = match library {
+                    Some(library) => {
+                        Self::library(library, intrinsic.kind.library_name(), operands)
+                    }
+                    None => {
+                        let mut operands = operands.into_iter();
+                        let left = operands.next().unwrap_or(Expr::Nil);
+
+                        let operator = match intrinsic.kind {
+                            IntrinsicKind::BitNot(_) => {
+                                let operand = Box::new(left);
+                                let value = Expr::Unary(UnaryOperator::BitNot, operand);
+                                let dest = Expr::Var(self.def_var(index, intrinsic.dest));
+
+                                return Ok((vec![assign(dest, value)], false));
+                            }
+                            IntrinsicKind::BitAnd(..) => BinaryOperator::BitAnd,
+                            IntrinsicKind::BitOr(..) => BinaryOperator::BitOr,
+                            IntrinsicKind::BitXor(..) => BinaryOperator::BitXor,
+                            IntrinsicKind::LeftShift(..) => BinaryOperator::LeftShift,
+                            IntrinsicKind::RightShift(..) => BinaryOperator::RightShift,
+                        };
+
+                        Expr::binary(operator, left, operands.next().unwrap_or(Expr::Nil))
+                    }
+                };
+
+                assign(Expr::Var(self.def_var(index, intrinsic.dest)), value)
+            }
+            Instruction::GetGlobal(get) => {
+                let value = Expr::Global(self.global(get.constant)?);
+                assign(Expr::Var(self.def_var(index, get.dest)), value)
+            }
+            Instruction::SetGlobal(set) => {
+                let value = Expr::Var(self.use_var(index, set.src));
+                assign(Expr::Global(self.global(set.constant)?), value)
+            }
+            Instruction::GetTable(get) => {
+                let object = Expr::Var(self.use_var(index, get.source));
+                let key = self.value(index, &get.key)?;
+                let value = Expr::Index(Box::new(object), Box::new(key));
+
+                assign(Expr::Var(self.def_var(index, get.dest)), value)
+            }
+            Instruction::SetTable(set) => {
+                let table = Expr::Var(self.use_var(index, set.table));
+                let key = self.value(index, &set.key)?;
+                let value = self.value(index, &set.value)?;
+
+                assign(Expr::Index(Box::new(table), Box::new(key)), value)
+            }
+            Instruction::SelfLookup(lookup) => {
+                let value = Expr::Var(self.use_var(index, lookup.object));
+                let key = self.value(index, &lookup.key)?;
+
+                StmtKind::SelfLookup {
+                    function: self.def_var(index, lookup.dest),
+                    object: self.def_var(index, lookup.dest + 1),
+                    value,
+                    key,
+                }
+            }
+            Instruction::BinaryOp(op) => {
+                let left = self.value(index, &op.left)?;
+                let right = self.value(index, &op.right)?;
+
+                let operator = match op.operator {
+                    BinaryOpKind::Add => BinaryOperator::Add,
+                    BinaryOpKind::Concat => BinaryOperator::Concat,
+                    BinaryOpKind::Div => BinaryOperator::Div,
+                    BinaryOpKind::IDiv if !self.options.dialect.has_floor_division() => {
+                        let quotient = Expr::binary(BinaryOperator::Div, left, right);
+                        let value = Self::library("math", "floor", vec![quotient]);
+
+                        return Ok((
+                            vec![assign(Expr::Var(self.def_var(index, op.dest)), value)],
+                            false,
+                        ));
+                    }
+                    BinaryOpKind::IDiv => BinaryOperator::IDiv,
+                    BinaryOpKind::Mod => BinaryOperator::Mod,
+                    BinaryOpKind::Mul => BinaryOperator::Mul,
+                    BinaryOpKind::Pow => BinaryOperator::Pow,
+                    BinaryOpKind::Sub => BinaryOperator::Sub,
+                };
+
+                let value = Expr::binary(operator, left, right);
+                assign(Expr::Var(self.def_var(index, op.dest)), value)
+            }
+            Instruction::UnaryOp(op) => {
+                let operand = self.value(index, &op.left)?;
+                let operator = match op.operator {
+                    UnaryOpKind::Len => UnaryOperator::Len,
+                    UnaryOpKind::Not => UnaryOperator::Not,
+                    UnaryOpKind::Neg => UnaryOperator::Neg,
+                };
+
+                let value = Expr::Unary(operator, Box::new(operand));
+                assign(Expr::Var(self.def_var(index, op.dest)), value)
+            }
+            Instruction::Concat(concat) => {
+                let mut operands = (concat.start..=concat.end)
+                    .map(|register| Expr::Var(self.use_var(index, register)))
+                    .collect::<Vec<_>>();
+                let mut value = operands
+                    .pop()
+                    .ok_or_else(|| anyhow!("concatenation of no values"))?;
+
+                // concatenation is right associative, so the chain is built from the end
+                while let Some(operand) = operands.pop() {
+                    value = Expr::binary(BinaryOperator::Concat, operand, value);
+                }
+
+                assign(Expr::Var(self.def_var(index, concat.dest)), value)
+            }
+            Instruction::NewTable(table) => assign(
+                Expr::Var(self.def_var(index, table.dest)),
+                Expr::Table(vec![]),
+            ),
+            Instruction::SetList(list) => {
+                let table = self.use_var(index, list.table);
+                let values = self.values(index, list.start, &list.count)?;
+
+                StmtKind::SetList {
+                    table,
+                    offset: list.offset,
+                    values,
+                }
+            }
+            Instruction::Call(call) => {
+                let callee = Box::new(Expr::Var(self.use_var(index, call.callee)));
+                let count = match call.num_args {
+                    OptVariable::Number(count) => {
+                        OptVariable::Number(count + call.self_call as usize)
+                    }
+                    OptVariable::Variable => OptVariable::Variable,
+                };
+                let args = self.values(index, call.callee + 1, &count)?;
+
+                match call.num_returns {
+                    OptVariable::Number(0) => StmtKind::Call(Expr::Call {
+                        callee,
+                        args,
+                        multiple: false,
+                    }),
+                    OptVariable::Number(results) => {
+                        let value = Expr::Call {
+                            callee,
+                            args,
+                            multiple: results != 1,
+                        };
+                        let targets = (call.callee..call.callee + results)
+                            .map(|register| Expr::Var(self.def_var(index, register)))
+                            .collect();
+
+                        StmtKind::Assign {
+                            targets,
+                            values: vec![value],
+                        }
+                    }
+                    // the results are taken by the instruction consuming them
+                    OptVariable::Variable => {
+                        self.pending = Some(Expr::Call {
+                            callee,
+                            args,
+                            multiple: true,
+                        });
+
+                        return Ok((vec![], false));
+                    }
+                }
+            }
+            Instruction::VarArg(vararg) => match vararg.count {
+                OptVariable::Number(0) => return Ok((vec![], false)),
+                OptVariable::Number(count) => StmtKind::Assign {
+                    targets: (vararg.dest..vararg.dest + count)
+                        .map(|register| Expr::Var(self.def_var(index, register)))
+                        .collect(),
+                    values: vec![Expr::VarArg {
+                        multiple: count != 1,
+                    }],
+                },
+                OptVariable::Variable => {
+                    self.pending = Some(Expr::VarArg { multiple: true });
+
+                    return Ok((vec![], false));
+                }
+            },
+            Instruction::Return(ret) => {
+                let values = self.values(index, ret.result_start, &ret.result_count)?;
+
+                return Ok((vec![StmtKind::Return(values)], true));
+            }
+            Instruction::Closure(closure) => {
+                let prototype = self
+                    .function
+                    .prototypes
+                    .get(closure.prototype)
+                    .ok_or_else(|| anyhow!("prototype {} does not exist", closure.prototype))?;
+
+                let captures = prototype
+                    .upvalues
+                    .iter()
+                    .map(
+                        |upvalue| match (upvalue.in_stack, upvalue.index == closure.dest) {
+                            (true, true) => Capture::Var(self.def_var(index, closure.dest)),
+                            (true, false) => Capture::Var(self.use_var(index, upvalue.index)),
+                            (false, _) => Capture::Upvalue(upvalue.index),
+                        },
+                    )
+                    .collect();
+
+                let value = super::function(prototype, captures, self.options)?;
+                let dest = Expr::Var(self.def_var(index, closure.dest));
+
+                assign(dest, Expr::Function(Box::new(value)))
+            }
+            Instruction::GetUpvalue(get) => assign(
+                Expr::Var(self.def_var(index, get.dest)),
+                Expr::Upvalue(get.upvalue),
+            ),
+            Instruction::SetUpvalue(set) => {
+                let value = Expr::Var(self.use_var(index, set.src));
+                assign(Expr::Upvalue(set.upvalue), value)
+            }
+            Instruction::ForGenCall(call) => {
+                *generic_vars = (call.dest..call.dest + call.count)
+                    .map(|register| self.def_var(index, register))
+                    .collect();
+
+                for (position, &var) in generic_vars.iter().enumerate() {
+                    self.infos[var].implicit = true;
+                    self.infos[var].hint = if position == 0 && call.count > 1 {
+                        Hint::Key
+                    } else {
+                        Hint::Value
+                    };
+                }
+
+                return Ok((vec![], false));
+            }
+            Instruction::ForNumPrep(prep) => {
+                let var = self.def_var(index, prep.var);
+                self.infos[var].implicit = true;
+                self.infos[var].hint = Hint::Counter;
+
+                return Ok((vec![], false));
+            }
+            Instruction::Label(_)
+            | Instruction::Jump(_)
+            | Instruction::JumpNot(_)
+            | Instruction::ConditionalJump(_)
+            | Instruction::ForNumLoop(_)
+            | Instruction::ForGenPrep(_)
+            | Instruction::ForGenLoop(_)
+            | Instruction::Close(_) => return Ok((vec![], false)),
+        };
+
+        Ok((vec![statement], false))
+    }
+
+    /// The values from register `start`, `count` of them or up to the top of the stack,
+    /// in which case the last is the pending multiple value.
+    fn values(&mut self, index: usize, start: usize, count: &OptVariable) -> Result<Vec<Expr>> {
+        let end = match count {
+            OptVariable::Number(count) => start + count,
+            OptVariable::Variable => self.open[index].unwrap_or(start),
+        };
+
+        let mut values = (start..end)
+            .map(|register| Expr::Var(self.use_var(index, register)))
+            .collect::<Vec<_>>();
+
+        if *count == OptVariable::Variable {
+            values.push(
+                self.pending
+                    .take()
+                    .ok_or_else(|| anyhow!("instruction {index} takes values nothing produced"))?,
+            );
+        }
+
+        Ok(values)
+    }
+}
@@ -20,12 +20,88 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+//! Bytecode to source decompilation. Each function is lifted from LUNIR intermediate
+//! language into statements over variables, which are simplified into expressions,
+//! structured into loops and conditionals, and emitted as a syntax tree for a
+//! [`Reconstructor`] to print.
+
+mod code;
+mod emit;
+mod lift;
+mod printer;
+mod simplify;
+mod structure;
+mod tests;
+
 use super::OptimizationLevel;
-use crate::ir::{ast::tree::*, il::IlChunk};
-use std::{
-    marker::PhantomData,
-    sync::{Arc, Weak},
-};
+use crate::ir::ast::{tree::*, Dialect};
+use crate::ir::il::Function as IlFunction;
+use crate::ir::span::Span;
+use anyhow::{Context, Result};
+use code::Capture;
+use std::sync::{Arc, Weak};
+
+pub use printer::LuaReconstructor;
+
+/// Reconstructs source code in a particular language from the syntax tree produced by
+/// decompilation.
+pub trait Reconstructor {
+    /// Produces the source code of `tree`.
+    fn reconstruct(&self, tree: &Node) -> Result<String>;
+
+    /// The dialect of Lua that the syntax tree is written in, which decides what syntax
+    /// decompilation may use.
+    fn dialect(&self) -> Dialect {
+        Dialect::default()
+    }
+}
+
+/// What decompilation may assume and do, as decided by the dialect and the optimization
+/// level.
+#[derive(Clone, Copy, Debug)]
+struct Options {
+    dialect: Dialect,
+    /// Whether temporaries are inlined into expressions wherever that keeps the order of
+    /// evaluation, rather than only where they are used right away.
+    inline: bool,
+    /// Whether operators are assumed to invoke no metamethods, so that they can be moved
+    /// past one another.
+    pure_operators: bool,
+}
+
+impl Options {
+    fn new(dialect: Dialect, level: &OptimizationLevel) -> Self {
+        Self {
+            dialect,
+            inline: !matches!(level, OptimizationLevel::None),
+            pure_operators: matches!(level, OptimizationLevel::All),
+        }
+    }
+}
+
+/// Decompiles `function`, whose upvalues are captured from the enclosing function as
+/// `captures`.
+fn function(
+    function: &IlFunction,
+    captures: Vec<Capture>,
+    options: Options,
+) -> Result<code::Function> {
+    let mut lifted = lift::lift(function, options)?;
+    simplify::simplify(&mut lifted, options);
+    let body = structure::structure(&mut lifted, options.dialect)?;
+
+    let line = function.debug.line_defined;
+
+    Ok(code::Function {
+        params: lifted.params,
+        is_vararg: function.arity.is_vararg,
+        body,
+        vars: lifted.vars,
+        captures,
+        upvalue_names: function.debug.upvalue_names.clone(),
+        span: (line != 0).then(|| Span::line(line)),
+    })
+}
 
 #[doc(hidden)]
 #[derive(Clone, Debug)]
@@ -33,23 +109,20 @@ pub struct NoReconstructor;
 
 #[doc(hidden)]
 #[derive(Clone, Debug)]
-pub struct WithReconstructor<'a, V: Visitor<'a>> {
-    pub visitor: V,
-    _marker: PhantomData<&'a V>,
-}
+pub struct WithReconstructor<R: Reconstructor>(R);
 
 #[doc(hidden)]
 #[derive(Clone, Debug)]
-pub struct NoChunk;
+pub struct NoFunction;
 
 #[doc(hidden)]
 #[derive(Clone, Debug)]
-pub struct WithChunk(IlChunk);
+pub struct WithFunction<'f>(&'f IlFunction);
 
 /// The interface of LUNIR's decompilation pipeline. `DecompilationJob` allows you to pass in parameters to the LUNIR decompilation pipeline and invoke it, even across threads.
 #[derive(Clone, Debug)]
 pub struct DecompilationJob<C, F> {
-    chunk: C,
+    function: C,
     optimization_level: OptimizationLevel,
     _reference: Weak<()>,
     reconstructor: F,
@@ -64,29 +137,26 @@ impl<C, F> DecompilationJob<C, F> {
     }
 }
 
-impl<'a, C> DecompilationJob<C, NoReconstructor> {
-    /// Adds a target format source reconstruction visitor to this `DecompilationJob` to allow it to produce a final source string.
-    pub fn reconstructor<V: Visitor<'a>>(
+impl<C> DecompilationJob<C, NoReconstructor> {
+    /// Adds a target language source reconstructor to this `DecompilationJob` to allow it to produce a final source string.
+    pub fn reconstructor<R: Reconstructor>(
         self,
-        visitor: V,
-    ) -> DecompilationJob<C, WithReconstructor<'a, V>> {
+        reconstructor: R,
+    ) -> DecompilationJob<C, WithReconstructor<R>> {
         DecompilationJob {
-            chunk: self.chunk,
+            function: self.function,
             optimization_level: self.optimization_level,
             _reference: self._reference,
-            reconstructor: WithReconstructor {
-                visitor,
-                _marker: PhantomData,
-            },
+            reconstructor: WithReconstructor(reconstructor),
         }
     }
 }
 
-impl<'a, F> DecompilationJob<NoChunk, F> {
-    /// Adds a source LUNIR intermediate language chunk to this `DecompilationJob`.
-    pub fn chunk(self, chunk: IlChunk) -> DecompilationJob<WithChunk, F> {
+impl<F> DecompilationJob<NoFunction, F> {
+    /// Adds the main function of a chunk of LUNIR intermediate language to this `DecompilationJob`.
+    pub fn function(self, function: &IlFunction) -> DecompilationJob<WithFunction<'_>, F> {
         DecompilationJob {
-            chunk: WithChunk(chunk),
+            function: WithFunction(function),
             optimization_level: self.optimization_level,
             _reference: self._reference,
             reconstructor: self.reconstructor,
@@ -94,11 +164,22 @@ impl<'a, F> DecompilationJob<NoChunk, F> {
     }
 }
 
-impl<'a, V: Visitor<'a>> DecompilationJob<WithChunk, WithReconstructor<'a, V>> {
+impl<'f, R: Reconstructor> DecompilationJob<WithFunction<'f>, WithReconstructor<R>> {
     /// Invokes LUNIR's decompilation pipeline with the parameters passed through the this `DecompilationJob`. This will consume the job.
     #[must_use = "The result of decompilation should be used."]
-    pub fn run(self) -> String {
-        todo!()
+    pub fn run(self) -> Result<String> {
+        let main = self.function.0;
+        let reconstructor = self.reconstructor.0;
+
+        main.verify()
+            .context("the function to decompile is malformed")?;
+
+        let options = Options::new(reconstructor.dialect(), &self.optimization_level);
+        let captures = (0..main.upvalues.len()).map(Capture::Upvalue).collect();
+        let function = function(main, captures, options)?;
+        let tree = emit::emit(&function, options.dialect)?;
+
+        reconstructor.reconstruct(&tree)
     }
 }
 
@@ -107,6 +188,12 @@ pub struct Decompiler {
     handle: Arc<()>,
 }
 
+impl Default for Decompiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Decompiler {
     /// Creates a new `Decompiler`.
     pub fn new() -> Self {
@@ -118,9 +205,9 @@ impl Decompiler {
 
 impl Decompiler {
     /// Constructs a `DecompilationJob`.
-    pub fn create_job(&self) -> DecompilationJob<NoChunk, NoReconstructor> {
+    pub fn create_job(&self) -> DecompilationJob<NoFunction, NoReconstructor> {
         DecompilationJob {
-            chunk: NoChunk,
+            function: NoFunction,
             _reference: Arc::downgrade(&self.handle),
             optimization_level: OptimizationLevel::default(),
             reconstructor: NoReconstructor,
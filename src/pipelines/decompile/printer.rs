@@ -0,0 +1,737 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Printing syntax trees as Lua source code. Expressions are parenthesized only where the
+//! precedence of their operators requires it, so the printed source parses back into the
+//! same tree, up to the parentheses that carry no meaning.
+
+use super::Reconstructor;
+use crate::ir::ast::tree::*;
+use crate::ir::ast::Dialect;
+use anyhow::{bail, Result};
+use std::fmt::Write;
+
+/// The precedence of expressions that are never split by an operator around them.
+const ATOM: u8 = u8::MAX;
+
+/// The precedence of unary operators, which bind tighter than every binary operator but
+/// `^`.
+const UNARY: u8 = 12;
+
+/// The precedence of `if` expressions and type assertions, which are parenthesized
+/// wherever they are the operand of an operator.
+const LOOSE: u8 = 0;
+
+/// Reconstructs Lua source code of a particular dialect, indented by four spaces.
+#[derive(Clone, Debug, Default)]
+pub struct LuaReconstructor {
+    dialect: Dialect,
+}
+
+impl LuaReconstructor {
+    /// Creates a `LuaReconstructor` that writes source code of `dialect`.
+    pub fn new(dialect: Dialect) -> Self {
+        Self { dialect }
+    }
+}
+
+impl Reconstructor for LuaReconstructor {
+    fn reconstruct(&self, tree: &Node) -> Result<String> {
+        block(&tree.block, 0)
+    }
+
+    fn dialect(&self) -> Dialect {
+        self.dialect
+    }
+}
+
+/// The precedence of `operator`, and whether it is right associative.
+fn precedence(operator: BinaryOperator) -> (u8, bool) {
+    match operator {
+        BinaryOperator::Or => (1, false),
+        BinaryOperator::And => (2, false),
+        BinaryOperator::Eq
+        | BinaryOperator::Ne
+        | BinaryOperator::Lt
+        | BinaryOperator::Le
+        | BinaryOperator::Gt
+        | BinaryOperator::Ge => (3, false),
+        BinaryOperator::BitOr => (4, false),
+        BinaryOperator::BitXor => (5, false),
+        BinaryOperator::BitAnd => (6, false),
+        BinaryOperator::LeftShift | BinaryOperator::RightShift => (7, false),
+        BinaryOperator::Concat => (9, true),
+        BinaryOperator::Add | BinaryOperator::Sub => (10, false),
+        BinaryOperator::Mul | BinaryOperator::Div | BinaryOperator::IDiv | BinaryOperator::Mod => {
+            (11, false)
+        }
+        BinaryOperator::Pow => (14, true),
+    }
+}
+
+fn binary_operator(operator: BinaryOperator) -> &'static str {
+    match operator {
+        BinaryOperator::Add => "+",
+        BinaryOperator::Sub => "-",
+        BinaryOperator::Mul => "*",
+        BinaryOperator::Div => "/",
+        BinaryOperator::IDiv => "//",
+        BinaryOperator::Mod => "%",
+        BinaryOperator::Pow => "^",
+        BinaryOperator::Concat => "..",
+        BinaryOperator::Eq => "==",
+        BinaryOperator::Ne => "~=",
+        BinaryOperator::Lt => "<",
+        BinaryOperator::Le => "<=",
+        BinaryOperator::Gt => ">",
+        BinaryOperator::Ge => ">=",
+        BinaryOperator::And => "and",
+        BinaryOperator::Or => "or",
+        BinaryOperator::BitAnd => "&",
+        BinaryOperator::BitOr => "|",
+        BinaryOperator::BitXor => "~",
+        BinaryOperator::LeftShift => "<<",
+        BinaryOperator::RightShift => ">>",
+    }
+}
+
+fn unary_operator(operator: UnaryOperator) -> &'static str {
+    match operator {
+        UnaryOperator::Neg => "-",
+        UnaryOperator::Not => "not ",
+        UnaryOperator::Len => "#",
+        UnaryOperator::BitNot => "~",
+    }
+}
+
+fn indentation(indent: usize) -> String {
+    "    ".repeat(indent)
+}
+
+fn join(items: impl IntoIterator<Item = Result<String>>) -> Result<String> {
+    Ok(items.into_iter().collect::<Result<Vec<_>>>()?.join(", "))
+}
+
+/// Quotes `string` with `quote`, escaping control characters and, unless `string` is
+/// valid UTF-8, every byte that is not ASCII as decimal escapes.
+fn quote(string: &[u8], quote: char) -> String {
+    let mut quoted = String::new();
+    quoted.push(quote);
+
+    let mut push = |c: char, bytes: &[u8]| match c {
+        '\\' => quoted.push_str("\\\\"),
+        '\n' => quoted.push_str("\\n"),
+        '\r' => quoted.push_str("\\r"),
+        '\t' => quoted.push_str("\\t"),
+        c if c == quote || (quote == '`' && c == '{') => {
+            quoted.push('\\');
+            quoted.push(c);
+        }
+        c if c.is_ascii_control() || c == '\u{fffd}' => {
+            for byte in bytes {
+                let _ = write!(quoted, "\\{byte:03}");
+            }
+        }
+        c => quoted.push(c),
+    };
+
+    match std::str::from_utf8(string) {
+        Ok(text) => {
+            let mut buffer = [0; 4];
+            for c in text.chars() {
+                push(c, c.encode_utf8(&mut buffer).as_bytes());
+            }
+        }
+        Err(_) => {
+            for byte in string {
+                let c = if byte.is_ascii() {
+                    *byte as char
+                } else {
+                    '\u{fffd}'
+                };
+                push(c, std::slice::from_ref(byte));
+            }
+        }
+    }
+
+    quoted.push(quote);
+    quoted
+}
+
+fn number(number: f64) -> String {
+    if number.is_nan() {
+        "(0/0)".to_owned()
+    } else if number.is_infinite() {
+        // the literal overflows into infinity
+        if number > 0.0 { "1e999" } else { "-1e999" }.to_owned()
+    } else {
+        // always written with a point or an exponent, so it is read back as a float
+        format!("{number:?}")
+    }
+}
+
+/// Prints the statements of `block`, each on its own lines indented by `indent` levels.
+fn block(block: &Block, indent: usize) -> Result<String> {
+    let mut printed = String::new();
+
+    for (index, statement) in block.statements.iter().enumerate() {
+        let text = self::statement(statement, indent)?;
+
+        printed.push_str(&indentation(indent));
+        // the parenthesis would otherwise continue the last statement as a call
+        if index > 0 && text.starts_with('(') {
+            printed.push(';');
+        }
+        printed.push_str(&text);
+        printed.push('\n');
+    }
+
+    Ok(printed)
+}
+
+/// Prints a block that ends with `end` or an equivalent keyword at `indent` levels.
+fn nested(block: &Block, indent: usize) -> Result<String> {
+    Ok(format!(
+        "\n{}{}",
+        self::block(block, indent + 1)?,
+        indentation(indent)
+    ))
+}
+
+fn statement(statement: &Statement, indent: usize) -> Result<String> {
+    Ok(match &statement.kind {
+        StatementKind::Local { names, values } => {
+            let names = join(names.iter().map(|local| {
+                let mut name = local.name.name.clone();
+                if let Some(attribute) = &local.attribute {
+                    let _ = write!(name, " <{}>", attribute.name);
+                }
+                if let Some(annotation) = &local.annotation {
+                    let _ = write!(name, ": {}", ty(annotation, indent)?);
+                }
+
+                Ok(name)
+            }))?;
+
+            if values.is_empty() {
+                format!("local {names}")
+            } else {
+                format!("local {names} = {}", list(values, indent)?)
+            }
+        }
+        StatementKind::Assign { targets, values } => {
+            format!("{} = {}", list(targets, indent)?, list(values, indent)?)
+        }
+        StatementKind::CompoundAssign {
+            operator,
+            target,
+            value,
+        } => format!(
+            "{} {}= {}",
+            expression(target, indent)?,
+            binary_operator(*operator),
+            expression(value, indent)?
+        ),
+        StatementKind::Call(call) => expression(call, indent)?,
+        StatementKind::Do(block) => format!("do{}end", nested(block, indent)?),
+        StatementKind::While { condition, block } => format!(
+            "while {} do{}end",
+            expression(condition, indent)?,
+            nested(block, indent)?
+        ),
+        StatementKind::Repeat { block, condition } => format!(
+            "repeat{}until {}",
+            nested(block, indent)?,
+            expression(condition, indent)?
+        ),
+        StatementKind::If {
+            branches,
+            otherwise,
+        } => {
+            let mut printed = String::new();
+
+            for (index, branch) in branches.iter().enumerate() {
+                let keyword = if index == 0 { "if" } else { "elseif" };
+                let _ = write!(
+                    printed,
+                    "{keyword} {} then{}",
+                    expression(&branch.condition, indent)?,
+                    nested(&branch.block, indent)?
+                );
+            }
+
+            if let Some(otherwise) = otherwise {
+                let _ = write!(printed, "else{}", nested(otherwise, indent)?);
+            }
+
+            printed.push_str("end");
+            printed
+        }
+        StatementKind::NumericFor {
+            variable,
+            start,
+            limit,
+            step,
+            block,
+        } => {
+            let mut range = format!(
+                "{} = {}, {}",
+                typed_name(variable, indent)?,
+                expression(start, indent)?,
+                expression(limit, indent)?
+            );
+            if let Some(step) = step {
+                let _ = write!(range, ", {}", expression(step, indent)?);
+            }
+
+            format!("for {range} do{}end", nested(block, indent)?)
+        }
+        StatementKind::GenericFor {
+            names,
+            values,
+            block,
+        } => format!(
+            "for {} in {} do{}end",
+            join(names.iter().map(|name| typed_name(name, indent)))?,
+            list(values, indent)?,
+            nested(block, indent)?
+        ),
+        StatementKind::Function { name, function } => {
+            let mut path = name
+                .path
+                .iter()
+                .map(|name| name.name.as_str())
+                .collect::<Vec<_>>()
+                .join(".");
+            if let Some(method) = &name.method {
+                let _ = write!(path, ":{}", method.name);
+            }
+
+            format!(
+                "{}function {path}{}",
+                attributes(function),
+                body(function, indent)?
+            )
+        }
+        StatementKind::LocalFunction { name, function } => format!(
+            "{}local function {}{}",
+            attributes(function),
+            name.name,
+            body(function, indent)?
+        ),
+        StatementKind::Return(values) if values.is_empty() => "return".to_owned(),
+        StatementKind::Return(values) => format!("return {}", list(values, indent)?),
+        StatementKind::Break => "break".to_owned(),
+        StatementKind::Continue => "continue".to_owned(),
+        StatementKind::Goto(label) => format!("goto {}", label.name),
+        StatementKind::Label(label) => format!("::{}::", label.name),
+        StatementKind::TypeAlias {
+            is_exported,
+            name,
+            generics,
+            value,
+        } => format!(
+            "{}type {}{} = {}",
+            if *is_exported { "export " } else { "" },
+            name.name,
+            generic_params(generics, indent)?,
+            ty(value, indent)?
+        ),
+        StatementKind::Error => bail!("the syntax tree holds a statement that failed to parse"),
+    })
+}
+
+fn attributes(function: &FunctionBody) -> String {
+    function
+        .attributes
+        .iter()
+        .map(|attribute| format!("@{} ", attribute.name))
+        .collect()
+}
+
+fn typed_name(name: &TypedName, indent: usize) -> Result<String> {
+    Ok(match &name.annotation {
+        Some(annotation) => format!("{}: {}", name.name.name, ty(annotation, indent)?),
+        None => name.name.name.clone(),
+    })
+}
+
+/// Prints the parameters and block of a function, from its generic parameters to `end`.
+fn body(function: &FunctionBody, indent: usize) -> Result<String> {
+    let mut params = function
+        .params
+        .iter()
+        .map(|param| typed_name(param, indent))
+        .collect::<Result<Vec<_>>>()?;
+
+    if function.is_vararg {
+        params.push(match &function.vararg_annotation {
+            Some(PackTail::Variadic(annotation)) => format!("...: {}", ty(annotation, indent)?),
+            Some(PackTail::Generic(name)) => format!("...: {}...", name.name),
+            None => "...".to_owned(),
+        });
+    }
+
+    let mut printed = format!(
+        "{}({})",
+        generic_params(&function.generics, indent)?,
+        params.join(", ")
+    );
+    if let Some(returns) = &function.returns {
+        let _ = write!(printed, ": {}", pack(returns, indent)?);
+    }
+
+    if function.block.statements.is_empty() {
+        printed.push_str(" end");
+    } else {
+        let _ = write!(printed, "{}end", nested(&function.block, indent)?);
+    }
+
+    Ok(printed)
+}
+
+/// Prints a list of expressions separated by commas.
+fn list(expressions: &[Expression], indent: usize) -> Result<String> {
+    join(expressions.iter().map(|e| expression(e, indent)))
+}
+
+fn expression(expression: &Expression, indent: usize) -> Result<String> {
+    Ok(operand(expression, indent)?.0)
+}
+
+/// Prints an expression that is called, indexed or has a method looked up, which must be
+/// a prefix expression.
+fn prefix(expression: &Expression, indent: usize) -> Result<String> {
+    let printed = self::expression(expression, indent)?;
+
+    Ok(match expression.kind {
+        ExpressionKind::Name(_)
+        | ExpressionKind::Index { .. }
+        | ExpressionKind::Field { .. }
+        | ExpressionKind::Call { .. }
+        | ExpressionKind::MethodCall { .. }
+        | ExpressionKind::Paren(_) => printed,
+        _ => format!("({printed})"),
+    })
+}
+
+/// Prints `expression` with the precedence of its outermost operator.
+fn operand(expression: &Expression, indent: usize) -> Result<(String, u8)> {
+    let printed = match &expression.kind {
+        ExpressionKind::Nil => "nil".to_owned(),
+        ExpressionKind::Boolean(b) => b.to_string(),
+        ExpressionKind::Number(n) => number(*n),
+        ExpressionKind::Integer(i) => i.to_string(),
+        ExpressionKind::String(string) => quote(string, '"'),
+        ExpressionKind::VarArg => "...".to_owned(),
+        ExpressionKind::Function(function) => {
+            format!(
+                "{}function{}",
+                attributes(function),
+                body(function, indent)?
+            )
+        }
+        ExpressionKind::Table(fields) if fields.is_empty() => "{}".to_owned(),
+        ExpressionKind::Table(fields) => format!(
+            "{{{}}}",
+            join(fields.iter().map(|field| {
+                Ok(match field {
+                    Field::Named { name, value } => {
+                        format!("{} = {}", name.name, self::expression(value, indent)?)
+                    }
+                    Field::Keyed { key, value } => format!(
+                        "[{}] = {}",
+                        self::expression(key, indent)?,
+                        self::expression(value, indent)?
+                    ),
+                    Field::Positional(value) => self::expression(value, indent)?,
+                })
+            }))?
+        ),
+        ExpressionKind::Name(name) => name.name.clone(),
+        ExpressionKind::Index { object, key } => format!(
+            "{}[{}]",
+            prefix(object, indent)?,
+            self::expression(key, indent)?
+        ),
+        ExpressionKind::Field { object, name } => {
+            format!("{}.{}", prefix(object, indent)?, name.name)
+        }
+        ExpressionKind::Call { callee, args } => {
+            format!("{}({})", prefix(callee, indent)?, list(args, indent)?)
+        }
+        ExpressionKind::MethodCall {
+            object,
+            method,
+            args,
+        } => format!(
+            "{}:{}({})",
+            prefix(object, indent)?,
+            method.name,
+            list(args, indent)?
+        ),
+        ExpressionKind::Paren(inner) => format!("({})", self::expression(inner, indent)?),
+        ExpressionKind::IfElse {
+            branches,
+            otherwise,
+        } => {
+            let mut printed = String::new();
+
+            for (index, branch) in branches.iter().enumerate() {
+                let keyword = if index == 0 { "if" } else { " elseif" };
+                let _ = write!(
+                    printed,
+                    "{keyword} {} then {}",
+                    self::expression(&branch.condition, indent)?,
+                    self::expression(&branch.value, indent)?
+                );
+            }
+            let _ = write!(printed, " else {}", self::expression(otherwise, indent)?);
+
+            return Ok((printed, LOOSE));
+        }
+        ExpressionKind::InterpolatedString {
+            strings,
+            expressions,
+        } => {
+            let mut printed = String::from("`");
+
+            for (index, string) in strings.iter().enumerate() {
+                let quoted = quote(string, '`');
+                printed.push_str(&quoted[1..quoted.len() - 1]);
+
+                if let Some(expression) = expressions.get(index) {
+                    // a table constructor would read as the end of the braces
+                    let _ = write!(printed, "{{ {} }}", self::expression(expression, indent)?);
+                }
+            }
+
+            printed.push('`');
+            printed
+        }
+        ExpressionKind::TypeAssertion {
+            expression,
+            annotation,
+        } => {
+            let (inner, precedence) = operand(expression, indent)?;
+            let inner = if precedence == ATOM {
+                inner
+            } else {
+                format!("({inner})")
+            };
+
+            return Ok((format!("{inner} :: {}", ty(annotation, indent)?), LOOSE));
+        }
+        ExpressionKind::Binary {
+            operator,
+            left,
+            right,
+        } => {
+            let (precedence, right_associative) = precedence(*operator);
+            let (left, left_precedence) = operand(left, indent)?;
+            let (right, right_precedence) = operand(right, indent)?;
+
+            let left = if left_precedence < precedence
+                || (left_precedence == precedence && right_associative)
+            {
+                format!("({left})")
+            } else {
+                left
+            };
+            let right = if right_precedence < precedence
+                || (right_precedence == precedence && !right_associative)
+            {
+                format!("({right})")
+            } else {
+                right
+            };
+
+            return Ok((
+                format!("{left} {} {right}", binary_operator(*operator)),
+                precedence,
+            ));
+        }
+        ExpressionKind::Unary { operator, operand } => {
+            let (printed, precedence) = self::operand(operand, indent)?;
+            let printed = if precedence < UNARY {
+                format!("({printed})")
+            } else {
+                printed
+            };
+
+            let operator = unary_operator(*operator);
+            // `--` would start a comment
+            let separator = if operator == "-" && printed.starts_with('-') {
+                " "
+            } else {
+                ""
+            };
+
+            return Ok((format!("{operator}{separator}{printed}"), UNARY));
+        }
+        ExpressionKind::Error => bail!("the syntax tree holds an expression that failed to parse"),
+    };
+
+    Ok((printed, ATOM))
+}
+
+fn generic_params(generics: &[GenericParam], indent: usize) -> Result<String> {
+    if generics.is_empty() {
+        return Ok(String::new());
+    }
+
+    let generics = join(generics.iter().map(|generic| {
+        let mut printed = generic.name.name.clone();
+        if generic.is_pack {
+            printed.push_str("...");
+        }
+        if let Some(default) = &generic.default {
+            let _ = write!(printed, " = {}", type_argument(default, indent)?);
+        }
+
+        Ok(printed)
+    }))?;
+
+    Ok(format!("<{generics}>"))
+}
+
+fn type_argument(argument: &TypeArgument, indent: usize) -> Result<String> {
+    match argument {
+        TypeArgument::Type(annotation) => ty(annotation, indent),
+        TypeArgument::Pack(TypePack {
+            types,
+            tail: Some(tail),
+        }) if types.is_empty() => pack_tail(tail, indent),
+        TypeArgument::Pack(pack) => parenthesized_pack(pack, indent),
+    }
+}
+
+fn pack_tail(tail: &PackTail, indent: usize) -> Result<String> {
+    Ok(match tail {
+        PackTail::Variadic(annotation) => format!("...{}", ty(annotation, indent)?),
+        PackTail::Generic(name) => format!("{}...", name.name),
+    })
+}
+
+fn parenthesized_pack(pack: &TypePack, indent: usize) -> Result<String> {
+    let mut types = pack
+        .types
+        .iter()
+        .map(|annotation| ty(annotation, indent))
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(tail) = &pack.tail {
+        types.push(pack_tail(tail, indent)?);
+    }
+
+    Ok(format!("({})", types.join(", ")))
+}
+
+/// Prints a pack of return types, which needs no parentheses when it is a single type.
+fn pack(pack: &TypePack, indent: usize) -> Result<String> {
+    match (&pack.types[..], &pack.tail) {
+        ([annotation], None) if !matches!(annotation.kind, TypeKind::Function { .. }) => {
+            ty(annotation, indent)
+        }
+        _ => parenthesized_pack(pack, indent),
+    }
+}
+
+fn ty(annotation: &Type, indent: usize) -> Result<String> {
+    Ok(match &annotation.kind {
+        TypeKind::Reference { module, name, args } => {
+            let mut printed = match module {
+                Some(module) => format!("{}.{}", module.name, name.name),
+                None => name.name.clone(),
+            };
+            if !args.is_empty() {
+                let _ = write!(
+                    printed,
+                    "<{}>",
+                    join(args.iter().map(|arg| type_argument(arg, indent)))?
+                );
+            }
+
+            printed
+        }
+        TypeKind::Typeof(expression) => {
+            format!("typeof({})", self::expression(expression, indent)?)
+        }
+        TypeKind::Nil => "nil".to_owned(),
+        TypeKind::Boolean(b) => b.to_string(),
+        TypeKind::String(string) => quote(string, '"'),
+        TypeKind::Table(fields) if fields.is_empty() => "{}".to_owned(),
+        TypeKind::Table(fields) => format!(
+            "{{ {} }}",
+            join(fields.iter().map(|field| {
+                Ok(match field {
+                    TableTypeField::Property { name, value } => {
+                        format!("{}: {}", name.name, ty(value, indent)?)
+                    }
+                    TableTypeField::Indexer { key, value } => {
+                        format!("[{}]: {}", ty(key, indent)?, ty(value, indent)?)
+                    }
+                })
+            }))?
+        ),
+        TypeKind::Array(element) => format!("{{ {} }}", ty(element, indent)?),
+        TypeKind::Function {
+            generics,
+            params,
+            vararg,
+            returns,
+        } => {
+            let mut printed = params
+                .iter()
+                .map(|param| {
+                    let annotation = ty(&param.annotation, indent)?;
+
+                    Ok(match &param.name {
+                        Some(name) => format!("{}: {annotation}", name.name),
+                        None => annotation,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            if let Some(vararg) = vararg {
+                printed.push(pack_tail(vararg, indent)?);
+            }
+
+            format!(
+                "{}({}) -> {}",
+                generic_params(generics, indent)?,
+                printed.join(", "),
+                pack(returns, indent)?
+            )
+        }
+        TypeKind::Union(types) => types
+            .iter()
+            .map(|annotation| ty(annotation, indent))
+            .collect::<Result<Vec<_>>>()?
+            .join(" | "),
+        TypeKind::Intersection(types) => types
+            .iter()
+            .map(|annotation| ty(annotation, indent))
+            .collect::<Result<Vec<_>>>()?
+            .join(" & "),
+        TypeKind::Optional(inner) => format!("{}?", ty(inner, indent)?),
+        TypeKind::Paren(inner) => format!("({})", ty(inner, indent)?),
+    })
+}
@@ -0,0 +1,945 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Simplifying lifted nodes until they read like source code. Temporaries are inlined
+//! into the expressions that use them, method calls and table constructors are rebuilt,
+//! and the nodes that short-circuiting operators compile into are collapsed back into
+//! expressions.
+
+use super::code::*;
+use super::lift::{Exit, Lifted};
+use super::Options;
+use crate::ir::ast::tree::{BinaryOperator, UnaryOperator};
+use std::collections::HashSet;
+
+/// What evaluating an expression may do, besides reading variables that cannot change.
+#[derive(Clone, Copy, Default)]
+struct Effects {
+    /// May run code of the program, through a call or a metamethod.
+    runs_code: bool,
+    /// Reads state that running code may change, such as tables, globals and upvalues.
+    reads_memory: bool,
+}
+
+impl Effects {
+    fn add(&mut self, other: Self) {
+        self.runs_code |= other.runs_code;
+        self.reads_memory |= other.reads_memory;
+    }
+
+    /// Whether `self` can be evaluated after `prefix` rather than before it.
+    fn commutes_with(self, prefix: Self) -> bool {
+        !(self.runs_code && (prefix.runs_code || prefix.reads_memory)
+            || self.reads_memory && prefix.runs_code)
+    }
+}
+
+struct Simplifier<'l> {
+    lifted: &'l mut Lifted,
+    options: Options,
+    /// The variables captured by closures, which running code may change.
+    captured: HashSet<Var>,
+    defs: Vec<usize>,
+    uses: Vec<usize>,
+    preds: Vec<Vec<usize>>,
+}
+
+/// Where the value of an inlined definition goes.
+#[derive(Clone, Copy)]
+enum Consumer {
+    Stmt(usize),
+    Exit,
+}
+
+/// The expressions a statement evaluates before it has any effect, in order. The targets
+/// of an assignment are not read, only the tables and keys they index.
+fn roots(kind: &StmtKind) -> Vec<&Expr> {
+    match kind {
+        StmtKind::Assign { targets, values } => targets
+            .iter()
+            .flat_map(|target| match target {
+                Expr::Index(object, key) => vec![&**object, &**key],
+                _ => vec![],
+            })
+            .chain(values)
+            .collect(),
+        _ => kind.exprs(),
+    }
+}
+
+fn roots_mut(kind: &mut StmtKind) -> Vec<&mut Expr> {
+    match kind {
+        StmtKind::Assign { targets, values } => targets
+            .iter_mut()
+            .flat_map(|target| match target {
+                Expr::Index(object, key) => vec![&mut **object, &mut **key],
+                _ => vec![],
+            })
+            .chain(values)
+            .collect(),
+        _ => kind.exprs_mut(),
+    }
+}
+
+/// Replaces the occurrence of `var` in `exprs` by `value`.
+fn replace(exprs: Vec<&mut Expr>, var: Var, value: Expr) {
+    let mut value = Some(value);
+
+    for expr in exprs {
+        expr.walk_mut(&mut |expr| {
+            if *expr == Expr::Var(var) {
+                if let Some(value) = value.take() {
+                    *expr = value;
+                }
+            }
+        });
+    }
+}
+
+/// Whether `var` occurs in `expr`, including as a capture of its functions.
+fn references(expr: &Expr, var: Var) -> bool {
+    let mut vars = vec![];
+    expr.vars(&mut vars);
+
+    vars.contains(&var)
+}
+
+impl<'l> Simplifier<'l> {
+    fn effects(&self, expr: &Expr) -> Effects {
+        let mut effects = Effects::default();
+        let overloadable = !self.options.pure_operators;
+
+        expr.walk(&mut |expr| match expr {
+            Expr::Call { .. } | Expr::MethodCall { .. } => effects.runs_code = true,
+            Expr::Global(_) | Expr::Index(..) => {
+                effects.reads_memory = true;
+                effects.runs_code |= overloadable;
+            }
+            Expr::Upvalue(_) => effects.reads_memory = true,
+            Expr::Var(var) if self.captured.contains(var) => effects.reads_memory = true,
+            Expr::Binary(operator, ..)
+                if !matches!(operator, BinaryOperator::And | BinaryOperator::Or) =>
+            {
+                effects.runs_code |= overloadable
+            }
+            Expr::Unary(operator, _) if *operator != UnaryOperator::Not => {
+                effects.runs_code |= overloadable
+            }
+            _ => {}
+        });
+
+        effects
+    }
+
+    /// Finds the occurrence of `var` in `expr`, adding the effects of what is evaluated
+    /// before it to `prefix`. Returns whether the occurrence is only evaluated under a
+    /// condition, as the right operand of `and` and `or` is.
+    fn search(&self, expr: &Expr, var: Var, prefix: &mut Effects, guarded: bool) -> Option<bool> {
+        if *expr == Expr::Var(var) {
+            return Some(guarded);
+        }
+
+        let children: Vec<(&Expr, bool)> = match expr {
+            Expr::Index(object, key) => vec![(object, guarded), (key, guarded)],
+            Expr::Call { callee, args, .. } => std::iter::once(&**callee)
+                .chain(args)
+                .map(|child| (child, guarded))
+                .collect(),
+            Expr::MethodCall { object, args, .. } => {
+                if let Some(found) = self.search(object, var, prefix, guarded) {
+                    return Some(found);
+                }
+                prefix.add(self.effects(object));
+
+                // looking the method up may run `__index`
+                prefix.reads_memory = true;
+                prefix.runs_code |= !self.options.pure_operators;
+
+                args.iter().map(|arg| (arg, guarded)).collect()
+            }
+            Expr::Binary(operator, left, right) => {
+                let conditional = matches!(operator, BinaryOperator::And | BinaryOperator::Or);
+                vec![(left, guarded), (right, guarded || conditional)]
+            }
+            Expr::Unary(_, operand) => vec![(operand, guarded)],
+            Expr::Table(fields) => fields
+                .iter()
+                .flat_map(|field| match field {
+                    Field::Keyed(key, value) => vec![(key, guarded), (value, guarded)],
+                    Field::Positional(value) => vec![(value, guarded)],
+                })
+                .collect(),
+            _ => vec![],
+        };
+
+        for (child, guarded) in children {
+            if let Some(found) = self.search(child, var, prefix, guarded) {
+                return Some(found);
+            }
+            prefix.add(self.effects(child));
+        }
+
+        None
+    }
+
+    /// Whether `value` can be moved from right before the consumer to where `var` occurs
+    /// in it.
+    fn can_move(&self, exprs: &[&Expr], var: Var, value: Effects) -> bool {
+        let mut prefix = Effects::default();
+
+        for expr in exprs {
+            match self.search(expr, var, &mut prefix, false) {
+                Some(guarded) => {
+                    return value.commutes_with(prefix) && !(guarded && value.runs_code)
+                }
+                None => prefix.add(self.effects(expr)),
+            }
+        }
+
+        false
+    }
+
+    fn count(&mut self) {
+        let mut defs = vec![0; self.lifted.vars.len()];
+        let mut uses = vec![0; self.lifted.vars.len()];
+
+        for node in self.lifted.nodes.iter().filter(|node| node.alive) {
+            for statement in &node.statements {
+                statement
+                    .kind
+                    .defs()
+                    .into_iter()
+                    .for_each(|var| defs[var] += 1);
+                statement
+                    .kind
+                    .uses()
+                    .into_iter()
+                    .for_each(|var| uses[var] += 1);
+            }
+
+            node.exit.defs().into_iter().for_each(|var| defs[var] += 1);
+
+            let mut vars = vec![];
+            node.exit
+                .exprs()
+                .iter()
+                .for_each(|expr| expr.vars(&mut vars));
+            vars.into_iter().for_each(|var| uses[var] += 1);
+        }
+
+        self.defs = defs;
+        self.uses = uses;
+
+        let mut preds = vec![vec![]; self.lifted.nodes.len()];
+        for (index, node) in self.lifted.nodes.iter().enumerate() {
+            if node.alive {
+                for successor in node.exit.successors() {
+                    preds[successor].push(index);
+                }
+            }
+        }
+        self.preds = preds;
+    }
+
+    /// Whether `var` only holds a value on its way from one instruction to another.
+    fn is_temporary(&self, var: Var) -> bool {
+        let info = &self.lifted.vars[var];
+
+        self.defs[var] == 1
+            && self.uses[var] == 1
+            && info.name.is_none()
+            && !info.implicit
+            && !self.captured.contains(&var)
+    }
+
+    /// Marks the nodes that cannot be reached from the entry as removed.
+    fn prune(&mut self) {
+        let mut reached = vec![false; self.lifted.nodes.len()];
+        let mut pending = vec![0];
+
+        while let Some(node) = pending.pop() {
+            if !std::mem::replace(&mut reached[node], true) {
+                pending.extend(self.lifted.nodes[node].exit.successors());
+            }
+        }
+
+        for (node, reached) in self.lifted.nodes.iter_mut().zip(reached) {
+            node.alive &= reached;
+        }
+    }
+
+    /// Inlines the temporaries of `node` and rebuilds its method calls, working from the
+    /// end so that every statement is inlined into one that is already complete.
+    fn inline(&mut self, node: usize) -> bool {
+        let mut changed = false;
+        let mut folding = true;
+        let mut index = self.lifted.nodes[node].statements.len();
+
+        while index > 0 {
+            index -= 1;
+
+            let statements = &self.lifted.nodes[node].statements;
+            let consumer = if index + 1 < statements.len() {
+                Consumer::Stmt(index + 1)
+            } else {
+                Consumer::Exit
+            };
+
+            // without inlining, only the temporaries of a condition are folded into it, and
+            // those of the values stored into a table, which may only be expressible in its
+            // constructor
+            if let Consumer::Stmt(consumer) = consumer {
+                folding |= matches!(statements[consumer].kind, StmtKind::SetList { .. });
+            }
+            if !folding && !self.options.inline {
+                continue;
+            }
+
+            let inlined = self.method_call(node, index, consumer)
+                || self.inline_statement(node, index, consumer);
+
+            changed |= inlined;
+            folding = inlined;
+        }
+
+        changed
+    }
+
+    fn consumer_exprs(&self, node: usize, consumer: Consumer) -> Vec<&Expr> {
+        let node = &self.lifted.nodes[node];
+
+        match consumer {
+            Consumer::Stmt(index) => roots(&node.statements[index].kind),
+            Consumer::Exit => node.exit.exprs(),
+        }
+    }
+
+    fn consumer_exprs_mut(&mut self, node: usize, consumer: Consumer) -> Vec<&mut Expr> {
+        let node = &mut self.lifted.nodes[node];
+
+        match consumer {
+            Consumer::Stmt(index) => roots_mut(&mut node.statements[index].kind),
+            Consumer::Exit => node.exit.exprs_mut(),
+        }
+    }
+
+    fn inline_statement(&mut self, node: usize, index: usize, consumer: Consumer) -> bool {
+        let (var, value) = match &self.lifted.nodes[node].statements[index].kind {
+            StmtKind::Assign { targets, values } => match (&targets[..], &values[..]) {
+                ([Expr::Var(var)], [value]) => (*var, value),
+                _ => return false,
+            },
+            _ => return false,
+        };
+
+        if !self.is_temporary(var) || references(value, var) {
+            return false;
+        }
+
+        let effects = self.effects(value);
+        if !self.can_move(&self.consumer_exprs(node, consumer), var, effects) {
+            return false;
+        }
+
+        let statement = self.lifted.nodes[node].statements.remove(index);
+        let consumer = match consumer {
+            Consumer::Stmt(consumer) => Consumer::Stmt(consumer - 1),
+            Consumer::Exit => Consumer::Exit,
+        };
+
+        if let StmtKind::Assign { mut values, .. } = statement.kind {
+            replace(
+                self.consumer_exprs_mut(node, consumer),
+                var,
+                values.remove(0),
+            );
+        }
+
+        true
+    }
+
+    /// Turns a method lookup followed by the call of the method into a method call.
+    fn method_call(&mut self, node: usize, index: usize, consumer: Consumer) -> bool {
+        let (function, object, value) = match &self.lifted.nodes[node].statements[index].kind {
+            StmtKind::SelfLookup {
+                function,
+                object,
+                value,
+                key: Expr::String(key),
+            } if is_identifier(key) => (*function, *object, value),
+            _ => return false,
+        };
+
+        if !self.is_temporary(function) || !self.is_temporary(object) {
+            return false;
+        }
+
+        // the call must pass the object first, right after looking the method up
+        let mut found = false;
+        for expr in self.consumer_exprs(node, consumer) {
+            expr.walk(&mut |expr| {
+                if let Expr::Call { callee, args, .. } = expr {
+                    found |=
+                        **callee == Expr::Var(function) && args.first() == Some(&Expr::Var(object));
+                }
+            });
+        }
+
+        let mut effects = self.effects(value);
+        effects.reads_memory = true;
+        effects.runs_code |= !self.options.pure_operators;
+
+        if !found || !self.can_move(&self.consumer_exprs(node, consumer), function, effects) {
+            return false;
+        }
+
+        let statement = self.lifted.nodes[node].statements.remove(index);
+        let (value, key) = match statement.kind {
+            StmtKind::SelfLookup {
+                value,
+                key: Expr::String(key),
+                ..
+            } => (value, key),
+            _ => unreachable!("the statement was matched as a method lookup"),
+        };
+        let consumer = match consumer {
+            Consumer::Stmt(consumer) => Consumer::Stmt(consumer - 1),
+            Consumer::Exit => Consumer::Exit,
+        };
+
+        let mut call = Some((value, key));
+        for expr in self.consumer_exprs_mut(node, consumer) {
+            expr.walk_mut(&mut |expr| {
+                let is_call =
+                    matches!(expr, Expr::Call { callee, .. } if **callee == Expr::Var(function));
+
+                if let (true, Expr::Call { args, multiple, .. }) = (is_call, &mut *expr) {
+                    if let Some((value, key)) = call.take() {
+                        let mut args = std::mem::take(args);
+                        args.remove(0);
+
+                        *expr = Expr::MethodCall {
+                            object: Box::new(value),
+                            method: key,
+                            args,
+                            multiple: *multiple,
+                        };
+                    }
+                }
+            });
+        }
+
+        true
+    }
+
+    /// Turns the method lookups that could not become method calls into plain assignments.
+    fn split_lookups(&mut self) {
+        for node in &mut self.lifted.nodes {
+            for statement in std::mem::take(&mut node.statements) {
+                match statement.kind {
+                    StmtKind::SelfLookup {
+                        function,
+                        object,
+                        value,
+                        key,
+                    } => {
+                        let lookup = Expr::Index(Box::new(Expr::Var(object)), Box::new(key));
+
+                        node.statements.push(Stmt::new(
+                            StmtKind::Assign {
+                                targets: vec![Expr::Var(object)],
+                                values: vec![value],
+                            },
+                            statement.span,
+                        ));
+                        node.statements.push(Stmt::new(
+                            StmtKind::Assign {
+                                targets: vec![Expr::Var(function)],
+                                values: vec![lookup],
+                            },
+                            statement.span,
+                        ));
+                    }
+                    kind => node.statements.push(Stmt::new(kind, statement.span)),
+                }
+            }
+        }
+    }
+
+    /// Folds the stores into a new table that follow its creation into its constructor.
+    fn tables(&mut self, node: usize) -> bool {
+        let mut changed = false;
+        let mut index = 0;
+
+        while index < self.lifted.nodes[node].statements.len() {
+            if let Some((end, fields)) = self.constructor(node, index) {
+                let statements = &mut self.lifted.nodes[node].statements;
+                statements.drain(index + 1..end);
+
+                if let StmtKind::Assign { values, .. } = &mut statements[index].kind {
+                    values[0] = Expr::Table(fields);
+                }
+                changed = true;
+            }
+
+            index += 1;
+        }
+
+        changed
+    }
+
+    /// Folds the call that produces the state of a generic `for` loop into the loop, where
+    /// the state is only held by temporaries. The call may produce more values than the
+    /// loop keeps, such as the value Lua 5.4 closes when the loop ends, as long as nothing
+    /// uses them.
+    fn generic_for(&mut self, node: usize) -> bool {
+        let folded = &self.lifted.nodes[node];
+        let state = match &folded.exit {
+            Exit::GenericFor { values, .. } => values,
+            _ => return false,
+        };
+        let (targets, value) = match folded.statements.last().map(|statement| &statement.kind) {
+            Some(StmtKind::Assign { targets, values }) => match &values[..] {
+                [value @ (Expr::Call { .. } | Expr::MethodCall { .. } | Expr::VarArg { .. })] => {
+                    (targets, value)
+                }
+                _ => return false,
+            },
+            _ => return false,
+        };
+
+        let is_unused = |var: &Var| {
+            let info = &self.lifted.vars[*var];
+
+            self.uses[*var] == 0
+                && self.defs[*var] == 1
+                && info.name.is_none()
+                && !self.captured.contains(var)
+        };
+
+        if targets.len() < state.len()
+            || targets.iter().zip(state).any(|(target, value)| {
+                !matches!((target, value), (Expr::Var(target), Expr::Var(var)) if target == var && self.is_temporary(*var))
+            })
+            || targets[state.len()..].iter().any(|target| !matches!(target, Expr::Var(var) if is_unused(var)))
+        {
+            return false;
+        }
+
+        let mut value = value.clone();
+        match &mut value {
+            Expr::Call { multiple, .. }
+            | Expr::MethodCall { multiple, .. }
+            | Expr::VarArg { multiple } => *multiple = true,
+            _ => unreachable!("the value was matched as a call"),
+        }
+
+        let node = &mut self.lifted.nodes[node];
+        node.statements.pop();
+        if let Exit::GenericFor { values, .. } = &mut node.exit {
+            *values = vec![value];
+        }
+
+        true
+    }
+
+    /// The statements after `index` that can be folded into the table it creates, as the
+    /// end of them and the fields of the constructor.
+    fn constructor(&self, node: usize, index: usize) -> Option<(usize, Vec<Field>)> {
+        let statements = &self.lifted.nodes[node].statements;
+        let (table, mut fields) = match &statements[index].kind {
+            StmtKind::Assign { targets, values } => match (&targets[..], &values[..]) {
+                ([Expr::Var(table)], [Expr::Table(fields)]) => (*table, fields.clone()),
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        if self.captured.contains(&table) {
+            return None;
+        }
+
+        let mut positional = fields
+            .iter()
+            .filter(|field| matches!(field, Field::Positional(_)))
+            .count();
+        // the temporaries whose values the next `SetList` stores, whose fields are already
+        // in place as the values are evaluated in the order of the constructor
+        let mut pending: Vec<Var> = vec![];
+        let mut committed = None;
+
+        for (end, statement) in statements.iter().enumerate().skip(index + 1) {
+            match &statement.kind {
+                StmtKind::Assign { targets, values } => match (&targets[..], &values[..]) {
+                    ([Expr::Var(var)], [value])
+                        if self.is_temporary(*var) && !references(value, table) =>
+                    {
+                        pending.push(*var);
+                        fields.push(Field::Positional(value.clone()));
+                    }
+                    ([Expr::Index(object, key)], [value])
+                        if **object == Expr::Var(table)
+                            && !references(key, table)
+                            && !references(value, table) =>
+                    {
+                        // the key and value may be held by the last temporaries
+                        let mut take = |expr: &Expr| match (expr, pending.last()) {
+                            (Expr::Var(var), Some(last)) if var == last => {
+                                pending.pop();
+                                fields.pop().map(|field| field.value().clone())
+                            }
+                            _ => None,
+                        };
+
+                        let value = take(value).unwrap_or_else(|| value.clone());
+                        let key = take(key).unwrap_or_else(|| (**key).clone());
+
+                        fields.push(Field::Keyed(key, value));
+                    }
+                    _ => break,
+                },
+                StmtKind::SetList {
+                    table: list,
+                    offset,
+                    values,
+                } if *list == table && *offset == positional => {
+                    let held = pending.len();
+
+                    if values.len() < held
+                        || values[held..].iter().any(|value| references(value, table))
+                        || pending
+                            .iter()
+                            .zip(values)
+                            .any(|(var, value)| *value != Expr::Var(*var))
+                    {
+                        break;
+                    }
+
+                    pending.clear();
+                    fields.extend(values[held..].iter().cloned().map(Field::Positional));
+                    positional += values.len();
+                }
+                _ => break,
+            }
+
+            if pending.is_empty() {
+                committed = Some((end + 1, fields.clone()));
+            }
+        }
+
+        committed
+    }
+
+    /// Whether `node` holds nothing but a single predecessor passes control through it,
+    /// so that it can be folded into that predecessor.
+    fn is_foldable(&self, node: usize, pred: usize) -> bool {
+        let folded = &self.lifted.nodes[node];
+
+        node != 0 && node != pred && folded.alive && !folded.synthetic && self.preds[node] == [pred]
+    }
+
+    /// Redirects the jumps to empty nodes that only jump elsewhere. Nodes that are jumped
+    /// back to are kept, as they may start a loop.
+    fn thread(&mut self) -> bool {
+        let mut changed = false;
+
+        for node in 1..self.lifted.nodes.len() {
+            let threaded = &self.lifted.nodes[node];
+            let target = match threaded.exit {
+                Exit::Jump(target) if target != node => target,
+                _ => continue,
+            };
+
+            if !threaded.alive
+                || threaded.synthetic
+                || !threaded.statements.is_empty()
+                || self.preds[node].iter().any(|&pred| {
+                    let pred = &self.lifted.nodes[pred];
+                    pred.synthetic || pred.first >= threaded.first
+                })
+            {
+                continue;
+            }
+
+            for pred in std::mem::take(&mut self.preds[node]) {
+                for successor in self.lifted.nodes[pred].exit.successors_mut() {
+                    if *successor == node {
+                        *successor = target;
+                    }
+                }
+                self.preds[target].push(pred);
+            }
+
+            self.lifted.nodes[node].alive = false;
+            changed = true;
+        }
+
+        changed
+    }
+
+    /// Collapses a condition that is tested by an empty node right after another into one
+    /// condition joined by `and` or `or`.
+    fn conditions(&mut self, node: usize) -> bool {
+        let (then, otherwise) = match &self.lifted.nodes[node].exit {
+            Exit::Branch {
+                then, otherwise, ..
+            } => (*then, *otherwise),
+            _ => return false,
+        };
+
+        for (next, is_then) in [(then, true), (otherwise, false)] {
+            if !self.is_foldable(next, node) || !self.lifted.nodes[next].statements.is_empty() {
+                continue;
+            }
+
+            let (inner, inner_then, inner_otherwise) = match &self.lifted.nodes[next].exit {
+                Exit::Branch {
+                    condition,
+                    then,
+                    otherwise,
+                } => (condition.clone(), *then, *otherwise),
+                _ => continue,
+            };
+
+            let outer = match &mut self.lifted.nodes[node].exit {
+                Exit::Branch { condition, .. } => std::mem::replace(condition, Expr::Nil),
+                _ => unreachable!("the exit was matched as a branch"),
+            };
+
+            let collapsed = if is_then && inner_otherwise == otherwise {
+                Some((BinaryOperator::And, inner, inner_then, otherwise))
+            } else if is_then && inner_then == otherwise {
+                Some((BinaryOperator::And, inner.not(), inner_otherwise, otherwise))
+            } else if !is_then && inner_then == then {
+                Some((BinaryOperator::Or, inner, then, inner_otherwise))
+            } else if !is_then && inner_otherwise == then {
+                Some((BinaryOperator::Or, inner.not(), then, inner_then))
+            } else {
+                None
+            };
+
+            match collapsed {
+                Some((operator, inner, then, otherwise)) => {
+                    self.lifted.nodes[node].exit = Exit::Branch {
+                        condition: Expr::binary(operator, outer, inner),
+                        then,
+                        otherwise,
+                    };
+                    self.lifted.nodes[next].alive = false;
+
+                    return true;
+                }
+                None => {
+                    if let Exit::Branch { condition, .. } = &mut self.lifted.nodes[node].exit {
+                        *condition = outer;
+                    }
+                }
+            }
+        }
+
+        false
+    }
+
+    /// The single assignment of a node to a variable, as the variable and the value.
+    fn single_assignment(&self, node: usize) -> Option<(Var, &Expr)> {
+        match &self.lifted.nodes[node].statements[..] {
+            [Stmt {
+                kind: StmtKind::Assign { targets, values },
+                ..
+            }] => match (&targets[..], &values[..]) {
+                ([Expr::Var(var)], [value]) => Some((*var, value)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Collapses the nodes that compute a value with `and`, `or` or a comparison back into
+    /// an expression.
+    fn values(&mut self, node: usize) -> bool {
+        let (condition, then, otherwise) = match &self.lifted.nodes[node].exit {
+            Exit::Branch {
+                condition,
+                then,
+                otherwise,
+            } => (condition.clone(), *then, *otherwise),
+            _ => return false,
+        };
+
+        // `d = a and b` tests `d = a` and assigns `b` only when it is truthy, `or` when
+        // it is falsy
+        if let Expr::Var(var) = condition {
+            for (assigned, merge, operator) in [
+                (then, otherwise, BinaryOperator::And),
+                (otherwise, then, BinaryOperator::Or),
+            ] {
+                if !self.is_foldable(assigned, node)
+                    || self.lifted.nodes[assigned].exit != Exit::Jump(merge)
+                {
+                    continue;
+                }
+
+                let right = match self.single_assignment(assigned) {
+                    Some((assigned_var, value)) if assigned_var == var => value.clone(),
+                    _ => continue,
+                };
+
+                let last = self.lifted.nodes[node].statements.last_mut();
+                if let Some(Stmt {
+                    kind: StmtKind::Assign { targets, values },
+                    ..
+                }) = last
+                {
+                    if targets[..] == [Expr::Var(var)] && values.len() == 1 {
+                        let left = values.remove(0);
+                        values.push(Expr::binary(operator, left, right));
+
+                        self.lifted.nodes[node].exit = Exit::Jump(merge);
+                        self.lifted.nodes[assigned].alive = false;
+
+                        return true;
+                    }
+                }
+            }
+        }
+
+        // a comparison assigns `true` where it holds and `false` where it does not
+        let (held, failed) = match (
+            self.single_assignment(then),
+            self.single_assignment(otherwise),
+        ) {
+            (Some((held, Expr::Boolean(a))), Some((failed, Expr::Boolean(b))))
+                if held == failed && a != b =>
+            {
+                (*a, failed)
+            }
+            _ => return false,
+        };
+        let merge = match self.lifted.nodes[then].exit {
+            Exit::Jump(merge) if self.lifted.nodes[otherwise].exit == Exit::Jump(merge) => merge,
+            _ => return false,
+        };
+
+        if !self.is_foldable(then, node) || !self.is_foldable(otherwise, node) {
+            return false;
+        }
+
+        let mut value = if held { condition } else { condition.not() };
+        if !value.is_boolean() {
+            value = Expr::Unary(
+                UnaryOperator::Not,
+                Box::new(Expr::Unary(UnaryOperator::Not, Box::new(value))),
+            );
+        }
+
+        let span = self.lifted.nodes[node].span;
+        let node = &mut self.lifted.nodes[node];
+        node.statements.push(Stmt::new(
+            StmtKind::Assign {
+                targets: vec![Expr::Var(failed)],
+                values: vec![value],
+            },
+            span,
+        ));
+        node.exit = Exit::Jump(merge);
+
+        self.lifted.nodes[then].alive = false;
+        self.lifted.nodes[otherwise].alive = false;
+
+        true
+    }
+
+    /// Appends the node that `node` jumps to when nothing else reaches it.
+    fn merge(&mut self, node: usize) -> bool {
+        let next = match self.lifted.nodes[node].exit {
+            Exit::Jump(next) => next,
+            _ => return false,
+        };
+
+        if self.lifted.nodes[node].synthetic || !self.is_foldable(next, node) {
+            return false;
+        }
+
+        let merged = &mut self.lifted.nodes[next];
+        let statements = std::mem::take(&mut merged.statements);
+        let exit = std::mem::replace(&mut merged.exit, Exit::Return);
+        let span = merged.span;
+        merged.alive = false;
+
+        let node = &mut self.lifted.nodes[node];
+        node.statements.extend(statements);
+        node.exit = exit;
+        node.span = span;
+
+        true
+    }
+
+    /// Runs every simplification over every node once, returning whether any applied.
+    fn pass(&mut self) -> bool {
+        type Simplification<'l> = fn(&mut Simplifier<'l>, usize) -> bool;
+
+        let mut changed = false;
+        self.count();
+
+        let within: [Simplification<'l>; 3] = [Self::inline, Self::tables, Self::generic_for];
+        let across: [Simplification<'l>; 3] = [Self::conditions, Self::values, Self::merge];
+
+        for simplifications in [&within[..], &across[..]] {
+            for node in 0..self.lifted.nodes.len() {
+                for simplification in simplifications {
+                    // the counts are only stale once something changed
+                    if self.lifted.nodes[node].alive && simplification(self, node) {
+                        changed = true;
+                        self.count();
+                    }
+                }
+            }
+
+            if self.thread() {
+                changed = true;
+                self.count();
+            }
+        }
+
+        changed
+    }
+}
+
+/// Simplifies the nodes of `lifted` until nothing more applies.
+pub(super) fn simplify(lifted: &mut Lifted, options: Options) {
+    let captured = lifted.open.iter().map(|(var, _)| *var).collect();
+    let mut simplifier = Simplifier {
+        lifted,
+        options,
+        captured,
+        defs: vec![],
+        uses: vec![],
+        preds: vec![],
+    };
+
+    simplifier.prune();
+    while simplifier.pass() {}
+
+    // the lookups that are left are not followed by a call of the method
+    simplifier.split_lookups();
+    while simplifier.pass() {}
+}
@@ -0,0 +1,870 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Structuring the control flow between nodes into loops and `if` statements.
+//!
+//! Loops are found from their headers, which dominate the nodes of the loop. Inside a
+//! region, which is the function or the body of a loop, an `if` statement ends where both
+//! of its branches meet again, which is the immediate post-dominator of the node that
+//! branches. Jumps out of a loop or back to its start become `break` and `continue`.
+
+use super::code::*;
+use super::lift::{Exit, Lifted, Node};
+use crate::ir::ast::Dialect;
+use anyhow::{anyhow, bail, Result};
+use petgraph::algo::dominators::simple_fast;
+use petgraph::graph::{DiGraph, NodeIndex};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone)]
+enum Shape {
+    /// Tests `condition` in the header before every iteration, which starts at `body`.
+    While { condition: Expr, body: usize },
+    /// Tests `condition` in `latch` after every iteration, leaving once it holds.
+    Repeat { latch: usize, condition: Expr },
+    /// Only leaves through `break`, `return` or `goto`.
+    Infinite,
+    NumericFor {
+        var: Var,
+        start: Expr,
+        limit: Expr,
+        step: Expr,
+    },
+    GenericFor {
+        vars: Vec<Var>,
+        values: Vec<Expr>,
+        body: usize,
+    },
+}
+
+struct Loop {
+    header: usize,
+    shape: Shape,
+    /// The nodes of the loop, which the header dominates.
+    nodes: HashSet<usize>,
+    /// The node the loop continues at when it is done, if it ever is.
+    follow: Option<usize>,
+    /// The node that starts the next iteration, which `continue` jumps to.
+    next: Option<usize>,
+}
+
+/// Where the branches of an `if` statement meet again.
+#[derive(Clone, Copy)]
+enum Merge {
+    Node(usize),
+    /// At the start of the next iteration of the loop.
+    Next,
+    /// After the loop.
+    Follow,
+}
+
+/// A loop whose body is being structured.
+struct Frame {
+    index: usize,
+    merges: HashMap<usize, Merge>,
+    /// The labels that `goto` jumps to in place of `continue` and `break`, once one does.
+    next_label: Option<String>,
+    follow_label: Option<String>,
+}
+
+struct Structurer<'l> {
+    nodes: &'l mut [Node],
+    dialect: Dialect,
+    loops: Vec<Loop>,
+    /// The loop each header starts.
+    headers: HashMap<usize, usize>,
+    /// Where the branches of an `if` outside of any loop meet again.
+    merges: HashMap<usize, Merge>,
+    frames: Vec<Frame>,
+    visited: Vec<bool>,
+    labels: usize,
+    /// The instructions at which the upvalue of each captured variable is open.
+    open: &'l [(Var, HashSet<usize>)],
+}
+
+fn successors(nodes: &[Node], node: usize) -> Vec<usize> {
+    if nodes[node].alive {
+        nodes[node].exit.successors()
+    } else {
+        vec![]
+    }
+}
+
+/// The immediate dominator of every node reached from the entry.
+fn dominators(nodes: &[Node]) -> Vec<Option<usize>> {
+    let mut graph = DiGraph::<(), ()>::with_capacity(nodes.len(), 0);
+    for _ in 0..nodes.len() {
+        graph.add_node(());
+    }
+
+    for node in 0..nodes.len() {
+        for successor in successors(nodes, node) {
+            graph.add_edge(NodeIndex::new(node), NodeIndex::new(successor), ());
+        }
+    }
+
+    let dominators = simple_fast(&graph, NodeIndex::new(0));
+
+    (0..nodes.len())
+        .map(|node| {
+            dominators
+                .immediate_dominator(NodeIndex::new(node))
+                .map(NodeIndex::index)
+        })
+        .collect()
+}
+
+fn dominates(idom: &[Option<usize>], dominator: usize, mut node: usize) -> bool {
+    loop {
+        if node == dominator {
+            return true;
+        }
+
+        match idom[node] {
+            Some(parent) => node = parent,
+            None => return false,
+        }
+    }
+}
+
+/// The edges that jump back to a node on the path from the entry, as their sources and
+/// targets. Every such target must dominate the source, or the loop it forms has more
+/// than one entry and no structured loop can express it.
+fn back_edges(nodes: &[Node], idom: &[Option<usize>]) -> Result<Vec<(usize, usize)>> {
+    let mut edges = vec![];
+    let mut state = vec![0u8; nodes.len()];
+    let mut stack = vec![(0, 0)];
+    state[0] = 1;
+
+    while let Some((node, next)) = stack.pop() {
+        let successors = successors(nodes, node);
+
+        match successors.get(next) {
+            Some(&successor) => {
+                stack.push((node, next + 1));
+
+                match state[successor] {
+                    0 => {
+                        state[successor] = 1;
+                        stack.push((successor, 0));
+                    }
+                    1 => {
+                        if !dominates(idom, successor, node) {
+                            bail!("control flow enters a loop from more than one place, which cannot be structured");
+                        }
+                        edges.push((node, successor));
+                    }
+                    _ => {}
+                }
+            }
+            None => state[node] = 2,
+        }
+    }
+
+    Ok(edges)
+}
+
+/// Finds the loops of the function.
+fn loops(nodes: &[Node], idom: &[Option<usize>]) -> Result<Vec<Loop>> {
+    let back_edges = back_edges(nodes, idom)?;
+    let alive = (0..nodes.len()).filter(|&node| nodes[node].alive);
+    let mut loops = vec![];
+
+    let region = |header: usize, follow: Option<usize>| {
+        (0..nodes.len())
+            .filter(|&node| nodes[node].alive && dominates(idom, header, node))
+            .filter(|&node| follow.map_or(true, |follow| !dominates(idom, follow, node)))
+            .collect::<HashSet<_>>()
+    };
+
+    // `for` loops are entered through the node preparing them
+    let mut counted = HashSet::new();
+    for node in alive.clone() {
+        match &nodes[node].exit {
+            Exit::NumericFor {
+                var,
+                start,
+                limit,
+                step,
+                body,
+                exit,
+            } => {
+                let latch = back_edges
+                    .iter()
+                    .find(|(_, target)| target == body)
+                    .map(|(source, _)| *source);
+
+                counted.insert(*body);
+                loops.push(Loop {
+                    header: *body,
+                    shape: Shape::NumericFor {
+                        var: *var,
+                        start: start.clone(),
+                        limit: limit.clone(),
+                        step: step.clone(),
+                    },
+                    nodes: region(*body, Some(*exit)),
+                    follow: Some(*exit),
+                    next: latch,
+                });
+            }
+            Exit::GenericFor { values, call } => {
+                let (vars, body, exit) = match &nodes[*call].exit {
+                    Exit::GenericLoop { vars, body, exit } => (vars.clone(), *body, *exit),
+                    _ => bail!("generic for loop does not call its iterator"),
+                };
+
+                counted.insert(*call);
+                loops.push(Loop {
+                    header: *call,
+                    shape: Shape::GenericFor {
+                        vars,
+                        values: values.clone(),
+                        body,
+                    },
+                    nodes: region(*call, Some(exit)),
+                    follow: Some(exit),
+                    next: Some(*call),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    let mut headers = back_edges
+        .iter()
+        .map(|(_, header)| *header)
+        .filter(|header| !counted.contains(header))
+        .collect::<Vec<_>>();
+    headers.sort_unstable();
+    headers.dedup();
+
+    for header in headers {
+        let latches = back_edges
+            .iter()
+            .filter(|(_, target)| *target == header)
+            .map(|(source, _)| *source)
+            .collect::<Vec<_>>();
+
+        // the nodes that reach a latch without passing through the header
+        let mut natural = HashSet::from([header]);
+        let mut pending = latches.clone();
+        let mut preds = vec![vec![]; nodes.len()];
+        for node in alive.clone() {
+            for successor in successors(nodes, node) {
+                preds[successor].push(node);
+            }
+        }
+        while let Some(node) = pending.pop() {
+            if natural.insert(node) {
+                pending.extend(preds[node].iter().copied());
+            }
+        }
+
+        let (shape, follow) = match &nodes[header].exit {
+            Exit::Branch {
+                condition,
+                then,
+                otherwise,
+            } if nodes[header].statements.is_empty()
+                && natural.contains(then) != natural.contains(otherwise) =>
+            {
+                if natural.contains(then) {
+                    let shape = Shape::While {
+                        condition: condition.clone(),
+                        body: *then,
+                    };
+
+                    (shape, Some(*otherwise))
+                } else {
+                    let shape = Shape::While {
+                        condition: condition.clone().not(),
+                        body: *otherwise,
+                    };
+
+                    (shape, Some(*then))
+                }
+            }
+            _ => match (
+                &latches[..],
+                latches.first().map(|&latch| &nodes[latch].exit),
+            ) {
+                (
+                    [latch],
+                    Some(Exit::Branch {
+                        condition,
+                        then,
+                        otherwise,
+                    }),
+                ) if (*then == header) != (*otherwise == header)
+                    && !natural.contains(if *then == header { otherwise } else { then }) =>
+                {
+                    if *then == header {
+                        let shape = Shape::Repeat {
+                            latch: *latch,
+                            condition: condition.clone().not(),
+                        };
+
+                        (shape, Some(*otherwise))
+                    } else {
+                        let shape = Shape::Repeat {
+                            latch: *latch,
+                            condition: condition.clone(),
+                        };
+
+                        (shape, Some(*then))
+                    }
+                }
+                _ => {
+                    // the loop is left through `break`, which jumps past every other
+                    // exit as the loop is compiled before what follows it
+                    let follow = natural
+                        .iter()
+                        .flat_map(|&node| successors(nodes, node))
+                        .filter(|successor| !natural.contains(successor))
+                        .max_by_key(|&successor| nodes[successor].first);
+
+                    (Shape::Infinite, follow)
+                }
+            },
+        };
+
+        let next = match shape {
+            Shape::Repeat { latch, .. } => latch,
+            _ => header,
+        };
+
+        loops.push(Loop {
+            header,
+            shape,
+            nodes: region(header, follow),
+            follow,
+            next: Some(next),
+        });
+    }
+
+    Ok(loops)
+}
+
+/// The immediate post-dominators of the nodes of a region, where jumps to the start of
+/// the next iteration or out of the loop end the region. A node whose paths only meet at
+/// the end of the region merges at the next iteration if any of them reaches it, as
+/// control that falls through to the end of the body needs no `continue`.
+fn merges(
+    nodes: &[Node],
+    region: &HashSet<usize>,
+    header: Option<usize>,
+    next: Option<usize>,
+    follow: Option<usize>,
+) -> HashMap<usize, Merge> {
+    const END: usize = 0;
+    const NEXT: usize = 1;
+    const FOLLOW: usize = 2;
+
+    let members = region.iter().copied().collect::<Vec<_>>();
+    let index = members
+        .iter()
+        .enumerate()
+        .map(|(position, &node)| (node, position + 3))
+        .collect::<HashMap<_, _>>();
+
+    // the edges are reversed, so that post-dominators are the dominators from the end
+    let mut graph = DiGraph::<(), ()>::with_capacity(members.len() + 3, 0);
+    for _ in 0..members.len() + 3 {
+        graph.add_node(());
+    }
+    let mut forward = vec![vec![]; members.len() + 3];
+    let mut edge = |from: usize, to: usize| {
+        graph.add_edge(NodeIndex::new(to), NodeIndex::new(from), ());
+        forward[from].push(to);
+    };
+
+    edge(NEXT, END);
+    edge(FOLLOW, END);
+
+    for &node in &members {
+        // a latch only leads back to the header, while a header starts the body
+        if Some(node) == next && next != follow && next != header {
+            continue;
+        }
+
+        if nodes[node].exit == Exit::Return {
+            edge(index[&node], END);
+        }
+
+        for successor in nodes[node].exit.successors() {
+            let target = if Some(successor) == next {
+                NEXT
+            } else if Some(successor) == follow {
+                FOLLOW
+            } else {
+                index.get(&successor).copied().unwrap_or(END)
+            };
+
+            edge(index[&node], target);
+        }
+    }
+
+    let dominators = simple_fast(&graph, NodeIndex::new(END));
+    let reaches_next = |start: usize| {
+        let mut seen = HashSet::from([start]);
+        let mut pending = vec![start];
+
+        while let Some(node) = pending.pop() {
+            if node == NEXT {
+                return true;
+            }
+
+            pending.extend(forward[node].iter().filter(|&&target| seen.insert(target)));
+        }
+
+        false
+    };
+
+    members
+        .iter()
+        .filter_map(|&node| {
+            let merge = match dominators
+                .immediate_dominator(NodeIndex::new(index[&node]))?
+                .index()
+            {
+                END if next.is_some() && reaches_next(index[&node]) => Merge::Next,
+                END => return None,
+                NEXT => Merge::Next,
+                FOLLOW => Merge::Follow,
+                position => Merge::Node(members[position - 3]),
+            };
+
+            Some((node, merge))
+        })
+        .collect()
+}
+
+impl<'l> Structurer<'l> {
+    fn label(&mut self, kind: &str) -> String {
+        self.labels += 1;
+
+        format!("{kind}_{}", self.labels)
+    }
+
+    /// Jumps to `target` from a loop that is `depth` loops deep, with a `goto` unless the
+    /// dialect has a statement for it.
+    fn jump(&mut self, depth: usize, next: bool, statements: &mut Vec<Stmt>) -> Result<()> {
+        let innermost = depth + 1 == self.frames.len();
+
+        if innermost && !next {
+            statements.push(Stmt::new(StmtKind::Break, None));
+            return Ok(());
+        }
+
+        if innermost && self.dialect.has_continue() {
+            statements.push(Stmt::new(StmtKind::Continue, None));
+            return Ok(());
+        }
+
+        if !self.dialect.has_goto() {
+            bail!(
+                "control flow jumps {} a loop in a way that {} cannot express",
+                if next { "to the end of" } else { "out of" },
+                self.dialect
+            );
+        }
+
+        let label = match (next, &self.frames[depth]) {
+            (
+                true,
+                Frame {
+                    next_label: Some(label),
+                    ..
+                },
+            )
+            | (
+                false,
+                Frame {
+                    follow_label: Some(label),
+                    ..
+                },
+            ) => label.clone(),
+            _ => {
+                let label = self.label(if next { "continue" } else { "break" });
+                let frame = &mut self.frames[depth];
+
+                if next {
+                    frame.next_label = Some(label.clone());
+                } else {
+                    frame.follow_label = Some(label.clone());
+                }
+
+                label
+            }
+        };
+
+        statements.push(Stmt::new(StmtKind::Goto(label), None));
+        Ok(())
+    }
+
+    /// Handles control reaching `target`, returning whether the sequence goes on there.
+    fn arrive(
+        &mut self,
+        target: usize,
+        stop: Option<usize>,
+        statements: &mut Vec<Stmt>,
+    ) -> Result<bool> {
+        if Some(target) == stop {
+            return Ok(false);
+        }
+
+        for depth in (0..self.frames.len()).rev() {
+            let structured = &self.loops[self.frames[depth].index];
+
+            if structured.follow == Some(target) {
+                self.jump(depth, false, statements)?;
+                return Ok(false);
+            }
+
+            if structured.next == Some(target) {
+                self.jump(depth, true, statements)?;
+                return Ok(false);
+            }
+        }
+
+        if let Some(frame) = self.frames.last() {
+            if !self.loops[frame.index].nodes.contains(&target) {
+                bail!("control flow leaves a loop in a way that cannot be structured");
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn merge(&self, node: usize) -> Option<usize> {
+        let (merges, structured) = match self.frames.last() {
+            Some(frame) => (&frame.merges, Some(&self.loops[frame.index])),
+            None => (&self.merges, None),
+        };
+
+        match merges.get(&node)? {
+            Merge::Node(merge) => Some(*merge),
+            Merge::Next => structured?.next,
+            Merge::Follow => structured?.follow,
+        }
+    }
+
+    fn visit(&mut self, node: usize) -> Result<Vec<Stmt>> {
+        if std::mem::replace(&mut self.visited[node], true) {
+            bail!("control flow reaches code twice in a way that cannot be structured");
+        }
+
+        Ok(std::mem::take(&mut self.nodes[node].statements))
+    }
+
+    /// Structures the nodes from `start` until control reaches `stop`, or leaves the
+    /// sequence otherwise. The loop `start` may be the header of is not entered again
+    /// when `skip` is set, as its body is what is being structured.
+    fn sequence(
+        &mut self,
+        mut current: usize,
+        stop: Option<usize>,
+        mut skip: bool,
+    ) -> Result<Vec<Stmt>> {
+        let mut statements = vec![];
+
+        loop {
+            if !skip {
+                if !self.arrive(current, stop, &mut statements)? {
+                    return Ok(statements);
+                }
+
+                if let Some(&index) = self.headers.get(&current) {
+                    if !matches!(
+                        self.loops[index].shape,
+                        Shape::NumericFor { .. } | Shape::GenericFor { .. }
+                    ) {
+                        match self.structure(index, &mut statements)? {
+                            Some(follow) => {
+                                current = follow;
+                                continue;
+                            }
+                            None => return Ok(statements),
+                        }
+                    }
+                }
+            }
+            skip = false;
+
+            statements.extend(self.visit(current)?);
+            let span = self.nodes[current].span;
+
+            match self.nodes[current].exit.clone() {
+                Exit::Return => return Ok(statements),
+                Exit::Jump(next) => current = next,
+                Exit::Branch {
+                    condition,
+                    then,
+                    otherwise,
+                } => {
+                    let merge = self.merge(current);
+                    let then = self.sequence(then, merge, false)?;
+                    let otherwise = self.sequence(otherwise, merge, false)?;
+
+                    statements.push(Stmt::new(
+                        StmtKind::If {
+                            condition,
+                            then,
+                            otherwise,
+                        },
+                        span,
+                    ));
+
+                    match merge {
+                        Some(merge) => current = merge,
+                        None => return Ok(statements),
+                    }
+                }
+                Exit::NumericFor { body: header, .. } | Exit::GenericFor { call: header, .. } => {
+                    let index = self.headers[&header];
+
+                    match self.structure(index, &mut statements)? {
+                        Some(follow) => current = follow,
+                        None => return Ok(statements),
+                    }
+                }
+                Exit::NumericLoop { .. } | Exit::GenericLoop { .. } => {
+                    bail!("control flow steps a for loop outside of its body")
+                }
+            }
+        }
+    }
+
+    /// Structures the loop `index` into `statements`, returning where it continues.
+    fn structure(&mut self, index: usize, statements: &mut Vec<Stmt>) -> Result<Option<usize>> {
+        let structured = &self.loops[index];
+        let (header, shape, follow, next) = (
+            structured.header,
+            structured.shape.clone(),
+            structured.follow,
+            structured.next,
+        );
+        let span = self.nodes[header].span;
+
+        self.frames.push(Frame {
+            index,
+            merges: merges(self.nodes, &structured.nodes, Some(header), next, follow),
+            next_label: None,
+            follow_label: None,
+        });
+
+        let (kind, mut body) = match shape {
+            Shape::While { condition, body } => {
+                self.visit(header)?;
+                (
+                    LoopKind::While(condition),
+                    self.sequence(body, Some(header), false)?,
+                )
+            }
+            Shape::Repeat { latch, condition } if latch == header => {
+                (LoopKind::Repeat(condition), self.visit(header)?)
+            }
+            Shape::Repeat { latch, condition } => {
+                let mut body = self.sequence(header, Some(latch), true)?;
+                body.extend(self.visit(latch)?);
+
+                (LoopKind::Repeat(condition), body)
+            }
+            Shape::Infinite => (
+                LoopKind::While(Expr::Boolean(true)),
+                self.sequence(header, Some(header), true)?,
+            ),
+            Shape::NumericFor {
+                var,
+                start,
+                limit,
+                step,
+            } => {
+                let mut body = self.sequence(header, next, false)?;
+                if let Some(latch) = next {
+                    body.extend(self.visit(latch)?);
+                }
+
+                (
+                    LoopKind::NumericFor {
+                        var,
+                        start,
+                        limit,
+                        step,
+                    },
+                    body,
+                )
+            }
+            Shape::GenericFor { vars, values, body } => {
+                self.visit(header)?;
+                (
+                    LoopKind::GenericFor { vars, values },
+                    self.sequence(body, Some(header), false)?,
+                )
+            }
+        };
+
+        let frame = self
+            .frames
+            .pop()
+            .ok_or_else(|| anyhow!("loop frame is missing"))?;
+        if let Some(label) = frame.next_label {
+            body.push(Stmt::new(StmtKind::Label(label), None));
+        }
+
+        // an upvalue that is still open when the next iteration starts belongs to a
+        // variable declared outside of the loop
+        let first = self.nodes[header].first;
+        let mut touches = self
+            .open
+            .iter()
+            .filter(|(_, insts)| insts.contains(&first))
+            .map(|(var, _)| *var)
+            .collect::<Vec<_>>();
+        touches.sort_unstable();
+        touches.dedup();
+
+        statements.push(Stmt::new(
+            StmtKind::Loop {
+                kind,
+                body,
+                touches,
+            },
+            span,
+        ));
+
+        if let Some(label) = frame.follow_label {
+            statements.push(Stmt::new(StmtKind::Label(label), None));
+        }
+
+        Ok(follow)
+    }
+}
+
+/// Hoists what follows an `if` out of its `else` when its `then` block never completes,
+/// and recurses into nested blocks.
+fn flatten(statements: Vec<Stmt>) -> Vec<Stmt> {
+    let mut flattened = Vec::with_capacity(statements.len());
+
+    for mut statement in statements {
+        for block in statement.kind.blocks_mut() {
+            *block = flatten(std::mem::take(block));
+        }
+
+        match statement.kind {
+            StmtKind::If {
+                condition,
+                then,
+                otherwise,
+            } if then.is_empty() && !otherwise.is_empty() => {
+                flattened.push(Stmt::new(
+                    StmtKind::If {
+                        condition: condition.not(),
+                        then: otherwise,
+                        otherwise: vec![],
+                    },
+                    statement.span,
+                ));
+            }
+            StmtKind::If {
+                condition,
+                then,
+                otherwise,
+            } if then.last().map_or(false, Stmt::is_jump) && !otherwise.is_empty() => {
+                flattened.push(Stmt::new(
+                    StmtKind::If {
+                        condition,
+                        then,
+                        otherwise: vec![],
+                    },
+                    statement.span,
+                ));
+                flattened.extend(otherwise);
+            }
+            kind => flattened.push(Stmt::new(kind, statement.span)),
+        }
+    }
+
+    flattened
+}
+
+/// Moves the statements of each latch that decides whether to loop again into a node of
+/// their own, so that `continue`, which jumps to the latch, skips none of them.
+fn split_latches(nodes: &mut Vec<Node>) -> Result<()> {
+    let idom = dominators(nodes);
+
+    for (latch, _) in back_edges(nodes, &idom)? {
+        if nodes[latch].statements.is_empty() || matches!(nodes[latch].exit, Exit::Jump(_)) {
+            continue;
+        }
+
+        let split = nodes.len();
+        let exit = std::mem::replace(&mut nodes[latch].exit, Exit::Jump(split));
+        let (span, first) = (nodes[latch].span, nodes[latch].first);
+
+        nodes.push(Node {
+            statements: vec![],
+            exit,
+            span,
+            first,
+            synthetic: true,
+            alive: true,
+        });
+    }
+
+    Ok(())
+}
+
+/// Structures the nodes of `lifted` into the statements of the function's body.
+pub(super) fn structure(lifted: &mut Lifted, dialect: Dialect) -> Result<Vec<Stmt>> {
+    split_latches(&mut lifted.nodes)?;
+
+    let idom = dominators(&lifted.nodes);
+    let loops = loops(&lifted.nodes, &idom)?;
+    let headers = loops
+        .iter()
+        .enumerate()
+        .map(|(index, structured)| (structured.header, index))
+        .collect();
+
+    let all = (0..lifted.nodes.len())
+        .filter(|&node| lifted.nodes[node].alive)
+        .collect();
+    let merges = merges(&lifted.nodes, &all, None, None, None);
+
+    let mut structurer = Structurer {
+        visited: vec![false; lifted.nodes.len()],
+        nodes: &mut lifted.nodes,
+        dialect,
+        loops,
+        headers,
+        merges,
+        frames: vec![],
+        labels: 0,
+        open: &lifted.open,
+    };
+
+    let body = structurer.sequence(0, None, false)?;
+
+    Ok(flatten(body))
+}
@@ -0,0 +1,389 @@
+#![cfg(all(test, feature = "compile"))]
+use super::*;
+use crate::formats::{Deserializer, Format};
+use crate::ir::ast::parse;
+use crate::ir::il::interpreter::{Interpreter, Semantics};
+use crate::pipelines::Compiler;
+
+/// Compiles `source` into `format` and decompiles the bytecode back into `dialect`.
+fn decompile(source: &str, dialect: Dialect, format: Format, level: OptimizationLevel) -> String {
+    let tree = parse(source, dialect).unwrap();
+    let bytecode = Compiler::new()
+        .create_job()
+        .tree(&tree)
+        .serializer(format)
+        .run()
+        .unwrap();
+    let function = format.deserialize(&bytecode).unwrap();
+
+    Decompiler::new()
+        .create_job()
+        .function(&function)
+        .optimization_level(level)
+        .reconstructor(LuaReconstructor::new(dialect))
+        .run()
+        .unwrap_or_else(|error| panic!("failed to decompile {format} bytecode: {error:?}"))
+}
+
+/// Runs `source`, returning what it printed.
+fn run(source: &str, dialect: Dialect) -> Vec<String> {
+    let tree = parse(source, dialect)
+        .unwrap_or_else(|error| panic!("failed to parse:\n{source}\n{error}"));
+    let bytecode = Compiler::new()
+        .create_job()
+        .tree(&tree)
+        .serializer(Format::Lir)
+        .run()
+        .unwrap();
+    let function = Format::Lir.deserialize(&bytecode).unwrap();
+    let semantics = if dialect.has_integers() {
+        Semantics::Lua53
+    } else {
+        Semantics::Lua51
+    };
+
+    let mut interpreter = Interpreter::new().semantics(semantics).with_base_library();
+    interpreter
+        .run(&function, vec![])
+        .unwrap_or_else(|error| panic!("failed to run:\n{source}\n{error:?}"));
+
+    interpreter.output().to_vec()
+}
+
+/// Decompiles `source` from every format that can hold `dialect` at every optimization
+/// level, checking that the decompiled source prints what `source` does.
+fn check(source: &str, dialect: Dialect) {
+    let expected = run(source, dialect);
+    let formats: &[Format] = match dialect {
+        Dialect::Lua51 => &[Format::Lua51, Format::Lua54, Format::Luau, Format::Lir],
+        Dialect::Luau => &[Format::Luau, Format::Lir],
+        _ => &[Format::Lua54, Format::Lir],
+    };
+
+    for &format in formats {
+        for level in [
+            OptimizationLevel::None,
+            OptimizationLevel::Moderate,
+            OptimizationLevel::All,
+        ] {
+            let decompiled = decompile(source, dialect, format, level.clone());
+
+            assert_eq!(
+                run(&decompiled, dialect),
+                expected,
+                "decompiled from {format} at {level:?}:\n{decompiled}"
+            );
+        }
+    }
+}
+
+const STATEMENTS: &str = r#"
+        local function fib(n)
+            if n < 2 then
+                return n
+            end
+            return fib(n - 1) + fib(n - 2)
+        end
+
+        local t = {1, 2, x = 3}
+        for i = 1, 10 do
+            if i % 2 == 0 then
+                print(i, fib(i))
+            elseif i == 5 then
+                print("five")
+            else
+                print("odd")
+            end
+        end
+
+        for k, v in pairs({a = 1}) do
+            print(k, v)
+        end
+
+        local s = 0
+        while s < 10 do
+            s = s + 3
+        end
+
+        local n = 0
+        repeat
+            local m = n * 2
+            n = n + 1
+        until m > 6
+
+        for i = 10, 1, -3 do
+            print(i)
+        end
+
+        local a, b, c = 1, 2
+        a, b = b, a
+        print(s, t.x, #t, n, a, b, c)
+    "#;
+
+#[test]
+fn statements_round_trip() {
+    check(STATEMENTS, Dialect::Lua54);
+}
+
+#[test]
+fn expressions_round_trip() {
+    let source = r#"
+        local x, y = 7, 2
+        print(x + y * 3, (x + y) * 3, x - -y, x ^ y ^ 2, (x ^ y) ^ 2, -x ^ 2)
+        print(x // y, x % y, x / y, 1e300 * 10, -(0 / 0) ~= -(0 / 0), 2^53)
+        print(x & y, x | y, x ~ y, ~x, x << 2, x >> 1, "a" .. "b" .. x)
+        print(x < y, x <= y, x > y, x >= y, x == y, x ~= y, not x)
+        print(x > 5 and "big" or "small", x and y or nil, nil or false, x or y and x)
+        print(x > 1 and y > 1, x > 1 or y > 9, not (x > 1))
+        local s = "a\n\"b\"\0\255"
+        print(#s, s)
+        local t = {1, 2, 3, [10] = "ten", ["key with spaces"] = true, f = function() return 1, 2 end}
+        print(#t, t[10], t["key with spaces"], t.f())
+        print(#{t.f()}, #{t.f(), 5}, (t.f()))
+        print(9223372036854775807, -9223372036854775807 - 1, 3.0, -0.0, 0x10)
+    "#;
+
+    check(source, Dialect::Lua54);
+}
+
+const CLOSURES: &str = r#"
+        local counters = {}
+        for i = 1, 3 do
+            counters[i] = function()
+                i = i + 10
+                return i
+            end
+        end
+        print(counters[1](), counters[1](), counters[2]())
+
+        local function make()
+            local n = 0
+            return function(...)
+                n = n + select('#', ...)
+                return n
+            end
+        end
+        local f = make()
+        print(f(1, 2), f(3))
+
+        local object = {value = 5}
+        function object.get(self)
+            return self.value
+        end
+        function object:set(value)
+            self.value = value
+        end
+        object:set(9)
+        print(object:get(), object.get(object))
+
+        local a = {b = {}}
+        function a.b.c(x)
+            return x * 2
+        end
+        print(a.b.c(21))
+
+        function global_function(...)
+            local t = {...}
+            return #t, ...
+        end
+        print(global_function(1, nil, 3))
+    "#;
+
+#[test]
+fn closures_round_trip() {
+    check(CLOSURES, Dialect::Lua54);
+}
+
+const LOOPS: &str = r#"
+        for i = 1, 5 do
+            if i == 4 then
+                break
+            end
+            print(i)
+        end
+
+        local i = 0
+        while true do
+            i = i + 1
+            if i > 3 then
+                break
+            end
+            if i == 2 then
+                goto continue
+            end
+            print("while", i)
+            ::continue::
+        end
+
+        for x = 1, 3 do
+            for y = 1, 3 do
+                if y > x then
+                    break
+                end
+                print(x, y)
+            end
+        end
+
+        local n = 0
+        repeat
+            n = n + 1
+            if n == 2 then
+                print("two")
+            end
+        until n >= 3
+
+        local t = {}
+        for k, v in ipairs({"a", "b", "c"}) do
+            t[#t + 1] = function() return k .. v end
+        end
+        print(t[1](), t[3]())
+    "#;
+
+#[test]
+fn loops_round_trip() {
+    check(LOOPS, Dialect::Lua54);
+}
+
+#[test]
+fn lua51_round_trip() {
+    let source = r#"
+        local function sum(...)
+            local total = 0
+            for _, value in ipairs({...}) do
+                total = total + value
+            end
+            return total
+        end
+        print(sum(1, 2, 3), 7 / 2, 2 ^ 10, 1e15, -3)
+
+        local i = 0
+        while i < 10 do
+            i = i + 1
+            if i > 5 then break end
+        end
+        print(i, "x" .. 1, #"abc")
+    "#;
+
+    check(source, Dialect::Lua51);
+}
+
+#[test]
+fn luau_round_trip() {
+    let source = r#"
+        local total = 0
+        for i = 1, 10 do
+            if i % 2 == 0 then
+                continue
+            end
+            total += i
+        end
+        print(total)
+
+        local t = {}
+        for i = 1, 3 do
+            t[#t + 1] = function() return i end
+        end
+        print(t[1](), t[3]())
+    "#;
+
+    check(source, Dialect::Luau);
+}
+
+#[test]
+fn debug_information_names_locals() {
+    let decompiled = decompile(
+        STATEMENTS,
+        Dialect::Lua54,
+        Format::Lua54,
+        OptimizationLevel::Moderate,
+    );
+
+    for expected in [
+        "local function fib(n)\n    if n < 2 then\n        return n\n    end\n",
+        "local t = {1, 2, x = 3}\n",
+        "for i = 1, 10 do\n",
+        "    elseif i == 5 then\n",
+        "for k, v in pairs({a = 1}) do\n",
+        "while s < 10 do\n    s = s + 3\nend\n",
+        "for i = 10, 1, -3 do\n",
+    ] {
+        assert!(
+            decompiled.contains(expected),
+            "{expected:?} is missing from:\n{decompiled}"
+        );
+    }
+}
+
+#[test]
+fn stripped_functions_get_generated_names() {
+    fn strip(function: &mut IlFunction) {
+        function.debug = Default::default();
+        function.prototypes.iter_mut().for_each(strip);
+    }
+
+    let tree = parse(CLOSURES, Dialect::Lua54).unwrap();
+    let bytecode = Compiler::new()
+        .create_job()
+        .tree(&tree)
+        .serializer(Format::Lua54)
+        .run()
+        .unwrap();
+    let mut function = Format::Lua54.deserialize(&bytecode).unwrap();
+    strip(&mut function);
+
+    let decompiled = Decompiler::new()
+        .create_job()
+        .function(&function)
+        .reconstructor(LuaReconstructor::new(Dialect::Lua54))
+        .run()
+        .unwrap();
+
+    assert!(
+        decompiled.starts_with("local v1 = {}\nfor i = 1, 3 do\n"),
+        "{decompiled}"
+    );
+    assert!(decompiled.contains("function(p4)"), "{decompiled}");
+    assert!(!decompiled.contains("counters"), "{decompiled}");
+    assert_eq!(
+        run(&decompiled, Dialect::Lua54),
+        run(CLOSURES, Dialect::Lua54)
+    );
+}
+
+#[test]
+fn statements_keep_the_lines_they_were_compiled_from() {
+    /// Records the line of every statement in the main function instead of printing it.
+    struct Lines;
+
+    impl Reconstructor for Lines {
+        fn reconstruct(&self, tree: &Node) -> Result<String> {
+            let lines = tree
+                .block
+                .statements
+                .iter()
+                .map(|statement| statement.span.to_string())
+                .collect::<Vec<_>>();
+
+            Ok(lines.join(" "))
+        }
+    }
+
+    let source = "local a = 1\n\nprint(a)\nlocal b = a + 2\n\n\nprint(b)\n";
+    let tree = parse(source, Dialect::Lua54).unwrap();
+    let bytecode = Compiler::new()
+        .create_job()
+        .tree(&tree)
+        .serializer(Format::Lua54)
+        .run()
+        .unwrap();
+    let function = Format::Lua54.deserialize(&bytecode).unwrap();
+
+    let lines = Decompiler::new()
+        .create_job()
+        .function(&function)
+        .reconstructor(Lines)
+        .run()
+        .unwrap();
+
+    assert_eq!(lines, "1 3 4 7");
+}
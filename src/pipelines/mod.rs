@@ -26,6 +26,9 @@ pub(crate) mod compile;
 #[cfg(feature = "decompile")]
 pub(crate) mod decompile;
 
+#[cfg(feature = "transpile")]
+pub(crate) mod transpile;
+
 /// Defines the level of optimisation that the LUNIR pipeline should apply, in general:
 /// - All includes all optimisations
 /// - Moderate includes all optimisations that are safe from miscompilation
@@ -42,4 +45,7 @@ pub enum OptimizationLevel {
 pub use compile::Compiler;
 
 #[cfg(feature = "decompile")]
-pub use decompile::Decompiler;
+pub use decompile::{Decompiler, LuaReconstructor, Reconstructor};
+
+#[cfg(feature = "transpile")]
+pub use transpile::Transpiler;
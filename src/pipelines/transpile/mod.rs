@@ -0,0 +1,273 @@
+// MIT License
+
+// Copyright (c) 2023 lunir-project
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Bytecode to bytecode retargeting. A chunk is lifted into LUNIR intermediate language by
+//! the deserializer of its format and lowered again by the serializer of the target format,
+//! without going through source.
+//!
+//! `goto` and `continue` need no special treatment here, as every bytecode format expresses
+//! them as plain jumps. Constructs that the target format has no instructions for are either
+//! polyfilled or reported, depending on the `Compatibility` of the job.
+
+mod tests;
+
+use super::OptimizationLevel;
+use crate::formats::{Deserializer, Detect, Serializer};
+use crate::ir::il::{BinaryOpKind, Function, Instruction, IntrinsicSource};
+use anyhow::{bail, Context, Result};
+use std::sync::{Arc, Weak};
+
+pub use crate::formats::{Feature, Format};
+
+/// Describes what a `TranspilationJob` does with constructs that the target format has no
+/// native instructions for.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compatibility {
+    /// Replace them with equivalent code, which may depend on libraries of the target runtime.
+    #[default]
+    Polyfill,
+    /// Fail, reporting every such construct.
+    Strict,
+}
+
+/// A construct that was polyfilled because the target format lacks it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Polyfill {
+    /// The indices of the child prototypes leading from the main function to the function
+    /// holding the polyfilled instruction, empty for the main function itself.
+    pub prototype: Vec<usize>,
+    /// The index of the polyfilled instruction in the lifted intermediate language.
+    pub instruction: usize,
+    pub feature: Feature,
+}
+
+/// The result of a `TranspilationJob`.
+#[derive(Clone, Debug)]
+pub struct Transpilation {
+    /// The bytecode in the target format.
+    pub bytecode: Vec<u8>,
+    /// Every construct that was polyfilled.
+    pub polyfills: Vec<Polyfill>,
+}
+
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct NoBytecode;
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct WithBytecode<'b>(&'b [u8]);
+
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct NoTarget;
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct WithTarget<S: Serializer>(S);
+
+#[derive(Clone, Debug)]
+pub struct TranspilationJob<B, D, T> {
+    bytecode: B,
+    compatibility: Compatibility,
+    optimization_level: OptimizationLevel,
+    _reference: Weak<()>,
+    source: D,
+    target: T,
+}
+
+impl<B, D, T> TranspilationJob<B, D, T> {
+    /// Sets the optimization level of this `TranspilationJob`.
+    pub fn optimization_level(mut self, level: OptimizationLevel) -> Self {
+        self.optimization_level = level;
+
+        self
+    }
+
+    /// Sets how this `TranspilationJob` treats constructs the target format lacks.
+    pub fn compatibility(mut self, compatibility: Compatibility) -> Self {
+        self.compatibility = compatibility;
+
+        self
+    }
+
+    /// Sets the deserializer that reads the source bytecode of this `TranspilationJob`, by
+    /// default any built-in format is accepted and detected from the header of the bytecode.
+    pub fn source<S: Deserializer>(self, deserializer: S) -> TranspilationJob<B, S, T> {
+        TranspilationJob {
+            bytecode: self.bytecode,
+            compatibility: self.compatibility,
+            optimization_level: self.optimization_level,
+            _reference: self._reference,
+            source: deserializer,
+            target: self.target,
+        }
+    }
+}
+
+impl<'b, D, T> TranspilationJob<NoBytecode, D, T> {
+    /// Adds the source bytecode to this `TranspilationJob`.
+    pub fn bytecode(self, bytecode: &'b [u8]) -> TranspilationJob<WithBytecode<'b>, D, T> {
+        TranspilationJob {
+            bytecode: WithBytecode(bytecode),
+            compatibility: self.compatibility,
+            optimization_level: self.optimization_level,
+            _reference: self._reference,
+            source: self.source,
+            target: self.target,
+        }
+    }
+}
+
+impl<B, D> TranspilationJob<B, D, NoTarget> {
+    /// Adds the serializer of the format this `TranspilationJob` produces bytecode in.
+    pub fn target<S: Serializer>(self, serializer: S) -> TranspilationJob<B, D, WithTarget<S>> {
+        TranspilationJob {
+            bytecode: self.bytecode,
+            compatibility: self.compatibility,
+            optimization_level: self.optimization_level,
+            _reference: self._reference,
+            source: self.source,
+            target: WithTarget(serializer),
+        }
+    }
+}
+
+impl<'b, D: Deserializer, S: Serializer> TranspilationJob<WithBytecode<'b>, D, WithTarget<S>> {
+    /// Invokes LUNIR's transpilation pipeline with the parameters passed through this `TranspilationJob`. This will consume the job.
+    #[must_use = "The result of transpilation should be used."]
+    pub fn run(self) -> Result<Transpilation> {
+        let target = self.target.0;
+
+        let function = self.source.deserialize(self.bytecode.0)?;
+
+        // a deserializer that lifts malformed IL would otherwise surface as an obscure error
+        // of the serializer
+        if cfg!(debug_assertions) {
+            function
+                .verify()
+                .context("the source bytecode was lifted into malformed IL")?;
+        }
+
+        let mut polyfills = Vec::new();
+        find_polyfills(&function, &target, &mut Vec::new(), &mut polyfills);
+
+        if self.compatibility == Compatibility::Strict && !polyfills.is_empty() {
+            let sites = polyfills
+                .iter()
+                .map(|polyfill| {
+                    let mut site = format!("{:?} at {}", polyfill.feature, polyfill.instruction);
+
+                    for index in polyfill.prototype.iter().rev() {
+                        site += &format!(" of prototype {index}");
+                    }
+
+                    site
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            bail!("the target format has no native support for: {sites}");
+        }
+
+        let bytecode = target
+            .serialize(&function)
+            .context("failed to write the target bytecode")?;
+
+        Ok(Transpilation {
+            bytecode,
+            polyfills,
+        })
+    }
+}
+
+/// Finds every instruction of `function` and the functions nested in it that `target` has
+/// no native support for, `path` leads from the main function to `function`.
+fn find_polyfills(
+    function: &Function,
+    target: &impl Serializer,
+    path: &mut Vec<usize>,
+    polyfills: &mut Vec<Polyfill>,
+) {
+    for (instruction, il) in function.chunk.inner().iter().enumerate() {
+        let feature = match il {
+            // calls into a bit library are kept as calls by targets without the operators
+            Instruction::Intrinsic(intrinsic) if intrinsic.source == IntrinsicSource::Operator => {
+                Feature::BitwiseOperators
+            }
+            Instruction::BinaryOp(op) if op.operator == BinaryOpKind::IDiv => {
+                Feature::IntegerDivision
+            }
+            _ => continue,
+        };
+
+        if !target.supports(feature) {
+            polyfills.push(Polyfill {
+                prototype: path.clone(),
+                instruction,
+                feature,
+            });
+        }
+    }
+
+    for (index, child) in function.prototypes.iter().enumerate() {
+        path.push(index);
+        find_polyfills(child, target, path, polyfills);
+        path.pop();
+    }
+}
+
+pub struct Transpiler {
+    handle: Arc<()>,
+}
+
+impl Transpiler {
+    /// Creates a new `Transpiler`.
+    pub fn new() -> Self {
+        Self {
+            handle: Arc::new(()),
+        }
+    }
+}
+
+impl Default for Transpiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transpiler {
+    /// Constructs a `TranspilationJob`.
+    pub fn create_job(&self) -> TranspilationJob<NoBytecode, Detect, NoTarget> {
+        TranspilationJob {
+            bytecode: NoBytecode,
+            compatibility: Compatibility::default(),
+            optimization_level: OptimizationLevel::default(),
+            _reference: Arc::downgrade(&self.handle),
+            source: Detect,
+            target: NoTarget,
+        }
+    }
+
+    /// Returns the number of currently living `TranspilationJob`s created by this `Transpiler` or by cloning `TranspilationJob`s created by this `Transpiler`.
+    pub fn job_count(&self) -> usize {
+        Arc::weak_count(&self.handle)
+    }
+}
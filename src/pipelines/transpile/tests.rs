@@ -0,0 +1,367 @@
+#![cfg(test)]
+use super::*;
+use crate::formats::{
+    lua51::{Lua51Deserializer, Lua51Serializer},
+    lua54::Lua54Serializer,
+    luau::LuauSerializer,
+    Deserializer, Serializer,
+};
+use crate::ir::il::{
+    Arity, BinaryOp, BinaryOpKind, Closure, Constant, DebugInfo, Function, GetGlobal, IlChunk,
+    Intrinsic, IntrinsicKind, IntrinsicSource, Load, OptVariable, Return, SetGlobal, Value,
+};
+
+fn function(constants: Vec<Constant>, chunk: IlChunk) -> Function {
+    Function {
+        chunk,
+        constants,
+        prototypes: vec![],
+        upvalues: vec![],
+        arity: Arity::vararg(0),
+        name: None,
+        max_stack_size: 2,
+        debug: DebugInfo::default(),
+    }
+}
+
+/// Lua 5.4 bytecode for `x = y & 3`.
+fn bitwise_chunk() -> Vec<u8> {
+    let constants = vec![
+        Constant::String(b"x".to_vec()),
+        Constant::String(b"y".to_vec()),
+        Constant::Number(3.0),
+    ];
+
+    let chunk = IlChunk::new(vec![
+        Instruction::GetGlobal(GetGlobal {
+            dest: 0,
+            constant: 1,
+        }),
+        Instruction::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::BitAnd(Value::StackIndex(0), Value::ConstantIndex(2)),
+            dest: 0,
+            source: IntrinsicSource::Operator,
+        }),
+        Instruction::SetGlobal(SetGlobal {
+            src: 0,
+            constant: 0,
+        }),
+        Instruction::Return(Return {
+            result_start: 0,
+            result_count: OptVariable::Number(0),
+        }),
+    ]);
+
+    Lua54Serializer::default()
+        .serialize(&function(constants, chunk))
+        .unwrap()
+}
+
+#[test]
+fn formats_are_detected() {
+    let chunk = IlChunk::new(vec![]);
+
+    let lua51 = Lua51Serializer::default()
+        .serialize(&function(vec![], chunk.clone()))
+        .unwrap();
+    let luau = LuauSerializer::default()
+        .serialize(&function(vec![], chunk))
+        .unwrap();
+
+    assert_eq!(Format::detect(&lua51), Some(Format::Lua51));
+    assert_eq!(Format::detect(&bitwise_chunk()), Some(Format::Lua54));
+    assert_eq!(Format::detect(&luau), Some(Format::Luau));
+    assert_eq!(Format::detect(b"print('hi')"), None);
+
+    assert_eq!("Lua5.1".parse::<Format>().unwrap(), Format::Lua51);
+    assert_eq!("lir".parse::<Format>().unwrap(), Format::Lir);
+    assert!("lua53".parse::<Format>().is_err());
+}
+
+#[test]
+fn bitwise_operators_are_polyfilled() {
+    let bytecode = bitwise_chunk();
+    let transpiler = Transpiler::new();
+
+    let transpilation = transpiler
+        .create_job()
+        .bytecode(&bytecode)
+        .target(Format::Lua51)
+        .run()
+        .unwrap();
+
+    assert_eq!(transpilation.polyfills.len(), 1);
+    assert_eq!(
+        transpilation.polyfills[0].feature,
+        Feature::BitwiseOperators
+    );
+
+    // the operator becomes a call to `bit.band`
+    let lifted = Lua51Deserializer
+        .deserialize(&transpilation.bytecode)
+        .unwrap();
+
+    assert!(lifted
+        .constants
+        .iter()
+        .any(|constant| matches!(constant, Constant::String(s) if s == b"band")));
+    assert!(lifted
+        .chunk
+        .inner()
+        .iter()
+        .any(|instruction| matches!(instruction, Instruction::Call(_))));
+
+    // Lua 5.4 has native bitwise operators
+    let transpilation = transpiler
+        .create_job()
+        .bytecode(&bytecode)
+        .target(Format::Lua54)
+        .run()
+        .unwrap();
+
+    assert!(transpilation.polyfills.is_empty());
+}
+
+#[test]
+fn strict_jobs_report_missing_features() {
+    let bytecode = bitwise_chunk();
+
+    let error = Transpiler::new()
+        .create_job()
+        .bytecode(&bytecode)
+        .target(Format::Luau)
+        .compatibility(Compatibility::Strict)
+        .run()
+        .unwrap_err();
+
+    assert!(error.to_string().contains("BitwiseOperators at"));
+}
+
+#[test]
+fn nested_functions_are_retargeted() {
+    let mut child = function(
+        vec![],
+        IlChunk::new(vec![
+            Instruction::Intrinsic(Intrinsic {
+                kind: IntrinsicKind::BitNot(Value::StackIndex(0)),
+                dest: 0,
+                source: IntrinsicSource::Operator,
+            }),
+            Instruction::Return(Return {
+                result_start: 0,
+                result_count: OptVariable::Number(1),
+            }),
+        ]),
+    );
+    child.arity.params = 1;
+
+    let mut main = function(
+        vec![],
+        IlChunk::new(vec![
+            Instruction::Closure(Closure {
+                dest: 0,
+                prototype: 0,
+            }),
+            Instruction::Return(Return {
+                result_start: 0,
+                result_count: OptVariable::Number(1),
+            }),
+        ]),
+    );
+    main.prototypes = vec![child];
+
+    let bytecode = Lua54Serializer::default().serialize(&main).unwrap();
+    let transpiler = Transpiler::new();
+
+    let transpilation = transpiler
+        .create_job()
+        .bytecode(&bytecode)
+        .target(Format::Luau)
+        .run()
+        .unwrap();
+
+    assert_eq!(transpilation.polyfills[0].prototype, vec![0]);
+    assert_eq!(
+        Format::Luau
+            .deserialize(&transpilation.bytecode)
+            .unwrap()
+            .prototypes
+            .len(),
+        1
+    );
+
+    let error = transpiler
+        .create_job()
+        .bytecode(&bytecode)
+        .target(Format::Lua51)
+        .compatibility(Compatibility::Strict)
+        .run()
+        .unwrap_err();
+
+    assert!(error
+        .to_string()
+        .contains("BitwiseOperators at 0 of prototype 0"));
+}
+
+#[test]
+fn luau_is_retargeted_to_lua51() {
+    let constants = vec![Constant::String(b"print".to_vec())];
+
+    let chunk = IlChunk::new(vec![
+        Instruction::GetGlobal(GetGlobal {
+            dest: 0,
+            constant: 0,
+        }),
+        Instruction::Load(Load {
+            dest: 1,
+            src: Value::Immediate(7),
+        }),
+        Instruction::Call(crate::ir::il::Call {
+            callee: 0,
+            self_call: false,
+            num_args: crate::ir::il::OptVariable::Number(1),
+            num_returns: crate::ir::il::OptVariable::Number(0),
+        }),
+        Instruction::Return(Return {
+            result_start: 0,
+            result_count: OptVariable::Number(0),
+        }),
+    ]);
+
+    let bytecode = LuauSerializer::default()
+        .serialize(&function(constants, chunk))
+        .unwrap();
+
+    let transpiler = Transpiler::new();
+    let job = transpiler.create_job().bytecode(&bytecode);
+
+    assert_eq!(transpiler.job_count(), 1);
+
+    let transpilation = job.target(Format::Lua51).run().unwrap();
+    let lifted = Lua51Deserializer
+        .deserialize(&transpilation.bytecode)
+        .unwrap()
+        .chunk;
+
+    assert_eq!(transpiler.job_count(), 0);
+    assert!(matches!(lifted.inner()[0], Instruction::GetGlobal(_)));
+    assert!(matches!(lifted.inner()[2], Instruction::Call(_)));
+}
+
+/// A third-party format that stores the number of instructions it was given.
+struct Counter;
+
+impl Serializer for Counter {
+    fn serialize(&self, function: &Function) -> Result<Vec<u8>> {
+        Ok(vec![function.chunk.inner().len() as u8])
+    }
+
+    fn supports(&self, _feature: Feature) -> bool {
+        true
+    }
+}
+
+#[test]
+fn custom_formats_can_be_plugged_in() {
+    let bytecode = bitwise_chunk();
+
+    let transpilation = Transpiler::new()
+        .create_job()
+        .source(Format::Lua54)
+        .bytecode(&bytecode)
+        .target(Box::new(Counter) as Box<dyn Serializer>)
+        .compatibility(Compatibility::Strict)
+        .run()
+        .unwrap();
+
+    let chunk = Format::Lua54.deserialize(&bytecode).unwrap().chunk;
+
+    assert_eq!(transpilation.bytecode, vec![chunk.inner().len() as u8]);
+    assert!(transpilation.polyfills.is_empty());
+}
+
+/// A third-party format whose bytecode is the textual IL of its main function.
+#[cfg(debug_assertions)]
+struct Text;
+
+#[cfg(debug_assertions)]
+impl Deserializer for Text {
+    fn deserialize(&self, bytes: &[u8]) -> Result<Function> {
+        std::str::from_utf8(bytes)?.parse()
+    }
+}
+
+// lifted IL is only verified in debug builds
+#[cfg(debug_assertions)]
+#[test]
+fn malformed_lifts_are_rejected() {
+    let transpilation = Transpiler::new()
+        .create_job()
+        .source(Text)
+        .bytecode(b"function (0)\n    stack 1\n    r0 = k0\nend")
+        .target(Box::new(Counter) as Box<dyn Serializer>)
+        .run();
+
+    let error = format!("{:#}", transpilation.unwrap_err());
+
+    assert!(error.contains("instruction 0: constant 0 is beyond the 0 constants"));
+    assert!(error.contains("instruction 1: the chunk does not end with a return"));
+}
+
+#[test]
+fn floor_division_is_polyfilled() {
+    // return x // 2
+    let chunk = IlChunk::new(vec![
+        Instruction::GetGlobal(GetGlobal {
+            dest: 0,
+            constant: 0,
+        }),
+        Instruction::BinaryOp(BinaryOp {
+            operator: BinaryOpKind::IDiv,
+            dest: 0,
+            left: Value::StackIndex(0),
+            right: Value::ConstantIndex(1),
+        }),
+        Instruction::Return(Return {
+            result_start: 0,
+            result_count: OptVariable::Number(1),
+        }),
+    ]);
+
+    let constants = vec![Constant::String(b"x".to_vec()), Constant::Integer(2)];
+    let bytecode = Lua54Serializer::default()
+        .serialize(&function(constants, chunk))
+        .unwrap();
+
+    let transpiler = Transpiler::new();
+
+    let transpilation = transpiler
+        .create_job()
+        .bytecode(&bytecode)
+        .target(Format::Lua51)
+        .run()
+        .unwrap();
+
+    assert_eq!(transpilation.polyfills.len(), 1);
+    assert_eq!(transpilation.polyfills[0].feature, Feature::IntegerDivision);
+
+    // the division becomes `math.floor(x / 2)`
+    let lifted = Lua51Deserializer
+        .deserialize(&transpilation.bytecode)
+        .unwrap();
+
+    assert!(lifted
+        .constants
+        .iter()
+        .any(|constant| matches!(constant, Constant::String(s) if s == b"floor")));
+
+    // Luau has a native floor division
+    let transpilation = transpiler
+        .create_job()
+        .bytecode(&bytecode)
+        .target(Format::Luau)
+        .run()
+        .unwrap();
+
+    assert!(transpilation.polyfills.is_empty());
+}
@@ -23,8 +23,14 @@
 #[cfg(feature = "ir")]
 pub use crate::ir::{ast::*, il::*};
 
+#[cfg(feature = "ir")]
+pub use crate::formats::{Deserializer, Serializer};
+
 #[cfg(feature = "compile")]
 pub use crate::pipelines::compile::*;
 
 #[cfg(feature = "decompile")]
 pub use crate::pipelines::decompile::*;
+
+#[cfg(feature = "transpile")]
+pub use crate::pipelines::transpile::*;